@@ -0,0 +1,18 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(set = 0, binding = 1) uniform sampler2D sprite;
+
+layout(location = 0) in vec2 f_uv;
+layout(location = 1) in vec4 f_color;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+    vec4 tinted = texture(sprite, f_uv) * f_color;
+    if (tinted.a <= 0.0) {
+        discard;
+    }
+    // Premultiplied, so that the same output works for both alpha and additive blending
+    color = vec4(tinted.rgb * tinted.a, tinted.a);
+}
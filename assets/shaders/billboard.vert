@@ -0,0 +1,29 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(std140, set = 0, binding = 0) uniform UniformArgs {
+    mat4 proj;
+    mat4 view;
+};
+
+// xyz: world space position, w: size
+layout(location = 0) in vec4 position_size;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec2 f_uv;
+layout(location = 1) out vec4 f_color;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)
+);
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    // Offset in view space so the quad always faces the camera
+    vec4 view_pos = view * vec4(position_size.xyz, 1.0);
+    view_pos.xy += corner * position_size.w * 0.5;
+    f_uv = vec2(corner.x, -corner.y) * 0.5 + 0.5;
+    f_color = color;
+    gl_Position = proj * view_pos;
+}
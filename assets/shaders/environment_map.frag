@@ -7,6 +7,13 @@ layout(std140, set = 0, binding = 0) uniform UniformArgs {
     mat4 proj;
     mat4 view;
     float roughness;
+    // rgb: fog color, a: maximum fog opacity
+    vec4 fog_color;
+    // x: fog density at the camera's height, y: height falloff, z: aerial perspective
+    vec4 fog_params;
+    // rgb: top (or solid) color, w: background mode
+    vec4 background_top;
+    vec4 background_bottom;
 };
 
 layout(set = 1, binding = 0) uniform sampler cube_sampler;
@@ -14,7 +21,49 @@ layout(set = 1, binding = 1) uniform textureCube cube_map;
 
 layout(location = 0) out vec4 color;
 
+const int BACKGROUND_ENVIRONMENT = 0;
+const int BACKGROUND_COLOR = 1;
+const int BACKGROUND_GRADIENT = 2;
+const int BACKGROUND_TRANSPARENT = 3;
+
+// Distance the environment is fogged as if it were at
+const float SKY_DISTANCE = 1000.0;
+
+// Attenuate and tint a color seen `dist` units away along `view_dir`, by exponential height
+// fog and aerial perspective
+vec3 apply_fog(vec3 color, const vec3 view_dir, const float dist) {
+    float density = fog_params.x;
+    float falloff = fog_params.y;
+    float aerial_perspective = fog_params.z;
+
+    // Integrate the density along the ray, which changes exponentially with height
+    float fy = falloff * view_dir.y * dist;
+    float optical_depth = density * dist * (abs(fy) > 0.0001 ? (1.0 - exp(-fy)) / fy : 1.0);
+    float fog = min(1.0 - exp(-optical_depth), fog_color.a);
+
+    // Shorter wavelengths are scattered more, so distant objects are tinted blue
+    vec3 aerial_transmittance = exp(-dist * aerial_perspective * vec3(0.33, 0.54, 1.0));
+    color = color * aerial_transmittance + fog_color.rgb * (1.0 - aerial_transmittance);
+
+    return mix(color, fog_color.rgb, fog);
+}
+
 void main() {
-    vec3 col = textureLod(samplerCube(cube_map, cube_sampler), f_pos, roughness).rgb;
+    int mode = int(background_top.w);
+    if (mode == BACKGROUND_TRANSPARENT) {
+        color = vec4(0.0);
+        return;
+    }
+
+    vec3 dir = normalize(f_pos);
+    vec3 col;
+    if (mode == BACKGROUND_COLOR) {
+        col = background_top.rgb;
+    } else if (mode == BACKGROUND_GRADIENT) {
+        col = mix(background_bottom.rgb, background_top.rgb, dir.y * 0.5 + 0.5);
+    } else {
+        col = textureLod(samplerCube(cube_map, cube_sampler), f_pos, roughness).rgb;
+    }
+    col = apply_fog(col, dir, SKY_DISTANCE);
     color = vec4(col, 1.0);
 }
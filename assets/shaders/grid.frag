@@ -0,0 +1,32 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 f_world_pos;
+
+layout(std140, set = 0, binding = 0) uniform UniformArgs {
+    mat4 proj;
+    mat4 view;
+    vec4 camera_pos;
+    vec4 color;
+    // x: cell size, y: extent, z: height, w: line width in pixels
+    vec4 params;
+};
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    // Distance to the nearest line in pixels, so lines stay the same width on screen
+    vec2 coord = f_world_pos.xz / params.x;
+    vec2 line_dist = abs(fract(coord - 0.5) - 0.5) / (fwidth(coord) * params.w);
+    float line = 1.0 - min(min(line_dist.x, line_dist.y), 1.0);
+
+    // Fade out towards the edge of the plane, hiding its extent and the aliased far lines
+    float dist = length(f_world_pos.xz - camera_pos.xz);
+    float fade = 1.0 - smoothstep(0.5 * params.y, params.y, dist);
+
+    float alpha = color.a * line * fade;
+    if (alpha <= 0.0) {
+        discard;
+    }
+    out_color = vec4(color.rgb, alpha);
+}
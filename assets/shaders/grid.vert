@@ -0,0 +1,25 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(std140, set = 0, binding = 0) uniform UniformArgs {
+    mat4 proj;
+    mat4 view;
+    vec4 camera_pos;
+    vec4 color;
+    // x: cell size, y: extent, z: height, w: line width in pixels
+    vec4 params;
+};
+
+layout(location = 0) out vec3 f_world_pos;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)
+);
+
+void main() {
+    // The plane follows the camera around, while the lines drawn on it stay put in world space
+    vec2 corner = CORNERS[gl_VertexIndex] * params.y;
+    f_world_pos = vec3(camera_pos.x + corner.x, params.z, camera_pos.z + corner.y);
+    gl_Position = proj * view * vec4(f_world_pos, 1.0);
+}
@@ -0,0 +1,17 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 a_pos;
+layout(location = 1) in vec4 a_color;
+
+layout(std140, set = 0, binding = 0) uniform Args {
+    mat4 proj;
+    mat4 view;
+};
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = a_color;
+    gl_Position = proj * view * vec4(a_pos, 1.0);
+}
@@ -1,18 +1,30 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
+// Optional features are enabled by defines injected when compiling a variant:
+// HAS_EMISSIVE, HAS_CLEARCOAT, ALPHA_MASK, DEBUG_VIEW, HAS_BENT_NORMAL, HAS_HEIGHT,
+// HAS_ANISOTROPY, HAS_SUBSURFACE and SUBSURFACE_PASS.
+
+#ifndef ALPHA_MASK
 layout(early_fragment_tests) in;
+#endif
 
 layout(location = 0) in vec4 f_world_pos;
 layout(location = 1) in vec3 f_norm;
 layout(location = 2) in vec3 f_tang;
 layout(location = 3) flat in float f_tbn_handedness;
 layout(location = 4) in vec2 f_uv;
+layout(location = 5) in vec2 f_lightmap_uv;
+// Bit i is set if lights[i] reaches the mesh
+layout(location = 6) flat in uint f_light_mask;
 
+// Point lights have their intensity in candela, directional lights in lux. For directional
+// lights `pos` is the direction towards the light.
 layout(std140) struct Light {
     vec3 pos;
     float intensity;
     vec3 color;
+    float directional;
 };
 
 layout(set = 0, binding = 0) uniform sampler tex_sampler;
@@ -20,23 +32,127 @@ layout(set = 0, binding = 1) uniform textureCube spec_cube_map;
 layout(set = 0, binding = 2) uniform textureCube irradiance_cube_map;
 layout(set = 0, binding = 3) uniform texture2D spec_brdf_map;
 
+// Irradiance as L2 spherical harmonics at each probe of a grid, X fastest, then Y, then Z.
+// probe_grid_counts.w is 0 until the grid is baked.
+layout(std430, set = 0, binding = 4) readonly buffer ProbeGrid {
+    vec4 probe_grid_min;
+    vec4 probe_grid_spacing;
+    ivec4 probe_grid_counts;
+    vec4 probe_sh[];
+};
+
+// Light reflected and emitted by the scene, voxelized around it, with opacity in alpha. Each
+// mip averages the one before, so wider cones sample higher mips.
+layout(set = 0, binding = 5) uniform texture3D voxel_radiance;
+
+// Depth of the shadowed directional light's cascades as seen from the light, one per layer
+layout(set = 0, binding = 6) uniform texture2DArray shadow_map;
+
+// The specular albedo of `spec_brdf_map` averaged over view angles, one texel per roughness
+layout(set = 0, binding = 7) uniform texture2D spec_brdf_avg_map;
+
 layout(std140, set = 1, binding = 0) uniform Args {
     layout(offset = 0) mat4 proj;
     layout(offset = 64) mat4 view;
     layout(offset = 128) vec3 camera_pos;
     layout(offset = 140) int lights_count;
     layout(offset = 144) Light lights[32];
+    // x: diffuse IBL occlusion strength, y: specular IBL occlusion strength,
+    // z: specular occlusion mode, one of the SPECULAR_OCCLUSION_ constants
+    layout(offset = 1168) vec4 occlusion;
+    // rgb: fog color, a: maximum fog opacity
+    layout(offset = 1184) vec4 fog_color;
+    // x: fog density at the camera's height, y: height falloff, z: aerial perspective
+    layout(offset = 1200) vec4 fog_params;
+    // xyz: corner of the voxel cone tracing volume, w: length of its sides
+    layout(offset = 1216) vec4 voxel_gi_bounds;
+    // x: whether voxel cone tracing is enabled, y: voxels along each side,
+    // z: whether specular cones are traced, w: number of mips
+    layout(offset = 1232) vec4 voxel_gi_params;
+    // Projection and view of the light for each shadow cascade, nearest first
+    layout(offset = 1248) mat4 shadow_view_proj[4];
+    // x: number of cascades, y: depth bias, z: normal bias, w: filter radius in texels
+    layout(offset = 1504) vec4 shadow_params;
+    // x: index of the shadowed light or -1, y: whether fragments are tinted by cascade
+    layout(offset = 1520) ivec4 shadow_light;
+    // x: parallax occlusion mapping steps when looking straight at a surface, y: at grazing
+    // angles, 0 if disabled
+    layout(offset = 1536) vec4 parallax_steps;
+    // x: IBL mode left of split_x, y: right of it, both one of the IBL_ constants,
+    // z: frame of the reference IBL, seeding its samples, w: reference samples per frame
+    layout(offset = 1552) vec4 ibl;
 };
 
-layout(set = 2, binding = 0) uniform texture2D albedo_map;
-layout(set = 2, binding = 1) uniform texture2D normal_map;
-layout(set = 2, binding = 2) uniform texture2D metallic_roughness_map;
-layout(set = 2, binding = 3) uniform texture2D ao_map;
-layout(set = 2, binding = 4) uniform texture2D emissive_map;
-layout(std140, set = 2, binding = 5) uniform MatData {
-    vec3 emissive_factor;
+// Tints of the cascades in the cascade debug view, matching `shadow::CASCADE_COLORS`
+const vec3 CASCADE_COLORS[4] = vec3[](
+    vec3(1.0, 0.25, 0.25),
+    vec3(0.25, 1.0, 0.25),
+    vec3(0.25, 0.5, 1.0),
+    vec3(1.0, 1.0, 0.25)
+);
+
+// Number of materials in the material set. Greater than one when materials are
+// descriptor-indexed, in which case `material_index` selects the material to draw.
+layout(constant_id = 0) const uint MATERIAL_SLOTS = 1;
+
+layout(set = 2, binding = 0) uniform sampler2D albedo_maps[MATERIAL_SLOTS];
+layout(set = 2, binding = 1) uniform sampler2D normal_maps[MATERIAL_SLOTS];
+layout(set = 2, binding = 2) uniform sampler2D metallic_roughness_maps[MATERIAL_SLOTS];
+layout(set = 2, binding = 3) uniform sampler2D ao_maps[MATERIAL_SLOTS];
+layout(set = 2, binding = 4) uniform sampler2D emissive_maps[MATERIAL_SLOTS];
+// Tangent space direction of least occlusion, encoded like the normal maps
+layout(set = 2, binding = 5) uniform sampler2D bent_normal_maps[MATERIAL_SLOTS];
+// Height in red, white being the top of the surface
+layout(set = 2, binding = 6) uniform sampler2D height_maps[MATERIAL_SLOTS];
+// Thickness in red, scaled by the thickness factor, for subsurface scattering
+layout(set = 2, binding = 7) uniform sampler2D thickness_maps[MATERIAL_SLOTS];
+// Tangent space direction of anisotropy in red and green, encoded like the normal maps, and
+// strength in blue, scaled by the anisotropy strength, as in KHR_materials_anisotropy
+layout(set = 2, binding = 8) uniform sampler2D anisotropy_maps[MATERIAL_SLOTS];
+
+struct Material {
+    vec4 emissive_factor;
+    // x: alpha cutoff, y: clearcoat factor, z: clearcoat roughness, w: occlusion strength
+    vec4 params;
+    // x: height scale, the depth of the height map's range in texture coordinates
+    vec4 parallax;
+    // x: anisotropy strength, yz: cosine and sine of the direction of anisotropy's angle
+    // from the tangent
+    vec4 anisotropy;
+    // rgb: tint of the light scattered under the surface, a: how far diffuse light wraps past
+    // the terminator
+    vec4 subsurface_color;
+    // x: scattering radius in world units, y: thickness factor
+    vec4 subsurface;
+    // Channels of x: metallic and y: roughness in the metallic roughness map, z: occlusion in
+    // the AO map
+    uvec4 channels;
+};
+
+layout(std140, set = 2, binding = 9) uniform MatData {
+    Material materials[MATERIAL_SLOTS];
 };
 
+// Indirect diffuse light baked for the mesh being drawn, in the units of the irradiance map
+layout(set = 3, binding = 0) uniform sampler2D lightmap;
+
+layout(push_constant) uniform DrawArgs {
+    uint material_index;
+    uint debug_view;
+    uint has_lightmap;
+    // Debug view of the A/B split view's right side, from pixel column split_x on
+    uint split_debug_view;
+    uint split_x;
+};
+
+const uint DEBUG_VIEW_LIT = 0;
+const uint DEBUG_VIEW_ALBEDO = 1;
+const uint DEBUG_VIEW_NORMAL = 2;
+const uint DEBUG_VIEW_METALLIC = 3;
+const uint DEBUG_VIEW_ROUGHNESS = 4;
+const uint DEBUG_VIEW_AO = 5;
+const uint DEBUG_VIEW_EMISSIVE = 6;
+
 layout(location = 0) out vec4 color;
 
 const float MAX_SPEC_LOD = 4.0;
@@ -72,17 +188,381 @@ float saturate(float v) {
     return clamp(v, 0.0, 1.0);
 }
 
-void main() {
-    vec3 albedo = texture(sampler2D(albedo_map, tex_sampler), f_uv).rgb;
-    vec3 normal = texture(sampler2D(normal_map, tex_sampler), f_uv).rgb;
-    vec2 metallic_roughness = texture(sampler2D(metallic_roughness_map, tex_sampler), f_uv).bg;
-    float metallic = metallic_roughness.x;
-    float roughness = metallic_roughness.y;
-    float ao = texture(sampler2D(ao_map, tex_sampler), f_uv).r;
-    vec3 emissive = texture(sampler2D(emissive_map, tex_sampler), f_uv).rgb;
+// The direction of anisotropy and the one across it, in world space, and the anisotropy
+// strength. Set in main for materials with HAS_ANISOTROPY.
+vec3 aniso_t;
+vec3 aniso_b;
+float aniso_strength;
 
-    normal = normal * 2.0 - 1.0;
+// Anisotropic GGX and its height correlated Smith visibility, as in the
+// KHR_materials_anisotropy spec. The roughness along the direction of anisotropy goes from
+// `at` = `ab` to 1 with the strength.
+float d_ggx_aniso(const float nh, const float th, const float bh, const float at, const float ab) {
+    float a2 = at * ab;
+    vec3 f = vec3(ab * th, at * bh, a2 * nh);
+    float w2 = a2 / dot(f, f);
+    return a2 * w2 * w2 / 3.1415926535;
+}
+
+// Shaped like `v_smithschlick`, the geometry term over NdotL * NdotV
+float v_smith_aniso(const float nl, const float nv, const float tl, const float bl,
+                    const float tv, const float bv, const float at, const float ab) {
+    float lambda_v = nl * length(vec3(at * tv, ab * bv, nv));
+    float lambda_l = nv * length(vec3(at * tl, ab * bl, nl));
+    return 2.0 / max(lambda_v + lambda_l, 0.0001);
+}
+
+// D times the geometry term over NdotL * NdotV of the specular lobe, which is stretched
+// along the direction of anisotropy for materials with HAS_ANISOTROPY
+float ggx_dv(const vec3 N, const vec3 V, const vec3 L, const vec3 H, const float a) {
+    float NdotL = saturate(dot(N, L));
+    float NdotV = abs(dot(N, V)) + 0.00001;
+    float NdotH = saturate(dot(N, H));
+#ifdef HAS_ANISOTROPY
+    float at = mix(a, 1.0, aniso_strength * aniso_strength);
+    return d_ggx_aniso(NdotH, dot(aniso_t, H), dot(aniso_b, H), at, a)
+        * clamp(v_smith_aniso(NdotL, NdotV, dot(aniso_t, L), dot(aniso_b, L), dot(aniso_t, V), dot(aniso_b, V), at, a), 0.0, 1.0);
+#else
+    return d_ggx(NdotH, a) * clamp(v_smithschlick(NdotL, NdotV, a), 0.0, 1.0);
+#endif
+}
+
+// Multiple scattering, after Kulla and Conty, "Revisiting Physically Based Shading at
+// Imageworks" (2017). The GGX lobe only counts light reflected once by the microfacets, so
+// rough surfaces lose the light bouncing between them and get darker than they should,
+// most visibly rough metals. The lost energy 1 - E(mu) is added back by a second lobe.
+
+// Fraction of the light from direction mu reflected once, for a white f0
+float spec_albedo(const float NdotV, const float roughness) {
+    vec2 env_brdf = texture(sampler2D(spec_brdf_map, tex_sampler), vec2(NdotV, roughness)).rg;
+    return env_brdf.x + env_brdf.y;
+}
+
+float spec_albedo_avg(const float roughness) {
+    return texture(sampler2D(spec_brdf_avg_map, tex_sampler), vec2(roughness, 0.5)).r;
+}
+
+// The reflectance of light bouncing any number of times, from the Fresnel reflectance
+// averaged over the hemisphere, which is f0 + (1 - f0) / 21 for Schlick's approximation
+vec3 multiscatter_fresnel(const vec3 f0, const float e_avg) {
+    vec3 f_avg = f0 + (1.0 - f0) / 21.0;
+    return f_avg * f_avg * e_avg / (1.0 - f_avg * (1.0 - e_avg));
+}
+
+// The multiple scattering lobe, light arriving from NdotL and leaving towards NdotV
+vec3 multiscatter_brdf(const vec3 f0, const float NdotL, const float NdotV, const float roughness) {
+    float e_avg = spec_albedo_avg(roughness);
+    float lost = (1.0 - spec_albedo(NdotV, roughness)) * (1.0 - spec_albedo(NdotL, roughness));
+    return multiscatter_fresnel(f0, e_avg) * lost / (3.1415926535 * max(1.0 - e_avg, 0.0001));
+}
+
+// Evaluate the irradiance stored at a probe in the direction of `n`
+vec3 probe_irradiance(const int probe, const vec3 n) {
+    int base = probe * 9;
+    vec3 irradiance = probe_sh[base].rgb * 0.282095
+        + probe_sh[base + 1].rgb * 0.488603 * n.y
+        + probe_sh[base + 2].rgb * 0.488603 * n.z
+        + probe_sh[base + 3].rgb * 0.488603 * n.x
+        + probe_sh[base + 4].rgb * 1.092548 * n.x * n.y
+        + probe_sh[base + 5].rgb * 1.092548 * n.y * n.z
+        + probe_sh[base + 6].rgb * 0.315392 * (3.0 * n.z * n.z - 1.0)
+        + probe_sh[base + 7].rgb * 1.092548 * n.x * n.z
+        + probe_sh[base + 8].rgb * 0.546274 * (n.x * n.x - n.y * n.y);
+    return max(irradiance, vec3(0.0));
+}
+
+// Trilinearly interpolate the irradiance of the eight probes around `pos`, falling back to
+// the irradiance cube map outside of the grid or before it is baked
+vec3 sample_irradiance(const vec3 pos, const vec3 n) {
+    vec3 cell = (pos - probe_grid_min.xyz) / probe_grid_spacing.xyz;
+    ivec3 counts = probe_grid_counts.xyz;
+    if (probe_grid_counts.w == 0
+        || any(lessThan(cell, vec3(0.0)))
+        || any(greaterThan(cell, vec3(counts - 1)))) {
+        return texture(samplerCube(irradiance_cube_map, tex_sampler), n).rgb;
+    }
+
+    ivec3 base = min(ivec3(cell), counts - 2);
+    vec3 t = cell - vec3(base);
+    vec3 irradiance = vec3(0.0);
+    for (int i = 0; i < 8; i++) {
+        ivec3 offset = ivec3(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+        ivec3 p = base + offset;
+        vec3 w = mix(1.0 - t, t, vec3(offset));
+        int probe = p.x + counts.x * (p.y + counts.y * p.z);
+        irradiance += probe_irradiance(probe, n) * w.x * w.y * w.z;
+    }
+    return irradiance;
+}
+
+// March a cone with the given tangent of its half angle through the voxel radiance volume,
+// accumulating light front to back. Returns the light gathered in rgb and how much of the
+// cone was blocked in a.
+vec4 cone_trace(const vec3 origin, const vec3 dir, const float aperture) {
+    float size = voxel_gi_bounds.w;
+    float voxel = size / voxel_gi_params.y;
+    float max_lod = voxel_gi_params.w - 1.0;
+    vec4 acc = vec4(0.0);
+    // Start a voxel away, so the surface doesn't block its own cones
+    float dist = voxel;
+    for (int i = 0; i < 64 && acc.a < 0.95; i++) {
+        vec3 uvw = (origin + dir * dist - voxel_gi_bounds.xyz) / size;
+        if (any(lessThan(uvw, vec3(0.0))) || any(greaterThan(uvw, vec3(1.0)))) {
+            break;
+        }
+        float diameter = max(voxel, 2.0 * aperture * dist);
+        float lod = min(log2(diameter / voxel), max_lod);
+        vec4 s = textureLod(sampler3D(voxel_radiance, tex_sampler), uvw, lod);
+        acc += (1.0 - acc.a) * s;
+        dist += diameter * 0.5;
+    }
+    return acc;
+}
+
+// Gather indirect diffuse light over the hemisphere around `n` with six 60 degree cones,
+// weighted by the cosine of their angle to the normal. Returned in the units of the
+// irradiance cube map.
+vec4 cone_trace_diffuse(const vec3 pos, const vec3 n) {
+    vec3 up = abs(n.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 t = normalize(cross(up, n));
+    vec3 b = cross(n, t);
+    const float aperture = 0.577;
+    vec4 acc = cone_trace(pos, n, aperture) * 0.25;
+    for (int i = 0; i < 5; i++) {
+        float phi = float(i) * 2.0 * 3.1415926535 / 5.0;
+        vec3 dir = normalize(n * 0.5 + (t * cos(phi) + b * sin(phi)) * 0.866);
+        acc += cone_trace(pos, dir, aperture) * 0.15;
+    }
+    return acc;
+}
+
+// The nearest shadow cascade covering `pos`, or -1 outside all of them. Returns its
+// light space position, with texture coordinates in xy and depth in z.
+int shadow_cascade(const vec3 pos, out vec3 light_pos) {
+    for (int i = 0; i < int(shadow_params.x); i++) {
+        vec4 clip = shadow_view_proj[i] * vec4(pos, 1.0);
+        light_pos = vec3(clip.xy * 0.5 + 0.5, clip.z);
+        if (all(greaterThanEqual(light_pos, vec3(0.0))) && all(lessThanEqual(light_pos, vec3(1.0)))) {
+            return i;
+        }
+    }
+    return -1;
+}
+
+// Fraction of the shadowed light reaching `pos`, filtered over the texels within the
+// filter radius. `n` is the geometric normal and `l` the direction towards the light.
+float shadow_factor(const vec3 pos, const vec3 n, const vec3 l) {
+    float NdotL = saturate(dot(n, l));
+    // Surfaces at a grazing angle to the light are pushed furthest out
+    vec3 offset_pos = pos + n * shadow_params.z * sqrt(1.0 - NdotL * NdotL);
+    vec3 light_pos;
+    int cascade = shadow_cascade(offset_pos, light_pos);
+    if (cascade < 0) {
+        return 1.0;
+    }
+
+    ivec2 size = textureSize(sampler2DArray(shadow_map, tex_sampler), 0).xy;
+    ivec2 center = ivec2(light_pos.xy * vec2(size));
+    int radius = min(int(ceil(shadow_params.w)), 3);
+    float depth = light_pos.z - shadow_params.y;
+    float lit = 0.0;
+    for (int y = -radius; y <= radius; y++) {
+        for (int x = -radius; x <= radius; x++) {
+            ivec2 texel = clamp(center + ivec2(x, y), ivec2(0), size - 1);
+            float occluder = texelFetch(sampler2DArray(shadow_map, tex_sampler), ivec3(texel, cascade), 0).r;
+            lit += depth <= occluder ? 1.0 : 0.0;
+        }
+    }
+    return lit / float((2 * radius + 1) * (2 * radius + 1));
+}
+
+// Attenuate and tint a color seen `dist` units away along `view_dir`, by exponential height
+// fog and aerial perspective
+vec3 apply_fog(vec3 color, const vec3 view_dir, const float dist) {
+    float density = fog_params.x;
+    float falloff = fog_params.y;
+    float aerial_perspective = fog_params.z;
+
+    // Integrate the density along the ray, which changes exponentially with height
+    float fy = falloff * view_dir.y * dist;
+    float optical_depth = density * dist * (abs(fy) > 0.0001 ? (1.0 - exp(-fy)) / fy : 1.0);
+    float fog = min(1.0 - exp(-optical_depth), fog_color.a);
+
+    // Shorter wavelengths are scattered more, so distant objects are tinted blue
+    vec3 aerial_transmittance = exp(-dist * aerial_perspective * vec3(0.33, 0.54, 1.0));
+    color = color * aerial_transmittance + fog_color.rgb * (1.0 - aerial_transmittance);
+
+    return mix(color, fog_color.rgb, fog);
+}
+
+const uint SPECULAR_OCCLUSION_AO = 0;
+const uint SPECULAR_OCCLUSION_LAGARDE = 1;
+const uint SPECULAR_OCCLUSION_BENT_NORMAL = 2;
 
+// Specular occlusion derived from ambient occlusion, from "Moving Frostbite to PBR" (Lagarde
+// and de Rousiers 2014). Glossy reflections at grazing angles are occluded less than the AO.
+float specular_occlusion_lagarde(float NdotV, float ao, float roughness) {
+    return clamp(pow(NdotV + ao, exp2(-16.0 * roughness - 1.0)) - 1.0 + ao, 0.0, 1.0);
+}
+
+// Fraction of the reflection lobe's cone which falls inside the cone of visible directions
+// around the bent normal, from "Practical Realtime Strategies for Accurate Indirect
+// Occlusion" (Jimenez et al. 2016). Both cones are treated as spherical caps.
+float specular_occlusion_bent_normal(vec3 bent_N, vec3 R, float ao, float roughness) {
+    // Cosine of the half angle of the visible cone, for a cosine weighted AO
+    float cos_vis = sqrt(max(1.0 - ao, 0.0));
+    // Cosine of the half angle of a cone fitted to the GGX lobe
+    float cos_spec = exp2(-3.32193 * roughness * roughness);
+    float cos_distance = dot(bent_N, R);
+
+    float r_vis = acos(cos_vis);
+    float r_spec = acos(cos_spec);
+    float distance = acos(clamp(cos_distance, -1.0, 1.0));
+    float smaller = min(r_vis, r_spec);
+    float larger = max(r_vis, r_spec);
+
+    float intersection;
+    if (distance <= larger - smaller) {
+        // One cap is inside the other
+        intersection = 1.0 - max(cos_vis, cos_spec);
+    } else if (distance >= r_vis + r_spec) {
+        intersection = 0.0;
+    } else {
+        // Partial overlap, approximated with a smoothstep between the two cases above
+        float t = smoothstep(0.0, 1.0, 1.0 - (distance - (larger - smaller)) / (r_vis + r_spec - (larger - smaller)));
+        intersection = (1.0 - max(cos_vis, cos_spec)) * t;
+    }
+    return clamp(intersection / max(1.0 - cos_spec, 0.0001), 0.0, 1.0);
+}
+
+const uint IBL_SPLIT_SUM = 0;
+const uint IBL_REFERENCE = 1;
+
+// PCG hash, from "Hash Functions for GPU Rendering" (Jarzynski and Olano 2020)
+uint pcg_hash(uint x) {
+    uint state = x * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// Uniform random number in [0, 1), advancing the seed
+float random(inout uint seed) {
+    seed = pcg_hash(seed);
+    return float(seed) * 2.3283064365386963e-10;
+}
+
+// Monte Carlo estimate of the environment light reflected towards V, by the same BRDF the
+// lights are shaded with. Samples alternate between a cosine distribution for the diffuse
+// lobe and the GGX distribution of half vectors for the specular one, combined with the
+// balance heuristic. Returns the diffuse part in `diffuse` and the specular part in
+// `specular`, to be occluded like the split sum's.
+void reference_ibl(const vec3 N, const vec3 V, const vec3 albedo, const float metallic,
+                   const float roughness, const vec3 f0, out vec3 diffuse, out vec3 specular) {
+    const float PI = 3.1415926535;
+    float a = roughness * roughness;
+    float NdotV = abs(dot(N, V)) + 0.00001;
+    vec3 up = abs(N.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 t = normalize(cross(up, N));
+    vec3 b = cross(N, t);
+
+    uint seed = pcg_hash(uint(gl_FragCoord.x) + pcg_hash(uint(gl_FragCoord.y) + pcg_hash(uint(ibl.z))));
+    uint samples = uint(ibl.w);
+    diffuse = vec3(0.0);
+    specular = vec3(0.0);
+    for (uint i = 0u; i < samples; i++) {
+        float u = random(seed);
+        float phi = 2.0 * PI * random(seed);
+        vec3 L;
+        if ((i & 1u) == 0u) {
+            float r = sqrt(u);
+            L = t * (r * cos(phi)) + b * (r * sin(phi)) + N * sqrt(1.0 - u);
+        } else {
+            float cos_theta = sqrt((1.0 - u) / (1.0 + (a * a - 1.0) * u));
+            float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+            vec3 H = t * (sin_theta * cos(phi)) + b * (sin_theta * sin(phi)) + N * cos_theta;
+            L = reflect(-V, H);
+        }
+
+        float NdotL = dot(N, L);
+        if (NdotL <= 0.0) {
+            continue;
+        }
+        vec3 H = normalize(V + L);
+        float NdotH = saturate(dot(N, H));
+        float VdotH = max(dot(V, H), 0.0001);
+
+        float pdf = 0.5 * NdotL / PI + 0.5 * d_ggx(NdotH, a) * NdotH / (4.0 * VdotH);
+        // The top mip of the prefiltered map is the environment itself
+        vec3 radiance = textureLod(samplerCube(spec_cube_map, tex_sampler), L, 0.0).rgb;
+        vec3 fresnel = f_schlick(f0, VdotH);
+        vec3 k_D = (vec3(1.0) - fresnel) * (1.0 - metallic);
+        vec3 spec_brdf = ggx_dv(N, V, L, H, a) * fresnel;
+        spec_brdf /= max(4.0 * NdotV * NdotL, 0.001);
+        spec_brdf += multiscatter_brdf(f0, NdotL, NdotV, roughness);
+
+        vec3 weight = radiance * NdotL / max(pdf, 0.0001);
+        diffuse += albedo / PI * k_D * weight;
+        specular += spec_brdf * weight;
+    }
+    diffuse /= float(samples);
+    specular /= float(samples);
+}
+
+// How much the direction light leaves thin parts in is bent towards the normal, and how
+// tightly it's focused around it
+const float TRANSMISSION_DISTORTION = 0.2;
+const float TRANSMISSION_POWER = 4.0;
+
+// Replaces N.L in the diffuse lighting of subsurface scattering materials. Light wraps past
+// the terminator, each channel as far as the scattering color lets it, and reaches the side
+// away from it through thin parts, from "Approximating Translucency for a Fast, Cheap and
+// Convincing Subsurface-Scattering Look" (Barre-Brisebois and Bouchard 2011).
+vec3 subsurface_lighting(const vec3 N, const vec3 V, const vec3 L, const vec3 scatter_color,
+                         const float wrap, const float thickness) {
+    float NdotL = dot(N, L);
+    float wrapped = saturate((NdotL + wrap) / (1.0 + wrap)) / (1.0 + wrap);
+    vec3 lit = mix(vec3(saturate(NdotL)), vec3(wrapped), scatter_color);
+
+    vec3 through = normalize(L + N * TRANSMISSION_DISTORTION);
+    float transmission = pow(saturate(dot(V, -through)), TRANSMISSION_POWER);
+    return lit + scatter_color * transmission * (1.0 - saturate(thickness));
+}
+
+// Matches `node::pbr::MAX_PARALLAX_STEPS`
+const int MAX_PARALLAX_STEPS = 128;
+
+// Parallax occlusion mapping. Marches the view ray, given in tangent space towards the
+// camera, down through the height field from the top of the surface, and returns the texture
+// coordinates where it first goes below the height field, interpolated between the last two
+// steps.
+vec2 parallax_occlusion(vec2 uv, vec3 view_tangent, float height_scale) {
+    float steps = ceil(mix(parallax_steps.y, parallax_steps.x, abs(view_tangent.z)));
+    if (steps < 1.0) {
+        return uv;
+    }
+    float layer_depth = 1.0 / steps;
+    // The offset is clamped at grazing angles, where it would otherwise blow up
+    vec2 delta = view_tangent.xy / max(view_tangent.z, 0.1) * height_scale / steps;
+    // Gradients of the unmodified coordinates, as the loop is non-uniform
+    vec2 dx = dFdx(uv);
+    vec2 dy = dFdy(uv);
+
+    float ray_depth = 0.0;
+    float depth = 1.0 - textureGrad(height_maps[material_index], uv, dx, dy).r;
+    for (int i = 0; i < MAX_PARALLAX_STEPS && float(i) < steps && ray_depth < depth; i++) {
+        uv -= delta;
+        ray_depth += layer_depth;
+        depth = 1.0 - textureGrad(height_maps[material_index], uv, dx, dy).r;
+    }
+
+    vec2 prev_uv = uv + delta;
+    float after = depth - ray_depth;
+    float before = 1.0 - textureGrad(height_maps[material_index], prev_uv, dx, dy).r - (ray_depth - layer_depth);
+    float weight = after / min(after - before, -0.0001);
+    return mix(uv, prev_uv, clamp(weight, 0.0, 1.0));
+}
+
+void main() {
     vec3 V = normalize(camera_pos - f_world_pos.xyz);
 
     vec3 N = normalize(f_norm);
@@ -90,15 +570,94 @@ void main() {
     vec3 B = normalize(cross(N, T)) * f_tbn_handedness;
     mat3 TBN = mat3(T, B, N);
 
+    vec2 uv = f_uv;
+#ifdef HAS_HEIGHT
+    uv = parallax_occlusion(uv, normalize(transpose(TBN) * V), materials[material_index].parallax.x);
+#endif
+
+    vec4 albedo_alpha = texture(albedo_maps[material_index], uv);
+#ifdef ALPHA_MASK
+    if (albedo_alpha.a < materials[material_index].params.x) {
+        discard;
+    }
+#endif
+    vec3 albedo = albedo_alpha.rgb;
+    vec3 normal = texture(normal_maps[material_index], uv).rgb;
+    uvec4 channels = materials[material_index].channels;
+    vec4 metallic_roughness = texture(metallic_roughness_maps[material_index], uv);
+    float metallic = metallic_roughness[channels.x];
+    float roughness = metallic_roughness[channels.y];
+    float ao = mix(1.0, texture(ao_maps[material_index], uv)[channels.z], materials[material_index].params.w);
+    float diffuse_ao = mix(1.0, ao, occlusion.x);
+#ifdef HAS_EMISSIVE
+    vec3 emissive = texture(emissive_maps[material_index], uv).rgb;
+    vec3 emissive_factor = materials[material_index].emissive_factor.rgb;
+#else
+    vec3 emissive = vec3(0.0);
+    vec3 emissive_factor = vec3(0.0);
+#endif
+
+    normal = normal * 2.0 - 1.0;
+
     N = normalize(TBN * normal);
     vec3 R = reflect(-V, N);
 
+#ifdef HAS_ANISOTROPY
+    vec3 aniso_texel = texture(anisotropy_maps[material_index], uv).rgb;
+    aniso_strength = materials[material_index].anisotropy.x * aniso_texel.b;
+    vec2 aniso_rotation = materials[material_index].anisotropy.yz;
+    vec2 aniso_direction = mat2(aniso_rotation.x, aniso_rotation.y, -aniso_rotation.y, aniso_rotation.x)
+        * normalize(aniso_texel.rg * 2.0 - 1.0);
+    aniso_t = TBN * vec3(aniso_direction, 0.0);
+    aniso_t = normalize(aniso_t - N * dot(N, aniso_t));
+    aniso_b = cross(N, aniso_t);
+
+    // The prefiltered map only has isotropic lobes, so reflections are stretched by bending
+    // the normal they are reflected about towards the plane across the direction of
+    // anisotropy, more so the smoother the surface, as in the KHR_materials_anisotropy sample
+    // renderer
+    vec3 aniso_normal = cross(cross(aniso_t, V), aniso_t);
+    float bend = 1.0 - aniso_strength * (1.0 - roughness);
+    bend *= bend;
+    R = reflect(-V, normalize(mix(aniso_normal, N, bend * bend)));
+#endif
+
     float NdotV = abs(dot(N, V)) + 0.00001;
 
+    uint specular_occlusion_mode = uint(occlusion.z);
+    float specular_occlusion = ao;
+    if (specular_occlusion_mode == SPECULAR_OCCLUSION_BENT_NORMAL) {
+#ifdef HAS_BENT_NORMAL
+        vec3 bent_N = normalize(TBN * (texture(bent_normal_maps[material_index], uv).rgb * 2.0 - 1.0));
+        specular_occlusion = specular_occlusion_bent_normal(bent_N, R, ao, roughness);
+#else
+        specular_occlusion = specular_occlusion_lagarde(NdotV, ao, roughness);
+#endif
+    } else if (specular_occlusion_mode == SPECULAR_OCCLUSION_LAGARDE) {
+        specular_occlusion = specular_occlusion_lagarde(NdotV, ao, roughness);
+    }
+    float specular_ao = mix(1.0, specular_occlusion, occlusion.y);
+
     vec3 f0 = mix(vec3(0.04), albedo, metallic);
 
-    vec3 ambient_irradiance = texture(samplerCube(irradiance_cube_map, tex_sampler), N).rgb;
+    // Lightmaps also capture occlusion by the rest of the scene, so they replace the probes
+    vec3 ambient_irradiance = has_lightmap != 0
+        ? texture(lightmap, f_lightmap_uv).rgb
+        : sample_irradiance(f_world_pos.xyz, N);
     vec3 ambient_spec = textureLod(samplerCube(spec_cube_map, tex_sampler), R, roughness * MAX_SPEC_LOD).rgb;
+    // Light the cones miss comes from the probes and environment map, as without them
+    if (voxel_gi_params.x > 0.5) {
+        if (has_lightmap == 0) {
+            vec4 traced = cone_trace_diffuse(f_world_pos.xyz, N);
+            ambient_irradiance = traced.rgb + ambient_irradiance * (1.0 - traced.a);
+        }
+        if (voxel_gi_params.z > 0.5) {
+            // Roughly the width of the GGX lobe
+            float aperture = clamp(roughness * roughness, 0.03, 1.0);
+            vec4 traced = cone_trace(f_world_pos.xyz, R, aperture);
+            ambient_spec = traced.rgb + ambient_spec * (1.0 - traced.a);
+        }
+    }
     vec2 env_brdf = texture(sampler2D(spec_brdf_map, tex_sampler), vec2(NdotV, roughness)).rg;
 
     vec3 ambient_spec_fres = f_schlick(f0, NdotV);
@@ -106,16 +665,63 @@ void main() {
     vec3 ambient_diffuse_fac = vec3(1.0) - ambient_spec_fres;
     ambient_diffuse_fac *= 1.0 - metallic;
 
-    vec3 ambient = (ambient_irradiance * albedo * ambient_diffuse_fac) + (ambient_spec * (ambient_spec_fres * env_brdf.x + env_brdf.y));
+    // The multiple scattering lobe is broad, so it reflects about the irradiance, times the
+    // cosine weighted integral of 1 - E(mu) which cancels the lobe's normalization
+    float e_avg = spec_albedo_avg(roughness);
+    vec3 ambient_spec_ms = ambient_irradiance * multiscatter_fresnel(f0, e_avg) * (1.0 - (env_brdf.x + env_brdf.y));
+
+    // Diffuse light is kept apart for subsurface scattering, which blurs it
+    vec3 ambient_diffuse = (ambient_irradiance * albedo * ambient_diffuse_fac) * diffuse_ao;
+    vec3 ambient = (ambient_spec * (ambient_spec_fres * env_brdf.x + env_brdf.y) + ambient_spec_ms) * specular_ao;
+
+    uint ibl_mode = uint(gl_FragCoord.x) >= split_x ? uint(ibl.y) : uint(ibl.x);
+    if (ibl_mode == IBL_REFERENCE) {
+        vec3 reference_diffuse;
+        vec3 reference_specular;
+        reference_ibl(N, V, albedo, metallic, roughness, f0, reference_diffuse, reference_specular);
+        ambient_diffuse = reference_diffuse * diffuse_ao;
+        ambient = reference_specular * specular_ao;
+    }
+
+#ifdef HAS_CLEARCOAT
+    // Clearcoat is a dielectric layer on top of the base material, using the same normal
+    float clearcoat = materials[material_index].params.y;
+    float clearcoat_roughness = materials[material_index].params.z;
+    float clearcoat_a = clearcoat_roughness * clearcoat_roughness;
+    vec3 clearcoat_fres = f_schlick(vec3(0.04), NdotV) * clearcoat;
+    vec3 clearcoat_spec = textureLod(samplerCube(spec_cube_map, tex_sampler), R, clearcoat_roughness * MAX_SPEC_LOD).rgb;
+    vec2 clearcoat_brdf = texture(sampler2D(spec_brdf_map, tex_sampler), vec2(NdotV, clearcoat_roughness)).rg;
+    ambient_diffuse *= 1.0 - clearcoat_fres;
+    ambient = ambient * (1.0 - clearcoat_fres) + clearcoat_spec * (0.04 * clearcoat_brdf.x + clearcoat_brdf.y) * clearcoat * specular_ao;
+#endif
+
+#ifdef HAS_SUBSURFACE
+    vec4 subsurface_color = materials[material_index].subsurface_color;
+    float thickness = texture(thickness_maps[material_index], uv).r * materials[material_index].subsurface.y;
+#endif
 
     float a = roughness * roughness;
     vec3 acc = vec3(0.0);
-    for (int i = 0; i < lights_count; ++i) {
-        vec3 L = lights[i].pos - f_world_pos.xyz;
-        float d2 = dot(L, L);
-        L = normalize(L);
+    vec3 diffuse_acc = vec3(0.0);
+    uint light_mask = f_light_mask;
+    while (light_mask != 0u) {
+        int i = findLSB(light_mask);
+        light_mask &= light_mask - 1u;
+        vec3 L;
+        vec3 l_contrib;
+        if (lights[i].directional > 0.5) {
+            L = normalize(lights[i].pos);
+            l_contrib = lights[i].color * lights[i].intensity;
+            if (i == shadow_light.x) {
+                l_contrib *= shadow_factor(f_world_pos.xyz, normalize(f_norm), L);
+            }
+        } else {
+            L = lights[i].pos - f_world_pos.xyz;
+            float d2 = dot(L, L);
+            L = normalize(L);
+            l_contrib = lights[i].color * lights[i].intensity / d2;
+        }
         vec3 H = normalize(V + L);
-        vec3 l_contrib = lights[i].color * lights[i].intensity / d2;
 
         float NdotL = saturate(dot(N, L));
         float NdotH = saturate(dot(N, H));
@@ -124,14 +730,85 @@ void main() {
         vec3 k_D = vec3(1.0) - fresnel;
         k_D *= 1.0 - metallic;
         
-        vec3 specular = d_ggx(NdotH, a) * clamp(v_smithschlick(NdotL, NdotV, a), 0.0, 1.0) * fresnel;
+        vec3 specular = ggx_dv(N, V, L, H, a) * fresnel;
         specular /= max(4.0 * NdotV * NdotL, 0.001);
+        specular += multiscatter_brdf(f0, NdotL, NdotV, roughness);
 
         vec3 diffuse = albedo / 3.1415926535 * k_D;
 
-        acc += (diffuse + specular) * NdotL * l_contrib;
+#ifdef HAS_SUBSURFACE
+        vec3 l_diffuse = diffuse * subsurface_lighting(N, V, L, subsurface_color.rgb, subsurface_color.a, thickness) * l_contrib;
+#else
+        vec3 l_diffuse = diffuse * NdotL * l_contrib;
+#endif
+        vec3 l_acc = specular * NdotL * l_contrib;
+
+#ifdef HAS_CLEARCOAT
+        vec3 clearcoat_light_fres = f_schlick(vec3(0.04), VdotH) * clearcoat;
+        vec3 clearcoat_specular = d_ggx(NdotH, clearcoat_a) * clamp(v_smithschlick(NdotL, NdotV, clearcoat_a), 0.0, 1.0) * clearcoat_light_fres;
+        clearcoat_specular /= max(4.0 * NdotV * NdotL, 0.001);
+        l_diffuse *= 1.0 - clearcoat_light_fres;
+        l_acc = l_acc * (1.0 - clearcoat_light_fres) + clearcoat_specular * NdotL * l_contrib;
+#endif
+
+        diffuse_acc += l_diffuse;
+        acc += l_acc;
+    }
+
+    vec3 diffuse_light = ambient_diffuse + diffuse_acc;
+    vec3 final = diffuse_light + ambient + acc + emissive * emissive_factor;
+    float view_dist = length(camera_pos - f_world_pos.xyz);
+    final = apply_fog(final, -V, view_dist);
+    // The fog scales colors and adds to them, so what's left of the diffuse light in the fogged
+    // color is the fog's scale of it
+    diffuse_light = apply_fog(diffuse_light, -V, view_dist) - apply_fog(vec3(0.0), -V, view_dist);
+
+    if (shadow_light.y != 0) {
+        vec3 light_pos;
+        int cascade = shadow_cascade(f_world_pos.xyz, light_pos);
+        if (cascade >= 0) {
+            final *= CASCADE_COLORS[cascade];
+            diffuse_light *= CASCADE_COLORS[cascade];
+        }
+    }
+
+#ifdef SUBSURFACE_PASS
+    // Only the diffuse light is drawn, which `subsurface_blur.comp` blurs and swaps for the
+    // unblurred light in the scene's image. Alpha is the scattering radius on screen, as a
+    // fraction of half its height, and 0 where there's nothing to blur.
+#ifdef DEBUG_VIEW
+    if ((uint(gl_FragCoord.x) >= split_x ? split_debug_view : debug_view) != DEBUG_VIEW_LIT) {
+        color = vec4(0.0);
+        return;
+    }
+#endif
+    float view_depth = max(abs((view * vec4(f_world_pos.xyz, 1.0)).z), 0.0001);
+    color = vec4(diffuse_light, materials[material_index].subsurface.x * abs(proj[1][1]) / view_depth);
+    return;
+#endif
+
+#ifdef DEBUG_VIEW
+    switch (uint(gl_FragCoord.x) >= split_x ? split_debug_view : debug_view) {
+        case DEBUG_VIEW_ALBEDO:
+            final = albedo;
+            break;
+        case DEBUG_VIEW_NORMAL:
+            final = N * 0.5 + 0.5;
+            break;
+        case DEBUG_VIEW_METALLIC:
+            final = vec3(metallic);
+            break;
+        case DEBUG_VIEW_ROUGHNESS:
+            final = vec3(roughness);
+            break;
+        case DEBUG_VIEW_AO:
+            final = vec3(ao);
+            break;
+        case DEBUG_VIEW_EMISSIVE:
+            final = emissive * emissive_factor;
+            break;
     }
+#endif
 
-    vec3 final = ambient * ao + acc + emissive * emissive_factor;
     color = vec4(final, 1.0);
 }
\ No newline at end of file
@@ -5,8 +5,10 @@ layout(location = 0) in vec3 a_pos;
 layout(location = 1) in vec3 a_norm;
 layout(location = 2) in vec4 a_tang;
 layout(location = 3) in vec2 a_uv;
+layout(location = 4) in vec2 a_lightmap_uv;
 // vec4[4] is used instead of mat4 due to spirv-cross bug for dx12 backend
-layout(location = 4) in vec4 model[4]; // per-instance.
+layout(location = 5) in vec4 model[4]; // per-instance.
+layout(location = 9) in uint a_light_mask; // per-instance, a bit per light reaching the mesh.
 
 layout(std140, set = 1, binding = 0) uniform Args {
     mat4 proj;
@@ -19,10 +21,14 @@ layout(location = 1) out vec3 frag_norm;
 layout(location = 2) out vec3 frag_tang;
 layout(location = 3) flat out float frag_tbn_handedness;
 layout(location = 4) out vec2 frag_uv;
+layout(location = 5) out vec2 frag_lightmap_uv;
+layout(location = 6) flat out uint frag_light_mask;
 
 void main() {
     mat4 model_mat = mat4(model[0], model[1], model[2], model[3]);
     frag_uv = a_uv;
+    frag_lightmap_uv = a_lightmap_uv;
+    frag_light_mask = a_light_mask;
     frag_norm = normalize((model_mat * vec4(a_norm, 0.0)).xyz);
     frag_tang = normalize((model_mat * vec4(a_tang.xyz, 0.0)).xyz);
     frag_tbn_handedness = a_tang.w;
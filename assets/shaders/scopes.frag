@@ -0,0 +1,99 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Draws the histogram and waveform binned by scopes.comp in the top left corner, over the
+// range of stops around the value the exposure saturates to.
+
+layout(location = 0) in vec2 f_uv;
+
+// Must match scopes.comp
+#define HISTOGRAM_BINS 128
+#define WAVEFORM_COLUMNS 256
+#define WAVEFORM_ROWS 128
+#define LOG2_MIN -16.0
+#define LOG2_MAX 16.0
+
+// Stops shown below and above the value which saturates at the exposure
+#define STOPS_BELOW 12.0
+#define STOPS_ABOVE 4.0
+
+// Size and position of each scope, in pixels
+#define PANEL_SIZE vec2(320.0, 160.0)
+#define MARGIN 16.0
+
+#define MODE_HISTOGRAM 1
+#define MODE_WAVEFORM 2
+
+layout(std430, set = 0, binding = 0) readonly buffer Scopes {
+    uint bins[];
+};
+
+layout(push_constant) uniform OverlayArgs {
+    float exposure_ev100;
+    uint mode;
+    uint pixels;
+};
+
+layout(location = 0) out vec4 color;
+
+// Position within the panel at `corner`, from 0 to 1 with y up, or outside that if the
+// fragment isn't in the panel
+vec2 panel_pos(vec2 corner) {
+    vec2 pos = (gl_FragCoord.xy - corner) / PANEL_SIZE;
+    return vec2(pos.x, 1.0 - pos.y);
+}
+
+bool in_panel(vec2 pos) {
+    return all(greaterThanEqual(pos, vec2(0.0))) && all(lessThan(pos, vec2(1.0)));
+}
+
+// Stops relative to saturation of a position along a scope's axis
+float stops_at(float t) {
+    return mix(-STOPS_BELOW, STOPS_ABOVE, t);
+}
+
+// Bin among `count` of a value this many stops from saturation, matching `log_bin` of
+// scopes.comp
+int bin_at(float stops, int count) {
+    // Luminance saturates at 1.0 after scaling by the exposure, see tonemap.frag
+    float log2_value = stops + exposure_ev100 + log2(1.2);
+    float t = (log2_value - LOG2_MIN) / (LOG2_MAX - LOG2_MIN);
+    return clamp(int(t * float(count)), 0, count - 1);
+}
+
+vec4 histogram(vec2 pos) {
+    float stops = stops_at(pos.x);
+    uint count = bins[bin_at(stops, HISTOGRAM_BINS)];
+    // Square root, so that the spread out parts stand out next to spikes
+    float height = sqrt(float(count) / float(pixels) * 8.0);
+    if (pos.y < height) {
+        return stops >= 0.0 ? vec4(1.0, 0.2, 0.2, 0.9) : vec4(0.9, 0.9, 0.9, 0.9);
+    }
+    return vec4(0.0, 0.0, 0.0, 0.6);
+}
+
+vec4 waveform(vec2 pos) {
+    float stops = stops_at(pos.y);
+    int column = min(int(pos.x * float(WAVEFORM_COLUMNS)), WAVEFORM_COLUMNS - 1);
+    int row = bin_at(stops, WAVEFORM_ROWS);
+    float column_pixels = float(pixels) / float(WAVEFORM_COLUMNS);
+    vec3 rgb;
+    for (int c = 0; c < 3; c++) {
+        uint count = bins[HISTOGRAM_BINS + (column * WAVEFORM_ROWS + row) * 3 + c];
+        rgb[c] = sqrt(float(count) / column_pixels * 8.0);
+    }
+    vec4 background = stops >= 0.0 ? vec4(0.3, 0.0, 0.0, 0.6) : vec4(0.0, 0.0, 0.0, 0.6);
+    return vec4(max(background.rgb, min(rgb, vec3(1.0))), background.a);
+}
+
+void main() {
+    vec2 histogram_pos = panel_pos(vec2(MARGIN));
+    vec2 waveform_pos = panel_pos(vec2(MARGIN, 2.0 * MARGIN + PANEL_SIZE.y));
+    if ((mode & MODE_HISTOGRAM) != 0 && in_panel(histogram_pos)) {
+        color = histogram(histogram_pos);
+    } else if ((mode & MODE_WAVEFORM) != 0 && in_panel(waveform_pos)) {
+        color = waveform(waveform_pos);
+    } else {
+        discard;
+    }
+}
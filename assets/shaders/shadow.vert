@@ -0,0 +1,14 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 a_pos;
+
+layout(push_constant) uniform ShadowArgs {
+    // Projection and view of the light for the cascade being drawn
+    mat4 light_view_proj;
+    mat4 model;
+};
+
+void main() {
+    gl_Position = light_view_proj * model * vec4(a_pos, 1.0);
+}
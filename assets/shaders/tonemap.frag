@@ -7,9 +7,18 @@ layout(set = 0, binding = 0) uniform sampler tex_sampler;
 layout(set = 0, binding = 1) uniform texture2D hdr_tex;
 
 layout(std140, set = 0, binding = 2) uniform Args {
-    float exposure;
+    // Exposure value at ISO 100
+    float exposure_ev100;
     int curve;
     float comparison_factor;
+    int encode_gamma;
+    int output_alpha;
+    int false_color;
+    // Right side of the A/B split view
+    float split_exposure_ev100;
+    int split_curve;
+    int split_enabled;
+    float split_divider;
 };
 
 layout(location = 0) out vec4 color;
@@ -72,24 +81,81 @@ vec3 aces_fitted(vec3 color)
     return color;
 }
 
+// Camera style false color, by stops from saturation of the exposed luminance. Zones that
+// are exposed well keep the tonemapped image, in gray.
+vec3 false_color_zone(float stops, vec3 mapped) {
+    if (stops >= 0.0) {
+        return vec3(1.0, 0.0, 0.0); // Clipped
+    } else if (stops >= -0.5) {
+        return vec3(1.0, 0.5, 0.0); // About to clip
+    } else if (stops >= -1.0) {
+        return vec3(1.0, 1.0, 0.0); // Bright highlights
+    } else if (stops >= -2.8 && stops < -2.2) {
+        return vec3(0.0, 0.8, 0.0); // Middle gray, 18% of saturation
+    } else if (stops < -9.0) {
+        return vec3(0.5, 0.0, 0.5); // Crushed to black
+    } else if (stops < -7.0) {
+        return vec3(0.0, 0.0, 1.0); // Deep shadows
+    } else if (stops < -5.5) {
+        return vec3(0.0, 0.6, 0.8); // Shadows
+    }
+    return vec3(dot(mapped, vec3(0.2126, 0.7152, 0.0722)));
+}
+
+// Linear to sRGB transfer function, for swapchains which don't apply it on write
+vec3 srgb_encode(const vec3 linear) {
+    vec3 lo = linear * 12.92;
+    vec3 hi = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    return mix(hi, lo, lessThanEqual(linear, vec3(0.0031308)));
+}
+
 void main() {
     vec2 uv = f_uv;
     uv.y = 1.0 - uv.y;
-    vec3 hdrColor = texture(sampler2D(hdr_tex, tex_sampler), uv).rgb;
+    vec4 hdr = texture(sampler2D(hdr_tex, tex_sampler), uv);
+    vec3 hdrColor = hdr.rgb;
+
+    float ev100 = exposure_ev100;
+    int side_curve = curve;
+    bool right_side = split_enabled != 0 && uv.x >= split_divider;
+    if (right_side) {
+        ev100 = split_exposure_ev100;
+        side_curve = split_curve;
+    }
 
-    hdrColor *= exposure; // exposure
+    // Scale so that luminance which saturates a camera sensor at this exposure maps to 1.0
+    float exposure = 1.0 / (1.2 * exp2(ev100));
+    hdrColor *= exposure;
 
     float factor;
 
-    if (curve == 0) {
+    if (side_curve == 0) {
         factor = 0.0;
-    } else if (curve == 1) {
+    } else if (side_curve == 1) {
         factor = 1.0;
-    } else if (curve == 2) {
+    } else if (side_curve == 2) {
         factor = comparison_factor;
     }
 
     vec3 mapped = mix(aces_fitted(hdrColor), tonemapUncharted2(hdrColor), step(uv.x, factor));
 
-    color = vec4(mapped, 1.0);
+    if (false_color != 0) {
+        float luminance = dot(hdrColor, vec3(0.2126, 0.7152, 0.0722));
+        mapped = false_color_zone(log2(max(luminance, 1e-20)), mapped);
+    }
+
+    // A line of about two pixels along the divider
+    if (split_enabled != 0) {
+        float pixel = 1.0 / float(textureSize(sampler2D(hdr_tex, tex_sampler), 0).x);
+        if (abs(uv.x - split_divider) < pixel) {
+            mapped = vec3(1.0);
+        }
+    }
+
+    if (encode_gamma != 0) {
+        mapped = srgb_encode(clamp(mapped, 0.0, 1.0));
+    }
+
+    // The HDR image is premultiplied by alpha, which compositors expect as well
+    color = vec4(mapped, output_alpha != 0 ? clamp(hdr.a, 0.0, 1.0) : 1.0);
 }
@@ -64,9 +64,6 @@ void main() {
     vec3 cube_vert = cube_verts[gl_VertexIndex + (6 * face_index)];
     f_pos = cube_vert;
 
-    vec2 vert = verts[gl_VertexIndex];
-    vert.y /= 6.0;
-    vert.y -= (5.0 / 6.0);
-    vert.y += float(face_index) * (1.0 / 3.0);
-    gl_Position = vec4(vert, 0.0, 1.0);
+    // Covers the whole face, which is a layer of its own
+    gl_Position = vec4(verts[gl_VertexIndex], 0.0, 1.0);
 }
\ No newline at end of file
@@ -2,8 +2,7 @@ use derivative::Derivative;
 use failure::format_err;
 use rendy::hal;
 use rendy::{
-    command::QueueId,
-    factory::{BufferState, Factory, ImageState},
+    factory::Factory,
     memory::MemoryUsageValue,
     mesh::PosNormTangTex,
     resource::{Buffer, BufferInfo, Escape},
@@ -13,9 +12,25 @@ use rendy::{
     },
 };
 
-use std::{collections::HashMap, fs::File, io::Read, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    geometry_pool::{GeometryPoolBuilder, GeometryRange},
+    shader::ShaderFeatures,
+    slot_map::{Handle, SlotMap},
+    upload::UploadQueues,
+};
 
-use crate::Backend;
+/// Depth of a height texture's range in texture coordinates, for materials which don't say
+const DEFAULT_HEIGHT_SCALE: f32 = 0.05;
+/// The anisotropy texel of materials without an anisotropy texture: along the tangent, at the
+/// full strength of the factor
+const ANISOTROPY_TEXEL: [u8; 4] = [255, 128, 255, 255];
 
 #[derive(Clone, Copy, Default)]
 #[repr(C, align(16))]
@@ -23,46 +38,345 @@ pub struct MaterialFactors {
     pub albedo: [f32; 4],
     pub metallic: f32,
     pub roughness: f32,
+    pub emissive: [f32; 3],
+    pub alpha_cutoff: f32,
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    /// How strongly the occlusion texture attenuates lighting, from 0.0 (not at all) to 1.0
+    pub occlusion_strength: f32,
+    /// Depth of the height texture's range in texture coordinates, for parallax occlusion
+    /// mapping
+    pub height_scale: f32,
+    /// How much the specular lobe is stretched along the direction of anisotropy, from 0.0
+    /// (isotropic) to 1.0
+    pub anisotropy: f32,
+    /// Angle of the direction of anisotropy from the tangent, counterclockwise in radians
+    pub anisotropy_rotation: f32,
+    /// How far past the terminator diffuse light wraps around, from 0.0 (no subsurface
+    /// scattering) to 1.0
+    pub subsurface: f32,
+    /// Tint of the light scattered under the surface
+    pub subsurface_color: [f32; 3],
+    /// Distance light travels under the surface before leaving it, in world units
+    pub subsurface_radius: f32,
+    /// Scales the thickness texture, below 1.0 light passes through from behind
+    pub thickness: f32,
+    /// Where metallic, roughness and occlusion are in their textures
+    pub channels: ChannelLayout,
+}
+
+/// The textures of a material, which decide the color space their files are read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaterialTexture {
+    Albedo,
+    Normal,
+    MetallicRoughness,
+    Ao,
+    Emissive,
+    BentNormal,
+    Height,
+    Thickness,
+    Anisotropy,
+}
+
+impl MaterialTexture {
+    /// Whether the texture holds colors, stored sRGB encoded and decoded to linear when
+    /// sampled. The others hold data which is sampled as it's stored.
+    pub fn is_srgb(self) -> bool {
+        match self {
+            MaterialTexture::Albedo | MaterialTexture::Emissive => true,
+            MaterialTexture::Normal
+            | MaterialTexture::MetallicRoughness
+            | MaterialTexture::Ao
+            | MaterialTexture::BentNormal
+            | MaterialTexture::Height
+            | MaterialTexture::Thickness
+            | MaterialTexture::Anisotropy => false,
+        }
+    }
+
+    /// How the texture's image files are read.
+    fn repr(self) -> Repr {
+        if self.is_srgb() {
+            Repr::Srgb
+        } else {
+            Repr::Unorm
+        }
+    }
+}
+
+/// A channel of a texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum Channel {
+    R = 0,
+    G = 1,
+    B = 2,
+    A = 3,
+}
+
+/// Which channels of a material's textures the values packed into them are read from. The
+/// default is glTF's packing, occlusion in red, roughness in green and metallic in blue, which
+/// also suits textures packing all three.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Derivative, serde::Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ChannelLayout {
+    /// Channel of the metallic roughness texture holding metallic
+    #[derivative(Default(value = "Channel::B"))]
+    pub metallic: Channel,
+    /// Channel of the metallic roughness texture holding roughness
+    #[derivative(Default(value = "Channel::G"))]
+    pub roughness: Channel,
+    /// Channel of the AO texture holding occlusion
+    #[derivative(Default(value = "Channel::R"))]
+    pub ao: Channel,
+}
+
+impl ChannelLayout {
+    /// A pixel of a metallic roughness texture holding the given values in this layout, and
+    /// opaque white in the other channels.
+    fn metallic_roughness_pixel(&self, metallic: u8, roughness: u8) -> [u8; 4] {
+        let mut pixel = [255; 4];
+        pixel[self.metallic as usize] = metallic;
+        pixel[self.roughness as usize] = roughness;
+        pixel
+    }
+}
+
+/// Per-material values read by the PBR shader, matching the std140 `Material` struct in `pbr.frag`.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct MaterialUniform {
+    /// Emissive factor in `rgb`, `a` is unused
+    pub emissive_factor: [f32; 4],
+    /// Alpha cutoff, clearcoat factor, clearcoat roughness and occlusion strength
+    pub params: [f32; 4],
+    /// Height scale in `x`, `yzw` are unused
+    pub parallax: [f32; 4],
+    /// Anisotropy strength in `x`, and the cosine and sine of its rotation in `yz`, `w` is
+    /// unused
+    pub anisotropy: [f32; 4],
+    /// Subsurface scattering color in `rgb` and wrap in `a`
+    pub subsurface_color: [f32; 4],
+    /// Subsurface scattering radius in `x` and thickness factor in `y`, `zw` are unused
+    pub subsurface: [f32; 4],
+    /// Channels of metallic, roughness and occlusion, see `ChannelLayout`, `w` is unused
+    pub channels: [u32; 4],
+}
+
+impl From<&MaterialFactors> for MaterialUniform {
+    fn from(factors: &MaterialFactors) -> Self {
+        MaterialUniform {
+            emissive_factor: [
+                factors.emissive[0],
+                factors.emissive[1],
+                factors.emissive[2],
+                0.0,
+            ],
+            params: [
+                factors.alpha_cutoff,
+                factors.clearcoat,
+                factors.clearcoat_roughness,
+                factors.occlusion_strength,
+            ],
+            parallax: [factors.height_scale, 0.0, 0.0, 0.0],
+            anisotropy: [
+                factors.anisotropy,
+                factors.anisotropy_rotation.cos(),
+                factors.anisotropy_rotation.sin(),
+                0.0,
+            ],
+            subsurface_color: [
+                factors.subsurface_color[0],
+                factors.subsurface_color[1],
+                factors.subsurface_color[2],
+                factors.subsurface,
+            ],
+            subsurface: [factors.subsurface_radius, factors.thickness, 0.0, 0.0],
+            channels: [
+                factors.channels.metallic as u32,
+                factors.channels.roughness as u32,
+                factors.channels.ao as u32,
+                0,
+            ],
+        }
+    }
 }
 
 pub struct MaterialData<B: hal::Backend> {
     pub factors: MaterialFactors,
+    /// The shader features this material needs, which selects the shader variant it's drawn with.
+    pub features: ShaderFeatures,
     pub albedo: Texture<B>,
     pub normal: Texture<B>,
     pub metallic_roughness: Texture<B>,
     pub ao: Texture<B>,
     pub emissive: Texture<B>,
-    pub emissive_factor_buffer: Escape<Buffer<B>>,
+    /// Flat unless the material has a bent normal texture, see `ShaderFeatures::HAS_BENT_NORMAL`
+    pub bent_normal: Texture<B>,
+    /// Unused unless the material has a height texture, see `ShaderFeatures::HAS_HEIGHT`
+    pub height: Texture<B>,
+    /// Unused unless the material scatters light under its surface, see
+    /// `ShaderFeatures::HAS_SUBSURFACE`
+    pub thickness: Texture<B>,
+    /// Unused unless the material is anisotropic, see `ShaderFeatures::HAS_ANISOTROPY`. The
+    /// direction of anisotropy in red and green, and its strength in blue, as in
+    /// `KHR_materials_anisotropy`.
+    pub anisotropy: Texture<B>,
+    pub uniform_buffer: Escape<Buffer<B>>,
 }
 
 #[derive(Default)]
-pub struct MaterialStorage<B: hal::Backend>(pub Vec<MaterialData<B>>);
-pub type MaterialHandle = usize;
+pub struct MaterialStorage<B: hal::Backend>(pub SlotMap<MaterialData<B>, MaterialKey>);
+/// Types the handles of materials, which don't depend on the backend of their textures
+pub enum MaterialKey {}
+pub type MaterialHandle = Handle<MaterialKey>;
 
-pub struct Primitive<B: hal::Backend> {
-    pub mesh_data: rendy::mesh::Mesh<B>,
+pub struct Primitive {
+    /// Where its vertices and indices are in the `GeometryPool`
+    pub range: GeometryRange,
+    pub geometry: PrimitiveGeometry,
     pub mesh_handle: MeshHandle,
     pub mat: MaterialHandle,
 }
 
-#[derive(Derivative)]
-#[derivative(Default(bound = ""))]
-pub struct PrimitiveStorage<B: hal::Backend>(pub Vec<Primitive<B>>);
-pub type PrimitiveHandle = usize;
+/// The parts of a primitive's vertex data kept on the host, for baking lightmaps.
+#[derive(Debug, Default, Clone)]
+pub struct PrimitiveGeometry {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub lightmap_uvs: Vec<[f32; 2]>,
+    pub indices: Vec<u32>,
+}
+
+impl PrimitiveGeometry {
+    /// Distance of the farthest vertex from the origin of the mesh
+    fn bounding_radius(&self) -> f32 {
+        self.positions
+            .iter()
+            .map(|p| nalgebra::Vector3::from(*p).norm())
+            .fold(0.0, f32::max)
+    }
+
+    /// Adds the primitive's shading vertices and its lightmap coordinates to the pool.
+    fn add_to_pool(
+        &self,
+        vertices: &[PosNormTangTex],
+        geometry_pool: &mut GeometryPoolBuilder,
+    ) -> GeometryRange {
+        geometry_pool.add(vertices, &self.lightmap_uvs, &self.indices)
+    }
+}
+
+#[derive(Default)]
+pub struct PrimitiveStorage(pub SlotMap<Primitive>);
+pub type PrimitiveHandle = Handle<Primitive>;
 
 #[derive(Default)]
 pub struct Mesh {
+    /// From the source file, or made up for meshes without one
+    pub name: String,
     pub primitives: Vec<PrimitiveHandle>,
     pub max_instances: u16,
+    /// Baked indirect diffuse light, shared by every instance of the mesh
+    pub lightmap: Option<LightmapHandle>,
+    /// Distance of the farthest vertex from the origin of the mesh, to tell how big it is
+    /// on screen
+    pub bounding_radius: f32,
+    /// Simpler meshes to draw instead of this one as it gets smaller on screen, ordered from
+    /// the most to the least detailed
+    pub lods: Vec<Lod>,
+    /// The mesh this is a level of detail of, if it is one
+    pub lod_of: Option<MeshHandle>,
+}
+
+/// A level of detail of a mesh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lod {
+    pub mesh: MeshHandle,
+    /// The height of the mesh's bounding sphere on screen, as a fraction of the screen height,
+    /// below which this level is drawn
+    pub screen_size: f32,
 }
 
 #[derive(Default)]
-pub struct MeshStorage(pub Vec<Mesh>);
-pub type MeshHandle = usize;
+pub struct MeshStorage(pub SlotMap<Mesh>);
+pub type MeshHandle = Handle<Mesh>;
+
+/// A texture of the indirect diffuse light reaching each point of a mesh, in the units of the
+/// irradiance cube map, addressed with the mesh's lightmap texture coordinates.
+pub struct Lightmap<B: hal::Backend> {
+    pub mesh: MeshHandle,
+    /// The entity whose transform places the mesh when baking
+    pub entity: specs::Entity,
+    /// Where the lightmap is loaded from, and written to when it's baked
+    pub path: PathBuf,
+    /// Size of the lightmap when it's baked
+    pub resolution: u32,
+    /// `None` until the file exists or the lightmap is baked
+    pub texture: Option<Texture<B>>,
+}
+
+pub struct LightmapStorage<B: hal::Backend> {
+    pub lightmaps: Vec<Lightmap<B>>,
+    /// Bound for meshes without a lightmap texture
+    pub placeholder: Texture<B>,
+}
+pub type LightmapHandle = usize;
 
 #[derive(Default)]
 pub struct MeshHandleMap(pub HashMap<String, MeshHandle>);
 
+/// The file of a glTF buffer. Buffers embedded in a data URI or in the binary chunk of a .glb
+/// file aren't supported.
+fn gltf_buffer_path(
+    base_path: &Path,
+    buffer: &gltf::Buffer<'_>,
+) -> Result<PathBuf, failure::Error> {
+    use gltf::buffer::Source;
+    match buffer.source() {
+        Source::Uri(uri) if uri.starts_with("data:") => Err(format_err!(
+            "Buffer {} is embedded in a data URI, which isn't supported",
+            buffer.index()
+        )),
+        Source::Uri(uri) => Ok(base_path.join(uri)),
+        Source::Bin => Err(format_err!(
+            "Buffer {} is the binary chunk of a .glb file, which isn't supported",
+            buffer.index()
+        )),
+    }
+}
+
+/// The file of a glTF image. Images in buffer views aren't supported.
+fn gltf_image_path(base_path: &Path, image: &gltf::Image<'_>) -> Result<PathBuf, failure::Error> {
+    match image.source() {
+        gltf::image::Source::View { .. } => Err(format_err!(
+            "Image {} is in a buffer view, which isn't supported",
+            image.index()
+        )),
+        gltf::image::Source::Uri { uri, .. } => Ok(base_path.join(uri)),
+    }
+}
+
+/// Checks that the buffers and images of a glTF file are files `GltfBuffers` and the texture
+/// loader can read, without reading them, so that a scene can be refused before the current
+/// one is unloaded.
+pub fn check_gltf_sources(base_path: &Path, gltf: &gltf::Gltf) -> Result<(), failure::Error> {
+    let metadata = |path: &Path| {
+        std::fs::metadata(path).map_err(|e| format_err!("Can't read {:?}: {}", path, e))
+    };
+    for buffer in gltf.buffers() {
+        let path = gltf_buffer_path(base_path, &buffer)?;
+        if metadata(&path)?.len() < buffer.length() as u64 {
+            failure::bail!("{:?} is shorter than buffer {}", path, buffer.index());
+        }
+    }
+    for image in gltf.images() {
+        metadata(&gltf_image_path(base_path, &image)?)?;
+    }
+    Ok(())
+}
+
 pub struct GltfBuffers(pub Vec<Vec<u8>>);
 
 impl GltfBuffers {
@@ -70,24 +384,15 @@ impl GltfBuffers {
         base_path: P,
         gltf: &gltf::Gltf,
     ) -> Result<Self, failure::Error> {
-        use gltf::buffer::Source;
         let mut buffers = vec![];
-        for (_index, buffer) in gltf.buffers().enumerate() {
-            let data = match buffer.source() {
-                Source::Uri(uri) => {
-                    if uri.starts_with("data:") {
-                        unimplemented!();
-                    } else {
-                        let mut file = File::open(base_path.as_ref().join(uri))?;
-                        let mut data: Vec<u8> = Vec::with_capacity(file.metadata()?.len() as usize);
-                        file.read_to_end(&mut data)?;
-                        data
-                    }
-                }
-                Source::Bin => unimplemented!(),
-            };
-
-            assert!(data.len() >= buffer.length());
+        for buffer in gltf.buffers() {
+            let path = gltf_buffer_path(base_path.as_ref(), &buffer)?;
+            let mut file = File::open(&path)?;
+            let mut data: Vec<u8> = Vec::with_capacity(file.metadata()?.len() as usize);
+            file.read_to_end(&mut data)?;
+            if data.len() < buffer.length() {
+                failure::bail!("{:?} is shorter than buffer {}", path, buffer.index());
+            }
             buffers.push(data);
         }
         Ok(GltfBuffers(buffers))
@@ -109,23 +414,125 @@ impl GltfBuffers {
     }
 }
 
+/// What glTF materials have beyond the core spec, which the `gltf` crate doesn't read: factors
+/// and textures of extensions, and a height texture in their extras.
+#[derive(Debug, Clone, Default)]
+pub struct GltfMaterialExtensions<'a> {
+    /// From `KHR_materials_anisotropy`
+    pub anisotropy_strength: f32,
+    pub anisotropy_rotation: f32,
+    pub anisotropy_texture: Option<gltf::Texture<'a>>,
+    /// From `KHR_materials_clearcoat`
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    /// From `heightTexture` in the material's extras, as there is no extension for it. Height
+    /// in red like in scene materials.
+    pub height_texture: Option<gltf::Texture<'a>>,
+    /// From `heightScale` in the material's extras, the same as a scene material's
+    pub height_scale: f32,
+}
+
+/// Reads the extensions and extras of each material of `gltf`, loaded from `path`, in the
+/// order of its materials, from the JSON directly.
+pub fn load_gltf_material_extensions<'a, P: AsRef<Path>>(
+    path: P,
+    gltf: &'a gltf::Gltf,
+) -> Result<Vec<GltfMaterialExtensions<'a>>, failure::Error> {
+    let path = path.as_ref();
+    let json: serde_json::Value =
+        serde_json::from_reader(std::io::BufReader::new(File::open(path)?))?;
+    let materials = match json
+        .get("materials")
+        .and_then(|materials| materials.as_array())
+    {
+        Some(materials) => materials,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(materials
+        .iter()
+        .map(|material| {
+            let mut extensions = GltfMaterialExtensions::default();
+            let name = material.get("name").and_then(|name| name.as_str()).unwrap_or("");
+            if let Some(anisotropy) = material.pointer("/extensions/KHR_materials_anisotropy") {
+                let factor = |name: &str| anisotropy.get(name).and_then(|value| value.as_f64());
+                extensions.anisotropy_strength = factor("anisotropyStrength").unwrap_or(0.0) as f32;
+                extensions.anisotropy_rotation = factor("anisotropyRotation").unwrap_or(0.0) as f32;
+                if let Some(index) = anisotropy
+                    .pointer("/anisotropyTexture/index")
+                    .and_then(|index| index.as_u64())
+                {
+                    extensions.anisotropy_texture = gltf.textures().nth(index as usize);
+                    if extensions.anisotropy_texture.is_none() {
+                        log::warn!(
+                            "Material {:?} of {:?} has an anisotropy texture which doesn't exist",
+                            name,
+                            path
+                        );
+                    }
+                }
+            }
+            if let Some(clearcoat) = material.pointer("/extensions/KHR_materials_clearcoat") {
+                let factor = |name: &str| clearcoat.get(name).and_then(|value| value.as_f64());
+                extensions.clearcoat = factor("clearcoatFactor").unwrap_or(0.0) as f32;
+                extensions.clearcoat_roughness =
+                    factor("clearcoatRoughnessFactor").unwrap_or(0.0) as f32;
+                let has_texture = [
+                    "clearcoatTexture",
+                    "clearcoatRoughnessTexture",
+                    "clearcoatNormalTexture",
+                ]
+                .iter()
+                .any(|texture| clearcoat.get(texture).is_some());
+                if has_texture {
+                    log::warn!(
+                        "Clearcoat textures are not supported, material {:?} of {:?} only uses its clearcoat factors",
+                        name,
+                        path
+                    );
+                }
+            }
+            if let Some(index) = material
+                .pointer("/extras/heightTexture/index")
+                .and_then(|index| index.as_u64())
+            {
+                extensions.height_texture = gltf.textures().nth(index as usize);
+                if extensions.height_texture.is_none() {
+                    log::warn!(
+                        "Material {:?} of {:?} has a height texture which doesn't exist",
+                        name,
+                        path
+                    );
+                }
+            }
+            extensions.height_scale = material
+                .pointer("/extras/heightScale")
+                .and_then(|scale| scale.as_f64())
+                .unwrap_or(DEFAULT_HEIGHT_SCALE as f64) as f32;
+            extensions
+        })
+        .collect())
+}
+
 pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
     mesh: &gltf::Mesh<'_>,
     max_instances: u16,
     generate_mips: bool,
     base_dir: P,
     buffers: &GltfBuffers,
+    material_extensions: &[GltfMaterialExtensions<'_>],
     base_mesh_index: usize,
     base_material_index: usize,
     material_storage: &mut Vec<Option<MaterialData<B>>>,
-    primitive_storage: &mut Vec<Option<Primitive<B>>>,
+    primitive_storage: &mut Vec<Option<Primitive>>,
     mesh_storage: &mut Vec<Option<Mesh>>,
+    geometry_pool: &mut GeometryPoolBuilder,
     factory: &mut Factory<B>,
-    queue: QueueId,
+    queues: &UploadQueues,
 ) -> Result<MeshHandle, failure::Error> {
     let mesh_idx = base_mesh_index + mesh.index();
     if let Some(_) = mesh_storage[mesh_idx] {
-        Ok(mesh_idx as MeshHandle)
+        Ok(MeshHandle::from_index(mesh_idx))
     } else {
         let mut primitives = Vec::new();
 
@@ -150,10 +557,16 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
             let uvs = reader
                 .read_tex_coords(0)
                 .ok_or(format_err!("Primitive does not have tex coords"))?
-                .into_f32();
+                .into_f32()
+                .collect::<Vec<_>>();
+            // Lightmaps use the second set of tex coords, or the first if there isn't one
+            let lightmap_uvs = match reader.read_tex_coords(1) {
+                Some(uvs) => uvs.into_f32().collect::<Vec<_>>(),
+                None => uvs.clone(),
+            };
 
             let vertices = positions
-                .zip(normals.zip(tangents.zip(uvs)))
+                .zip(normals.zip(tangents.zip(uvs.into_iter())))
                 .map(|(pos, (norm, (tang, uv)))| PosNormTangTex {
                     position: pos.into(),
                     normal: norm.into(),
@@ -162,10 +575,13 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
                 })
                 .collect::<Vec<_>>();
 
-            let prim_mesh = rendy::mesh::Mesh::<Backend>::builder()
-                .with_indices(&indices[..])
-                .with_vertices(&vertices[..])
-                .build(queue, factory)?;
+            let geometry = PrimitiveGeometry {
+                positions: vertices.iter().map(|v| v.position.0).collect(),
+                normals: vertices.iter().map(|v| v.normal.0).collect(),
+                lightmap_uvs,
+                indices,
+            };
+            let range = geometry.add_to_pool(&vertices, geometry_pool);
 
             let material = primitive.material();
             let mat_idx = base_material_index
@@ -175,19 +591,52 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
 
             if let None = material_storage[mat_idx] {
                 let pbr_met_rough = material.pbr_metallic_roughness();
+                let extensions = material
+                    .index()
+                    .and_then(|index| material_extensions.get(index))
+                    .cloned()
+                    .unwrap_or_default();
 
                 let factors = MaterialFactors {
                     albedo: pbr_met_rough.base_color_factor(),
                     metallic: pbr_met_rough.metallic_factor(),
                     roughness: pbr_met_rough.roughness_factor(),
+                    emissive: material.emissive_factor(),
+                    alpha_cutoff: material.alpha_cutoff(),
+                    clearcoat: extensions.clearcoat,
+                    clearcoat_roughness: extensions.clearcoat_roughness,
+                    occlusion_strength: material
+                        .occlusion_texture()
+                        .map(|occlusion| occlusion.strength())
+                        .unwrap_or(1.0),
+                    height_scale: extensions.height_scale,
+                    anisotropy: extensions.anisotropy_strength,
+                    anisotropy_rotation: extensions.anisotropy_rotation,
+                    subsurface: 0.0,
+                    subsurface_color: [0.0; 3],
+                    subsurface_radius: 0.0,
+                    thickness: 1.0,
+                    channels: ChannelLayout::default(),
                 };
 
-                let state = ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
-                    access: hal::image::Access::SHADER_READ,
-                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
-                };
+                let mut features = ShaderFeatures::NONE;
+                if material.emissive_texture().is_some() {
+                    features |= ShaderFeatures::HAS_EMISSIVE;
+                }
+                if let gltf::material::AlphaMode::Mask = material.alpha_mode() {
+                    features |= ShaderFeatures::ALPHA_MASK;
+                }
+                if factors.clearcoat > 0.0 {
+                    features |= ShaderFeatures::HAS_CLEARCOAT;
+                }
+                if extensions.height_texture.is_some() && factors.height_scale > 0.0 {
+                    features |= ShaderFeatures::HAS_HEIGHT;
+                }
+                if factors.anisotropy > 0.0 {
+                    features |= ShaderFeatures::HAS_ANISOTROPY;
+                }
+
+                let state = queues.texture_state(generate_mips);
 
                 let albedo = load_gltf_texture(
                     &base_dir,
@@ -195,20 +644,28 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
                         .base_color_texture()
                         .ok_or(format_err!("Material has no base color texture"))?
                         .texture(),
-                    true,
+                    MaterialTexture::Albedo,
                     generate_mips,
                 )?
                 .build(state, factory)?;
 
-                let metallic_roughness = load_gltf_texture(
-                    &base_dir,
-                    pbr_met_rough
-                        .metallic_roughness_texture()
-                        .ok_or(format_err!("Material has no metallic_roughness texture"))?
-                        .texture(),
-                    false,
-                    generate_mips,
-                )?
+                // Without a texture glTF takes the factors as they are, so they are baked into
+                // one like for materials defined in the scene
+                let metallic_roughness = match pbr_met_rough.metallic_roughness_texture() {
+                    Some(info) => load_gltf_texture(
+                        &base_dir,
+                        info.texture(),
+                        MaterialTexture::MetallicRoughness,
+                        generate_mips,
+                    )?,
+                    None => solid_material_texture(
+                        MaterialTexture::MetallicRoughness,
+                        factors.channels.metallic_roughness_pixel(
+                            to_unorm(factors.metallic),
+                            to_unorm(factors.roughness),
+                        ),
+                    ),
+                }
                 .build(state, factory)?;
 
                 let normal = load_gltf_texture(
@@ -217,39 +674,64 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
                         .normal_texture()
                         .ok_or(format_err!("Material has no normal texture"))?
                         .texture(),
-                    false,
+                    MaterialTexture::Normal,
                     generate_mips,
                 )?
                 .build(state, factory)?;
 
-                let ao = load_gltf_texture(
-                    &base_dir,
-                    material
-                        .occlusion_texture()
-                        .ok_or(format_err!("Material has no occlusion texture"))?
-                        .texture(),
-                    false,
-                    generate_mips,
-                )?
+                let ao = match material.occlusion_texture() {
+                    Some(info) => load_gltf_texture(
+                        &base_dir,
+                        info.texture(),
+                        MaterialTexture::Ao,
+                        generate_mips,
+                    )?,
+                    None => solid_material_texture(MaterialTexture::Ao, [255, 255, 255, 255]),
+                }
                 .build(state, factory)?;
 
-                let emissive = if let Some(emissive_info) = material.emissive_texture() {
-                    load_gltf_texture(&base_dir, emissive_info.texture(), true, generate_mips)?
-                        .build(state, factory)?
-                } else {
-                    rendy::texture::TextureBuilder::new()
-                        .with_data(vec![rendy::texture::pixel::Rgb8Srgb { repr: [0, 0, 0] }])
-                        .with_data_width(1)
-                        .with_data_height(1)
-                        .with_kind(hal::image::Kind::D2(1, 1, 1, 1))
-                        .with_view_kind(hal::image::ViewKind::D2)
-                        .build(state, factory)?
-                };
+                let emissive = match material.emissive_texture() {
+                    Some(info) => load_gltf_texture(
+                        &base_dir,
+                        info.texture(),
+                        MaterialTexture::Emissive,
+                        generate_mips,
+                    )?,
+                    None => solid_material_texture(MaterialTexture::Emissive, [0, 0, 0, 255]),
+                }
+                .build(state, factory)?;
+                let height = match extensions.height_texture {
+                    Some(texture) => load_gltf_texture(
+                        &base_dir,
+                        texture,
+                        MaterialTexture::Height,
+                        generate_mips,
+                    )?,
+                    None => solid_material_texture(MaterialTexture::Height, [255, 255, 255, 255]),
+                }
+                .build(state, factory)?;
+                // Scaled by the anisotropy strength and rotated by its rotation in the shader
+                let anisotropy = match extensions.anisotropy_texture {
+                    Some(texture) => load_gltf_texture(
+                        &base_dir,
+                        texture,
+                        MaterialTexture::Anisotropy,
+                        generate_mips,
+                    )?,
+                    None => solid_material_texture(MaterialTexture::Anisotropy, ANISOTROPY_TEXEL),
+                }
+                .build(state, factory)?;
+                // glTF has no bent normal or thickness maps
+                let bent_normal =
+                    solid_material_texture(MaterialTexture::BentNormal, [128, 128, 255, 255])
+                        .build(state, factory)?;
+                let thickness =
+                    solid_material_texture(MaterialTexture::Thickness, [255, 255, 255, 255])
+                        .build(state, factory)?;
 
-                let emissive_factor = material.emissive_factor();
-                let emissive_factor_buffer = factory.create_buffer(
+                let uniform_buffer = factory.create_buffer(
                     BufferInfo {
-                        size: std::mem::size_of::<[f32; 3]>() as u64,
+                        size: std::mem::size_of::<MaterialUniform>() as u64,
                         usage: hal::buffer::Usage::UNIFORM | hal::buffer::Usage::TRANSFER_DST,
                     },
                     MemoryUsageValue::Data,
@@ -257,73 +739,720 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
 
                 unsafe {
                     factory.upload_buffer(
-                        &emissive_factor_buffer,
+                        &uniform_buffer,
                         0,
-                        &emissive_factor,
+                        &[MaterialUniform::from(&factors)],
                         None,
-                        BufferState {
-                            queue,
-                            stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
-                            access: hal::buffer::Access::SHADER_READ,
-                        },
+                        queues.uniform_state(),
                     )?;
                 }
 
                 material_storage[mat_idx] = Some(MaterialData {
                     factors,
+                    features,
                     albedo,
                     metallic_roughness,
                     normal,
                     ao,
                     emissive,
-                    emissive_factor_buffer,
+                    bent_normal,
+                    height,
+                    thickness,
+                    anisotropy,
+                    uniform_buffer,
                 });
             }
 
             primitive_storage.push(Some(Primitive {
-                mesh_data: prim_mesh,
-                mesh_handle: mesh_idx,
-                mat: mat_idx as MaterialHandle,
+                range,
+                geometry,
+                mesh_handle: MeshHandle::from_index(mesh_idx),
+                mat: MaterialHandle::from_index(mat_idx),
             }));
 
-            primitives.push(primitive_storage.len() - 1);
+            primitives.push(PrimitiveHandle::from_index(primitive_storage.len() - 1));
         }
 
+        let bounding_radius = primitives
+            .iter()
+            .map(|&p| {
+                primitive_storage[p.index()]
+                    .as_ref()
+                    .unwrap()
+                    .geometry
+                    .bounding_radius()
+            })
+            .fold(0.0, f32::max);
+
         mesh_storage[mesh_idx] = Some(Mesh {
+            name: mesh
+                .name()
+                .map(String::from)
+                .unwrap_or_else(|| format!("Mesh {}", mesh.index())),
             primitives,
             max_instances,
+            lightmap: None,
+            bounding_radius,
+            lods: Vec::new(),
+            lod_of: None,
         });
 
-        Ok(mesh_idx as MeshHandle)
+        Ok(MeshHandle::from_index(mesh_idx))
+    }
+}
+
+/// Material factors for meshes loaded from files which don't carry glTF materials. The
+/// factors are baked into 1x1 textures where there is no texture, as the PBR shader reads
+/// albedo, metallic and roughness from textures only.
+#[derive(Debug, Clone, Copy, Derivative, serde::Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct InlineMaterial {
+    /// Linear base color and alpha
+    #[derivative(Default(value = "[1.0, 1.0, 1.0, 1.0]"))]
+    pub albedo: [f32; 4],
+    pub metallic: f32,
+    #[derivative(Default(value = "0.5"))]
+    pub roughness: f32,
+    /// Multiplies the emissive texture, if there is one
+    pub emissive: [f32; 3],
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    /// Depth of the height texture's range in texture coordinates, if there is one
+    #[derivative(Default(value = "DEFAULT_HEIGHT_SCALE"))]
+    pub height_scale: f32,
+    /// Stretches the specular highlights along the tangent rotated by `anisotropy_rotation`,
+    /// like on brushed metal
+    pub anisotropy: f32,
+    pub anisotropy_rotation: f32,
+    /// Wraps diffuse light past the terminator and blurs it under the surface, like on skin
+    /// or wax
+    pub subsurface: f32,
+    #[derivative(Default(value = "[1.0, 0.4, 0.25]"))]
+    pub subsurface_color: [f32; 3],
+    /// How far light is blurred under the surface, in world units
+    #[derivative(Default(value = "0.01"))]
+    pub subsurface_radius: f32,
+    /// Multiplies the thickness texture, or is the thickness without one
+    #[derivative(Default(value = "1.0"))]
+    pub thickness: f32,
+}
+
+/// Image files used by a material defined in the scene file, relative to the application
+/// root. A texture replaces the matching factor rather than being scaled by it, except
+/// for the emissive, thickness and anisotropy textures.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct MaterialTextures {
+    pub albedo: Option<String>,
+    /// Roughness in green and metallic in blue, as in glTF, unless `channels` says otherwise
+    pub metallic_roughness: Option<String>,
+    pub normal: Option<String>,
+    pub ao: Option<String>,
+    pub emissive: Option<String>,
+    /// Tangent space direction of least occlusion, encoded like the normal map, which is
+    /// used for specular occlusion
+    pub bent_normal: Option<String>,
+    /// Height in red, white being the top of the surface, for parallax occlusion mapping
+    pub height: Option<String>,
+    /// Thickness in red for subsurface scattering, light passes through where it's dark
+    pub thickness: Option<String>,
+    /// Direction of anisotropy in tangent space in red and green, encoded like the normal
+    /// map, and strength in blue, as in `KHR_materials_anisotropy`
+    pub anisotropy: Option<String>,
+    /// Where metallic and roughness are in `metallic_roughness`, and occlusion in `ao`, for
+    /// textures packed differently than glTF's
+    pub channels: ChannelLayout,
+}
+
+/// Builds a material from factors and optional texture files, sampled with repeat
+/// wrapping so they can tile.
+pub fn build_material<B: hal::Backend>(
+    factors: &InlineMaterial,
+    textures: &MaterialTextures,
+    generate_mips: bool,
+    factory: &mut Factory<B>,
+    queues: &UploadQueues,
+) -> Result<MaterialData<B>, failure::Error> {
+    let factors = MaterialFactors {
+        albedo: factors.albedo,
+        metallic: factors.metallic,
+        roughness: factors.roughness,
+        emissive: factors.emissive,
+        alpha_cutoff: 0.5,
+        clearcoat: factors.clearcoat,
+        clearcoat_roughness: factors.clearcoat_roughness,
+        occlusion_strength: 1.0,
+        height_scale: factors.height_scale,
+        anisotropy: factors.anisotropy,
+        anisotropy_rotation: factors.anisotropy_rotation,
+        subsurface: factors.subsurface,
+        subsurface_color: factors.subsurface_color,
+        subsurface_radius: factors.subsurface_radius,
+        thickness: factors.thickness,
+        channels: textures.channels,
+    };
+
+    let mut features = ShaderFeatures::NONE;
+    if factors.emissive.iter().any(|&c| c > 0.0) {
+        features |= ShaderFeatures::HAS_EMISSIVE;
+    }
+    if factors.clearcoat > 0.0 {
+        features |= ShaderFeatures::HAS_CLEARCOAT;
+    }
+    if textures.bent_normal.is_some() {
+        features |= ShaderFeatures::HAS_BENT_NORMAL;
+    }
+    if textures.height.is_some() && factors.height_scale > 0.0 {
+        features |= ShaderFeatures::HAS_HEIGHT;
+    }
+    if factors.anisotropy > 0.0 {
+        features |= ShaderFeatures::HAS_ANISOTROPY;
+    }
+    if factors.subsurface > 0.0 {
+        features |= ShaderFeatures::HAS_SUBSURFACE;
+    }
+
+    let state = queues.texture_state(generate_mips);
+    // Missing files are replaced with a solid fallback texel, given as stored
+    let mut texture = |path: &Option<String>,
+                       slot: MaterialTexture,
+                       fallback: [u8; 4]|
+     -> Result<Texture<B>, failure::Error> {
+        let builder = match path {
+            Some(path) => load_texture_file(
+                Path::new(&crate::application_root_dir()).join(path),
+                slot.repr(),
+                generate_mips,
+            )?,
+            None => solid_material_texture(slot, fallback),
+        };
+        Ok(builder.build(state, factory)?)
+    };
+
+    // The linear albedo factor is encoded, since the texture is decoded when sampled
+    let albedo = texture(
+        &textures.albedo,
+        MaterialTexture::Albedo,
+        [
+            to_unorm(linear_to_srgb(factors.albedo[0])),
+            to_unorm(linear_to_srgb(factors.albedo[1])),
+            to_unorm(linear_to_srgb(factors.albedo[2])),
+            to_unorm(factors.albedo[3]),
+        ],
+    )?;
+    let metallic_roughness = texture(
+        &textures.metallic_roughness,
+        MaterialTexture::MetallicRoughness,
+        factors
+            .channels
+            .metallic_roughness_pixel(to_unorm(factors.metallic), to_unorm(factors.roughness)),
+    )?;
+    let normal = texture(
+        &textures.normal,
+        MaterialTexture::Normal,
+        [128, 128, 255, 255],
+    )?;
+    let ao = texture(&textures.ao, MaterialTexture::Ao, [255, 255, 255, 255])?;
+    // Scaled by the emissive factor in the shader
+    let emissive = texture(
+        &textures.emissive,
+        MaterialTexture::Emissive,
+        [255, 255, 255, 255],
+    )?;
+    let bent_normal = texture(
+        &textures.bent_normal,
+        MaterialTexture::BentNormal,
+        [128, 128, 255, 255],
+    )?;
+    let height = texture(
+        &textures.height,
+        MaterialTexture::Height,
+        [255, 255, 255, 255],
+    )?;
+    // Scaled by the thickness factor in the shader
+    let thickness = texture(
+        &textures.thickness,
+        MaterialTexture::Thickness,
+        [255, 255, 255, 255],
+    )?;
+    // Scaled by the anisotropy strength and rotated by its rotation in the shader
+    let anisotropy = texture(
+        &textures.anisotropy,
+        MaterialTexture::Anisotropy,
+        ANISOTROPY_TEXEL,
+    )?;
+
+    let uniform_buffer = factory.create_buffer(
+        BufferInfo {
+            size: std::mem::size_of::<MaterialUniform>() as u64,
+            usage: hal::buffer::Usage::UNIFORM | hal::buffer::Usage::TRANSFER_DST,
+        },
+        MemoryUsageValue::Data,
+    )?;
+
+    unsafe {
+        factory.upload_buffer(
+            &uniform_buffer,
+            0,
+            &[MaterialUniform::from(&factors)],
+            None,
+            queues.uniform_state(),
+        )?;
+    }
+
+    Ok(MaterialData {
+        factors,
+        features,
+        albedo,
+        metallic_roughness,
+        normal,
+        ao,
+        emissive,
+        bent_normal,
+        height,
+        thickness,
+        anisotropy,
+        uniform_buffer,
+    })
+}
+
+/// Loads an OBJ or PLY mesh as a single primitive with the given material, adding it to
+/// the storages. Normals and tangents are generated if the file doesn't have them.
+pub fn load_mesh_file<P: AsRef<Path>>(
+    path: P,
+    material: MaterialHandle,
+    max_instances: u16,
+    primitive_storage: &mut Vec<Option<Primitive>>,
+    mesh_storage: &mut Vec<Option<Mesh>>,
+    geometry_pool: &mut GeometryPoolBuilder,
+) -> Result<MeshHandle, failure::Error> {
+    log::info!("Loading mesh: {:#?}", path.as_ref());
+    let name = path
+        .as_ref()
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    add_mesh_data(
+        crate::mesh_file::load(path)?,
+        name,
+        material,
+        max_instances,
+        primitive_storage,
+        mesh_storage,
+        geometry_pool,
+    )
+}
+
+/// Adds mesh data read from a file or generated as a single primitive with the given
+/// material, filling in missing normals and tangents.
+pub fn add_mesh_data(
+    mesh: crate::mesh_file::MeshData,
+    name: String,
+    material: MaterialHandle,
+    max_instances: u16,
+    primitive_storage: &mut Vec<Option<Primitive>>,
+    mesh_storage: &mut Vec<Option<Mesh>>,
+    geometry_pool: &mut GeometryPoolBuilder,
+) -> Result<MeshHandle, failure::Error> {
+    let (vertices, indices) = mesh.into_vertices();
+    let lightmap_uvs = vertices.iter().map(|v| v.tex_coord.0).collect();
+    Ok(add_primitive_mesh(
+        &vertices,
+        lightmap_uvs,
+        indices,
+        name,
+        material,
+        max_instances,
+        primitive_storage,
+        mesh_storage,
+        geometry_pool,
+    ))
+}
+
+/// Adds a mesh of a single primitive with the given vertices and material.
+pub fn add_primitive_mesh(
+    vertices: &[PosNormTangTex],
+    lightmap_uvs: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+    name: String,
+    material: MaterialHandle,
+    max_instances: u16,
+    primitive_storage: &mut Vec<Option<Primitive>>,
+    mesh_storage: &mut Vec<Option<Mesh>>,
+    geometry_pool: &mut GeometryPoolBuilder,
+) -> MeshHandle {
+    let geometry = PrimitiveGeometry {
+        positions: vertices.iter().map(|v| v.position.0).collect(),
+        normals: vertices.iter().map(|v| v.normal.0).collect(),
+        lightmap_uvs,
+        indices,
+    };
+    let range = geometry.add_to_pool(vertices, geometry_pool);
+    let bounding_radius = geometry.bounding_radius();
+
+    let mesh_handle = MeshHandle::from_index(mesh_storage.len());
+    primitive_storage.push(Some(Primitive {
+        range,
+        geometry,
+        mesh_handle,
+        mat: material,
+    }));
+    mesh_storage.push(Some(Mesh {
+        name,
+        primitives: vec![PrimitiveHandle::from_index(primitive_storage.len() - 1)],
+        max_instances,
+        lightmap: None,
+        bounding_radius,
+        lods: Vec::new(),
+        lod_of: None,
+    }));
+
+    mesh_handle
+}
+
+/// Adds a copy of a mesh whose primitives all use `material`, sharing their vertices and
+/// indices in the pool. The copy has neither levels of detail nor a lightmap.
+pub fn add_material_variant(
+    mesh: MeshHandle,
+    material: MaterialHandle,
+    primitive_storage: &mut Vec<Option<Primitive>>,
+    mesh_storage: &mut Vec<Option<Mesh>>,
+) -> MeshHandle {
+    let variant_handle = MeshHandle::from_index(mesh_storage.len());
+    let source = mesh_storage[mesh.index()]
+        .as_ref()
+        .expect("Meshes are loaded before their variants");
+    let mut primitives = Vec::with_capacity(source.primitives.len());
+    for primitive in source.primitives.iter() {
+        let primitive = primitive_storage[primitive.index()]
+            .as_ref()
+            .expect("Primitives of loaded meshes are loaded");
+        let variant = Primitive {
+            range: primitive.range,
+            geometry: primitive.geometry.clone(),
+            mesh_handle: variant_handle,
+            mat: material,
+        };
+        primitive_storage.push(Some(variant));
+        primitives.push(PrimitiveHandle::from_index(primitive_storage.len() - 1));
+    }
+    let variant = Mesh {
+        name: format!("{} ({})", source.name, material.index()),
+        primitives,
+        max_instances: source.max_instances,
+        lightmap: None,
+        bounding_radius: source.bounding_radius,
+        lods: Vec::new(),
+        lod_of: None,
+    };
+    mesh_storage.push(Some(variant));
+    variant_handle
+}
+
+/// An 8 bit unorm channel holding `c`, clamped to [0, 1].
+fn to_unorm(c: f32) -> u8 {
+    (c.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+/// A 1x1 texture of `slot` holding `texel`, which is sRGB encoded if the slot's files are.
+fn solid_material_texture(slot: MaterialTexture, texel: [u8; 4]) -> TextureBuilder<'static> {
+    if slot.is_srgb() {
+        solid_texture(rendy::texture::pixel::Rgba8Srgb { repr: texel })
+    } else {
+        solid_texture(rendy::texture::pixel::Rgba8Unorm { repr: texel })
+    }
+}
+
+fn solid_texture<P: rendy::texture::pixel::AsPixel>(pixel: P) -> TextureBuilder<'static> {
+    TextureBuilder::new()
+        .with_data(vec![pixel])
+        .with_data_width(1)
+        .with_data_height(1)
+        .with_kind(hal::image::Kind::D2(1, 1, 1, 1))
+        .with_view_kind(hal::image::ViewKind::D2)
+}
+
+fn load_texture_file<P: AsRef<Path>>(
+    path: P,
+    repr: Repr,
+    generate_mips: bool,
+) -> Result<TextureBuilder<'static>, failure::Error> {
+    log::info!("Loading image: {:#?}", path.as_ref());
+    rendy::texture::image::load_from_image(
+        std::io::BufReader::new(File::open(path)?),
+        ImageTextureConfig {
+            repr,
+            generate_mips,
+            sampler_info: hal::image::SamplerDesc::new(
+                hal::image::Filter::Linear,
+                hal::image::WrapMode::Tile,
+            ),
+            ..Default::default()
+        },
+    )
+    .map_err(|e| e.into())
+}
+
+/// Loads a lightmap from an OpenEXR image, or any float or 8 bit image the `image` crate
+/// reads, which is taken as linear.
+pub fn load_lightmap<P: AsRef<Path>>(path: P) -> Result<TextureBuilder<'static>, failure::Error> {
+    if is_exr(&path) {
+        let (width, height, texels) = load_exr(path)?;
+        Ok(TextureBuilder::new()
+            .with_kind(hal::image::Kind::D2(width, height, 1, 1))
+            .with_view_kind(hal::image::ViewKind::D2)
+            .with_data_width(width)
+            .with_data_height(height)
+            .with_data(texels))
+    } else {
+        Ok(
+            load_texture_file(path, Repr::Unorm, false)?.with_sampler_info(
+                hal::image::SamplerDesc::new(
+                    hal::image::Filter::Linear,
+                    hal::image::WrapMode::Clamp,
+                ),
+            ),
+        )
+    }
+}
+
+/// Whether a path has the `.exr` extension of OpenEXR images.
+pub fn is_exr<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("exr"))
+        .unwrap_or(false)
+}
+
+/// Loads the first RGBA layer of an OpenEXR image as linear float texels in rows from the
+/// top. Images without an alpha channel get an alpha of 1.
+pub fn load_exr<P: AsRef<Path>>(
+    path: P,
+) -> Result<(u32, u32, Vec<rendy::texture::pixel::Rgba32Sfloat>), failure::Error> {
+    log::info!("Loading image: {:#?}", path.as_ref());
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| {
+            (
+                resolution.width(),
+                vec![
+                    rendy::texture::pixel::Rgba32Sfloat { repr: [0.0; 4] };
+                    resolution.width() * resolution.height()
+                ],
+            )
+        },
+        |(width, texels), position, (r, g, b, a): (f32, f32, f32, f32)| {
+            texels[position.y() * *width + position.x()].repr = [r, g, b, a];
+        },
+    )?;
+    let size = image.layer_data.size;
+    let (_, texels) = image.layer_data.channel_data.pixels;
+    Ok((size.width() as u32, size.height() as u32, texels))
+}
+
+/// Writes linear float texels, in rows from the top, to an RGBA OpenEXR image.
+pub fn save_exr<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    texels: &[[f32; 4]],
+) -> Result<(), failure::Error> {
+    if texels.len() != (width * height) as usize {
+        failure::bail!(
+            "{} texels don't make a {}x{} image",
+            texels.len(),
+            width,
+            height
+        );
+    }
+    exr::prelude::write_rgba_file(path, width as usize, height as usize, |x, y| {
+        let texel = texels[y * width as usize + x];
+        (texel[0], texel[1], texel[2], texel[3])
+    })?;
+    Ok(())
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
     }
 }
 
 fn load_gltf_texture<P>(
     base_dir: P,
     texture: gltf::Texture<'_>,
-    srgb: bool,
+    slot: MaterialTexture,
     generate_mips: bool,
 ) -> Result<TextureBuilder<'static>, failure::Error>
 where
     P: AsRef<Path>,
 {
-    match texture.source().source() {
-        gltf::image::Source::View { .. } => unimplemented!(),
-        gltf::image::Source::Uri { uri, .. } => {
-            let path = base_dir.as_ref().join(uri);
-            log::info!("Loading image: {:#?}", path);
-            rendy::texture::image::load_from_image(
-                std::io::BufReader::new(File::open(path)?),
-                ImageTextureConfig {
-                    repr: match srgb {
-                        true => Repr::Srgb,
-                        false => Repr::Unorm,
-                    },
-                    generate_mips,
-                    ..Default::default()
-                },
-            )
-            .map_err(|e| e.into())
+    let path = gltf_image_path(base_dir.as_ref(), &texture.source())?;
+    log::info!("Loading image: {:#?}", path);
+    rendy::texture::image::load_from_image(
+        std::io::BufReader::new(File::open(path)?),
+        ImageTextureConfig {
+            repr: slot.repr(),
+            generate_mips,
+            sampler_info: gltf_sampler_info(&texture.sampler()),
+            ..Default::default()
+        },
+    )
+    .map_err(|e| e.into())
+}
+
+/// Convert glTF sampler settings to a sampler description. Identical descriptions share
+/// the same sampler, as the factory caches them. Filters the asset leaves unspecified
+/// default to linear.
+fn gltf_sampler_info(sampler: &gltf::texture::Sampler<'_>) -> hal::image::SamplerDesc {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    use hal::image::{Filter, WrapMode};
+
+    let wrap_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => WrapMode::Clamp,
+        WrappingMode::MirroredRepeat => WrapMode::Mirror,
+        WrappingMode::Repeat => WrapMode::Tile,
+    };
+
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => Filter::Nearest,
+        Some(MagFilter::Linear) | None => Filter::Linear,
+    };
+
+    let (min_filter, mip_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) | Some(MinFilter::NearestMipmapNearest) => {
+            (Filter::Nearest, Filter::Nearest)
+        }
+        Some(MinFilter::NearestMipmapLinear) => (Filter::Nearest, Filter::Linear),
+        Some(MinFilter::LinearMipmapNearest) => (Filter::Linear, Filter::Nearest),
+        Some(MinFilter::Linear) | Some(MinFilter::LinearMipmapLinear) | None => {
+            (Filter::Linear, Filter::Linear)
+        }
+    };
+
+    let mut info = hal::image::SamplerDesc::new(mag_filter, WrapMode::Tile);
+    info.min_filter = min_filter;
+    info.mip_filter = mip_filter;
+    info.wrap_mode = (
+        wrap_mode(sampler.wrap_s()),
+        wrap_mode(sampler.wrap_t()),
+        WrapMode::Tile,
+    );
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_color_textures_are_srgb() {
+        assert!(MaterialTexture::Albedo.is_srgb());
+        assert!(MaterialTexture::Emissive.is_srgb());
+
+        for slot in [
+            MaterialTexture::Normal,
+            MaterialTexture::MetallicRoughness,
+            MaterialTexture::Ao,
+            MaterialTexture::BentNormal,
+            MaterialTexture::Height,
+            MaterialTexture::Thickness,
+            MaterialTexture::Anisotropy,
+        ]
+        .iter()
+        {
+            assert!(!slot.is_srgb(), "{:?} holds linear data", slot);
+        }
+    }
+
+    const SLOTS: [MaterialTexture; 9] = [
+        MaterialTexture::Albedo,
+        MaterialTexture::Normal,
+        MaterialTexture::MetallicRoughness,
+        MaterialTexture::Ao,
+        MaterialTexture::Emissive,
+        MaterialTexture::BentNormal,
+        MaterialTexture::Height,
+        MaterialTexture::Thickness,
+        MaterialTexture::Anisotropy,
+    ];
+
+    fn has_format(builder: &TextureBuilder<'_>, format: hal::format::Format) -> bool {
+        format!("{:?}", builder).contains(&format!("format: {:?}", format))
+    }
+
+    fn expected_format(slot: MaterialTexture) -> hal::format::Format {
+        match slot {
+            MaterialTexture::Albedo | MaterialTexture::Emissive => hal::format::Format::Rgba8Srgb,
+            _ => hal::format::Format::Rgba8Unorm,
+        }
+    }
+
+    #[test]
+    fn factors_are_clamped_and_rounded_to_unorm() {
+        assert_eq!(to_unorm(0.0), 0);
+        assert_eq!(to_unorm(1.0), 255);
+        assert_eq!(to_unorm(0.5), 128);
+        assert_eq!(to_unorm(0.2), 51);
+        assert_eq!(to_unorm(-1.0), 0);
+        assert_eq!(to_unorm(2.0), 255);
+    }
+
+    #[test]
+    fn albedo_factor_is_stored_srgb_encoded() {
+        assert_eq!(to_unorm(linear_to_srgb(0.0)), 0);
+        assert_eq!(to_unorm(linear_to_srgb(1.0)), 255);
+        assert_eq!(to_unorm(linear_to_srgb(0.5)), 188);
+        // Below the linear segment's end
+        assert_eq!(to_unorm(linear_to_srgb(0.002)), 7);
+    }
+
+    #[test]
+    fn metallic_roughness_are_packed_in_their_channels() {
+        assert_eq!(
+            ChannelLayout::default().metallic_roughness_pixel(10, 20),
+            [255, 20, 10, 255]
+        );
+
+        let layout = ChannelLayout {
+            metallic: Channel::R,
+            roughness: Channel::A,
+            ao: Channel::G,
+        };
+        assert_eq!(layout.metallic_roughness_pixel(10, 20), [10, 255, 255, 20]);
+    }
+
+    #[test]
+    fn solid_textures_have_their_slots_format() {
+        for &slot in SLOTS.iter() {
+            let builder = solid_material_texture(slot, [255; 4]);
+            assert!(has_format(&builder, expected_format(slot)), "{:?}", slot);
+        }
+    }
+
+    #[test]
+    fn gltf_textures_are_read_in_their_slots_format() {
+        let dir = std::env::temp_dir().join("rendy-pbr-asset-tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbaImage::new(2, 2)
+            .save(dir.join("texel.png"))
+            .unwrap();
+        let gltf = gltf::Gltf::from_slice(
+            br#"{
+                "asset": { "version": "2.0" },
+                "images": [{ "uri": "texel.png" }],
+                "textures": [{ "source": 0 }]
+            }"#,
+        )
+        .unwrap();
+
+        for &slot in SLOTS.iter() {
+            let texture = gltf.textures().next().unwrap();
+            let builder = load_gltf_texture(&dir, texture, slot, false).unwrap();
+            assert!(has_format(&builder, expected_format(slot)), "{:?}", slot);
         }
     }
 }
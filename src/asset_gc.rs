@@ -0,0 +1,166 @@
+//! Unloading of the meshes and materials no entity uses anymore, for long sessions which
+//! replace what's in the scene. Nothing is unloaded on its own: setting `Aux::collect_assets`,
+//! e.g. with **F4**, has `AssetGcSystem` unload every mesh without an entity on its next run,
+//! then every material no primitive is left using.
+//!
+//! Meshes are referenced by the entities `InstanceCacheUpdateSystem` gave an instance of them,
+//! and a mesh and its levels of detail count as one, since entities switch between them as
+//! they move. The mesh of the instance array is kept regardless, since its entities are made
+//! at runtime.
+//!
+//! The textures and uniform buffers of unloaded materials, and the lightmaps of unloaded
+//! meshes, are kept until `AssetGc::release` drops them once the frames in flight are done
+//! with them, after which the graph is rebuilt to stop binding them. The graph isn't run in
+//! the frame they're unloaded in, since it's sized for the meshes it was built with. Vertices
+//! and indices stay in the geometry pool, whose buffers every primitive shares, until it's
+//! built again.
+use crate::{
+    asset,
+    node::pbr::Aux,
+    systems::{InstanceArray, InstanceCache},
+};
+use rendy::{hal, texture::Texture};
+use specs::prelude::*;
+
+use std::collections::HashSet;
+
+pub struct AssetGc<B: hal::Backend> {
+    /// Whether assets were unloaded since the last `release`
+    unloaded: bool,
+    released_materials: Vec<asset::MaterialData<B>>,
+    released_lightmaps: Vec<Texture<B>>,
+}
+
+impl<B: hal::Backend> Default for AssetGc<B> {
+    fn default() -> Self {
+        AssetGc {
+            unloaded: false,
+            released_materials: Vec::new(),
+            released_lightmaps: Vec::new(),
+        }
+    }
+}
+
+impl<B: hal::Backend> AssetGc<B> {
+    /// Whether assets were unloaded since the last `release`, which graphs still draw.
+    pub fn unloaded(&self) -> bool {
+        self.unloaded
+    }
+
+    /// Drops the GPU resources of the unloaded assets, returning them to the factory. Frames
+    /// in flight must be done with them, and graphs rebuilt after, since they still bind them.
+    pub fn release(&mut self) {
+        self.unloaded = false;
+        log::info!(
+            "Released {} materials and {} lightmaps",
+            self.released_materials.len(),
+            self.released_lightmaps.len()
+        );
+        self.released_materials.clear();
+        self.released_lightmaps.clear();
+    }
+}
+
+/// The meshes whose group of levels of detail has entities or is the instance array's, by the
+/// mesh the levels are of.
+fn referenced_meshes(
+    mesh_storage: &asset::MeshStorage,
+    cache: &InstanceCache,
+    instance_array: &InstanceArray,
+) -> HashSet<asset::MeshHandle> {
+    mesh_storage
+        .0
+        .iter()
+        .filter(|(handle, _)| {
+            cache.mesh_instance_counts[handle.index()] > 0 || instance_array.mesh == Some(*handle)
+        })
+        .map(|(handle, mesh)| mesh.lod_of.unwrap_or(handle))
+        .collect()
+}
+
+/// Unloads the meshes and materials without references when `Aux::collect_assets` is set.
+/// Runs after `InstanceCacheUpdateSystem`, for the instances to count the entities which just
+/// changed meshes.
+pub struct AssetGcSystem<B>(pub core::marker::PhantomData<B>);
+
+impl<'a, B: hal::Backend> System<'a> for AssetGcSystem<B> {
+    type SystemData = (
+        Write<'a, Aux>,
+        WriteExpect<'a, AssetGc<B>>,
+        Read<'a, InstanceCache>,
+        Read<'a, InstanceArray>,
+        Write<'a, asset::MeshStorage>,
+        Write<'a, asset::PrimitiveStorage>,
+        Write<'a, asset::MaterialStorage<B>>,
+        WriteExpect<'a, asset::LightmapStorage<B>>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            mut aux,
+            mut gc,
+            cache,
+            instance_array,
+            mut mesh_storage,
+            mut primitive_storage,
+            mut material_storage,
+            mut lightmap_storage,
+        ): Self::SystemData,
+    ) {
+        if !std::mem::replace(&mut aux.collect_assets, false) {
+            return;
+        }
+        let _scope = crate::profile::scope("AssetGcSystem");
+
+        let referenced = referenced_meshes(&mesh_storage, &cache, &instance_array);
+        let unreferenced = mesh_storage
+            .0
+            .iter()
+            .filter(|(handle, mesh)| !referenced.contains(&mesh.lod_of.unwrap_or(*handle)))
+            .map(|(handle, _)| handle)
+            .collect::<Vec<_>>();
+        let mut primitives = 0;
+        for handle in unreferenced.iter() {
+            let mesh = mesh_storage
+                .0
+                .remove(*handle)
+                .expect("Unreferenced meshes are in the storage");
+            for primitive in mesh.primitives {
+                primitive_storage.0.remove(primitive);
+                primitives += 1;
+            }
+            let lightmap = mesh
+                .lightmap
+                .and_then(|lightmap| lightmap_storage.lightmaps[lightmap].texture.take());
+            gc.released_lightmaps.extend(lightmap);
+        }
+
+        let used_materials = primitive_storage
+            .0
+            .values()
+            .map(|primitive| primitive.mat)
+            .collect::<HashSet<_>>();
+        let unused_materials = material_storage
+            .0
+            .iter()
+            .map(|(handle, _)| handle)
+            .filter(|handle| !used_materials.contains(handle))
+            .collect::<Vec<_>>();
+        for handle in unused_materials.iter() {
+            let material = material_storage
+                .0
+                .remove(*handle)
+                .expect("Unused materials are in the storage");
+            gc.released_materials.push(material);
+        }
+
+        gc.unloaded |= !unreferenced.is_empty() || !unused_materials.is_empty();
+        log::info!(
+            "Unloaded {} meshes, {} primitives and {} materials without references",
+            unreferenced.len(),
+            primitives,
+            unused_materials.len()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Command line arguments, which override parts of the scene config so scenes and settings
+//! can be switched without editing files.
+use clap::{App, Arg};
+
+use crate::scene::{EnvironmentSource, Quality, SceneConfig};
+
+pub struct Args {
+    /// Path of the scene config, relative to the application root unless absolute
+    pub scene: String,
+    /// The scene configs given after the first, to switch to at runtime, see
+    /// `crate::scene_manager`
+    pub more_scenes: Vec<String>,
+    pub environment: Option<EnvironmentSource>,
+    pub filter_quality: Option<Quality>,
+    pub window_size: Option<(f64, f64)>,
+    pub backend: Option<rendy::core::EnabledBackend>,
+    /// `None` keeps the presentation mode rendy prefers
+    pub vsync: Option<bool>,
+    /// Bake the scene's lightmaps at load, replacing their files
+    pub bake_lightmaps: bool,
+    /// Write the input events of the run to this file
+    pub record: Option<String>,
+    /// Replay the input events recorded in this file
+    pub replay: Option<String>,
+    /// Replay without showing the window, and exit once the replay is done
+    pub headless: bool,
+    /// Write descriptions of the render graphs to this file, as DOT or JSON
+    pub dump_graph: Option<String>,
+    /// Time CPU scopes, and write them to this file as a Chrome trace when closing
+    pub profile: Option<String>,
+    /// Run the white furnace test in its own scene, then exit, see `crate::furnace`
+    pub white_furnace: bool,
+    /// Compare the scene against its golden image, or replace it, then exit, see `crate::golden`
+    pub golden: Option<crate::golden::Mode>,
+    /// Run a frame in each configuration of the render graph, then exit, see `crate::smoke`
+    pub smoke: bool,
+    /// Abort on the first validation error, see `crate::validation`
+    pub abort_on_validation_error: bool,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self, failure::Error> {
+        let matches = App::new("rendy-pbr")
+            .about("A realtime physically-based renderer")
+            .arg(
+                Arg::with_name("scene")
+                    .long("scene")
+                    .value_name("PATH")
+                    .default_value("assets/scene.ron")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("The scene config to load. Give it more than once to switch between the scenes with F3"),
+            )
+            .arg(
+                Arg::with_name("environment")
+                    .long("environment")
+                    .value_name("PATH")
+                    .help("An equirectangular .hdr or .exr image, or a .ktx cube map to use as the environment"),
+            )
+            .arg(
+                Arg::with_name("filter-quality")
+                    .long("filter-quality")
+                    .value_name("QUALITY")
+                    .possible_values(&["low", "medium", "high"])
+                    .help("Filter the environment once at this quality, instead of the scene's schedule"),
+            )
+            .arg(
+                Arg::with_name("window-size")
+                    .long("window-size")
+                    .value_name("WIDTHxHEIGHT")
+                    .help("Initial size of the window, in logical pixels"),
+            )
+            .arg(
+                Arg::with_name("backend")
+                    .long("backend")
+                    .value_name("BACKEND")
+                    .help("The graphics backend to use, out of the ones compiled in"),
+            )
+            .arg(
+                Arg::with_name("vsync")
+                    .long("vsync")
+                    .value_name("on|off")
+                    .possible_values(&["on", "off"])
+                    .help("Wait for vertical sync when presenting, or present immediately"),
+            )
+            .arg(
+                Arg::with_name("bake-lightmaps")
+                    .long("bake-lightmaps")
+                    .help("Bake the lightmaps of the scene at load and write them to their files"),
+            )
+            .arg(
+                Arg::with_name("record")
+                    .long("record")
+                    .value_name("PATH")
+                    .conflicts_with("replay")
+                    .help("Record the input events of this run to a file, to replay them later"),
+            )
+            .arg(
+                Arg::with_name("replay")
+                    .long("replay")
+                    .value_name("PATH")
+                    .help("Replay recorded input events, starting from the recorded scene and settings"),
+            )
+            .arg(
+                Arg::with_name("headless")
+                    .long("headless")
+                    .requires("replay")
+                    .help("Replay with a hidden window, then print the final state and exit"),
+            )
+            .arg(
+                Arg::with_name("dump-graph")
+                    .long("dump-graph")
+                    .value_name("PATH")
+                    .help("Write the nodes, images and dependencies of the render graphs to a .dot file, or a .json file"),
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .value_name("PATH")
+                    .help("Time the main systems and passes on the CPU, logging their averages and writing them to a Chrome trace .json file on close"),
+            )
+            .arg(
+                Arg::with_name("white-furnace")
+                    .long("white-furnace")
+                    .conflicts_with_all(&["environment", "replay", "record"])
+                    .help("Check that spheres of every roughness and metallic value reflect all the light of a white environment, then exit, with an error if any doesn't"),
+            )
+            .arg(
+                Arg::with_name("golden")
+                    .long("golden")
+                    .conflicts_with_all(&["environment", "filter-quality", "replay", "record", "white-furnace"])
+                    .help("Compare a panorama of the scene against its golden image in assets/golden, then exit, with an error if they differ"),
+            )
+            .arg(
+                Arg::with_name("bless")
+                    .long("bless")
+                    .requires("golden")
+                    .help("Save the panorama as the scene's golden image instead of comparing it"),
+            )
+            .arg(
+                Arg::with_name("smoke")
+                    .long("smoke")
+                    .conflicts_with_all(&["replay", "record", "white-furnace", "golden"])
+                    .help("Build the render graph with every pass toggled on and off and run a frame of each with a hidden window, then exit, with an error if any failed"),
+            )
+            .arg(
+                Arg::with_name("abort-on-validation-error")
+                    .long("abort-on-validation-error")
+                    .help("Abort on the first error of the validation layers, enabled in debug builds or with the `validation` feature"),
+            )
+            .get_matches();
+
+        let environment = matches.value_of("environment").map(|path| {
+            let is_ktx = path.to_ascii_lowercase().ends_with(".ktx");
+            if is_ktx {
+                EnvironmentSource::Ktx(path.to_owned())
+            } else {
+                EnvironmentSource::Equirectangular(path.to_owned())
+            }
+        });
+
+        let filter_quality = matches
+            .value_of("filter-quality")
+            .map(|quality| match quality {
+                "low" => Quality::Low,
+                "medium" => Quality::Medium,
+                _ => Quality::High,
+            });
+
+        let window_size = match matches.value_of("window-size") {
+            Some(size) => {
+                let mut parts = size.split('x').map(|part| part.trim().parse::<f64>());
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(Ok(width)), Some(Ok(height)), None) if width > 0.0 && height > 0.0 => {
+                        Some((width, height))
+                    }
+                    _ => failure::bail!("Invalid window size {:?}, expected e.g. 1280x960", size),
+                }
+            }
+            None => None,
+        };
+
+        let backend =
+            match matches.value_of("backend") {
+                Some(name) => {
+                    let backend = name
+                        .parse::<rendy::core::Backend>()
+                        .map_err(|_| failure::format_err!("Unknown backend {:?}", name))?;
+                    Some(std::convert::TryFrom::try_from(backend).map_err(|_| {
+                        failure::format_err!("Backend {:?} is not compiled in", name)
+                    })?)
+                }
+                None => None,
+            };
+
+        let white_furnace = matches.is_present("white-furnace");
+        let mut scenes = matches.values_of("scene").unwrap().map(str::to_owned);
+        let scene = if white_furnace {
+            crate::furnace::SCENE.to_owned()
+        } else {
+            scenes.next().unwrap()
+        };
+        let more_scenes = if white_furnace {
+            Vec::new()
+        } else {
+            scenes.collect()
+        };
+
+        Ok(Args {
+            scene,
+            more_scenes,
+            environment,
+            filter_quality,
+            window_size,
+            backend,
+            vsync: matches.value_of("vsync").map(|vsync| vsync == "on"),
+            bake_lightmaps: matches.is_present("bake-lightmaps"),
+            record: matches.value_of("record").map(str::to_owned),
+            replay: matches.value_of("replay").map(str::to_owned),
+            headless: matches.is_present("headless"),
+            dump_graph: matches.value_of("dump-graph").map(str::to_owned),
+            profile: matches.value_of("profile").map(str::to_owned),
+            white_furnace,
+            golden: if !matches.is_present("golden") {
+                None
+            } else if matches.is_present("bless") {
+                Some(crate::golden::Mode::Bless)
+            } else {
+                Some(crate::golden::Mode::Compare)
+            },
+            smoke: matches.is_present("smoke"),
+            abort_on_validation_error: matches.is_present("abort-on-validation-error"),
+        })
+    }
+
+    /// Replaces the settings of the scene config which were given on the command line.
+    pub fn apply(&mut self, scene_config: &mut SceneConfig) {
+        if let Some(environment) = self.environment.take() {
+            scene_config.environment_map = environment;
+        }
+        if let Some(quality) = self.filter_quality.take() {
+            scene_config.environment_filter_quality = vec![quality];
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Undo and redo of interactive edits of the scene, like dragging the gizmo. Whatever edits
+//! the scene records the edit in `CommandStack` once it's done, with the values before and
+//! after it. **Ctrl+Z** undoes the last edit, and **Ctrl+Y** or **Ctrl+Shift+Z** redoes it.
+use crate::{components, input::EventBucket};
+use rendy::init::winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use specs::prelude::*;
+
+use std::collections::VecDeque;
+
+/// The most edits kept to undo. Older ones are forgotten.
+const MAX_UNDO: usize = 256;
+
+/// An edit of a component, which can be applied in either direction
+#[derive(Debug, Clone)]
+pub enum Command {
+    Transform {
+        entity: Entity,
+        before: components::Transform,
+        after: components::Transform,
+    },
+    Light {
+        entity: Entity,
+        before: components::Light,
+        after: components::Light,
+    },
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Transform { .. } => "transform edit",
+            Command::Light { .. } => "light edit",
+        }
+    }
+
+    /// Sets the values from before the edit if `undo`, and from after it otherwise. Edits of
+    /// entities which were deleted since do nothing.
+    fn apply(
+        &self,
+        undo: bool,
+        transforms: &mut WriteStorage<'_, components::Transform>,
+        lights: &mut WriteStorage<'_, components::Light>,
+    ) {
+        match self {
+            Command::Transform {
+                entity,
+                before,
+                after,
+            } => {
+                if let Some(transform) = transforms.get_mut(*entity) {
+                    *transform = if undo { before } else { after }.clone();
+                }
+            }
+            Command::Light {
+                entity,
+                before,
+                after,
+            } => {
+                if let Some(light) = lights.get_mut(*entity) {
+                    *light = if undo { *before } else { *after };
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CommandStack {
+    undo: VecDeque<Command>,
+    redo: Vec<Command>,
+}
+
+impl CommandStack {
+    /// Records an edit which was just made. Whatever was undone before it can't be redone
+    /// anymore.
+    pub fn push(&mut self, command: Command) {
+        self.redo.clear();
+        if self.undo.len() == MAX_UNDO {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(command);
+    }
+}
+
+/// Undoes and redoes edits on their shortcuts.
+pub struct CommandStackSystem;
+
+impl<'a> System<'a> for CommandStackSystem {
+    type SystemData = (
+        Read<'a, EventBucket>,
+        Write<'a, CommandStack>,
+        WriteStorage<'a, components::Transform>,
+        WriteStorage<'a, components::Light>,
+    );
+
+    fn run(&mut self, (events, mut stack, mut transforms, mut lights): Self::SystemData) {
+        for event in events.0.iter() {
+            if let Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                virtual_keycode: Some(key),
+                                state: ElementState::Pressed,
+                                modifiers,
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } = event
+            {
+                if !modifiers.ctrl {
+                    continue;
+                }
+                let undo = match key {
+                    VirtualKeyCode::Z if !modifiers.shift => true,
+                    VirtualKeyCode::Z | VirtualKeyCode::Y => false,
+                    _ => continue,
+                };
+                let command = if undo {
+                    stack.undo.pop_back()
+                } else {
+                    stack.redo.pop()
+                };
+                match command {
+                    Some(command) => {
+                        command.apply(undo, &mut transforms, &mut lights);
+                        log::info!(
+                            "{} {}",
+                            if undo { "Undid" } else { "Redid" },
+                            command.name()
+                        );
+                        if undo {
+                            stack.redo.push(command);
+                        } else {
+                            stack.undo.push_back(command);
+                        }
+                    }
+                    None => log::info!("Nothing to {}", if undo { "undo" } else { "redo" }),
+                }
+            }
+        }
+    }
+}
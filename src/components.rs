@@ -1,4 +1,6 @@
-use crate::asset;
+use crate::{asset, input::ViewportInfo, node::pbr::CameraArgs, raycast};
+
+use derivative::Derivative;
 
 use serde::Deserialize;
 use specs::prelude::*;
@@ -11,29 +13,362 @@ pub struct Camera {
     pub pitch: f32,
     pub dist: f32,
     pub focus: nalgebra::Point3<f32>,
-    pub proj: nalgebra::Perspective3<f32>,
+    /// Vertical field of view, in radians
+    pub fov: f32,
+    /// Width over height of the viewport, updated when the window is resized
+    pub aspect: f32,
+    pub znear: f32,
+    /// Ignored if `infinite_far` is set
+    pub zfar: f32,
+    /// Put the far plane at infinity, so nothing is ever clipped by it. Best combined with
+    /// `reversed_z`, which keeps depth precise far away.
+    pub infinite_far: bool,
+    /// Map the near plane to depth 1 and the far plane to depth 0, which spreads float
+    /// depth precision much more evenly over distance
+    pub reversed_z: bool,
+    /// The layers of the entities this camera sees, see `Layers`
+    pub layers: u32,
+}
+
+impl Camera {
+    /// The projection matrix, from view space to clip space with a 0 to 1 depth range if
+    /// `reversed_z` is set. Built from the current parameters, so changes to them and to
+    /// the aspect ratio take effect on the next frame.
+    pub fn projection(&self) -> nalgebra::Matrix4<f32> {
+        let mut proj = nalgebra::Perspective3::new(
+            self.aspect,
+            self.fov,
+            self.znear,
+            if self.infinite_far {
+                // Only used for the depth rows, which are replaced below
+                self.znear * 2.0
+            } else {
+                self.zfar
+            },
+        )
+        .to_homogeneous();
+        let (near, far) = (self.znear, self.zfar);
+        match (self.reversed_z, self.infinite_far) {
+            (false, false) => (),
+            (false, true) => {
+                proj[(2, 2)] = -1.0;
+                proj[(2, 3)] = -2.0 * near;
+            }
+            (true, false) => {
+                proj[(2, 2)] = near / (far - near);
+                proj[(2, 3)] = near * far / (far - near);
+            }
+            (true, true) => {
+                proj[(2, 2)] = 0.0;
+                proj[(2, 3)] = near;
+            }
+        }
+        proj
+    }
+
+    /// From `focus` to the eye
+    fn eye_offset(&self) -> nalgebra::Vector3<f32> {
+        self.dist
+            * nalgebra::Vector3::new(
+                self.yaw.sin() * self.pitch.cos(),
+                self.pitch.sin(),
+                self.yaw.cos() * self.pitch.cos(),
+            )
+    }
+
+    pub fn eye(&self) -> nalgebra::Point3<f32> {
+        self.focus + self.eye_offset()
+    }
+
+    /// Moves `focus` so that the eye is at `eye` at the current `dist`, `yaw` and `pitch`, to
+    /// look around from where the camera is rather than orbit.
+    pub fn set_eye(&mut self, eye: nalgebra::Point3<f32>) {
+        self.focus = eye - self.eye_offset();
+    }
+
+    /// The transform of the camera orbiting `focus` at `dist`, `yaw` and `pitch`, looking at it
+    pub fn orbit_transform(&self) -> nalgebra::Similarity3<f32> {
+        let eye = self.eye();
+
+        nalgebra::Similarity3::from_parts(
+            nalgebra::Translation::from(eye.coords.clone()),
+            // Invert direction for right handed
+            nalgebra::UnitQuaternion::face_towards(&(eye - self.focus), &nalgebra::Vector3::y()),
+            1.0,
+        )
+    }
+
+    /// The ray from the eye through the cursor at `pos`, placed by `orbit_transform` like the
+    /// camera systems place cameras.
+    pub fn screen_ray(
+        &self,
+        pos: rendy::init::winit::dpi::LogicalPosition,
+        viewport: &ViewportInfo,
+    ) -> raycast::Ray {
+        let camera = CameraArgs {
+            proj: self.projection(),
+            view: self.orbit_transform().inverse().to_homogeneous(),
+            camera_pos: self.eye(),
+        };
+        raycast::screen_ray(&camera, pos, viewport)
+    }
 }
 
 impl Component for Camera {
     type Storage = FlaggedStorage<Self, HashMapStorage<Self>>;
 }
 
+/// The brightness of a light, in photometric units. The unit determines the kind of light.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum LightIntensity {
+    /// Luminous intensity of a point light, in candela (lm/sr)
+    Candela(f32),
+    /// Luminous power of a point light, in lumens
+    Lumens(f32),
+    /// Illuminance of a directional light, in lux (lm/m²). The light shines along the
+    /// negative Z axis of the light's transform, like a camera looks.
+    Lux(f32),
+}
+
+impl LightIntensity {
+    pub fn is_directional(&self) -> bool {
+        match self {
+            LightIntensity::Lux(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The value passed to the shaders: luminous intensity in candela for point lights,
+    /// and illuminance in lux for directional lights.
+    pub fn shader_value(&self) -> f32 {
+        match *self {
+            LightIntensity::Candela(cd) => cd,
+            LightIntensity::Lumens(lm) => lumens_to_candela(lm),
+            LightIntensity::Lux(lx) => lx,
+        }
+    }
+}
+
+/// Luminous intensity of an isotropic point light emitting the given luminous power.
+pub fn lumens_to_candela(lumens: f32) -> f32 {
+    lumens / (4.0 * std::f32::consts::PI)
+}
+
 #[derive(Debug, Clone, Copy, Deserialize)]
 pub struct Light {
-    pub intensity: f32,
+    pub intensity: LightIntensity,
     pub color: [f32; 3],
+    /// How this light's shadows are rendered. Lights cast no shadows by default.
+    #[serde(default)]
+    pub shadow: ShadowSettings,
+    /// Distance past which a point light is left out of the light lists of meshes, in world
+    /// units. Defaults to where its illuminance falls below `LIGHT_CUTOFF_LUX`. Its light ends
+    /// abruptly there, so it should be set past where the light is noticeable.
+    #[serde(default)]
+    pub range: Option<f32>,
+}
+
+/// Illuminance under which a point light without a `range` is considered not to reach.
+pub const LIGHT_CUTOFF_LUX: f32 = 0.01;
+
+impl Light {
+    /// How far the light reaches, `None` for directional lights, which reach everywhere.
+    pub fn reach(&self) -> Option<f32> {
+        if self.intensity.is_directional() {
+            return None;
+        }
+        Some(
+            self.range
+                .unwrap_or_else(|| (self.intensity.shader_value() / LIGHT_CUTOFF_LUX).sqrt()),
+        )
+    }
+}
+
+/// Per light shadow map settings, to trade shadow acne against peter-panning and hard
+/// against soft edges for each light.
+#[derive(Debug, Derivative, Clone, Copy, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    /// Width and height of each cascade of the shadow map, capped by the `shadows` quality of
+    /// the render settings
+    #[derivative(Default(value = "1024"))]
+    pub resolution: u32,
+    /// Constant offset subtracted from the depth compared against the shadow map, which
+    /// removes acne but detaches shadows from their casters when too large
+    #[derivative(Default(value = "0.005"))]
+    pub depth_bias: f32,
+    /// Offset of the receiving point along its normal, in world units, scaled by the angle
+    /// to the light. Removes acne on surfaces facing away from the light.
+    #[derivative(Default(value = "0.02"))]
+    pub normal_bias: f32,
+    /// Radius of the percentage-closer filter, in shadow map texels. 0 gives hard edges.
+    #[derivative(Default(value = "1.0"))]
+    pub pcf_radius: f32,
+    /// Number of shadow cascades a directional light splits the view into, up to 4
+    #[derivative(Default(value = "4"))]
+    pub cascades: u32,
+    /// Blend between evenly spaced cascade splits at 0.0 and logarithmically spaced ones at
+    /// 1.0, which give nearby shadows more resolution
+    #[derivative(Default(value = "0.75"))]
+    pub split_lambda: f32,
+    /// Distance from the camera directional light shadows reach, in world units
+    #[derivative(Default(value = "30.0"))]
+    pub max_distance: f32,
+    /// The layers of the meshes casting these shadows, see `Layers`. All of them by default.
+    #[derivative(Default(value = "Layers::ALL"))]
+    pub layers: u32,
 }
 
 impl Component for Light {
     type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
 }
 
+/// A camera-facing sprite drawn at the entity's position, used to mark things that have
+/// no geometry of their own, like lights.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Billboard {
+    /// Width and height of the sprite, in world units
+    pub size: f32,
+    /// Tint multiplied with the sprite texture, with alpha
+    #[serde(default = "Billboard::default_color")]
+    pub color: [f32; 4],
+}
+
+impl Billboard {
+    fn default_color() -> [f32; 4] {
+        [1.0, 1.0, 1.0, 1.0]
+    }
+}
+
+impl Component for Billboard {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Continuously spawns particles at the entity's position, which are simulated by the
+/// `ParticleSystem` and drawn additively.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ParticleEmitter {
+    /// Particles spawned per second
+    pub rate: f32,
+    /// Seconds each particle lives for
+    pub lifetime: f32,
+    /// Initial velocity of each particle, in world units per second
+    pub velocity: [f32; 3],
+    /// Maximum random offset added to each component of the initial velocity
+    #[serde(default)]
+    pub spread: f32,
+    /// Constant acceleration, e.g. gravity or buoyancy
+    #[serde(default)]
+    pub acceleration: [f32; 3],
+    /// Width and height of each particle, in world units
+    pub size: f32,
+    /// Color when spawned, fading linearly to `end_color` over the lifetime. Values may
+    /// be above 1.0, since particles are drawn to the HDR target.
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    /// Fractional particles carried over between updates
+    #[serde(skip)]
+    pub accumulator: f32,
+}
+
+impl Component for ParticleEmitter {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Rotates the entity continuously around an axis in its own space, driven by the
+/// `ProceduralAnimationSystem`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Spin {
+    pub axis: [f32; 3],
+    /// Radians per second, counterclockwise seen from the tip of the axis
+    pub speed: f32,
+}
+
+impl Component for Spin {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Moves the entity back and forth along an axis, in its parent's space, around the position
+/// it had when it started moving.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Oscillate {
+    pub axis: [f32; 3],
+    /// Furthest distance from the starting position
+    pub amplitude: f32,
+    /// Full swings per second
+    pub frequency: f32,
+    /// Position the entity oscillates around, taken from its transform on the first update
+    #[serde(skip)]
+    pub origin: Option<[f32; 3]>,
+}
+
+impl Component for Oscillate {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Circles the entity around a point in its parent's space, in the horizontal plane at the
+/// point's height. It sets the position outright, so it overrides `Oscillate`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Orbit {
+    pub center: [f32; 3],
+    pub radius: f32,
+    /// Radians per second, counterclockwise seen from above
+    pub speed: f32,
+}
+
+impl Component for Orbit {
+    type Storage = DenseVecStorage<Self>;
+}
+
 pub struct Mesh(pub asset::MeshHandle);
 
 impl Component for Mesh {
     type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
 }
 
+/// The lights reaching a mesh, as a mask of their indices in the lights given to the
+/// shaders by `node::pbr::gather_lights`. Written by `systems::LightListSystem`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LightList(pub u32);
+
+impl Component for LightList {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// The layers an entity is in, as a mask of up to 32 of them. Cameras and shadowed lights
+/// have a mask of the layers they see, and an entity's mesh is only drawn by those whose mask
+/// shares a layer with it. Entities without it are in the first layer only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Layers(pub u32);
+
+impl Layers {
+    /// The mask of every layer
+    pub const ALL: u32 = !0;
+}
+
+impl Component for Layers {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+/// Keeps an entity's mesh out of the shadow maps.
+#[derive(Debug, Default)]
+pub struct NoShadows;
+
+impl Component for NoShadows {
+    type Storage = NullStorage<Self>;
+}
+
+/// Indicates that an entity's mesh doesn't move, so its shadows are cached rather than drawn
+/// every frame. Moving it anyway draws the cached shadows of every static mesh again.
+#[derive(Debug, Default)]
+pub struct Static;
+
+impl Component for Static {
+    type Storage = NullStorage<Self>;
+}
+
 /// Indicates that an entity is the active camera.
 #[derive(Debug, Default)]
 pub struct ActiveCamera;
@@ -42,6 +377,15 @@ impl Component for ActiveCamera {
     type Storage = NullStorage<Self>;
 }
 
+/// Indicates that an entity is the camera of the debug window. It isn't moved by input, and
+/// its aspect ratio is the debug window's.
+#[derive(Debug, Default)]
+pub struct DebugWindowCamera;
+
+impl Component for DebugWindowCamera {
+    type Storage = NullStorage<Self>;
+}
+
 // pub struct Environment<B: hal::Backend> {
 //     mesh: Mesh<B>,
 //     hdr: Texture<B>,
@@ -49,3 +393,162 @@ impl Component for ActiveCamera {
 //     spec_filtered: Texture<B>,
 //     bdrf: Texture<B>,
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEAR: f32 = 0.1;
+    const FAR: f32 = 100.0;
+
+    /// Looking down a 90 degree vertical field of view on a viewport twice as wide as it is
+    /// high, so the x and y scales are 0.5 and 1.
+    fn camera(reversed_z: bool, infinite_far: bool) -> Camera {
+        Camera {
+            yaw: 0.0,
+            pitch: 0.0,
+            dist: 1.0,
+            focus: nalgebra::Point3::origin(),
+            fov: std::f32::consts::FRAC_PI_2,
+            aspect: 2.0,
+            znear: NEAR,
+            zfar: FAR,
+            infinite_far,
+            reversed_z,
+            layers: 1,
+        }
+    }
+
+    fn expected(m22: f32, m23: f32) -> nalgebra::Matrix4<f32> {
+        nalgebra::Matrix4::new(
+            0.5, 0.0, 0.0, 0.0, //
+            0.0, 1.0, 0.0, 0.0, //
+            0.0, 0.0, m22, m23, //
+            0.0, 0.0, -1.0, 0.0,
+        )
+    }
+
+    /// Depth of the point `distance` in front of the camera, after the perspective divide
+    fn depth(proj: &nalgebra::Matrix4<f32>, distance: f32) -> f32 {
+        let clip = proj * nalgebra::Vector4::new(0.0, 0.0, -distance, 1.0);
+        clip.z / clip.w
+    }
+
+    fn assert_matrix_eq(actual: nalgebra::Matrix4<f32>, expected: nalgebra::Matrix4<f32>) {
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!(
+                (a - e).abs() <= 1e-5 * e.abs().max(1.0),
+                "{} != {}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn projection_forward() {
+        assert_matrix_eq(
+            camera(false, false).projection(),
+            expected(
+                -(FAR + NEAR) / (FAR - NEAR),
+                -2.0 * FAR * NEAR / (FAR - NEAR),
+            ),
+        );
+    }
+
+    #[test]
+    fn projection_infinite_far() {
+        let proj = camera(false, true).projection();
+        assert_matrix_eq(proj, expected(-1.0, -2.0 * NEAR));
+        assert!((depth(&proj, NEAR) + 1.0).abs() < 1e-5);
+        assert!((depth(&proj, 1.0e7) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn projection_reversed_z() {
+        let proj = camera(true, false).projection();
+        assert_matrix_eq(
+            proj,
+            expected(NEAR / (FAR - NEAR), NEAR * FAR / (FAR - NEAR)),
+        );
+        assert!((depth(&proj, NEAR) - 1.0).abs() < 1e-5);
+        assert!(depth(&proj, FAR).abs() < 1e-5);
+    }
+
+    #[test]
+    fn projection_reversed_z_infinite_far() {
+        let proj = camera(true, true).projection();
+        assert_matrix_eq(proj, expected(0.0, NEAR));
+        assert!((depth(&proj, NEAR) - 1.0).abs() < 1e-5);
+        assert!(depth(&proj, 1.0e7) < 1e-7);
+        // Further away is always closer to 0
+        assert!(depth(&proj, 10.0) > depth(&proj, 20.0));
+    }
+
+    /// 200 by 100 physical pixels at a scale factor of 2, so 100 by 50 logical ones, with the
+    /// aspect ratio of `camera`.
+    fn viewport() -> ViewportInfo {
+        ViewportInfo {
+            window_id: unsafe { rendy::init::winit::window::WindowId::dummy() },
+            physical_size: rendy::init::winit::dpi::PhysicalSize::new(200.0, 100.0),
+            scale_factor: 2.0,
+        }
+    }
+
+    fn assert_direction_eq(ray: &raycast::Ray, expected: nalgebra::Vector3<f32>) {
+        let expected = expected.normalize();
+        assert!(
+            (ray.direction.as_ref() - expected).amax() < 1e-5,
+            "{} != {}",
+            ray.direction.as_ref(),
+            expected
+        );
+    }
+
+    #[test]
+    fn screen_ray_through_center_and_corners() {
+        use rendy::init::winit::dpi::LogicalPosition;
+
+        // Every depth mapping gives the same rays, since only the x and y rows are used
+        for &(reversed_z, infinite_far) in
+            [(false, false), (false, true), (true, false), (true, true)].iter()
+        {
+            // At 0, 0, 1, looking down -Z with a scale of 0.5 for x and 1 for y
+            let camera = camera(reversed_z, infinite_far);
+            let ray = |x, y| camera.screen_ray(LogicalPosition::new(x, y), &viewport());
+
+            let center = ray(50.0, 25.0);
+            assert!((center.origin - nalgebra::Point3::new(0.0, 0.0, 1.0)).amax() < 1e-6);
+            assert_direction_eq(&center, nalgebra::Vector3::new(0.0, 0.0, -1.0));
+            assert_direction_eq(&ray(0.0, 0.0), nalgebra::Vector3::new(-2.0, 1.0, -1.0));
+            assert_direction_eq(&ray(100.0, 0.0), nalgebra::Vector3::new(2.0, 1.0, -1.0));
+            assert_direction_eq(&ray(0.0, 50.0), nalgebra::Vector3::new(-2.0, -1.0, -1.0));
+            assert_direction_eq(&ray(100.0, 50.0), nalgebra::Vector3::new(2.0, -1.0, -1.0));
+        }
+    }
+
+    #[test]
+    fn screen_ray_of_a_turned_camera() {
+        use rendy::init::winit::dpi::LogicalPosition;
+
+        // At 5, 0, 0 looking down -X, so its right is -Z
+        let eye = nalgebra::Point3::new(5.0, 0.0, 0.0);
+        let args = CameraArgs {
+            proj: camera(false, false).projection(),
+            view: nalgebra::Matrix4::look_at_rh(
+                &eye,
+                &nalgebra::Point3::origin(),
+                &nalgebra::Vector3::y(),
+            ),
+            camera_pos: eye,
+        };
+        let ray = |x, y| raycast::screen_ray(&args, LogicalPosition::new(x, y), &viewport());
+
+        let center = ray(50.0, 25.0);
+        assert_eq!(center.origin, eye);
+        assert_direction_eq(&center, nalgebra::Vector3::new(-1.0, 0.0, 0.0));
+        // Two to the right, one up and one forward
+        assert_direction_eq(&ray(100.0, 0.0), nalgebra::Vector3::new(-1.0, 1.0, -2.0));
+        assert_direction_eq(&ray(0.0, 50.0), nalgebra::Vector3::new(-1.0, -1.0, 2.0));
+    }
+}
@@ -0,0 +1,77 @@
+//! Counts of the state changes and draw calls recorded by the scene's pipelines, to measure
+//! what sorting and batching draws saves.
+//!
+//! Pipelines add to the current frame's counts as they record their draws, and the main loop
+//! ends the frame after the graph has run. The averages since they were last taken are logged
+//! along with the FPS.
+use std::{fmt, ops::AddAssign};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DrawCounts {
+    pub pipeline_binds: u32,
+    pub descriptor_binds: u32,
+    pub vertex_buffer_binds: u32,
+    /// Calls to draw, each of which may draw several indirect commands
+    pub draw_calls: u32,
+    /// Draw commands, as many as draw calls unless they are drawn with multi-draw
+    pub draws: u32,
+}
+
+impl AddAssign for DrawCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.pipeline_binds += other.pipeline_binds;
+        self.descriptor_binds += other.descriptor_binds;
+        self.vertex_buffer_binds += other.vertex_buffer_binds;
+        self.draw_calls += other.draw_calls;
+        self.draws += other.draws;
+    }
+}
+
+impl fmt::Display for DrawCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} pipeline binds, {} descriptor binds, {} vertex buffer binds, {} draw calls ({} draws)",
+            self.pipeline_binds,
+            self.descriptor_binds,
+            self.vertex_buffer_binds,
+            self.draw_calls,
+            self.draws
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DrawStats {
+    /// Of the frame being recorded
+    pub current: DrawCounts,
+    /// Of the last whole frame
+    pub last_frame: DrawCounts,
+    /// Since the averages were last taken
+    totals: DrawCounts,
+    frames: u32,
+}
+
+impl DrawStats {
+    pub fn end_frame(&mut self) {
+        self.last_frame = std::mem::replace(&mut self.current, DrawCounts::default());
+        self.totals += self.last_frame;
+        self.frames += 1;
+    }
+
+    /// The counts per frame since the last call, or `None` if no frame ended since then.
+    pub fn take_averages(&mut self) -> Option<DrawCounts> {
+        let frames = std::mem::replace(&mut self.frames, 0);
+        let totals = std::mem::replace(&mut self.totals, DrawCounts::default());
+        if frames == 0 {
+            return None;
+        }
+        Some(DrawCounts {
+            pipeline_binds: totals.pipeline_binds / frames,
+            descriptor_binds: totals.descriptor_binds / frames,
+            vertex_buffer_binds: totals.vertex_buffer_binds / frames,
+            draw_calls: totals.draw_calls / frames,
+            draws: totals.draws / frames,
+        })
+    }
+}
@@ -0,0 +1,204 @@
+//! A bump allocator for the lists pipelines build while preparing and drawing a frame, like
+//! dirty transforms, sorted sprites and push constants, which are thrown away once the frame
+//! is recorded.
+//!
+//! Allocating only bumps an offset into a chunk, and the main loop resets the arena after the
+//! graph has run, so the same memory is reused every frame instead of going through the heap
+//! allocator. A frame which doesn't fit in the chunk spills into new ones, which are merged
+//! into a single chunk big enough for it on the next reset.
+//!
+//! Only `Copy` types can be allocated, since nothing is dropped.
+use std::{mem, ptr, slice, sync::Mutex};
+
+/// Alignment of every chunk, the most a type allocated from the arena can need.
+const BLOCK_SIZE: usize = 16;
+/// Size of the first chunk, and the least a new one is made.
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+#[repr(align(16))]
+struct Block([u8; BLOCK_SIZE]);
+
+struct Chunks {
+    /// Every chunk since the last reset. Allocations are made from the last one.
+    chunks: Vec<Vec<Block>>,
+    /// Bytes used of the last chunk
+    used: usize,
+    /// Bytes allocated since the last reset
+    allocated: usize,
+}
+
+impl Chunks {
+    fn last_size(&self) -> usize {
+        self.chunks
+            .last()
+            .map_or(0, |chunk| chunk.len() * BLOCK_SIZE)
+    }
+
+    fn push_chunk(&mut self, size: usize) {
+        let size = size.max(MIN_CHUNK_SIZE).max(self.last_size() * 2);
+        let blocks = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        self.chunks.push(vec![Block([0; BLOCK_SIZE]); blocks]);
+        self.used = 0;
+    }
+
+    /// The start of the free room in the last chunk for `T`, making a new chunk if it has room
+    /// for less than `len` of them, and how many fit there.
+    fn reserve<T>(&mut self, len: usize) -> (*mut T, usize) {
+        let size = mem::size_of::<T>();
+        let mut offset = align_up(self.used, mem::align_of::<T>());
+        if offset + len * size > self.last_size() {
+            self.push_chunk(len * size);
+            offset = 0;
+        }
+        let chunk = self.chunks.last_mut().unwrap();
+        let start = unsafe { (chunk.as_mut_ptr() as *mut u8).add(offset) as *mut T };
+        (start, (chunk.len() * BLOCK_SIZE - offset) / size)
+    }
+
+    /// Moves the first `len` values at `start` into a new chunk with room for twice as many.
+    fn grow<T>(&mut self, start: *mut T, len: usize) -> (*mut T, usize) {
+        self.push_chunk(len * 2 * mem::size_of::<T>());
+        let (new_start, capacity) = self.reserve::<T>(len * 2);
+        unsafe {
+            ptr::copy_nonoverlapping(start, new_start, len);
+        }
+        (new_start, capacity)
+    }
+
+    /// Marks the `len` values at `start` in the last chunk as used.
+    fn commit<T>(&mut self, start: *mut T, len: usize) {
+        let base = self.chunks.last().unwrap().as_ptr() as usize;
+        let end = start as usize - base + len * mem::size_of::<T>();
+        self.allocated += end - self.used;
+        self.used = end;
+    }
+}
+
+fn align_up(offset: usize, align: usize) -> usize {
+    (offset + align - 1) / align * align
+}
+
+pub struct FrameArena {
+    chunks: Mutex<Chunks>,
+}
+
+impl Default for FrameArena {
+    fn default() -> Self {
+        let mut chunks = Chunks {
+            chunks: Vec::new(),
+            used: 0,
+            allocated: 0,
+        };
+        chunks.push_chunk(MIN_CHUNK_SIZE);
+        FrameArena {
+            chunks: Mutex::new(chunks),
+        }
+    }
+}
+
+impl FrameArena {
+    /// Collects `values` into the arena. The iterator must not allocate from the arena itself.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_iter<T: Copy, I: IntoIterator<Item = T>>(&self, values: I) -> &mut [T] {
+        assert!(mem::size_of::<T>() > 0 && mem::align_of::<T>() <= BLOCK_SIZE);
+        let mut chunks = self
+            .chunks
+            .try_lock()
+            .expect("Allocating from the frame arena while collecting into it");
+        let values = values.into_iter();
+        let (mut start, mut capacity) = chunks.reserve::<T>(values.size_hint().0);
+        let mut len = 0;
+        for value in values {
+            if len == capacity {
+                let (new_start, new_capacity) = chunks.grow(start, len);
+                start = new_start;
+                capacity = new_capacity;
+            }
+            unsafe {
+                start.add(len).write(value);
+            }
+            len += 1;
+        }
+        chunks.commit(start, len);
+        // Each allocation is a different range of a chunk, which is only freed by `reset`
+        unsafe { slice::from_raw_parts_mut(start, len) }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, values: &[T]) -> &mut [T] {
+        self.alloc_iter(values.iter().cloned())
+    }
+
+    /// `len` copies of `value`.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_fill<T: Copy>(&self, len: usize, value: T) -> &mut [T] {
+        self.alloc_iter(std::iter::repeat(value).take(len))
+    }
+
+    /// Frees everything allocated, keeping a single chunk with room for as much as was
+    /// allocated since the last reset.
+    pub fn reset(&mut self) {
+        let chunks = self.chunks.get_mut().unwrap();
+        if chunks.chunks.len() > 1 {
+            let size = chunks.allocated;
+            chunks.chunks.clear();
+            chunks.push_chunk(size);
+        }
+        chunks.used = 0;
+        chunks.allocated = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(align(16))]
+    struct Aligned([f32; 4]);
+
+    #[test]
+    fn alignment() {
+        let arena = FrameArena::default();
+        let bytes = arena.alloc_slice(&[1u8, 2, 3]);
+        let words = arena.alloc_slice(&[4u64, 5]);
+        let aligned = arena.alloc_fill(2, Aligned([6.0; 4]));
+        assert_eq!(bytes, &[1, 2, 3]);
+        assert_eq!(words, &[4, 5]);
+        assert_eq!(aligned, &[Aligned([6.0; 4]); 2]);
+        assert_eq!(words.as_ptr() as usize % mem::align_of::<u64>(), 0);
+        assert_eq!(aligned.as_ptr() as usize % 16, 0);
+    }
+
+    #[test]
+    fn reset_reuses_memory() {
+        let mut arena = FrameArena::default();
+        let first = arena.alloc_fill(100, 1u32).as_ptr() as usize;
+        arena.reset();
+        let second = arena.alloc_fill(100, 2u32);
+        assert_eq!(second.as_ptr() as usize, first);
+        assert!(second.iter().all(|&value| value == 2));
+    }
+
+    #[test]
+    fn grows_past_first_chunk() {
+        let mut arena = FrameArena::default();
+        let small = arena.alloc_slice(&[1u32, 2, 3]);
+        // Without a size hint, so it spills over while being collected
+        let len = MIN_CHUNK_SIZE / mem::size_of::<u32>() * 3;
+        let big = arena.alloc_iter((0..len as u32).filter(|_| true));
+        assert_eq!(big.len(), len);
+        assert!(big.iter().enumerate().all(|(i, &value)| value == i as u32));
+        // Earlier allocations aren't moved by later chunks
+        assert_eq!(small, &[1, 2, 3]);
+
+        arena.reset();
+        let chunks = arena.chunks.get_mut().unwrap();
+        assert_eq!(chunks.chunks.len(), 1);
+        assert!(chunks.last_size() >= (len + 3) * mem::size_of::<u32>());
+        // Fits in the merged chunk now
+        arena.alloc_fill(len, 0u32);
+        assert_eq!(arena.chunks.get_mut().unwrap().chunks.len(), 1);
+    }
+}
@@ -0,0 +1,59 @@
+//! RenderDoc captures, available with the `rd` feature. Without it capturing does nothing.
+//!
+//! The environment preprocess is captured from startup through the first frame, and single
+//! PBR frames on request. RenderDoc's replay UI is opened on each finished capture.
+#[cfg(feature = "rd")]
+use renderdoc::prelude::*;
+
+pub struct FrameCapture {
+    #[cfg(feature = "rd")]
+    rd: renderdoc::RenderDoc<renderdoc::V120>,
+}
+
+impl FrameCapture {
+    #[cfg(feature = "rd")]
+    pub fn new() -> Result<Self, failure::Error> {
+        let rd = renderdoc::RenderDoc::new()
+            .map_err(|e| failure::format_err!("Failed to init renderdoc: {:?}", e))?;
+        Ok(FrameCapture { rd })
+    }
+
+    #[cfg(not(feature = "rd"))]
+    pub fn new() -> Result<Self, failure::Error> {
+        Ok(FrameCapture {})
+    }
+
+    /// Starts capturing everything submitted until `end`.
+    pub fn start(&mut self, label: &str) {
+        #[cfg(feature = "rd")]
+        {
+            log::info!("Starting RenderDoc capture of {}", label);
+            self.rd
+                .start_frame_capture(std::ptr::null(), std::ptr::null());
+        }
+        #[cfg(not(feature = "rd"))]
+        log::warn!("Can't capture {}, built without the `rd` feature", label);
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        #[cfg(feature = "rd")]
+        return self.rd.is_frame_capturing();
+        #[cfg(not(feature = "rd"))]
+        false
+    }
+
+    /// Ends the capture, if one was started, and opens it in the replay UI.
+    pub fn end(&mut self) {
+        if !self.is_capturing() {
+            return;
+        }
+        #[cfg(feature = "rd")]
+        {
+            self.rd
+                .end_frame_capture(std::ptr::null(), std::ptr::null());
+            if let Err(e) = self.rd.launch_replay_ui("rendy-pbr") {
+                log::error!("Failed to launch the RenderDoc replay UI: {:?}", e);
+            }
+        }
+    }
+}
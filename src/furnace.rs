@@ -0,0 +1,180 @@
+//! The white furnace test. Spheres with a white base color, sweeping roughness and metallic,
+//! are lit by nothing but a uniform environment. A BRDF which conserves energy reflects all of
+//! the light reaching it, so every sphere should come out as bright as the environment around
+//! it: brighter spheres gain energy, darker ones lose it.
+//!
+//! `--white-furnace` loads `assets/white_furnace.ron`, reads the spheres back once its
+//! environment is filtered, logs how far off each one is and exits with an error if any of
+//! them is off by more than the scene's tolerance.
+use derivative::Derivative;
+use rendy::{
+    command::{Families, QueueId},
+    factory::Factory,
+};
+use serde::Deserialize;
+
+use rendy::hal;
+
+use crate::{asset, components, node};
+
+/// Path of the test scene, relative to the application root
+pub const SCENE: &str = "assets/white_furnace.ron";
+
+/// Size of the cube faces the spheres are read back from
+const CAPTURE_RES: u32 = 256;
+/// Where each sphere is sampled, as fractions of its radius on screen. The rings away from
+/// the middle see the surface at grazing angles, where energy loss shows the most.
+const SAMPLE_RINGS: [f32; 3] = [0.0, 0.5, 0.8];
+const SAMPLES_PER_RING: usize = 8;
+
+/// How far the spheres may be from the environment's radiance before the test fails, as
+/// fractions of it.
+#[derive(Debug, Clone, Copy, Deserialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct FurnaceTolerance {
+    #[derivative(Default(value = "0.02"))]
+    pub max_gain: f32,
+    #[derivative(Default(value = "0.05"))]
+    pub max_loss: f32,
+}
+
+/// The darkest and brightest samples of one sphere, relative to the environment.
+struct SphereResult {
+    metallic: f32,
+    roughness: f32,
+    min: f32,
+    max: f32,
+}
+
+/// Draws the scene into a cube around the active camera and compares every mesh, expected to be one
+/// of the spheres, against `radiance`. Logs a line per sphere and returns whether they were
+/// all within `tolerance`.
+pub fn check<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    world: &mut specs::World,
+    settings: &crate::PbrGraphSettings,
+    radiance: [f32; 3],
+    tolerance: &FurnaceTolerance,
+) -> Result<bool, failure::Error> {
+    let (camera, position) = crate::active_camera(world)?;
+    let mut renderer = node::pbr::capture::CubeRenderer::new(
+        factory,
+        families,
+        queue,
+        world,
+        settings,
+        CAPTURE_RES,
+    )?;
+    let faces = renderer.render(factory, families, world, position, &camera);
+    renderer.dispose(factory, world);
+    let faces = faces?;
+
+    let mut results = measure::<B>(world, &faces, position, radiance);
+    if results.is_empty() {
+        failure::bail!("The white furnace scene has no meshes to check");
+    }
+    results.sort_by(|a, b| {
+        (a.metallic, a.roughness)
+            .partial_cmp(&(b.metallic, b.roughness))
+            .unwrap()
+    });
+
+    let mut passed = true;
+    for result in results.iter() {
+        let ok = result.max <= 1.0 + tolerance.max_gain && result.min >= 1.0 - tolerance.max_loss;
+        passed &= ok;
+        let line = format!(
+            "metallic {:.2}, roughness {:.2}: {:+.1}% to {:+.1}%",
+            result.metallic,
+            result.roughness,
+            (result.min - 1.0) * 100.0,
+            (result.max - 1.0) * 100.0,
+        );
+        if ok {
+            log::info!("White furnace {}", line);
+        } else {
+            log::error!("White furnace {}", line);
+        }
+    }
+    if passed {
+        log::info!("White furnace test passed for {} spheres", results.len());
+    } else {
+        log::error!(
+            "White furnace test failed, allowing a gain of {:.1}% and a loss of {:.1}%",
+            tolerance.max_gain * 100.0,
+            tolerance.max_loss * 100.0
+        );
+    }
+    Ok(passed)
+}
+
+/// Samples each mesh in rings around the direction to its center, relative to `radiance`.
+/// Meshes are labelled with the factors of their first primitive's material.
+fn measure<B: hal::Backend>(
+    world: &specs::World,
+    faces: &[node::env_preprocess::debug::Subresource],
+    position: nalgebra::Point3<f32>,
+    radiance: [f32; 3],
+) -> Vec<SphereResult> {
+    use specs::prelude::*;
+
+    let mesh_storage = world.read_resource::<asset::MeshStorage>();
+    let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
+    let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+    let meshes = world.read_storage::<components::Mesh>();
+    let transforms = world.read_storage::<components::GlobalTransform>();
+
+    let mut results = Vec::new();
+    for (mesh, transform) in (&meshes, &transforms).join() {
+        let mesh = &mesh_storage.0[mesh.0];
+        let factors = match mesh.primitives.first() {
+            Some(&primitive) => material_storage.0[primitive_storage.0[primitive].mat].factors,
+            None => continue,
+        };
+
+        let center = nalgebra::Point3::from(transform.0.column(3).xyz());
+        let scale = transform.0.column(0).xyz().norm();
+        let to_center = center - position;
+        let distance = to_center.norm();
+        let radius = mesh.bounding_radius * scale;
+        if distance <= radius {
+            continue;
+        }
+        let forward = to_center / distance;
+        let angular_radius = (radius / distance).asin();
+        let helper = if forward.y.abs() < 0.9 {
+            nalgebra::Vector3::y()
+        } else {
+            nalgebra::Vector3::x()
+        };
+        let right = forward.cross(&helper).normalize();
+        let up = right.cross(&forward);
+
+        let mut min = std::f32::MAX;
+        let mut max = std::f32::MIN;
+        for &ring in SAMPLE_RINGS.iter() {
+            let samples = if ring == 0.0 { 1 } else { SAMPLES_PER_RING };
+            for i in 0..samples {
+                let azimuth = i as f32 / samples as f32 * 2.0 * std::f32::consts::PI;
+                let offset = (ring * angular_radius).tan();
+                let dir = forward + (right * azimuth.cos() + up * azimuth.sin()) * offset;
+                let texel = node::pbr::capture::sample_cube(faces, &dir.normalize());
+                for c in 0..3 {
+                    let relative = texel[c] / radiance[c];
+                    min = min.min(relative);
+                    max = max.max(relative);
+                }
+            }
+        }
+        results.push(SphereResult {
+            metallic: factors.metallic,
+            roughness: factors.roughness,
+            min,
+            max,
+        });
+    }
+    results
+}
@@ -0,0 +1,152 @@
+//! The vertices and indices of every primitive, packed into a few shared buffers so that
+//! draws only rebind them once per pass and find their primitive by its offsets.
+//!
+//! Primitives add their data to a `GeometryPoolBuilder` as they're loaded, which keeps it on
+//! the host until the whole scene is loaded and then uploads it all at once.
+use crate::upload::UploadQueues;
+use rendy::{
+    command::RenderPassEncoder,
+    factory::Factory,
+    hal,
+    memory::MemoryUsageValue,
+    mesh::{PosNormTangTex, TexCoord},
+    resource::{Buffer, BufferInfo, Escape},
+};
+
+/// Where a primitive's data is in the pool, as the draw commands take it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GeometryRange {
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Added to each index, as the indices of a primitive start from 0
+    pub vertex_offset: i32,
+}
+
+impl GeometryRange {
+    pub fn indices(&self) -> std::ops::Range<u32> {
+        self.first_index..self.first_index + self.index_count
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GeometryPoolBuilder {
+    vertices: Vec<PosNormTangTex>,
+    lightmap_uvs: Vec<TexCoord>,
+    indices: Vec<u32>,
+}
+
+impl GeometryPoolBuilder {
+    /// Appends the shading vertices, lightmap texture coordinates and indices of a primitive.
+    pub fn add(
+        &mut self,
+        vertices: &[PosNormTangTex],
+        lightmap_uvs: &[[f32; 2]],
+        indices: &[u32],
+    ) -> GeometryRange {
+        assert_eq!(vertices.len(), lightmap_uvs.len());
+        let range = GeometryRange {
+            first_index: self.indices.len() as u32,
+            index_count: indices.len() as u32,
+            vertex_offset: self.vertices.len() as i32,
+        };
+        self.vertices.extend_from_slice(vertices);
+        self.lightmap_uvs
+            .extend(lightmap_uvs.iter().map(|uv| TexCoord::from(*uv)));
+        self.indices.extend_from_slice(indices);
+        range
+    }
+
+    /// The shading vertices of a primitive added before, which has `vertex_count` of them.
+    pub fn vertices(&self, range: GeometryRange, vertex_count: usize) -> &[PosNormTangTex] {
+        let first = range.vertex_offset as usize;
+        &self.vertices[first..first + vertex_count]
+    }
+
+    /// Uploads the data, on the transfer queue if there is a dedicated one, after which the
+    /// buffers have to be handed over to the graphics queue with `upload::transfer_to_graphics`.
+    pub fn build<B: hal::Backend>(
+        self,
+        factory: &mut Factory<B>,
+        queues: &UploadQueues,
+    ) -> Result<GeometryPool<B>, failure::Error> {
+        log::info!(
+            "Geometry pool: {} vertices, {} indices",
+            self.vertices.len(),
+            self.indices.len()
+        );
+        Ok(GeometryPool {
+            vertices: upload(
+                factory,
+                queues,
+                &self.vertices,
+                hal::buffer::Usage::VERTEX,
+                hal::buffer::Access::VERTEX_BUFFER_READ,
+            )?,
+            lightmap_uvs: upload(
+                factory,
+                queues,
+                &self.lightmap_uvs,
+                hal::buffer::Usage::VERTEX,
+                hal::buffer::Access::VERTEX_BUFFER_READ,
+            )?,
+            indices: upload(
+                factory,
+                queues,
+                &self.indices,
+                hal::buffer::Usage::INDEX,
+                hal::buffer::Access::INDEX_BUFFER_READ,
+            )?,
+        })
+    }
+}
+
+fn upload<B: hal::Backend, T: Copy>(
+    factory: &mut Factory<B>,
+    queues: &UploadQueues,
+    data: &[T],
+    usage: hal::buffer::Usage,
+    access: hal::buffer::Access,
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    // Empty buffers aren't allowed, so a scene without meshes still gets one element
+    let buffer = factory.create_buffer(
+        BufferInfo {
+            size: (std::mem::size_of::<T>() * data.len().max(1)) as u64,
+            usage: usage | hal::buffer::Usage::TRANSFER_DST,
+        },
+        MemoryUsageValue::Data,
+    )?;
+    if !data.is_empty() {
+        unsafe {
+            factory.upload_buffer(&buffer, 0, data, None, queues.vertex_state(access))?;
+        }
+    }
+    Ok(buffer)
+}
+
+pub struct GeometryPool<B: hal::Backend> {
+    /// `PosNormTangTex` vertices of every primitive
+    pub vertices: Escape<Buffer<B>>,
+    /// `TexCoord` lightmap texture coordinates, one per vertex
+    pub lightmap_uvs: Escape<Buffer<B>>,
+    /// `u32` indices, relative to the first vertex of their primitive
+    pub indices: Escape<Buffer<B>>,
+}
+
+impl<B: hal::Backend> GeometryPool<B> {
+    /// Binds the vertices at `first_binding`, followed by the lightmap texture coordinates if
+    /// `lightmap_uvs`, and the indices.
+    pub fn bind(
+        &self,
+        first_binding: u32,
+        lightmap_uvs: bool,
+        encoder: &mut RenderPassEncoder<'_, B>,
+    ) {
+        let buffers = std::iter::once(self.vertices.raw())
+            .chain(Some(self.lightmap_uvs.raw()).filter(|_| lightmap_uvs))
+            .map(|buffer| (buffer, 0));
+        unsafe {
+            encoder.bind_vertex_buffers(first_binding, buffers);
+            encoder.bind_index_buffer(self.indices.raw(), 0, hal::IndexType::U32);
+        }
+    }
+}
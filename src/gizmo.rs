@@ -0,0 +1,447 @@
+//! Handles to move, rotate and scale the selected entity with the mouse.
+//!
+//! **N** selects the next entity with a mesh (hold shift to go backwards), and **T** switches
+//! between translating, rotating and scaling. The handles are drawn as overlay lines at the
+//! entity's origin, along or around the world axes, at a constant size on screen. Dragging
+//! one with the left mouse button moves the entity along its axis, rotates it around it, or
+//! scales it; scale is uniform, so each scale handle scales along all three axes.
+use crate::{
+    command::{Command, CommandStack},
+    components,
+    input::{self, EventBucket},
+    node::pbr::lines::OverlayLines,
+    raycast::{Ray, Raycast},
+};
+use derivative::Derivative;
+use rendy::init::winit::event::{
+    ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent,
+};
+use specs::prelude::*;
+
+/// Length of the handles, as a fraction of their distance to the camera
+const SCREEN_SIZE: f32 = 0.15;
+/// How close to a handle the cursor has to be to grab it, as a fraction of its length
+const PICK_TOLERANCE: f32 = 0.08;
+const RING_SEGMENTS: usize = 48;
+const AXIS_COLORS: [[f32; 4]; 3] = [
+    [0.9, 0.15, 0.15, 1.0],
+    [0.15, 0.9, 0.15, 1.0],
+    [0.2, 0.35, 1.0, 1.0],
+];
+const ACTIVE_COLOR: [f32; 4] = [1.0, 0.85, 0.1, 1.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Derivative)]
+#[derivative(Default)]
+pub enum GizmoMode {
+    #[derivative(Default)]
+    Translate,
+    Rotate,
+    Scale,
+}
+
+impl GizmoMode {
+    fn next(self) -> Self {
+        match self {
+            GizmoMode::Translate => GizmoMode::Rotate,
+            GizmoMode::Rotate => GizmoMode::Scale,
+            GizmoMode::Scale => GizmoMode::Translate,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Drag {
+    axis: usize,
+    /// The local transform of the entity when the drag started
+    start: components::Transform,
+    /// The origin of the entity when the drag started
+    center: nalgebra::Point3<f32>,
+    /// Where the handle was grabbed: on the axis for translating and scaling, on the plane
+    /// around it for rotating
+    grab: nalgebra::Point3<f32>,
+    /// From world space to the space of the entity's parent, which its transform is in
+    to_parent: nalgebra::Matrix4<f32>,
+}
+
+#[derive(Debug, Default)]
+pub struct Gizmo {
+    pub selected: Option<Entity>,
+    pub mode: GizmoMode,
+    /// Axis of the handle under the cursor
+    hovered: Option<usize>,
+    drag: Option<Drag>,
+}
+
+impl Gizmo {
+    pub fn is_dragging(&self) -> bool {
+        self.drag.is_some()
+    }
+
+    /// Stops dragging, recording the edit the drag made to be undone
+    fn end_drag(
+        &mut self,
+        transforms: &WriteStorage<'_, components::Transform>,
+        commands: &mut CommandStack,
+    ) {
+        if let (Some(drag), Some(entity)) = (self.drag.take(), self.selected) {
+            if let Some(transform) = transforms.get(entity) {
+                if *transform != drag.start {
+                    commands.push(Command::Transform {
+                        entity,
+                        before: drag.start,
+                        after: transform.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Axis of the handle being dragged, or else the one under the cursor
+    fn active_axis(&self) -> Option<usize> {
+        self.drag.as_ref().map(|drag| drag.axis).or(self.hovered)
+    }
+}
+
+fn world_axis(index: usize) -> nalgebra::Vector3<f32> {
+    nalgebra::Vector3::ith(index, 1.0)
+}
+
+fn gizmo_size(center: &nalgebra::Point3<f32>, camera_pos: &nalgebra::Point3<f32>) -> f32 {
+    (center - camera_pos).norm() * SCREEN_SIZE
+}
+
+/// Where the closest points of the line through `origin` along `direction` and `ray` are,
+/// as the distance along the line and along the ray, and the distance between them. `None` if
+/// they are parallel.
+fn closest_to_line(
+    ray: &Ray,
+    origin: &nalgebra::Point3<f32>,
+    direction: &nalgebra::Vector3<f32>,
+) -> Option<(f32, f32, f32)> {
+    let r = origin - ray.origin;
+    let b = direction.dot(ray.direction.as_ref());
+    let denom = 1.0 - b * b;
+    if denom < 1e-6 {
+        return None;
+    }
+    let c = direction.dot(&r);
+    let f = ray.direction.dot(&r);
+    let along_line = (b * f - c) / denom;
+    let along_ray = f + along_line * b;
+    let distance = (r + direction * along_line - ray.direction.as_ref() * along_ray).norm();
+    Some((along_line, along_ray, distance))
+}
+
+/// Where `ray` crosses the plane through `center` facing `normal`, and how far along the ray
+fn plane_hit(
+    ray: &Ray,
+    center: &nalgebra::Point3<f32>,
+    normal: &nalgebra::Vector3<f32>,
+) -> Option<(nalgebra::Point3<f32>, f32)> {
+    let denom = normal.dot(ray.direction.as_ref());
+    if denom.abs() < 1e-6 {
+        return None;
+    }
+    let t = normal.dot(&(center - ray.origin)) / denom;
+    if t < 0.0 {
+        None
+    } else {
+        Some((ray.at(t), t))
+    }
+}
+
+/// Where `ray` grabs the handle around or along `axis`
+fn grab_point(
+    mode: GizmoMode,
+    ray: &Ray,
+    center: &nalgebra::Point3<f32>,
+    axis: &nalgebra::Vector3<f32>,
+) -> Option<nalgebra::Point3<f32>> {
+    match mode {
+        GizmoMode::Rotate => plane_hit(ray, center, axis).map(|(point, _)| point),
+        GizmoMode::Translate | GizmoMode::Scale => {
+            closest_to_line(ray, center, axis).map(|(along, _, _)| center + axis * along)
+        }
+    }
+}
+
+/// The axis of the handle nearest to the camera which `ray` passes close enough to
+fn hit_test(
+    mode: GizmoMode,
+    ray: &Ray,
+    center: &nalgebra::Point3<f32>,
+    size: f32,
+) -> Option<usize> {
+    let tolerance = size * PICK_TOLERANCE;
+    (0..3)
+        .filter_map(|index| {
+            let axis = world_axis(index);
+            match mode {
+                GizmoMode::Rotate => plane_hit(ray, center, &axis)
+                    .filter(|(point, _)| ((point - center).norm() - size).abs() <= tolerance)
+                    .map(|(_, along_ray)| (index, along_ray)),
+                GizmoMode::Translate | GizmoMode::Scale => closest_to_line(ray, center, &axis)
+                    .filter(|&(along, along_ray, distance)| {
+                        along >= 0.0 && along <= size && along_ray >= 0.0 && distance <= tolerance
+                    })
+                    .map(|(_, along_ray, _)| (index, along_ray)),
+            }
+        })
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(index, _)| index)
+}
+
+/// The local transform of the dragged entity with the cursor at `ray`
+fn dragged_transform(mode: GizmoMode, drag: &Drag, ray: &Ray) -> Option<components::Transform> {
+    let axis = world_axis(drag.axis);
+    let point = grab_point(mode, ray, &drag.center, &axis)?;
+    let to_parent = |v: nalgebra::Vector3<f32>| (drag.to_parent * v.to_homogeneous()).xyz();
+    let start = &drag.start.0;
+    let mut transform = drag.start.clone();
+    match mode {
+        GizmoMode::Translate => {
+            transform.0.isometry.translation.vector += to_parent(point - drag.grab);
+        }
+        GizmoMode::Rotate => {
+            let from = drag.grab - drag.center;
+            let to = point - drag.center;
+            let angle = axis.dot(&from.cross(&to)).atan2(from.dot(&to));
+            let rotation = nalgebra::UnitQuaternion::from_axis_angle(
+                &nalgebra::Unit::new_normalize(to_parent(axis)),
+                angle,
+            );
+            transform.0.isometry.rotation = rotation * start.isometry.rotation;
+        }
+        GizmoMode::Scale => {
+            let from = (drag.grab - drag.center).dot(&axis);
+            let to = (point - drag.center).dot(&axis);
+            if from.abs() < 1e-6 {
+                return None;
+            }
+            transform.0 = nalgebra::Similarity3::from_parts(
+                start.isometry.translation,
+                start.isometry.rotation,
+                start.scaling() * (to / from).max(0.01),
+            );
+        }
+    }
+    Some(transform)
+}
+
+/// Selects entities, switches modes and drags the handles. Runs before the camera input,
+/// which leaves the left mouse button alone while a handle is dragged.
+pub struct GizmoSystem;
+
+impl<'a> System<'a> for GizmoSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, EventBucket>,
+        Read<'a, input::InputState>,
+        Read<'a, Raycast>,
+        Write<'a, Gizmo>,
+        Write<'a, CommandStack>,
+        WriteStorage<'a, components::Transform>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::Mesh>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            events,
+            input,
+            raycast,
+            mut gizmo,
+            mut commands,
+            mut transforms,
+            globals,
+            meshes,
+        ): Self::SystemData,
+    ) {
+        if let Some(selected) = gizmo.selected {
+            if !entities.is_alive(selected) {
+                gizmo.selected = None;
+                gizmo.drag = None;
+            }
+        }
+
+        let mut input = (*input).clone();
+        for event in events.0.iter() {
+            let event = match event {
+                Event::WindowEvent { event, .. } => event,
+                _ => continue,
+            };
+            input.update_with_window_event(event);
+            match event {
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            virtual_keycode: Some(key),
+                            state: ElementState::Pressed,
+                            modifiers,
+                            ..
+                        },
+                    ..
+                } => match key {
+                    VirtualKeyCode::N => {
+                        let selectable = (&entities, &meshes, &transforms)
+                            .join()
+                            .map(|(entity, _, _)| entity)
+                            .collect::<Vec<_>>();
+                        let current = gizmo
+                            .selected
+                            .and_then(|selected| selectable.iter().position(|&e| e == selected));
+                        // Steps through the entities, and nothing between the last and first
+                        let next = match (current, modifiers.shift) {
+                            (None, false) => Some(0),
+                            (None, true) => selectable.len().checked_sub(1),
+                            (Some(index), false) => Some(index + 1),
+                            (Some(index), true) => index.checked_sub(1),
+                        };
+                        gizmo.end_drag(&transforms, &mut commands);
+                        gizmo.selected = next.and_then(|index| selectable.get(index).cloned());
+                        gizmo.hovered = None;
+                    }
+                    VirtualKeyCode::T => {
+                        gizmo.end_drag(&transforms, &mut commands);
+                        gizmo.mode = gizmo.mode.next();
+                        gizmo.hovered = None;
+                        log::info!("Gizmo mode: {:?}", gizmo.mode);
+                    }
+                    _ => (),
+                },
+                WindowEvent::MouseInput {
+                    state,
+                    button: MouseButton::Left,
+                    modifiers,
+                    ..
+                } => match state {
+                    ElementState::Pressed if !modifiers.ctrl && !modifiers.alt => {
+                        let selected = gizmo.selected;
+                        let axis = gizmo.hovered;
+                        let mode = gizmo.mode;
+                        gizmo.drag = selected
+                            .and_then(|entity| {
+                                Some((transforms.get(entity)?, globals.get(entity)?))
+                            })
+                            .and_then(|(transform, global)| {
+                                let axis = axis?;
+                                let center = nalgebra::Point3::from(global.0.column(3).xyz());
+                                let ray = raycast.cursor_ray(input.mouse.pos)?;
+                                let grab = grab_point(mode, &ray, &center, &world_axis(axis))?;
+                                let parent = global.0 * transform.0.inverse().to_homogeneous();
+                                Some(Drag {
+                                    axis,
+                                    start: transform.clone(),
+                                    center,
+                                    grab,
+                                    to_parent: parent
+                                        .try_inverse()
+                                        .unwrap_or_else(nalgebra::Matrix4::identity),
+                                })
+                            });
+                    }
+                    ElementState::Released => gizmo.end_drag(&transforms, &mut commands),
+                    _ => (),
+                },
+                WindowEvent::CursorMoved { .. } => {
+                    let (selected, ray) =
+                        match (gizmo.selected, raycast.cursor_ray(input.mouse.pos)) {
+                            (Some(selected), Some(ray)) => (selected, ray),
+                            _ => continue,
+                        };
+                    let mode = gizmo.mode;
+                    if let Some(drag) = gizmo.drag.as_ref() {
+                        if let Some(transform) = dragged_transform(mode, drag, &ray) {
+                            if let Some(t) = transforms.get_mut(selected) {
+                                *t = transform;
+                            }
+                        }
+                    } else if let (Some(global), Some(camera)) =
+                        (globals.get(selected), raycast.camera.as_ref())
+                    {
+                        let center = nalgebra::Point3::from(global.0.column(3).xyz());
+                        let size = gizmo_size(&center, &camera.camera_pos);
+                        gizmo.hovered = hit_test(mode, &ray, &center, size);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Draws the handles around the selected entity, where it is after this dispatch moved it.
+pub struct GizmoDrawSystem;
+
+impl<'a> System<'a> for GizmoDrawSystem {
+    type SystemData = (
+        Read<'a, Gizmo>,
+        Read<'a, Raycast>,
+        ReadStorage<'a, components::GlobalTransform>,
+        Write<'a, OverlayLines>,
+    );
+
+    fn run(&mut self, (gizmo, raycast, globals, mut lines): Self::SystemData) {
+        let lines = &mut lines.0;
+        lines.clear();
+
+        let (global, camera) = match (
+            gizmo.selected.and_then(|selected| globals.get(selected)),
+            raycast.camera.as_ref(),
+        ) {
+            (Some(global), Some(camera)) => (global, camera),
+            _ => return,
+        };
+        let center = nalgebra::Point3::from(global.0.column(3).xyz());
+        let size = gizmo_size(&center, &camera.camera_pos);
+
+        for index in 0..3 {
+            let color = if gizmo.active_axis() == Some(index) {
+                ACTIVE_COLOR
+            } else {
+                AXIS_COLORS[index]
+            };
+            let axis = world_axis(index);
+            let (u, v) = (world_axis((index + 1) % 3), world_axis((index + 2) % 3));
+            let tip = center + axis * size;
+            match gizmo.mode {
+                GizmoMode::Translate => {
+                    lines.line(center, tip, color);
+                    let base = tip - axis * (size * 0.2);
+                    for side in [u, -u, v, -v].iter() {
+                        lines.line(tip, base + side * (size * 0.07), color);
+                    }
+                }
+                GizmoMode::Scale => {
+                    lines.line(center, tip, color);
+                    let half = size * 0.06;
+                    let corner = |a: f32, b: f32, c: f32| tip + axis * a + u * b + v * c;
+                    lines.frustum(
+                        &[
+                            corner(-half, -half, -half),
+                            corner(-half, half, -half),
+                            corner(-half, half, half),
+                            corner(-half, -half, half),
+                            corner(half, -half, -half),
+                            corner(half, half, -half),
+                            corner(half, half, half),
+                            corner(half, -half, half),
+                        ],
+                        color,
+                    );
+                }
+                GizmoMode::Rotate => {
+                    let point = |i: usize| {
+                        let angle = i as f32 / RING_SEGMENTS as f32 * std::f32::consts::PI * 2.0;
+                        center + (u * angle.cos() + v * angle.sin()) * size
+                    };
+                    for i in 0..RING_SEGMENTS {
+                        lines.line(point(i), point(i + 1), color);
+                    }
+                }
+            }
+        }
+    }
+}
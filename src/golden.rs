@@ -0,0 +1,156 @@
+//! Golden image tests. `--golden` draws the scene into a cube around the active camera once
+//! its environment is filtered, and compares the panorama against the one stored for the
+//! scene in `assets/golden`, exiting with an error if too many pixels differ. `--bless` writes
+//! the panorama as the scene's golden image instead, to accept a change in shading.
+//!
+//! Scenes checked this way should hold still: anything spinning or oscillating is drawn
+//! wherever it happens to be when the environment is done.
+use std::path::{Path, PathBuf};
+
+use derivative::Derivative;
+use rendy::{
+    command::{Families, QueueId},
+    factory::Factory,
+};
+use serde::Deserialize;
+
+use rendy::hal;
+
+use crate::{asset, node};
+
+/// Directory of the golden images, relative to the application root
+pub const DIR: &str = "assets/golden";
+
+/// Size of the cube faces the panorama is made of, which makes it twice as tall and four times
+/// as wide
+const CAPTURE_RES: u32 = 256;
+
+/// Whether to compare the scene against its golden image or replace it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Compare,
+    Bless,
+}
+
+/// How far the panorama may be from the golden image before the test fails.
+#[derive(Debug, Clone, Copy, Deserialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct GoldenTolerance {
+    /// Largest difference of a pixel's channels, after tonemapping and encoding them as sRGB,
+    /// for it to count as the same. Roughly 5 steps of an 8 bit channel by default.
+    #[derivative(Default(value = "0.02"))]
+    pub max_difference: f32,
+    /// Fraction of the pixels which may differ by more.
+    #[derivative(Default(value = "0.001"))]
+    pub max_differing: f32,
+}
+
+/// Path of the golden image of a scene config, named after its file.
+pub fn golden_path(scene: &str) -> PathBuf {
+    let stem = Path::new(scene)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("scene");
+    Path::new(&crate::application_root_dir())
+        .join(DIR)
+        .join(format!("{}.exr", stem))
+}
+
+/// Draws the scene as a panorama from the active camera and compares it against the image at `path`, or writes it
+/// there when blessing. Logs the result, writes the panorama next to the golden image when
+/// they differ, and returns whether the test passed.
+pub fn check<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    world: &mut specs::World,
+    settings: &crate::PbrGraphSettings,
+    (mode, path): (Mode, &Path),
+    tolerance: &GoldenTolerance,
+) -> Result<bool, failure::Error> {
+    let (camera, position) = crate::active_camera(world)?;
+    let mut renderer = node::pbr::capture::CubeRenderer::new(
+        factory,
+        families,
+        queue,
+        world,
+        settings,
+        CAPTURE_RES,
+    )?;
+    let faces = renderer.render(factory, families, world, position, &camera);
+    renderer.dispose(factory, world);
+    let faces = faces?;
+
+    let height = CAPTURE_RES * 2;
+    let width = height * 2;
+    let texels = node::pbr::capture::to_equirectangular(&faces, height);
+
+    if mode == Mode::Bless {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        asset::save_exr(path, width, height, &texels)?;
+        log::info!("Saved golden image to {:?}", path);
+        return Ok(true);
+    }
+
+    if !path.exists() {
+        failure::bail!(
+            "No golden image at {:?}, run with --bless to write one",
+            path
+        );
+    }
+    let (golden_width, golden_height, golden) = asset::load_exr(path)?;
+    if (golden_width, golden_height) != (width, height) {
+        failure::bail!(
+            "The golden image at {:?} is {}x{}, expected {}x{}",
+            path,
+            golden_width,
+            golden_height,
+            width,
+            height
+        );
+    }
+
+    let mut differing = 0;
+    let mut max_difference = 0.0f32;
+    for (texel, golden) in texels.iter().zip(golden.iter()) {
+        let difference = (0..3)
+            .map(|c| (perceived(texel[c]) - perceived(golden.repr[c])).abs())
+            .fold(0.0, f32::max);
+        max_difference = max_difference.max(difference);
+        if difference > tolerance.max_difference {
+            differing += 1;
+        }
+    }
+    let fraction = differing as f32 / texels.len() as f32;
+    if fraction <= tolerance.max_differing {
+        log::info!(
+            "Golden image test passed, {:.3}% of pixels differ, by up to {:.3}",
+            fraction * 100.0,
+            max_difference
+        );
+        return Ok(true);
+    }
+
+    let actual = path.with_extension("actual.exr");
+    asset::save_exr(&actual, width, height, &texels)?;
+    log::error!(
+        "Golden image test failed, {:.3}% of pixels differ by more than {:.3}, allowing {:.3}%, \
+         by up to {:.3}. Saved the scene to {:?}",
+        fraction * 100.0,
+        tolerance.max_difference,
+        tolerance.max_differing * 100.0,
+        max_difference,
+        actual
+    );
+    Ok(false)
+}
+
+/// A linear channel as it would roughly be seen: tonemapped into 0 to 1, then encoded as sRGB,
+/// so differences in dark and bright areas weigh about as much as they look.
+fn perceived(c: f32) -> f32 {
+    let c = c.max(0.0);
+    asset::linear_to_srgb(c / (1.0 + c))
+}
@@ -0,0 +1,274 @@
+//! Descriptions of the render graphs as they are built, written out by `--dump-graph` to
+//! inspect the order of the passes and what each one reads and writes. Rendy's graph builder
+//! can't be inspected once nodes are added, so images, buffers and nodes are added through a
+//! `GraphDescription`, which records them on the way.
+//!
+//! Nodes are only ordered by the explicit dependencies recorded here, and by the images and
+//! buffers they access, which rendy orders them by as well.
+use rendy::{
+    graph::{BufferId, GraphBuilder, ImageId, NodeBuilder, NodeId},
+    hal,
+};
+use serde::Serialize;
+
+use std::{fmt::Write as _, path::Path};
+
+#[derive(Debug, Serialize)]
+pub struct ImageDescription {
+    pub name: String,
+    pub size: [u32; 3],
+    pub levels: u8,
+    pub format: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BufferDescription {
+    pub name: String,
+    pub size: u64,
+}
+
+/// An image or buffer accessed by a node
+#[derive(Debug, Serialize)]
+pub struct ResourceAccess {
+    pub name: String,
+    /// Vulkan style access flags, e.g. `SHADER_READ | SHADER_WRITE`
+    pub access: String,
+    pub writes: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeDescription {
+    pub name: String,
+    pub images: Vec<ResourceAccess>,
+    pub buffers: Vec<ResourceAccess>,
+    /// Names of the nodes this one was explicitly made to run after
+    pub dependencies: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GraphDescription {
+    pub name: String,
+    pub images: Vec<ImageDescription>,
+    pub buffers: Vec<BufferDescription>,
+    /// In the order they were added
+    pub nodes: Vec<NodeDescription>,
+    #[serde(skip)]
+    image_ids: Vec<ImageId>,
+    #[serde(skip)]
+    buffer_ids: Vec<BufferId>,
+    #[serde(skip)]
+    node_ids: Vec<NodeId>,
+}
+
+impl GraphDescription {
+    pub fn new(name: &str) -> Self {
+        GraphDescription {
+            name: name.to_owned(),
+            images: Vec::new(),
+            buffers: Vec::new(),
+            nodes: Vec::new(),
+            image_ids: Vec::new(),
+            buffer_ids: Vec::new(),
+            node_ids: Vec::new(),
+        }
+    }
+
+    /// Creates an image in `builder`, recording it under `name`.
+    pub fn create_image<B: hal::Backend, T: ?Sized>(
+        &mut self,
+        builder: &mut GraphBuilder<B, T>,
+        name: &str,
+        kind: hal::image::Kind,
+        levels: hal::image::Level,
+        format: hal::format::Format,
+        clear: Option<hal::command::ClearValue>,
+    ) -> ImageId {
+        let id = builder.create_image(kind, levels, format, clear);
+        let extent = kind.extent();
+        self.image_ids.push(id);
+        self.images.push(ImageDescription {
+            name: name.to_owned(),
+            size: [extent.width, extent.height, kind.num_layers() as u32],
+            levels,
+            format: format!("{:?}", format),
+        });
+        id
+    }
+
+    /// Creates a buffer in `builder`, recording it under `name`.
+    pub fn create_buffer<B: hal::Backend, T: ?Sized>(
+        &mut self,
+        builder: &mut GraphBuilder<B, T>,
+        name: &str,
+        size: u64,
+    ) -> BufferId {
+        let id = builder.create_buffer(size);
+        self.buffer_ids.push(id);
+        self.buffers.push(BufferDescription {
+            name: name.to_owned(),
+            size,
+        });
+        id
+    }
+
+    /// Adds a node to `builder`, recording it under `name` along with what it accesses.
+    pub fn add_node<B, T, N>(
+        &mut self,
+        builder: &mut GraphBuilder<B, T>,
+        name: &str,
+        node: N,
+    ) -> NodeId
+    where
+        B: hal::Backend,
+        T: ?Sized,
+        N: NodeBuilder<B, T> + 'static,
+    {
+        let write_image = hal::image::Access::COLOR_ATTACHMENT_WRITE
+            | hal::image::Access::DEPTH_STENCIL_ATTACHMENT_WRITE
+            | hal::image::Access::TRANSFER_WRITE
+            | hal::image::Access::SHADER_WRITE
+            | hal::image::Access::HOST_WRITE
+            | hal::image::Access::MEMORY_WRITE;
+        let write_buffer = hal::buffer::Access::TRANSFER_WRITE
+            | hal::buffer::Access::SHADER_WRITE
+            | hal::buffer::Access::HOST_WRITE
+            | hal::buffer::Access::MEMORY_WRITE;
+        let images = node
+            .images()
+            .into_iter()
+            .map(|(id, access)| ResourceAccess {
+                name: self.image_name(id),
+                access: format!("{:?}", access.access),
+                writes: access.access.intersects(write_image),
+            })
+            .collect();
+        let buffers = node
+            .buffers()
+            .into_iter()
+            .map(|(id, access)| ResourceAccess {
+                name: self.buffer_name(id),
+                access: format!("{:?}", access.access),
+                writes: access.access.intersects(write_buffer),
+            })
+            .collect();
+        let dependencies = node
+            .dependencies()
+            .into_iter()
+            .map(|id| self.node_name(id))
+            .collect();
+
+        let id = builder.add_node(node);
+        self.node_ids.push(id);
+        self.nodes.push(NodeDescription {
+            name: name.to_owned(),
+            images,
+            buffers,
+            dependencies,
+        });
+        id
+    }
+
+    fn image_name(&self, id: ImageId) -> String {
+        match self.image_ids.iter().position(|&i| i == id) {
+            Some(index) => self.images[index].name.clone(),
+            None => format!("{:?}", id),
+        }
+    }
+
+    fn buffer_name(&self, id: BufferId) -> String {
+        match self.buffer_ids.iter().position(|&i| i == id) {
+            Some(index) => self.buffers[index].name.clone(),
+            None => format!("{:?}", id),
+        }
+    }
+
+    fn node_name(&self, id: NodeId) -> String {
+        match self.node_ids.iter().position(|&i| i == id) {
+            Some(index) => self.nodes[index].name.clone(),
+            None => format!("{:?}", id),
+        }
+    }
+
+    /// The graph as a cluster of a DOT digraph. Nodes are boxes and resources ellipses, with
+    /// an edge from each resource to the nodes reading it and from each node to the
+    /// resources it writes. Explicit dependencies are dashed.
+    fn write_dot(&self, out: &mut String, index: usize) -> std::fmt::Result {
+        let node = |name: &str| format!("\"{}/{}\"", self.name, name);
+        writeln!(out, "  subgraph cluster_{} {{", index)?;
+        writeln!(out, "    label = \"{}\";", self.name)?;
+        for image in self.images.iter() {
+            writeln!(
+                out,
+                "    {} [shape = ellipse, label = \"{}\\n{}x{}x{} {}\"];",
+                node(&image.name),
+                image.name,
+                image.size[0],
+                image.size[1],
+                image.size[2],
+                image.format
+            )?;
+        }
+        for buffer in self.buffers.iter() {
+            writeln!(
+                out,
+                "    {} [shape = ellipse, label = \"{}\\n{} bytes\"];",
+                node(&buffer.name),
+                buffer.name,
+                buffer.size
+            )?;
+        }
+        for (order, desc) in self.nodes.iter().enumerate() {
+            writeln!(
+                out,
+                "    {} [shape = box, label = \"{}: {}\"];",
+                node(&desc.name),
+                order,
+                desc.name
+            )?;
+            for access in desc.images.iter().chain(desc.buffers.iter()) {
+                let (from, to) = if access.writes {
+                    (&desc.name, &access.name)
+                } else {
+                    (&access.name, &desc.name)
+                };
+                writeln!(
+                    out,
+                    "    {} -> {} [label = \"{}\"];",
+                    node(from),
+                    node(to),
+                    access.access
+                )?;
+            }
+            for dependency in desc.dependencies.iter() {
+                writeln!(
+                    out,
+                    "    {} -> {} [style = dashed];",
+                    node(dependency),
+                    node(&desc.name)
+                )?;
+            }
+        }
+        writeln!(out, "  }}")
+    }
+}
+
+/// Writes the descriptions of `graphs` to `path`, as JSON if its extension is `.json` and as
+/// a DOT digraph otherwise.
+pub fn write(path: &Path, graphs: &[&GraphDescription]) -> Result<(), failure::Error> {
+    let is_json = path
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("json"));
+    let contents = if is_json {
+        serde_json::to_string_pretty(graphs)?
+    } else {
+        let mut dot = String::from("digraph render_graphs {\n");
+        for (index, graph) in graphs.iter().enumerate() {
+            graph.write_dot(&mut dot, index)?;
+        }
+        dot.push_str("}\n");
+        dot
+    };
+    std::fs::write(path, contents)?;
+    log::info!("Wrote the render graphs to {:?}", path);
+    Ok(())
+}
@@ -1,12 +1,85 @@
 use derivative::Derivative;
 use rendy::init::winit::{
     self,
+    dpi::{LogicalSize, PhysicalSize},
     event::{ElementState, Event, ModifiersState, MouseButton, WindowEvent},
+    window::{Window, WindowId},
 };
 
 #[derive(Default)]
 pub struct EventBucket(pub Vec<Event<()>>);
 
+/// Changes to the window requested by systems, applied by the main loop after each dispatch
+#[derive(Debug, Default)]
+pub struct WindowControl {
+    /// `Some(true)` to grab and hide the cursor, `Some(false)` to release and show it again
+    pub grab_cursor: Option<bool>,
+}
+
+/// Size of the main window, kept up to date by `systems::ViewportSystem`. Render targets and
+/// camera aspects are derived from its physical size, while cursor positions are logical
+/// like every size winit reports, so they're compared against `logical_size`.
+#[derive(Debug, Clone, Copy)]
+pub struct ViewportInfo {
+    pub window_id: WindowId,
+    pub physical_size: PhysicalSize,
+    /// Physical pixels per logical pixel
+    pub scale_factor: f64,
+}
+
+impl ViewportInfo {
+    pub fn new(window: &Window) -> Self {
+        let scale_factor = window.hidpi_factor();
+        ViewportInfo {
+            window_id: window.id(),
+            physical_size: window.inner_size().to_physical(scale_factor),
+            scale_factor,
+        }
+    }
+
+    pub fn logical_size(&self) -> LogicalSize {
+        self.physical_size.to_logical(self.scale_factor)
+    }
+
+    /// False while the window is minimized
+    pub fn has_area(&self) -> bool {
+        self.physical_size.width > 0.0 && self.physical_size.height > 0.0
+    }
+
+    pub fn aspect(&self) -> f32 {
+        (self.physical_size.width / self.physical_size.height) as f32
+    }
+
+    /// The size the scene is rendered at before it's tonemapped to the window
+    pub fn render_size(&self, render_scale: f64) -> PhysicalSize {
+        PhysicalSize::new(
+            self.physical_size.width * render_scale,
+            self.physical_size.height * render_scale,
+        )
+    }
+
+    /// Returns whether the size changed. Events of other windows are ignored.
+    pub fn update_with_window_event(&mut self, window_id: WindowId, event: &WindowEvent) -> bool {
+        if window_id != self.window_id {
+            return false;
+        }
+        let old_size = self.physical_size;
+        match *event {
+            WindowEvent::Resized(size) => {
+                self.physical_size = size.to_physical(self.scale_factor);
+            }
+            // Followed by a resize, which may not come if the logical size stays the same
+            WindowEvent::HiDpiFactorChanged(scale_factor) => {
+                let size = self.logical_size();
+                self.scale_factor = scale_factor;
+                self.physical_size = size.to_physical(scale_factor);
+            }
+            _ => (),
+        }
+        self.physical_size != old_size
+    }
+}
+
 #[derive(Derivative, Debug, Clone, Copy)]
 #[derivative(Default)]
 pub struct MouseState {
@@ -24,6 +97,8 @@ pub const ROTATE_SENSITIVITY: f32 = 0.005;
 pub const TRANSLATE_SENSITIVITY: f32 = 0.005;
 pub const ZOOM_MOUSE_SENSITIVITY: f32 = 0.0125;
 pub const ZOOM_SCROLL_SENSITIVITY: f32 = 0.25;
+/// Units per second
+pub const FLY_SPEED: f32 = 2.0;
 pub const EXPOSURE_ADJUST_SENSITIVITY: f32 = 0.1;
 pub const CUBE_ROUGHNESS_SENSITIVITY: f32 = 0.1;
 
@@ -32,12 +107,10 @@ pub const CUBE_ROUGHNESS_SENSITIVITY: f32 = 0.1;
 pub struct InputState {
     pub mouse: MouseState,
     pub modifiers: ModifiersState,
-    #[derivative(Default(value = "winit::dpi::LogicalSize::new(0., 0.)"))]
-    pub window_size: winit::dpi::LogicalSize,
 }
 
 impl InputState {
-    pub fn new(window_size: winit::dpi::LogicalSize) -> Self {
+    pub fn new() -> Self {
         InputState {
             mouse: MouseState {
                 left: ElementState::Released,
@@ -46,12 +119,12 @@ impl InputState {
                 pos: winit::dpi::LogicalPosition::new(0.0, 0.0),
             },
             modifiers: Default::default(),
-            window_size,
         }
     }
 
-    pub fn calc_comparison_factor(&self) -> f32 {
-        self.mouse.pos.x as f32 / self.window_size.width as f32
+    /// Horizontal position of the cursor across the window, from 0 to 1
+    pub fn calc_comparison_factor(&self, viewport: &ViewportInfo) -> f32 {
+        (self.mouse.pos.x / viewport.logical_size().width) as f32
     }
 
     pub fn update_with_window_event(&mut self, event: &WindowEvent) {
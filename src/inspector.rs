@@ -0,0 +1,164 @@
+//! The components of the entity selected with the gizmo, as plain values for a panel to show,
+//! and edits of them coming back from it. Nothing draws a panel yet; a UI reads `Inspector`
+//! after each dispatch and queues its edits with `Inspector::edit`, which are applied and made
+//! undoable in the next dispatch.
+use crate::{
+    asset,
+    command::{Command, CommandStack},
+    components, gizmo,
+};
+use specs::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransformValues {
+    pub translation: [f32; 3],
+    /// Euler angles in radians, as `euler_rotation` is given in scene configs
+    pub rotation: [f32; 3],
+    pub scale: f32,
+}
+
+impl From<&components::Transform> for TransformValues {
+    fn from(transform: &components::Transform) -> Self {
+        let (roll, pitch, yaw) = transform.0.isometry.rotation.euler_angles();
+        TransformValues {
+            translation: transform.0.isometry.translation.vector.into(),
+            rotation: [roll, pitch, yaw],
+            scale: transform.0.scaling(),
+        }
+    }
+}
+
+impl From<&TransformValues> for components::Transform {
+    fn from(values: &TransformValues) -> Self {
+        components::Transform::new(
+            nalgebra::Translation3::new(
+                values.translation[0],
+                values.translation[1],
+                values.translation[2],
+            ),
+            nalgebra::UnitQuaternion::from_euler_angles(
+                values.rotation[0],
+                values.rotation[1],
+                values.rotation[2],
+            ),
+            values.scale,
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MeshValues {
+    pub handle: asset::MeshHandle,
+    pub name: String,
+    /// The material of each primitive of the mesh
+    pub materials: Vec<asset::MaterialHandle>,
+    pub max_instances: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct InspectedEntity {
+    pub entity: Entity,
+    /// Relative to the parent, if the entity has one
+    pub transform: Option<TransformValues>,
+    pub mesh: Option<MeshValues>,
+    pub light: Option<components::Light>,
+}
+
+/// A new value for a component of the inspected entity
+#[derive(Debug, Clone)]
+pub enum InspectorEdit {
+    Transform(TransformValues),
+    Light(components::Light),
+}
+
+#[derive(Debug, Default)]
+pub struct Inspector {
+    /// As of the last dispatch, `None` while nothing is selected
+    pub inspected: Option<InspectedEntity>,
+    edits: Vec<(Entity, InspectorEdit)>,
+}
+
+impl Inspector {
+    /// Queues an edit of the inspected entity. Ignored while nothing is selected.
+    pub fn edit(&mut self, edit: InspectorEdit) {
+        if let Some(inspected) = &self.inspected {
+            self.edits.push((inspected.entity, edit));
+        }
+    }
+}
+
+/// Applies the queued edits, then gathers the components of the selected entity.
+pub struct InspectorSystem;
+
+impl<'a> System<'a> for InspectorSystem {
+    type SystemData = (
+        Read<'a, gizmo::Gizmo>,
+        Write<'a, Inspector>,
+        Write<'a, CommandStack>,
+        Read<'a, asset::MeshStorage>,
+        Read<'a, asset::PrimitiveStorage>,
+        WriteStorage<'a, components::Transform>,
+        WriteStorage<'a, components::Light>,
+        ReadStorage<'a, components::Mesh>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            gizmo,
+            mut inspector,
+            mut commands,
+            mesh_storage,
+            primitive_storage,
+            mut transforms,
+            mut lights,
+            meshes,
+        ): Self::SystemData,
+    ) {
+        for (entity, edit) in std::mem::replace(&mut inspector.edits, Vec::new()) {
+            match edit {
+                InspectorEdit::Transform(values) => {
+                    if let Some(transform) = transforms.get_mut(entity) {
+                        let before = transform.clone();
+                        *transform = (&values).into();
+                        commands.push(Command::Transform {
+                            entity,
+                            before,
+                            after: transform.clone(),
+                        });
+                    }
+                }
+                InspectorEdit::Light(light) => {
+                    if let Some(current) = lights.get_mut(entity) {
+                        let before = *current;
+                        *current = light;
+                        commands.push(Command::Light {
+                            entity,
+                            before,
+                            after: light,
+                        });
+                    }
+                }
+            }
+        }
+
+        inspector.inspected = gizmo.selected.map(|entity| InspectedEntity {
+            entity,
+            transform: transforms.get(entity).map(TransformValues::from),
+            mesh: meshes.get(entity).map(|mesh| {
+                let data = &mesh_storage.0[mesh.0];
+                MeshValues {
+                    handle: mesh.0,
+                    name: data.name.clone(),
+                    materials: data
+                        .primitives
+                        .iter()
+                        .map(|&primitive| primitive_storage.0[primitive].mat)
+                        .collect(),
+                    max_instances: data.max_instances,
+                }
+            }),
+            light: lights.get(entity).cloned(),
+        });
+    }
+}
@@ -0,0 +1,284 @@
+//! Baking of lightmaps, by drawing the scene into a cube from every texel of a mesh's
+//! lightmap and integrating the light arriving over the hemisphere above it. Every texel
+//! waits for its own render, so this is only practical for simple scenes and small maps.
+use rendy::{
+    command::{Families, QueueId},
+    factory::{Factory, ImageState},
+};
+
+use rendy::hal;
+
+use crate::{
+    asset, components,
+    node::{env_preprocess::debug::Subresource, pbr::capture},
+};
+
+/// How far texels are moved off the surface along its normal, so the surface itself isn't
+/// caught by the near plane
+const NORMAL_OFFSET: f32 = 0.005;
+
+/// Size of the cube faces drawn at each texel
+const CUBE_RESOLUTION: u32 = 32;
+
+/// A point on the surface of a mesh covered by a lightmap texel, in world space.
+struct SurfacePoint {
+    position: nalgebra::Point3<f32>,
+    normal: nalgebra::Vector3<f32>,
+}
+
+/// Finds the surface point at the center of every texel covered by the lightmap coordinates
+/// of a mesh's triangles. Where triangles overlap in the lightmap, the last one wins.
+fn rasterize(
+    mesh: &asset::Mesh,
+    primitive_storage: &asset::PrimitiveStorage,
+    transform: &nalgebra::Matrix4<f32>,
+    resolution: u32,
+) -> Vec<Option<SurfacePoint>> {
+    let normal_matrix = transform
+        .fixed_slice::<nalgebra::U3, nalgebra::U3>(0, 0)
+        .clone_owned()
+        .try_inverse()
+        .map(|inverse| inverse.transpose())
+        .unwrap_or_else(nalgebra::Matrix3::identity);
+    let size = resolution as f32;
+
+    let mut texels = (0..resolution * resolution)
+        .map(|_| None)
+        .collect::<Vec<_>>();
+    for primitive in mesh.primitives.iter() {
+        let geometry = &primitive_storage.0[*primitive].geometry;
+        for triangle in geometry.indices.chunks(3) {
+            if triangle.len() < 3 {
+                continue;
+            }
+            let corners = [
+                triangle[0] as usize,
+                triangle[1] as usize,
+                triangle[2] as usize,
+            ];
+            let uv = corners.iter().map(|i| {
+                let [u, v] = geometry.lightmap_uvs[*i];
+                nalgebra::Vector2::new(u * size, v * size)
+            });
+            let uv = uv.collect::<Vec<_>>();
+            let area = (uv[1] - uv[0]).perp(&(uv[2] - uv[0]));
+            if area.abs() < std::f32::EPSILON {
+                continue;
+            }
+
+            let min_x = uv.iter().map(|p| p.x).fold(std::f32::MAX, f32::min);
+            let max_x = uv.iter().map(|p| p.x).fold(std::f32::MIN, f32::max);
+            let min_y = uv.iter().map(|p| p.y).fold(std::f32::MAX, f32::min);
+            let max_y = uv.iter().map(|p| p.y).fold(std::f32::MIN, f32::max);
+            let x_range = (min_x.floor().max(0.0) as u32)..(max_x.ceil().min(size) as u32);
+            let y_range = (min_y.floor().max(0.0) as u32)..(max_y.ceil().min(size) as u32);
+            for y in y_range {
+                for x in x_range.clone() {
+                    let p = nalgebra::Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+                    let w0 = (uv[2] - uv[1]).perp(&(p - uv[1])) / area;
+                    let w1 = (uv[0] - uv[2]).perp(&(p - uv[2])) / area;
+                    let w2 = 1.0 - w0 - w1;
+                    if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                        continue;
+                    }
+
+                    let weights = [w0, w1, w2];
+                    let mut position = nalgebra::Vector3::zeros();
+                    let mut normal = nalgebra::Vector3::zeros();
+                    for (corner, weight) in corners.iter().zip(weights.iter()) {
+                        position += nalgebra::Vector3::from(geometry.positions[*corner]) * *weight;
+                        normal += nalgebra::Vector3::from(geometry.normals[*corner]) * *weight;
+                    }
+                    let normal = (normal_matrix * normal)
+                        .try_normalize(1.0e-6)
+                        .unwrap_or_else(nalgebra::Vector3::y);
+                    let position = nalgebra::Point3::from_homogeneous(
+                        transform * nalgebra::Point3::from(position).to_homogeneous(),
+                    )
+                    .unwrap();
+                    texels[(y * resolution + x) as usize] = Some(SurfacePoint {
+                        position: position + normal * NORMAL_OFFSET,
+                        normal,
+                    });
+                }
+            }
+        }
+    }
+    texels
+}
+
+/// The irradiance arriving from the hemisphere around `normal`, divided by pi like the
+/// irradiance cube map.
+fn hemisphere_irradiance(faces: &[Subresource], normal: &nalgebra::Vector3<f32>) -> [f32; 3] {
+    let mut irradiance = [0.0; 3];
+    for (face, data) in faces.iter().enumerate().take(6) {
+        let (forward, up) = capture::face_basis(face);
+        let right = forward.cross(&up);
+        let size = data.width as f32;
+        for y in 0..data.height {
+            for x in 0..data.width {
+                let a = (x as f32 + 0.5) / size * 2.0 - 1.0;
+                let b = 1.0 - (y as f32 + 0.5) / size * 2.0;
+                let dir = (forward + right * a + up * b).normalize();
+                let cos_theta = dir.dot(normal);
+                if cos_theta <= 0.0 {
+                    continue;
+                }
+                let solid_angle = (2.0 / size).powi(2) / (1.0 + a * a + b * b).powf(1.5);
+                let radiance = data.texels[(y * data.width + x) as usize];
+                for c in 0..3 {
+                    irradiance[c] += radiance[c] * cos_theta * solid_angle;
+                }
+            }
+        }
+    }
+    for c in irradiance.iter_mut() {
+        *c /= std::f32::consts::PI;
+    }
+    irradiance
+}
+
+/// Fills texels no triangle covers with the average of their covered neighbours, a few
+/// texels deep, so bilinear filtering along the edges of UV islands doesn't bleed black in.
+fn dilate(texels: &mut [[f32; 4]], resolution: u32) {
+    let size = resolution as i32;
+    for _ in 0..2 {
+        let source = texels.to_vec();
+        for y in 0..size {
+            for x in 0..size {
+                if source[(y * size + x) as usize][3] > 0.0 {
+                    continue;
+                }
+                let mut sum = [0.0; 4];
+                let mut count = 0.0;
+                for (dx, dy) in (-1..=1).flat_map(|dy| (-1..=1).map(move |dx| (dx, dy))) {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx >= size || ny >= size {
+                        continue;
+                    }
+                    let neighbour = source[(ny * size + nx) as usize];
+                    if neighbour[3] > 0.0 {
+                        for c in 0..3 {
+                            sum[c] += neighbour[c];
+                        }
+                        count += 1.0;
+                    }
+                }
+                if count > 0.0 {
+                    texels[(y * size + x) as usize] =
+                        [sum[0] / count, sum[1] / count, sum[2] / count, 1.0];
+                }
+            }
+        }
+    }
+}
+
+/// Bakes every lightmap of the scene, writes each to its path as OpenEXR and replaces its
+/// texture. Lights are drawn dynamically on top of lightmaps, so they only hold the light
+/// bounced off other surfaces and the sky. Lightmaps loaded before baking light the scene
+/// as well, so baking again adds a bounce.
+pub fn bake_all<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    world: &mut specs::World,
+    settings: &crate::PbrGraphSettings,
+    camera: &components::Camera,
+) -> Result<(), failure::Error> {
+    let camera = components::Camera {
+        znear: NORMAL_OFFSET * 0.5,
+        ..*camera
+    };
+    // Lightmaps of meshes which were unloaded are left as they are
+    let lightmap_indices = {
+        let storage = world.read_resource::<asset::LightmapStorage<B>>();
+        let mesh_storage = world.read_resource::<asset::MeshStorage>();
+        (0..storage.lightmaps.len())
+            .filter(|&index| mesh_storage.0.get(storage.lightmaps[index].mesh).is_some())
+            .collect::<Vec<_>>()
+    };
+
+    let mut renderer =
+        capture::CubeRenderer::new(factory, families, queue, world, settings, CUBE_RESOLUTION)?;
+
+    let baked = lightmap_indices
+        .into_iter()
+        .map(|index| {
+            let (points, resolution) = {
+                let storage = world.read_resource::<asset::LightmapStorage<B>>();
+                let lightmap = &storage.lightmaps[index];
+                let transforms = world.read_storage::<components::GlobalTransform>();
+                let transform = match transforms.get(lightmap.entity) {
+                    Some(transform) => transform.0,
+                    None => nalgebra::Matrix4::identity(),
+                };
+                let mesh_storage = world.read_resource::<asset::MeshStorage>();
+                let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
+                log::info!(
+                    "Baking {}x{} lightmap {:?}",
+                    lightmap.resolution,
+                    lightmap.resolution,
+                    lightmap.path
+                );
+                (
+                    rasterize(
+                        &mesh_storage.0[lightmap.mesh],
+                        &*primitive_storage,
+                        &transform,
+                        lightmap.resolution,
+                    ),
+                    lightmap.resolution,
+                )
+            };
+
+            let mut texels = Vec::with_capacity(points.len());
+            for point in points.iter() {
+                texels.push(match point {
+                    Some(point) => {
+                        let faces =
+                            renderer.render(factory, families, world, point.position, &camera)?;
+                        let [r, g, b] = hemisphere_irradiance(&faces, &point.normal);
+                        [r, g, b, 1.0]
+                    }
+                    None => [0.0; 4],
+                });
+            }
+            dilate(&mut texels, resolution);
+            Ok((index, resolution, texels))
+        })
+        .collect::<Result<Vec<_>, failure::Error>>();
+    renderer.dispose(factory, world);
+    let baked = baked?;
+
+    let mut storage = world.write_resource::<asset::LightmapStorage<B>>();
+    for (index, resolution, texels) in baked {
+        let lightmap = &mut storage.lightmaps[index];
+        if let Some(dir) = lightmap.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        asset::save_exr(&lightmap.path, resolution, resolution, &texels)?;
+        let texels = texels
+            .into_iter()
+            .map(|repr| rendy::texture::pixel::Rgba32Sfloat { repr })
+            .collect::<Vec<_>>();
+        lightmap.texture = Some(
+            rendy::texture::TextureBuilder::new()
+                .with_kind(hal::image::Kind::D2(resolution, resolution, 1, 1))
+                .with_view_kind(hal::image::ViewKind::D2)
+                .with_data_width(resolution)
+                .with_data_height(resolution)
+                .with_data(texels)
+                .build(
+                    ImageState {
+                        queue,
+                        stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                        access: hal::image::Access::SHADER_READ,
+                        layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                    },
+                    factory,
+                )?,
+        );
+        log::info!("Wrote lightmap {:?}", lightmap.path);
+    }
+    Ok(())
+}
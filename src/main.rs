@@ -4,8 +4,8 @@
 )]
 
 use rendy::{
-    command::{Families, Graphics, Supports},
-    factory::{Config, Factory, ImageState},
+    command::{Families, Graphics, QueueId, Supports},
+    factory::{Config, Factory},
     graph::{present::PresentNode, render::*, GraphBuilder},
     init::winit::{
         self,
@@ -15,28 +15,64 @@ use rendy::{
     },
 };
 
-use std::{collections::HashSet, time};
+use std::time;
 
 use rendy::hal;
 
 use specs::prelude::*;
 
 mod asset;
+mod asset_gc;
+mod cli;
+mod command;
 mod components;
+mod draw_stats;
+mod frame_arena;
+mod frame_capture;
+mod furnace;
+mod geometry_pool;
+mod gizmo;
+mod golden;
+mod graph_dump;
 mod input;
+mod inspector;
+mod lightmap;
+mod memory_stats;
+mod mesh_file;
 mod node;
+mod prefab;
+mod probes;
+mod procedural;
+mod profile;
+mod queues;
+mod raycast;
+mod render_frame;
+mod replay;
 mod scene;
+mod scene_manager;
+#[cfg(feature = "scripting")]
+mod script;
+mod settings;
+mod shader;
+mod slot_map;
+mod smoke;
+mod static_batch;
 mod systems;
 mod transform;
+mod upload;
+mod validation;
+mod voxel_gi;
 
-pub const ENV_CUBEMAP_RES: u32 = 512;
-pub const ENV_CUBEMAP_MIP_LEVELS: u8 = 6;
 pub const IRRADIANCE_CUBEMAP_RES: u32 = 64;
 pub const SPEC_CUBEMAP_RES: u32 = 128;
 pub const SPEC_CUBEMAP_MIP_LEVELS: u8 = 6;
 pub const SPEC_BRDF_MAP_RES: u32 = 256;
 pub const MAX_LIGHTS: usize = 32;
 pub const FRAMES_IN_FLIGHT: u32 = 3;
+pub const PANORAMA_FACE_RES: u32 = 1024;
+/// Frames in a row the render graph may fail to run in, and be rebuilt after, before the app
+/// gives up and closes
+pub const MAX_GRAPH_FAILURES: u32 = 3;
 
 #[cfg(feature = "dx12")]
 pub type Backend = rendy::dx12::Backend;
@@ -52,10 +88,11 @@ pub type Backend = rendy::empty::Backend;
 
 #[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
 fn main() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Warn)
-        .filter_module("rendy_pbr", log::LevelFilter::Info)
-        .init();
+    validation::init_logger(
+        env_logger::Builder::from_default_env()
+            .filter_level(log::LevelFilter::Warn)
+            .filter_module("rendy_pbr", log::LevelFilter::Info),
+    );
 
     match err_main() {
         Err(e) => {
@@ -96,35 +133,168 @@ pub fn application_root_dir() -> String {
 
 #[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
 fn err_main() -> Result<(), failure::Error> {
-    #[cfg(feature = "rd")]
-    let mut rd: renderdoc::RenderDoc<renderdoc::V120> =
-        renderdoc::RenderDoc::new().expect("Failed to init renderdoc");
-    #[cfg(feature = "rd")]
-    use renderdoc::prelude::*;
+    // Before rendy, so RenderDoc can hook the device as it is created
+    let frame_capture = frame_capture::FrameCapture::new()?;
+
+    let config = Config {
+        devices: Default::default(),
+        heaps: Default::default(),
+        queues: queues::QueueConfig,
+    };
+
+    let mut args = cli::Args::parse()?;
+    validation::set_abort_on_error(args.abort_on_validation_error);
 
-    let config: Config = Default::default();
+    // A replay starts from the scene and settings its recording started from
+    let mut event_log = replay::EventLog::Live;
+    let mut recorded_settings = None;
+    if let Some(path) = args.replay.as_ref() {
+        let (header, replay) = replay::EventLog::replay(std::path::Path::new(path))?;
+        if header.scene != args.scene {
+            log::info!("Replaying in the recorded scene {}", header.scene);
+        }
+        args.scene = header.scene;
+        // The recording only knows the scene it started in
+        args.more_scenes.clear();
+        args.window_size = None;
+        recorded_settings = Some(header.settings);
+        event_log = replay;
+    }
+
+    // Loaded up front, since it also configures the window
+    let mut scene_config = scene::SceneConfig::from_path(&args.scene)?;
+    // Golden images are compared without the user's preferences, e.g. their filter quality
+    let settings = match (recorded_settings, args.golden) {
+        (Some(settings), _) => settings,
+        (None, Some(_)) => settings::Settings::default(),
+        (None, None) => settings::Settings::load(),
+    };
+    if let Some(quality) = settings.environment_filter_quality {
+        scene_config.environment_filter_quality = vec![quality];
+    }
+    // Command line arguments take precedence over both
+    args.apply(&mut scene_config);
+    let (width, height) = args
+        .window_size
+        .unwrap_or((settings.window.width, settings.window.height));
+    let vsync = args.vsync;
+    let bake_lightmaps = args.bake_lightmaps;
+    // The smoke test runs unattended like a headless replay
+    let headless = args.headless || args.smoke;
+    let smoke = args.smoke;
+    let dump_graph = args.dump_graph.take();
+    let profile_path = args.profile.take();
+    let white_furnace = args.white_furnace;
+    let scenes = std::iter::once(args.scene.clone())
+        .chain(args.more_scenes.drain(..))
+        .collect::<Vec<_>>();
+    let golden = args
+        .golden
+        .map(|mode| (mode, golden::golden_path(&args.scene)));
+    if profile_path.is_some() {
+        profile::enable();
+    }
+
+    if let Some(path) = args.record.as_ref() {
+        let mut settings = settings.clone();
+        settings.window.width = width;
+        settings.window.height = height;
+        event_log = replay::EventLog::record(
+            std::path::Path::new(path),
+            &replay::Header {
+                scene: args.scene.clone(),
+                settings,
+            },
+        )?;
+    }
 
     let event_loop = EventLoop::new();
 
     let window = WindowBuilder::new()
         .with_title("rendy-pbr")
-        .with_inner_size(winit::dpi::LogicalSize::new(1280.0, 960.0));
+        .with_inner_size(winit::dpi::LogicalSize::new(width, height))
+        .with_transparent(scene_config.transparent_window)
+        .with_visible(!headless)
+        .with_maximized(settings.window.mode == settings::WindowMode::Maximized)
+        .with_fullscreen(match settings.window.mode {
+            settings::WindowMode::Fullscreen => Some(winit::window::Fullscreen::Borderless(
+                event_loop.primary_monitor(),
+            )),
+            _ => None,
+        });
+
+    let options = RunOptions {
+        scene_config,
+        scenes,
+        settings,
+        vsync,
+        bake_lightmaps,
+        event_log,
+        headless,
+        dump_graph,
+        profile_path,
+        white_furnace,
+        golden,
+        smoke,
+        frame_capture,
+    };
 
-    let rendy = rendy::init::AnyWindowedRendy::init_auto(&config, window, &event_loop).unwrap();
+    validation::enable_layers();
+    let rendy = match args.backend {
+        Some(backend) => rendy::init::AnyWindowedRendy::init(backend, &config, window, &event_loop),
+        None => rendy::init::AnyWindowedRendy::init_auto(&config, window, &event_loop),
+    }
+    .unwrap();
     rendy::with_any_windowed_rendy!((rendy)
         (factory, families, surface, window) => {
-            run(event_loop, surface, window, factory, families)
+            run(event_loop, surface, window, factory, families, options)
         }
     )
 }
 
+/// What the app is started with besides the window and device, from the command line, the
+/// first scene and the user's settings.
+struct RunOptions {
+    scene_config: scene::SceneConfig,
+    /// Paths of the scenes to switch between, starting with the one of `scene_config`
+    scenes: Vec<String>,
+    settings: settings::Settings,
+    vsync: Option<bool>,
+    bake_lightmaps: bool,
+    event_log: replay::EventLog,
+    headless: bool,
+    dump_graph: Option<String>,
+    profile_path: Option<String>,
+    white_furnace: bool,
+    golden: Option<(golden::Mode, std::path::PathBuf)>,
+    smoke: bool,
+    frame_capture: frame_capture::FrameCapture,
+}
+
 fn run<B: hal::Backend>(
     event_loop: rendy::init::winit::event_loop::EventLoop<()>,
     surface: rendy::wsi::Surface<B>,
     window: Window,
     mut factory: Factory<B>,
     mut families: Families<B>,
+    options: RunOptions,
 ) -> Result<(), failure::Error> {
+    let RunOptions {
+        scene_config,
+        scenes,
+        mut settings,
+        vsync,
+        bake_lightmaps,
+        mut event_log,
+        headless,
+        dump_graph,
+        profile_path,
+        white_furnace,
+        golden,
+        smoke,
+        mut frame_capture,
+    } = options;
+
     // Initialize specs and register components
     let mut world = specs::World::new();
 
@@ -132,20 +302,30 @@ fn run<B: hal::Backend>(
     world.register::<components::GlobalTransform>();
     world.register::<components::Parent>();
     world.register::<components::Mesh>();
+    world.register::<components::LightList>();
+    world.register::<components::NoShadows>();
+    world.register::<components::Static>();
+    world.register::<components::Layers>();
     world.register::<components::Camera>();
     world.register::<components::ActiveCamera>();
+    world.register::<components::DebugWindowCamera>();
     world.register::<components::Light>();
-
-    let scene_config = scene::SceneConfig::from_path("assets/scene.ron")?;
-
-    let input = input::InputState::new(window.inner_size());
+    world.register::<components::Billboard>();
+    world.register::<components::ParticleEmitter>();
+    world.register::<components::Spin>();
+    world.register::<components::Oscillate>();
+    world.register::<components::Orbit>();
+
+    let input = input::InputState::new();
+    let viewport = input::ViewportInfo::new(&window);
     let event_bucket = input::EventBucket(Vec::new());
 
     #[cfg(feature = "rd")]
-    rd.start_frame_capture(std::ptr::null(), std::ptr::null());
+    frame_capture.start("the environment preprocess");
 
-    let size = window.inner_size().to_physical(window.hidpi_factor());
-    let aspect = (size.width / size.height) as f32;
+    let aspect = viewport.aspect();
+    // The scene is rendered at a scaled resolution, then tonemapped to the window's
+    let render_size = viewport.render_size(settings.render_scale());
 
     let align = hal::adapter::PhysicalDevice::limits(factory.physical())
         .min_uniform_buffer_offset_alignment;
@@ -164,365 +344,100 @@ fn run<B: hal::Backend>(
         .as_slice()[0]
         .id();
 
-    // Preprocess steps to load environment map, convert it to a cubemap,
-    // and filter it for use later
-
-    // Equirectangular env map to environment cube map
-
-    let mut preprocessed_environment_data = {
-        let mut env_preprocess_graph_builder =
-            GraphBuilder::<B, node::env_preprocess::Aux<B>>::new();
-
-        let env_cube_faces_img = env_preprocess_graph_builder.create_image(
-            hal::image::Kind::D2(ENV_CUBEMAP_RES, ENV_CUBEMAP_RES * 6, 1, 1),
-            1,
-            hal::format::Format::Rgba32Sfloat,
-            Some(hal::command::ClearValue {
-                color: hal::command::ClearColor {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            }),
-        );
-
-        let equirect_to_faces_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::equirectangular_to_cube_faces::Pipeline::<B>::builder()
-                .into_subpass()
-                .with_color(env_cube_faces_img)
-                .into_pass(),
-        );
-
-        let faces_to_env_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::faces_to_cubemap::FacesToCubemap::<Backend>::builder(
-                vec![env_cube_faces_img],
-                "environment",
-                node::env_preprocess::faces_to_cubemap::CopyMips::GenerateMips,
-            )
-            .with_dependency(equirect_to_faces_pass),
-        );
-
-        // Environment cube map to convolved irradiance cube map
+    let upload_queues = upload::UploadQueues::new(queue, &families);
 
-        let irradiance_cube_faces_img = env_preprocess_graph_builder.create_image(
-            hal::image::Kind::D2(IRRADIANCE_CUBEMAP_RES, IRRADIANCE_CUBEMAP_RES * 6, 1, 1),
-            1,
+    // Halves the memory and bandwidth of the HDR target and the environment intermediates
+    let (color_target_format, rg_target_format) = if scene_config.half_float_targets {
+        (
+            hal::format::Format::Rgba16Sfloat,
+            hal::format::Format::Rg16Sfloat,
+        )
+    } else {
+        (
             hal::format::Format::Rgba32Sfloat,
-            Some(hal::command::ClearValue {
-                color: hal::command::ClearColor {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            }),
-        );
-
-        let env_to_irradiance_faces_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::env_to_irradiance::Pipeline::<B>::builder()
-                .with_dependency(faces_to_env_pass)
-                .into_subpass()
-                .with_color(irradiance_cube_faces_img)
-                .into_pass(),
-        );
-
-        let _irradiance_to_cube_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::faces_to_cubemap::FacesToCubemap::<B>::builder(
-                vec![irradiance_cube_faces_img],
-                "irradiance",
-                node::env_preprocess::faces_to_cubemap::CopyMips::CopyMips(1),
-            )
-            .with_dependency(env_to_irradiance_faces_pass),
-        );
-
-        // Environment cube map to convolved specular cube map with different roughnesses stored in mip levels
-
-        let mut env_to_spec_faces_subpasses = Vec::new();
-        let mut spec_cube_faces_images = Vec::new();
-
-        for mip_level in 0..SPEC_CUBEMAP_MIP_LEVELS {
-            let res = SPEC_CUBEMAP_RES / 2u32.pow(mip_level as u32);
-            let mut subpass = node::env_preprocess::env_to_specular::Pipeline::<B>::builder()
-                .with_dependency(faces_to_env_pass)
-                .into_subpass();
-            let image = env_preprocess_graph_builder.create_image(
-                hal::image::Kind::D2(res, res * 6, 1, 1),
-                1,
-                hal::format::Format::Rgba32Sfloat,
-                Some(hal::command::ClearValue {
-                    color: hal::command::ClearColor {
-                        float32: [0.0, 0.0, 0.0, 1.0],
-                    },
-                }),
-            );
-            subpass.add_color(image);
-            spec_cube_faces_images.push(image);
-            env_to_spec_faces_subpasses.push(subpass);
-        }
-
-        let mut env_to_spec_faces_passes = Vec::new();
-        while !env_to_spec_faces_subpasses.is_empty() {
-            env_to_spec_faces_passes.push(
-                env_preprocess_graph_builder
-                    .add_node(env_to_spec_faces_subpasses.pop().unwrap().into_pass()),
-            );
-        }
-
-        let mut builder = node::env_preprocess::faces_to_cubemap::FacesToCubemap::<B>::builder(
-            spec_cube_faces_images.clone(),
-            "specular",
-            node::env_preprocess::faces_to_cubemap::CopyMips::CopyMips(SPEC_CUBEMAP_MIP_LEVELS),
-        );
-
-        for pass in env_to_spec_faces_passes {
-            builder.add_dependency(pass);
-        }
-
-        let _spec_to_cube_pass = env_preprocess_graph_builder.add_node(builder);
-
-        let spec_brdf_map = env_preprocess_graph_builder.create_image(
-            hal::image::Kind::D2(SPEC_BRDF_MAP_RES, SPEC_BRDF_MAP_RES, 1, 1),
-            1,
             hal::format::Format::Rg32Sfloat,
-            Some(hal::command::ClearValue {
-                color: hal::command::ClearColor {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            }),
-        );
-
-        let brdf_integration_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::integrate_spec_brdf::Pipeline::builder()
-                .into_subpass()
-                .with_color(spec_brdf_map)
-                .into_pass(),
-        );
-
-        let _brdf_to_texture = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::copy_to_texture::CopyToTexture::<Backend>::builder(
-                spec_brdf_map,
-                "spec_brdf",
-            )
-            .with_dependency(brdf_integration_pass),
-        );
-
-        let equirect_file = std::fs::File::open(
-            &std::path::Path::new(&application_root_dir())
-                .join(scene_config.environment_map.clone()),
-        )?;
+        )
+    };
 
-        let equirect_tex = rendy::texture::image::load_from_image(
-            std::io::BufReader::new(equirect_file),
-            rendy::texture::image::ImageTextureConfig {
-                repr: rendy::texture::image::Repr::Float,
-                ..Default::default()
-            },
-        )?
-        .build(
-            ImageState {
-                queue,
-                stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
-                access: hal::image::Access::SHADER_READ,
-                layout: hal::image::Layout::ShaderReadOnlyOptimal,
-            },
+    // Preprocess steps to load environment map and convert it to a cubemap.
+    // Filtering it is started afterwards on the compute queue and runs alongside
+    // the first frames, on a copy of the cubemap so it can be displayed meanwhile.
+    // The pipeline is kept to preprocess environment maps dropped onto the window later.
+    let mut env_pipeline =
+        node::env_preprocess::pipeline::EnvPipeline::builder(color_target_format, rg_target_format)
+            .with_filter_quality(&scene_config.environment_filter_quality)
+            .with_specular_filter(scene_config.specular_filter.clone())
+            .with_env_cubemap_res(scene_config.environment_cubemap.resolution)
+            .with_env_cubemap_mip_levels(scene_config.environment_cubemap.mip_levels)
+            .build(upload_queues, align);
+    let (environment_storage, environment_filter) =
+        env_pipeline.process(&mut factory, &mut families, &scene_config.environment_map)?;
+    let mut environment_filter = Some(environment_filter);
+
+    let env_cube_mip_levels = environment_storage
+        .env_cube
+        .as_ref()
+        .unwrap()
+        .image()
+        .levels();
+    // Checked once the environment is filtered, against the radiance of the environment
+    let mut furnace_check = match (white_furnace, &scene_config.environment_map) {
+        (false, _) => None,
+        (true, scene::EnvironmentSource::Uniform(radiance)) => {
+            Some((*radiance, scene_config.furnace))
+        }
+        (true, _) => failure::bail!("The white furnace test needs a uniform environment map"),
+    };
+    // Checked once the environment is filtered, like the white furnace test
+    let golden_tolerance = scene_config.golden;
+    let golden_run = golden.is_some();
+    let mut golden_check = golden;
+    // Set by the white furnace, golden image and smoke tests, which close the app once they
+    // are done
+    let mut test_failed = None;
+    let validate_environment = scene_config.validate_environment;
+    if validate_environment {
+        node::env_preprocess::validate::check_cube_mips(
             &mut factory,
+            &mut families,
+            queue,
+            environment_storage.env_cube.as_ref().unwrap().image(),
+            "Environment cube map",
         )?;
-
-        let env_cubemap_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                ENV_CUBEMAP_RES,
-                ENV_CUBEMAP_RES,
-                6,
-                1,
-            ))
-            .with_mip_levels(rendy::texture::MipLevels::Levels(
-                std::num::NonZeroU8::new(ENV_CUBEMAP_MIP_LEVELS).unwrap(),
-            ))
-            .with_view_kind(rendy::resource::ViewKind::Cube)
-            .with_data_width(ENV_CUBEMAP_RES)
-            .with_data_height(ENV_CUBEMAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rgba32Sfloat {
-                    repr: [0.0, 0.0, 0.0, 1.0]
-                };
-                (ENV_CUBEMAP_RES * ENV_CUBEMAP_RES * 6) as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
-                &mut factory,
-            )?;
-
-        let irradiance_cubemap_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                IRRADIANCE_CUBEMAP_RES,
-                IRRADIANCE_CUBEMAP_RES,
-                6,
-                1,
-            ))
-            .with_view_kind(rendy::resource::ViewKind::Cube)
-            .with_data_width(IRRADIANCE_CUBEMAP_RES)
-            .with_data_height(IRRADIANCE_CUBEMAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rgba32Sfloat {
-                    repr: [0.0, 0.0, 0.0, 1.0]
-                };
-                (IRRADIANCE_CUBEMAP_RES * IRRADIANCE_CUBEMAP_RES * 6)
-                    as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
-                &mut factory,
-            )?;
-
-        let spec_cubemap_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                SPEC_CUBEMAP_RES,
-                SPEC_CUBEMAP_RES,
-                6,
-                1,
-            ))
-            .with_mip_levels(rendy::texture::MipLevels::Levels(
-                std::num::NonZeroU8::new(SPEC_CUBEMAP_MIP_LEVELS).unwrap(),
-            ))
-            .with_view_kind(rendy::resource::ViewKind::Cube)
-            .with_data_width(SPEC_CUBEMAP_RES)
-            .with_data_height(SPEC_CUBEMAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rgba32Sfloat {
-                    repr: [0.0, 0.0, 0.0, 1.0]
-                };
-                (SPEC_CUBEMAP_RES * SPEC_CUBEMAP_RES * 6) as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
-                &mut factory,
-            )?;
-
-        let spec_brdf_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                SPEC_BRDF_MAP_RES,
-                SPEC_BRDF_MAP_RES,
-                1,
-                1,
-            ))
-            .with_view_kind(rendy::resource::ViewKind::D2)
-            .with_data_width(SPEC_BRDF_MAP_RES)
-            .with_data_height(SPEC_BRDF_MAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rg32Sfloat { repr: [0.0, 0.0] };
-                (SPEC_BRDF_MAP_RES * SPEC_BRDF_MAP_RES) as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
+    }
+    let dump_environment = scene_config
+        .dump_environment
+        .as_ref()
+        .map(|dir| std::path::Path::new(&application_root_dir()).join(dir));
+    if let Some(dir) = dump_environment.as_ref() {
+        for (image, name) in &[
+            (
+                environment_storage.env_cube.as_ref().unwrap().image(),
+                "environment",
+            ),
+            (
+                environment_storage.spec_brdf_map.as_ref().unwrap().image(),
+                "spec_brdf",
+            ),
+            (
+                environment_storage
+                    .spec_brdf_avg_map
+                    .as_ref()
+                    .unwrap()
+                    .image(),
+                "spec_brdf_avg",
+            ),
+        ] {
+            node::env_preprocess::debug::dump_texture(
                 &mut factory,
+                &mut families,
+                queue,
+                image,
+                dir,
+                name,
             )?;
-
-        use scene::Quality;
-        let mut env_preprocess_aux = node::env_preprocess::Aux {
-            align,
-            irradiance_theta_samples: match scene_config.environment_filter_quality {
-                Quality::High => 720,
-                Quality::Medium => 512,
-                Quality::Low => 256,
-            },
-            spec_samples: match scene_config.environment_filter_quality {
-                Quality::High => 8192,
-                Quality::Medium => 4096,
-                Quality::Low => 1024,
-            },
-            equirectangular_texture: equirect_tex,
-            environment_cubemap: Some(env_cubemap_tex),
-            irradiance_cubemap: Some(irradiance_cubemap_tex),
-            spec_cubemap: Some(spec_cubemap_tex),
-            spec_brdf_map: Some(spec_brdf_tex),
-            queue,
-            mip_level: std::sync::atomic::AtomicUsize::new(0),
-        };
-
-        let mut env_preprocess_graph = env_preprocess_graph_builder.build(
-            &mut factory,
-            &mut families,
-            &mut env_preprocess_aux,
-        )?;
-
-        factory.maintain(&mut families);
-        env_preprocess_graph.run(&mut factory, &mut families, &mut env_preprocess_aux);
-        env_preprocess_graph.dispose(&mut factory, &mut env_preprocess_aux);
-
-        env_preprocess_aux
-    };
-
-    let mut pbr_graph_builder = GraphBuilder::<B, specs::World>::new();
-
-    let hdr = pbr_graph_builder.create_image(
-        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
-        1,
-        hal::format::Format::Rgba32Sfloat,
-        Some(hal::command::ClearValue {
-            color: hal::command::ClearColor {
-                float32: [0.1, 0.3, 0.4, 1.0],
-            },
-        }),
-    );
-
-    let color = pbr_graph_builder.create_image(
-        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
-        1,
-        factory.get_surface_format(&surface),
-        Some(hal::command::ClearValue {
-            color: hal::command::ClearColor {
-                float32: [0.1, 0.3, 0.4, 1.0],
-            },
-        }),
-    );
-
-    let depth = pbr_graph_builder.create_image(
-        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
-        1,
-        hal::format::Format::D32Sfloat,
-        Some(hal::command::ClearValue {
-            depth_stencil: hal::command::ClearDepthStencil {
-                depth: 1.0,
-                stencil: 0,
-            },
-        }),
-    );
-
-    let mesh_pass = pbr_graph_builder.add_node(
-        node::pbr::environment_map::Pipeline::builder()
-            .into_subpass()
-            .with_group(node::pbr::mesh::Pipeline::builder())
-            .with_color(hdr)
-            .with_depth_stencil(depth)
-            .into_pass(),
-    );
-
-    let tonemap_pass = pbr_graph_builder.add_node(
-        node::pbr::tonemap::Pipeline::builder()
-            .with_image(hdr)
-            .into_subpass()
-            .with_dependency(mesh_pass)
-            .with_color(color)
-            .into_pass(),
-    );
-
-    pbr_graph_builder
-        .add_node(PresentNode::builder(&factory, surface, color).with_dependency(tonemap_pass));
+        }
+    }
 
     // Hierarchy system must be added before loading scene
     let mut hierarchy_system = specs_hierarchy::HierarchySystem::<components::Parent>::new();
@@ -530,118 +445,422 @@ fn run<B: hal::Backend>(
     let mut transform_system = systems::TransformSystem::new();
     specs::System::setup(&mut transform_system, &mut world.res);
 
+    let worker_threads = scene_config.worker_threads;
+    let shader_debug_views = scene_config.shader_debug_views;
+    let occlusion = scene_config.occlusion;
+    let parallax = scene_config.parallax;
+    let ibl_reference = scene_config.ibl_reference;
+    let fog = scene_config.fog;
+    let background = scene_config.background;
+    let transparent_window = scene_config.transparent_window;
+    let grid = scene_config.grid;
+    let reversed_z = scene_config.reversed_z;
+    let render_settings = scene_config.render_settings;
+    let debug_window_settings = scene_config.debug_window;
+    let probe_grid = scene_config.probes;
+    let voxel_gi_settings = scene_config.voxel_gi;
+    let script = scene_config.script.clone();
+    let shared_settings = scene_manager::SharedSettings::of(&scene_config);
+
+    let mipmap_model_textures = scene_config.mipmap_model_textures;
     // Load scene from config file
-    let (material_storage, primitive_storage, mesh_storage, _scene_entities) =
-        scene_config.load(aspect, &mut factory, queue, &mut world)?;
+    let (
+        material_storage,
+        primitive_storage,
+        geometry_pool,
+        mesh_storage,
+        lightmap_storage,
+        scene_entities,
+    ) = {
+        let _scope = profile::scope("scene load");
+        scene_config.load(aspect, &mut factory, &upload_queues, &mut world)?
+    };
+
+    world.add_resource(upload::PendingTransfers::<B>::default());
+    transfer_scene_assets(
+        &mut factory,
+        &mut families,
+        &upload_queues,
+        (&material_storage, mipmap_model_textures),
+        &geometry_pool,
+        &lightmap_storage,
+        &mut world.write_resource::<upload::PendingTransfers<B>>(),
+    )?;
 
     let num_meshes = mesh_storage.0.len();
     let num_materials = material_storage.0.len();
 
+    let shader_variants = material_shader_variants(&material_storage, shader_debug_views);
+
+    let shadow_map = node::pbr::shadow::ShadowMap::new(
+        &mut factory,
+        &mut families,
+        queue,
+        &world,
+        render_settings.shadows,
+    )?;
+    let shadow_cascades = shadow_map.cascades();
+
+    // sRGB swapchains gamma encode on write, otherwise the tonemapper has to
+    let surface_format = factory.get_surface_format(&surface);
+    let srgb_surface = surface_format.base_format().1 == hal::format::ChannelType::Srgb;
+    log::info!("Using surface format: {:?}", surface_format);
+
+    let debug_window = match debug_window_settings.as_ref() {
+        Some(settings) => {
+            let debug_window = open_debug_window(&event_loop, settings, !headless)?;
+            let debug_window_aspect = (settings.size.0 / settings.size.1) as f32;
+            let debug_cameras = world.read_storage::<components::DebugWindowCamera>();
+            let mut cameras = world.write_storage::<components::Camera>();
+            for (camera, _) in (&mut cameras, &debug_cameras).join() {
+                camera.aspect = debug_window_aspect;
+            }
+            Some(debug_window)
+        }
+        None => None,
+    };
+
     let pbr_aux = node::pbr::Aux {
         frames: FRAMES_IN_FLIGHT as _,
         align,
         tonemapper_args: node::pbr::tonemap::TonemapperArgs {
-            exposure: 1.7,
-            curve: 0,
+            exposure_ev100: settings.exposure_ev100,
+            curve: settings.tonemap_curve,
             comparison_factor: 0.5,
+            encode_gamma: (!srgb_surface) as i32,
+            output_alpha: transparent_window as i32,
+            false_color: 0,
         },
         cube_display: node::pbr::environment_map::CubeDisplay::Environment,
         cube_roughness: 1.0,
+        cube_mip: None,
+        env_cube_mip_levels,
+        debug_view: node::pbr::mesh::DebugView::Lit,
+        split_view: node::pbr::tonemap::SplitView {
+            enabled: false,
+            divider: 0.5,
+            exposure_ev100: settings.exposure_ev100,
+            curve: settings.tonemap_curve,
+            debug_view: node::pbr::mesh::DebugView::Lit,
+            ibl_mode: node::pbr::IblMode::SplitSum,
+            render_width: render_size.width as u32,
+        },
+        scopes: node::pbr::scopes::ScopesMode::Off,
+        render_settings,
+        shader_debug_views,
+        occlusion,
+        parallax,
+        fog,
+        background,
+        grid,
+        capture_panorama: false,
+        capture_screenshot: false,
+        capture_frame: false,
+        bake_probes: false,
+        collect_assets: false,
+        voxel_gi: voxel_gi::VoxelGiUniform::new(&voxel_gi_settings),
+        shadow_cascade_debug: false,
+        shadow_cascades_frozen: false,
+        ibl_mode: node::pbr::IblMode::SplitSum,
+        ibl_reference,
+        ibl_reference_frames: 0,
     };
 
     // Add specs resources
     world.add_resource(pbr_aux);
     world.add_resource(input);
+    world.add_resource(viewport);
+    world.add_resource(raycast::Raycast::default());
     world.add_resource(event_bucket);
+    world.add_resource(input::WindowControl::default());
+    world.add_resource(systems::FrameTime::default());
     world.add_resource(material_storage);
     world.add_resource(primitive_storage);
+    world.add_resource(geometry_pool);
     world.add_resource(mesh_storage);
+    world.add_resource(lightmap_storage);
+    world.add_resource(memory_stats::GpuMemoryStats::default());
+    world.add_resource(node::pbr::mesh::MeshViewBuffers::<B>::default());
+    world.add_resource(draw_stats::DrawStats::default());
+    world.add_resource(frame_arena::FrameArena::default());
+    world.add_resource(shadow_map);
+    world.add_resource(node::pbr::shadow::ShadowCascades::default());
+    world.add_resource(node::pbr::lines::DebugLines::default());
+    world.add_resource(node::pbr::lines::OverlayLines::default());
+    world.add_resource(gizmo::Gizmo::default());
+    world.add_resource(command::CommandStack::default());
+    world.add_resource(inspector::Inspector::default());
+    world.add_resource(scene_manager::SceneManager::new(scenes, shared_settings));
     world.add_resource(node::pbr::EnvironmentStorage {
-        env_cube: preprocessed_environment_data.environment_cubemap.take(),
-        irradiance_cube: preprocessed_environment_data.irradiance_cubemap.take(),
-        spec_cube: preprocessed_environment_data.spec_cubemap.take(),
-        spec_brdf_map: preprocessed_environment_data.spec_brdf_map.take(),
-    });
-    std::mem::drop(preprocessed_environment_data);
-    world.add_resource(systems::HelmetArraySize { x: 0, y: 0, z: 0 });
-    world.add_resource(systems::HelmetArrayEntities(Vec::new()));
-    world.add_resource(systems::MeshInstanceStorage(Default::default()));
-    world.add_resource(systems::InstanceCache {
-        dirty_entities: vec![specs::BitSet::new(); FRAMES_IN_FLIGHT as _],
-        dirty_mesh_indirects: vec![HashSet::new(); FRAMES_IN_FLIGHT as _],
-        mesh_instance_counts: vec![0; num_meshes],
-        material_bitsets: vec![specs::BitSet::new(); num_materials],
+        probes: Some(probes::create_buffer(&mut factory, None)?),
+        voxel_radiance: Some(voxel_gi::create_volume(&mut factory, queue, 1, 1)?),
+        ..environment_storage
     });
 
-    let instance_cache_update_system = {
-        let mut mesh_storage = world.write_storage::<components::Mesh>();
-
-        systems::InstanceCacheUpdateSystem {
-            frames_in_flight: FRAMES_IN_FLIGHT as usize,
-            previous_frame: FRAMES_IN_FLIGHT as usize - 1,
-            transform_reader_id: world
-                .write_storage::<components::GlobalTransform>()
-                .register_reader(),
-            mesh_reader_id: mesh_storage.register_reader(),
-            dirty_entities_scratch: specs::BitSet::new(),
-            dirty_mesh_indirects_scratch: HashSet::new(),
-            mesh_inserted: mesh_storage.mask().clone(),
-            mesh_deleted: BitSet::new(),
-            mesh_modified: BitSet::new(),
-            mesh_entity_bitsets: vec![BitSet::new(); num_meshes],
-            _pd: core::marker::PhantomData::<Backend>,
-        }
+    let mut voxel_gi = if voxel_gi_settings.enabled {
+        let (voxel_gi, volume) =
+            voxel_gi::VoxelGi::new(&mut factory, &mut families, queue, voxel_gi_settings)?;
+        let mut env_storage = world.write_resource::<node::pbr::EnvironmentStorage<B>>();
+        env_storage.voxel_radiance = Some(volume);
+        env_storage.generation += 1;
+        Some(voxel_gi)
+    } else {
+        None
     };
+    world.add_resource(asset_gc::AssetGc::<B>::default());
+    world.add_resource(systems::InstanceArraySize { x: 0, y: 0, z: 0 });
+    world.add_resource(systems::InstanceArrayEntities(Vec::new()));
+    world.add_resource(systems::MeshInstanceStorage::default());
+    world.add_resource(systems::ParticleStorage::default());
+    world.add_resource(render_frame::RenderFrames::default());
+    world.add_resource(systems::InstanceCache::new(
+        FRAMES_IN_FLIGHT as _,
+        num_meshes,
+        num_materials,
+    ));
+
+    let instance_cache_update_system =
+        systems::InstanceCacheUpdateSystem::new(&world, FRAMES_IN_FLIGHT as usize);
+
+    let mut dispatcher_builder = DispatcherBuilder::new();
+    if let Some(num_threads) = worker_threads {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()?;
+        dispatcher_builder = dispatcher_builder.with_pool(std::sync::Arc::new(pool));
+    }
 
-    let mut dispatcher = DispatcherBuilder::new()
-        .with(systems::CameraInputSystem, "camera_input_system", &[])
+    // Only true ordering requirements are listed as dependencies; systems which don't
+    // conflict on resource access are free to run in parallel.
+    dispatcher_builder = dispatcher_builder
+        .with(systems::ViewportSystem, "viewport_system", &[])
+        .with(gizmo::GizmoSystem, "gizmo_system", &[])
         .with(
-            systems::PbrAuxInputSystem {
-                helmet_mesh: 0 as asset::MeshHandle,
-            },
+            command::CommandStackSystem,
+            "command_stack_system",
+            &["gizmo_system"],
+        )
+        .with(
+            inspector::InspectorSystem,
+            "inspector_system",
+            &["command_stack_system"],
+        )
+        .with(
+            systems::CameraInputSystem::default(),
+            "camera_input_system",
+            &["viewport_system", "gizmo_system"],
+        )
+        .with(
+            systems::PbrAuxInputSystem,
             "pbr_aux_input_system",
-            &[],
+            &["viewport_system"],
         )
         .with(
-            systems::HelmetArraySizeUpdateSystem {
+            systems::InstanceArraySizeUpdateSystem {
                 curr_size: Default::default(),
-                helmet_mesh: 0 as asset::MeshHandle,
             },
-            "helmet_array_size_update_system",
+            "instance_array_size_update_system",
             &["pbr_aux_input_system"],
         )
+        // Entities spawned by resizing the instance array are picked up by the hierarchy in
+        // the same frame, so their global transforms aren't a frame late.
         .with(
             hierarchy_system,
             "transform_hierarchy_system",
-            &[
-                "helmet_array_size_update_system",
-                "pbr_aux_input_system",
-                "camera_input_system",
-            ],
+            &["instance_array_size_update_system"],
         )
+        .with(
+            systems::ProceduralAnimationSystem,
+            "procedural_animation_system",
+            &[],
+        );
+
+    // The script runs after everything else moving entities, so it has the last word, and
+    // before transforms are propagated.
+    let transform_dependencies = vec![
+        "gizmo_system",
+        "command_stack_system",
+        "inspector_system",
+        "transform_hierarchy_system",
+        "instance_array_size_update_system",
+        "camera_input_system",
+        "procedural_animation_system",
+    ];
+    #[cfg(feature = "scripting")]
+    let transform_dependencies = match &script {
+        Some(path) => {
+            dispatcher_builder.add(
+                script::ScriptSystem::new(path, scene_entities)?,
+                "script_system",
+                &[
+                    "pbr_aux_input_system",
+                    "instance_array_size_update_system",
+                    "procedural_animation_system",
+                ],
+            );
+            let mut dependencies = transform_dependencies;
+            dependencies.push("script_system");
+            dependencies
+        }
+        None => transform_dependencies,
+    };
+    #[cfg(not(feature = "scripting"))]
+    {
+        let _ = scene_entities;
+        if let Some(path) = &script {
+            log::warn!(
+                "Ignoring script {}, scripting needs the `scripting` feature",
+                path
+            );
+        }
+    }
+
+    let mut dispatcher = dispatcher_builder
         .with(
             transform_system,
             "transform_system",
-            &["transform_hierarchy_system"],
+            &transform_dependencies,
+        )
+        .with(
+            systems::LodSelectionSystem,
+            "lod_selection_system",
+            &["transform_system"],
         )
         .with(
             instance_cache_update_system,
             "instance_cache_update_system",
+            &["lod_selection_system"],
+        )
+        .with(
+            asset_gc::AssetGcSystem::<B>(core::marker::PhantomData),
+            "asset_gc_system",
+            &["instance_cache_update_system", "pbr_aux_input_system"],
+        )
+        .with(
+            systems::LightListSystem,
+            "light_list_system",
+            &["lod_selection_system"],
+        )
+        // Copies what the graph draws out of the ECS once the systems above changed it
+        .with(
+            render_frame::RenderExtractSystem,
+            "render_extract_system",
+            &[
+                "instance_cache_update_system",
+                "asset_gc_system",
+                "light_list_system",
+            ],
+        )
+        .with(
+            systems::RaycastUpdateSystem,
+            "raycast_update_system",
+            &["transform_system"],
+        )
+        .with(
+            gizmo::GizmoDrawSystem,
+            "gizmo_draw_system",
+            &["raycast_update_system"],
+        )
+        .with(
+            systems::ShadowCascadeSystem {
+                max_cascades: shadow_cascades,
+                mesh_reader_id: world.write_storage::<components::Mesh>().register_reader(),
+                transform_reader_id: world
+                    .write_storage::<components::GlobalTransform>()
+                    .register_reader(),
+                layers_reader_id: world
+                    .write_storage::<components::Layers>()
+                    .register_reader(),
+            },
+            "shadow_cascade_system",
             &["transform_system"],
         )
+        .with(
+            systems::ParticleSystem::default(),
+            "particle_system",
+            &["transform_system"],
+        )
+        .with(
+            systems::GpuMemoryStatsSystem::<B>(core::marker::PhantomData),
+            "gpu_memory_stats_system",
+            &[],
+        )
+        // Input state must only be advanced once every system replaying this frame's
+        // events on top of the previous state has run.
         .with(
             systems::InputSystem,
             "input_system",
-            &["pbr_aux_input_system", "camera_input_system"],
+            &[
+                "pbr_aux_input_system",
+                "camera_input_system",
+                "gizmo_system",
+            ],
         )
         .build();
 
     // Dispatch once to build all needed initial state before first frame render
     dispatcher.dispatch(&mut world.res);
 
-    let pbr_graph = pbr_graph_builder
-        .with_frames_in_flight(FRAMES_IN_FLIGHT)
-        .build(&mut factory, &mut families, &mut world)?;
+    let mut graph_settings = PbrGraphSettings {
+        shader_variants,
+        num_materials,
+        reversed_z,
+        render_settings,
+        shader_debug_views,
+        ibl_accumulation: false,
+        color_target_format,
+        render_scale: settings.render_scale(),
+        vsync,
+        debug_window: debug_window_settings,
+    };
+
+    // Baked before the graph is built, which binds the lightmaps once
+    if bake_lightmaps {
+        let (camera, _) = active_camera(&world)?;
+        lightmap::bake_all(
+            &mut factory,
+            &mut families,
+            queue,
+            &mut world,
+            &graph_settings,
+            &camera,
+        )?;
+    }
+    let mut pbr_description = graph_dump::GraphDescription::new("pbr");
+    let pbr_graph_builder = pbr_graph_builder(
+        &mut factory,
+        queue,
+        &world,
+        surface,
+        debug_window.as_ref(),
+        &graph_settings,
+        &mut pbr_description,
+    )?;
+
+    // Written before building, so that a graph which fails to build can be looked at too
+    if let Some(path) = dump_graph.as_ref() {
+        graph_dump::write(
+            std::path::Path::new(path),
+            &[env_pipeline.description(), &pbr_description],
+        )?;
+    }
+
+    let pbr_graph = pbr_graph_builder.build(&mut factory, &mut families, &mut world)?;
+
+    if let Some(probe_grid) = probe_grid.as_ref() {
+        bake_probes(
+            &mut factory,
+            &mut families,
+            queue,
+            &mut world,
+            &graph_settings,
+            probe_grid,
+        )?;
+    }
+
+    world
+        .read_resource::<memory_stats::GpuMemoryStats>()
+        .log_summary();
 
     let started = time::Instant::now();
 
@@ -649,20 +868,155 @@ fn run<B: hal::Backend>(
 
     let mut checkpoint = started;
 
+    let mut last_frame = time::Instant::now();
+
+    let mut smoke_test = if smoke {
+        Some(smoke::SmokeTest::new(
+            &world.read_resource::<node::pbr::Aux>(),
+        ))
+    } else {
+        None
+    };
+
     let mut world = Some(world);
     let mut pbr_graph = Some(pbr_graph);
+    // Set when the graph can't be rebuilt, usually as the device was lost, to close the app
+    let mut device_lost = false;
+    let mut graph_failed = false;
+    // Frames in a row the graph failed to run in, see `MAX_GRAPH_FAILURES`
+    let mut graph_failures = 0;
+    // Set when passes are toggled, to rebuild the graph with `graph_settings` after the frame
+    let mut reconfigure_graph = false;
+    // Between `Suspended` and `Resumed` events, without a graph or swapchain
+    let mut suspended = false;
+    let mut window_focused = true;
+    // Detected from the window being resized to nothing
+    let mut minimized = false;
     event_loop.run(move |event, _, control_flow| {
+        // A headless replay and the white furnace, golden image and smoke tests close the app
+        // once they are done
+        let done = headless && event_log.is_finished() || test_failed.is_some();
+        let event = if (device_lost || done) && world.is_some() {
+            Event::WindowEvent {
+                window_id: window.id(),
+                event: WindowEvent::CloseRequested,
+            }
+        } else {
+            event
+        };
+
+        if let Event::WindowEvent { window_id, event } = &event {
+            if *window_id == window.id() {
+                match event {
+                    WindowEvent::Focused(focused) => window_focused = *focused,
+                    WindowEvent::Resized(size) => {
+                        minimized = size.width == 0.0 || size.height == 0.0
+                    }
+                    _ => (),
+                }
+            }
+        }
+        // A headless replay has to run to the end unattended
+        let paused = !headless
+            && (minimized || !window_focused && settings.unfocused == settings::Unfocused::Pause);
+
         match event {
+            // Nothing is updated or drawn while suspended, the swapchain may not be usable
+            Event::Suspended => {
+                log::info!("Suspended, releasing the swapchain");
+                suspended = true;
+                if let (Some(world), Some(graph)) = (world.as_mut(), pbr_graph.take()) {
+                    graph.dispose(&mut factory, world);
+                }
+                *control_flow = ControlFlow::Wait;
+            }
+            Event::Resumed => {
+                if suspended {
+                    log::info!("Resumed, re-creating the swapchain");
+                    suspended = false;
+                    if let Some(world) = world.as_mut() {
+                        match build_pbr_graph(
+                            &mut factory,
+                            &mut families,
+                            queue,
+                            world,
+                            &window,
+                            debug_window.as_ref(),
+                            &graph_settings,
+                        ) {
+                            Ok(graph) => pbr_graph = Some(graph),
+                            Err(e) => {
+                                log::error!("Failed to rebuild the render graph: {}", e);
+                                device_lost = true;
+                            }
+                        }
+                    }
+                    // Frame times don't jump ahead by the time spent suspended
+                    last_frame = time::Instant::now();
+                }
+                *control_flow = ControlFlow::Poll;
+            }
+            Event::EventsCleared if suspended || paused => *control_flow = ControlFlow::Wait,
             Event::EventsCleared => {
+                if let (false, false, settings::Unfocused::Throttle(fps)) =
+                    (headless, window_focused, settings.unfocused)
+                {
+                    let next_frame = last_frame + time::Duration::from_secs_f32(1.0 / fps.max(1.0));
+                    if time::Instant::now() < next_frame {
+                        *control_flow = ControlFlow::WaitUntil(next_frame);
+                        return;
+                    }
+                }
+
                 // Update logic then request redraw
                 if let Some(world) = world.as_mut() {
+                    let now = time::Instant::now();
+                    let live_delta = (now - last_frame).as_secs_f32();
+                    last_frame = now;
+                    let frame = event_log.frame(
+                        &mut world.write_resource::<input::EventBucket>().0,
+                        live_delta,
+                        window.id(),
+                    );
+                    let delta = match frame {
+                        Ok(delta) => delta,
+                        Err(e) => {
+                            log::error!("Failed to record events, stopped recording: {}", e);
+                            event_log = replay::EventLog::Live;
+                            live_delta
+                        }
+                    };
+                    world.write_resource::<systems::FrameTime>().advance(delta);
+
                     world.maintain();
-                    dispatcher.dispatch(&mut world.res);
+                    {
+                        let _scope = profile::scope("dispatch");
+                        dispatcher.dispatch(&mut world.res);
+                    }
+
+                    let grab_cursor = world
+                        .write_resource::<input::WindowControl>()
+                        .grab_cursor
+                        .take();
+                    if let Some(grab) = grab_cursor {
+                        if let Err(e) = window.set_cursor_grab(grab) {
+                            log::warn!("Failed to grab the cursor: {:?}", e);
+                        }
+                        window.set_cursor_visible(!grab);
+                    }
 
                     world.write_resource::<input::EventBucket>().0.clear();
                     window.request_redraw();
                 }
             }
+            // The debug window only shows the scene, closing it just hides it
+            Event::WindowEvent { window_id, event } if window_id != window.id() => {
+                if let (WindowEvent::CloseRequested, Some(debug_window)) =
+                    (event, debug_window.as_ref())
+                {
+                    debug_window.set_visible(false);
+                }
+            }
             Event::WindowEvent {
                 event: WindowEvent::RedrawRequested,
                 ..
@@ -671,21 +1025,271 @@ fn run<B: hal::Backend>(
                 match (world.as_mut(), pbr_graph.as_mut()) {
                     (Some(world), Some(pbr_graph)) => {
                         factory.maintain(&mut families);
+                        world
+                            .write_resource::<upload::PendingTransfers<B>>()
+                            .collect(&mut factory)
+                            .unwrap();
+
+                        // Swap in the filtered environment maps whenever a pass of the
+                        // filter schedule completes
+                        let filtered = environment_filter
+                            .as_mut()
+                            .map(|filter| filter.poll(&mut factory, &mut families).unwrap())
+                            .unwrap_or(None);
+                        if let Some((irradiance_cube, spec_cube)) = filtered {
+                            // The previous maps may still be in use by frames in flight
+                            factory.wait_idle().unwrap();
+                            let mut env_storage =
+                                world.write_resource::<node::pbr::EnvironmentStorage<B>>();
+                            if validate_environment {
+                                for (cube, name) in &[
+                                    (&irradiance_cube, "Irradiance cube map"),
+                                    (&spec_cube, "Specular cube map"),
+                                ] {
+                                    node::env_preprocess::validate::check_cube_mips(
+                                        &mut factory,
+                                        &mut families,
+                                        queue,
+                                        cube.image(),
+                                        name,
+                                    )
+                                    .unwrap();
+                                }
+                            }
+                            if let Some(dir) = dump_environment.as_ref() {
+                                for (cube, name) in
+                                    &[(&irradiance_cube, "irradiance"), (&spec_cube, "specular")]
+                                {
+                                    node::env_preprocess::debug::dump_texture(
+                                        &mut factory,
+                                        &mut families,
+                                        queue,
+                                        cube.image(),
+                                        dir,
+                                        name,
+                                    )
+                                    .unwrap();
+                                }
+                            }
+                            env_storage.irradiance_cube = Some(irradiance_cube);
+                            env_storage.spec_cube = Some(spec_cube);
+                            env_storage.generation += 1;
+                        }
+                        if environment_filter
+                            .as_ref()
+                            .map(|filter| filter.is_done())
+                            .unwrap_or(false)
+                        {
+                            environment_filter
+                                .take()
+                                .unwrap()
+                                .dispose(&mut factory, &mut families)
+                                .unwrap();
+
+                            if let Some((radiance, tolerance)) = furnace_check.take() {
+                                let passed = furnace::check(
+                                    &mut factory,
+                                    &mut families,
+                                    queue,
+                                    world,
+                                    &graph_settings,
+                                    radiance,
+                                    &tolerance,
+                                );
+                                test_failed = Some(match passed {
+                                    Ok(passed) => !passed,
+                                    Err(e) => {
+                                        log::error!("Failed to run the white furnace test: {}", e);
+                                        true
+                                    }
+                                });
+                            }
+                            if let Some((mode, path)) = golden_check.take() {
+                                let passed = golden::check(
+                                    &mut factory,
+                                    &mut families,
+                                    queue,
+                                    world,
+                                    &graph_settings,
+                                    (mode, &path),
+                                    &golden_tolerance,
+                                );
+                                test_failed = Some(match passed {
+                                    Ok(passed) => !passed,
+                                    Err(e) => {
+                                        log::error!("Failed to run the golden image test: {}", e);
+                                        true
+                                    }
+                                });
+                            }
+                        }
+
+                        if let Some(voxel_gi) = voxel_gi.as_mut() {
+                            voxel_gi.update(&mut factory, &mut families, world).unwrap();
+                        }
 
-                        pbr_graph.run(&mut factory, &mut families, world);
+                        let capture_frame = std::mem::replace(
+                            &mut world.write_resource::<node::pbr::Aux>().capture_frame,
+                            false,
+                        );
+                        if capture_frame && !frame_capture.is_capturing() {
+                            frame_capture.start("a PBR frame");
+                        }
+                        // rendy panics when the device or a surface is lost mid-frame. Any other
+                        // panic is a bug, and is let through.
+                        let ran = if world.read_resource::<asset_gc::AssetGc<B>>().unloaded() {
+                            // The graph was built for the meshes just unloaded, so it isn't run
+                            // until it's rebuilt below
+                            Ok(())
+                        } else {
+                            let _scope = profile::scope("pbr graph");
+                            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                pbr_graph.run(&mut factory, &mut families, world)
+                            }))
+                        };
+                        let ran = match ran {
+                            Ok(()) => {
+                                graph_failures = 0;
+                                true
+                            }
+                            Err(payload) => {
+                                if !is_device_or_surface_loss(&*payload) {
+                                    std::panic::resume_unwind(payload);
+                                }
+                                graph_failures += 1;
+                                if graph_failures > MAX_GRAPH_FAILURES {
+                                    log::error!(
+                                        "The render graph failed to run in {} frames in a row",
+                                        graph_failures
+                                    );
+                                    device_lost = true;
+                                } else {
+                                    graph_failed = true;
+                                }
+                                false
+                            }
+                        };
+                        let smoke_passed = smoke_test.as_mut().and_then(|smoke_test| {
+                            smoke_test
+                                .frame_done(&mut world.write_resource::<node::pbr::Aux>(), ran)
+                        });
+                        if let Some(passed) = smoke_passed {
+                            smoke_test = None;
+                            test_failed = Some(!passed);
+                        }
+                        // Drawn by every window by now
+                        world
+                            .write_resource::<node::pbr::lines::DebugLines>()
+                            .clear();
+                        world.write_resource::<draw_stats::DrawStats>().end_frame();
+                        world.write_resource::<frame_arena::FrameArena>().reset();
+
+                        let capture_requested = std::mem::replace(
+                            &mut world.write_resource::<node::pbr::Aux>().capture_panorama,
+                            false,
+                        );
+                        if capture_requested {
+                            let seconds = time::SystemTime::now()
+                                .duration_since(time::UNIX_EPOCH)
+                                .map(|since| since.as_secs())
+                                .unwrap_or(0);
+                            let path = std::path::Path::new(&application_root_dir())
+                                .join(format!("panorama_{}.exr", seconds));
+                            if let Err(e) = capture_panorama(
+                                &mut factory,
+                                &mut families,
+                                queue,
+                                world,
+                                &graph_settings,
+                                &path,
+                            ) {
+                                log::error!("Failed to capture panorama: {}", e);
+                            }
+                        }
+
+                        let screenshot_requested = std::mem::replace(
+                            &mut world.write_resource::<node::pbr::Aux>().capture_screenshot,
+                            false,
+                        );
+                        if screenshot_requested {
+                            let seconds = time::SystemTime::now()
+                                .duration_since(time::UNIX_EPOCH)
+                                .map(|since| since.as_secs())
+                                .unwrap_or(0);
+                            let path = std::path::Path::new(&application_root_dir())
+                                .join(format!("screenshot_{}.exr", seconds));
+                            if let Err(e) = capture_screenshot(
+                                &mut factory,
+                                &mut families,
+                                queue,
+                                world,
+                                &graph_settings,
+                                &path,
+                            ) {
+                                log::error!("Failed to capture screenshot: {}", e);
+                            }
+                        }
+
+                        let bake_requested = std::mem::replace(
+                            &mut world.write_resource::<node::pbr::Aux>().bake_probes,
+                            false,
+                        );
+                        if bake_requested {
+                            match probe_grid.as_ref() {
+                                Some(probe_grid) => {
+                                    if let Err(e) = bake_probes(
+                                        &mut factory,
+                                        &mut families,
+                                        queue,
+                                        world,
+                                        &graph_settings,
+                                        probe_grid,
+                                    ) {
+                                        log::error!("Failed to bake light probes: {}", e);
+                                    }
+                                }
+                                None => log::warn!("No probe grid set in the scene config"),
+                            }
+                        }
 
-                        #[cfg(feature = "rd")]
-                        let renderdoc_capturing = rd.is_frame_capturing();
-                        #[cfg(not(feature = "rd"))]
-                        let renderdoc_capturing = false;
+                        if world.read_resource::<asset_gc::AssetGc<B>>().unloaded() {
+                            // The unloaded assets may still be in use by frames in flight
+                            factory.wait_idle().unwrap();
+                            world.write_resource::<asset_gc::AssetGc<B>>().release();
+                            graph_settings.shader_variants = material_shader_variants(
+                                &world.read_resource::<asset::MaterialStorage<B>>(),
+                                graph_settings.shader_debug_views,
+                            );
+                            reconfigure_graph = true;
+                        }
 
-                        if renderdoc_capturing {
-                            #[cfg(feature = "rd")]
-                            rd.end_frame_capture(std::ptr::null(), std::ptr::null());
-                            #[cfg(feature = "rd")]
-                            rd.launch_replay_ui("rendy-pbr").unwrap();
+                        // The graph is only rebuilt between frames, once the one just run is done
+                        let (render_settings, shader_debug_views, ibl_accumulation) = {
+                            let aux = world.read_resource::<node::pbr::Aux>();
+                            (
+                                aux.render_settings,
+                                aux.shader_debug_views,
+                                aux.ibl_accumulation(),
+                            )
+                        };
+                        if ibl_accumulation != graph_settings.ibl_accumulation {
+                            graph_settings.ibl_accumulation = ibl_accumulation;
+                            reconfigure_graph = true;
+                        }
+                        if render_settings != graph_settings.render_settings
+                            || shader_debug_views != graph_settings.shader_debug_views
+                        {
+                            graph_settings.render_settings = render_settings;
+                            graph_settings.shader_debug_views = shader_debug_views;
+                            graph_settings.shader_variants = material_shader_variants(
+                                &world.read_resource::<asset::MaterialStorage<B>>(),
+                                shader_debug_views,
+                            );
+                            reconfigure_graph = true;
                         }
 
+                        frame_capture.end();
+
                         let elapsed = checkpoint.elapsed();
 
                         frames += 1;
@@ -697,12 +1301,215 @@ fn run<B: hal::Backend>(
                                 "Tonemapper Settings: {}",
                                 world.read_resource::<node::pbr::Aux>().tonemapper_args
                             );
+                            if let Some(counts) = world
+                                .write_resource::<draw_stats::DrawStats>()
+                                .take_averages()
+                            {
+                                log::info!("Per frame: {}", counts);
+                            }
+                            for (name, average, count) in profile::take_averages() {
+                                log::info!(
+                                    "  {}: {:.3} ms ({} times)",
+                                    name,
+                                    average.as_secs_f64() * 1000.0,
+                                    count
+                                );
+                            }
                             checkpoint += elapsed;
                             frames = 0;
                         }
                     }
                     _ => (),
                 }
+
+                // Like the graph, the scene is only switched between frames
+                let scene_request = world.as_mut().and_then(|world| {
+                    world
+                        .write_resource::<scene_manager::SceneManager>()
+                        .take_request()
+                });
+                if let (Some(index), Some(world)) = (scene_request, world.as_mut()) {
+                    let path = {
+                        let scene_manager = world.read_resource::<scene_manager::SceneManager>();
+                        scene_manager.path(index).to_owned()
+                    };
+                    let scene_config = world
+                        .read_resource::<scene_manager::SceneManager>()
+                        .read_config(index);
+                    match scene_config {
+                        Ok(scene_config) => {
+                            if let Some(graph) = pbr_graph.take() {
+                                log::info!("Switching to scene {}", path);
+                                let disposed = dispose_pbr_graph(&mut factory, world, graph);
+                                let mut load =
+                                    |world: &mut specs::World, scene_config: scene::SceneConfig| {
+                                        let environment_map = scene_config.environment_map.clone();
+                                        switch_scene(
+                                            &mut factory,
+                                            &mut families,
+                                            &upload_queues,
+                                            world,
+                                            &mut graph_settings,
+                                            scene_config,
+                                        )
+                                        .and_then(|()| {
+                                            replace_environment(
+                                                &mut factory,
+                                                &mut families,
+                                                &mut env_pipeline,
+                                                &environment_map,
+                                                &mut environment_filter,
+                                                world,
+                                            )
+                                        })
+                                        .and_then(|()| {
+                                            build_pbr_graph(
+                                                &mut factory,
+                                                &mut families,
+                                                queue,
+                                                world,
+                                                &window,
+                                                debug_window.as_ref(),
+                                                &graph_settings,
+                                            )
+                                        })
+                                    };
+                                let switched = match disposed
+                                    .and_then(|()| load(world, scene_config))
+                                {
+                                    Ok(graph) => {
+                                        world
+                                            .write_resource::<scene_manager::SceneManager>()
+                                            .set_current(index);
+                                        Ok(graph)
+                                    }
+                                    Err(e) => {
+                                        log::error!("Failed to switch to scene {}: {}", path, e);
+                                        // The current scene is unloaded by now, so it's loaded
+                                        // again, without the glTF file dropped last if any
+                                        let scene_config = {
+                                            let mut scene_manager = world
+                                                .write_resource::<scene_manager::SceneManager>(
+                                            );
+                                            scene_manager.discard_dropped();
+                                            let current = scene_manager.current();
+                                            log::info!(
+                                                "Loading scene {} again",
+                                                scene_manager.path(current)
+                                            );
+                                            scene_manager.read_config(current)
+                                        };
+                                        scene_config
+                                            .and_then(|scene_config| load(world, scene_config))
+                                    }
+                                };
+                                match switched {
+                                    Ok(graph) => {
+                                        pbr_graph = Some(graph);
+                                        // Built with the current settings already
+                                        reconfigure_graph = false;
+                                    }
+                                    Err(e) => {
+                                        log::error!("Failed to load the scene again: {}", e);
+                                        device_lost = true;
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Can't switch to scene {}: {}", path, e);
+                            world
+                                .write_resource::<scene_manager::SceneManager>()
+                                .discard_dropped();
+                        }
+                    }
+                }
+
+                let failed = std::mem::replace(&mut graph_failed, false);
+                if std::mem::replace(&mut reconfigure_graph, false) || failed {
+                    if let (Some(world), Some(graph)) = (world.as_mut(), pbr_graph.take()) {
+                        let rebuilt =
+                            dispose_pbr_graph(&mut factory, world, graph).and_then(|()| {
+                                build_pbr_graph(
+                                    &mut factory,
+                                    &mut families,
+                                    queue,
+                                    world,
+                                    &window,
+                                    debug_window.as_ref(),
+                                    &graph_settings,
+                                )
+                            });
+                        match rebuilt {
+                            Ok(graph) => {
+                                if failed {
+                                    log::warn!("Rebuilt the render graph after it failed to run");
+                                } else {
+                                    log::info!(
+                                        "Rebuilt the render graph: {:?}, debug views: {}, \
+                                         reference IBL accumulation: {}",
+                                        graph_settings.render_settings,
+                                        graph_settings.shader_debug_views,
+                                        graph_settings.ibl_accumulation
+                                    );
+                                }
+                                pbr_graph = Some(graph);
+                            }
+                            Err(e) => {
+                                log::error!("Failed to rebuild the render graph: {}", e);
+                                if let Some(smoke_test) = smoke_test.as_mut() {
+                                    smoke_test.build_failed();
+                                    test_failed = Some(true);
+                                }
+                                device_lost = true;
+                            }
+                        }
+                    }
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::DroppedFile(path),
+                ..
+            } => {
+                let extension = path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| ext.to_ascii_lowercase());
+                match (extension.as_ref().map(String::as_str), world.as_mut()) {
+                    (Some("hdr"), Some(world)) | (Some("exr"), Some(world)) => {
+                        log::info!("Loading dropped environment map: {:?}", path);
+                        let source = scene::EnvironmentSource::Equirectangular(
+                            path.to_string_lossy().into_owned(),
+                        );
+                        if let Err(e) = replace_environment(
+                            &mut factory,
+                            &mut families,
+                            &mut env_pipeline,
+                            &source,
+                            &mut environment_filter,
+                            world,
+                        ) {
+                            log::error!("Failed to load environment map {:?}: {}", path, e);
+                        }
+                    }
+                    (Some("gltf"), Some(world)) => {
+                        // The scene is loaded again with it after the frame, since the
+                        // graph is built for the meshes and materials of the scene
+                        log::info!("Adding dropped glTF file to the scene: {:?}", path);
+                        world
+                            .write_resource::<scene_manager::SceneManager>()
+                            .request_gltf(path.to_string_lossy().into_owned());
+                    }
+                    (Some("glb"), _) => log::warn!(
+                        "Can't load {:?}: binary glTF files aren't supported, only .gltf files \
+                         with their buffers and images beside them",
+                        path
+                    ),
+                    _ => log::warn!(
+                        "Dropped file {:?} is not a .gltf file or an .hdr or .exr environment map",
+                        path
+                    ),
+                }
             }
             // Close on close requested
             Event::WindowEvent {
@@ -710,12 +1517,70 @@ fn run<B: hal::Backend>(
                 ..
             } => {
                 let mut world = world.take().unwrap();
-                pbr_graph.take().unwrap().dispose(&mut factory, &mut world);
+
+                if headless && event_log.is_replay() {
+                    // Printed for runs to be compared, e.g. by CI
+                    match ron::ser::to_string_pretty(
+                        &event_log.summary(&world),
+                        ron::ser::PrettyConfig::default(),
+                    ) {
+                        Ok(summary) => println!("{}", summary),
+                        Err(e) => log::error!("Failed to write replay summary: {}", e),
+                    }
+                }
+
+                // A replay and a golden image test started from other settings than the user's
+                if !event_log.is_replay() && !golden_run {
+                    let tonemapper_args = world.read_resource::<node::pbr::Aux>().tonemapper_args;
+                    settings.exposure_ev100 = tonemapper_args.exposure_ev100;
+                    settings.tonemap_curve = tonemapper_args.curve;
+                    if settings.window.mode == settings::WindowMode::Windowed {
+                        let size = window.inner_size();
+                        settings.window.width = size.width;
+                        settings.window.height = size.height;
+                    }
+                    if let Err(e) = settings.save() {
+                        log::error!("Failed to save settings: {}", e);
+                    }
+                }
+
+                if let Some(path) = profile_path.as_ref() {
+                    if let Err(e) = profile::write_chrome_trace(std::path::Path::new(path)) {
+                        log::error!("Failed to write profile: {}", e);
+                    }
+                }
+
+                // Most likely the device was lost, then nothing can be disposed of
+                if device_lost {
+                    log::error!(
+                        "Closing without cleaning up, the render graph couldn't be rebuilt"
+                    );
+                    std::process::exit(1);
+                }
+
+                if let Some(filter) = environment_filter.take() {
+                    filter.dispose(&mut factory, &mut families).unwrap();
+                }
+                if let Some(voxel_gi) = voxel_gi.take() {
+                    voxel_gi.dispose(&mut factory).unwrap();
+                }
+                if let Some(graph) = pbr_graph.take() {
+                    graph.dispose(&mut factory, &mut world);
+                }
+                world
+                    .write_resource::<upload::PendingTransfers<B>>()
+                    .dispose(&mut factory)
+                    .unwrap();
+                validation::log_summary();
+
                 // world must be dropped before factory so that resources held in
                 // material/mesh/primitive storages can be sent back to the factory for
                 // disposal before it is destroyed.
                 std::mem::drop(world);
 
+                if test_failed == Some(true) {
+                    std::process::exit(1);
+                }
                 *control_flow = ControlFlow::Exit;
             }
             // Otherwise add the event to the bucket and continue polling
@@ -729,6 +1594,734 @@ fn run<B: hal::Backend>(
     });
 }
 
+/// Whether the payload of a panic in `Graph::run` is from the device or a surface being lost,
+/// or the swapchain being out of date. rendy panics with the gfx-hal error in the message, as
+/// running a graph can't fail otherwise.
+fn is_device_or_surface_loss(payload: &(dyn std::any::Any + Send)) -> bool {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("");
+    ["DeviceLost", "SurfaceLost", "OutOfDate"]
+        .iter()
+        .any(|error| message.contains(error))
+}
+
+/// Preprocesses a new environment map and swaps it in, restarting the filter on it. The
+/// previous irradiance and specular maps are shown until the first filter pass is done.
+fn replace_environment<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    env_pipeline: &mut node::env_preprocess::pipeline::EnvPipeline,
+    source: &scene::EnvironmentSource,
+    environment_filter: &mut Option<node::env_preprocess::filter::EnvironmentFilter<B>>,
+    world: &mut specs::World,
+) -> Result<(), failure::Error> {
+    let (mut preprocessed, filter) = env_pipeline.process(factory, families, source)?;
+
+    if let Some(filter) = environment_filter.take() {
+        filter.dispose(factory, families)?;
+    }
+    *environment_filter = Some(filter);
+
+    let env_cube = preprocessed.env_cube.take().unwrap();
+    {
+        let mut aux = world.write_resource::<node::pbr::Aux>();
+        aux.env_cube_mip_levels = env_cube.image().levels();
+        aux.cube_mip = None;
+    }
+
+    // The previous maps may still be in use by frames in flight
+    factory.wait_idle()?;
+    let mut env_storage = world.write_resource::<node::pbr::EnvironmentStorage<B>>();
+    env_storage.env_cube = Some(env_cube);
+    env_storage.spec_brdf_map = preprocessed.spec_brdf_map.take();
+    env_storage.spec_brdf_avg_map = preprocessed.spec_brdf_avg_map.take();
+    env_storage.generation += 1;
+    Ok(())
+}
+
+/// Replaces the scene in `world` with the one from `scene_config`, see `scene_manager`, and
+/// updates `settings` for the materials of the new one. The graph must be disposed of first,
+/// since it binds the assets of the current scene, and built again afterwards. The environment
+/// of the new scene is left for the caller to preprocess.
+fn switch_scene<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    upload_queues: &upload::UploadQueues,
+    world: &mut specs::World,
+    settings: &mut PbrGraphSettings,
+    scene_config: scene::SceneConfig,
+) -> Result<(), failure::Error> {
+    world.write_resource::<asset_gc::AssetGc<B>>().release();
+
+    scene_manager::unload(world);
+    let aspect = world.read_resource::<input::ViewportInfo>().aspect();
+    let mipmap_model_textures = scene_config.mipmap_model_textures;
+    let (material_storage, primitive_storage, geometry_pool, mesh_storage, lightmap_storage, _) = {
+        let _scope = profile::scope("scene load");
+        scene_config.load(aspect, factory, upload_queues, world)?
+    };
+    transfer_scene_assets(
+        factory,
+        families,
+        upload_queues,
+        (&material_storage, mipmap_model_textures),
+        &geometry_pool,
+        &lightmap_storage,
+        &mut world.write_resource::<upload::PendingTransfers<B>>(),
+    )?;
+
+    if let Some(debug_window_settings) = settings.debug_window.as_ref() {
+        let debug_window_aspect =
+            (debug_window_settings.size.0 / debug_window_settings.size.1) as f32;
+        let debug_cameras = world.read_storage::<components::DebugWindowCamera>();
+        let mut cameras = world.write_storage::<components::Camera>();
+        for (camera, _) in (&mut cameras, &debug_cameras).join() {
+            camera.aspect = debug_window_aspect;
+        }
+    }
+
+    settings.num_materials = material_storage.0.len();
+    settings.shader_variants =
+        material_shader_variants(&material_storage, settings.shader_debug_views);
+    {
+        let mut cache = world.write_resource::<systems::InstanceCache>();
+        *cache = systems::InstanceCache::new(
+            cache.dirty_entities.len(),
+            mesh_storage.0.len(),
+            material_storage.0.len(),
+        );
+    }
+    // The previous scene's assets are dropped here, the graph using them is gone
+    *world.write_resource::<asset::MaterialStorage<B>>() = material_storage;
+    *world.write_resource::<asset::PrimitiveStorage>() = primitive_storage;
+    *world.write_resource::<geometry_pool::GeometryPool<B>>() = geometry_pool;
+    *world.write_resource::<asset::MeshStorage>() = mesh_storage;
+    *world.write_resource::<asset::LightmapStorage<B>>() = lightmap_storage;
+    Ok(())
+}
+
+/// Hands the textures, uniform buffers and geometry of a loaded scene over to the graphics
+/// queue. The material textures of scenes which mipmap them are on the graphics queue already,
+/// see `UploadQueues::texture_state`.
+fn transfer_scene_assets<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queues: &upload::UploadQueues,
+    (material_storage, mipmapped): (&asset::MaterialStorage<B>, bool),
+    geometry_pool: &geometry_pool::GeometryPool<B>,
+    lightmap_storage: &asset::LightmapStorage<B>,
+    pending: &mut upload::PendingTransfers<B>,
+) -> Result<(), failure::Error> {
+    let scene_images = material_storage
+        .0
+        .values()
+        .filter(|_| !mipmapped)
+        .flat_map(|mat_data| {
+            vec![
+                &**mat_data.albedo.image(),
+                &**mat_data.normal.image(),
+                &**mat_data.metallic_roughness.image(),
+                &**mat_data.ao.image(),
+                &**mat_data.emissive.image(),
+                &**mat_data.bent_normal.image(),
+                &**mat_data.height.image(),
+                &**mat_data.thickness.image(),
+                &**mat_data.anisotropy.image(),
+            ]
+        })
+        .chain(
+            lightmap_storage
+                .lightmaps
+                .iter()
+                .filter_map(|lightmap| lightmap.texture.as_ref())
+                .chain(std::iter::once(&lightmap_storage.placeholder))
+                .map(|texture| &**texture.image()),
+        )
+        .collect::<Vec<_>>();
+    let buffers = material_storage
+        .0
+        .values()
+        .map(|mat_data| (&*mat_data.uniform_buffer, hal::buffer::Access::UNIFORM_READ))
+        .chain(vec![
+            (
+                &*geometry_pool.vertices,
+                hal::buffer::Access::VERTEX_BUFFER_READ,
+            ),
+            (
+                &*geometry_pool.lightmap_uvs,
+                hal::buffer::Access::VERTEX_BUFFER_READ,
+            ),
+            (
+                &*geometry_pool.indices,
+                hal::buffer::Access::INDEX_BUFFER_READ,
+            ),
+        ])
+        .collect::<Vec<_>>();
+    upload::transfer_to_graphics(factory, families, queues, &scene_images, &buffers, pending)
+}
+
+/// The shader variants the materials are drawn with, in one group per distinct variant.
+fn material_shader_variants<B: hal::Backend>(
+    material_storage: &asset::MaterialStorage<B>,
+    debug_views: bool,
+) -> Vec<shader::ShaderFeatures> {
+    let mut global_features = shader::ShaderFeatures::NONE;
+    if debug_views {
+        global_features |= shader::ShaderFeatures::DEBUG_VIEW;
+    }
+    let mut shader_variants = material_storage
+        .0
+        .values()
+        .map(|mat_data| mat_data.features | global_features)
+        .collect::<Vec<_>>();
+    shader_variants.sort();
+    shader_variants.dedup();
+    shader_variants
+}
+
+/// What the PBR graph is built from besides the world, kept to build it again when its
+/// swapchain or device has to be replaced, or its passes are toggled. The graphs capturing
+/// cubes, like for probes and panoramas, draw with the same pipelines.
+#[derive(Clone)]
+pub struct PbrGraphSettings {
+    shader_variants: Vec<shader::ShaderFeatures>,
+    num_materials: usize,
+    reversed_z: bool,
+    render_settings: scene::RenderSettings,
+    /// Whether `shader_variants` include the material debug views
+    shader_debug_views: bool,
+    /// Whether the main view's frames are averaged for the reference IBL, see
+    /// `node::pbr::accumulate`
+    ibl_accumulation: bool,
+    color_target_format: hal::format::Format,
+    /// Of the scene's resolution relative to the window's
+    render_scale: f64,
+    vsync: Option<bool>,
+    debug_window: Option<scene::DebugWindowSettings>,
+}
+
+/// Adds the nodes drawing the scene into the main window's `surface`, and into the debug
+/// window if there is one, to a new graph builder. The shadow map, the size of the main window
+/// and everything the pipelines read are taken from `world`.
+fn pbr_graph_builder<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    queue: QueueId,
+    world: &specs::World,
+    surface: rendy::wsi::Surface<B>,
+    debug_window: Option<&Window>,
+    settings: &PbrGraphSettings,
+    pbr_description: &mut graph_dump::GraphDescription,
+) -> Result<GraphBuilder<B, specs::World>, failure::Error> {
+    let viewport = *world.read_resource::<input::ViewportInfo>();
+    let size = viewport.physical_size;
+    let render_size = viewport.render_size(settings.render_scale);
+    world
+        .write_resource::<node::pbr::Aux>()
+        .split_view
+        .render_width = render_size.width as u32;
+    let reversed_z = settings.reversed_z;
+
+    let mut pbr_graph_builder = GraphBuilder::<B, specs::World>::new();
+
+    let shadow_nodes = node::pbr::shadow::add_shadow_nodes(
+        &mut pbr_graph_builder,
+        pbr_description,
+        &world.read_resource::<node::pbr::shadow::ShadowMap<B>>(),
+        queue.family,
+    );
+
+    let hdr = pbr_description.create_image(
+        &mut pbr_graph_builder,
+        "hdr",
+        hal::image::Kind::D2(render_size.width as u32, render_size.height as u32, 1, 1),
+        1,
+        settings.color_target_format,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.1, 0.3, 0.4, 1.0],
+            },
+        }),
+    );
+
+    let surface_format = factory.get_surface_format(&surface);
+    let color = pbr_description.create_image(
+        &mut pbr_graph_builder,
+        "color",
+        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
+        1,
+        surface_format,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.1, 0.3, 0.4, 1.0],
+            },
+        }),
+    );
+
+    let depth = pbr_description.create_image(
+        &mut pbr_graph_builder,
+        "depth",
+        hal::image::Kind::D2(render_size.width as u32, render_size.height as u32, 1, 1),
+        1,
+        hal::format::Format::D32Sfloat,
+        Some(hal::command::ClearValue {
+            depth_stencil: hal::command::ClearDepthStencil {
+                depth: if reversed_z { 0.0 } else { 1.0 },
+                stencil: 0,
+            },
+        }),
+    );
+
+    let occlusion_culling = if settings.render_settings.occlusion_culling {
+        Some(node::pbr::hiz::add_occlusion_culling_nodes(
+            &mut pbr_graph_builder,
+            pbr_description,
+            world,
+            render_size.width as u32,
+            render_size.height as u32,
+            reversed_z,
+            queue.family,
+        ))
+    } else {
+        None
+    };
+
+    let mut mesh_subpass = node::pbr::scene_subpass(
+        factory,
+        &settings.shader_variants,
+        settings.num_materials,
+        reversed_z,
+        node::pbr::View::Main,
+        occlusion_culling
+            .as_ref()
+            .map(|(_, culled_draws)| culled_draws),
+    );
+    for node in shadow_nodes.iter() {
+        mesh_subpass = mesh_subpass.with_dependency(*node);
+    }
+    if let Some((cull_node, _)) = occlusion_culling {
+        mesh_subpass = mesh_subpass.with_dependency(cull_node);
+    }
+    let mesh_pass = pbr_description.add_node(
+        &mut pbr_graph_builder,
+        "scene",
+        mesh_subpass
+            .with_color(hdr)
+            .with_depth_stencil(depth)
+            .into_pass(),
+    );
+
+    // Subsurface scattering materials are drawn again, and their blurred diffuse light is
+    // swapped into a copy of the HDR target read in its place
+    let (scene_pass, scene) = if settings.render_settings.subsurface_scattering
+        && node::pbr::subsurface::has_subsurface_variants(&settings.shader_variants)
+    {
+        node::pbr::subsurface::add_subsurface_nodes(
+            &mut pbr_graph_builder,
+            pbr_description,
+            factory,
+            &settings.shader_variants,
+            settings.num_materials,
+            reversed_z,
+            hdr,
+            depth,
+            render_size.width as u32,
+            render_size.height as u32,
+            settings.color_target_format,
+            queue.family,
+            mesh_pass,
+        )
+    } else {
+        (mesh_pass, hdr)
+    };
+
+    // The reference IBL's frames are averaged into an image read in place of the HDR target
+    let (shaded_pass, shaded) = if settings.ibl_accumulation {
+        node::pbr::accumulate::add_accumulate_node(
+            &mut pbr_graph_builder,
+            pbr_description,
+            scene,
+            render_size.width as u32,
+            render_size.height as u32,
+            queue.family,
+            scene_pass,
+        )
+    } else {
+        (scene_pass, scene)
+    };
+
+    let mut tonemap_subpass = node::pbr::tonemap::Pipeline::builder()
+        .with_image(shaded)
+        .into_subpass()
+        .with_dependency(shaded_pass);
+    if settings.render_settings.scopes {
+        let (scopes_node, scopes) = node::pbr::scopes::add_scopes_node(
+            &mut pbr_graph_builder,
+            pbr_description,
+            shaded,
+            queue.family,
+            shaded_pass,
+        );
+        tonemap_subpass = tonemap_subpass
+            .with_group(
+                node::pbr::scopes::PipelineDesc::new(
+                    render_size.width as u32,
+                    render_size.height as u32,
+                )
+                .builder()
+                .with_buffer(scopes),
+            )
+            .with_dependency(scopes_node);
+    }
+    let tonemap_pass = pbr_description.add_node(
+        &mut pbr_graph_builder,
+        "tonemap",
+        tonemap_subpass.with_color(color).into_pass(),
+    );
+
+    let mut present = PresentNode::builder(factory, surface, color).with_dependency(tonemap_pass);
+    if let Some(vsync) = settings.vsync {
+        // Higher priority is preferred, modes without one are never picked
+        present = present.with_present_modes_priority(move |mode| match (vsync, mode) {
+            (true, hal::window::PresentMode::Fifo) => Some(1),
+            (true, hal::window::PresentMode::Relaxed) => Some(0),
+            (false, hal::window::PresentMode::Immediate) => Some(1),
+            (false, hal::window::PresentMode::Mailbox) => Some(0),
+            _ => None,
+        });
+    }
+    pbr_description.add_node(&mut pbr_graph_builder, "present", present);
+
+    if let (Some(window), Some(debug_settings)) = (debug_window, settings.debug_window.as_ref()) {
+        add_debug_window(
+            factory,
+            &mut pbr_graph_builder,
+            pbr_description,
+            (window, debug_settings),
+            settings,
+            &shadow_nodes,
+            surface_format,
+        )?;
+    }
+
+    Ok(pbr_graph_builder.with_frames_in_flight(FRAMES_IN_FLIGHT))
+}
+
+/// Disposes of a graph which failed to run or is about to be replaced, once its frames are done.
+/// The world's resources, like the assets and environment, are kept for the next one. Fails if
+/// the device itself was lost, since every asset was allocated from it and rendy's factory
+/// can't be re-created in place.
+fn dispose_pbr_graph<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    world: &mut specs::World,
+    graph: rendy::graph::Graph<B, specs::World>,
+) -> Result<(), failure::Error> {
+    use hal::device::Device as _;
+
+    if let Err(e) = factory.device().wait_idle() {
+        // Waiting for its frames would fail too
+        std::mem::forget(graph);
+        failure::bail!("The device was lost: {:?}", e);
+    }
+    graph.dispose(factory, world);
+    Ok(())
+}
+
+/// Builds the graph with new swapchains for the windows, after the previous one is disposed.
+fn build_pbr_graph<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    world: &mut specs::World,
+    window: &Window,
+    debug_window: Option<&Window>,
+    settings: &PbrGraphSettings,
+) -> Result<rendy::graph::Graph<B, specs::World>, failure::Error> {
+    let surface = factory
+        .create_surface(window)
+        .map_err(|e| failure::format_err!("Failed to create surface: {:?}", e))?;
+    Ok(pbr_graph_builder(
+        factory,
+        queue,
+        world,
+        surface,
+        debug_window,
+        settings,
+        &mut graph_dump::GraphDescription::new("pbr"),
+    )?
+    .build(factory, families, world)?)
+}
+
+fn open_debug_window(
+    event_loop: &EventLoop<()>,
+    settings: &scene::DebugWindowSettings,
+    visible: bool,
+) -> Result<Window, failure::Error> {
+    Ok(WindowBuilder::new()
+        .with_title("rendy-pbr debug")
+        .with_inner_size(winit::dpi::LogicalSize::new(
+            settings.size.0,
+            settings.size.1,
+        ))
+        .with_visible(visible)
+        .build(event_loop)?)
+}
+
+/// Adds the nodes drawing into the debug window to the main graph, after the shadow map
+/// cascades. It has targets of its own but shares everything else with the main window, so
+/// what it shows is always in sync with it.
+fn add_debug_window<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    graph_builder: &mut GraphBuilder<B, specs::World>,
+    graph_description: &mut graph_dump::GraphDescription,
+    (window, window_settings): (&Window, &scene::DebugWindowSettings),
+    settings: &PbrGraphSettings,
+    shadow_nodes: &[rendy::graph::NodeId],
+    surface_format: hal::format::Format,
+) -> Result<(), failure::Error> {
+    use rendy::graph::render::{RenderGroupBuilder, SimpleGraphicsPipelineDesc};
+
+    let reversed_z = settings.reversed_z;
+
+    let surface = factory
+        .create_surface(window)
+        .map_err(|e| failure::format_err!("Failed to create debug window surface: {:?}", e))?;
+    let size = input::ViewportInfo::new(window).physical_size;
+
+    let hdr = graph_description.create_image(
+        graph_builder,
+        "debug_window_hdr",
+        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
+        1,
+        settings.color_target_format,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.1, 0.3, 0.4, 1.0],
+            },
+        }),
+    );
+    // Blitted to the swapchain, which converts to its format if it differs
+    let color = graph_description.create_image(
+        graph_builder,
+        "debug_window_color",
+        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
+        1,
+        surface_format,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.1, 0.3, 0.4, 1.0],
+            },
+        }),
+    );
+    let depth = graph_description.create_image(
+        graph_builder,
+        "debug_window_depth",
+        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
+        1,
+        hal::format::Format::D32Sfloat,
+        Some(hal::command::ClearValue {
+            depth_stencil: hal::command::ClearDepthStencil {
+                depth: if reversed_z { 0.0 } else { 1.0 },
+                stencil: 0,
+            },
+        }),
+    );
+
+    let mut subpass = match window_settings.cube_map {
+        Some(cube_display) => node::pbr::environment_map::PipelineDesc::default()
+            .with_reversed_z(reversed_z)
+            .with_view(node::pbr::View::DebugWindow)
+            .with_cube_display(cube_display)
+            .builder()
+            .into_subpass(),
+        None => node::pbr::scene_subpass(
+            factory,
+            &settings.shader_variants,
+            settings.num_materials,
+            reversed_z,
+            node::pbr::View::DebugWindow,
+            None,
+        ),
+    };
+    for node in shadow_nodes {
+        subpass = subpass.with_dependency(*node);
+    }
+    let scene_pass = graph_description.add_node(
+        graph_builder,
+        "debug_window_scene",
+        subpass
+            .with_color(hdr)
+            .with_depth_stencil(depth)
+            .into_pass(),
+    );
+
+    let tonemap_pass = graph_description.add_node(
+        graph_builder,
+        "debug_window_tonemap",
+        node::pbr::tonemap::PipelineDesc::default()
+            .with_view(node::pbr::View::DebugWindow)
+            .builder()
+            .with_image(hdr)
+            .into_subpass()
+            .with_dependency(scene_pass)
+            .with_color(color)
+            .into_pass(),
+    );
+
+    // Never waits for vertical sync, which the main window already does if asked to
+    graph_description.add_node(
+        graph_builder,
+        "debug_window_present",
+        PresentNode::builder(factory, surface, color)
+            .with_dependency(tonemap_pass)
+            .with_present_modes_priority(|mode| match mode {
+                hal::window::PresentMode::Mailbox => Some(2),
+                hal::window::PresentMode::Immediate => Some(1),
+                hal::window::PresentMode::Fifo => Some(0),
+                _ => None,
+            }),
+    );
+
+    Ok(())
+}
+
+/// Draws the scene into the six faces of a cube from the position of the active camera and
+/// saves them to `path` as an equirectangular OpenEXR image. Builds a graph of its own and
+/// waits for it, so it stalls the app for a moment.
+fn capture_panorama<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    world: &mut specs::World,
+    settings: &PbrGraphSettings,
+    path: &std::path::Path,
+) -> Result<(), failure::Error> {
+    let (camera, position) = active_camera(world)?;
+
+    let mut renderer = node::pbr::capture::CubeRenderer::new(
+        factory,
+        families,
+        queue,
+        world,
+        settings,
+        PANORAMA_FACE_RES,
+    )?;
+    let faces = renderer.render(factory, families, world, position, &camera);
+    renderer.dispose(factory, world);
+    let faces = faces?;
+
+    let height = PANORAMA_FACE_RES * 2;
+    let texels = node::pbr::capture::to_equirectangular(&faces, height);
+    asset::save_exr(path, height * 2, height, &texels)?;
+    log::info!("Saved panorama to {:?}", path);
+    Ok(())
+}
+
+/// Draws the scene from the active camera at the main view's resolution and saves it to `path`
+/// as an OpenEXR image, before tonemapping. Drawn like a panorama's faces, so without the grid
+/// and other overlays. Builds a graph of its own and waits for it.
+fn capture_screenshot<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    world: &mut specs::World,
+    settings: &PbrGraphSettings,
+    path: &std::path::Path,
+) -> Result<(), failure::Error> {
+    let camera = world
+        .read_resource::<render_frame::RenderFrames>()
+        .front()
+        .main_view
+        .ok_or_else(|| failure::format_err!("No active camera"))?
+        .camera;
+    let render_size = world
+        .read_resource::<input::ViewportInfo>()
+        .render_size(settings.render_scale);
+    let (width, height) = (render_size.width as u32, render_size.height as u32);
+
+    let mut renderer = node::pbr::capture::CubeRenderer::single_view(
+        factory,
+        families,
+        queue,
+        world,
+        settings,
+        (width, height),
+    )?;
+    let view = renderer.render_view(factory, families, world, camera);
+    renderer.dispose(factory, world);
+    let view = view?;
+
+    asset::save_exr(path, width, height, &view.texels)?;
+    log::info!("Saved screenshot to {:?}", path);
+    Ok(())
+}
+
+/// Bakes the light probe grid by drawing the scene into a cube at each probe, then replaces
+/// the probes used by the mesh pipeline. The cubes are lit by the probes and environment maps
+/// already in place, so each bake adds a bounce of indirect light.
+fn bake_probes<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    world: &mut specs::World,
+    settings: &PbrGraphSettings,
+    probe_grid: &probes::ProbeGridSettings,
+) -> Result<(), failure::Error> {
+    let (camera, _) = active_camera(world)?;
+    let started = time::Instant::now();
+
+    let mut renderer = node::pbr::capture::CubeRenderer::new(
+        factory,
+        families,
+        queue,
+        world,
+        settings,
+        probe_grid.resolution,
+    )?;
+    let coefficients = probe_grid
+        .positions()
+        .into_iter()
+        .map(|position| {
+            renderer
+                .render(factory, families, world, position, &camera)
+                .map(|faces| probes::project_irradiance(&faces))
+        })
+        .collect::<Result<Vec<_>, _>>();
+    renderer.dispose(factory, world);
+    let coefficients = coefficients?;
+
+    let buffer = probes::create_buffer(factory, Some((probe_grid, &coefficients)))?;
+    // The previous probes may still be in use by frames in flight
+    factory.wait_idle()?;
+    let mut env_storage = world.write_resource::<node::pbr::EnvironmentStorage<B>>();
+    env_storage.probes = Some(buffer);
+    env_storage.generation += 1;
+    log::info!(
+        "Baked {} light probes in {:?}",
+        coefficients.len(),
+        started.elapsed()
+    );
+    Ok(())
+}
+
+/// The active camera and its position.
+fn active_camera(
+    world: &specs::World,
+) -> Result<(components::Camera, nalgebra::Point3<f32>), failure::Error> {
+    use specs::prelude::*;
+
+    let transforms = world.read_storage::<components::GlobalTransform>();
+    let cameras = world.read_storage::<components::Camera>();
+    let active_cameras = world.read_storage::<components::ActiveCamera>();
+    (&active_cameras, &cameras, &transforms)
+        .join()
+        .map(|(_, cam, trans)| (*cam, nalgebra::Point3::from(trans.0.column(3).xyz())))
+        .next()
+        .ok_or_else(|| failure::format_err!("No active camera"))
+}
+
 #[cfg(not(any(feature = "dx12", feature = "metal", feature = "vulkan")))]
 fn main() -> Result<(), failure::Error> {
     panic!("Specify feature: { dx12, metal, vulkan }");
@@ -0,0 +1,128 @@
+//! Totals of the GPU memory held by assets and pipelines, to keep an eye on as scenes grow.
+//!
+//! Assets are gathered from their storages by `systems::GpuMemoryStatsSystem` whenever they
+//! change, and pipelines add what they allocate themselves when they're built and remove it
+//! when they're disposed. The images of the render graphs are allocated by rendy and aren't
+//! counted.
+use rendy::{
+    hal,
+    memory::Block,
+    resource::{Buffer, Image},
+};
+
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MemoryTotals {
+    pub count: usize,
+    /// Bytes in device local memory, which may also be host visible
+    pub device_local: u64,
+    /// Bytes in host memory the device reads over the bus
+    pub host: u64,
+}
+
+impl MemoryTotals {
+    pub fn add_buffer<B: hal::Backend>(&mut self, buffer: &Buffer<B>) {
+        self.add_block(buffer.block());
+    }
+
+    /// Images without memory of their own, like swapchain images, aren't counted.
+    pub fn add_image<B: hal::Backend>(&mut self, image: &Image<B>) {
+        if let Some(block) = image.block() {
+            self.add_block(block);
+        }
+    }
+
+    /// For allocations whose memory can't be looked at, like the buffers of rendy meshes.
+    pub fn add_bytes(&mut self, size: u64, device_local: bool) {
+        self.count += 1;
+        if device_local {
+            self.device_local += size;
+        } else {
+            self.host += size;
+        }
+    }
+
+    fn add_block<B: hal::Backend>(&mut self, block: &impl Block<B>) {
+        self.add_bytes(
+            block.size(),
+            block
+                .properties()
+                .contains(hal::memory::Properties::DEVICE_LOCAL),
+        );
+    }
+
+    pub fn total(&self) -> u64 {
+        self.device_local + self.host
+    }
+}
+
+impl std::ops::AddAssign for MemoryTotals {
+    fn add_assign(&mut self, other: MemoryTotals) {
+        self.count += other.count;
+        self.device_local += other.device_local;
+        self.host += other.host;
+    }
+}
+
+impl fmt::Display for MemoryTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in {} allocations ({} device local, {} host)",
+            Mebibytes(self.total()),
+            self.count,
+            Mebibytes(self.device_local),
+            Mebibytes(self.host)
+        )
+    }
+}
+
+struct Mebibytes(u64);
+
+impl fmt::Display for Mebibytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2} MiB", self.0 as f64 / (1024.0 * 1024.0))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GpuMemoryStats {
+    /// By kind of asset, e.g. "textures"
+    pub assets: BTreeMap<&'static str, MemoryTotals>,
+    /// Indexed by the ids `add_pipeline` returns, `None` once removed
+    pipelines: Vec<Option<(String, MemoryTotals)>>,
+}
+
+impl GpuMemoryStats {
+    /// Adds the memory a pipeline allocated, returning the id to remove it by.
+    pub fn add_pipeline(&mut self, name: String, totals: MemoryTotals) -> usize {
+        self.pipelines.push(Some((name, totals)));
+        self.pipelines.len() - 1
+    }
+
+    pub fn remove_pipeline(&mut self, id: usize) {
+        self.pipelines[id] = None;
+    }
+
+    pub fn total(&self) -> MemoryTotals {
+        let mut total = MemoryTotals::default();
+        for totals in self.assets.values() {
+            total += *totals;
+        }
+        for (_, totals) in self.pipelines.iter().flatten() {
+            total += *totals;
+        }
+        total
+    }
+
+    pub fn log_summary(&self) {
+        log::info!("GPU memory: {}", self.total());
+        for (kind, totals) in self.assets.iter() {
+            log::info!("  {}: {}", kind, totals);
+        }
+        for (name, totals) in self.pipelines.iter().flatten() {
+            log::info!("  {} pipeline: {}", name, totals);
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! Minimal loaders for Wavefront OBJ and Stanford PLY meshes, for assets which aren't
+//! available as glTF. Only triangle and polygon geometry is read; OBJ materials and groups
+//! are ignored. Normals and tangents are generated when the file doesn't have them.
+use rendy::mesh::PosNormTangTex;
+
+use std::{collections::HashMap, path::Path};
+
+/// Mesh data as read from a file, before missing attributes are generated
+pub struct MeshData {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Option<Vec<[f32; 3]>>,
+    pub tex_coords: Option<Vec<[f32; 2]>>,
+    pub indices: Vec<u32>,
+}
+
+/// Loads an `.obj` or `.ply` file, depending on its extension.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<MeshData, failure::Error> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    let bytes = std::fs::read(path)?;
+    match extension.as_ref().map(String::as_str) {
+        Some("obj") => parse_obj(&String::from_utf8_lossy(&bytes)),
+        Some("ply") => parse_ply(&bytes),
+        _ => failure::bail!("{:?} is neither an OBJ nor a PLY file", path),
+    }
+    .map_err(|e| failure::format_err!("Failed to load {:?}: {}", path, e))
+}
+
+fn parse_obj(text: &str) -> Result<MeshData, failure::Error> {
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut tex_coords = Vec::new();
+
+    // Each distinct position/tex coord/normal combination becomes one vertex
+    let mut vertices: HashMap<(usize, Option<usize>, Option<usize>), u32> = HashMap::new();
+    let mut corners = Vec::new();
+    let mut indices = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let parse_floats =
+            |tokens: std::str::SplitWhitespace, n: usize| -> Result<Vec<f32>, failure::Error> {
+                let values = tokens
+                    .take(n)
+                    .map(|token| token.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| failure::format_err!("line {}: {}", line_index + 1, e))?;
+                if values.len() < n {
+                    failure::bail!("line {}: expected {} values", line_index + 1, n);
+                }
+                Ok(values)
+            };
+        match tokens.next() {
+            Some("v") => {
+                let v = parse_floats(tokens, 3)?;
+                positions.push([v[0], v[1], v[2]]);
+            }
+            Some("vn") => {
+                let v = parse_floats(tokens, 3)?;
+                normals.push([v[0], v[1], v[2]]);
+            }
+            Some("vt") => {
+                let v = parse_floats(tokens, 2)?;
+                // OBJ tex coords start at the bottom left
+                tex_coords.push([v[0], 1.0 - v[1]]);
+            }
+            Some("f") => {
+                let face_start = corners.len();
+                for corner in tokens {
+                    let mut parts = corner.split('/');
+                    let resolve =
+                        |part: Option<&str>, len: usize| -> Result<Option<usize>, failure::Error> {
+                            match part {
+                                None | Some("") => Ok(None),
+                                Some(part) => {
+                                    let index = part.parse::<i64>().map_err(|e| {
+                                        failure::format_err!("line {}: {}", line_index + 1, e)
+                                    })?;
+                                    // Negative indices count back from the latest element
+                                    let index = if index < 0 {
+                                        len as i64 + index
+                                    } else {
+                                        index - 1
+                                    };
+                                    if index < 0 || index >= len as i64 {
+                                        failure::bail!(
+                                            "line {}: index {} out of bounds",
+                                            line_index + 1,
+                                            part
+                                        );
+                                    }
+                                    Ok(Some(index as usize))
+                                }
+                            }
+                        };
+                    let position = resolve(parts.next(), positions.len())?.ok_or_else(|| {
+                        failure::format_err!("line {}: face without position", line_index + 1)
+                    })?;
+                    let tex_coord = resolve(parts.next(), tex_coords.len())?;
+                    let normal = resolve(parts.next(), normals.len())?;
+                    corners.push((position, tex_coord, normal));
+                }
+                if corners.len() - face_start < 3 {
+                    failure::bail!("line {}: face with less than 3 corners", line_index + 1);
+                }
+                // Triangulate polygons as a fan
+                for i in 1..corners.len() - face_start - 1 {
+                    for &corner in &[face_start, face_start + i, face_start + i + 1] {
+                        let next_index = vertices.len() as u32;
+                        indices.push(*vertices.entry(corners[corner]).or_insert(next_index));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut ordered = vertices.into_iter().collect::<Vec<_>>();
+    ordered.sort_by_key(|(_, index)| *index);
+
+    // Attributes are only used if every vertex has them
+    let has_tex_coords = ordered.iter().all(|((_, t, _), _)| t.is_some());
+    let has_normals = ordered.iter().all(|((_, _, n), _)| n.is_some());
+
+    Ok(MeshData {
+        positions: ordered.iter().map(|((p, _, _), _)| positions[*p]).collect(),
+        normals: if has_normals {
+            Some(
+                ordered
+                    .iter()
+                    .map(|((_, _, n), _)| normals[n.unwrap()])
+                    .collect(),
+            )
+        } else {
+            None
+        },
+        tex_coords: if has_tex_coords {
+            Some(
+                ordered
+                    .iter()
+                    .map(|((_, t, _), _)| tex_coords[t.unwrap()])
+                    .collect(),
+            )
+        } else {
+            None
+        },
+        indices,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<Self, failure::Error> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => failure::bail!("unknown property type {}", name),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+}
+
+enum PlyProperty {
+    Scalar(String, PlyType),
+    List(String, PlyType, PlyType),
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+/// Reads the values of a PLY body, either as text or little endian binary
+enum PlyReader<'a> {
+    Ascii(std::str::SplitWhitespace<'a>),
+    Binary(&'a [u8]),
+}
+
+impl<'a> PlyReader<'a> {
+    fn read(&mut self, ty: PlyType) -> Result<f64, failure::Error> {
+        match self {
+            PlyReader::Ascii(tokens) => Ok(tokens
+                .next()
+                .ok_or_else(|| failure::format_err!("unexpected end of data"))?
+                .parse::<f64>()?),
+            PlyReader::Binary(bytes) => {
+                let size = ty.size();
+                if bytes.len() < size {
+                    failure::bail!("unexpected end of data");
+                }
+                let data: &'a [u8] = bytes;
+                let (value, rest) = data.split_at(size);
+                *bytes = rest;
+                let mut buf = [0u8; 8];
+                buf[..size].copy_from_slice(value);
+                Ok(match ty {
+                    PlyType::I8 => value[0] as i8 as f64,
+                    PlyType::U8 => value[0] as f64,
+                    PlyType::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    PlyType::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    PlyType::I32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    PlyType::U32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    PlyType::F32 => {
+                        f32::from_bits(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])) as f64
+                    }
+                    PlyType::F64 => f64::from_bits(u64::from_le_bytes(buf)),
+                })
+            }
+        }
+    }
+}
+
+fn parse_ply(bytes: &[u8]) -> Result<MeshData, failure::Error> {
+    const END_HEADER: &[u8] = b"end_header";
+    let header_end = bytes
+        .windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+        .ok_or_else(|| failure::format_err!("no end_header"))?;
+    let body_start = bytes[header_end..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map(|newline| header_end + newline + 1)
+        .unwrap_or(bytes.len());
+    let header = String::from_utf8_lossy(&bytes[..header_end]);
+
+    let mut lines = header.lines();
+    if lines.next().map(str::trim) != Some("ply") {
+        failure::bail!("not a PLY file");
+    }
+
+    let mut binary = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    for line in lines {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        match tokens.first().cloned() {
+            Some("format") => match tokens.get(1).cloned() {
+                Some("ascii") => binary = Some(false),
+                Some("binary_little_endian") => binary = Some(true),
+                format => failure::bail!("unsupported format {:?}", format),
+            },
+            Some("element") if tokens.len() == 3 => elements.push(PlyElement {
+                name: tokens[1].to_string(),
+                count: tokens[2].parse()?,
+                properties: Vec::new(),
+            }),
+            Some("property") => {
+                let property = if tokens.len() == 5 && tokens[1] == "list" {
+                    PlyProperty::List(
+                        tokens[4].to_string(),
+                        PlyType::parse(tokens[2])?,
+                        PlyType::parse(tokens[3])?,
+                    )
+                } else if tokens.len() == 3 {
+                    PlyProperty::Scalar(tokens[2].to_string(), PlyType::parse(tokens[1])?)
+                } else {
+                    failure::bail!("malformed property: {}", line)
+                };
+                elements
+                    .last_mut()
+                    .ok_or_else(|| failure::format_err!("property outside of an element"))?
+                    .properties
+                    .push(property);
+            }
+            _ => (),
+        }
+    }
+
+    let body = &bytes[body_start..];
+    let mut reader = match binary {
+        Some(true) => PlyReader::Binary(body),
+        Some(false) => PlyReader::Ascii(
+            std::str::from_utf8(body)
+                .map_err(|e| failure::format_err!("{}", e))?
+                .split_whitespace(),
+        ),
+        None => failure::bail!("missing format"),
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut tex_coords = Vec::new();
+    let mut indices = Vec::new();
+    let (mut has_normals, mut has_tex_coords) = (false, false);
+
+    for element in &elements {
+        for _ in 0..element.count {
+            let mut scalars = HashMap::new();
+            let mut face = Vec::new();
+            for property in &element.properties {
+                match property {
+                    PlyProperty::Scalar(name, ty) => {
+                        scalars.insert(name.as_str(), reader.read(*ty)? as f32);
+                    }
+                    PlyProperty::List(name, count_ty, item_ty) => {
+                        let count = reader.read(*count_ty)? as usize;
+                        for _ in 0..count {
+                            let value = reader.read(*item_ty)?;
+                            if name == "vertex_indices" || name == "vertex_index" {
+                                face.push(value as u32);
+                            }
+                        }
+                    }
+                }
+            }
+
+            match element.name.as_str() {
+                "vertex" => {
+                    let get = |names: &[&str]| names.iter().filter_map(|n| scalars.get(n)).next();
+                    let (x, y, z) = match (get(&["x"]), get(&["y"]), get(&["z"])) {
+                        (Some(x), Some(y), Some(z)) => (*x, *y, *z),
+                        _ => failure::bail!("vertex without a position"),
+                    };
+                    positions.push([x, y, z]);
+                    if let (Some(nx), Some(ny), Some(nz)) =
+                        (get(&["nx"]), get(&["ny"]), get(&["nz"]))
+                    {
+                        has_normals = true;
+                        normals.push([*nx, *ny, *nz]);
+                    }
+                    if let (Some(u), Some(v)) = (
+                        get(&["u", "s", "texture_u", "texture_s"]),
+                        get(&["v", "t", "texture_v", "texture_t"]),
+                    ) {
+                        has_tex_coords = true;
+                        // Like OBJ, tex coords start at the bottom left
+                        tex_coords.push([*u, 1.0 - *v]);
+                    }
+                }
+                "face" => {
+                    if face.len() < 3 {
+                        failure::bail!("face with less than 3 corners");
+                    }
+                    for i in 1..face.len() - 1 {
+                        indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    if let Some(index) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+        failure::bail!("vertex index {} out of bounds", index);
+    }
+
+    Ok(MeshData {
+        normals: if has_normals && normals.len() == positions.len() {
+            Some(normals)
+        } else {
+            None
+        },
+        tex_coords: if has_tex_coords && tex_coords.len() == positions.len() {
+            Some(tex_coords)
+        } else {
+            None
+        },
+        positions,
+        indices,
+    })
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> nalgebra::Vector3<f32> {
+    nalgebra::Vector3::new(a[0] - b[0], a[1] - b[1], a[2] - b[2])
+}
+
+impl MeshData {
+    /// Builds the vertices, generating smooth normals and tangents if they are missing.
+    /// Meshes without tex coords get zeroed ones, and an arbitrary tangent.
+    pub fn into_vertices(self) -> (Vec<PosNormTangTex>, Vec<u32>) {
+        let MeshData {
+            positions,
+            normals,
+            tex_coords,
+            indices,
+        } = self;
+
+        let normals = normals.unwrap_or_else(|| {
+            // Area weighted, since the cross product is twice the triangle's area
+            let mut normals = vec![nalgebra::Vector3::zeros(); positions.len()];
+            for tri in indices.chunks(3) {
+                let (a, b, c) = (tri[0] as usize, tri[1] as usize, tri[2] as usize);
+                let normal =
+                    sub(positions[b], positions[a]).cross(&sub(positions[c], positions[a]));
+                for &i in &[a, b, c] {
+                    normals[i] += normal;
+                }
+            }
+            normals
+                .into_iter()
+                .map(|n| {
+                    let n = n.try_normalize(1.0e-12).unwrap_or(nalgebra::Vector3::y());
+                    [n.x, n.y, n.z]
+                })
+                .collect()
+        });
+
+        let tangents = match &tex_coords {
+            Some(tex_coords) => {
+                let mut tangents = vec![nalgebra::Vector3::zeros(); positions.len()];
+                let mut bitangents = vec![nalgebra::Vector3::zeros(); positions.len()];
+                for tri in indices.chunks(3) {
+                    let (a, b, c) = (tri[0] as usize, tri[1] as usize, tri[2] as usize);
+                    let e1 = sub(positions[b], positions[a]);
+                    let e2 = sub(positions[c], positions[a]);
+                    let (du1, dv1) = (
+                        tex_coords[b][0] - tex_coords[a][0],
+                        tex_coords[b][1] - tex_coords[a][1],
+                    );
+                    let (du2, dv2) = (
+                        tex_coords[c][0] - tex_coords[a][0],
+                        tex_coords[c][1] - tex_coords[a][1],
+                    );
+                    let det = du1 * dv2 - du2 * dv1;
+                    if det.abs() < 1.0e-12 {
+                        continue;
+                    }
+                    let r = 1.0 / det;
+                    let tangent = (e1 * dv2 - e2 * dv1) * r;
+                    let bitangent = (e2 * du1 - e1 * du2) * r;
+                    for &i in &[a, b, c] {
+                        tangents[i] += tangent;
+                        bitangents[i] += bitangent;
+                    }
+                }
+                tangents
+                    .into_iter()
+                    .zip(bitangents.into_iter())
+                    .zip(normals.iter())
+                    .map(|((t, b), n)| {
+                        let n = nalgebra::Vector3::new(n[0], n[1], n[2]);
+                        // Gram-Schmidt orthogonalize, with the handedness in w
+                        let t = (t - n * n.dot(&t))
+                            .try_normalize(1.0e-12)
+                            .unwrap_or_else(|| any_perpendicular(&n));
+                        let w = if n.cross(&t).dot(&b) < 0.0 { -1.0 } else { 1.0 };
+                        [t.x, t.y, t.z, w]
+                    })
+                    .collect::<Vec<_>>()
+            }
+            None => normals
+                .iter()
+                .map(|n| {
+                    let t = any_perpendicular(&nalgebra::Vector3::new(n[0], n[1], n[2]));
+                    [t.x, t.y, t.z, 1.0]
+                })
+                .collect(),
+        };
+
+        let vertices = positions
+            .iter()
+            .zip(normals.iter())
+            .zip(tangents.iter())
+            .enumerate()
+            .map(|(i, ((position, normal), tangent))| PosNormTangTex {
+                position: (*position).into(),
+                normal: (*normal).into(),
+                tangent: (*tangent).into(),
+                tex_coord: tex_coords
+                    .as_ref()
+                    .map(|tex_coords| tex_coords[i])
+                    .unwrap_or([0.0, 0.0])
+                    .into(),
+            })
+            .collect();
+
+        (vertices, indices)
+    }
+}
+
+fn any_perpendicular(n: &nalgebra::Vector3<f32>) -> nalgebra::Vector3<f32> {
+    let axis = if n.x.abs() < 0.9 {
+        nalgebra::Vector3::x()
+    } else {
+        nalgebra::Vector3::y()
+    };
+    n.cross(&axis).normalize()
+}
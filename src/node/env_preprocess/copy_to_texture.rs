@@ -69,7 +69,13 @@ where
     TR: CopyToTextureResource<B>,
 {
     fn family(&self, _factory: &mut Factory<B>, families: &Families<B>) -> Option<FamilyId> {
-        families.find(|family| Supports::<Transfer>::supports(&family.capability()).is_some())
+        // Prefer a dedicated transfer family, so the copy doesn't occupy the graphics queue
+        families
+            .find(|family| family.capability() == hal::queue::QueueType::Transfer)
+            .or_else(|| {
+                families
+                    .find(|family| Supports::<Transfer>::supports(&family.capability()).is_some())
+            })
     }
 
     fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
@@ -155,13 +161,24 @@ where
             let (mut stages, mut barriers) = gfx_release_barriers(ctx, None, images.iter());
             let end_state = aux.texture_end_state(&self.output_tex_name);
             stages.start |= hal::pso::PipelineStage::TRANSFER;
-            stages.end |= end_state.stage;
+            // If the texture ends up used on another family, release ownership of it to
+            // that family, which then has to acquire it with a matching barrier
+            let families = if end_state.queue.family != family.id() {
+                stages.end |= hal::pso::PipelineStage::BOTTOM_OF_PIPE;
+                Some(
+                    hal::queue::QueueFamilyId(family.id().index)
+                        ..hal::queue::QueueFamilyId(end_state.queue.family.index),
+                )
+            } else {
+                stages.end |= end_state.stage;
+                None
+            };
             barriers.push(hal::memory::Barrier::Image {
                 states: (
                     hal::image::Access::TRANSFER_WRITE,
                     hal::image::Layout::TransferDstOptimal,
                 )..(end_state.access, end_state.layout),
-                families: None,
+                families,
                 target: target_tex.image().raw(),
                 range: hal::image::SubresourceRange {
                     aspects: hal::format::Aspects::COLOR,
@@ -202,6 +219,7 @@ where
         signals: &[&'a B::Semaphore],
         fence: Option<&mut Fence<B>>,
     ) {
+        let _scope = crate::profile::scope("CopyToTexture::run");
         queue.submit(
             Some(
                 Submission::new()
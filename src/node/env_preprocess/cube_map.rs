@@ -0,0 +1,98 @@
+//! The environment cube map and the filter's copy of it. When the environment comes from an
+//! equirectangular image these are rendered to, which `TextureBuilder` can't make textures
+//! for, so they are kept as their image, view and sampler instead.
+use rendy::{
+    factory::Factory,
+    memory::MemoryUsageValue,
+    resource::{
+        Escape, Filter, Handle, Image, ImageInfo, ImageView, ImageViewInfo, Sampler, SamplerDesc,
+        ViewKind, WrapMode,
+    },
+    texture::Texture,
+};
+
+use rendy::hal;
+
+#[derive(Debug)]
+pub struct CubeMap<B: hal::Backend> {
+    image: Handle<Image<B>>,
+    view: Escape<ImageView<B>>,
+    sampler: Handle<Sampler<B>>,
+}
+
+impl<B: hal::Backend> CubeMap<B> {
+    /// A cube map which can be rendered to one layer and level at a time. Its contents are
+    /// undefined until then.
+    pub fn render_target(
+        factory: &mut Factory<B>,
+        resolution: u32,
+        mip_levels: u8,
+        format: hal::format::Format,
+    ) -> Result<Self, failure::Error> {
+        let image: Handle<Image<B>> = factory
+            .create_image(
+                ImageInfo {
+                    kind: hal::image::Kind::D2(resolution, resolution, 6, 1),
+                    levels: mip_levels,
+                    format,
+                    tiling: hal::image::Tiling::Optimal,
+                    view_caps: hal::image::ViewCapabilities::KIND_CUBE,
+                    usage: hal::image::Usage::COLOR_ATTACHMENT
+                        | hal::image::Usage::SAMPLED
+                        | hal::image::Usage::TRANSFER_SRC
+                        | hal::image::Usage::TRANSFER_DST,
+                },
+                MemoryUsageValue::Data,
+            )?
+            .into();
+        let sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Linear, WrapMode::Clamp))?
+            .into();
+        Self::new(factory, image, sampler)
+    }
+
+    /// Takes the image and sampler of a cube map texture, such as one loaded from a file.
+    pub fn from_texture(
+        factory: &mut Factory<B>,
+        texture: Texture<B>,
+    ) -> Result<Self, failure::Error> {
+        Self::new(factory, texture.image().clone(), texture.sampler().clone())
+    }
+
+    fn new(
+        factory: &mut Factory<B>,
+        image: Handle<Image<B>>,
+        sampler: Handle<Sampler<B>>,
+    ) -> Result<Self, failure::Error> {
+        let view = factory.create_image_view(
+            image.clone(),
+            ImageViewInfo {
+                view_kind: ViewKind::Cube,
+                format: image.format(),
+                swizzle: hal::format::Swizzle::NO,
+                range: hal::image::SubresourceRange {
+                    aspects: hal::format::Aspects::COLOR,
+                    levels: 0..image.levels(),
+                    layers: 0..6,
+                },
+            },
+        )?;
+        Ok(CubeMap {
+            image,
+            view,
+            sampler,
+        })
+    }
+
+    pub fn image(&self) -> &Handle<Image<B>> {
+        &self.image
+    }
+
+    pub fn view(&self) -> &ImageView<B> {
+        &self.view
+    }
+
+    pub fn sampler(&self) -> &Handle<Sampler<B>> {
+        &self.sampler
+    }
+}
@@ -0,0 +1,230 @@
+//! Loading of finished environment cube maps from disk, which skips the equirectangular
+//! conversion. Faces are in the order +X, -X, +Y, -Y, +Z, -Z, as in cube map array layers.
+use rendy::texture::pixel::Rgba32Sfloat;
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// The top mip of a cube map, as linear float texels, face after face.
+pub struct CubeData {
+    pub resolution: u32,
+    pub texels: Vec<Rgba32Sfloat>,
+}
+
+/// Loads a cube map from six square images of equal size. Radiance `.hdr` and OpenEXR `.exr`
+/// files are read as is; any other format is assumed to be sRGB encoded and is linearized.
+pub fn load_faces<P: AsRef<Path>>(paths: &[P]) -> Result<CubeData, failure::Error> {
+    if paths.len() != 6 {
+        failure::bail!("A cube map needs 6 faces, got {}", paths.len());
+    }
+
+    let mut resolution = None;
+    let mut texels = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let (width, height, face) = load_face(path)?;
+        if width != height {
+            failure::bail!(
+                "Cube map face {:?} is not square: {}x{}",
+                path,
+                width,
+                height
+            );
+        }
+        match resolution {
+            None => resolution = Some(width),
+            Some(res) if res != width => failure::bail!(
+                "Cube map face {:?} is {}x{}, but the previous faces are {}x{}",
+                path,
+                width,
+                width,
+                res,
+                res
+            ),
+            _ => (),
+        }
+        texels.extend(face);
+    }
+
+    Ok(CubeData {
+        resolution: resolution.unwrap(),
+        texels,
+    })
+}
+
+/// A cube map of a single color in every direction, like the white environment of the white
+/// furnace test. Constant, so the resolution only needs to leave room for the filter's mips.
+pub fn uniform(color: [f32; 3], resolution: u32) -> CubeData {
+    let texel = Rgba32Sfloat {
+        repr: [color[0], color[1], color[2], 1.0],
+    };
+    CubeData {
+        resolution,
+        texels: vec![texel; (resolution * resolution * 6) as usize],
+    }
+}
+
+fn load_face(path: &Path) -> Result<(u32, u32, Vec<Rgba32Sfloat>), failure::Error> {
+    let is_hdr = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("hdr"))
+        .unwrap_or(false);
+
+    if crate::asset::is_exr(path) {
+        crate::asset::load_exr(path)
+    } else if is_hdr {
+        let decoder = image::hdr::HDRDecoder::new(BufReader::new(File::open(path)?))?;
+        let meta = decoder.metadata();
+        let texels = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .map(|rgb| Rgba32Sfloat {
+                repr: [rgb.data[0], rgb.data[1], rgb.data[2], 1.0],
+            })
+            .collect();
+        Ok((meta.width, meta.height, texels))
+    } else {
+        let image = image::open(path)?.to_rgba();
+        let (width, height) = image.dimensions();
+        let texels = image
+            .pixels()
+            .map(|rgba| Rgba32Sfloat {
+                repr: [
+                    srgb_to_linear(rgba.data[0]),
+                    srgb_to_linear(rgba.data[1]),
+                    srgb_to_linear(rgba.data[2]),
+                    rgba.data[3] as f32 / 255.0,
+                ],
+            })
+            .collect();
+        Ok((width, height, texels))
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let c = value as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+const KTX_IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x31, 0x31, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const KTX_ENDIANNESS: u32 = 0x0403_0201;
+
+const GL_HALF_FLOAT: u32 = 0x140B;
+const GL_FLOAT: u32 = 0x1406;
+const GL_RGB: u32 = 0x1907;
+const GL_RGBA: u32 = 0x1908;
+
+/// Loads the top mip of a KTX (version 1) cube map. Only uncompressed RGB or RGBA data
+/// with 16 or 32 bit float channels is supported, which covers what HDR tools export.
+pub fn load_ktx<P: AsRef<Path>>(path: P) -> Result<CubeData, failure::Error> {
+    let path = path.as_ref();
+    let mut bytes = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+
+    if bytes.len() < 64 || bytes[..12] != KTX_IDENTIFIER {
+        failure::bail!("{:?} is not a KTX file", path);
+    }
+    let header = |index: usize| read_u32(&bytes, 12 + index * 4);
+    if header(0) != KTX_ENDIANNESS {
+        failure::bail!("{:?} is big endian, which is not supported", path);
+    }
+
+    let gl_type = header(1);
+    let gl_format = header(3);
+    let width = header(6);
+    let height = header(7);
+    let faces = header(10);
+    let key_value_bytes = header(12) as usize;
+
+    let channels = match gl_format {
+        GL_RGB => 3,
+        GL_RGBA => 4,
+        _ => failure::bail!(
+            "{:?} has unsupported format {:#x}, expected RGB or RGBA",
+            path,
+            gl_format
+        ),
+    };
+    let channel_size = match gl_type {
+        GL_HALF_FLOAT => 2,
+        GL_FLOAT => 4,
+        _ => failure::bail!(
+            "{:?} has unsupported type {:#x}, expected a 16 or 32 bit float",
+            path,
+            gl_type
+        ),
+    };
+    if faces != 6 || width != height {
+        failure::bail!(
+            "{:?} is not a cube map: {} faces of {}x{}",
+            path,
+            faces,
+            width,
+            height
+        );
+    }
+
+    let row_size = width as usize * channels * channel_size;
+    if row_size % 4 != 0 {
+        failure::bail!("{:?} has padded rows, which is not supported", path);
+    }
+
+    // Mip 0 follows the header and key/value data, prefixed with the size of one face
+    let mut offset = 64 + key_value_bytes + 4;
+    let face_size = row_size * height as usize;
+    if bytes.len() < offset + face_size * 6 {
+        failure::bail!("{:?} is truncated", path);
+    }
+
+    let mut texels = Vec::with_capacity((width * height * 6) as usize);
+    for _ in 0..6 {
+        for texel in bytes[offset..offset + face_size].chunks(channels * channel_size) {
+            let mut repr = [0.0, 0.0, 0.0, 1.0];
+            for (channel, value) in repr.iter_mut().zip(texel.chunks(channel_size)) {
+                *channel = match channel_size {
+                    2 => f16_to_f32(u16::from_le_bytes([value[0], value[1]])),
+                    _ => f32::from_bits(read_u32(value, 0)),
+                };
+            }
+            texels.push(Rgba32Sfloat { repr });
+        }
+        // Rows are 4 byte aligned, so faces are too and there is no cube padding
+        offset += face_size;
+    }
+
+    Ok(CubeData {
+        resolution: width,
+        texels,
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+pub fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1F) as i32;
+    let mantissa = (half & 0x3FF) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1F if mantissa == 0.0 => sign * std::f32::INFINITY,
+        0x1F => std::f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
@@ -1,334 +1,253 @@
-use genmesh::{
-    generators::{IndexedPolygon, SharedVertex},
-    Triangulate,
-};
-
+//! Reads the images made by environment preprocessing back from the GPU and writes them to
+//! disk, so the conversion and filtering can be checked without a graphics debugger.
+//!
+//! Every mip of every layer is written twice: as a clamped, gamma encoded PNG for viewing,
+//! and as an OpenEXR file which keeps the full range and precision of the values.
 use rendy::{
-    command::{QueueId, RenderPassEncoder},
+    command::{Families, OneShot, QueueId, Submission},
     factory::Factory,
-    graph::{render::*, GraphContext, NodeBuffer, NodeImage},
-    hal::{device::Device, pso::DescriptorPool},
     memory::MemoryUsageValue,
-    mesh::{AsVertex, Mesh, Position},
-    resource::{Buffer, BufferInfo, DescriptorSetLayout, Escape, Handle},
-    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+    resource::{BufferInfo, Image},
 };
 
 use rendy::hal;
 
-use crate::node::env_preprocess::Aux;
-
-#[derive(Clone, Copy)]
-#[repr(C)]
-pub struct UniformArgs {
-    proj: nalgebra::Matrix4<f32>,
-    view: nalgebra::Matrix4<f32>,
-}
-
-lazy_static::lazy_static! {
-    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
-        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/debug.vert"),
-        ShaderKind::Vertex,
-        SourceLanguage::GLSL,
-        "main",
-    );
+use std::path::Path;
 
-    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
-        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/debug.frag"),
-        ShaderKind::Fragment,
-        SourceLanguage::GLSL,
-        "main",
-    );
+use crate::node::env_preprocess::cubemap_file::f16_to_f32;
 
-    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
-        .with_vertex(&*VERTEX).unwrap()
-        .with_fragment(&*FRAGMENT).unwrap();
-}
-
-#[derive(Debug, PartialEq, Eq)]
-pub struct Settings {
-    align: u64,
-}
-
-impl<B: hal::Backend> From<&Aux<B>> for Settings {
-    fn from(aux: &Aux<B>) -> Self {
-        Self::from_aux(aux)
-    }
-}
-
-impl<B: hal::Backend> From<&mut Aux<B>> for Settings {
-    fn from(aux: &mut Aux<B>) -> Self {
-        Self::from_aux(aux)
-    }
-}
-
-impl Settings {
-    const UNIFORM_SIZE: u64 = std::mem::size_of::<UniformArgs>() as u64;
-
-    fn from_aux<B: hal::Backend>(aux: &Aux<B>) -> Self {
-        Settings { align: aux.align }
-    }
-
-    #[inline]
-    fn buffer_frame_size(&self) -> u64 {
-        ((Self::UNIFORM_SIZE - 1) / self.align + 1) * self.align
-    }
+/// One mip of one layer of a texture, in host memory.
+pub struct Subresource {
+    pub level: u8,
+    pub layer: u16,
+    pub width: u32,
+    pub height: u32,
+    /// Texels in rows from the top. Channels missing from the format are 0, or 1 for alpha.
+    pub texels: Vec<[f32; 4]>,
 }
 
-#[derive(Debug, Default)]
-pub struct PipelineDesc;
-
-pub struct Pipeline<B: hal::Backend> {
-    cube: Mesh<B>,
-    set: B::DescriptorSet,
-    pool: B::DescriptorPool,
-    #[allow(dead_code)]
-    buffer: Escape<Buffer<B>>,
-}
-
-impl<B: hal::Backend> std::fmt::Debug for Pipeline<B> {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Equirect Pipeline")
-    }
-}
-
-impl<B> SimpleGraphicsPipelineDesc<B, Aux<B>> for PipelineDesc
-where
-    B: hal::Backend,
-{
-    type Pipeline = Pipeline<B>;
-
-    fn vertices(
-        &self,
-    ) -> Vec<(
-        Vec<hal::pso::Element<hal::format::Format>>,
-        hal::pso::ElemStride,
-        hal::pso::VertexInputRate,
-    )> {
-        vec![Position::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex)]
-    }
-
-    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
-        vec![hal::pso::ColorBlendDesc {
-            mask: hal::pso::ColorMask::ALL,
-            blend: None,
-        }]
-    }
-
-    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
-        None
-    }
-
-    fn load_shader_set(
-        &self,
-        factory: &mut Factory<B>,
-        _aux: &Aux<B>,
-    ) -> rendy::shader::ShaderSet<B> {
-        SHADERS.build(factory, Default::default()).unwrap()
-    }
-
-    fn layout(&self) -> Layout {
-        Layout {
-            sets: vec![SetLayout {
-                bindings: vec![
-                    hal::pso::DescriptorSetLayoutBinding {
-                        binding: 0,
-                        ty: hal::pso::DescriptorType::UniformBuffer,
-                        count: 1,
-                        stage_flags: hal::pso::ShaderStageFlags::GRAPHICS,
-                        immutable_samplers: false,
-                    },
-                    hal::pso::DescriptorSetLayoutBinding {
-                        binding: 1,
-                        ty: hal::pso::DescriptorType::Sampler,
-                        count: 1,
-                        stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
-                        immutable_samplers: false,
-                    },
-                    hal::pso::DescriptorSetLayoutBinding {
-                        binding: 2,
-                        ty: hal::pso::DescriptorType::SampledImage,
-                        count: 1,
-                        stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
-                        immutable_samplers: false,
-                    },
-                ],
-            }],
-            push_constants: Vec::new(),
-        }
-    }
-
-    fn build<'a>(
-        self,
-        _ctx: &GraphContext<B>,
-        factory: &mut Factory<B>,
-        queue: QueueId,
-        aux: &Aux<B>,
-        buffers: Vec<NodeBuffer>,
-        images: Vec<NodeImage>,
-        set_layouts: &[Handle<DescriptorSetLayout<B>>],
-    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
-        assert!(buffers.is_empty());
-        assert!(images.is_empty());
-        assert!(set_layouts.len() == 1);
-
-        let cube = genmesh::generators::Cube::new();
-        let cube_vertices: Vec<_> = cube
-            .shared_vertex_iter()
-            .map(|v| Position(v.pos.into()))
-            .collect();
-
-        let cube_flattened_vertices: Vec<_> =
-            genmesh::Vertices::vertices(cube.indexed_polygon_iter().triangulate())
-                .map(|i| cube_vertices[i])
-                .collect();
-
-        let cube = Mesh::<B>::builder()
-            .with_vertices(&cube_flattened_vertices[..])
-            .build(queue, factory)
-            .unwrap();
-
-        let mut pool = unsafe {
-            factory.create_descriptor_pool(
-                1,
-                vec![
-                    hal::pso::DescriptorRangeDesc {
-                        ty: hal::pso::DescriptorType::UniformBuffer,
-                        count: 1,
-                    },
-                    hal::pso::DescriptorRangeDesc {
-                        ty: hal::pso::DescriptorType::Sampler,
-                        count: 1,
+/// Copies every mip of every layer of a floating point texture to host memory.
+///
+/// The texture must be owned by `queue`'s family and in `ShaderReadOnlyOptimal`, which it
+/// is left in. Waits for the copy to complete, so it is only meant for debugging.
+pub fn read_texture<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    image: &Image<B>,
+) -> Result<Vec<Subresource>, failure::Error> {
+    let (channels, half) = match image.format() {
+        hal::format::Format::Rgba32Sfloat => (4, false),
+        hal::format::Format::Rgba16Sfloat => (4, true),
+        hal::format::Format::Rg32Sfloat => (2, false),
+        hal::format::Format::Rg16Sfloat => (2, true),
+        format => failure::bail!("Can't read back textures of format {:?}", format),
+    };
+    let channel_size: u64 = if half { 2 } else { 4 };
+    let texel_size = channels * channel_size;
+
+    let extent = image.kind().extent();
+    let layers = image.layers();
+    let mip_extents = (0..image.levels())
+        .map(|level| {
+            (
+                (extent.width >> level).max(1),
+                (extent.height >> level).max(1),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mip_offsets = mip_extents
+        .iter()
+        .scan(0, |offset, (width, height)| {
+            let mip_offset = *offset;
+            *offset += (width * height) as u64 * u64::from(layers) * texel_size;
+            Some(mip_offset)
+        })
+        .collect::<Vec<_>>();
+    let total_size = mip_extents
+        .iter()
+        .map(|(width, height)| (width * height) as u64 * u64::from(layers) * texel_size)
+        .sum::<u64>();
+
+    let mut buffer = factory.create_buffer(
+        BufferInfo {
+            size: total_size,
+            usage: hal::buffer::Usage::TRANSFER_DST,
+        },
+        MemoryUsageValue::Download,
+    )?;
+
+    let range = hal::image::SubresourceRange {
+        aspects: hal::format::Aspects::COLOR,
+        levels: 0..image.levels(),
+        layers: 0..layers,
+    };
+
+    unsafe {
+        let family = families.family_mut(queue.family);
+        let mut pool = factory.create_command_pool(family)?;
+
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(OneShot, ());
+        let mut encoder = buf_recording.encoder();
+        encoder.pipeline_barrier(
+            hal::pso::PipelineStage::FRAGMENT_SHADER..hal::pso::PipelineStage::TRANSFER,
+            hal::memory::Dependencies::empty(),
+            Some(hal::memory::Barrier::Image {
+                states: (
+                    hal::image::Access::SHADER_READ,
+                    hal::image::Layout::ShaderReadOnlyOptimal,
+                )
+                    ..(
+                        hal::image::Access::TRANSFER_READ,
+                        hal::image::Layout::TransferSrcOptimal,
+                    ),
+                families: None,
+                target: image.raw(),
+                range: range.clone(),
+            }),
+        );
+        encoder.copy_image_to_buffer(
+            image.raw(),
+            hal::image::Layout::TransferSrcOptimal,
+            buffer.raw(),
+            mip_extents.iter().zip(mip_offsets.iter()).enumerate().map(
+                |(level, ((width, height), offset))| hal::command::BufferImageCopy {
+                    buffer_offset: *offset,
+                    buffer_width: 0,
+                    buffer_height: 0,
+                    image_layers: hal::image::SubresourceLayers {
+                        aspects: hal::format::Aspects::COLOR,
+                        level: level as u8,
+                        layers: 0..layers,
                     },
-                    hal::pso::DescriptorRangeDesc {
-                        ty: hal::pso::DescriptorType::SampledImage,
-                        count: 1,
+                    image_offset: hal::image::Offset::ZERO,
+                    image_extent: hal::image::Extent {
+                        width: *width,
+                        height: *height,
+                        depth: 1,
                     },
-                ],
-                hal::pso::DescriptorPoolCreateFlags::empty(),
-            )?
-        };
-
-        let settings: Settings = aux.into();
-
-        let mut buffer = factory
-            .create_buffer(
-                BufferInfo {
-                    size: settings.buffer_frame_size(),
-                    usage: hal::buffer::Usage::UNIFORM,
-                },
-                MemoryUsageValue::Dynamic,
-            )
-            .unwrap();
-
-        let set = unsafe {
-            let set = pool.allocate_set(&set_layouts[0].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Buffer(
-                        buffer.raw(),
-                        Some(0)..Some(Settings::UNIFORM_SIZE),
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(
-                        aux.spec_cubemap.as_ref().unwrap().sampler().raw(),
-                    )),
                 },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 2,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        aux.spec_cubemap.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
-            set
-        };
-
-        let origin = nalgebra::Point3::origin();
-        unsafe {
-            factory
-                .upload_visible_buffer(
-                    &mut buffer,
-                    0,
-                    &[UniformArgs {
-                        proj: {
-                            let mut proj = nalgebra::Perspective3::<f32>::new(
-                                1.0,
-                                std::f32::consts::FRAC_PI_2,
-                                0.1,
-                                100.0,
-                            )
-                            .to_homogeneous();
-                            proj[(1, 1)] *= -1.0;
-                            proj
-                        },
-                        view: nalgebra::Matrix4::look_at_rh(
-                            &origin,
-                            &nalgebra::Point3::new(0.0, 0.0, 1.0),
-                            &-nalgebra::Vector3::y(),
-                        ),
-                    }],
+            ),
+        );
+        encoder.pipeline_barrier(
+            hal::pso::PipelineStage::TRANSFER..hal::pso::PipelineStage::FRAGMENT_SHADER,
+            hal::memory::Dependencies::empty(),
+            Some(hal::memory::Barrier::Image {
+                states: (
+                    hal::image::Access::TRANSFER_READ,
+                    hal::image::Layout::TransferSrcOptimal,
                 )
-                .unwrap()
-        };
-
-        Ok(Pipeline {
-            cube,
-            set,
-            pool,
-            buffer,
-        })
-    }
-}
-
-impl<B> SimpleGraphicsPipeline<B, Aux<B>> for Pipeline<B>
-where
-    B: hal::Backend,
-{
-    type Desc = PipelineDesc;
-
-    fn prepare(
-        &mut self,
-        _factory: &Factory<B>,
-        _queue: QueueId,
-        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
-        _index: usize,
-        _aux: &Aux<B>,
-    ) -> PrepareResult {
-        PrepareResult::DrawReuse
+                    ..(
+                        hal::image::Access::SHADER_READ,
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    ),
+                families: None,
+                target: image.raw(),
+                range,
+            }),
+        );
+        let (submit, command_buffer) = buf_recording.finish().submit_once();
+
+        let mut fence = factory.create_fence(false)?;
+        family.queue_mut(queue.index).submit(
+            Some(Submission::new().submits(Some(submit))),
+            Some(&mut fence),
+        );
+        factory.wait_for_fence(&mut fence, !0)?;
+        factory.destroy_fence(fence);
+
+        pool.free_buffers(Some(command_buffer.mark_complete()));
+        factory.destroy_command_pool(pool);
     }
 
-    fn draw(
-        &mut self,
-        layout: &B::PipelineLayout,
-        mut encoder: RenderPassEncoder<'_, B>,
-        _index: usize,
-        _aux: &Aux<B>,
-    ) {
-        assert!(self
-            .cube
-            .bind(0, &[Position::vertex()], &mut encoder)
-            .is_ok());
-        unsafe {
-            encoder.bind_graphics_descriptor_sets(layout, 0, Some(&self.set), std::iter::empty());
-            encoder.draw(0..36, 0..6);
+    let mut subresources = Vec::new();
+    unsafe {
+        let mut mapped = buffer.map(factory.device(), 0..total_size)?;
+        let bytes: &[u8] = mapped.read(factory.device(), 0..total_size)?;
+        let texels = bytes
+            .chunks(texel_size as usize)
+            .map(|texel| {
+                let mut values = [0.0, 0.0, 0.0, 1.0];
+                for (channel, value) in values.iter_mut().zip(texel.chunks(channel_size as usize)) {
+                    *channel = if half {
+                        f16_to_f32(u16::from_le_bytes([value[0], value[1]]))
+                    } else {
+                        f32::from_bits(u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                    };
+                }
+                values
+            })
+            .collect::<Vec<_>>();
+        for (level, ((width, height), offset)) in
+            mip_extents.iter().zip(mip_offsets.iter()).enumerate()
+        {
+            let layer_texels = (width * height) as usize;
+            let mip_start = (*offset / texel_size) as usize;
+            for layer in 0..layers {
+                let start = mip_start + layer as usize * layer_texels;
+                subresources.push(Subresource {
+                    level: level as u8,
+                    layer,
+                    width: *width,
+                    height: *height,
+                    texels: texels[start..start + layer_texels].to_vec(),
+                });
+            }
         }
     }
 
-    fn dispose(mut self, factory: &mut Factory<B>, _aux: &Aux<B>) {
-        unsafe {
-            self.pool.reset();
-            factory.destroy_descriptor_pool(self.pool);
-        }
+    Ok(subresources)
+}
+
+/// Writes every mip of every layer of a texture to `dir`, as
+/// `<name>_mip<level>[_face<layer>].png` and `.exr`. The face is only part of the name for
+/// textures with more than one layer.
+pub fn dump_texture<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    image: &Image<B>,
+    dir: &Path,
+    name: &str,
+) -> Result<(), failure::Error> {
+    std::fs::create_dir_all(dir)?;
+    let layered = image.layers() > 1;
+    for subresource in read_texture(factory, families, queue, image)? {
+        let file_name = if layered {
+            format!(
+                "{}_mip{}_face{}",
+                name, subresource.level, subresource.layer
+            )
+        } else {
+            format!("{}_mip{}", name, subresource.level)
+        };
+
+        let png = subresource
+            .texels
+            .iter()
+            .flat_map(|texel| {
+                let encode = |c: f32| {
+                    (crate::asset::linear_to_srgb(c.max(0.0).min(1.0)) * 255.0).round() as u8
+                };
+                vec![encode(texel[0]), encode(texel[1]), encode(texel[2])]
+            })
+            .collect::<Vec<_>>();
+        image::save_buffer(
+            dir.join(format!("{}.png", file_name)),
+            &png,
+            subresource.width,
+            subresource.height,
+            image::ColorType::RGB(8),
+        )?;
+
+        crate::asset::save_exr(
+            dir.join(format!("{}.exr", file_name)),
+            subresource.width,
+            subresource.height,
+            &subresource.texels,
+        )?;
     }
+    log::info!("Wrote {} to {:?}", name, dir);
+    Ok(())
 }
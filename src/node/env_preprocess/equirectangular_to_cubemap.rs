@@ -0,0 +1,486 @@
+//! Renders the equirectangular environment map into the layers of cube maps, one face at a
+//! time, then fills in their mips.
+//!
+//! Render graph attachments are always single layer 2D images, so the node renders outside
+//! the graph's passes, with its own render pass and a framebuffer for each face of each cube
+//! map, viewing a single layer of its top level.
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, ExecutableState, Families, Family, FamilyId, Fence, Graphics,
+        MultiShot, PendingState, Queue, QueueId, SimultaneousUse, Submission, Submit, Supports,
+    },
+    factory::{blit_image, BlitRegion, Factory, ImageState},
+    frame::Frames,
+    graph::{
+        BufferAccess, BufferId, DynNode, GraphContext, ImageAccess, ImageId, NodeBuffer,
+        NodeBuildError, NodeBuilder, NodeId, NodeImage,
+    },
+    hal::{device::Device, pso::DescriptorPool},
+    resource::{Escape, ImageView, ImageViewInfo, ViewKind},
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use crate::node::env_preprocess::Aux;
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/unproject_cubemap_tex.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/equirectangular_to_cube_faces.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+}
+
+#[derive(Debug)]
+pub struct EquirectangularToCubemap<B: hal::Backend> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+    render_pass: B::RenderPass,
+    /// One for each face of each cube map
+    face_views: Vec<Escape<ImageView<B>>>,
+    framebuffers: Vec<B::Framebuffer>,
+    set_layout: B::DescriptorSetLayout,
+    pipeline_layout: B::PipelineLayout,
+    pipeline: B::GraphicsPipeline,
+    descriptor_pool: B::DescriptorPool,
+}
+
+impl<B: hal::Backend> EquirectangularToCubemap<B> {
+    /// Renders into the cube maps of `Aux` with the names in `cubemaps`, which must all have
+    /// the same resolution and format.
+    pub fn builder(cubemaps: &[&str]) -> EquirectangularToCubemapBuilder {
+        EquirectangularToCubemapBuilder {
+            cubemaps: cubemaps.iter().map(|name| String::from(*name)).collect(),
+            dependencies: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EquirectangularToCubemapBuilder {
+    cubemaps: Vec<String>,
+    dependencies: Vec<NodeId>,
+}
+
+impl EquirectangularToCubemapBuilder {
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn add_dependency(&mut self, dependency: NodeId) -> &mut Self {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn with_dependency(mut self, dependency: NodeId) -> Self {
+        self.add_dependency(dependency);
+        self
+    }
+}
+
+impl<B> NodeBuilder<B, Aux<B>> for EquirectangularToCubemapBuilder
+where
+    B: hal::Backend,
+{
+    fn family(&self, _factory: &mut Factory<B>, families: &Families<B>) -> Option<FamilyId> {
+        families.find(|family| Supports::<Graphics>::supports(&family.capability()).is_some())
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        Vec::new()
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        _ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        queue: usize,
+        aux: &Aux<B>,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, Aux<B>>>, NodeBuildError> {
+        assert!(buffers.is_empty());
+        assert!(images.is_empty());
+
+        let equirectangular_texture = aux
+            .equirectangular_texture
+            .as_ref()
+            .expect("Equirectangular environment map missing");
+        let cubemaps = self
+            .cubemaps
+            .iter()
+            .map(|name| match name.as_str() {
+                "environment" => aux.environment_cubemap.as_ref().unwrap(),
+                "filter_source" => aux.filter_source_cubemap.as_ref().unwrap(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        let format = cubemaps[0].image().format();
+        let extent = cubemaps[0].image().kind().extent();
+
+        let render_pass = unsafe {
+            factory.device().create_render_pass(
+                Some(hal::pass::Attachment {
+                    format: Some(format),
+                    samples: 1,
+                    // Every texel of the face is drawn
+                    ops: hal::pass::AttachmentOps::new(
+                        hal::pass::AttachmentLoadOp::DontCare,
+                        hal::pass::AttachmentStoreOp::Store,
+                    ),
+                    stencil_ops: hal::pass::AttachmentOps::DONT_CARE,
+                    // Ready to be blitted into the next mip
+                    layouts: hal::image::Layout::Undefined..hal::image::Layout::TransferSrcOptimal,
+                }),
+                Some(hal::pass::SubpassDesc {
+                    colors: &[(0, hal::image::Layout::ColorAttachmentOptimal)],
+                    depth_stencil: None,
+                    inputs: &[],
+                    resolves: &[],
+                    preserves: &[],
+                }),
+                std::iter::empty::<hal::pass::SubpassDependency>(),
+            )
+        }
+        .unwrap();
+
+        let mut face_views = Vec::with_capacity(cubemaps.len() * 6);
+        let mut framebuffers = Vec::with_capacity(cubemaps.len() * 6);
+        for cubemap in cubemaps.iter() {
+            for face in 0..6 {
+                let view = factory
+                    .create_image_view(
+                        cubemap.image().clone(),
+                        ImageViewInfo {
+                            view_kind: ViewKind::D2,
+                            format,
+                            swizzle: hal::format::Swizzle::NO,
+                            range: hal::image::SubresourceRange {
+                                aspects: hal::format::Aspects::COLOR,
+                                levels: 0..1,
+                                layers: face..face + 1,
+                            },
+                        },
+                    )
+                    .expect("Could not create cube map face view");
+                let framebuffer = unsafe {
+                    factory
+                        .device()
+                        .create_framebuffer(&render_pass, Some(view.raw()), extent)
+                        .unwrap()
+                };
+                face_views.push(view);
+                framebuffers.push(framebuffer);
+            }
+        }
+
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory
+                .device()
+                .create_descriptor_set_layout(
+                    vec![
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 0,
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 1,
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                            immutable_samplers: false,
+                        },
+                    ],
+                    std::iter::empty::<B::Sampler>(),
+                )
+                .unwrap();
+            let pipeline_layout = factory
+                .device()
+                .create_pipeline_layout(
+                    Some(&set_layout),
+                    std::iter::empty::<(hal::pso::ShaderStageFlags, std::ops::Range<u32>)>(),
+                )
+                .unwrap();
+            (set_layout, pipeline_layout)
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    1,
+                    vec![
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                        },
+                    ],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layout).unwrap();
+            factory.write_descriptor_sets(vec![
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Sampler(
+                        equirectangular_texture.sampler().raw(),
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        equirectangular_texture.view().raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+            ]);
+            set
+        };
+
+        let pipeline =
+            unsafe { create_pipeline(factory, &render_pass, &pipeline_layout, extent).unwrap() };
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+
+        let area = hal::pso::Rect {
+            x: 0,
+            y: 0,
+            w: extent.width as i16,
+            h: extent.height as i16,
+        };
+        let rendered_state = ImageState {
+            queue: QueueId {
+                family: family.id(),
+                index: queue,
+            },
+            stage: hal::pso::PipelineStage::COLOR_ATTACHMENT_OUTPUT,
+            access: hal::image::Access::COLOR_ATTACHMENT_WRITE,
+            layout: hal::image::Layout::TransferSrcOptimal,
+        };
+        let undefined_state = ImageState {
+            stage: hal::pso::PipelineStage::TOP_OF_PIPE,
+            access: hal::image::Access::empty(),
+            layout: hal::image::Layout::Undefined,
+            ..rendered_state
+        };
+        let end_state = ImageState {
+            queue: aux.queue,
+            stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            access: hal::image::Access::SHADER_READ,
+            layout: hal::image::Layout::ShaderReadOnlyOptimal,
+        };
+
+        for (cubemap, framebuffers) in cubemaps.iter().zip(framebuffers.chunks(6)) {
+            for (face, framebuffer) in framebuffers.iter().enumerate() {
+                let mut pass =
+                    encoder.begin_render_pass_inline(&render_pass, framebuffer, area, &[]);
+                unsafe {
+                    pass.bind_graphics_pipeline(&pipeline);
+                    pass.bind_graphics_descriptor_sets(
+                        &pipeline_layout,
+                        0,
+                        Some(&set),
+                        std::iter::empty(),
+                    );
+                    // The instance picks the face, see `unproject_cubemap_tex.vert`
+                    pass.draw(0..6, face as u32..face as u32 + 1);
+                }
+            }
+
+            // The top level was rendered, the others are only written by the blits
+            let image = cubemap.image();
+            if image.levels() > 1 {
+                let (_queue, blits) = BlitRegion::mip_blits_for_image(
+                    image,
+                    std::iter::once(rendered_state).chain(std::iter::repeat(undefined_state)),
+                    std::iter::repeat(end_state),
+                );
+                for blit in blits {
+                    unsafe {
+                        blit_image(
+                            &mut encoder,
+                            image,
+                            image,
+                            hal::image::Filter::Linear,
+                            Some(blit),
+                        );
+                    }
+                }
+            } else {
+                unsafe {
+                    encoder.pipeline_barrier(
+                        rendered_state.stage..end_state.stage,
+                        hal::memory::Dependencies::empty(),
+                        Some(hal::memory::Barrier::Image {
+                            states: (rendered_state.access, rendered_state.layout)
+                                ..(end_state.access, end_state.layout),
+                            families: None,
+                            target: image.raw(),
+                            range: hal::image::SubresourceRange {
+                                aspects: hal::format::Aspects::COLOR,
+                                levels: 0..1,
+                                layers: 0..6,
+                            },
+                        }),
+                    );
+                }
+            }
+        }
+
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        Ok(Box::new(EquirectangularToCubemap {
+            pool,
+            submit,
+            buffer,
+            render_pass,
+            face_views,
+            framebuffers,
+            set_layout,
+            pipeline_layout,
+            pipeline,
+            descriptor_pool,
+        }))
+    }
+}
+
+/// A pipeline drawing a face over the whole of `extent`, with no vertex buffers.
+unsafe fn create_pipeline<B: hal::Backend>(
+    factory: &Factory<B>,
+    render_pass: &B::RenderPass,
+    layout: &B::PipelineLayout,
+    extent: hal::image::Extent,
+) -> Result<B::GraphicsPipeline, failure::Error> {
+    let vertex = factory.device().create_shader_module(&VERTEX.spirv()?)?;
+    let fragment = factory.device().create_shader_module(&FRAGMENT.spirv()?)?;
+
+    let mut desc = hal::pso::GraphicsPipelineDesc::new(
+        hal::pso::GraphicsShaderSet {
+            vertex: hal::pso::EntryPoint {
+                entry: "main",
+                module: &vertex,
+                specialization: hal::pso::Specialization::default(),
+            },
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: Some(hal::pso::EntryPoint {
+                entry: "main",
+                module: &fragment,
+                specialization: hal::pso::Specialization::default(),
+            }),
+        },
+        hal::pso::Primitive::TriangleList,
+        hal::pso::Rasterizer::FILL,
+        layout,
+        hal::pass::Subpass {
+            index: 0,
+            main_pass: render_pass,
+        },
+    );
+    desc.blender.targets.push(hal::pso::ColorBlendDesc {
+        mask: hal::pso::ColorMask::ALL,
+        blend: None,
+    });
+    let rect = hal::pso::Rect {
+        x: 0,
+        y: 0,
+        w: extent.width as i16,
+        h: extent.height as i16,
+    };
+    desc.baked_states.viewport = Some(hal::pso::Viewport {
+        rect,
+        depth: 0.0..1.0,
+    });
+    desc.baked_states.scissor = Some(rect);
+
+    let pipeline = factory.device().create_graphics_pipeline(&desc, None);
+    factory.device().destroy_shader_module(vertex);
+    factory.device().destroy_shader_module(fragment);
+    Ok(pipeline?)
+}
+
+impl<B> DynNode<B, Aux<B>> for EquirectangularToCubemap<B>
+where
+    B: hal::Backend,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        _aux: &Aux<B>,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("EquirectangularToCubemap::run");
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submit))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, _aux: &Aux<B>) {
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+        let device = factory.device();
+        device.destroy_graphics_pipeline(self.pipeline);
+        device.destroy_pipeline_layout(self.pipeline_layout);
+        device.destroy_descriptor_set_layout(self.set_layout);
+        for framebuffer in self.framebuffers {
+            device.destroy_framebuffer(framebuffer);
+        }
+        drop(self.face_views);
+        device.destroy_render_pass(self.render_pass);
+    }
+}
@@ -0,0 +1,804 @@
+//! Filters the environment cube map into the irradiance and specular cube maps with compute
+//! shaders. The work is submitted on a dedicated compute queue if the device has one and is
+//! not waited on, so the scene can be rendered while it runs, progressively refining the
+//! result with more samples.
+//!
+//! Each filter writes the faces of every mip level it produces into a storage buffer, which
+//! is then copied into the cube map textures on the same queue.
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, Families, Fence, OneShot, PendingOnceState, PrimaryLevel,
+        QueueId, Submission,
+    },
+    factory::{Factory, ImageState},
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    resource::{Buffer, BufferInfo, Escape},
+    shader::{PathBufShaderInfo, Shader, ShaderKind, SourceLanguage},
+    texture::Texture,
+};
+
+use rendy::hal;
+
+use super::cube_map::CubeMap;
+
+use derivative::Derivative;
+use serde::Deserialize;
+
+use std::{borrow::Cow, collections::VecDeque, mem::size_of};
+
+use crate::scene::Quality;
+
+lazy_static::lazy_static! {
+    static ref IRRADIANCE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/env_to_irradiance.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SPECULAR: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/env_to_specular.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+}
+
+/// Matches `local_size_x` and `local_size_y` of the filter shaders.
+const WORKGROUP_SIZE: u32 = 8;
+
+const SPEC_MIPS: usize = crate::SPEC_CUBEMAP_MIP_LEVELS as usize;
+
+/// Fewest samples taken for a rough specular mip level.
+const MIN_SPEC_SAMPLES: u32 = 16;
+
+/// How many times fewer samples the fast specular filter takes.
+const FAST_SPEC_SAMPLE_DIVISOR: u32 = 4;
+
+/// How the specular cube map is prefiltered.
+#[derive(Debug, Derivative, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derivative(Default)]
+pub enum SpecularFilterMode {
+    /// A quarter of the samples, each reading the source one mip level blurrier than its
+    /// footprint to hide the extra noise. Fine for quick first passes.
+    Fast,
+    /// The full sample counts, each reading the source at the mip level matching its
+    /// footprint.
+    #[derivative(Default)]
+    HighQuality,
+}
+
+impl SpecularFilterMode {
+    /// Added to the source mip level each sample reads.
+    fn lod_bias(self) -> f32 {
+        match self {
+            SpecularFilterMode::Fast => 1.0,
+            SpecularFilterMode::HighQuality => 0.0,
+        }
+    }
+}
+
+/// The specular filter's mode and sample counts, applied to every entry of the filter
+/// schedule.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct SpecularFilterSettings {
+    pub mode: SpecularFilterMode,
+    /// Samples for each mip level of the specular cube map, from the sharpest. Replaces the
+    /// counts picked from the quality and mode; levels past the end of the list keep theirs.
+    pub samples_per_mip: Option<Vec<u32>>,
+}
+
+/// The number of samples taken by each filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterSettings {
+    pub irradiance_theta_samples: u32,
+    /// Samples for each mip level of the specular cube map, from the sharpest
+    pub spec_samples: [u32; SPEC_MIPS],
+    pub spec_mode: SpecularFilterMode,
+}
+
+impl FilterSettings {
+    /// Apply the specular filter's mode, and its sample counts if it has any.
+    pub fn with_specular_filter(mut self, specular: &SpecularFilterSettings) -> Self {
+        if specular.mode == SpecularFilterMode::Fast && self.spec_mode != SpecularFilterMode::Fast {
+            for count in self.spec_samples.iter_mut().skip(1) {
+                *count = (*count / FAST_SPEC_SAMPLE_DIVISOR).max(MIN_SPEC_SAMPLES);
+            }
+        }
+        self.spec_mode = specular.mode;
+        if let Some(samples) = &specular.samples_per_mip {
+            for (count, samples) in self.spec_samples.iter_mut().zip(samples) {
+                *count = (*samples).max(1);
+            }
+        }
+        self
+    }
+}
+
+impl From<&Quality> for FilterSettings {
+    fn from(quality: &Quality) -> Self {
+        let (irradiance_theta_samples, max_spec_samples) = match quality {
+            Quality::High => (720, 8192),
+            Quality::Medium => (512, 4096),
+            Quality::Low => (256, 1024),
+        };
+        FilterSettings {
+            irradiance_theta_samples,
+            spec_samples: spec_samples_per_mip(max_spec_samples),
+            spec_mode: SpecularFilterMode::HighQuality,
+        }
+    }
+}
+
+/// Sample counts doubling with each specular mip level up to `max` for the roughest one.
+/// The sharpest level is a mirror reflection, for which a single sample is exact.
+fn spec_samples_per_mip(max: u32) -> [u32; SPEC_MIPS] {
+    let mut samples = [1; SPEC_MIPS];
+    for (level, count) in samples.iter_mut().enumerate().skip(1) {
+        *count = (max >> (SPEC_MIPS - 1 - level)).max(MIN_SPEC_SAMPLES);
+    }
+    samples
+}
+
+/// Push constants of the filter shaders.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct FilterArgs {
+    /// Index of the first texel of this mip level in the output buffer
+    offset: u32,
+    resolution: u32,
+    roughness: f32,
+    env_resolution: f32,
+    /// Samples taken by the specular filter, the irradiance filter's are a specialization
+    /// constant
+    sample_count: u32,
+    /// Added to the source mip level each specular sample reads
+    lod_bias: f32,
+    /// Last mip level of the source cube map
+    env_max_lod: f32,
+}
+
+impl FilterArgs {
+    /// Push constant range, in 32-bit words.
+    const RANGE: std::ops::Range<u32> = 0..(size_of::<FilterArgs>() / 4) as u32;
+
+    fn as_words(&self) -> [u32; 7] {
+        [
+            self.offset,
+            self.resolution,
+            self.roughness.to_bits(),
+            self.env_resolution.to_bits(),
+            self.sample_count,
+            self.lod_bias.to_bits(),
+            self.env_max_lod.to_bits(),
+        ]
+    }
+}
+
+/// One mip level written by a filter.
+#[derive(Debug, Clone, Copy)]
+struct MipOutput {
+    level: u8,
+    resolution: u32,
+    roughness: f32,
+    /// Offset of the level in the output buffer, in texels
+    offset: u32,
+}
+
+impl MipOutput {
+    const TEXEL_SIZE: u64 = size_of::<[f32; 4]>() as u64;
+
+    fn texels(&self) -> u32 {
+        self.resolution * self.resolution * 6
+    }
+
+    fn buffer_offset(&self) -> u64 {
+        self.offset as u64 * Self::TEXEL_SIZE
+    }
+}
+
+/// Lay out the mip levels of a cube map one after another in the output buffer,
+/// starting at `offset` texels.
+fn mip_outputs(offset: u32, resolution: u32, levels: u8) -> Vec<MipOutput> {
+    let mut offset = offset;
+    (0..levels)
+        .map(|level| {
+            let mip = MipOutput {
+                level,
+                resolution: resolution >> level,
+                roughness: if levels > 1 {
+                    level as f32 / (levels - 1) as f32
+                } else {
+                    0.0
+                },
+                offset,
+            };
+            offset += mip.texels();
+            mip
+        })
+        .collect()
+}
+
+/// Filters an environment cube map once per entry of a schedule of increasing quality,
+/// so that a quick low quality result can be shown while better ones are computed.
+///
+/// The filter keeps its own copy of the environment cube map, which is owned by the
+/// compute queue for as long as the filter runs.
+#[derive(Debug)]
+pub struct EnvironmentFilter<B: hal::Backend> {
+    queue: QueueId,
+    graphics: QueueId,
+    schedule: VecDeque<FilterSettings>,
+    source: CubeMap<B>,
+    descriptor_pool: B::DescriptorPool,
+    set_layout: B::DescriptorSetLayout,
+    set: B::DescriptorSet,
+    pipeline_layout: B::PipelineLayout,
+    output: Escape<Buffer<B>>,
+    irradiance_mips: Vec<MipOutput>,
+    specular_mips: Vec<MipOutput>,
+    pass: Option<FilterPass<B>>,
+}
+
+/// A single run of the filters, writing new irradiance and specular cube maps.
+#[derive(Debug)]
+struct FilterPass<B: hal::Backend> {
+    settings: FilterSettings,
+    pool: CommandPool<B>,
+    buffer: CommandBuffer<B, hal::queue::QueueType, PendingOnceState, PrimaryLevel>,
+    fence: Fence<B>,
+    irradiance_pipeline: B::ComputePipeline,
+    specular_pipeline: B::ComputePipeline,
+    irradiance: Texture<B>,
+    specular: Texture<B>,
+}
+
+impl<B: hal::Backend> EnvironmentFilter<B> {
+    /// Start filtering an environment cube map, which must be readable by fragment shaders
+    /// on the graphics queue and is not used by anything else afterwards. The schedule
+    /// must not be empty.
+    pub fn start(
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        graphics: QueueId,
+        source: CubeMap<B>,
+        schedule: Vec<FilterSettings>,
+    ) -> Result<Self, failure::Error> {
+        if schedule.is_empty() {
+            failure::bail!("The environment filter schedule is empty");
+        }
+
+        let queue = crate::queues::dedicated_queue(families, hal::queue::QueueType::Compute)
+            .unwrap_or(graphics);
+
+        let irradiance_mips = mip_outputs(0, crate::IRRADIANCE_CUBEMAP_RES, 1);
+        let specular_mips = mip_outputs(
+            irradiance_mips.iter().map(MipOutput::texels).sum(),
+            crate::SPEC_CUBEMAP_RES,
+            crate::SPEC_CUBEMAP_MIP_LEVELS,
+        );
+        let total_texels: u32 = irradiance_mips
+            .iter()
+            .chain(specular_mips.iter())
+            .map(MipOutput::texels)
+            .sum();
+
+        let output = factory.create_buffer(
+            BufferInfo {
+                size: total_texels as u64 * MipOutput::TEXEL_SIZE,
+                usage: hal::buffer::Usage::STORAGE | hal::buffer::Usage::TRANSFER_SRC,
+            },
+            MemoryUsageValue::Data,
+        )?;
+
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory.device().create_descriptor_set_layout(
+                vec![
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 0,
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                        immutable_samplers: false,
+                    },
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 1,
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                        immutable_samplers: false,
+                    },
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 2,
+                        ty: hal::pso::DescriptorType::StorageBuffer,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                        immutable_samplers: false,
+                    },
+                ],
+                std::iter::empty::<B::Sampler>(),
+            )?;
+            let pipeline_layout = factory.device().create_pipeline_layout(
+                Some(&set_layout),
+                Some((hal::pso::ShaderStageFlags::COMPUTE, FilterArgs::RANGE)),
+            )?;
+            (set_layout, pipeline_layout)
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory.create_descriptor_pool(
+                1,
+                vec![
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::StorageBuffer,
+                        count: 1,
+                    },
+                ],
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )?
+        };
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layout)?;
+            factory.write_descriptor_sets(vec![
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Sampler(source.sampler().raw())),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        source.view().raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 2,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Buffer(output.raw(), None..None)),
+                },
+            ]);
+            set
+        };
+
+        let mut filter = EnvironmentFilter {
+            queue,
+            graphics,
+            schedule: schedule.into(),
+            source,
+            descriptor_pool,
+            set_layout,
+            set,
+            pipeline_layout,
+            output,
+            irradiance_mips,
+            specular_mips,
+            pass: None,
+        };
+
+        // Hand the source cube map over to the compute queue for good
+        if filter.dedicated() {
+            unsafe {
+                crate::upload::submit_barriers(
+                    factory,
+                    families,
+                    graphics,
+                    hal::pso::PipelineStage::FRAGMENT_SHADER
+                        ..hal::pso::PipelineStage::BOTTOM_OF_PIPE,
+                    vec![filter.source_ownership_barrier()],
+                )?;
+            }
+        }
+
+        filter.start_pass(factory, families, true)?;
+        Ok(filter)
+    }
+
+    fn dedicated(&self) -> bool {
+        self.queue.family != self.graphics.family
+    }
+
+    /// Whether every pass of the schedule has been finished.
+    pub fn is_done(&self) -> bool {
+        self.pass.is_none()
+    }
+
+    /// Check whether the current pass has completed. If it has, the irradiance and specular
+    /// cube maps it produced are returned, readable by fragment shaders on the graphics
+    /// queue, and the next pass of the schedule is started.
+    pub fn poll(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+    ) -> Result<Option<(Texture<B>, Texture<B>)>, failure::Error> {
+        let complete = match &self.pass {
+            Some(pass) => unsafe { factory.device().get_fence_status(pass.fence.raw())? },
+            None => false,
+        };
+        if !complete {
+            return Ok(None);
+        }
+
+        let pass = self.pass.take().unwrap();
+        log::info!("Environment filtering complete with {:?}", pass.settings);
+        let filtered = self.finish_pass(factory, families, pass)?;
+        if !self.schedule.is_empty() {
+            self.start_pass(factory, families, false)?;
+        }
+        Ok(Some(filtered))
+    }
+
+    /// Record and submit the next pass of the schedule.
+    fn start_pass(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        acquire_source: bool,
+    ) -> Result<(), failure::Error> {
+        let settings = self.schedule.pop_front().unwrap();
+        log::info!(
+            "Filtering environment with {:?} on queue {:?}",
+            settings,
+            self.queue
+        );
+
+        let target_state = ImageState {
+            queue: self.queue,
+            stage: hal::pso::PipelineStage::TRANSFER,
+            access: hal::image::Access::TRANSFER_WRITE,
+            layout: hal::image::Layout::TransferDstOptimal,
+        };
+        let irradiance = black_cubemap(factory, crate::IRRADIANCE_CUBEMAP_RES, 1, target_state)?;
+        let specular = black_cubemap(
+            factory,
+            crate::SPEC_CUBEMAP_RES,
+            crate::SPEC_CUBEMAP_MIP_LEVELS,
+            target_state,
+        )?;
+
+        let (irradiance_pipeline, specular_pipeline) = unsafe {
+            (
+                create_pipeline(
+                    factory,
+                    &self.pipeline_layout,
+                    &*IRRADIANCE,
+                    &[settings.irradiance_theta_samples],
+                )?,
+                create_pipeline(factory, &self.pipeline_layout, &*SPECULAR, &[])?,
+            )
+        };
+
+        // The cube map uploads are only submitted on maintain, and must be submitted first
+        factory.maintain(families);
+
+        let dedicated = self.dedicated();
+        let family = families.family_mut(self.queue.family);
+        let mut pool = factory.create_command_pool(family)?;
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(OneShot, ());
+        {
+            let mut encoder = buf_recording.encoder();
+            unsafe {
+                if dedicated && acquire_source {
+                    encoder.pipeline_barrier(
+                        hal::pso::PipelineStage::TOP_OF_PIPE
+                            ..hal::pso::PipelineStage::COMPUTE_SHADER,
+                        hal::memory::Dependencies::empty(),
+                        Some(self.source_ownership_barrier()),
+                    );
+                }
+
+                encoder.bind_compute_descriptor_sets(
+                    &self.pipeline_layout,
+                    0,
+                    Some(&self.set),
+                    std::iter::empty(),
+                );
+
+                let source_image = self.source.image();
+                for (pipeline, mips, samples) in &[
+                    (
+                        &irradiance_pipeline,
+                        &self.irradiance_mips,
+                        &[settings.irradiance_theta_samples][..],
+                    ),
+                    (
+                        &specular_pipeline,
+                        &self.specular_mips,
+                        &settings.spec_samples[..],
+                    ),
+                ] {
+                    encoder.bind_compute_pipeline(pipeline);
+                    for mip in mips.iter() {
+                        let args = FilterArgs {
+                            offset: mip.offset,
+                            resolution: mip.resolution,
+                            roughness: mip.roughness,
+                            env_resolution: source_image.kind().extent().width as f32,
+                            sample_count: samples[mip.level as usize],
+                            lod_bias: settings.spec_mode.lod_bias(),
+                            env_max_lod: (source_image.levels() - 1) as f32,
+                        };
+                        encoder.push_constants(
+                            &self.pipeline_layout,
+                            hal::pso::ShaderStageFlags::COMPUTE,
+                            FilterArgs::RANGE.start,
+                            &args.as_words(),
+                        );
+                        let groups = (mip.resolution + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+                        encoder.dispatch([groups, groups, 6]);
+                    }
+                }
+
+                encoder.pipeline_barrier(
+                    hal::pso::PipelineStage::COMPUTE_SHADER..hal::pso::PipelineStage::TRANSFER,
+                    hal::memory::Dependencies::empty(),
+                    Some(hal::memory::Barrier::Buffer {
+                        states: hal::buffer::Access::SHADER_WRITE
+                            ..hal::buffer::Access::TRANSFER_READ,
+                        families: None,
+                        target: self.output.raw(),
+                        range: None..None,
+                    }),
+                );
+
+                for (texture, mips) in &[
+                    (&irradiance, &self.irradiance_mips),
+                    (&specular, &self.specular_mips),
+                ] {
+                    encoder.copy_buffer_to_image(
+                        self.output.raw(),
+                        texture.image().raw(),
+                        hal::image::Layout::TransferDstOptimal,
+                        mips.iter().map(|mip| hal::command::BufferImageCopy {
+                            buffer_offset: mip.buffer_offset(),
+                            buffer_width: mip.resolution,
+                            buffer_height: mip.resolution,
+                            image_layers: hal::image::SubresourceLayers {
+                                aspects: hal::format::Aspects::COLOR,
+                                level: mip.level,
+                                layers: 0..6,
+                            },
+                            image_offset: hal::image::Offset::ZERO,
+                            image_extent: hal::image::Extent {
+                                width: mip.resolution,
+                                height: mip.resolution,
+                                depth: 1,
+                            },
+                        }),
+                    );
+                }
+
+                // Make the filtered cube maps readable by fragment shaders, releasing them
+                // to the graphics queue if the work was done on a dedicated compute queue
+                let (release_families, end_stage) = if dedicated {
+                    (
+                        Some(self.compute_to_graphics()),
+                        hal::pso::PipelineStage::BOTTOM_OF_PIPE,
+                    )
+                } else {
+                    (None, hal::pso::PipelineStage::FRAGMENT_SHADER)
+                };
+                encoder.pipeline_barrier(
+                    hal::pso::PipelineStage::TRANSFER..end_stage,
+                    hal::memory::Dependencies::empty(),
+                    filtered_barriers(&irradiance, &specular, release_families),
+                );
+            }
+        }
+        let (submit, buffer) = buf_recording.finish().submit_once();
+
+        let mut fence = factory.create_fence(false)?;
+        unsafe {
+            family.queue_mut(self.queue.index).submit(
+                Some(Submission::new().submits(Some(submit))),
+                Some(&mut fence),
+            );
+        }
+
+        self.pass = Some(FilterPass {
+            settings,
+            pool,
+            buffer,
+            fence,
+            irradiance_pipeline,
+            specular_pipeline,
+            irradiance,
+            specular,
+        });
+        Ok(())
+    }
+
+    /// Wait for a pass to complete, acquire its cube maps on the graphics queue and destroy
+    /// the rest of it.
+    fn finish_pass(
+        &self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        mut pass: FilterPass<B>,
+    ) -> Result<(Texture<B>, Texture<B>), failure::Error> {
+        factory.wait_for_fence(&mut pass.fence, !0)?;
+
+        if self.dedicated() {
+            unsafe {
+                crate::upload::submit_barriers(
+                    factory,
+                    families,
+                    self.graphics,
+                    hal::pso::PipelineStage::TOP_OF_PIPE..hal::pso::PipelineStage::FRAGMENT_SHADER,
+                    filtered_barriers(
+                        &pass.irradiance,
+                        &pass.specular,
+                        Some(self.compute_to_graphics()),
+                    ),
+                )?;
+            }
+        }
+
+        unsafe {
+            pass.pool.free_buffers(Some(pass.buffer.mark_complete()));
+            factory.destroy_command_pool(pass.pool);
+            factory.destroy_fence(pass.fence);
+            factory
+                .device()
+                .destroy_compute_pipeline(pass.irradiance_pipeline);
+            factory
+                .device()
+                .destroy_compute_pipeline(pass.specular_pipeline);
+        }
+
+        Ok((pass.irradiance, pass.specular))
+    }
+
+    /// Wait for any pass in flight and destroy the filter, skipping the rest of the schedule.
+    pub fn dispose(
+        mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+    ) -> Result<(), failure::Error> {
+        if let Some(pass) = self.pass.take() {
+            self.finish_pass(factory, families, pass)?;
+        }
+
+        unsafe {
+            self.descriptor_pool.reset();
+            factory.destroy_descriptor_pool(self.descriptor_pool);
+            factory
+                .device()
+                .destroy_pipeline_layout(self.pipeline_layout);
+            factory
+                .device()
+                .destroy_descriptor_set_layout(self.set_layout);
+        }
+        Ok(())
+    }
+
+    fn compute_to_graphics(&self) -> std::ops::Range<hal::queue::QueueFamilyId> {
+        hal::queue::QueueFamilyId(self.queue.family.index)
+            ..hal::queue::QueueFamilyId(self.graphics.family.index)
+    }
+
+    /// Transfer ownership of the source cube map from the graphics to the compute queue,
+    /// keeping it readable.
+    fn source_ownership_barrier(&self) -> hal::memory::Barrier<'_, B> {
+        let readable = (
+            hal::image::Access::SHADER_READ,
+            hal::image::Layout::ShaderReadOnlyOptimal,
+        );
+        hal::memory::Barrier::Image {
+            states: readable..readable,
+            families: Some(
+                hal::queue::QueueFamilyId(self.graphics.family.index)
+                    ..hal::queue::QueueFamilyId(self.queue.family.index),
+            ),
+            target: self.source.image().raw(),
+            range: hal::image::SubresourceRange {
+                aspects: hal::format::Aspects::COLOR,
+                levels: 0..self.source.image().levels(),
+                layers: 0..6,
+            },
+        }
+    }
+}
+
+unsafe fn create_pipeline<B: hal::Backend>(
+    factory: &Factory<B>,
+    layout: &B::PipelineLayout,
+    shader: &PathBufShaderInfo,
+    specialization: &[u32],
+) -> Result<B::ComputePipeline, failure::Error> {
+    let module = factory.device().create_shader_module(&shader.spirv()?)?;
+    let pipeline = factory.device().create_compute_pipeline(
+        &hal::pso::ComputePipelineDesc::new(
+            hal::pso::EntryPoint {
+                entry: "main",
+                module: &module,
+                specialization: hal::pso::Specialization {
+                    constants: Cow::from(
+                        (0..specialization.len() as u32)
+                            .map(|id| hal::pso::SpecializationConstant {
+                                id,
+                                range: id as u16 * 4..(id as u16 + 1) * 4,
+                            })
+                            .collect::<Vec<_>>(),
+                    ),
+                    data: Cow::from(std::slice::from_raw_parts(
+                        specialization.as_ptr() as *const u8,
+                        specialization.len() * size_of::<u32>(),
+                    )),
+                },
+            },
+            layout,
+        ),
+        None,
+    );
+    factory.device().destroy_shader_module(module);
+    Ok(pipeline?)
+}
+
+/// A black cube map to be filled in by a filter, in the given state.
+fn black_cubemap<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    resolution: u32,
+    mip_levels: u8,
+    state: ImageState,
+) -> Result<Texture<B>, failure::Error> {
+    Ok(rendy::texture::TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D2(resolution, resolution, 6, 1))
+        .with_mip_levels(rendy::texture::MipLevels::Levels(
+            std::num::NonZeroU8::new(mip_levels).unwrap(),
+        ))
+        .with_view_kind(rendy::resource::ViewKind::Cube)
+        .with_data_width(resolution)
+        .with_data_height(resolution)
+        .with_data(vec![
+            rendy::texture::pixel::Rgba32Sfloat {
+                repr: [0.0, 0.0, 0.0, 1.0]
+            };
+            (resolution * resolution * 6) as usize
+        ])
+        .build(state, factory)?)
+}
+
+/// Transition the filtered cube maps from being copied into to being sampled.
+fn filtered_barriers<'a, B: hal::Backend>(
+    irradiance: &'a Texture<B>,
+    specular: &'a Texture<B>,
+    families: Option<std::ops::Range<hal::queue::QueueFamilyId>>,
+) -> Vec<hal::memory::Barrier<'a, B>> {
+    [irradiance, specular]
+        .iter()
+        .map(|texture| hal::memory::Barrier::Image {
+            states: (
+                hal::image::Access::TRANSFER_WRITE,
+                hal::image::Layout::TransferDstOptimal,
+            )
+                ..(
+                    hal::image::Access::SHADER_READ,
+                    hal::image::Layout::ShaderReadOnlyOptimal,
+                ),
+            families: families.clone(),
+            target: texture.image().raw(),
+            range: hal::image::SubresourceRange {
+                aspects: hal::format::Aspects::COLOR,
+                levels: 0..texture.image().levels(),
+                layers: 0..6,
+            },
+        })
+        .collect()
+}
@@ -25,13 +25,36 @@ lazy_static::lazy_static! {
         "main",
     );
 
+    static ref AVERAGE_FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/integrate_spec_brdf_avg.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
     static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
         .with_vertex(&*VERTEX).unwrap()
         .with_fragment(&*FRAGMENT).unwrap();
+
+    static ref AVERAGE_SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*AVERAGE_FRAGMENT).unwrap();
 }
 
 #[derive(Debug, Default)]
-pub struct PipelineDesc;
+pub struct PipelineDesc {
+    average: bool,
+}
+
+impl PipelineDesc {
+    /// Integrate the specular lobe's albedo averaged over view angles instead, into an image
+    /// one texel high with a texel per roughness. Read along with the BRDF map to add back
+    /// the energy of multiple scattering.
+    pub fn averaged(mut self) -> Self {
+        self.average = true;
+        self
+    }
+}
 
 pub struct Pipeline;
 
@@ -63,7 +86,11 @@ where
         factory: &mut Factory<B>,
         _aux: &Aux<B>,
     ) -> rendy::shader::ShaderSet<B> {
-        SHADERS.build(factory, Default::default()).unwrap()
+        if self.average {
+            AVERAGE_SHADERS.build(factory, Default::default()).unwrap()
+        } else {
+            SHADERS.build(factory, Default::default()).unwrap()
+        }
     }
 
     fn layout(&self) -> Layout {
@@ -111,6 +138,7 @@ where
         _index: usize,
         _aux: &Aux<B>,
     ) {
+        let _scope = crate::profile::scope("IntegrateSpecBrdfPipeline::draw");
         unsafe {
             encoder.draw(0..3, 0..1);
         }
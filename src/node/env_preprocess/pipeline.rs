@@ -0,0 +1,462 @@
+//! The environment preprocess as a whole, from an environment map file to the textures the
+//! scene is lit with. The map is loaded into a cube map, converted first if it's
+//! equirectangular, the specular BRDF map is integrated, and the irradiance and specular cube
+//! maps are filtered from a copy of the cube map.
+//!
+//! An `EnvPipeline` is built once and runs the preprocess again for every environment map
+//! loaded, like those dropped onto the window.
+use rendy::{
+    command::Families,
+    factory::{Factory, ImageState},
+    graph::{
+        render::{SimpleGraphicsPipeline, SimpleGraphicsPipelineDesc},
+        GraphBuilder,
+    },
+};
+
+use rendy::hal;
+
+use crate::{
+    graph_dump::GraphDescription,
+    node::{
+        env_preprocess::{
+            copy_to_texture::CopyToTexture,
+            cube_map::CubeMap,
+            cubemap_file,
+            equirectangular_to_cubemap::EquirectangularToCubemap,
+            filter::{EnvironmentFilter, FilterSettings, SpecularFilterSettings},
+            integrate_spec_brdf, Aux,
+        },
+        pbr::EnvironmentStorage,
+    },
+    scene::{EnvironmentSource, Quality},
+    upload::UploadQueues,
+};
+
+/// Least and most face resolution picked for the cube map of an equirectangular map
+const MIN_AUTO_CUBEMAP_RES: u32 = 64;
+const MAX_AUTO_CUBEMAP_RES: u32 = 2048;
+/// Width and height of the smallest mip picked for the cube map of an equirectangular map
+const SMALLEST_AUTO_MIP_RES: u32 = 16;
+/// Face resolution of a uniform environment's cube map, which only matters for its mips
+const UNIFORM_CUBEMAP_RES: u32 = MIN_AUTO_CUBEMAP_RES;
+
+/// The face resolution of the cube map an equirectangular map `equirect_width` texels wide is
+/// converted to. Its width goes around four faces, so they get about as many texels along the
+/// horizon, rounded up to a power of two.
+pub fn auto_cubemap_res(equirect_width: u32) -> u32 {
+    (equirect_width / 4)
+        .max(1)
+        .next_power_of_two()
+        .max(MIN_AUTO_CUBEMAP_RES)
+        .min(MAX_AUTO_CUBEMAP_RES)
+}
+
+/// The number of mips of a cube map with faces `res` texels wide, down to
+/// `SMALLEST_AUTO_MIP_RES`.
+pub fn auto_mip_levels(res: u32) -> u8 {
+    (32 - (res / SMALLEST_AUTO_MIP_RES).max(1).leading_zeros()) as u8
+}
+
+/// Mips in the full chain of a cube map with faces `res` texels wide
+fn max_mip_levels(res: u32) -> u8 {
+    (32 - res.max(1).leading_zeros()) as u8
+}
+
+/// Resolutions, formats and filter quality of an `EnvPipeline`.
+#[derive(Debug, Clone)]
+pub struct EnvPipelineBuilder {
+    /// Picked from the source's resolution if not set
+    env_cubemap_res: Option<u32>,
+    /// Picked from the cube map's resolution if not set
+    env_cubemap_mip_levels: Option<u8>,
+    spec_brdf_map_res: u32,
+    color_target_format: hal::format::Format,
+    rg_target_format: hal::format::Format,
+    filter_schedule: Vec<FilterSettings>,
+    specular_filter: SpecularFilterSettings,
+}
+
+impl EnvPipelineBuilder {
+    /// The environment cube maps are rendered in `color_target_format` and the BRDF map in
+    /// `rg_target_format`. The cube map's resolution and mips are picked from the source's
+    /// resolution, the BRDF map's resolution defaults to the crate's constant, and the filter
+    /// runs once at high quality.
+    pub fn new(
+        color_target_format: hal::format::Format,
+        rg_target_format: hal::format::Format,
+    ) -> Self {
+        EnvPipelineBuilder {
+            env_cubemap_res: None,
+            env_cubemap_mip_levels: None,
+            spec_brdf_map_res: crate::SPEC_BRDF_MAP_RES,
+            color_target_format,
+            rg_target_format,
+            filter_schedule: vec![(&Quality::High).into()],
+            specular_filter: SpecularFilterSettings::default(),
+        }
+    }
+
+    /// Width and height of each face of the cube map equirectangular maps are converted to,
+    /// or `None` to pick it with `auto_cubemap_res`. Cube maps loaded as such keep their own.
+    pub fn with_env_cubemap_res(mut self, res: Option<u32>) -> Self {
+        self.env_cubemap_res = res;
+        self
+    }
+
+    /// Mip levels of the cube map equirectangular maps are converted to, or `None` to pick
+    /// them with `auto_mip_levels`. Capped at the full mip chain.
+    pub fn with_env_cubemap_mip_levels(mut self, levels: Option<u8>) -> Self {
+        self.env_cubemap_mip_levels = levels;
+        self
+    }
+
+    pub fn with_spec_brdf_map_res(mut self, res: u32) -> Self {
+        self.spec_brdf_map_res = res;
+        self
+    }
+
+    /// The qualities to filter at, in order, each one replacing the result of the last.
+    pub fn with_filter_quality(mut self, qualities: &[Quality]) -> Self {
+        self.filter_schedule = qualities.iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_filter_schedule(mut self, schedule: Vec<FilterSettings>) -> Self {
+        self.filter_schedule = schedule;
+        self
+    }
+
+    /// The specular filter's mode and sample counts, applied to every entry of the schedule.
+    pub fn with_specular_filter(mut self, specular: SpecularFilterSettings) -> Self {
+        self.specular_filter = specular;
+        self
+    }
+
+    pub fn build(self, upload_queues: UploadQueues, align: u64) -> EnvPipeline {
+        EnvPipeline {
+            settings: self,
+            upload_queues,
+            align,
+            description: GraphDescription::new("env_preprocess"),
+        }
+    }
+}
+
+pub struct EnvPipeline {
+    settings: EnvPipelineBuilder,
+    upload_queues: UploadQueues,
+    align: u64,
+    description: GraphDescription,
+}
+
+impl EnvPipeline {
+    pub fn builder(
+        color_target_format: hal::format::Format,
+        rg_target_format: hal::format::Format,
+    ) -> EnvPipelineBuilder {
+        EnvPipelineBuilder::new(color_target_format, rg_target_format)
+    }
+
+    /// The passes of the last preprocess, see `crate::graph_dump`
+    pub fn description(&self) -> &GraphDescription {
+        &self.description
+    }
+
+    /// Preprocesses the environment map at `source` and starts filtering it. The returned
+    /// storage holds the environment cube map and the specular BRDF map, with placeholders for
+    /// the irradiance and specular cube maps until the filter's first pass is done, and no
+    /// probes or voxel volume.
+    pub fn process<B: hal::Backend>(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        source: &EnvironmentSource,
+    ) -> Result<(EnvironmentStorage<B>, EnvironmentFilter<B>), failure::Error> {
+        let queue = self.upload_queues.graphics;
+        let mut aux = self.preprocess(factory, families, source)?;
+        let filter = EnvironmentFilter::start(
+            factory,
+            families,
+            queue,
+            aux.filter_source_cubemap.take().unwrap(),
+            self.settings
+                .filter_schedule
+                .iter()
+                .map(|settings| settings.with_specular_filter(&self.settings.specular_filter))
+                .collect(),
+        )?;
+        let storage = EnvironmentStorage {
+            env_cube: aux.environment_cubemap.take(),
+            irradiance_cube: Some(EnvironmentStorage::placeholder_cube(factory, queue)?),
+            spec_cube: Some(EnvironmentStorage::placeholder_cube(factory, queue)?),
+            spec_brdf_map: aux.spec_brdf_map.take(),
+            spec_brdf_avg_map: aux.spec_brdf_avg_map.take(),
+            probes: None,
+            voxel_radiance: None,
+            generation: 0,
+        };
+        Ok((storage, filter))
+    }
+
+    /// Loads the environment map into a cube map, converting it first if it is
+    /// equirectangular, and integrates the specular BRDF map. The filter source cube map is a
+    /// copy of the environment cube map for the filter to read while the environment is
+    /// displayed.
+    fn preprocess<B: hal::Backend>(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        source: &EnvironmentSource,
+    ) -> Result<Aux<B>, failure::Error> {
+        let settings = &self.settings;
+        let queue = self.upload_queues.graphics;
+        let graph_description = &mut self.description;
+        *graph_description = GraphDescription::new("env_preprocess");
+
+        // Finished cube maps are loaded as they are, skipping the conversion
+        let root = std::path::Path::new(&crate::application_root_dir()).to_path_buf();
+        let (equirect_path, loaded_cube) = match source {
+            EnvironmentSource::Equirectangular(path) => (Some(root.join(path)), None),
+            EnvironmentSource::CubeFaces(paths) => (
+                None,
+                Some(cubemap_file::load_faces(
+                    &paths.iter().map(|path| root.join(path)).collect::<Vec<_>>(),
+                )?),
+            ),
+            EnvironmentSource::Ktx(path) => (None, Some(cubemap_file::load_ktx(root.join(path))?)),
+            EnvironmentSource::Uniform(color) => (
+                None,
+                Some(cubemap_file::uniform(*color, UNIFORM_CUBEMAP_RES)),
+            ),
+        };
+
+        let sampled = ImageState {
+            queue,
+            stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            access: hal::image::Access::SHADER_READ,
+            layout: hal::image::Layout::ShaderReadOnlyOptimal,
+        };
+
+        let equirect_tex = match &equirect_path {
+            Some(path) if crate::asset::is_exr(path) => {
+                let (width, height, texels) = crate::asset::load_exr(path)?;
+                Some(
+                    rendy::texture::TextureBuilder::new()
+                        .with_kind(rendy::resource::Kind::D2(width, height, 1, 1))
+                        .with_view_kind(rendy::resource::ViewKind::D2)
+                        .with_data_width(width)
+                        .with_data_height(height)
+                        .with_data(&texels[..])
+                        .build(sampled, factory)?,
+                )
+            }
+            Some(path) => Some(
+                rendy::texture::image::load_from_image(
+                    std::io::BufReader::new(std::fs::File::open(path)?),
+                    rendy::texture::image::ImageTextureConfig {
+                        repr: rendy::texture::image::Repr::Float,
+                        ..Default::default()
+                    },
+                )?
+                .build(sampled, factory)?,
+            ),
+            None => None,
+        };
+
+        let (cubemap_res, mip_levels) = match &equirect_tex {
+            Some(equirect_tex) => {
+                let width = equirect_tex.image().kind().extent().width;
+                let res = settings
+                    .env_cubemap_res
+                    .unwrap_or_else(|| auto_cubemap_res(width))
+                    .max(1);
+                let levels = settings
+                    .env_cubemap_mip_levels
+                    .unwrap_or_else(|| auto_mip_levels(res))
+                    .max(1)
+                    .min(max_mip_levels(res));
+                log::info!(
+                    "Converting the {} texel wide environment map to a {}x{} cube map with {} mips",
+                    width,
+                    res,
+                    res,
+                    levels
+                );
+                (res, levels)
+            }
+            // Unused, cube maps keep their own
+            None => (1, 1),
+        };
+
+        let mut graph_builder = GraphBuilder::<B, Aux<B>>::new();
+
+        // Equirectangular env map to environment cube map
+        if equirect_path.is_some() {
+            let _equirect_to_cubemap_pass = graph_description.add_node(
+                &mut graph_builder,
+                "equirectangular_to_cubemap",
+                EquirectangularToCubemap::<B>::builder(&["environment", "filter_source"]),
+            );
+        }
+
+        let spec_brdf_map = graph_description.create_image(
+            &mut graph_builder,
+            "spec_brdf_map",
+            hal::image::Kind::D2(settings.spec_brdf_map_res, settings.spec_brdf_map_res, 1, 1),
+            1,
+            settings.rg_target_format,
+            Some(hal::command::ClearValue {
+                color: hal::command::ClearColor {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+            }),
+        );
+
+        let brdf_integration_pass = graph_description.add_node(
+            &mut graph_builder,
+            "integrate_spec_brdf",
+            integrate_spec_brdf::Pipeline::builder()
+                .into_subpass()
+                .with_color(spec_brdf_map)
+                .into_pass(),
+        );
+
+        let _brdf_to_texture = graph_description.add_node(
+            &mut graph_builder,
+            "spec_brdf_to_texture",
+            CopyToTexture::<B>::builder(spec_brdf_map, "spec_brdf")
+                .with_dependency(brdf_integration_pass),
+        );
+
+        let spec_brdf_avg_map = graph_description.create_image(
+            &mut graph_builder,
+            "spec_brdf_avg_map",
+            hal::image::Kind::D2(settings.spec_brdf_map_res, 1, 1, 1),
+            1,
+            settings.rg_target_format,
+            Some(hal::command::ClearValue {
+                color: hal::command::ClearColor {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+            }),
+        );
+
+        let brdf_avg_integration_pass = graph_description.add_node(
+            &mut graph_builder,
+            "integrate_spec_brdf_avg",
+            integrate_spec_brdf::PipelineDesc::default()
+                .averaged()
+                .builder()
+                .into_subpass()
+                .with_color(spec_brdf_avg_map)
+                .into_pass(),
+        );
+
+        let _brdf_avg_to_texture = graph_description.add_node(
+            &mut graph_builder,
+            "spec_brdf_avg_to_texture",
+            CopyToTexture::<B>::builder(spec_brdf_avg_map, "spec_brdf_avg")
+                .with_dependency(brdf_avg_integration_pass),
+        );
+
+        let build_env_cubemap = |factory: &mut Factory<B>| match &loaded_cube {
+            // Ready to sample once built, with mips generated from the top level
+            Some(cube) => {
+                let texture = rendy::texture::TextureBuilder::new()
+                    .with_kind(rendy::resource::Kind::D2(
+                        cube.resolution,
+                        cube.resolution,
+                        6,
+                        1,
+                    ))
+                    .with_mip_levels(rendy::texture::MipLevels::GenerateAuto)
+                    .with_view_kind(rendy::resource::ViewKind::Cube)
+                    .with_data_width(cube.resolution)
+                    .with_data_height(cube.resolution)
+                    .with_data(&cube.texels[..])
+                    .build(sampled, factory)?;
+                CubeMap::from_texture(factory, texture)
+            }
+            // Rendered by the EquirectangularToCubemap node, which leaves it ready to sample
+            None => CubeMap::render_target(
+                factory,
+                cubemap_res,
+                mip_levels,
+                settings.color_target_format,
+            ),
+        };
+        let env_cubemap_tex = build_env_cubemap(factory)?;
+        let filter_source_tex = build_env_cubemap(factory)?;
+
+        let build_brdf_map = |factory: &mut Factory<B>, width: u32, height: u32| {
+            rendy::texture::TextureBuilder::new()
+                .with_kind(rendy::resource::Kind::D2(width, height, 1, 1))
+                .with_view_kind(rendy::resource::ViewKind::D2)
+                .with_data_width(width)
+                .with_data_height(height)
+                .with_raw_data(
+                    vec![
+                        0u8;
+                        (width * height) as usize
+                            * settings.rg_target_format.surface_desc().bits as usize
+                            / 8
+                    ],
+                    settings.rg_target_format,
+                )
+                .build(
+                    // Written by a copy on the transfer queue, if there is a dedicated one
+                    ImageState {
+                        queue: self.upload_queues.transfer,
+                        stage: hal::pso::PipelineStage::TRANSFER,
+                        access: hal::image::Access::TRANSFER_WRITE,
+                        layout: hal::image::Layout::TransferDstOptimal,
+                    },
+                    factory,
+                )
+        };
+        let spec_brdf_tex = build_brdf_map(
+            factory,
+            settings.spec_brdf_map_res,
+            settings.spec_brdf_map_res,
+        )?;
+        let spec_brdf_avg_tex = build_brdf_map(factory, settings.spec_brdf_map_res, 1)?;
+
+        let mut aux = Aux {
+            align: self.align,
+            equirectangular_texture: equirect_tex,
+            environment_cubemap: Some(env_cubemap_tex),
+            filter_source_cubemap: Some(filter_source_tex),
+            spec_brdf_map: Some(spec_brdf_tex),
+            spec_brdf_avg_map: Some(spec_brdf_avg_tex),
+            queue,
+        };
+
+        let mut graph = graph_builder.build(factory, families, &mut aux)?;
+
+        factory.maintain(families);
+        graph.run(factory, families, &mut aux);
+        graph.dispose(factory, &mut aux);
+
+        // The BRDF maps are released by the copies on the transfer queue
+        factory.wait_idle()?;
+        crate::upload::acquire_on_graphics(
+            factory,
+            families,
+            &self.upload_queues,
+            &[
+                (
+                    &**aux.spec_brdf_map.as_ref().unwrap().image(),
+                    hal::image::Layout::TransferDstOptimal,
+                ),
+                (
+                    &**aux.spec_brdf_avg_map.as_ref().unwrap().image(),
+                    hal::image::Layout::TransferDstOptimal,
+                ),
+            ],
+            &[],
+        )?;
+
+        Ok(aux)
+    }
+}
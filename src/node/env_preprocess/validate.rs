@@ -0,0 +1,92 @@
+//! Reads cube maps back from the GPU to check that every face of every mip was written,
+//! which catches missing copies and seams in the conversion and filtering steps that are
+//! easy to miss by eye, like a black face in a rarely viewed low mip.
+use rendy::{
+    command::{Families, QueueId},
+    factory::Factory,
+    resource::Image,
+};
+
+use rendy::hal;
+
+use crate::node::env_preprocess::debug::read_texture;
+
+/// Whether a face of a mip looks written.
+#[derive(Debug)]
+pub struct FaceReport {
+    pub mip: u8,
+    pub face: u8,
+    /// Texels which are exactly black
+    pub black_texels: usize,
+    /// Texels with a NaN or infinite channel
+    pub invalid_texels: usize,
+    pub total_texels: usize,
+}
+
+impl FaceReport {
+    /// A face which is all black was most likely never written, since real environments
+    /// always have some light in every direction.
+    pub fn is_valid(&self) -> bool {
+        self.invalid_texels == 0 && self.black_texels < self.total_texels
+    }
+}
+
+/// Copies every mip of an `Rgba32Sfloat` or `Rgba16Sfloat` cube map to host memory and checks each face,
+/// logging a warning for the faces which look wrong. Returns whether all were fine.
+///
+/// The cube map must be owned by `queue`'s family and in `ShaderReadOnlyOptimal`, which it
+/// is left in. Waits for the copy to complete, so it is only meant for debugging.
+pub fn check_cube_mips<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    image: &Image<B>,
+    name: &str,
+) -> Result<bool, failure::Error> {
+    match image.format() {
+        hal::format::Format::Rgba32Sfloat | hal::format::Format::Rgba16Sfloat => (),
+        _ => failure::bail!("{} is not an Rgba32Sfloat or Rgba16Sfloat cube map", name),
+    }
+    if image.layers() != 6 {
+        failure::bail!("{} is not a cube map", name);
+    }
+    let levels = image.levels();
+
+    let reports = read_texture(factory, families, queue, image)?
+        .into_iter()
+        .map(|face| FaceReport {
+            mip: face.level,
+            face: face.layer as u8,
+            black_texels: face
+                .texels
+                .iter()
+                .filter(|t| t[0] == 0.0 && t[1] == 0.0 && t[2] == 0.0)
+                .count(),
+            invalid_texels: face
+                .texels
+                .iter()
+                .filter(|t| t.iter().any(|c| !c.is_finite()))
+                .count(),
+            total_texels: face.texels.len(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut valid = true;
+    for report in reports.iter().filter(|report| !report.is_valid()) {
+        valid = false;
+        log::warn!(
+            "{} mip {} face {} looks unwritten: {} of {} texels black, {} NaN or infinite",
+            name,
+            report.mip,
+            report.face,
+            report.black_texels,
+            report.total_texels,
+            report.invalid_texels
+        );
+    }
+    if valid {
+        log::info!("{}: all {} mips of all faces were written", name, levels);
+    }
+
+    Ok(valid)
+}
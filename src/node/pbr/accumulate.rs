@@ -0,0 +1,573 @@
+//! Averages the main view over frames for the reference image based lighting, whose per pixel
+//! samples of the environment are noisy in any single frame. A compute node blends each new
+//! frame into a running mean of the frames since the camera or environment last changed, and
+//! writes it to an image the tonemapper and scopes read instead of the HDR target.
+//!
+//! Only added to the graph while either side of the main view uses `IblMode::Reference`,
+//! since the split sum approximation gives the same result every frame.
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, ExecutableState, Families, Family, FamilyId, Fence, MultiShot,
+        PendingState, Queue, SimultaneousUse, Submission, Submit,
+    },
+    factory::Factory,
+    frame::Frames,
+    graph::{
+        gfx_acquire_barriers, gfx_release_barriers, BufferAccess, BufferId, DynNode, GraphBuilder,
+        GraphContext, ImageAccess, ImageId, NodeBuffer, NodeBuildError, NodeBuilder, NodeId,
+        NodeImage,
+    },
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    resource::{
+        Buffer, BufferInfo, Escape, Filter, ImageView, ImageViewInfo, Sampler, SamplerDesc,
+        ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use std::mem::size_of;
+
+use crate::{
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{Aux, EnvironmentStorage, View},
+};
+
+lazy_static::lazy_static! {
+    static ref ACCUMULATE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/accumulate.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+}
+
+/// Matches both sizes of `accumulate.comp`.
+const GROUP_SIZE: u32 = 8;
+
+/// Format of the averaged image, matching the `rgba32f` qualifier of `accumulate.comp`.
+const ACCUMULATED_FORMAT: hal::format::Format = hal::format::Format::Rgba32Sfloat;
+
+/// Adds the node averaging `hdr` over frames into a new image of the same size, which is
+/// returned to be read in its place. The node runs after `dependency`, which draws `hdr`.
+pub fn add_accumulate_node<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, specs::World>,
+    description: &mut crate::graph_dump::GraphDescription,
+    hdr: ImageId,
+    width: u32,
+    height: u32,
+    family: FamilyId,
+    dependency: NodeId,
+) -> (NodeId, ImageId) {
+    let accumulated = description.create_image(
+        builder,
+        "accumulated",
+        hal::image::Kind::D2(width, height, 1, 1),
+        1,
+        ACCUMULATED_FORMAT,
+        None,
+    );
+    let node = description.add_node(
+        builder,
+        "accumulate",
+        AccumulateNodeBuilder {
+            hdr,
+            accumulated,
+            family,
+            dependencies: vec![dependency],
+        },
+    );
+    (node, accumulated)
+}
+
+/// Read by `accumulate.comp` from each frame's region of the args buffer.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct AccumulateArgs {
+    /// Weight of the new frame in the mean: 1 to start over, 0 to leave the mean as it is
+    weight: f32,
+    width: u32,
+    height: u32,
+    _pad: u32,
+}
+
+/// What the averaged frames have to agree on. The mean starts over when any of it changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AccumulationKey {
+    view_proj: [[f32; 4]; 4],
+    camera_pos: [f32; 3],
+    environment_generation: u64,
+    ibl_uniform: [f32; 2],
+    divider: u32,
+}
+
+#[derive(Debug)]
+struct AccumulateNodeBuilder {
+    hdr: ImageId,
+    accumulated: ImageId,
+    family: FamilyId,
+    dependencies: Vec<NodeId>,
+}
+
+#[derive(Debug)]
+struct AccumulateNode<B: hal::Backend> {
+    pool: CommandPool<B>,
+    /// One per frame in flight, each reading its frame's args
+    submits: Vec<Submit<B, SimultaneousUse>>,
+    buffers: Vec<
+        CommandBuffer<
+            B,
+            hal::queue::QueueType,
+            PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+        >,
+    >,
+    descriptor_pool: B::DescriptorPool,
+    set_layout: B::DescriptorSetLayout,
+    pipeline_layout: B::PipelineLayout,
+    pipeline: B::ComputePipeline,
+    sampler: Escape<Sampler<B>>,
+    hdr_view: Escape<ImageView<B>>,
+    accumulated_view: Escape<ImageView<B>>,
+    /// The `AccumulateArgs` of each frame, written before it runs
+    args: Escape<Buffer<B>>,
+    args_frame_size: u64,
+    /// The running mean of every pixel, kept between frames
+    means: Escape<Buffer<B>>,
+    width: u32,
+    height: u32,
+    key: Option<AccumulationKey>,
+    /// Frames in the mean so far
+    frames: u32,
+    /// Id of the node's buffers in `GpuMemoryStats`
+    memory_stats_id: usize,
+}
+
+impl<B> NodeBuilder<B, specs::World> for AccumulateNodeBuilder
+where
+    B: hal::Backend,
+{
+    fn family(&self, _factory: &mut Factory<B>, _families: &Families<B>) -> Option<FamilyId> {
+        Some(self.family)
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![
+            (
+                self.hdr,
+                ImageAccess {
+                    access: hal::image::Access::SHADER_READ,
+                    usage: hal::image::Usage::SAMPLED,
+                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                    stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+                },
+            ),
+            (
+                self.accumulated,
+                ImageAccess {
+                    access: hal::image::Access::SHADER_WRITE,
+                    usage: hal::image::Usage::STORAGE,
+                    layout: hal::image::Layout::General,
+                    stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+                },
+            ),
+        ]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        aux: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, specs::World>>, NodeBuildError> {
+        use hal::adapter::PhysicalDevice;
+
+        assert!(buffers.is_empty());
+        assert_eq!(images.len(), 2);
+
+        let frames = aux.read_resource::<Aux>().frames;
+
+        let hdr = ctx
+            .get_image(images[0].id)
+            .expect("Accumulation HDR image missing");
+        let extent = hdr.kind().extent();
+        let hdr_view = factory
+            .create_image_view(
+                hdr.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format: hdr.format(),
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[0].range.clone(),
+                },
+            )
+            .expect("Could not create accumulation input image view");
+        let accumulated = ctx
+            .get_image(images[1].id)
+            .expect("Accumulated image missing");
+        let accumulated_view = factory
+            .create_image_view(
+                accumulated.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format: accumulated.format(),
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[1].range.clone(),
+                },
+            )
+            .expect("Could not create accumulated image view");
+        let sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+            .unwrap();
+
+        let align = factory
+            .physical()
+            .limits()
+            .min_storage_buffer_offset_alignment;
+        let args_size = size_of::<AccumulateArgs>() as u64;
+        let args_frame_size = ((args_size - 1) / align + 1) * align;
+        let args = factory
+            .create_buffer(
+                BufferInfo {
+                    size: args_frame_size * frames as u64,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+        let means = factory
+            .create_buffer(
+                BufferInfo {
+                    size: (extent.width * extent.height) as u64 * size_of::<[f32; 4]>() as u64,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                MemoryUsageValue::Data,
+            )
+            .unwrap();
+
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory
+                .device()
+                .create_descriptor_set_layout(
+                    vec![
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 0,
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 1,
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 2,
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 3,
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 4,
+                            ty: hal::pso::DescriptorType::StorageImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                    ],
+                    std::iter::empty::<B::Sampler>(),
+                )
+                .unwrap();
+            let pipeline_layout = factory
+                .device()
+                .create_pipeline_layout(
+                    Some(&set_layout),
+                    std::iter::empty::<(hal::pso::ShaderStageFlags, std::ops::Range<u32>)>(),
+                )
+                .unwrap();
+            (set_layout, pipeline_layout)
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    frames,
+                    vec![
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: frames,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: frames,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: frames * 2,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::StorageImage,
+                            count: frames,
+                        },
+                    ],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let pipeline = unsafe { create_pipeline(factory, &pipeline_layout, &*ACCUMULATE).unwrap() };
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+        let mut submits = Vec::with_capacity(frames);
+        let mut command_buffers = Vec::with_capacity(frames);
+        for index in 0..frames {
+            let set = unsafe {
+                let set = descriptor_pool.allocate_set(&set_layout).unwrap();
+                let frame_offset = args_frame_size * index as u64;
+                factory.write_descriptor_sets(vec![
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 0,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Sampler(sampler.raw())),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 1,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(
+                            hdr_view.raw(),
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 2,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            args.raw(),
+                            Some(frame_offset)..Some(frame_offset + args_size),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 3,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(means.raw(), None..None)),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 4,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(
+                            accumulated_view.raw(),
+                            hal::image::Layout::General,
+                        )),
+                    },
+                ]);
+                set
+            };
+
+            let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+            let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+            let mut encoder = buf_recording.encoder();
+            unsafe {
+                super::begin_marker(&mut encoder, "Reference IBL accumulation");
+                let (stages, barriers) = gfx_acquire_barriers(ctx, buffers.iter(), images.iter());
+                if !barriers.is_empty() {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+                }
+
+                encoder.bind_compute_pipeline(&pipeline);
+                encoder.bind_compute_descriptor_sets(
+                    &pipeline_layout,
+                    0,
+                    Some(&set),
+                    std::iter::empty(),
+                );
+                encoder.dispatch([
+                    (extent.width + GROUP_SIZE - 1) / GROUP_SIZE,
+                    (extent.height + GROUP_SIZE - 1) / GROUP_SIZE,
+                    1,
+                ]);
+
+                let (stages, barriers) = gfx_release_barriers(ctx, buffers.iter(), images.iter());
+                if !barriers.is_empty() {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+                }
+                super::end_marker(&mut encoder);
+            }
+            let (submit, buffer) = buf_recording.finish().submit();
+            submits.push(submit);
+            command_buffers.push(buffer);
+        }
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&args);
+        memory.add_buffer(&means);
+        let memory_stats_id = aux
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline("Reference IBL accumulation".to_owned(), memory);
+
+        Ok(Box::new(AccumulateNode {
+            pool,
+            submits,
+            buffers: command_buffers,
+            descriptor_pool,
+            set_layout,
+            pipeline_layout,
+            pipeline,
+            sampler,
+            hdr_view,
+            accumulated_view,
+            args,
+            args_frame_size,
+            means,
+            width: extent.width,
+            height: extent.height,
+            key: None,
+            frames: 0,
+            memory_stats_id,
+        }))
+    }
+}
+
+impl<B: hal::Backend> AccumulateNode<B> {
+    /// Writes the args of a frame, starting the mean over if what the frame shows changed
+    /// since the last one, and counts the frame in `Aux::ibl_reference_frames`.
+    unsafe fn write_args(&mut self, factory: &Factory<B>, index: usize, world: &specs::World) {
+        let camera = super::camera_args(View::Main, world);
+        let mut aux = world.write_resource::<Aux>();
+        let ibl_uniform = aux.ibl_uniform();
+        let key = AccumulationKey {
+            view_proj: (camera.proj * camera.view).into(),
+            camera_pos: camera.camera_pos.coords.into(),
+            environment_generation: world.read_resource::<EnvironmentStorage<B>>().generation,
+            ibl_uniform: [ibl_uniform[0], ibl_uniform[1]],
+            divider: aux.split_view.divider_pixel(),
+        };
+        if self.key != Some(key) {
+            self.key = Some(key);
+            self.frames = 0;
+        }
+
+        let weight = if self.frames < aux.ibl_reference.max_frames.max(1) {
+            self.frames += 1;
+            1.0 / self.frames as f32
+        } else {
+            0.0
+        };
+        aux.ibl_reference_frames = self.frames;
+
+        factory
+            .upload_visible_buffer(
+                &mut self.args,
+                self.args_frame_size * index as u64,
+                &[AccumulateArgs {
+                    weight,
+                    width: self.width,
+                    height: self.height,
+                    _pad: 0,
+                }],
+            )
+            .unwrap();
+    }
+}
+
+impl<B> DynNode<B, specs::World> for AccumulateNode<B>
+where
+    B: hal::Backend,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        aux: &specs::World,
+        frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("AccumulateNode::run");
+        let index = frames.next().index() as usize % self.submits.len();
+        self.write_args(factory, index, aux);
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submits[index]))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, aux: &specs::World) {
+        aux.write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
+        // Nothing reads the frame count once the mean is gone
+        aux.write_resource::<Aux>().ibl_reference_frames = 0;
+        drop(self.submits);
+        self.pool.free_buffers(
+            self.buffers
+                .into_iter()
+                .map(|buffer| buffer.mark_complete()),
+        );
+        factory.destroy_command_pool(self.pool);
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+        factory.device().destroy_compute_pipeline(self.pipeline);
+        factory
+            .device()
+            .destroy_pipeline_layout(self.pipeline_layout);
+        factory
+            .device()
+            .destroy_descriptor_set_layout(self.set_layout);
+    }
+}
+
+unsafe fn create_pipeline<B: hal::Backend>(
+    factory: &Factory<B>,
+    layout: &B::PipelineLayout,
+    shader: &PathBufShaderInfo,
+) -> Result<B::ComputePipeline, failure::Error> {
+    let module = factory.device().create_shader_module(&shader.spirv()?)?;
+    let pipeline = factory.device().create_compute_pipeline(
+        &hal::pso::ComputePipelineDesc::new(
+            hal::pso::EntryPoint {
+                entry: "main",
+                module: &module,
+                specialization: hal::pso::Specialization::default(),
+            },
+            layout,
+        ),
+        None,
+    );
+    factory.device().destroy_shader_module(module);
+    Ok(pipeline?)
+}
@@ -0,0 +1,527 @@
+//! Camera-facing sprites drawn over the meshes: alpha blended markers for entities with a
+//! `components::Billboard`, and additive particles from the `systems::ParticleStorage`.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::{Factory, ImageState},
+    graph::{render::*, GraphContext, NodeBuffer, NodeImage},
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    resource::{Buffer, BufferInfo, DescriptorSetLayout, Escape, Handle},
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+    texture::{pixel::Rgba8Srgb, Texture, TextureBuilder},
+};
+
+use rendy::hal;
+
+use std::mem::size_of;
+
+use crate::{
+    frame_arena::FrameArena,
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{Aux, View},
+    render_frame::{RenderBillboard, RenderFrames},
+    systems,
+};
+
+/// The most billboards drawn in one frame. The ones furthest from the camera are dropped.
+pub const MAX_BILLBOARDS: usize = 1024;
+
+/// Width and height of the built in sprite texture
+const SPRITE_SIZE: u32 = 64;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct UniformArgs {
+    proj: nalgebra::Matrix4<f32>,
+    view: nalgebra::Matrix4<f32>,
+}
+
+/// Per instance vertex data
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct BillboardInstance {
+    /// xyz: world space position, w: size
+    position_size: [f32; 4],
+    color: [f32; 4],
+}
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/billboard.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/billboard.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*FRAGMENT).unwrap();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Settings {
+    align: u64,
+    max_instances: usize,
+}
+
+impl Settings {
+    const UNIFORM_SIZE: u64 = size_of::<UniformArgs>() as u64;
+
+    fn from_world<B: hal::Backend>(world: &specs::World, sprites: Sprites) -> Self {
+        let aux = world.read_resource::<Aux>();
+        Self {
+            align: aux.align,
+            max_instances: sprites.max_instances(),
+        }
+    }
+
+    #[inline]
+    fn buffer_frame_size(&self) -> u64 {
+        ((Self::UNIFORM_SIZE - 1) / self.align + 1) * self.align
+    }
+
+    #[inline]
+    fn instances_frame_size(&self) -> u64 {
+        (size_of::<BillboardInstance>() * self.max_instances) as u64
+    }
+}
+
+/// Which sprites a pipeline draws
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sprites {
+    /// `components::Billboard`s, alpha blended back to front
+    Billboards,
+    /// Live particles, blended additively so they need no sorting
+    Particles,
+}
+
+impl Sprites {
+    fn max_instances(self) -> usize {
+        match self {
+            Sprites::Billboards => MAX_BILLBOARDS,
+            Sprites::Particles => systems::MAX_PARTICLES,
+        }
+    }
+}
+
+/// A soft white disc with a bright core, which reads well as a light and can be
+/// tinted per billboard.
+fn sprite_pixels() -> Vec<Rgba8Srgb> {
+    let half = SPRITE_SIZE as f32 / 2.0;
+    (0..SPRITE_SIZE * SPRITE_SIZE)
+        .map(|i| {
+            let x = (i % SPRITE_SIZE) as f32 + 0.5 - half;
+            let y = (i / SPRITE_SIZE) as f32 + 0.5 - half;
+            let r = (x * x + y * y).sqrt() / half;
+            let alpha = (1.0 - r).max(0.0).min(1.0).powf(0.5);
+            let core = (1.0 - r * 2.0).max(0.0);
+            let value = (0.75 + 0.25 * core) * 255.0;
+            Rgba8Srgb {
+                repr: [value as u8, value as u8, value as u8, (alpha * 255.0) as u8],
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct PipelineDesc {
+    sprites: Sprites,
+    reversed_z: bool,
+    view: View,
+}
+
+impl PipelineDesc {
+    pub fn billboards() -> Self {
+        PipelineDesc {
+            sprites: Sprites::Billboards,
+            reversed_z: false,
+            view: View::Main,
+        }
+    }
+
+    pub fn particles() -> Self {
+        PipelineDesc {
+            sprites: Sprites::Particles,
+            reversed_z: false,
+            view: View::Main,
+        }
+    }
+
+    pub fn with_reversed_z(mut self, reversed_z: bool) -> Self {
+        self.reversed_z = reversed_z;
+        self
+    }
+
+    /// Draw from the camera of another view than the main window's.
+    pub fn with_view(mut self, view: View) -> Self {
+        self.view = view;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    sprites: Sprites,
+    sets: Vec<B::DescriptorSet>,
+    settings: Settings,
+    pool: B::DescriptorPool,
+    buffer: Escape<Buffer<B>>,
+    instance_buffer: Escape<Buffer<B>>,
+    instance_counts: Vec<u32>,
+    sprite: Texture<B>,
+    view: View,
+    memory_stats_id: usize,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn vertices(
+        &self,
+    ) -> Vec<(
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    )> {
+        vec![(
+            vec![
+                hal::pso::Element {
+                    format: hal::format::Format::Rgba32Sfloat,
+                    offset: 0,
+                },
+                hal::pso::Element {
+                    format: hal::format::Format::Rgba32Sfloat,
+                    offset: 16,
+                },
+            ],
+            size_of::<BillboardInstance>() as hal::pso::ElemStride,
+            hal::pso::VertexInputRate::Instance(1),
+        )]
+    }
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        let blend = match self.sprites {
+            Sprites::Billboards => hal::pso::BlendState::PREMULTIPLIED_ALPHA,
+            Sprites::Particles => hal::pso::BlendState::ADD,
+        };
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::ALL,
+            blend: Some(blend),
+        }]
+    }
+
+    /// Hidden behind meshes, but not depth tested against each other
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        Some(super::depth_test(self.reversed_z, false))
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _aux: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        Layout {
+            sets: vec![SetLayout {
+                bindings: vec![
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 0,
+                        ty: hal::pso::DescriptorType::UniformBuffer,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                        immutable_samplers: false,
+                    },
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 1,
+                        ty: hal::pso::DescriptorType::CombinedImageSampler,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                ],
+            }],
+            push_constants: Vec::new(),
+        }
+    }
+
+    fn build<'a>(
+        self,
+        _ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert!(images.is_empty());
+        assert!(set_layouts.len() == 1);
+
+        let aux = world.read_resource::<Aux>();
+        let frames = aux.frames;
+
+        let sprite = TextureBuilder::new()
+            .with_kind(rendy::resource::Kind::D2(SPRITE_SIZE, SPRITE_SIZE, 1, 1))
+            .with_view_kind(rendy::resource::ViewKind::D2)
+            .with_data_width(SPRITE_SIZE)
+            .with_data_height(SPRITE_SIZE)
+            .with_data(sprite_pixels())
+            .build(
+                ImageState {
+                    queue,
+                    stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                    access: hal::image::Access::SHADER_READ,
+                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                },
+                factory,
+            )
+            .unwrap();
+
+        let mut pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    frames,
+                    vec![
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::UniformBuffer,
+                            count: frames,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::CombinedImageSampler,
+                            count: frames,
+                        },
+                    ],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let settings = Settings::from_world::<B>(world, self.sprites);
+
+        let buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.buffer_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::UNIFORM,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+
+        let instance_buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.instances_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::VERTEX,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+
+        let mut sets = Vec::new();
+        for frame in 0..frames {
+            sets.push(unsafe {
+                let set = pool.allocate_set(&set_layouts[0].raw()).unwrap();
+                factory.write_descriptor_sets(vec![
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 0,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            buffer.raw(),
+                            Some(settings.buffer_frame_size() * frame as u64)
+                                ..Some(settings.buffer_frame_size() * (frame + 1) as u64),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 1,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::CombinedImageSampler(
+                            sprite.view().raw(),
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                            sprite.sampler().raw(),
+                        )),
+                    },
+                ]);
+                set
+            });
+        }
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&buffer);
+        memory.add_buffer(&instance_buffer);
+        memory.add_image(sprite.image());
+        let memory_stats_id = world
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline(format!("{:?}", self.sprites), memory);
+
+        Ok(Pipeline {
+            sprites: self.sprites,
+            sets,
+            settings,
+            pool,
+            buffer,
+            instance_buffer,
+            instance_counts: vec![0; frames],
+            sprite,
+            view: self.view,
+            memory_stats_id,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        index: usize,
+        world: &specs::World,
+    ) -> PrepareResult {
+        let _scope = crate::profile::scope("BillboardPipeline::prepare");
+
+        let camera_args = super::camera_args(self.view, world);
+        let arena = world.read_resource::<FrameArena>();
+
+        let instances: &[BillboardInstance] = match self.sprites {
+            Sprites::Billboards => {
+                let frames = world.read_resource::<RenderFrames>();
+                let instances = arena.alloc_iter(frames.front().billboards.iter().map(
+                    |RenderBillboard {
+                         position,
+                         billboard,
+                     }| BillboardInstance {
+                        position_size: [position.x, position.y, position.z, billboard.size],
+                        color: billboard.color,
+                    },
+                ));
+
+                // Blended back to front, since they don't write depth
+                let distance = |instance: &BillboardInstance| {
+                    let p = instance.position_size;
+                    nalgebra::distance_squared(
+                        &camera_args.camera_pos,
+                        &nalgebra::Point3::new(p[0], p[1], p[2]),
+                    )
+                };
+                instances.sort_by(|a, b| {
+                    distance(b)
+                        .partial_cmp(&distance(a))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                let first = instances.len().saturating_sub(MAX_BILLBOARDS);
+                &instances[first..]
+            }
+            Sprites::Particles => arena.alloc_iter(
+                world
+                    .read_resource::<systems::ParticleStorage>()
+                    .0
+                    .iter()
+                    .take(systems::MAX_PARTICLES)
+                    .map(|particle| BillboardInstance {
+                        position_size: [
+                            particle.position.x,
+                            particle.position.y,
+                            particle.position.z,
+                            particle.size,
+                        ],
+                        color: particle.color(),
+                    }),
+            ),
+        };
+
+        self.instance_counts[index] = instances.len() as u32;
+
+        unsafe {
+            factory
+                .upload_visible_buffer(
+                    &mut self.buffer,
+                    self.settings.buffer_frame_size() * index as u64,
+                    &[UniformArgs {
+                        proj: camera_args.proj,
+                        view: camera_args.view,
+                    }],
+                )
+                .unwrap();
+            if !instances.is_empty() {
+                factory
+                    .upload_visible_buffer(
+                        &mut self.instance_buffer,
+                        self.settings.instances_frame_size() * index as u64,
+                        instances,
+                    )
+                    .unwrap();
+            }
+        };
+
+        // The number of sprites can change every frame
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        index: usize,
+        _world: &specs::World,
+    ) {
+        let _scope = crate::profile::scope("BillboardPipeline::draw");
+        let count = self.instance_counts[index];
+        if count == 0 {
+            return;
+        }
+
+        unsafe {
+            super::begin_marker(
+                &mut encoder,
+                &format!("{:?}: {} instances", self.sprites, count),
+            );
+            encoder.bind_graphics_descriptor_sets(
+                layout,
+                0,
+                Some(&self.sets[index]),
+                std::iter::empty(),
+            );
+            encoder.bind_vertex_buffers(
+                0,
+                std::iter::once((
+                    self.instance_buffer.raw(),
+                    self.settings.instances_frame_size() * index as u64,
+                )),
+            );
+            encoder.draw(0..6, 0..count);
+            super::end_marker(&mut encoder);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, world: &specs::World) {
+        world
+            .write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
+        unsafe {
+            self.pool.reset();
+            factory.destroy_descriptor_pool(self.pool);
+        }
+    }
+}
@@ -0,0 +1,784 @@
+//! Rendering of the scene into the six faces of a cube from any position, read back to host
+//! memory. Used to capture equirectangular panoramas and to bake light probes. The same graph
+//! with a single face captures HDR screenshots from the active camera.
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, ExecutableState, Families, Family, FamilyId, Fence, MultiShot,
+        PendingState, Queue, QueueId, SimultaneousUse, Submission, Submit,
+    },
+    factory::{Factory, ImageState},
+    frame::Frames,
+    graph::{
+        gfx_acquire_barriers, gfx_release_barriers, BufferAccess, BufferId, DynNode, Graph,
+        GraphBuilder, GraphContext, ImageAccess, ImageId, NodeBuffer, NodeBuildError, NodeBuilder,
+        NodeId, NodeImage,
+    },
+    resource::{Handle, Image},
+    texture::Texture,
+};
+
+use rendy::hal;
+
+use derivative::Derivative;
+use std::f32::consts::PI;
+
+use crate::{
+    components,
+    node::env_preprocess::debug::{read_texture, Subresource},
+    node::pbr::{CameraArgs, CubeCameras},
+};
+
+/// The forward and up directions of the faces, in the order of the cube map layers.
+const FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+];
+
+/// The forward and up directions of a face. Its images have up towards row 0, and right
+/// along `forward.cross(&up)`.
+pub fn face_basis(face: usize) -> (nalgebra::Vector3<f32>, nalgebra::Vector3<f32>) {
+    let (forward, up) = FACES[face];
+    (forward.into(), up.into())
+}
+
+/// The camera looking out of a face of the cube, from `position`. Depth settings are taken
+/// from `camera`, the field of view covers exactly one face.
+pub fn face_camera(
+    face: usize,
+    position: nalgebra::Point3<f32>,
+    camera: &components::Camera,
+) -> CameraArgs {
+    let (forward, up) = face_basis(face);
+    let face_camera = components::Camera {
+        fov: PI / 2.0,
+        aspect: 1.0,
+        ..*camera
+    };
+    CameraArgs {
+        proj: face_camera.projection(),
+        view: nalgebra::Matrix4::look_at_rh(&position, &(position + forward), &up),
+        camera_pos: position,
+    }
+}
+
+/// A graph drawing the scene into the faces of a cube, kept to draw several cubes in a row, or
+/// into a single view. It is only up to date with the scene it was built with, so it should
+/// not outlive a single batch of renders.
+pub struct CubeRenderer<B: hal::Backend> {
+    graph: Graph<B, specs::World>,
+    cube: Texture<B>,
+    queue: QueueId,
+}
+
+impl<B: hal::Backend> CubeRenderer<B> {
+    /// Drawn with the pipelines, depth convention and target format of the main graph's
+    /// `settings`.
+    pub fn new(
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        queue: QueueId,
+        world: &specs::World,
+        settings: &crate::PbrGraphSettings,
+        resolution: u32,
+    ) -> Result<Self, failure::Error> {
+        Self::build(
+            factory,
+            families,
+            queue,
+            world,
+            settings,
+            (resolution, resolution, 6),
+        )
+    }
+
+    /// A renderer of a single view of `width` by `height`, drawn with `render_view`.
+    pub fn single_view(
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        queue: QueueId,
+        world: &specs::World,
+        settings: &crate::PbrGraphSettings,
+        (width, height): (u32, u32),
+    ) -> Result<Self, failure::Error> {
+        Self::build(
+            factory,
+            families,
+            queue,
+            world,
+            settings,
+            (width, height, 1),
+        )
+    }
+
+    /// Drawn from the cameras of `CubeCameras`, one for each of `faces`
+    fn build(
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        queue: QueueId,
+        world: &specs::World,
+        settings: &crate::PbrGraphSettings,
+        (width, height, faces): (u32, u32, u16),
+    ) -> Result<Self, failure::Error> {
+        let format = settings.color_target_format;
+        let reversed_z = settings.reversed_z;
+        let cube = rendy::texture::TextureBuilder::new()
+            .with_kind(rendy::resource::Kind::D2(width, height, faces, 1))
+            .with_view_kind(if faces == 6 {
+                rendy::resource::ViewKind::Cube
+            } else {
+                rendy::resource::ViewKind::D2
+            })
+            .with_data_width(width)
+            .with_data_height(height)
+            .with_raw_data(
+                vec![
+                    0u8;
+                    (width * height * faces as u32) as usize * format.surface_desc().bits as usize
+                        / 8
+                ],
+                format,
+            )
+            .build(
+                ImageState {
+                    queue,
+                    stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                    access: hal::image::Access::SHADER_READ,
+                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                },
+                factory,
+            )?;
+
+        let mut graph_builder = GraphBuilder::<B, specs::World>::new();
+        // Lightmaps are baked before the main graph first draws the shadow map, so the
+        // cascades are drawn here as well. They stay fit to the scene's camera, not the cube.
+        let shadow_nodes = super::shadow::add_shadow_nodes(
+            &mut graph_builder,
+            &mut crate::graph_dump::GraphDescription::new("cube_capture"),
+            &*world.read_resource::<super::shadow::ShadowMap<B>>(),
+            queue.family,
+        );
+        for face in 0..faces as usize {
+            let hdr = graph_builder.create_image(
+                hal::image::Kind::D2(width, height, 1, 1),
+                1,
+                format,
+                Some(hal::command::ClearValue {
+                    color: hal::command::ClearColor {
+                        float32: [0.0, 0.0, 0.0, 1.0],
+                    },
+                }),
+            );
+            let depth = graph_builder.create_image(
+                hal::image::Kind::D2(width, height, 1, 1),
+                1,
+                hal::format::Format::D32Sfloat,
+                Some(hal::command::ClearValue {
+                    depth_stencil: hal::command::ClearDepthStencil {
+                        depth: if reversed_z { 0.0 } else { 1.0 },
+                        stencil: 0,
+                    },
+                }),
+            );
+            let mut face_subpass = super::scene_subpass(
+                factory,
+                &settings.shader_variants,
+                settings.num_materials,
+                reversed_z,
+                super::View::CubeFace(face),
+                None,
+            );
+            for node in shadow_nodes.iter() {
+                face_subpass = face_subpass.with_dependency(*node);
+            }
+            let face_pass = graph_builder.add_node(
+                face_subpass
+                    .with_color(hdr)
+                    .with_depth_stencil(depth)
+                    .into_pass(),
+            );
+            graph_builder.add_node(
+                CopyToLayer::builder(hdr, cube.image().clone(), face as u16, queue.family)
+                    .with_dependency(face_pass),
+            );
+        }
+
+        let graph = graph_builder
+            .with_frames_in_flight(crate::FRAMES_IN_FLIGHT)
+            .build(factory, families, world)?;
+
+        Ok(CubeRenderer { graph, cube, queue })
+    }
+
+    /// Draws the scene from `position` with the depth settings of `camera`, and reads the
+    /// faces back. Waits for the device to be idle, both before and after drawing.
+    pub fn render(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        world: &mut specs::World,
+        position: nalgebra::Point3<f32>,
+        camera: &components::Camera,
+    ) -> Result<Vec<Subresource>, failure::Error> {
+        world.add_resource(CubeCameras(
+            (0..6)
+                .map(|face| face_camera(face, position, camera))
+                .collect(),
+        ));
+        factory.wait_idle()?;
+        self.graph.run(factory, families, world);
+        factory.wait_idle()?;
+        read_texture(factory, families, self.queue, self.cube.image())
+    }
+
+    /// Draws the scene from `camera` into the single view, and reads it back. Waits for the
+    /// device to be idle, both before and after drawing.
+    pub fn render_view(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        world: &mut specs::World,
+        camera: CameraArgs,
+    ) -> Result<Subresource, failure::Error> {
+        world.add_resource(CubeCameras(vec![camera]));
+        factory.wait_idle()?;
+        self.graph.run(factory, families, world);
+        factory.wait_idle()?;
+        Ok(read_texture(factory, families, self.queue, self.cube.image())?.remove(0))
+    }
+
+    pub fn dispose(self, factory: &mut Factory<B>, world: &specs::World) {
+        self.graph.dispose(factory, world);
+    }
+}
+
+/// Resamples the six faces of a cube map, in the layer order of `face_camera`, to an
+/// equirectangular image twice as wide as it is high, with the same mapping as the
+/// environment maps loaded from equirectangular images.
+pub fn to_equirectangular(faces: &[Subresource], height: u32) -> Vec<[f32; 4]> {
+    let width = height * 2;
+    let mut texels = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        let latitude = ((y as f32 + 0.5) / height as f32 - 0.5) * PI;
+        for x in 0..width {
+            let longitude = ((x as f32 + 0.5) / width as f32 - 0.5) * 2.0 * PI;
+            let dir = nalgebra::Vector3::new(
+                latitude.cos() * longitude.sin(),
+                -latitude.sin(),
+                latitude.cos() * longitude.cos(),
+            );
+            texels.push(sample_cube(faces, &dir));
+        }
+    }
+    texels
+}
+
+/// Bilinearly samples the faces of a cube map read back by `CubeRenderer` in direction `dir`.
+pub fn sample_cube(faces: &[Subresource], dir: &nalgebra::Vector3<f32>) -> [f32; 4] {
+    let face = (0..6)
+        .max_by(|a, b| {
+            let along = |face| face_basis(face).0.dot(dir);
+            along(*a).partial_cmp(&along(*b)).unwrap()
+        })
+        .unwrap();
+    let (forward, up) = face_basis(face);
+    let right = forward.cross(&up);
+    let depth = forward.dot(dir);
+    let (a, b) = (right.dot(dir) / depth, up.dot(dir) / depth);
+
+    // Rows go from the top of the face down, so up is towards row 0
+    let face = &faces[face];
+    let size = face.width as f32;
+    let px = ((a + 1.0) * 0.5 * size - 0.5).max(0.0).min(size - 1.0);
+    let py = ((1.0 - b) * 0.5 * size - 0.5).max(0.0).min(size - 1.0);
+    let (x0, y0) = (px.floor() as u32, py.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(face.width - 1), (y0 + 1).min(face.width - 1));
+    let (fx, fy) = (px.fract(), py.fract());
+
+    let texel = |x: u32, y: u32| face.texels[(y * face.width + x) as usize];
+    let mut result = [0.0; 4];
+    for c in 0..4 {
+        let top = texel(x0, y0)[c] * (1.0 - fx) + texel(x1, y0)[c] * fx;
+        let bottom = texel(x0, y1)[c] * (1.0 - fx) + texel(x1, y1)[c] * fx;
+        result[c] = top * (1.0 - fy) + bottom * fy;
+    }
+    result
+}
+
+/// Copies a rendered image into one layer of another, like a face into a cube map or a shadow
+/// cascade's depth into the shadow map. The layer is in `ShaderReadOnlyOptimal` before and
+/// after, ready to be sampled or read back on the same queue.
+#[derive(Derivative)]
+#[derivative(Debug(bound = ""))]
+pub struct CopyToLayer<B: hal::Backend, T: ?Sized> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+    #[derivative(Debug = "ignore")]
+    condition: Option<CopyCondition<T>>,
+}
+
+/// Decides every frame whether a `CopyToLayer` copies, from the graph's aux data.
+pub type CopyCondition<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+
+impl<B: hal::Backend, T: ?Sized> CopyToLayer<B, T> {
+    /// `target` must only ever be used by `family`.
+    pub fn builder(
+        input: ImageId,
+        target: Handle<Image<B>>,
+        layer: u16,
+        family: FamilyId,
+    ) -> CopyToLayerBuilder<B, T> {
+        CopyToLayerBuilder {
+            input,
+            target,
+            layer,
+            family,
+            dependencies: vec![],
+            condition: None,
+        }
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug(bound = ""))]
+pub struct CopyToLayerBuilder<B: hal::Backend, T: ?Sized> {
+    input: ImageId,
+    target: Handle<Image<B>>,
+    layer: u16,
+    family: FamilyId,
+    dependencies: Vec<NodeId>,
+    #[derivative(Debug = "ignore")]
+    condition: Option<CopyCondition<T>>,
+}
+
+impl<B: hal::Backend, T: ?Sized> CopyToLayerBuilder<B, T> {
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn with_dependency(mut self, dependency: NodeId) -> Self {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    /// Only copy in the frames `condition` returns true for, leaving the layer as it was in
+    /// the others.
+    pub fn with_condition(
+        mut self,
+        condition: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.condition = Some(Box::new(condition));
+        self
+    }
+}
+
+impl<B, T> NodeBuilder<B, T> for CopyToLayerBuilder<B, T>
+where
+    B: hal::Backend,
+    T: ?Sized,
+{
+    fn family(&self, _factory: &mut Factory<B>, _families: &Families<B>) -> Option<FamilyId> {
+        Some(self.family)
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![(
+            self.input,
+            ImageAccess {
+                access: hal::image::Access::TRANSFER_READ,
+                layout: hal::image::Layout::TransferSrcOptimal,
+                usage: hal::image::Usage::TRANSFER_SRC,
+                stages: hal::pso::PipelineStage::TRANSFER,
+            },
+        )]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        _aux: &T,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, T>>, NodeBuildError> {
+        assert_eq!(buffers.len(), 0);
+        assert_eq!(images.len(), 1);
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+
+        let layers = self.layer..self.layer + 1;
+        let aspects = self.target.format().surface_desc().aspects;
+        {
+            let (mut stages, mut barriers) = gfx_acquire_barriers(ctx, None, images.iter());
+            stages.start |= hal::pso::PipelineStage::FRAGMENT_SHADER;
+            stages.end |= hal::pso::PipelineStage::TRANSFER;
+            barriers.push(hal::memory::Barrier::Image {
+                states: (
+                    hal::image::Access::SHADER_READ,
+                    hal::image::Layout::ShaderReadOnlyOptimal,
+                )
+                    ..(
+                        hal::image::Access::TRANSFER_WRITE,
+                        hal::image::Layout::TransferDstOptimal,
+                    ),
+                families: None,
+                target: self.target.raw(),
+                range: hal::image::SubresourceRange {
+                    aspects,
+                    levels: 0..1,
+                    layers: layers.clone(),
+                },
+            });
+            unsafe {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+            };
+        }
+
+        let image = ctx.get_image(images[0].id).unwrap();
+        unsafe {
+            encoder.copy_image(
+                image.raw(),
+                images[0].layout,
+                self.target.raw(),
+                hal::image::Layout::TransferDstOptimal,
+                Some(hal::command::ImageCopy {
+                    src_subresource: hal::image::SubresourceLayers {
+                        aspects,
+                        level: 0,
+                        layers: 0..1,
+                    },
+                    src_offset: hal::image::Offset::ZERO,
+                    dst_subresource: hal::image::SubresourceLayers {
+                        aspects,
+                        level: 0,
+                        layers: layers.clone(),
+                    },
+                    dst_offset: hal::image::Offset::ZERO,
+                    extent: hal::image::Extent {
+                        width: image.kind().extent().width,
+                        height: image.kind().extent().height,
+                        depth: 1,
+                    },
+                }),
+            );
+        }
+
+        {
+            let (mut stages, mut barriers) = gfx_release_barriers(ctx, None, images.iter());
+            stages.start |= hal::pso::PipelineStage::TRANSFER;
+            stages.end |= hal::pso::PipelineStage::FRAGMENT_SHADER;
+            barriers.push(hal::memory::Barrier::Image {
+                states: (
+                    hal::image::Access::TRANSFER_WRITE,
+                    hal::image::Layout::TransferDstOptimal,
+                )
+                    ..(
+                        hal::image::Access::SHADER_READ,
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    ),
+                families: None,
+                target: self.target.raw(),
+                range: hal::image::SubresourceRange {
+                    aspects,
+                    levels: 0..1,
+                    layers,
+                },
+            });
+            unsafe {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+            };
+        }
+
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        Ok(Box::new(CopyToLayer {
+            pool,
+            submit,
+            buffer,
+            condition: self.condition,
+        }))
+    }
+}
+
+impl<B, T> DynNode<B, T> for CopyToLayer<B, T>
+where
+    B: hal::Backend,
+    T: ?Sized,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        aux: &T,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("CopyToLayer::run");
+        let copy = self
+            .condition
+            .as_ref()
+            .map_or(true, |condition| condition(aux));
+        // Skipped copies still wait and signal for the nodes around them
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submit).filter(|_| copy))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, _aux: &T) {
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+    }
+}
+
+/// Copies one layer of an image into a rendered image, the reverse of `CopyToLayer`, like a
+/// cached shadow cascade into the depth its moving casters are drawn over. The layer is in
+/// `ShaderReadOnlyOptimal` before and after.
+#[derive(Debug)]
+pub struct CopyFromLayer<B: hal::Backend> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+}
+
+impl<B: hal::Backend> CopyFromLayer<B> {
+    /// `source` must only ever be used by `family`.
+    pub fn builder(
+        source: Handle<Image<B>>,
+        layer: u16,
+        output: ImageId,
+        family: FamilyId,
+    ) -> CopyFromLayerBuilder<B> {
+        CopyFromLayerBuilder {
+            source,
+            layer,
+            output,
+            family,
+            dependencies: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CopyFromLayerBuilder<B: hal::Backend> {
+    source: Handle<Image<B>>,
+    layer: u16,
+    output: ImageId,
+    family: FamilyId,
+    dependencies: Vec<NodeId>,
+}
+
+impl<B: hal::Backend> CopyFromLayerBuilder<B> {
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn with_dependency(mut self, dependency: NodeId) -> Self {
+        self.dependencies.push(dependency);
+        self
+    }
+}
+
+impl<B, T> NodeBuilder<B, T> for CopyFromLayerBuilder<B>
+where
+    B: hal::Backend,
+    T: ?Sized,
+{
+    fn family(&self, _factory: &mut Factory<B>, _families: &Families<B>) -> Option<FamilyId> {
+        Some(self.family)
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![(
+            self.output,
+            ImageAccess {
+                access: hal::image::Access::TRANSFER_WRITE,
+                layout: hal::image::Layout::TransferDstOptimal,
+                usage: hal::image::Usage::TRANSFER_DST,
+                stages: hal::pso::PipelineStage::TRANSFER,
+            },
+        )]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        _aux: &T,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, T>>, NodeBuildError> {
+        assert_eq!(buffers.len(), 0);
+        assert_eq!(images.len(), 1);
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+
+        let layers = self.layer..self.layer + 1;
+        let aspects = self.source.format().surface_desc().aspects;
+        let range = hal::image::SubresourceRange {
+            aspects,
+            levels: 0..1,
+            layers: layers.clone(),
+        };
+        {
+            let (mut stages, mut barriers) = gfx_acquire_barriers(ctx, None, images.iter());
+            stages.start |= hal::pso::PipelineStage::FRAGMENT_SHADER;
+            stages.end |= hal::pso::PipelineStage::TRANSFER;
+            barriers.push(hal::memory::Barrier::Image {
+                states: (
+                    hal::image::Access::SHADER_READ,
+                    hal::image::Layout::ShaderReadOnlyOptimal,
+                )
+                    ..(
+                        hal::image::Access::TRANSFER_READ,
+                        hal::image::Layout::TransferSrcOptimal,
+                    ),
+                families: None,
+                target: self.source.raw(),
+                range: range.clone(),
+            });
+            unsafe {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+            };
+        }
+
+        let image = ctx.get_image(images[0].id).unwrap();
+        unsafe {
+            encoder.copy_image(
+                self.source.raw(),
+                hal::image::Layout::TransferSrcOptimal,
+                image.raw(),
+                images[0].layout,
+                Some(hal::command::ImageCopy {
+                    src_subresource: hal::image::SubresourceLayers {
+                        aspects,
+                        level: 0,
+                        layers,
+                    },
+                    src_offset: hal::image::Offset::ZERO,
+                    dst_subresource: hal::image::SubresourceLayers {
+                        aspects,
+                        level: 0,
+                        layers: 0..1,
+                    },
+                    dst_offset: hal::image::Offset::ZERO,
+                    extent: hal::image::Extent {
+                        width: image.kind().extent().width,
+                        height: image.kind().extent().height,
+                        depth: 1,
+                    },
+                }),
+            );
+        }
+
+        {
+            let (mut stages, mut barriers) = gfx_release_barriers(ctx, None, images.iter());
+            stages.start |= hal::pso::PipelineStage::TRANSFER;
+            stages.end |= hal::pso::PipelineStage::FRAGMENT_SHADER;
+            barriers.push(hal::memory::Barrier::Image {
+                states: (
+                    hal::image::Access::TRANSFER_READ,
+                    hal::image::Layout::TransferSrcOptimal,
+                )
+                    ..(
+                        hal::image::Access::SHADER_READ,
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    ),
+                families: None,
+                target: self.source.raw(),
+                range,
+            });
+            unsafe {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+            };
+        }
+
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        Ok(Box::new(CopyFromLayer {
+            pool,
+            submit,
+            buffer,
+        }))
+    }
+}
+
+impl<B, T> DynNode<B, T> for CopyFromLayer<B>
+where
+    B: hal::Backend,
+    T: ?Sized,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        _aux: &T,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("CopyFromLayer::run");
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submit))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, _aux: &T) {
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+    }
+}
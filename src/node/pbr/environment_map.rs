@@ -12,18 +12,18 @@ use rendy::{
     hal::{device::Device, pso::DescriptorPool},
     memory::MemoryUsageValue,
     mesh::{AsVertex, Mesh, Position},
-    resource::{Buffer, BufferInfo, DescriptorSetLayout, Escape, Handle},
+    resource::{Buffer, BufferInfo, DescriptorSetLayout, Escape, Handle, ImageView, Sampler},
     shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
 };
 
 use rendy::hal;
 
 use crate::{
-    components,
-    node::pbr::{Aux, CameraArgs},
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{Aux, View},
 };
 
-#[derive(Derivative)]
+#[derive(Debug, Derivative, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
 #[derivative(Default)]
 pub enum CubeDisplay {
     #[derivative(Default)]
@@ -32,12 +32,71 @@ pub enum CubeDisplay {
     Specular,
 }
 
+impl CubeDisplay {
+    const ALL: [CubeDisplay; 3] = [
+        CubeDisplay::Environment,
+        CubeDisplay::Irradiance,
+        CubeDisplay::Specular,
+    ];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+
+    pub fn prev(self) -> Self {
+        Self::ALL[(self as usize + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+/// What is drawn behind the meshes
+#[derive(Debug, Derivative, Clone, Copy, serde::Deserialize)]
+#[derivative(Default)]
+pub enum Background {
+    /// The cube map selected by `CubeDisplay`
+    #[derivative(Default)]
+    Environment,
+    /// A single HDR color
+    Color([f32; 3]),
+    /// A vertical gradient by view direction, from `bottom` straight down to `top`
+    /// straight up
+    Gradient { top: [f32; 3], bottom: [f32; 3] },
+    /// Nothing, leaving the background transparent for compositing
+    Transparent,
+}
+
+/// Background parameters in the layout used by the shader.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct BackgroundUniform {
+    /// Top (or solid) color in `rgb`, the background mode in `w`
+    top: [f32; 4],
+    bottom: [f32; 4],
+}
+
+impl From<Background> for BackgroundUniform {
+    fn from(background: Background) -> Self {
+        let (mode, top, bottom) = match background {
+            Background::Environment => (0.0, [0.0; 3], [0.0; 3]),
+            Background::Color(color) => (1.0, color, color),
+            Background::Gradient { top, bottom } => (2.0, top, bottom),
+            Background::Transparent => (3.0, [0.0; 3], [0.0; 3]),
+        };
+        BackgroundUniform {
+            top: [top[0], top[1], top[2], mode],
+            bottom: [bottom[0], bottom[1], bottom[2], 0.0],
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct UniformArgs {
     proj: nalgebra::Matrix4<f32>,
     view: nalgebra::Matrix4<f32>,
     roughness: f32,
+    _pad: [f32; 3],
+    fog: super::FogUniform,
+    background: BackgroundUniform,
 }
 
 lazy_static::lazy_static! {
@@ -80,7 +139,30 @@ impl Settings {
 }
 
 #[derive(Debug, Default)]
-pub struct PipelineDesc;
+pub struct PipelineDesc {
+    reversed_z: bool,
+    view: View,
+    cube_display: Option<CubeDisplay>,
+}
+
+impl PipelineDesc {
+    pub fn with_reversed_z(mut self, reversed_z: bool) -> Self {
+        self.reversed_z = reversed_z;
+        self
+    }
+
+    /// Draw from the camera of another view than the main window's.
+    pub fn with_view(mut self, view: View) -> Self {
+        self.view = view;
+        self
+    }
+
+    /// Always show this cube map, rather than the one selected by `Aux::cube_display`.
+    pub fn with_cube_display(mut self, cube_display: CubeDisplay) -> Self {
+        self.cube_display = Some(cube_display);
+        self
+    }
+}
 
 pub struct Pipeline<B: hal::Backend> {
     cube: Mesh<B>,
@@ -88,10 +170,15 @@ pub struct Pipeline<B: hal::Backend> {
     env_cubemap_set: B::DescriptorSet,
     irradiance_cubemap_set: B::DescriptorSet,
     spec_cubemap_set: B::DescriptorSet,
+    /// Generation of the environment maps written to the cube map sets
+    env_generation: u64,
     settings: Settings,
     pool: B::DescriptorPool,
     #[allow(dead_code)]
     buffer: Escape<Buffer<B>>,
+    view: View,
+    memory_stats_id: usize,
+    cube_display: Option<CubeDisplay>,
 }
 
 impl<B: hal::Backend> std::fmt::Debug for Pipeline<B> {
@@ -116,6 +203,18 @@ where
         vec![Position::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex)]
     }
 
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        Some(super::depth_test(self.reversed_z, true))
+    }
+
+    /// Replaces rather than blends, so a transparent background clears the alpha channel
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::ALL,
+            blend: None,
+        }]
+    }
+
     fn load_shader_set(
         &self,
         factory: &mut Factory<B>,
@@ -247,82 +346,24 @@ where
             });
         }
 
-        let env_cubemap_set = unsafe {
-            let set = pool.allocate_set(&set_layouts[1].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(
-                        env_storage.env_cube.as_ref().unwrap().sampler().raw(),
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.env_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
-            set
-        };
-
-        let irradiance_cubemap_set = unsafe {
+        let mut allocate_cube_set = |sampler: &Sampler<B>, view: &ImageView<B>| unsafe {
             let set = pool.allocate_set(&set_layouts[1].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(
-                        env_storage
-                            .irradiance_cube
-                            .as_ref()
-                            .unwrap()
-                            .sampler()
-                            .raw(),
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.irradiance_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
-            set
-        };
-
-        let spec_cubemap_set = unsafe {
-            let set = pool.allocate_set(&set_layouts[1].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(
-                        env_storage.spec_cube.as_ref().unwrap().sampler().raw(),
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.spec_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
+            write_cube_set(factory, &set, sampler, view);
             set
         };
+        let env_cube = env_storage.env_cube.as_ref().unwrap();
+        let env_cubemap_set = allocate_cube_set(env_cube.sampler(), env_cube.view());
+        let irradiance_cube = env_storage.irradiance_cube.as_ref().unwrap();
+        let irradiance_cubemap_set =
+            allocate_cube_set(irradiance_cube.sampler(), irradiance_cube.view());
+        let spec_cube = env_storage.spec_cube.as_ref().unwrap();
+        let spec_cubemap_set = allocate_cube_set(spec_cube.sampler(), spec_cube.view());
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&buffer);
+        let memory_stats_id = world
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline("Environment map".to_owned(), memory);
 
         Ok(Pipeline {
             cube,
@@ -330,13 +371,44 @@ where
             env_cubemap_set,
             irradiance_cubemap_set,
             spec_cubemap_set,
+            env_generation: env_storage.generation,
             settings,
             pool,
             buffer,
+            view: self.view,
+            memory_stats_id,
+            cube_display: self.cube_display,
         })
     }
 }
 
+/// The environment cube map is a `CubeMap` rather than a texture, so the cube maps are passed
+/// as their sampler and view.
+unsafe fn write_cube_set<B: hal::Backend>(
+    factory: &Factory<B>,
+    set: &B::DescriptorSet,
+    sampler: &Sampler<B>,
+    view: &ImageView<B>,
+) {
+    factory.write_descriptor_sets(vec![
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 0,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Sampler(sampler.raw())),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 1,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                view.raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+    ]);
+}
+
 impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
 where
     B: hal::Backend,
@@ -351,18 +423,44 @@ where
         index: usize,
         world: &specs::World,
     ) -> PrepareResult {
-        use specs::prelude::*;
+        let _scope = crate::profile::scope("EnvironmentMapPipeline::prepare");
+        let env_storage = world.read_resource::<super::EnvironmentStorage<B>>();
+        if self.env_generation != env_storage.generation {
+            let env_cube = env_storage.env_cube.as_ref().unwrap();
+            let irradiance_cube = env_storage.irradiance_cube.as_ref().unwrap();
+            let spec_cube = env_storage.spec_cube.as_ref().unwrap();
+            unsafe {
+                write_cube_set(
+                    factory,
+                    &self.env_cubemap_set,
+                    env_cube.sampler(),
+                    env_cube.view(),
+                );
+                write_cube_set(
+                    factory,
+                    &self.irradiance_cubemap_set,
+                    irradiance_cube.sampler(),
+                    irradiance_cube.view(),
+                );
+                write_cube_set(
+                    factory,
+                    &self.spec_cubemap_set,
+                    spec_cube.sampler(),
+                    spec_cube.view(),
+                );
+            }
+            self.env_generation = env_storage.generation;
+        }
 
         let aux = world.read_resource::<Aux>();
-        let transforms = world.read_storage::<components::GlobalTransform>();
-        let cameras = world.read_storage::<components::Camera>();
-        let active_cameras = world.read_storage::<components::ActiveCamera>();
-        let mut camera_args: CameraArgs = (&active_cameras, &cameras, &transforms)
-            .join()
-            .map(|(_, cam, trans)| (cam, trans).into())
-            .next()
-            .expect("No active camera!");
+        let mut camera_args = super::camera_args(self.view, world);
+        // The cube map inspection controls only apply to the one selected with them
+        let (cube_mip, cube_display) = match self.cube_display {
+            Some(cube_display) => (None, cube_display),
+            None => (aux.cube_mip, aux.cube_display),
+        };
 
+        let camera_height = camera_args.camera_pos.y;
         camera_args.view.column_mut(3)[0] = 0.0;
         camera_args.view.column_mut(3)[1] = 0.0;
         camera_args.view.column_mut(3)[2] = 0.0;
@@ -375,11 +473,15 @@ where
                     &[UniformArgs {
                         proj: camera_args.proj,
                         view: camera_args.view,
-                        roughness: match aux.cube_display {
-                            CubeDisplay::Irradiance => 0.0,
-                            CubeDisplay::Environment => 0.0,
-                            CubeDisplay::Specular => aux.cube_roughness,
+                        roughness: match (cube_mip, cube_display) {
+                            (Some(mip), _) => mip.min(aux.cube_mip_levels() - 1) as f32,
+                            (None, CubeDisplay::Irradiance) => 0.0,
+                            (None, CubeDisplay::Environment) => 0.0,
+                            (None, CubeDisplay::Specular) => aux.cube_roughness,
                         },
+                        _pad: [0.0; 3],
+                        fog: super::FogUniform::new(&aux.fog, camera_height),
+                        background: aux.background.into(),
                     }],
                 )
                 .unwrap()
@@ -395,11 +497,18 @@ where
         index: usize,
         world: &specs::World,
     ) {
+        let _scope = crate::profile::scope("EnvironmentMapPipeline::draw");
+        unsafe {
+            super::begin_marker(&mut encoder, "Environment map");
+        }
         assert!(self
             .cube
             .bind(0, &[Position::vertex()], &mut encoder)
             .is_ok());
-        let cube_set = match world.read_resource::<Aux>().cube_display {
+        let cube_display = self
+            .cube_display
+            .unwrap_or(world.read_resource::<Aux>().cube_display);
+        let cube_set = match cube_display {
             CubeDisplay::Irradiance => &self.irradiance_cubemap_set,
             CubeDisplay::Environment => &self.env_cubemap_set,
             CubeDisplay::Specular => &self.spec_cubemap_set,
@@ -412,10 +521,14 @@ where
                 std::iter::empty(),
             );
             encoder.draw(0..36, 0..1);
+            super::end_marker(&mut encoder);
         }
     }
 
-    fn dispose(mut self, factory: &mut Factory<B>, _aux: &specs::World) {
+    fn dispose(mut self, factory: &mut Factory<B>, world: &specs::World) {
+        world
+            .write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
         unsafe {
             self.pool.reset();
             factory.destroy_descriptor_pool(self.pool);
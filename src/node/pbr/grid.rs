@@ -0,0 +1,331 @@
+//! A grid on a horizontal ground plane, drawn over the meshes to help with orientation.
+use derivative::Derivative;
+
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{render::*, GraphContext, NodeBuffer, NodeImage},
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    resource::{Buffer, BufferInfo, DescriptorSetLayout, Escape, Handle},
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use crate::{
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{Aux, View},
+};
+
+/// Appearance of the ground grid. Toggled at runtime with the G key.
+#[derive(Debug, Derivative, Clone, Copy, serde::Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct GridSettings {
+    pub enabled: bool,
+    /// Distance between lines, in world units
+    #[derivative(Default(value = "1.0"))]
+    pub cell_size: f32,
+    /// Distance from the camera the grid extends to, in world units
+    #[derivative(Default(value = "50.0"))]
+    pub extent: f32,
+    /// Height of the ground plane
+    pub height: f32,
+    /// Width of the lines, in pixels
+    #[derivative(Default(value = "1.0"))]
+    pub line_width: f32,
+    /// Color of the lines, with alpha
+    #[derivative(Default(value = "[0.5, 0.5, 0.5, 0.5]"))]
+    pub color: [f32; 4],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct UniformArgs {
+    proj: nalgebra::Matrix4<f32>,
+    view: nalgebra::Matrix4<f32>,
+    camera_pos: [f32; 4],
+    color: [f32; 4],
+    params: [f32; 4],
+}
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/grid.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/grid.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*FRAGMENT).unwrap();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Settings {
+    align: u64,
+}
+
+impl Settings {
+    const UNIFORM_SIZE: u64 = std::mem::size_of::<UniformArgs>() as u64;
+
+    fn from_world<B: hal::Backend>(world: &specs::World) -> Self {
+        let aux = world.read_resource::<Aux>();
+        Self { align: aux.align }
+    }
+
+    #[inline]
+    fn buffer_frame_size(&self) -> u64 {
+        ((Self::UNIFORM_SIZE - 1) / self.align + 1) * self.align
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PipelineDesc {
+    reversed_z: bool,
+    view: View,
+}
+
+impl PipelineDesc {
+    pub fn with_reversed_z(mut self, reversed_z: bool) -> Self {
+        self.reversed_z = reversed_z;
+        self
+    }
+
+    /// Draw from the camera of another view than the main window's.
+    pub fn with_view(mut self, view: View) -> Self {
+        self.view = view;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    ubo_sets: Vec<B::DescriptorSet>,
+    settings: Settings,
+    pool: B::DescriptorPool,
+    buffer: Escape<Buffer<B>>,
+    view: View,
+    memory_stats_id: usize,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn vertices(
+        &self,
+    ) -> Vec<(
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    )> {
+        Vec::new()
+    }
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::ALL,
+            blend: Some(hal::pso::BlendState::ALPHA),
+        }]
+    }
+
+    /// Tested against the meshes, but doesn't occlude anything itself
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        Some(super::depth_test(self.reversed_z, false))
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _aux: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        Layout {
+            sets: vec![SetLayout {
+                bindings: vec![hal::pso::DescriptorSetLayoutBinding {
+                    binding: 0,
+                    ty: hal::pso::DescriptorType::UniformBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::VERTEX
+                        | hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                }],
+            }],
+            push_constants: Vec::new(),
+        }
+    }
+
+    fn build<'a>(
+        self,
+        _ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert!(images.is_empty());
+        assert!(set_layouts.len() == 1);
+
+        let aux = world.read_resource::<Aux>();
+        let frames = aux.frames;
+
+        let mut pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    frames,
+                    vec![hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::UniformBuffer,
+                        count: frames,
+                    }],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let settings = Settings::from_world::<B>(world);
+
+        let buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.buffer_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::UNIFORM,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+
+        let mut ubo_sets = Vec::new();
+        for frame in 0..frames {
+            ubo_sets.push(unsafe {
+                let set = pool.allocate_set(&set_layouts[0].raw()).unwrap();
+                factory.write_descriptor_sets(vec![hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Buffer(
+                        buffer.raw(),
+                        Some(settings.buffer_frame_size() * frame as u64)
+                            ..Some(settings.buffer_frame_size() * (frame + 1) as u64),
+                    )),
+                }]);
+                set
+            });
+        }
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&buffer);
+        let memory_stats_id = world
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline("Grid".to_owned(), memory);
+
+        Ok(Pipeline {
+            ubo_sets,
+            settings,
+            pool,
+            buffer,
+            view: self.view,
+            memory_stats_id,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        index: usize,
+        world: &specs::World,
+    ) -> PrepareResult {
+        let _scope = crate::profile::scope("GridPipeline::prepare");
+        let aux = world.read_resource::<Aux>();
+        let grid = aux.grid;
+
+        if grid.enabled {
+            let camera_args = super::camera_args(self.view, world);
+
+            unsafe {
+                factory
+                    .upload_visible_buffer(
+                        &mut self.buffer,
+                        self.settings.buffer_frame_size() * index as u64,
+                        &[UniformArgs {
+                            proj: camera_args.proj,
+                            view: camera_args.view,
+                            camera_pos: [
+                                camera_args.camera_pos.x,
+                                camera_args.camera_pos.y,
+                                camera_args.camera_pos.z,
+                                1.0,
+                            ],
+                            color: grid.color,
+                            params: [grid.cell_size, grid.extent, grid.height, grid.line_width],
+                        }],
+                    )
+                    .unwrap()
+            };
+        }
+
+        // Recorded every frame, since the grid can be toggled at any time
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        index: usize,
+        world: &specs::World,
+    ) {
+        let _scope = crate::profile::scope("GridPipeline::draw");
+        if !world.read_resource::<Aux>().grid.enabled {
+            return;
+        }
+
+        unsafe {
+            super::begin_marker(&mut encoder, "Grid");
+            encoder.bind_graphics_descriptor_sets(
+                layout,
+                0,
+                Some(&self.ubo_sets[index]),
+                std::iter::empty(),
+            );
+            encoder.draw(0..6, 0..1);
+            super::end_marker(&mut encoder);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, world: &specs::World) {
+        world
+            .write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
+        unsafe {
+            self.pool.reset();
+            factory.destroy_descriptor_pool(self.pool);
+        }
+    }
+}
@@ -0,0 +1,1463 @@
+//! Occlusion culling with a hierarchical depth buffer. The meshes of the main view are drawn
+//! into a depth pre-pass, which is reduced into a pyramid keeping the farthest depth under
+//! each texel. A compute node then tests the bounding sphere of every instance against the
+//! level of the pyramid where it covers at most two texels each way, and writes the
+//! transforms of the instances which may be visible and a draw command per primitive with
+//! their count. The main view's mesh pipelines draw from those instead of every instance.
+//!
+//! Bounding spheres are around the origin of their mesh, see `asset::Mesh::bounding_radius`,
+//! so meshes far from their origin are culled conservatively.
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, DrawIndexedCommand, ExecutableState, Families, Family,
+        FamilyId, Fence, MultiShot, PendingState, Queue, QueueId, RenderPassEncoder,
+        SimultaneousUse, Submission, Submit,
+    },
+    factory::Factory,
+    frame::Frames,
+    graph::{
+        gfx_acquire_barriers, gfx_release_barriers, render::*, BufferAccess, BufferId, DynNode,
+        GraphBuilder, GraphContext, ImageAccess, ImageId, NodeBuffer, NodeBuildError, NodeBuilder,
+        NodeId, NodeImage,
+    },
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    mesh::{AsVertex, Model, PosNormTangTex},
+    resource::{
+        Buffer, BufferInfo, DescriptorSetLayout, Escape, Filter, Handle, ImageView, ImageViewInfo,
+        Sampler, SamplerDesc, ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use std::mem::size_of;
+
+use crate::{
+    asset,
+    draw_stats::{DrawCounts, DrawStats},
+    frame_arena::FrameArena,
+    geometry_pool::GeometryPool,
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{mesh::Settings, Aux, View},
+    render_frame::RenderFrames,
+};
+
+lazy_static::lazy_static! {
+    static ref DEPTH_VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/shadow.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref DEPTH_SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*DEPTH_VERTEX).unwrap();
+
+    static ref DOWNSAMPLE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/hiz_downsample.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref CULL: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/hiz_cull.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+}
+
+/// Matches both sizes of `hiz_downsample.comp`.
+const DOWNSAMPLE_GROUP_SIZE: u32 = 8;
+/// Matches `local_size_x` of `hiz_cull.comp`.
+const CULL_GROUP_SIZE: u32 = 64;
+
+/// The passes of `hiz_cull.comp`, pushed as a constant. Must match the shader.
+const PASS_CLEAR: u32 = 0;
+const PASS_CULL: u32 = 1;
+const PASS_COMMANDS: u32 = 2;
+
+/// Push constants of the depth pre-pass: the camera's projection and view, then the model
+/// matrix of the mesh, as column major floats. The same as the shadow pass's.
+const PUSH_CONSTANT_WORDS: u32 = 32;
+
+/// The buffers the cull node writes for the main view's mesh pipelines, laid out like each
+/// pipeline's own indirect commands, transforms and light masks.
+#[derive(Debug, Clone, Copy)]
+pub struct CulledDraws {
+    /// A `DrawIndexedCommand` per primitive
+    pub indirect: BufferId,
+    /// The transforms of the visible instances of each mesh, from the mesh's first slot
+    pub transforms: BufferId,
+    /// The `components::LightList` of each visible instance, in the same slots as its transform
+    pub light_masks: BufferId,
+}
+
+/// Adds the depth pre-pass, the pyramid built from it and the cull node, which the main
+/// view's mesh pass must depend on.
+pub fn add_occlusion_culling_nodes<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, specs::World>,
+    description: &mut crate::graph_dump::GraphDescription,
+    world: &specs::World,
+    width: u32,
+    height: u32,
+    reversed_z: bool,
+    family: FamilyId,
+) -> (NodeId, CulledDraws) {
+    let depth = description.create_image(
+        builder,
+        "prepass_depth",
+        hal::image::Kind::D2(width, height, 1, 1),
+        1,
+        hal::format::Format::D32Sfloat,
+        Some(hal::command::ClearValue {
+            depth_stencil: hal::command::ClearDepthStencil {
+                depth: if reversed_z { 0.0 } else { 1.0 },
+                stencil: 0,
+            },
+        }),
+    );
+    let prepass = description.add_node(
+        builder,
+        "depth_prepass",
+        PrepassDesc { reversed_z }
+            .builder()
+            .into_subpass()
+            .with_depth_stencil(depth)
+            .into_pass(),
+    );
+
+    let levels = 32 - width.max(height).leading_zeros();
+    let hiz = description.create_image(
+        builder,
+        "hiz",
+        hal::image::Kind::D2(width, height, 1, 1),
+        levels as u8,
+        hal::format::Format::R32Sfloat,
+        None,
+    );
+    let pyramid = description.add_node(
+        builder,
+        "hiz_pyramid",
+        HiZNodeBuilder {
+            depth,
+            hiz,
+            reversed_z,
+            family,
+            dependencies: vec![prepass],
+        },
+    );
+
+    let settings = Settings::from_world::<B>(world);
+    let culled_draws = CulledDraws {
+        indirect: description.create_buffer(
+            builder,
+            "culled_indirect",
+            (size_of::<DrawIndexedCommand>() * settings.num_primitives.max(1)) as u64,
+        ),
+        transforms: description.create_buffer(
+            builder,
+            "culled_transforms",
+            size_of::<Model>() as u64 * settings.total_max_mesh_instances.max(1),
+        ),
+        light_masks: description.create_buffer(
+            builder,
+            "culled_light_masks",
+            size_of::<u32>() as u64 * settings.total_max_mesh_instances.max(1),
+        ),
+    };
+    let cull = description.add_node(
+        builder,
+        "occlusion_cull",
+        CullNodeBuilder {
+            hiz,
+            culled_draws,
+            reversed_z,
+            family,
+            dependencies: vec![pyramid],
+        },
+    );
+    (cull, culled_draws)
+}
+
+/// Draws the depth of every mesh as seen from the main view's camera.
+#[derive(Debug)]
+pub struct PrepassDesc {
+    reversed_z: bool,
+}
+
+#[derive(Debug)]
+pub struct Prepass;
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PrepassDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Prepass;
+
+    fn vertices(
+        &self,
+    ) -> Vec<(
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    )> {
+        vec![PosNormTangTex::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex)]
+    }
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        Vec::new()
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        Some(super::depth_test(self.reversed_z, true))
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _aux: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        DEPTH_SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        Layout {
+            sets: Vec::new(),
+            push_constants: vec![(hal::pso::ShaderStageFlags::VERTEX, 0..PUSH_CONSTANT_WORDS)],
+        }
+    }
+
+    fn build<'a>(
+        self,
+        _ctx: &GraphContext<B>,
+        _factory: &mut Factory<B>,
+        _queue: QueueId,
+        _world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Prepass, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert!(images.is_empty());
+        assert!(set_layouts.is_empty());
+        Ok(Prepass)
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Prepass
+where
+    B: hal::Backend,
+{
+    type Desc = PrepassDesc;
+
+    fn prepare(
+        &mut self,
+        _factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        _index: usize,
+        _world: &specs::World,
+    ) -> PrepareResult {
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        _index: usize,
+        world: &specs::World,
+    ) {
+        let _scope = crate::profile::scope("Prepass::draw");
+
+        let camera = super::camera_args(View::Main, world);
+        let view_proj = camera.proj * camera.view;
+        let mask = super::view_layers(View::Main, world);
+
+        let frames = world.read_resource::<RenderFrames>();
+        let mesh_storage = world.read_resource::<asset::MeshStorage>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
+        let geometry_pool = world.read_resource::<GeometryPool<B>>();
+        let arena = world.read_resource::<FrameArena>();
+        unsafe {
+            super::begin_marker(&mut encoder, "Depth pre-pass");
+        }
+        geometry_pool.bind(0, false, &mut encoder);
+        let mut counts = DrawCounts {
+            pipeline_binds: 1,
+            vertex_buffer_binds: 1,
+            ..Default::default()
+        };
+        // Meshes the main view doesn't see don't hide others either
+        for instance in frames
+            .front()
+            .instances
+            .iter()
+            .filter(|instance| instance.visible(mask))
+        {
+            let constants = arena.alloc_iter(
+                view_proj
+                    .iter()
+                    .chain(instance.transform.iter())
+                    .map(|value| value.to_bits()),
+            );
+            unsafe {
+                encoder.push_constants(layout, hal::pso::ShaderStageFlags::VERTEX, 0, constants);
+            }
+            for primitive in mesh_storage.0[instance.mesh].primitives.iter() {
+                let range = primitive_storage.0[*primitive].range;
+                unsafe {
+                    encoder.draw_indexed(range.indices(), range.vertex_offset, 0..1);
+                }
+                counts.draw_calls += 1;
+                counts.draws += 1;
+            }
+        }
+        world.write_resource::<DrawStats>().current += counts;
+        unsafe {
+            super::end_marker(&mut encoder);
+        }
+    }
+
+    fn dispose(self, _factory: &mut Factory<B>, _world: &specs::World) {}
+}
+
+/// Reduces the depth pre-pass into every level of the pyramid, each from the one before.
+#[derive(Debug)]
+struct HiZNodeBuilder {
+    depth: ImageId,
+    hiz: ImageId,
+    reversed_z: bool,
+    family: FamilyId,
+    dependencies: Vec<NodeId>,
+}
+
+#[derive(Debug)]
+struct HiZNode<B: hal::Backend> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+    descriptor_pool: B::DescriptorPool,
+    set_layout: B::DescriptorSetLayout,
+    pipeline_layout: B::PipelineLayout,
+    pipeline: B::ComputePipeline,
+    sampler: Escape<Sampler<B>>,
+    depth_view: Escape<ImageView<B>>,
+    /// One per level
+    level_views: Vec<Escape<ImageView<B>>>,
+}
+
+impl<B> NodeBuilder<B, specs::World> for HiZNodeBuilder
+where
+    B: hal::Backend,
+{
+    fn family(&self, _factory: &mut Factory<B>, _families: &Families<B>) -> Option<FamilyId> {
+        Some(self.family)
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![
+            (
+                self.depth,
+                ImageAccess {
+                    access: hal::image::Access::SHADER_READ,
+                    usage: hal::image::Usage::SAMPLED,
+                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                    stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+                },
+            ),
+            (
+                self.hiz,
+                ImageAccess {
+                    access: hal::image::Access::SHADER_READ | hal::image::Access::SHADER_WRITE,
+                    usage: hal::image::Usage::STORAGE | hal::image::Usage::SAMPLED,
+                    layout: hal::image::Layout::General,
+                    stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+                },
+            ),
+        ]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        _aux: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, specs::World>>, NodeBuildError> {
+        assert!(buffers.is_empty());
+        assert_eq!(images.len(), 2);
+
+        let depth = ctx
+            .get_image(images[0].id)
+            .expect("Depth pre-pass image missing");
+        let hiz = ctx.get_image(images[1].id).expect("Hi-Z image missing");
+        let extent = hiz.kind().extent();
+        let levels = hiz.levels();
+
+        let depth_view = factory
+            .create_image_view(
+                depth.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format: depth.format(),
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[0].range.clone(),
+                },
+            )
+            .expect("Could not create depth pre-pass view");
+        let level_views = (0..levels)
+            .map(|level| {
+                factory
+                    .create_image_view(
+                        hiz.clone(),
+                        ImageViewInfo {
+                            view_kind: ViewKind::D2,
+                            format: hiz.format(),
+                            swizzle: hal::format::Swizzle::NO,
+                            range: hal::image::SubresourceRange {
+                                aspects: hal::format::Aspects::COLOR,
+                                levels: level..level + 1,
+                                layers: 0..1,
+                            },
+                        },
+                    )
+                    .expect("Could not create Hi-Z level view")
+            })
+            .collect::<Vec<_>>();
+        let sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+            .unwrap();
+
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory
+                .device()
+                .create_descriptor_set_layout(
+                    vec![
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 0,
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 1,
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 2,
+                            ty: hal::pso::DescriptorType::StorageImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                    ],
+                    std::iter::empty::<B::Sampler>(),
+                )
+                .unwrap();
+            let pipeline_layout = factory
+                .device()
+                .create_pipeline_layout(
+                    Some(&set_layout),
+                    Some((hal::pso::ShaderStageFlags::COMPUTE, 0..3)),
+                )
+                .unwrap();
+            (set_layout, pipeline_layout)
+        };
+
+        let sets = levels as usize;
+        let mut descriptor_pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    sets,
+                    vec![
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: sets,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: sets,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::StorageImage,
+                            count: sets,
+                        },
+                    ],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        // Each level is reduced from the previous one, and the first from the depth itself
+        let sets = (0..levels as usize)
+            .map(|level| unsafe {
+                let set = descriptor_pool.allocate_set(&set_layout).unwrap();
+                let source = if level == 0 {
+                    (depth_view.raw(), hal::image::Layout::ShaderReadOnlyOptimal)
+                } else {
+                    (level_views[level - 1].raw(), hal::image::Layout::General)
+                };
+                factory.write_descriptor_sets(vec![
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 0,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Sampler(sampler.raw())),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 1,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(source.0, source.1)),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 2,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(
+                            level_views[level].raw(),
+                            hal::image::Layout::General,
+                        )),
+                    },
+                ]);
+                set
+            })
+            .collect::<Vec<_>>();
+
+        let pipeline = unsafe { create_pipeline(factory, &pipeline_layout, &*DOWNSAMPLE).unwrap() };
+
+        let level_size = |level: u32| {
+            (
+                (extent.width >> level).max(1),
+                (extent.height >> level).max(1),
+            )
+        };
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+        unsafe {
+            super::begin_marker(&mut encoder, "Hi-Z pyramid");
+            let (stages, barriers) = gfx_acquire_barriers(ctx, buffers.iter(), images.iter());
+            if !barriers.is_empty() {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+            }
+
+            encoder.bind_compute_pipeline(&pipeline);
+            for level in 0..levels as u32 {
+                let source_size = if level == 0 {
+                    (extent.width, extent.height)
+                } else {
+                    level_size(level - 1)
+                };
+                let size = level_size(level);
+                encoder.bind_compute_descriptor_sets(
+                    &pipeline_layout,
+                    0,
+                    Some(&sets[level as usize]),
+                    std::iter::empty(),
+                );
+                encoder.push_constants(
+                    &pipeline_layout,
+                    hal::pso::ShaderStageFlags::COMPUTE,
+                    0,
+                    &[source_size.0, source_size.1, self.reversed_z as u32],
+                );
+                encoder.dispatch([
+                    (size.0 + DOWNSAMPLE_GROUP_SIZE - 1) / DOWNSAMPLE_GROUP_SIZE,
+                    (size.1 + DOWNSAMPLE_GROUP_SIZE - 1) / DOWNSAMPLE_GROUP_SIZE,
+                    1,
+                ]);
+                // The next level reads this one
+                encoder.pipeline_barrier(
+                    hal::pso::PipelineStage::COMPUTE_SHADER
+                        ..hal::pso::PipelineStage::COMPUTE_SHADER,
+                    hal::memory::Dependencies::empty(),
+                    Some(hal::memory::Barrier::Image {
+                        states: (
+                            hal::image::Access::SHADER_WRITE,
+                            hal::image::Layout::General,
+                        )
+                            ..(hal::image::Access::SHADER_READ, hal::image::Layout::General),
+                        families: None,
+                        target: hiz.raw(),
+                        range: hal::image::SubresourceRange {
+                            aspects: hal::format::Aspects::COLOR,
+                            levels: level as u8..level as u8 + 1,
+                            layers: 0..1,
+                        },
+                    }),
+                );
+            }
+
+            let (stages, barriers) = gfx_release_barriers(ctx, buffers.iter(), images.iter());
+            if !barriers.is_empty() {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+            }
+            super::end_marker(&mut encoder);
+        }
+
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        Ok(Box::new(HiZNode {
+            pool,
+            submit,
+            buffer,
+            descriptor_pool,
+            set_layout,
+            pipeline_layout,
+            pipeline,
+            sampler,
+            depth_view,
+            level_views,
+        }))
+    }
+}
+
+impl<B> DynNode<B, specs::World> for HiZNode<B>
+where
+    B: hal::Backend,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        _aux: &specs::World,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("HiZNode::run");
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submit))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, _aux: &specs::World) {
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+        factory.device().destroy_compute_pipeline(self.pipeline);
+        factory
+            .device()
+            .destroy_pipeline_layout(self.pipeline_layout);
+        factory
+            .device()
+            .destroy_descriptor_set_layout(self.set_layout);
+    }
+}
+
+/// The start of each frame's region of the instances buffer, matching `Instances` in
+/// `hiz_cull.comp`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct CullArgs {
+    view_proj: [[f32; 4]; 4],
+    hiz_size: [u32; 2],
+    hiz_levels: u32,
+    reversed_z: u32,
+    num_instances: u32,
+    num_meshes: u32,
+    num_primitives: u32,
+    /// Whether draw commands start at their mesh's first transform, see
+    /// `mesh::supports_multi_draw`
+    multi_draw: u32,
+}
+
+/// An instance slot of a mesh, laid out like the mesh pipelines' transforms.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct CullInstance {
+    model: [[f32; 4]; 4],
+    mesh: u32,
+    /// Whether an entity uses the slot
+    live: u32,
+    light_mask: u32,
+    _pad: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct CullMesh {
+    bounding_radius: f32,
+    /// Slot of the mesh's first instance in the transforms
+    first_transform: u32,
+}
+
+/// The draw command of a primitive, without its instance count.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct CullPrimitive {
+    index_count: u32,
+    first_index: u32,
+    vertex_offset: i32,
+    mesh: u32,
+}
+
+/// Tests every instance against the pyramid and writes `CulledDraws`.
+#[derive(Debug)]
+struct CullNodeBuilder {
+    hiz: ImageId,
+    culled_draws: CulledDraws,
+    reversed_z: bool,
+    family: FamilyId,
+    dependencies: Vec<NodeId>,
+}
+
+#[derive(Debug)]
+struct CullNode<B: hal::Backend> {
+    pool: CommandPool<B>,
+    /// One per frame in flight, each reading its frame's instances
+    submits: Vec<Submit<B, SimultaneousUse>>,
+    buffers: Vec<
+        CommandBuffer<
+            B,
+            hal::queue::QueueType,
+            PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+        >,
+    >,
+    descriptor_pool: B::DescriptorPool,
+    set_layout: B::DescriptorSetLayout,
+    pipeline_layout: B::PipelineLayout,
+    pipeline: B::ComputePipeline,
+    sampler: Escape<Sampler<B>>,
+    hiz_view: Escape<ImageView<B>>,
+    /// The `CullArgs` and instances of each frame, written before it's culled
+    instances: Escape<Buffer<B>>,
+    instances_frame_size: u64,
+    meshes: Escape<Buffer<B>>,
+    primitives: Escape<Buffer<B>>,
+    /// The visible instances of each mesh, counted while culling
+    counts: Escape<Buffer<B>>,
+    settings: Settings,
+    args: CullArgs,
+    /// Id of the node's buffers in `GpuMemoryStats`
+    memory_stats_id: usize,
+}
+
+impl<B> NodeBuilder<B, specs::World> for CullNodeBuilder
+where
+    B: hal::Backend,
+{
+    fn family(&self, _factory: &mut Factory<B>, _families: &Families<B>) -> Option<FamilyId> {
+        Some(self.family)
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        let written = BufferAccess {
+            access: hal::buffer::Access::SHADER_WRITE,
+            usage: hal::buffer::Usage::STORAGE,
+            stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+        };
+        vec![
+            (self.culled_draws.indirect, written),
+            (self.culled_draws.transforms, written),
+            (self.culled_draws.light_masks, written),
+        ]
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![(
+            self.hiz,
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+            },
+        )]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        aux: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, specs::World>>, NodeBuildError> {
+        use hal::adapter::PhysicalDevice;
+
+        assert_eq!(buffers.len(), 3);
+        assert_eq!(images.len(), 1);
+
+        let frames = aux.read_resource::<Aux>().frames;
+        let settings = Settings::from_world::<B>(aux);
+        let mesh_storage = aux.read_resource::<asset::MeshStorage>();
+        let primitive_storage = aux.read_resource::<asset::PrimitiveStorage>();
+
+        let indirect = ctx
+            .get_buffer(buffers[0].id)
+            .expect("Culled indirect buffer missing");
+        let transforms = ctx
+            .get_buffer(buffers[1].id)
+            .expect("Culled transforms buffer missing");
+        let light_masks = ctx
+            .get_buffer(buffers[2].id)
+            .expect("Culled light masks buffer missing");
+        let hiz = ctx.get_image(images[0].id).expect("Hi-Z image missing");
+        let extent = hiz.kind().extent();
+        let hiz_view = factory
+            .create_image_view(
+                hiz.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format: hiz.format(),
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[0].range.clone(),
+                },
+            )
+            .expect("Could not create Hi-Z view");
+        let sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+            .unwrap();
+
+        let num_instances = settings.total_max_mesh_instances as usize;
+        let num_meshes = mesh_storage.0.len();
+        let num_primitives = settings.num_primitives;
+        let args = CullArgs {
+            view_proj: [[0.0; 4]; 4],
+            hiz_size: [extent.width, extent.height],
+            hiz_levels: hiz.levels() as u32,
+            reversed_z: self.reversed_z as u32,
+            num_instances: num_instances as u32,
+            num_meshes: num_meshes as u32,
+            num_primitives: num_primitives as u32,
+            multi_draw: super::mesh::supports_multi_draw(factory) as u32,
+        };
+
+        let align = factory
+            .physical()
+            .limits()
+            .min_storage_buffer_offset_alignment;
+        let instances_size =
+            (size_of::<CullArgs>() + size_of::<CullInstance>() * num_instances.max(1)) as u64;
+        let instances_frame_size = ((instances_size - 1) / align + 1) * align;
+        let instances = factory
+            .create_buffer(
+                BufferInfo {
+                    size: instances_frame_size * frames as u64,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+
+        let mut meshes = factory
+            .create_buffer(
+                BufferInfo {
+                    size: (size_of::<CullMesh>() * num_meshes.max(1)) as u64,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+        let mut primitives = factory
+            .create_buffer(
+                BufferInfo {
+                    size: (size_of::<CullPrimitive>() * num_primitives.max(1)) as u64,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+        let counts = factory
+            .create_buffer(
+                BufferInfo {
+                    size: (size_of::<u32>() * num_meshes.max(1)) as u64,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                MemoryUsageValue::Data,
+            )
+            .unwrap();
+        // The slots of removed meshes and primitives are left zeroed, which have no instances
+        // and draw nothing
+        unsafe {
+            let mut mesh_data = vec![CullMesh::default(); num_meshes];
+            for (handle, mesh) in mesh_storage.0.iter() {
+                mesh_data[handle.index()] = CullMesh {
+                    bounding_radius: mesh.bounding_radius,
+                    first_transform: settings.mesh_transforms_index(handle) as u32,
+                };
+            }
+            factory
+                .upload_visible_buffer(&mut meshes, 0, &mesh_data[..])
+                .unwrap();
+            let mut primitive_data = vec![CullPrimitive::default(); num_primitives];
+            for (handle, primitive) in primitive_storage.0.iter() {
+                primitive_data[handle.index()] = CullPrimitive {
+                    index_count: primitive.range.index_count,
+                    first_index: primitive.range.first_index,
+                    vertex_offset: primitive.range.vertex_offset,
+                    mesh: primitive.mesh_handle.index() as u32,
+                };
+            }
+            factory
+                .upload_visible_buffer(&mut primitives, 0, &primitive_data[..])
+                .unwrap();
+        }
+
+        let storage_binding = |binding| hal::pso::DescriptorSetLayoutBinding {
+            binding,
+            ty: hal::pso::DescriptorType::StorageBuffer,
+            count: 1,
+            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+            immutable_samplers: false,
+        };
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory
+                .device()
+                .create_descriptor_set_layout(
+                    vec![
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 0,
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 1,
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        storage_binding(2),
+                        storage_binding(3),
+                        storage_binding(4),
+                        storage_binding(5),
+                        storage_binding(6),
+                        storage_binding(7),
+                        storage_binding(8),
+                    ],
+                    std::iter::empty::<B::Sampler>(),
+                )
+                .unwrap();
+            let pipeline_layout = factory
+                .device()
+                .create_pipeline_layout(
+                    Some(&set_layout),
+                    Some((hal::pso::ShaderStageFlags::COMPUTE, 0..1)),
+                )
+                .unwrap();
+            (set_layout, pipeline_layout)
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    frames,
+                    vec![
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: frames,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: frames,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: frames * 7,
+                        },
+                    ],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let pipeline = unsafe { create_pipeline(factory, &pipeline_layout, &*CULL).unwrap() };
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+        let mut submits = Vec::with_capacity(frames);
+        let mut command_buffers = Vec::with_capacity(frames);
+        for index in 0..frames {
+            let set = unsafe {
+                let set = descriptor_pool.allocate_set(&set_layout).unwrap();
+                let frame_offset = instances_frame_size * index as u64;
+                let storage_buffers = [
+                    (
+                        2,
+                        instances.raw(),
+                        Some(frame_offset)..Some(frame_offset + instances_size),
+                    ),
+                    (3, meshes.raw(), None..None),
+                    (4, primitives.raw(), None..None),
+                    (5, counts.raw(), None..None),
+                    (6, indirect.raw(), None..None),
+                    (7, transforms.raw(), None..None),
+                    (8, light_masks.raw(), None..None),
+                ];
+                factory.write_descriptor_sets(
+                    vec![
+                        hal::pso::DescriptorSetWrite {
+                            set: &set,
+                            binding: 0,
+                            array_offset: 0,
+                            descriptors: Some(hal::pso::Descriptor::Sampler(sampler.raw())),
+                        },
+                        hal::pso::DescriptorSetWrite {
+                            set: &set,
+                            binding: 1,
+                            array_offset: 0,
+                            descriptors: Some(hal::pso::Descriptor::Image(
+                                hiz_view.raw(),
+                                hal::image::Layout::ShaderReadOnlyOptimal,
+                            )),
+                        },
+                    ]
+                    .into_iter()
+                    .chain(storage_buffers.iter().map(
+                        |(binding, buffer, range)| hal::pso::DescriptorSetWrite {
+                            set: &set,
+                            binding: *binding,
+                            array_offset: 0,
+                            descriptors: Some(hal::pso::Descriptor::Buffer(*buffer, range.clone())),
+                        },
+                    )),
+                );
+                set
+            };
+
+            let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+            let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+            let mut encoder = buf_recording.encoder();
+            unsafe {
+                super::begin_marker(&mut encoder, "Occlusion culling");
+                let (stages, barriers) = gfx_acquire_barriers(ctx, buffers.iter(), images.iter());
+                if !barriers.is_empty() {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+                }
+
+                encoder.bind_compute_pipeline(&pipeline);
+                encoder.bind_compute_descriptor_sets(
+                    &pipeline_layout,
+                    0,
+                    Some(&set),
+                    std::iter::empty(),
+                );
+                let passes = [
+                    (PASS_CLEAR, num_meshes),
+                    (PASS_CULL, num_instances),
+                    (PASS_COMMANDS, num_primitives),
+                ];
+                for (i, (pass, invocations)) in passes.iter().enumerate() {
+                    if i > 0 {
+                        // Each pass reads the counts the previous one wrote
+                        encoder.pipeline_barrier(
+                            hal::pso::PipelineStage::COMPUTE_SHADER
+                                ..hal::pso::PipelineStage::COMPUTE_SHADER,
+                            hal::memory::Dependencies::empty(),
+                            Some(hal::memory::Barrier::Buffer {
+                                states: hal::buffer::Access::SHADER_WRITE
+                                    ..hal::buffer::Access::SHADER_READ
+                                        | hal::buffer::Access::SHADER_WRITE,
+                                families: None,
+                                target: counts.raw(),
+                                range: None..None,
+                            }),
+                        );
+                    }
+                    encoder.push_constants(
+                        &pipeline_layout,
+                        hal::pso::ShaderStageFlags::COMPUTE,
+                        0,
+                        &[*pass],
+                    );
+                    encoder.dispatch([
+                        (*invocations as u32 + CULL_GROUP_SIZE - 1) / CULL_GROUP_SIZE,
+                        1,
+                        1,
+                    ]);
+                }
+
+                let (stages, barriers) = gfx_release_barriers(ctx, buffers.iter(), images.iter());
+                if !barriers.is_empty() {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+                }
+                super::end_marker(&mut encoder);
+            }
+            let (submit, buffer) = buf_recording.finish().submit();
+            submits.push(submit);
+            command_buffers.push(buffer);
+        }
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&instances);
+        memory.add_buffer(&meshes);
+        memory.add_buffer(&primitives);
+        memory.add_buffer(&counts);
+        let memory_stats_id = aux
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline("Occlusion culling".to_owned(), memory);
+
+        Ok(Box::new(CullNode {
+            pool,
+            submits,
+            buffers: command_buffers,
+            descriptor_pool,
+            set_layout,
+            pipeline_layout,
+            pipeline,
+            sampler,
+            hiz_view,
+            instances,
+            instances_frame_size,
+            meshes,
+            primitives,
+            counts,
+            settings,
+            args,
+            memory_stats_id,
+        }))
+    }
+}
+
+impl<B: hal::Backend> CullNode<B> {
+    /// Writes the camera and the transform and light mask of every live instance slot for a
+    /// frame.
+    unsafe fn write_instances(&mut self, factory: &Factory<B>, index: usize, world: &specs::World) {
+        let camera = super::camera_args(View::Main, world);
+        self.args.view_proj = (camera.proj * camera.view).into();
+        let mask = super::view_layers(View::Main, world);
+
+        let frames = world.read_resource::<RenderFrames>();
+        let arena = world.read_resource::<FrameArena>();
+        let instances = arena.alloc_fill(
+            self.settings.total_max_mesh_instances as usize,
+            CullInstance::default(),
+        );
+        // Instances the main view doesn't see are left out like empty slots
+        for instance in frames
+            .front()
+            .instances
+            .iter()
+            .filter(|instance| instance.visible(mask))
+        {
+            let slot = self
+                .settings
+                .instance_transform_index(instance.mesh, instance.instance);
+            instances[slot] = CullInstance {
+                model: instance.transform.into(),
+                mesh: instance.mesh.index() as u32,
+                live: 1,
+                light_mask: instance.light_mask,
+                _pad: 0,
+            };
+        }
+
+        let offset = self.instances_frame_size * index as u64;
+        factory
+            .upload_visible_buffer(&mut self.instances, offset, &[self.args])
+            .unwrap();
+        factory
+            .upload_visible_buffer(
+                &mut self.instances,
+                offset + size_of::<CullArgs>() as u64,
+                instances,
+            )
+            .unwrap();
+    }
+}
+
+impl<B> DynNode<B, specs::World> for CullNode<B>
+where
+    B: hal::Backend,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        aux: &specs::World,
+        frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("CullNode::run");
+        let index = frames.next().index() as usize % self.submits.len();
+        self.write_instances(factory, index, aux);
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submits[index]))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, aux: &specs::World) {
+        aux.write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
+        drop(self.submits);
+        self.pool.free_buffers(
+            self.buffers
+                .into_iter()
+                .map(|buffer| buffer.mark_complete()),
+        );
+        factory.destroy_command_pool(self.pool);
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+        factory.device().destroy_compute_pipeline(self.pipeline);
+        factory
+            .device()
+            .destroy_pipeline_layout(self.pipeline_layout);
+        factory
+            .device()
+            .destroy_descriptor_set_layout(self.set_layout);
+    }
+}
+
+unsafe fn create_pipeline<B: hal::Backend>(
+    factory: &Factory<B>,
+    layout: &B::PipelineLayout,
+    shader: &PathBufShaderInfo,
+) -> Result<B::ComputePipeline, failure::Error> {
+    let module = factory.device().create_shader_module(&shader.spirv()?)?;
+    let pipeline = factory.device().create_compute_pipeline(
+        &hal::pso::ComputePipelineDesc::new(
+            hal::pso::EntryPoint {
+                entry: "main",
+                module: &module,
+                specialization: hal::pso::Specialization::default(),
+            },
+            layout,
+        ),
+        None,
+    );
+    factory.device().destroy_shader_module(module);
+    Ok(pipeline?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::components::Camera;
+    use nalgebra::{Matrix4, Vector2, Vector3, Vector4};
+
+    const SIZE: [i32; 2] = [64, 64];
+    const WALL_DISTANCE: f32 = 5.0;
+
+    /// A level of the pyramid, as `hiz_downsample.comp` writes it.
+    struct Level {
+        size: [i32; 2],
+        depths: Vec<f32>,
+    }
+
+    impl Level {
+        fn fetch(&self, x: i32, y: i32) -> f32 {
+            self.depths[(y * self.size[0] + x) as usize]
+        }
+
+        fn downsample(&self, size: [i32; 2], reversed_z: bool) -> Level {
+            let mut depths = Vec::new();
+            for y in 0..size[1] {
+                for x in 0..size[0] {
+                    let start = [x * self.size[0] / size[0], y * self.size[1] / size[1]];
+                    let end = [
+                        (start[0] + 1).max(((x + 1) * self.size[0] + size[0] - 1) / size[0]),
+                        (start[1] + 1).max(((y + 1) * self.size[1] + size[1] - 1) / size[1]),
+                    ];
+                    let mut farthest = if reversed_z { 1.0 } else { 0.0 };
+                    for sy in start[1]..end[1] {
+                        for sx in start[0]..end[0] {
+                            let depth = self.fetch(sx, sy);
+                            farthest = if reversed_z {
+                                farthest.min(depth)
+                            } else {
+                                farthest.max(depth)
+                            };
+                        }
+                    }
+                    depths.push(farthest);
+                }
+            }
+            Level { size, depths }
+        }
+    }
+
+    /// The pyramid of a depth buffer, with as many levels as `HiZ` makes.
+    fn pyramid(depth: Level, reversed_z: bool) -> Vec<Level> {
+        let levels = 32 - (depth.size[0].max(depth.size[1]) as u32).leading_zeros();
+        let mut pyramid = vec![depth.downsample(depth.size, reversed_z)];
+        for level in 1..levels {
+            let size = [
+                (depth.size[0] >> level).max(1),
+                (depth.size[1] >> level).max(1),
+            ];
+            let next = pyramid.last().unwrap().downsample(size, reversed_z);
+            pyramid.push(next);
+        }
+        pyramid
+    }
+
+    /// A CPU reference of `visible` in `hiz_cull.comp`, line for line.
+    fn visible(
+        view_proj: &Matrix4<f32>,
+        center: Vector3<f32>,
+        radius: f32,
+        reversed_z: bool,
+        pyramid: &[Level],
+    ) -> bool {
+        let nearer = |a: f32, b: f32| if reversed_z { a > b } else { a < b };
+        let mut ndc_min = Vector2::repeat(1.0f32);
+        let mut ndc_max = Vector2::repeat(-1.0f32);
+        let mut nearest_depth = if reversed_z { 0.0 } else { 1.0f32 };
+        for i in 0..8 {
+            let sign = |bit| if i & bit != 0 { 1.0 } else { -1.0 };
+            let corner = center + radius * Vector3::new(sign(1), sign(2), sign(4));
+            let clip = view_proj * Vector4::new(corner.x, corner.y, corner.z, 1.0);
+            if clip.w <= 0.0 {
+                return true;
+            }
+            let ndc = clip.xyz() / clip.w;
+            ndc_min = ndc_min.inf(&ndc.xy());
+            ndc_max = ndc_max.sup(&ndc.xy());
+            nearest_depth = if reversed_z {
+                nearest_depth.max(ndc.z)
+            } else {
+                nearest_depth.min(ndc.z)
+            };
+        }
+        if ndc_max.x < -1.0 || ndc_max.y < -1.0 || ndc_min.x > 1.0 || ndc_min.y > 1.0 {
+            return false;
+        }
+        let ndc_min = ndc_min.map(|v| v.max(-1.0).min(1.0));
+        let ndc_max = ndc_max.map(|v| v.max(-1.0).min(1.0));
+
+        let size = Vector2::new(SIZE[0] as f32, SIZE[1] as f32);
+        let texel_min = Vector2::new(ndc_min.x + 1.0, 1.0 - ndc_max.y).component_mul(&size) * 0.5;
+        let texel_max = Vector2::new(ndc_max.x + 1.0, 1.0 - ndc_min.y).component_mul(&size) * 0.5;
+        let extent = texel_max - texel_min;
+        let level = extent.x.max(extent.y).max(1.0).log2().ceil() as i32;
+        let level = level.max(0).min(pyramid.len() as i32 - 1);
+
+        let hiz = &pyramid[level as usize];
+        let texel = |v: f32, axis: usize| ((v as i32) >> level).max(0).min(hiz.size[axis] - 1);
+        let (min_x, min_y) = (texel(texel_min.x, 0), texel(texel_min.y, 1));
+        let (max_x, max_y) = (texel(texel_max.x, 0), texel(texel_max.y, 1));
+        let mut farthest = if reversed_z { 1.0 } else { 0.0f32 };
+        for &(x, y) in [
+            (min_x, min_y),
+            (max_x, min_y),
+            (min_x, max_y),
+            (max_x, max_y),
+        ]
+        .iter()
+        {
+            let depth = hiz.fetch(x, y);
+            farthest = if reversed_z {
+                farthest.min(depth)
+            } else {
+                farthest.max(depth)
+            };
+        }
+        !nearer(farthest, nearest_depth)
+    }
+
+    /// Looking down -Z from the origin, with a 90 degree field of view on a square viewport.
+    fn projection(reversed_z: bool) -> Matrix4<f32> {
+        Camera {
+            yaw: 0.0,
+            pitch: 0.0,
+            dist: 1.0,
+            focus: nalgebra::Point3::origin(),
+            fov: std::f32::consts::FRAC_PI_2,
+            aspect: 1.0,
+            znear: 0.1,
+            zfar: 100.0,
+            infinite_far: false,
+            reversed_z,
+            layers: 1,
+        }
+        .projection()
+    }
+
+    /// The pyramid of a depth pre-pass which drew a wall `WALL_DISTANCE` away over the
+    /// columns `wall` returns true for, and nothing elsewhere.
+    fn wall_pyramid(
+        proj: &Matrix4<f32>,
+        reversed_z: bool,
+        wall: impl Fn(i32) -> bool,
+    ) -> Vec<Level> {
+        let clip = proj * Vector4::new(0.0, 0.0, -WALL_DISTANCE, 1.0);
+        let wall_depth = clip.z / clip.w;
+        let clear = if reversed_z { 0.0 } else { 1.0 };
+        let depths = (0..SIZE[1])
+            .flat_map(|_| (0..SIZE[0]).map(|x| if wall(x) { wall_depth } else { clear }))
+            .collect();
+        pyramid(Level { size: SIZE, depths }, reversed_z)
+    }
+
+    #[test]
+    fn spheres_behind_a_wall_are_culled() {
+        for &reversed_z in [false, true].iter() {
+            let proj = projection(reversed_z);
+            let pyramid = wall_pyramid(&proj, reversed_z, |_| true);
+            let behind = Vector3::new(0.0, 0.0, -20.0);
+            assert!(!visible(&proj, behind, 1.0, reversed_z, &pyramid));
+            let in_front = Vector3::new(0.0, 0.0, -2.0);
+            assert!(visible(&proj, in_front, 0.5, reversed_z, &pyramid));
+            // Reaching in front of the wall
+            assert!(visible(&proj, behind, 16.0, reversed_z, &pyramid));
+        }
+    }
+
+    #[test]
+    fn spheres_behind_a_gap_are_kept() {
+        for &reversed_z in [false, true].iter() {
+            let proj = projection(reversed_z);
+            // The left half of the view is walled
+            let pyramid = wall_pyramid(&proj, reversed_z, |x| x < SIZE[0] / 2);
+            let left = Vector3::new(-8.0, 0.0, -20.0);
+            let right = Vector3::new(8.0, 0.0, -20.0);
+            assert!(!visible(&proj, left, 1.0, reversed_z, &pyramid));
+            assert!(visible(&proj, right, 1.0, reversed_z, &pyramid));
+        }
+    }
+}
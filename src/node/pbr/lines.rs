@@ -0,0 +1,440 @@
+//! Colored line segments drawn over the scene to visualize things without geometry of their
+//! own. Systems push lines into `DebugLines` each frame, which are cleared once every view
+//! has drawn them. `OverlayLines` are drawn by a pipeline of their own without a depth test,
+//! on top of everything in the main window.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{render::*, GraphContext, NodeBuffer, NodeImage},
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    resource::{Buffer, BufferInfo, DescriptorSetLayout, Escape, Handle},
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use std::mem::size_of;
+
+use crate::{
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{Aux, View},
+};
+
+/// The most lines drawn in one frame. Lines pushed past it are dropped.
+pub const MAX_DEBUG_LINES: usize = 4096;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct UniformArgs {
+    proj: nalgebra::Matrix4<f32>,
+    view: nalgebra::Matrix4<f32>,
+}
+
+/// Per vertex data, two per line
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct LineVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+
+/// Lines to draw in the next frame.
+#[derive(Debug, Default)]
+pub struct DebugLines(Vec<LineVertex>);
+
+impl DebugLines {
+    pub fn line(
+        &mut self,
+        start: nalgebra::Point3<f32>,
+        end: nalgebra::Point3<f32>,
+        color: [f32; 4],
+    ) {
+        for point in [start, end].iter() {
+            self.0.push(LineVertex {
+                position: [point.x, point.y, point.z],
+                color,
+            });
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// The edges of a box or frustum, given the four corners of one face in order around
+    /// it, then the four of the opposite face in the same order.
+    pub fn frustum(&mut self, corners: &[nalgebra::Point3<f32>; 8], color: [f32; 4]) {
+        for i in 0..4 {
+            let next = (i + 1) % 4;
+            self.line(corners[i], corners[next], color);
+            self.line(corners[i + 4], corners[next + 4], color);
+            self.line(corners[i], corners[i + 4], color);
+        }
+    }
+}
+
+/// Lines never hidden by the scene, like the handles of the gizmo. Their owner replaces them
+/// every frame.
+#[derive(Debug, Default)]
+pub struct OverlayLines(pub DebugLines);
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/lines.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/lines.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*FRAGMENT).unwrap();
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Settings {
+    align: u64,
+}
+
+impl Settings {
+    const UNIFORM_SIZE: u64 = size_of::<UniformArgs>() as u64;
+
+    fn from_world<B: hal::Backend>(world: &specs::World) -> Self {
+        let aux = world.read_resource::<Aux>();
+        Self { align: aux.align }
+    }
+
+    #[inline]
+    fn buffer_frame_size(&self) -> u64 {
+        ((Self::UNIFORM_SIZE - 1) / self.align + 1) * self.align
+    }
+
+    #[inline]
+    fn vertices_frame_size(&self) -> u64 {
+        (size_of::<LineVertex>() * MAX_DEBUG_LINES * 2) as u64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PipelineDesc {
+    reversed_z: bool,
+    view: View,
+    overlay: bool,
+}
+
+impl PipelineDesc {
+    /// Draws `OverlayLines` instead of `DebugLines`, without a depth test.
+    pub fn overlay() -> Self {
+        PipelineDesc {
+            overlay: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_reversed_z(mut self, reversed_z: bool) -> Self {
+        self.reversed_z = reversed_z;
+        self
+    }
+
+    /// Draw from the camera of another view than the main window's.
+    pub fn with_view(mut self, view: View) -> Self {
+        self.view = view;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    sets: Vec<B::DescriptorSet>,
+    settings: Settings,
+    pool: B::DescriptorPool,
+    buffer: Escape<Buffer<B>>,
+    vertex_buffer: Escape<Buffer<B>>,
+    vertex_counts: Vec<u32>,
+    view: View,
+    overlay: bool,
+    memory_stats_id: usize,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn vertices(
+        &self,
+    ) -> Vec<(
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    )> {
+        vec![(
+            vec![
+                hal::pso::Element {
+                    format: hal::format::Format::Rgb32Sfloat,
+                    offset: 0,
+                },
+                hal::pso::Element {
+                    format: hal::format::Format::Rgba32Sfloat,
+                    offset: 12,
+                },
+            ],
+            size_of::<LineVertex>() as hal::pso::ElemStride,
+            hal::pso::VertexInputRate::Vertex,
+        )]
+    }
+
+    fn input_assembler(&self) -> hal::pso::InputAssemblerDesc {
+        hal::pso::InputAssemblerDesc::new(hal::Primitive::LineList)
+    }
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::ALL,
+            blend: Some(hal::pso::BlendState::ALPHA),
+        }]
+    }
+
+    /// Hidden behind meshes, but doesn't occlude anything itself. Overlay lines aren't hidden
+    /// at all.
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        if self.overlay {
+            None
+        } else {
+            Some(super::depth_test(self.reversed_z, false))
+        }
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _aux: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        Layout {
+            sets: vec![SetLayout {
+                bindings: vec![hal::pso::DescriptorSetLayoutBinding {
+                    binding: 0,
+                    ty: hal::pso::DescriptorType::UniformBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                    immutable_samplers: false,
+                }],
+            }],
+            push_constants: Vec::new(),
+        }
+    }
+
+    fn build<'a>(
+        self,
+        _ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert!(images.is_empty());
+        assert!(set_layouts.len() == 1);
+
+        let aux = world.read_resource::<Aux>();
+        let frames = aux.frames;
+
+        let mut pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    frames,
+                    vec![hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::UniformBuffer,
+                        count: frames,
+                    }],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let settings = Settings::from_world::<B>(world);
+
+        let buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.buffer_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::UNIFORM,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+
+        let vertex_buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.vertices_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::VERTEX,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+
+        let mut sets = Vec::new();
+        for frame in 0..frames {
+            sets.push(unsafe {
+                let set = pool.allocate_set(&set_layouts[0].raw()).unwrap();
+                factory.write_descriptor_sets(vec![hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Buffer(
+                        buffer.raw(),
+                        Some(settings.buffer_frame_size() * frame as u64)
+                            ..Some(settings.buffer_frame_size() * (frame + 1) as u64),
+                    )),
+                }]);
+                set
+            });
+        }
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&buffer);
+        memory.add_buffer(&vertex_buffer);
+        let memory_stats_id = world.write_resource::<GpuMemoryStats>().add_pipeline(
+            if self.overlay {
+                "Overlay lines"
+            } else {
+                "Debug lines"
+            }
+            .to_owned(),
+            memory,
+        );
+
+        Ok(Pipeline {
+            sets,
+            settings,
+            pool,
+            buffer,
+            vertex_buffer,
+            vertex_counts: vec![0; frames],
+            view: self.view,
+            overlay: self.overlay,
+            memory_stats_id,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        index: usize,
+        world: &specs::World,
+    ) -> PrepareResult {
+        let _scope = crate::profile::scope("LinesPipeline::prepare");
+        let overlay_lines;
+        let debug_lines;
+        let lines = if self.overlay {
+            overlay_lines = world.read_resource::<OverlayLines>();
+            &overlay_lines.0
+        } else {
+            debug_lines = world.read_resource::<DebugLines>();
+            &*debug_lines
+        };
+        let count = lines.0.len().min(MAX_DEBUG_LINES * 2);
+        self.vertex_counts[index] = count as u32;
+
+        if count > 0 {
+            let camera_args = super::camera_args(self.view, world);
+            unsafe {
+                factory
+                    .upload_visible_buffer(
+                        &mut self.buffer,
+                        self.settings.buffer_frame_size() * index as u64,
+                        &[UniformArgs {
+                            proj: camera_args.proj,
+                            view: camera_args.view,
+                        }],
+                    )
+                    .unwrap();
+                factory
+                    .upload_visible_buffer(
+                        &mut self.vertex_buffer,
+                        self.settings.vertices_frame_size() * index as u64,
+                        &lines.0[..count],
+                    )
+                    .unwrap();
+            }
+        }
+
+        // The lines are pushed anew every frame
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        index: usize,
+        _world: &specs::World,
+    ) {
+        let _scope = crate::profile::scope("LinesPipeline::draw");
+        let count = self.vertex_counts[index];
+        if count == 0 {
+            return;
+        }
+
+        unsafe {
+            super::begin_marker(
+                &mut encoder,
+                &format!(
+                    "{} lines: {}",
+                    if self.overlay { "Overlay" } else { "Debug" },
+                    count / 2
+                ),
+            );
+            encoder.bind_graphics_descriptor_sets(
+                layout,
+                0,
+                Some(&self.sets[index]),
+                std::iter::empty(),
+            );
+            encoder.bind_vertex_buffers(
+                0,
+                std::iter::once((
+                    self.vertex_buffer.raw(),
+                    self.settings.vertices_frame_size() * index as u64,
+                )),
+            );
+            encoder.draw(0..count, 0..1);
+            super::end_marker(&mut encoder);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, world: &specs::World) {
+        world
+            .write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
+        unsafe {
+            self.pool.reset();
+            factory.destroy_descriptor_pool(self.pool);
+        }
+    }
+}
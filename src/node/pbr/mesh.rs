@@ -1,10 +1,12 @@
+use derivative::Derivative;
+
 use rendy::{
     command::{DrawIndexedCommand, QueueId, RenderPassEncoder},
     factory::Factory,
-    graph::{render::*, GraphContext, NodeBuffer, NodeImage},
+    graph::{render::*, BufferAccess, GraphContext, NodeBuffer, NodeImage},
     hal::{device::Device, pso::DescriptorPool},
     memory::MemoryUsageValue,
-    mesh::{AsVertex, Model, PosNormTangTex},
+    mesh::{AsVertex, Model, PosNormTangTex, TexCoord},
     resource::{
         Buffer, BufferInfo, DescriptorSetLayout, Escape, Filter, Handle, Sampler, SamplerDesc,
         WrapMode,
@@ -12,13 +14,19 @@ use rendy::{
     shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
 };
 
-use std::mem::size_of;
+use std::{borrow::Cow, collections::HashMap, mem::size_of};
 
 use rendy::hal;
 
 use crate::{
-    asset, components,
-    node::pbr::{Aux, CameraArgs},
+    asset,
+    draw_stats::{DrawCounts, DrawStats},
+    frame_arena::FrameArena,
+    geometry_pool::GeometryPool,
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{Aux, CameraArgs, View},
+    render_frame::{RenderFrames, RenderInstance},
+    shader::{ShaderFeatures, ShaderVariants},
     systems,
 };
 
@@ -30,16 +38,10 @@ lazy_static::lazy_static! {
         "main",
     );
 
-    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+    static ref FRAGMENT: ShaderVariants = ShaderVariants::new(
         std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/pbr.frag"),
         ShaderKind::Fragment,
-        SourceLanguage::GLSL,
-        "main",
     );
-
-    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
-        .with_vertex(&*VERTEX).unwrap()
-        .with_fragment(&*FRAGMENT).unwrap();
 }
 
 #[derive(Clone, Copy)]
@@ -48,52 +50,710 @@ pub struct UniformArgs {
     camera: CameraArgs,
     num_lights: i32,
     lights: [super::LightData; crate::MAX_LIGHTS],
+    /// Diffuse and specular IBL occlusion strengths, then the `SpecularOcclusion` mode in `z`,
+    /// `w` is unused
+    occlusion: [f32; 4],
+    fog: super::FogUniform,
+    voxel_gi: crate::voxel_gi::VoxelGiUniform,
+    shadow: super::shadow::ShadowUniform,
+    /// Minimum and maximum parallax occlusion mapping steps, `zw` are unused
+    parallax: [f32; 4],
+    /// `IblMode` on each side of the split view, then the reference IBL's frame and samples,
+    /// see `Aux::ibl_uniform`
+    ibl: [f32; 4],
+}
+
+/// Number of texture maps bound per material.
+const MATERIAL_TEXTURES: usize = 9;
+
+/// Which shading input to display instead of the lit result.
+#[derive(Debug, Derivative, Clone, Copy, PartialEq, Eq)]
+#[derivative(Default)]
+#[repr(u32)]
+pub enum DebugView {
+    #[derivative(Default)]
+    Lit = 0,
+    Albedo,
+    Normal,
+    Metallic,
+    Roughness,
+    AmbientOcclusion,
+    Emissive,
+}
+
+impl DebugView {
+    const ALL: [DebugView; 7] = [
+        DebugView::Lit,
+        DebugView::Albedo,
+        DebugView::Normal,
+        DebugView::Metallic,
+        DebugView::Roughness,
+        DebugView::AmbientOcclusion,
+        DebugView::Emissive,
+    ];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+
+    pub fn prev(self) -> Self {
+        Self::ALL[(self as usize + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+/// Small per-draw values, passed as push constants so that changing them between
+/// draws doesn't require any descriptor updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct DrawArgs {
+    material_index: u32,
+    debug_view: u32,
+    /// Whether the mesh has a lightmap bound
+    lightmap: u32,
+    /// Debug view right of `split_x`, the first pixel column of the split view's right side
+    split_debug_view: u32,
+    split_x: u32,
 }
 
-#[derive(Debug, Default)]
-pub struct PipelineDesc;
+impl DrawArgs {
+    /// Push constant range, in 32-bit words.
+    const RANGE: std::ops::Range<u32> = 0..(size_of::<DrawArgs>() / 4) as u32;
+
+    fn as_words(&self) -> [u32; 5] {
+        [
+            self.material_index,
+            self.debug_view,
+            self.lightmap,
+            self.split_debug_view,
+            self.split_x,
+        ]
+    }
+
+    unsafe fn push<B: hal::Backend>(
+        &self,
+        layout: &B::PipelineLayout,
+        encoder: &mut RenderPassEncoder<'_, B>,
+    ) {
+        encoder.push_constants(
+            layout,
+            hal::pso::ShaderStageFlags::FRAGMENT,
+            Self::RANGE.start,
+            &self.as_words(),
+        );
+    }
+}
 
 #[derive(Debug)]
-pub struct Pipeline<B: hal::Backend> {
-    descriptor_pool: B::DescriptorPool,
+pub struct PipelineDesc {
+    /// The number of materials addressable through a single material descriptor set.
+    /// When this is greater than one, all materials are bound once as descriptor arrays
+    /// and selected per draw with a push constant, otherwise there is one set per material.
+    material_slots: usize,
+    /// The fragment shader variant to use. Only materials whose features match the
+    /// material features of the variant are drawn by this pipeline.
+    features: ShaderFeatures,
+    reversed_z: bool,
+    view: View,
+    /// Draw the instances left by occlusion culling, from the indirect commands, transforms
+    /// and light masks the cull node writes, rather than every instance.
+    culled_draws: bool,
+    /// Draw each `DrawBatch` with a single indirect call, see `supports_multi_draw`.
+    multi_draw: bool,
+}
+
+/// Whether the device can draw several indirect commands in one call, each with its own
+/// first instance. Draw commands then find their mesh's transforms by their first instance
+/// rather than by rebinding the transforms for every mesh, so that consecutive primitives
+/// sharing a material and lightmap are drawn together.
+pub(super) fn supports_multi_draw<B: hal::Backend>(factory: &Factory<B>) -> bool {
+    use hal::adapter::PhysicalDevice;
+
+    factory
+        .physical()
+        .features()
+        .contains(hal::Features::MULTI_DRAW_INDIRECT | hal::Features::DRAW_INDIRECT_FIRST_INSTANCE)
+}
+
+impl Default for PipelineDesc {
+    fn default() -> Self {
+        PipelineDesc {
+            material_slots: 1,
+            features: ShaderFeatures::NONE,
+            reversed_z: false,
+            view: View::Main,
+            culled_draws: false,
+            multi_draw: false,
+        }
+    }
+}
+
+impl PipelineDesc {
+    /// Uses descriptor-indexed material textures if the device can index sampled image
+    /// arrays from shaders and has room for every material, otherwise falls back to
+    /// binding one descriptor set per material.
+    pub fn new<B: hal::Backend>(factory: &Factory<B>, num_materials: usize) -> Self {
+        use hal::adapter::PhysicalDevice;
+
+        let physical = factory.physical();
+        let supports_indexing = physical
+            .features()
+            .contains(hal::Features::SHADER_SAMPLED_IMAGE_ARRAY_DYNAMIC_INDEXING);
+        // The static set also holds the three environment maps, the voxel volume and the
+        // shadow map, and the lightmap set one more
+        let fits_in_stage = num_materials * MATERIAL_TEXTURES + 6
+            <= physical.limits().max_per_stage_descriptor_sampled_images;
+
+        let multi_draw = supports_multi_draw(factory);
+        if multi_draw {
+            log::info!("Using multi-draw indirect");
+        }
+
+        if supports_indexing && fits_in_stage && num_materials > 1 {
+            log::info!("Using descriptor-indexed material textures");
+            PipelineDesc {
+                material_slots: num_materials,
+                multi_draw,
+                ..Default::default()
+            }
+        } else {
+            log::info!("Using per-material descriptor sets");
+            PipelineDesc {
+                multi_draw,
+                ..Default::default()
+            }
+        }
+    }
+
+    pub fn with_features(mut self, features: ShaderFeatures) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn with_reversed_z(mut self, reversed_z: bool) -> Self {
+        self.reversed_z = reversed_z;
+        self
+    }
+
+    /// Draw from the camera of another view than the main window's.
+    pub fn with_view(mut self, view: View) -> Self {
+        self.view = view;
+        self
+    }
+
+    /// Draw from the buffers of `hiz::CulledDraws`, which must be given to the group in
+    /// order.
+    pub fn with_culled_draws(mut self, culled_draws: bool) -> Self {
+        self.culled_draws = culled_draws;
+        self
+    }
+
+    fn bindless(&self) -> bool {
+        self.material_slots > 1
+    }
+}
+
+fn material_textures<B: hal::Backend>(
+    mat_data: &asset::MaterialData<B>,
+) -> [&rendy::texture::Texture<B>; MATERIAL_TEXTURES] {
+    [
+        &mat_data.albedo,
+        &mat_data.normal,
+        &mat_data.metallic_roughness,
+        &mat_data.ao,
+        &mat_data.emissive,
+        &mat_data.bent_normal,
+        &mat_data.height,
+        &mat_data.thickness,
+        &mat_data.anisotropy,
+    ]
+}
+
+/// The uniforms, indirect commands, transforms and light masks of the meshes seen from a view,
+/// which are the same for every shader variant. The groups of the variants drawing a view
+/// share them, and only one of them writes them each frame.
+#[derive(Debug)]
+struct ViewBuffers<B: hal::Backend> {
     uniform_indirect_buffer: Escape<Buffer<B>>,
     transform_buffer: Escape<Buffer<B>>,
+    /// The `components::LightList` of every instance slot, as `u32` masks
+    light_mask_buffer: Escape<Buffer<B>>,
+    settings: Settings,
+    multi_draw: bool,
+    /// Whether every indirect command and transform of a frame's buffers was written
+    uploaded_frames: Vec<bool>,
+    /// The layers the view saw when the transforms were written, see `components::Layers`
+    layers: u32,
+    /// Id of the buffers in `GpuMemoryStats`
+    memory_stats_id: usize,
+    /// Ids of the groups drawing from them
+    users: Vec<usize>,
+    /// Id of the group writing them, `None` once it's disposed until another one prepares
+    writer: Option<usize>,
+}
+
+/// The `ViewBuffers` of each view some mesh pipeline draws.
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct MeshViewBuffers<B: hal::Backend> {
+    views: HashMap<View, ViewBuffers<B>>,
+    /// Id of the next pipeline built
+    next_id: usize,
+}
+
+impl<B: hal::Backend> MeshViewBuffers<B> {
+    /// Registers a pipeline drawing `view`, creating the view's buffers if it's the first.
+    /// Returns the pipeline's id.
+    fn acquire(
+        &mut self,
+        factory: &Factory<B>,
+        world: &specs::World,
+        view: View,
+        multi_draw: bool,
+    ) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(buffers) = self.views.get_mut(&view) {
+            buffers.users.push(id);
+            return id;
+        }
+
+        let frames = world.read_resource::<Aux>().frames;
+        let settings = Settings::from_world::<B>(world);
+        let uniform_indirect_buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.uniform_indirect_buffer_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::UNIFORM | hal::buffer::Usage::INDIRECT,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+        let transform_buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.transform_buffer_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::VERTEX,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+        let light_mask_buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size: settings.light_mask_buffer_frame_size() * frames as u64,
+                    usage: hal::buffer::Usage::VERTEX,
+                },
+                MemoryUsageValue::Dynamic,
+            )
+            .unwrap();
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&uniform_indirect_buffer);
+        memory.add_buffer(&transform_buffer);
+        memory.add_buffer(&light_mask_buffer);
+        let memory_stats_id = world
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline(format!("PBR mesh instances [{:?}]", view), memory);
+
+        self.views.insert(
+            view,
+            ViewBuffers {
+                uniform_indirect_buffer,
+                transform_buffer,
+                light_mask_buffer,
+                settings,
+                multi_draw,
+                uploaded_frames: vec![false; frames],
+                layers: super::view_layers(view, world),
+                memory_stats_id,
+                users: vec![id],
+                writer: Some(id),
+            },
+        );
+        id
+    }
+
+    /// Unregisters a pipeline, destroying the view's buffers if it was the last drawing it.
+    fn release(&mut self, view: View, id: usize, memory_stats: &mut GpuMemoryStats) {
+        let buffers = self
+            .views
+            .get_mut(&view)
+            .expect("Released view buffers missing");
+        buffers.users.retain(|user| *user != id);
+        if buffers.writer == Some(id) {
+            buffers.writer = None;
+        }
+        if buffers.users.is_empty() {
+            memory_stats.remove_pipeline(buffers.memory_stats_id);
+            self.views.remove(&view);
+        }
+    }
+
+    fn get(&self, view: View) -> &ViewBuffers<B> {
+        self.views.get(&view).expect("View buffers missing")
+    }
+}
+
+impl<B: hal::Backend> ViewBuffers<B> {
+    /// Writes the uniforms, and the indirect commands and transforms which changed, to the
+    /// frame's part of the buffers.
+    fn prepare(&mut self, factory: &Factory<B>, index: usize, view: View, world: &specs::World) {
+        if self.settings != Settings::from_world::<B>(world) {
+            unimplemented!();
+        }
+
+        use rendy::memory::Write;
+        use specs::prelude::*;
+
+        let aux = world.read_resource::<Aux>();
+        let frames = world.read_resource::<RenderFrames>();
+        let frame = frames.front();
+        let (n_lights, lights_data) = super::gather_lights(world);
+        let camera_args = super::camera_args(view, world);
+        unsafe {
+            factory
+                .upload_visible_buffer(
+                    &mut self.uniform_indirect_buffer,
+                    self.settings.uniform_offset(index as u64),
+                    &[UniformArgs {
+                        camera: camera_args,
+                        num_lights: n_lights as i32,
+                        lights: lights_data,
+                        occlusion: [
+                            aux.occlusion.diffuse,
+                            aux.occlusion.specular,
+                            aux.occlusion.specular_mode as u32 as f32,
+                            0.0,
+                        ],
+                        fog: super::FogUniform::new(&aux.fog, camera_args.camera_pos.y),
+                        voxel_gi: aux.voxel_gi,
+                        shadow: world
+                            .read_resource::<super::shadow::ShadowCascades>()
+                            .uniform(aux.shadow_cascade_debug && !view.is_cube_face()),
+                        parallax: aux.parallax.uniform(),
+                        // Only the main view averages the reference IBL's frames
+                        ibl: if view == View::Main {
+                            aux.ibl_uniform()
+                        } else {
+                            [0.0, 0.0, 0.0, 1.0]
+                        },
+                    }],
+                )
+                .unwrap()
+        };
+
+        let instance_cache = world.read_resource::<systems::InstanceCache>();
+        let mesh_storage = world.read_resource::<asset::MeshStorage>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
+
+        // Dynamic memory is kept mapped by the allocator for its whole lifetime, so mapping
+        // here is only offset arithmetic. What does cost is writing (and, on non-coherent
+        // memory, flushing) the range, so only the span covering dirty entries is touched.
+        // Only changes are marked dirty, so buffers created after the scene was loaded are
+        // written whole the first time each frame's part is used
+        // Instances outside of the view's layers are written with a zero transform, which
+        // collapses their triangles, so all of them are written again when the layers change
+        let layers = super::view_layers(view, world);
+        if layers != self.layers {
+            self.layers = layers;
+            for uploaded in self.uploaded_frames.iter_mut() {
+                *uploaded = false;
+            }
+        }
+        let full_upload = !self.uploaded_frames[index];
+        self.uploaded_frames[index] = true;
+
+        let arena = world.read_resource::<FrameArena>();
+        let dirty_primitives: &[asset::PrimitiveHandle] = if full_upload {
+            arena.alloc_iter(primitive_storage.0.iter().map(|(primitive, _)| primitive))
+        } else {
+            arena.alloc_iter(
+                instance_cache.dirty_mesh_indirects[index]
+                    .iter()
+                    .filter_map(|dirty_mesh| mesh_storage.0.get(*dirty_mesh))
+                    .flat_map(|mesh| mesh.primitives.iter().cloned()),
+            )
+        };
+
+        if let Some((first, last)) =
+            dirty_span(dirty_primitives.iter().map(|primitive| primitive.index()))
+        {
+            let indirect_offset = self.settings.indirect_offset(index as u64)
+                + self.settings.primitive_indirect_offset(first);
+            let indirect_size = self.settings.primitive_indirect_offset(last + 1)
+                - self.settings.primitive_indirect_offset(first);
+            let mut indirects_mapped = self
+                .uniform_indirect_buffer
+                .map(
+                    factory.device(),
+                    indirect_offset..indirect_offset + indirect_size,
+                )
+                .unwrap();
+            let mut indirects_writer = unsafe {
+                indirects_mapped
+                    .write(factory.device(), 0..indirect_size)
+                    .unwrap()
+            };
+            let indirects_slice = unsafe { indirects_writer.slice() };
+
+            for prim_handle in dirty_primitives.iter() {
+                let primitive = &primitive_storage.0[*prim_handle];
+                let command = DrawIndexedCommand {
+                    index_count: primitive.range.index_count,
+                    instance_count: instance_cache.mesh_instance_counts
+                        [primitive.mesh_handle.index()],
+                    first_index: primitive.range.first_index,
+                    vertex_offset: primitive.range.vertex_offset,
+                    first_instance: self
+                        .settings
+                        .first_instance(primitive.mesh_handle, self.multi_draw),
+                };
+
+                indirects_slice[prim_handle.index() - first] = command;
+            }
+        }
+
+        let settings = &self.settings;
+        let dirty_transform = |instance: &RenderInstance| {
+            let transform = if instance.visible(layers) {
+                instance.transform
+            } else {
+                nalgebra::Matrix4::zeros()
+            };
+            (
+                settings.instance_transform_index(instance.mesh, instance.instance),
+                transform,
+            )
+        };
+        let dirty_transforms: &[(usize, nalgebra::Matrix4<f32>)] = if full_upload {
+            arena.alloc_iter(frame.instances.iter().map(dirty_transform))
+        } else {
+            arena.alloc_iter(
+                (&instance_cache.dirty_entities[index])
+                    .join()
+                    .filter_map(|id| frame.instance(id))
+                    .map(dirty_transform),
+            )
+        };
+
+        if let Some((first, last)) = dirty_span(dirty_transforms.iter().map(|(idx, _)| *idx)) {
+            let model_size = size_of::<Model>() as u64;
+            let transforms_offset =
+                self.settings.transforms_offset(index as u64) + first as u64 * model_size;
+            let transforms_size = (last - first + 1) as u64 * model_size;
+            let mut transforms_mapped = self
+                .transform_buffer
+                .map(
+                    factory.device(),
+                    transforms_offset..transforms_offset + transforms_size,
+                )
+                .unwrap();
+            let mut transforms_writer = unsafe {
+                transforms_mapped
+                    .write(factory.device(), 0..transforms_size)
+                    .unwrap()
+            };
+            let transforms_slice = unsafe { transforms_writer.slice() };
+
+            for (idx, transform) in dirty_transforms.iter() {
+                transforms_slice[*idx - first] = *transform;
+            }
+        }
+
+        // Lights move without their meshes being marked dirty, so every mask is written
+        let light_masks = arena.alloc_fill(settings.total_max_mesh_instances as usize, 0u32);
+        for instance in frame.instances.iter() {
+            light_masks[settings.instance_transform_index(instance.mesh, instance.instance)] =
+                instance.light_mask;
+        }
+        unsafe {
+            factory
+                .upload_visible_buffer(
+                    &mut self.light_mask_buffer,
+                    settings.light_masks_offset(index as u64),
+                    light_masks,
+                )
+                .unwrap();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    descriptor_pool: B::DescriptorPool,
+    /// Id of the pipeline in `MeshViewBuffers`
+    id: usize,
     texture_sampler: Escape<Sampler<B>>,
     static_set: B::DescriptorSet,
+    /// Generation of the environment maps written to the static set
+    env_generation: u64,
     ubo_sets: Vec<B::DescriptorSet>,
     mat_sets: Vec<B::DescriptorSet>,
-    settings: Settings,
+    /// One set per lightmap, then one with the placeholder for meshes without one
+    lightmap_sets: Vec<B::DescriptorSet>,
+    /// The lightmap set of each mesh, `None` for the placeholder
+    mesh_lightmaps: Vec<Option<usize>>,
+    /// Uniform values of every material, only used with descriptor-indexed materials.
+    #[allow(dead_code)]
+    material_buffer: Option<Escape<Buffer<B>>>,
+    bindless: bool,
+    draw_list: Vec<DrawItem>,
+    /// The draw list in runs drawn by a single call, only with multi-draw
+    batches: Vec<DrawBatch>,
+    multi_draw: bool,
+    view: View,
+    /// Names the shader variant in frame captures
+    label: String,
+    /// Id of the pipeline's material buffer in `GpuMemoryStats`
+    memory_stats_id: Option<usize>,
+    /// The indirect commands, transforms and light masks of the instances left by occlusion
+    /// culling, drawn instead of the pipeline's own
+    culled_draws: Option<(Handle<Buffer<B>>, Handle<Buffer<B>>, Handle<Buffer<B>>)>,
+}
+
+/// Where a frame's draws read their indirect commands, and the transforms and light masks
+/// bound from binding 2, with the offsets those of the frame start at.
+struct DrawSource<'a, B: hal::Backend> {
+    settings: &'a Settings,
+    indirect: (&'a B::Buffer, u64),
+    instances: [(&'a B::Buffer, u64); 2],
+}
+
+/// A single primitive draw. The draw list is sorted by `sort_key`, so that consecutive
+/// draws can skip rebinding state they share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DrawItem {
+    material: asset::MaterialHandle,
+    mesh: asset::MeshHandle,
+    primitive: asset::PrimitiveHandle,
+}
+
+impl DrawItem {
+    fn build_list<B: hal::Backend>(
+        primitive_storage: &asset::PrimitiveStorage,
+        material_storage: &asset::MaterialStorage<B>,
+        mesh_lightmaps: &[Option<usize>],
+        features: ShaderFeatures,
+    ) -> Vec<Self> {
+        let material_features = features & ShaderFeatures::MATERIAL;
+        let mut draw_list = primitive_storage
+            .0
+            .iter()
+            .filter(|(_, prim)| material_storage.0[prim.mat].features == material_features)
+            .map(|(primitive, prim)| DrawItem {
+                material: prim.mat,
+                mesh: prim.mesh_handle,
+                primitive,
+            })
+            .collect::<Vec<_>>();
+        draw_list.sort_by_key(|draw| draw.sort_key(mesh_lightmaps[draw.mesh.index()]));
+        draw_list
+    }
+
+    /// Packs the state a draw binds, the most costly to change first: the material in the top
+    /// 16 bits, then the lightmap, then the primitive in the low 32. Primitives of a mesh have
+    /// consecutive handles, so they stay together and in the order of their indirect commands.
+    fn sort_key(&self, lightmap: Option<usize>) -> u64 {
+        let lightmap = lightmap.map_or(0, |lightmap| lightmap + 1);
+        let material = self.material.index();
+        debug_assert!(material <= 0xffff && lightmap <= 0xffff);
+        (material as u64) << 48
+            | (lightmap as u64) << 32
+            | self.primitive.index() as u64 & 0xffff_ffff
+    }
+}
+
+/// Consecutive draws of the draw list sharing their material and lightmap, whose indirect
+/// commands are next to each other, so that they can be drawn with one multi-draw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DrawBatch {
+    material: asset::MaterialHandle,
+    lightmap: Option<usize>,
+    first_primitive: asset::PrimitiveHandle,
+    count: u32,
+}
+
+impl DrawBatch {
+    /// Batches of at most `max_count` draws each.
+    fn build_list(
+        draw_list: &[DrawItem],
+        mesh_lightmaps: &[Option<usize>],
+        max_count: u32,
+    ) -> Vec<Self> {
+        let mut batches: Vec<DrawBatch> = Vec::new();
+        for draw in draw_list {
+            let lightmap = mesh_lightmaps[draw.mesh.index()];
+            match batches.last_mut() {
+                Some(batch)
+                    if batch.material == draw.material
+                        && batch.lightmap == lightmap
+                        && batch.first_primitive.index() + batch.count as usize
+                            == draw.primitive.index()
+                        && batch.count < max_count =>
+                {
+                    batch.count += 1;
+                }
+                _ => batches.push(DrawBatch {
+                    material: draw.material,
+                    lightmap,
+                    first_primitive: draw.primitive,
+                    count: 1,
+                }),
+            }
+        }
+        batches
+    }
+}
+
+/// Returns the first and last index touched by a set of dirty indices, if there are any.
+fn dirty_span<I: Iterator<Item = usize>>(indices: I) -> Option<(usize, usize)> {
+    indices.fold(None, |span, idx| match span {
+        None => Some((idx, idx)),
+        Some((first, last)) => Some((first.min(idx), last.max(idx))),
+    })
 }
 
 #[derive(Debug, PartialEq, Eq)]
-struct Settings {
+pub(super) struct Settings {
     align: u64,
-    num_primitives: usize,
+    pub(super) num_primitives: usize,
     max_mesh_instances: Vec<u16>,
-    total_max_mesh_instances: u64,
+    mesh_transforms_indices: Vec<usize>,
+    pub(super) total_max_mesh_instances: u64,
 }
 
 impl Settings {
     const UNIFORM_SIZE: u64 = size_of::<UniformArgs>() as u64;
 
-    fn from_world<B: hal::Backend>(world: &specs::World) -> Self {
+    pub(super) fn from_world<B: hal::Backend>(world: &specs::World) -> Self {
         let aux = world.read_resource::<Aux>();
 
         let mesh_storage = world.read_resource::<asset::MeshStorage>();
-        let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
 
+        // Empty slots get no instances, but keep the offsets of the meshes after them
         let max_mesh_instances = mesh_storage
             .0
-            .iter()
-            .map(|mesh| mesh.max_instances)
+            .slots()
+            .map(|mesh| mesh.map_or(0, |mesh| mesh.max_instances))
             .collect::<Vec<_>>();
 
         let total_max_mesh_instances = max_mesh_instances.iter().map(|n| *n as u64).sum();
 
+        let mesh_transforms_indices = max_mesh_instances
+            .iter()
+            .scan(0, |index, n| {
+                let mesh_index = *index;
+                *index += *n as usize;
+                Some(mesh_index)
+            })
+            .collect::<Vec<_>>();
+
         Settings {
             align: aux.align,
             num_primitives: primitive_storage.0.len(),
             max_mesh_instances,
+            mesh_transforms_indices,
             total_max_mesh_instances,
         }
     }
@@ -118,6 +778,12 @@ impl Settings {
         ((self.transform_size() - 1) / self.align + 1) * self.align
     }
 
+    #[inline]
+    fn light_mask_buffer_frame_size(&self) -> u64 {
+        ((size_of::<u32>() as u64 * self.total_max_mesh_instances - 1) / self.align + 1)
+            * self.align
+    }
+
     #[inline]
     fn uniform_offset(&self, index: u64) -> u64 {
         self.uniform_indirect_buffer_frame_size() * index as u64
@@ -128,22 +794,35 @@ impl Settings {
         self.transform_buffer_frame_size() * index as u64
     }
 
+    #[inline]
+    fn light_masks_offset(&self, index: u64) -> u64 {
+        self.light_mask_buffer_frame_size() * index as u64
+    }
+
     #[inline]
     fn indirect_offset(&self, index: u64) -> u64 {
         self.uniform_offset(index) + Self::UNIFORM_SIZE
     }
 
     #[inline]
-    fn mesh_transforms_index(&self, mesh_index: usize) -> usize {
-        self.max_mesh_instances[0..mesh_index]
-            .iter()
-            .map(|n| *n as usize)
-            .sum::<usize>()
+    pub(super) fn mesh_transforms_index(&self, mesh: asset::MeshHandle) -> usize {
+        self.mesh_transforms_indices[mesh.index()]
     }
 
     #[inline]
-    fn instance_transform_index(&self, mesh_index: usize, instance: u16) -> usize {
-        self.mesh_transforms_index(mesh_index) + instance as usize
+    pub(super) fn instance_transform_index(&self, mesh: asset::MeshHandle, instance: u16) -> usize {
+        self.mesh_transforms_index(mesh) + instance as usize
+    }
+
+    /// The first instance of the draw commands of a mesh. With multi-draw, the transforms are
+    /// bound once and each mesh's start at its first instance.
+    #[inline]
+    fn first_instance(&self, mesh: asset::MeshHandle, multi_draw: bool) -> u32 {
+        if multi_draw {
+            self.mesh_transforms_index(mesh) as u32
+        } else {
+            0
+        }
     }
 
     #[inline]
@@ -158,6 +837,46 @@ where
 {
     type Pipeline = Pipeline<B>;
 
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        // The subsurface pass writes the scattering radius to alpha, see `subsurface.rs`
+        let blend = if self.features.contains(ShaderFeatures::SUBSURFACE_PASS) {
+            None
+        } else {
+            Some(hal::pso::BlendState::ALPHA)
+        };
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::ALL,
+            blend,
+        }]
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        Some(super::depth_test(self.reversed_z, true))
+    }
+
+    fn buffers(&self) -> Vec<BufferAccess> {
+        if !self.culled_draws {
+            return Vec::new();
+        }
+        vec![
+            BufferAccess {
+                access: hal::buffer::Access::INDIRECT_COMMAND_READ,
+                usage: hal::buffer::Usage::INDIRECT,
+                stages: hal::pso::PipelineStage::DRAW_INDIRECT,
+            },
+            BufferAccess {
+                access: hal::buffer::Access::VERTEX_BUFFER_READ,
+                usage: hal::buffer::Usage::VERTEX,
+                stages: hal::pso::PipelineStage::VERTEX_INPUT,
+            },
+            BufferAccess {
+                access: hal::buffer::Access::VERTEX_BUFFER_READ,
+                usage: hal::buffer::Usage::VERTEX,
+                stages: hal::pso::PipelineStage::VERTEX_INPUT,
+            },
+        ]
+    }
+
     fn layout(&self) -> Layout {
         // Layout to update only once at the beginning
         let static_layout = SetLayout {
@@ -194,6 +913,38 @@ where
                     stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
                     immutable_samplers: false,
                 },
+                // light probe grid
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 4,
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+                // voxel radiance volume
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 5,
+                    ty: hal::pso::DescriptorType::SampledImage,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+                // shadow cascades
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 6,
+                    ty: hal::pso::DescriptorType::SampledImage,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+                // averaged specular albedo
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 7,
+                    ty: hal::pso::DescriptorType::SampledImage,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
             ],
         };
         // Layout to update once per frame
@@ -206,28 +957,39 @@ where
                 immutable_samplers: false,
             }],
         };
-        // SampledImage for each texture map, can reuse same sampler
-        let mut bindings = Vec::with_capacity(4);
-        for i in 0..5 {
+        // CombinedImageSampler array for each texture map, since each texture is sampled
+        // with the sampler settings of the asset it came from
+        let mut bindings = Vec::with_capacity(MATERIAL_TEXTURES + 1);
+        for i in 0..MATERIAL_TEXTURES {
             bindings.push(hal::pso::DescriptorSetLayoutBinding {
-                binding: i,
-                ty: hal::pso::DescriptorType::SampledImage,
-                count: 1,
+                binding: i as u32,
+                ty: hal::pso::DescriptorType::CombinedImageSampler,
+                count: self.material_slots,
                 stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
                 immutable_samplers: false,
             });
         }
         bindings.push(hal::pso::DescriptorSetLayoutBinding {
-            binding: 5,
+            binding: MATERIAL_TEXTURES as u32,
             ty: hal::pso::DescriptorType::UniformBuffer,
             count: 1,
             stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
             immutable_samplers: false,
         });
         let material_layout = SetLayout { bindings };
+        // Layout to update once per mesh
+        let lightmap_layout = SetLayout {
+            bindings: vec![hal::pso::DescriptorSetLayoutBinding {
+                binding: 0,
+                ty: hal::pso::DescriptorType::CombinedImageSampler,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            }],
+        };
         Layout {
-            sets: vec![static_layout, ubo_layout, material_layout],
-            push_constants: Vec::new(),
+            sets: vec![static_layout, ubo_layout, material_layout, lightmap_layout],
+            push_constants: vec![(hal::pso::ShaderStageFlags::FRAGMENT, DrawArgs::RANGE)],
         }
     }
 
@@ -240,7 +1002,17 @@ where
     )> {
         vec![
             PosNormTangTex::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex),
+            TexCoord::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex),
             Model::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Instance(1)),
+            // The instance's `components::LightList`
+            (
+                vec![hal::pso::Element {
+                    format: hal::format::Format::R32Uint,
+                    offset: 0,
+                }],
+                size_of::<u32>() as hal::pso::ElemStride,
+                hal::pso::VertexInputRate::Instance(1),
+            ),
         ]
     }
 
@@ -249,12 +1021,28 @@ where
         factory: &mut Factory<B>,
         _aux: &specs::World,
     ) -> rendy::shader::ShaderSet<B> {
-        SHADERS.build(factory, Default::default()).unwrap()
+        let material_slots = self.material_slots as u32;
+        let mut spec_constants = rendy::shader::SpecConstantSet::default();
+        spec_constants.fragment = Some(hal::pso::Specialization {
+            constants: Cow::from(vec![hal::pso::SpecializationConstant {
+                id: 0,
+                range: 0..4,
+            }]),
+            data: Cow::from(material_slots.to_ne_bytes().to_vec()),
+        });
+        let fragment = FRAGMENT.get(self.features).unwrap();
+        rendy::shader::ShaderSetBuilder::default()
+            .with_vertex(&*VERTEX)
+            .unwrap()
+            .with_fragment(&fragment)
+            .unwrap()
+            .build(factory, spec_constants)
+            .unwrap()
     }
 
     fn build<'a>(
         self,
-        _ctx: &GraphContext<B>,
+        ctx: &GraphContext<B>,
         factory: &mut Factory<B>,
         _queue: QueueId,
         world: &specs::World,
@@ -262,25 +1050,54 @@ where
         images: Vec<NodeImage>,
         set_layouts: &[Handle<DescriptorSetLayout<B>>],
     ) -> Result<Pipeline<B>, hal::pso::CreationError> {
-        assert!(buffers.is_empty());
+        assert_eq!(buffers.len(), if self.culled_draws { 3 } else { 0 });
         assert!(images.is_empty());
-        assert_eq!(set_layouts.len(), 3);
+        assert_eq!(set_layouts.len(), 4);
+
+        let culled_draws = if self.culled_draws {
+            let buffer = |node_buffer: &NodeBuffer| {
+                ctx.get_buffer(node_buffer.id)
+                    .expect("Culled draws buffer missing")
+                    .clone()
+            };
+            Some((
+                buffer(&buffers[0]),
+                buffer(&buffers[1]),
+                buffer(&buffers[2]),
+            ))
+        } else {
+            None
+        };
 
         let aux = world.read_resource::<Aux>();
         let frames = aux.frames;
         let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
         let env_storage = world.read_resource::<super::EnvironmentStorage<B>>();
+        let mesh_storage = world.read_resource::<asset::MeshStorage>();
+        let lightmap_storage = world.read_resource::<asset::LightmapStorage<B>>();
+        let shadow_map = world.read_resource::<super::shadow::ShadowMap<B>>();
 
         let num_mats = material_storage.0.len();
-        let num_env_maps = 3;
+        // The environment maps and voxel volume, then the shadow map
+        let num_static_images = 6;
+        let bindless = self.bindless();
+        let num_mat_sets = if bindless { 1 } else { num_mats };
+        let lightmap_textures = lightmap_storage
+            .lightmaps
+            .iter()
+            .map(|lightmap| lightmap.texture.as_ref())
+            .collect::<Vec<_>>();
+        let num_lightmap_sets = lightmap_textures.len() + 1;
         let mut descriptor_pool = unsafe {
             factory.create_descriptor_pool(
-                // one per material, one per frame for ubo, and one for static set
-                frames + num_mats + 1,
+                // one per material (or one for all of them), one per frame for ubo,
+                // one for static set, and one per lightmap plus the placeholder
+                frames + num_mat_sets + 1 + num_lightmap_sets,
                 vec![
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::UniformBuffer,
-                        count: frames + num_mats,
+                        count: frames + num_mat_sets,
                     },
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::Sampler,
@@ -288,33 +1105,25 @@ where
                     },
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::SampledImage,
-                        count: (num_mats * 5) + (num_env_maps),
+                        count: num_static_images,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::StorageBuffer,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::CombinedImageSampler,
+                        count: num_mats * MATERIAL_TEXTURES + num_lightmap_sets,
                     },
                 ],
                 hal::pso::DescriptorPoolCreateFlags::empty(),
             )?
         };
 
-        let settings = Settings::from_world::<B>(world);
-
-        let uniform_indirect_buffer = factory
-            .create_buffer(
-                BufferInfo {
-                    size: settings.uniform_indirect_buffer_frame_size() * frames as u64,
-                    usage: hal::buffer::Usage::UNIFORM | hal::buffer::Usage::INDIRECT,
-                },
-                MemoryUsageValue::Dynamic,
-            )
-            .unwrap();
-        let transform_buffer = factory
-            .create_buffer(
-                BufferInfo {
-                    size: settings.transform_buffer_frame_size() * frames as u64,
-                    usage: hal::buffer::Usage::VERTEX,
-                },
-                MemoryUsageValue::Dynamic,
-            )
-            .unwrap();
+        let mut view_buffers = world.write_resource::<MeshViewBuffers<B>>();
+        let id = view_buffers.acquire(factory, world, self.view, self.multi_draw);
+        let buffers = view_buffers.get(self.view);
+        let settings = &buffers.settings;
 
         let texture_sampler = factory
             .create_sampler(SamplerDesc::new(Filter::Linear, WrapMode::Clamp))
@@ -329,34 +1138,18 @@ where
                     array_offset: 0,
                     descriptors: Some(hal::pso::Descriptor::Sampler(texture_sampler.raw())),
                 },
+                // Drawn into every frame, but never replaced
                 hal::pso::DescriptorSetWrite {
                     set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.spec_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 2,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.irradiance_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 3,
+                    binding: 6,
                     array_offset: 0,
                     descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.spec_brdf_map.as_ref().unwrap().view().raw(),
+                        shadow_map.view().raw(),
                         hal::image::Layout::ShaderReadOnlyOptimal,
                     )),
                 },
             ]);
+            write_environment_set(factory, &set, &*env_storage);
             set
         };
 
@@ -369,7 +1162,7 @@ where
                     binding: 0,
                     array_offset: 0,
                     descriptors: Some(hal::pso::Descriptor::Buffer(
-                        uniform_indirect_buffer.raw(),
+                        buffers.uniform_indirect_buffer.raw(),
                         Some(settings.uniform_offset(index as u64))
                             ..Some(settings.uniform_offset(index as u64) + Settings::UNIFORM_SIZE),
                     )),
@@ -379,83 +1172,359 @@ where
         }
 
         let mut mat_sets = Vec::new();
+        // Materials are addressed by the index of their handle, so the slots of removed ones
+        // are filled with another material, which nothing draws with
+        let filler_material = material_storage.0.values().next();
+        let mut material_buffer = None;
+
+        if bindless {
+            let mut buffer = factory
+                .create_buffer(
+                    BufferInfo {
+                        size: (size_of::<asset::MaterialUniform>() * num_mats) as u64,
+                        usage: hal::buffer::Usage::UNIFORM,
+                    },
+                    MemoryUsageValue::Dynamic,
+                )
+                .unwrap();
+            let material_uniforms = material_storage
+                .0
+                .slots()
+                .filter_map(|mat_data| mat_data.or(filler_material))
+                .map(|mat_data| asset::MaterialUniform::from(&mat_data.factors))
+                .collect::<Vec<_>>();
+            unsafe {
+                factory
+                    .upload_visible_buffer(&mut buffer, 0, &material_uniforms[..])
+                    .unwrap();
+            }
 
-        for mat_data in material_storage.0.iter() {
             unsafe {
                 let set = descriptor_pool.allocate_set(&set_layouts[2].raw()).unwrap();
-                factory.write_descriptor_sets(vec![
-                    hal::pso::DescriptorSetWrite {
-                        set: &set,
-                        binding: 0,
-                        array_offset: 0,
-                        descriptors: Some(hal::pso::Descriptor::Image(
-                            mat_data.albedo.view().raw(),
-                            hal::image::Layout::ShaderReadOnlyOptimal,
-                        )),
-                    },
-                    hal::pso::DescriptorSetWrite {
-                        set: &set,
-                        binding: 1,
-                        array_offset: 0,
-                        descriptors: Some(hal::pso::Descriptor::Image(
-                            mat_data.normal.view().raw(),
-                            hal::image::Layout::ShaderReadOnlyOptimal,
-                        )),
-                    },
-                    hal::pso::DescriptorSetWrite {
-                        set: &set,
-                        binding: 2,
-                        array_offset: 0,
-                        descriptors: Some(hal::pso::Descriptor::Image(
-                            mat_data.metallic_roughness.view().raw(),
-                            hal::image::Layout::ShaderReadOnlyOptimal,
-                        )),
-                    },
-                    hal::pso::DescriptorSetWrite {
-                        set: &set,
-                        binding: 3,
-                        array_offset: 0,
-                        descriptors: Some(hal::pso::Descriptor::Image(
-                            mat_data.ao.view().raw(),
-                            hal::image::Layout::ShaderReadOnlyOptimal,
-                        )),
-                    },
-                    hal::pso::DescriptorSetWrite {
+                let mut writes = Vec::with_capacity(MATERIAL_TEXTURES + 1);
+                for binding in 0..MATERIAL_TEXTURES {
+                    writes.push(hal::pso::DescriptorSetWrite {
                         set: &set,
-                        binding: 4,
+                        binding: binding as u32,
                         array_offset: 0,
-                        descriptors: Some(hal::pso::Descriptor::Image(
-                            mat_data.emissive.view().raw(),
-                            hal::image::Layout::ShaderReadOnlyOptimal,
-                        )),
-                    },
-                    hal::pso::DescriptorSetWrite {
+                        descriptors: material_storage
+                            .0
+                            .slots()
+                            .filter_map(|mat_data| mat_data.or(filler_material))
+                            .map(|mat_data| {
+                                let texture = material_textures(mat_data)[binding];
+                                hal::pso::Descriptor::CombinedImageSampler(
+                                    texture.view().raw(),
+                                    hal::image::Layout::ShaderReadOnlyOptimal,
+                                    texture.sampler().raw(),
+                                )
+                            })
+                            .collect::<Vec<_>>(),
+                    });
+                }
+                writes.push(hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: MATERIAL_TEXTURES as u32,
+                    array_offset: 0,
+                    descriptors: vec![hal::pso::Descriptor::Buffer(buffer.raw(), None..None)],
+                });
+                factory.write_descriptor_sets(writes);
+                mat_sets.push(set);
+            }
+
+            material_buffer = Some(buffer);
+        } else {
+            for mat_data in material_storage
+                .0
+                .slots()
+                .filter_map(|mat_data| mat_data.or(filler_material))
+            {
+                unsafe {
+                    let set = descriptor_pool.allocate_set(&set_layouts[2].raw()).unwrap();
+                    let mut writes = material_textures(mat_data)
+                        .iter()
+                        .enumerate()
+                        .map(|(binding, texture)| hal::pso::DescriptorSetWrite {
+                            set: &set,
+                            binding: binding as u32,
+                            array_offset: 0,
+                            descriptors: vec![hal::pso::Descriptor::CombinedImageSampler(
+                                texture.view().raw(),
+                                hal::image::Layout::ShaderReadOnlyOptimal,
+                                texture.sampler().raw(),
+                            )],
+                        })
+                        .collect::<Vec<_>>();
+                    writes.push(hal::pso::DescriptorSetWrite {
                         set: &set,
-                        binding: 5,
+                        binding: MATERIAL_TEXTURES as u32,
                         array_offset: 0,
-                        descriptors: Some(hal::pso::Descriptor::Buffer(
-                            mat_data.emissive_factor_buffer.raw(),
+                        descriptors: vec![hal::pso::Descriptor::Buffer(
+                            mat_data.uniform_buffer.raw(),
                             None..None,
-                        )),
-                    },
-                ]);
-                mat_sets.push(set);
+                        )],
+                    });
+                    factory.write_descriptor_sets(writes);
+                    mat_sets.push(set);
+                }
             }
         }
 
+        // Lightmaps without a texture yet get the placeholder, which is never sampled
+        let lightmap_sets = lightmap_textures
+            .iter()
+            .map(|texture| texture.unwrap_or(&lightmap_storage.placeholder))
+            .chain(std::iter::once(&lightmap_storage.placeholder))
+            .map(|texture| unsafe {
+                let set = descriptor_pool.allocate_set(&set_layouts[3].raw()).unwrap();
+                factory.write_descriptor_sets(Some(hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::CombinedImageSampler(
+                        texture.view().raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                        texture.sampler().raw(),
+                    )),
+                }));
+                set
+            })
+            .collect::<Vec<_>>();
+        let mesh_lightmaps = mesh_storage
+            .0
+            .slots()
+            .map(|mesh| {
+                mesh.and_then(|mesh| mesh.lightmap)
+                    .filter(|handle| lightmap_textures[*handle].is_some())
+            })
+            .collect::<Vec<_>>();
+
+        let draw_list = DrawItem::build_list(
+            &*primitive_storage,
+            &*material_storage,
+            &mesh_lightmaps,
+            self.features,
+        );
+        let batches = if self.multi_draw {
+            use hal::adapter::PhysicalDevice;
+
+            let max_count = factory.physical().limits().max_draw_indirect_count;
+            DrawBatch::build_list(&draw_list, &mesh_lightmaps, max_count)
+        } else {
+            Vec::new()
+        };
+
+        let label = format!("PBR meshes [{}]", self.features);
+        let memory_stats_id = material_buffer.as_ref().map(|buffer| {
+            let mut memory = MemoryTotals::default();
+            memory.add_buffer(buffer);
+            world
+                .write_resource::<GpuMemoryStats>()
+                .add_pipeline(label.clone(), memory)
+        });
+
         Ok(Pipeline {
             descriptor_pool,
-            uniform_indirect_buffer,
-            transform_buffer,
+            id,
             texture_sampler,
             static_set,
+            env_generation: env_storage.generation,
             ubo_sets,
             mat_sets,
-            settings,
+            lightmap_sets,
+            mesh_lightmaps,
+            material_buffer,
+            bindless,
+            draw_list,
+            batches,
+            multi_draw: self.multi_draw,
+            view: self.view,
+            label,
+            memory_stats_id,
+            culled_draws,
         })
     }
 }
 
+/// Write the environment maps to bindings 1 to 3 of the static set, the light probes to
+/// binding 4, the voxel radiance volume to binding 5 and the averaged specular albedo to
+/// binding 7.
+unsafe fn write_environment_set<B: hal::Backend>(
+    factory: &Factory<B>,
+    set: &B::DescriptorSet,
+    env_storage: &super::EnvironmentStorage<B>,
+) {
+    factory.write_descriptor_sets(vec![
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 1,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.spec_cube.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 2,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.irradiance_cube.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 3,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.spec_brdf_map.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 4,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Buffer(
+                env_storage.probes.as_ref().unwrap().raw(),
+                None..None,
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 5,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.voxel_radiance.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 7,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.spec_brdf_avg_map.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+    ]);
+}
+
+impl<B: hal::Backend> Pipeline<B> {
+    /// Binds the material of a draw, with its set or its push constant.
+    unsafe fn bind_material(
+        &self,
+        layout: &B::PipelineLayout,
+        encoder: &mut RenderPassEncoder<'_, B>,
+        draw_args: &mut DrawArgs,
+        counts: &mut DrawCounts,
+        material: asset::MaterialHandle,
+    ) {
+        if self.bindless {
+            draw_args.material_index = material.index() as u32;
+            draw_args.push(layout, encoder);
+        } else {
+            encoder.bind_graphics_descriptor_sets(
+                layout,
+                2,
+                Some(&self.mat_sets[material.index()]),
+                std::iter::empty(),
+            );
+            counts.descriptor_binds += 1;
+        }
+    }
+
+    /// Binds a lightmap set, or the placeholder for `None`.
+    unsafe fn bind_lightmap(
+        &self,
+        layout: &B::PipelineLayout,
+        encoder: &mut RenderPassEncoder<'_, B>,
+        draw_args: &mut DrawArgs,
+        counts: &mut DrawCounts,
+        lightmap: Option<usize>,
+    ) {
+        encoder.bind_graphics_descriptor_sets(
+            layout,
+            3,
+            Some(&self.lightmap_sets[lightmap.unwrap_or(self.lightmap_sets.len() - 1)]),
+            std::iter::empty(),
+        );
+        counts.descriptor_binds += 1;
+        let has_lightmap = lightmap.is_some() as u32;
+        if draw_args.lightmap != has_lightmap {
+            draw_args.lightmap = has_lightmap;
+            draw_args.push(layout, encoder);
+        }
+    }
+
+    /// Draws each primitive with its own call, rebinding the transforms and light masks for
+    /// every mesh, when the device can't multi-draw.
+    fn draw_each(
+        &self,
+        layout: &B::PipelineLayout,
+        encoder: &mut RenderPassEncoder<'_, B>,
+        instance_cache: &systems::InstanceCache,
+        draw_args: &mut DrawArgs,
+        counts: &mut DrawCounts,
+        source: DrawSource<'_, B>,
+    ) {
+        let (indirect, indirect_offset) = source.indirect;
+        let instance_buffers = source.instances;
+        let mut bound_material = None;
+        let mut bound_mesh = None;
+        for draw in self.draw_list.iter() {
+            if instance_cache.mesh_instance_counts[draw.mesh.index()] == 0 {
+                continue;
+            }
+            if bound_material != Some(draw.material) {
+                unsafe {
+                    self.bind_material(layout, encoder, draw_args, counts, draw.material);
+                }
+                bound_material = Some(draw.material);
+            }
+            if bound_mesh != Some(draw.mesh) {
+                unsafe {
+                    let lightmap = self.mesh_lightmaps[draw.mesh.index()];
+                    self.bind_lightmap(layout, encoder, draw_args, counts, lightmap);
+                    let first = source.settings.mesh_transforms_index(draw.mesh) as u64;
+                    let (transforms, transforms_offset) = instance_buffers[0];
+                    let (light_masks, light_masks_offset) = instance_buffers[1];
+                    encoder.bind_vertex_buffers(
+                        2,
+                        vec![
+                            (
+                                transforms,
+                                transforms_offset + first * size_of::<Model>() as u64,
+                            ),
+                            (
+                                light_masks,
+                                light_masks_offset + first * size_of::<u32>() as u64,
+                            ),
+                        ],
+                    );
+                }
+                counts.vertex_buffer_binds += 1;
+                bound_mesh = Some(draw.mesh);
+            }
+            unsafe {
+                encoder.draw_indexed_indirect(
+                    indirect,
+                    indirect_offset
+                        + source
+                            .settings
+                            .primitive_indirect_offset(draw.primitive.index()),
+                    1,
+                    size_of::<DrawIndexedCommand>() as u32,
+                );
+            }
+            counts.draw_calls += 1;
+            counts.draws += 1;
+        }
+    }
+}
+
 impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
 where
     B: hal::Backend,
@@ -470,117 +1539,25 @@ where
         index: usize,
         world: &specs::World,
     ) -> PrepareResult {
-        if self.settings != Settings::from_world::<B>(world) {
-            unimplemented!();
-        }
-
-        use rendy::memory::Write;
-        use specs::{prelude::*, storage::UnprotectedStorage};
-
-        let lights = world.read_storage::<components::Light>();
-        let transforms = world.read_storage::<components::GlobalTransform>();
-
-        let mut n_lights = 0;
-        let mut lights_data = [Default::default(); crate::MAX_LIGHTS];
-        for (light, transform) in (&lights, &transforms).join() {
-            if n_lights >= crate::MAX_LIGHTS {
-                break;
+        let _scope = crate::profile::scope("MeshPipeline::prepare");
+        let env_storage = world.read_resource::<super::EnvironmentStorage<B>>();
+        if self.env_generation != env_storage.generation {
+            unsafe {
+                write_environment_set(factory, &self.static_set, &*env_storage);
             }
-
-            lights_data[n_lights] = super::LightData {
-                pos: nalgebra::Point3::from(transform.0.column(3).xyz()),
-                color: light.color,
-                intensity: light.intensity,
-                _pad: 0f32,
-            };
-
-            n_lights += 1;
+            self.env_generation = env_storage.generation;
         }
-        let cameras = world.read_storage::<components::Camera>();
-        let active_cameras = world.read_storage::<components::ActiveCamera>();
-        let camera_args: CameraArgs = (&active_cameras, &cameras, &transforms)
-            .join()
-            .map(|(_, cam, trans)| (cam, trans).into())
-            .next()
-            .expect("No active camera!");
-        unsafe {
-            factory
-                .upload_visible_buffer(
-                    &mut self.uniform_indirect_buffer,
-                    self.settings.uniform_offset(index as u64),
-                    &[UniformArgs {
-                        camera: camera_args,
-                        num_lights: n_lights as i32,
-                        lights: lights_data,
-                    }],
-                )
-                .unwrap()
-        };
-
-        let instance_cache = world.read_resource::<systems::InstanceCache>();
-        let mesh_storage = world.read_resource::<asset::MeshStorage>();
-        let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
-
-        let indirect_offset = self.settings.indirect_offset(index as u64);
-        let indirect_size = self.settings.indirect_size();
-        let indirect_end = indirect_offset + indirect_size;
-        {
-            let mut indirects_mapped = self
-                .uniform_indirect_buffer
-                .map(factory.device(), indirect_offset..indirect_end)
-                .unwrap();
-            let mut indirects_writer = unsafe {
-                indirects_mapped
-                    .write(factory.device(), 0..indirect_size)
-                    .unwrap()
-            };
-            let indirects_slice = unsafe { indirects_writer.slice() };
 
-            for dirty_mesh in instance_cache.dirty_mesh_indirects[index].iter() {
-                for prim_index in mesh_storage.0[*dirty_mesh].primitives.iter() {
-                    let command = DrawIndexedCommand {
-                        index_count: primitive_storage.0[*prim_index].mesh_data.len(),
-                        instance_count: instance_cache.mesh_instance_counts[*dirty_mesh],
-                        first_index: 0,
-                        vertex_offset: 0,
-                        first_instance: 0,
-                    };
-
-                    indirects_slice[*prim_index] = command;
-                }
-            }
+        let mut view_buffers = world.write_resource::<MeshViewBuffers<B>>();
+        let buffers = view_buffers
+            .views
+            .get_mut(&self.view)
+            .expect("View buffers missing");
+        if buffers.writer.is_none() {
+            buffers.writer = Some(self.id);
         }
-
-        let mesh_instance_storage = world.read_resource::<systems::MeshInstanceStorage>();
-        let entities = world.entities();
-
-        let transforms_offset = self.settings.transforms_offset(index as u64);
-        let transforms_size = self.settings.transform_size();
-        let transforms_end = transforms_offset + self.settings.transform_size();
-        {
-            let mut transforms_mapped = self
-                .transform_buffer
-                .map(factory.device(), transforms_offset..transforms_end)
-                .unwrap();
-            let mut transforms_writer = unsafe {
-                transforms_mapped
-                    .write(factory.device(), 0..transforms_size)
-                    .unwrap()
-            };
-            let transforms_slice = unsafe { transforms_writer.slice() };
-
-            for (entity, transform, _) in (
-                &entities,
-                &transforms,
-                &instance_cache.dirty_entities[index],
-            )
-                .join()
-            {
-                let systems::MeshInstance { mesh, instance } =
-                    unsafe { mesh_instance_storage.0.get(entity.id()) };
-                let idx = self.settings.instance_transform_index(*mesh, *instance);
-                transforms_slice[idx] = transform.0;
-            }
+        if buffers.writer == Some(self.id) {
+            buffers.prepare(factory, index, self.view, world);
         }
 
         PrepareResult::DrawRecord
@@ -593,53 +1570,151 @@ where
         index: usize,
         world: &specs::World,
     ) {
-        let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
+        let _scope = crate::profile::scope("MeshPipeline::draw");
+        let geometry_pool = world.read_resource::<GeometryPool<B>>();
+        let instance_cache = world.read_resource::<systems::InstanceCache>();
+        let instances: u32 = self
+            .draw_list
+            .iter()
+            .map(|draw| instance_cache.mesh_instance_counts[draw.mesh.index()])
+            .sum();
+        let mut counts = DrawCounts {
+            pipeline_binds: 1,
+            descriptor_binds: 1,
+            ..Default::default()
+        };
         unsafe {
+            super::begin_marker(
+                &mut encoder,
+                &format!("{}: {} primitive instances", self.label, instances),
+            );
             encoder.bind_graphics_descriptor_sets(
                 layout,
                 0,
                 vec![&self.static_set, &self.ubo_sets[index]],
                 std::iter::empty(),
             );
+            if self.bindless {
+                // Every material lives in the same set, so it only needs binding once
+                encoder.bind_graphics_descriptor_sets(
+                    layout,
+                    2,
+                    Some(&self.mat_sets[0]),
+                    std::iter::empty(),
+                );
+                counts.descriptor_binds += 1;
+            }
         }
-        let transforms_offset = self.settings.transforms_offset(index as u64);
-        let indirect_offset = self.settings.indirect_offset(index as u64);
-        for (mat_idx, set) in self.mat_sets.iter().enumerate() {
+        let aux = world.read_resource::<Aux>();
+        let split = aux.split_view.enabled && self.view == View::Main;
+        let mut draw_args = DrawArgs {
+            material_index: 0,
+            debug_view: aux.debug_view as u32,
+            lightmap: 0,
+            split_debug_view: if split {
+                aux.split_view.debug_view
+            } else {
+                aux.debug_view
+            } as u32,
+            split_x: aux.split_view.divider_pixel(),
+        };
+        unsafe {
+            draw_args.push(layout, &mut encoder);
+        }
+        let view_buffers = world.read_resource::<MeshViewBuffers<B>>();
+        let buffers = view_buffers.get(self.view);
+        let settings = &buffers.settings;
+        let indirect_offset = settings.indirect_offset(index as u64);
+        // Every primitive's vertices and indices are in the pool, found by their draw command
+        geometry_pool.bind(0, true, &mut encoder);
+        counts.vertex_buffer_binds += 1;
+        let (indirect, indirect_offset) = match &self.culled_draws {
+            Some((indirect, _, _)) => (indirect.raw(), 0),
+            None => (buffers.uniform_indirect_buffer.raw(), indirect_offset),
+        };
+        // The transforms and light masks, bound together from binding 2
+        let instance_buffers = match &self.culled_draws {
+            Some((_, transforms, light_masks)) => [(transforms.raw(), 0), (light_masks.raw(), 0)],
+            None => [
+                (
+                    buffers.transform_buffer.raw(),
+                    settings.transforms_offset(index as u64),
+                ),
+                (
+                    buffers.light_mask_buffer.raw(),
+                    settings.light_masks_offset(index as u64),
+                ),
+            ],
+        };
+        if self.multi_draw {
+            // Each draw command's first instance is where its mesh's transforms start
             unsafe {
-                encoder.bind_graphics_descriptor_sets(layout, 2, Some(set), std::iter::empty());
+                encoder.bind_vertex_buffers(2, instance_buffers.iter().cloned());
             }
-            for (prim_idx, primitive) in primitive_storage
-                .0
-                .iter()
-                .enumerate()
-                .filter(|(_, primitive)| primitive.mat == mat_idx)
-            {
-                assert!(primitive
-                    .mesh_data
-                    .bind(0, &[PosNormTangTex::vertex()], &mut encoder)
-                    .is_ok());
+            counts.vertex_buffer_binds += 1;
+            let mut bound_material = None;
+            let mut bound_lightmap = None;
+            for batch in self.batches.iter() {
                 unsafe {
-                    encoder.bind_vertex_buffers(
-                        1,
-                        std::iter::once((
-                            self.transform_buffer.raw(),
-                            transforms_offset
-                                + self.settings.mesh_transforms_index(primitive.mesh_handle) as u64
-                                    * size_of::<Model>() as u64,
-                        )),
-                    );
+                    if bound_material != Some(batch.material) {
+                        self.bind_material(
+                            layout,
+                            &mut encoder,
+                            &mut draw_args,
+                            &mut counts,
+                            batch.material,
+                        );
+                        bound_material = Some(batch.material);
+                    }
+                    if bound_lightmap != Some(batch.lightmap) {
+                        self.bind_lightmap(
+                            layout,
+                            &mut encoder,
+                            &mut draw_args,
+                            &mut counts,
+                            batch.lightmap,
+                        );
+                        bound_lightmap = Some(batch.lightmap);
+                    }
                     encoder.draw_indexed_indirect(
-                        self.uniform_indirect_buffer.raw(),
-                        indirect_offset + self.settings.primitive_indirect_offset(prim_idx),
-                        1,
+                        indirect,
+                        indirect_offset
+                            + settings.primitive_indirect_offset(batch.first_primitive.index()),
+                        batch.count,
                         size_of::<DrawIndexedCommand>() as u32,
                     );
                 }
+                counts.draw_calls += 1;
+                counts.draws += batch.count;
             }
+        } else {
+            self.draw_each(
+                layout,
+                &mut encoder,
+                &instance_cache,
+                &mut draw_args,
+                &mut counts,
+                DrawSource {
+                    settings,
+                    indirect: (indirect, indirect_offset),
+                    instances: instance_buffers,
+                },
+            );
+        }
+        world.write_resource::<DrawStats>().current += counts;
+        unsafe {
+            super::end_marker(&mut encoder);
         }
     }
 
-    fn dispose(mut self, factory: &mut Factory<B>, _world: &specs::World) {
+    fn dispose(mut self, factory: &mut Factory<B>, world: &specs::World) {
+        let mut memory_stats = world.write_resource::<GpuMemoryStats>();
+        if let Some(id) = self.memory_stats_id {
+            memory_stats.remove_pipeline(id);
+        }
+        world
+            .write_resource::<MeshViewBuffers<B>>()
+            .release(self.view, self.id, &mut memory_stats);
         unsafe {
             self.descriptor_pool.reset();
             factory.destroy_descriptor_pool(self.descriptor_pool);
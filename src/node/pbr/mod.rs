@@ -1,9 +1,21 @@
-use crate::components;
+use crate::{
+    components,
+    render_frame::{RenderFrame, RenderFrames, RenderView},
+};
 use derivative::Derivative;
 use rendy::hal;
 
+pub mod accumulate;
+pub mod billboard;
+pub mod capture;
 pub mod environment_map;
+pub mod grid;
+pub mod hiz;
+pub mod lines;
 pub mod mesh;
+pub mod scopes;
+pub mod shadow;
+pub mod subsurface;
 pub mod tonemap;
 
 #[derive(Debug, Clone, Copy)]
@@ -17,31 +29,435 @@ pub struct CameraArgs {
 impl From<(&components::Camera, &components::GlobalTransform)> for CameraArgs {
     fn from((cam, trans): (&components::Camera, &components::GlobalTransform)) -> Self {
         CameraArgs {
-            proj: cam.proj.to_homogeneous(),
+            proj: cam.projection(),
             view: trans.0.try_inverse().unwrap(),
             camera_pos: nalgebra::Point3::from(trans.0.column(3).xyz()),
         }
     }
 }
 
+/// Cameras looking out of the six faces of a cube, in the order of cube map layers, which
+/// pipelines built for a cube face draw from. Replaced before each render of a cube.
+#[derive(Debug, Default)]
+pub struct CubeCameras(pub Vec<CameraArgs>);
+
+/// What a pipeline draws the scene for, which decides the camera it draws from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Derivative)]
+#[derivative(Default)]
+pub enum View {
+    /// The main window, from the scene's active camera
+    #[derivative(Default)]
+    Main,
+    /// A face of a cube map being captured, from the camera of that face in `CubeCameras`
+    CubeFace(usize),
+    /// The debug window, from the camera marked with `DebugWindowCamera`
+    DebugWindow,
+}
+
+impl View {
+    pub fn is_cube_face(self) -> bool {
+        match self {
+            View::CubeFace(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Opens a labelled region of commands, which frame debuggers like RenderDoc group the
+/// commands recorded until `end_marker` under, so that captures can be navigated by pass.
+pub unsafe fn begin_marker<B: hal::Backend, C>(
+    encoder: &mut rendy::command::EncoderCommon<'_, B, C>,
+    label: &str,
+) {
+    use hal::command::RawCommandBuffer;
+    encoder.raw().begin_debug_marker(label, 0);
+}
+
+pub unsafe fn end_marker<B: hal::Backend, C>(
+    encoder: &mut rendy::command::EncoderCommon<'_, B, C>,
+) {
+    use hal::command::RawCommandBuffer;
+    encoder.raw().end_debug_marker();
+}
+
+/// The camera of a view in the extracted frame, where the debug window falls back to the
+/// main view if no camera is marked for it. Cube faces have no camera of their own there.
+fn render_view(view: View, frame: &RenderFrame) -> Option<RenderView> {
+    match view {
+        View::Main | View::CubeFace(_) => frame.main_view,
+        View::DebugWindow => frame.debug_view.or(frame.main_view),
+    }
+}
+
+/// The camera a pipeline draws from, see `View`. The debug window falls back to the
+/// active camera if no camera is marked for it.
+pub fn camera_args(view: View, world: &specs::World) -> CameraArgs {
+    match view {
+        View::CubeFace(face) => world.read_resource::<CubeCameras>().0[face],
+        _ => {
+            let frames = world.read_resource::<RenderFrames>();
+            render_view(view, frames.front())
+                .expect("No active camera!")
+                .camera
+        }
+    }
+}
+
+/// The layers of the entities seen from a view, see `components::Layers`. Cube faces are
+/// captured with the layers of the main view.
+pub fn view_layers(view: View, world: &specs::World) -> u32 {
+    let frames = world.read_resource::<RenderFrames>();
+    render_view(view, frames.front()).map_or(components::Layers::ALL, |view| view.layers)
+}
+
+/// The subpass drawing the scene into an HDR target: the environment, then each PBR shader
+/// variant, then what is blended over them. Built for a cube face, the scene is drawn from
+/// that face's camera and the grid, debug lines and light billboards are left out. Overlay
+/// lines are only drawn in the main window, last. With `culled_draws`, the meshes of the main
+/// view are drawn from what the occlusion culling node found visible.
+pub fn scene_subpass<B: hal::Backend>(
+    factory: &rendy::factory::Factory<B>,
+    shader_variants: &[crate::shader::ShaderFeatures],
+    num_materials: usize,
+    reversed_z: bool,
+    view: View,
+    culled_draws: Option<&hiz::CulledDraws>,
+) -> rendy::graph::render::SubpassBuilder<B, specs::World> {
+    use rendy::graph::render::{RenderGroupBuilder, SimpleGraphicsPipelineDesc};
+
+    let mut subpass = environment_map::PipelineDesc::default()
+        .with_reversed_z(reversed_z)
+        .with_view(view)
+        .builder()
+        .into_subpass();
+    for features in shader_variants {
+        if view == View::Main {
+            log::info!("Using PBR shader variant: {}", features);
+        }
+        let desc = mesh::PipelineDesc::new(factory, num_materials)
+            .with_features(*features)
+            .with_reversed_z(reversed_z)
+            .with_view(view);
+        subpass = match culled_draws.filter(|_| view == View::Main) {
+            Some(culled_draws) => subpass.with_group(
+                desc.with_culled_draws(true)
+                    .builder()
+                    .with_buffer(culled_draws.indirect)
+                    .with_buffer(culled_draws.transforms)
+                    .with_buffer(culled_draws.light_masks),
+            ),
+            None => subpass.with_group(desc.builder()),
+        };
+    }
+
+    // Drawn after the meshes, since they are blended over them
+    if view.is_cube_face() {
+        return subpass.with_group(
+            billboard::PipelineDesc::particles()
+                .with_reversed_z(reversed_z)
+                .with_view(view)
+                .builder(),
+        );
+    }
+    let subpass = subpass
+        .with_group(
+            grid::PipelineDesc::default()
+                .with_reversed_z(reversed_z)
+                .with_view(view)
+                .builder(),
+        )
+        .with_group(
+            lines::PipelineDesc::default()
+                .with_reversed_z(reversed_z)
+                .with_view(view)
+                .builder(),
+        )
+        .with_group(
+            billboard::PipelineDesc::billboards()
+                .with_reversed_z(reversed_z)
+                .with_view(view)
+                .builder(),
+        )
+        .with_group(
+            billboard::PipelineDesc::particles()
+                .with_reversed_z(reversed_z)
+                .with_view(view)
+                .builder(),
+        );
+    if view == View::Main {
+        subpass.with_group(lines::PipelineDesc::overlay().builder())
+    } else {
+        subpass
+    }
+}
+
+/// The depth test of the pipelines drawn in the mesh subpass. With reversed-Z the near plane
+/// is at depth 1 and the far plane at 0, so nearer fragments have a greater depth.
+pub fn depth_test(reversed_z: bool, write: bool) -> hal::pso::DepthStencilDesc {
+    hal::pso::DepthStencilDesc {
+        depth: Some(hal::pso::DepthTest {
+            fun: if reversed_z {
+                hal::pso::Comparison::GreaterEqual
+            } else {
+                hal::pso::Comparison::LessEqual
+            },
+            write,
+        }),
+        depth_bounds: false,
+        stencil: None,
+    }
+}
+
 #[derive(Debug, Derivative, Clone, Copy)]
 #[derivative(Default)]
 #[repr(C)]
 pub struct LightData {
+    /// Position of a point light, or direction towards a directional light
     #[derivative(Default(value = "nalgebra::Point3::<f32>::origin()"))]
     pub pos: nalgebra::Point3<f32>,
+    /// Luminous intensity in candela, or illuminance in lux for a directional light
     pub intensity: f32,
     pub color: [f32; 3],
-    pub _pad: f32,
+    /// 1.0 for a directional light, 0.0 for a point light
+    pub directional: f32,
+}
+
+impl LightData {
+    pub fn new(light: &components::Light, transform: &components::GlobalTransform) -> Self {
+        let directional = light.intensity.is_directional();
+        LightData {
+            pos: if directional {
+                nalgebra::Point3::from(transform.0.column(2).xyz().normalize())
+            } else {
+                nalgebra::Point3::from(transform.0.column(3).xyz())
+            },
+            color: light.color,
+            intensity: light.intensity.shader_value(),
+            directional: if directional { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+/// The lights of the extracted frame as the shaders read them, and how many there are.
+/// Lights past `MAX_LIGHTS` are left out.
+pub fn gather_lights(world: &specs::World) -> (usize, [LightData; crate::MAX_LIGHTS]) {
+    let frames = world.read_resource::<RenderFrames>();
+    let lights = &frames.front().lights;
+    let mut lights_data = [Default::default(); crate::MAX_LIGHTS];
+    lights_data[..lights.len()].copy_from_slice(lights);
+    (lights.len(), lights_data)
 }
 
 #[derive(Derivative)]
 #[derivative(Default(bound = ""))]
 pub struct EnvironmentStorage<B: hal::Backend> {
-    pub env_cube: Option<rendy::texture::Texture<B>>,
+    pub env_cube: Option<crate::node::env_preprocess::cube_map::CubeMap<B>>,
     pub irradiance_cube: Option<rendy::texture::Texture<B>>,
     pub spec_cube: Option<rendy::texture::Texture<B>>,
     pub spec_brdf_map: Option<rendy::texture::Texture<B>>,
+    /// The specular albedo averaged over view angles, for each roughness, which scales the
+    /// multiple scattering lobe
+    pub spec_brdf_avg_map: Option<rendy::texture::Texture<B>>,
+    /// The baked light probe grid, see `crate::probes`
+    pub probes: Option<rendy::resource::Escape<rendy::resource::Buffer<B>>>,
+    /// The radiance volume written by `crate::voxel_gi`, a single black voxel without it
+    pub voxel_radiance: Option<rendy::texture::Texture<B>>,
+    /// Incremented whenever the cube maps, probes or voxel volume are replaced, so that nodes
+    /// holding descriptors of the old ones know to rewrite them.
+    pub generation: u64,
+}
+
+impl<B: hal::Backend> EnvironmentStorage<B> {
+    /// A black 1x1 cube map, used in place of the environment maps while they are filtered.
+    pub fn placeholder_cube(
+        factory: &mut rendy::factory::Factory<B>,
+        queue: rendy::command::QueueId,
+    ) -> Result<rendy::texture::Texture<B>, failure::Error> {
+        Ok(rendy::texture::TextureBuilder::new()
+            .with_kind(rendy::resource::Kind::D2(1, 1, 6, 1))
+            .with_view_kind(rendy::resource::ViewKind::Cube)
+            .with_data_width(1)
+            .with_data_height(1)
+            .with_data(vec![
+                rendy::texture::pixel::Rgba32Sfloat {
+                    repr: [0.0, 0.0, 0.0, 1.0]
+                };
+                6
+            ])
+            .build(
+                rendy::factory::ImageState {
+                    queue,
+                    stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                    access: hal::image::Access::SHADER_READ,
+                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                },
+                factory,
+            )?)
+    }
+}
+
+/// How strongly ambient occlusion attenuates image based lighting, from 0.0 (not at all)
+/// to 1.0. Applied on top of each material's own occlusion strength.
+#[derive(Debug, Derivative, Clone, Copy, serde::Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct OcclusionSettings {
+    #[derivative(Default(value = "1.0"))]
+    pub diffuse: f32,
+    #[derivative(Default(value = "1.0"))]
+    pub specular: f32,
+    /// How the ambient occlusion is turned into occlusion of the environment reflections
+    pub specular_mode: SpecularOcclusion,
+}
+
+/// Where the occlusion of the environment reflections comes from. Applying the ambient
+/// occlusion to them as is darkens glancing and glossy reflections too much, while not
+/// occluding them at all makes reflections leak into crevices.
+#[derive(Debug, Derivative, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[derivative(Default)]
+pub enum SpecularOcclusion {
+    /// The ambient occlusion, as for diffuse light
+    Ao,
+    /// Derived from the ambient occlusion, the view angle and the roughness
+    #[derivative(Default)]
+    Lagarde,
+    /// The intersection of the visible cone around the material's bent normal with the
+    /// reflection lobe. Materials without a bent normal texture fall back to `Lagarde`.
+    BentNormal,
+}
+
+impl SpecularOcclusion {
+    const ALL: [SpecularOcclusion; 3] = [
+        SpecularOcclusion::Ao,
+        SpecularOcclusion::Lagarde,
+        SpecularOcclusion::BentNormal,
+    ];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+}
+
+/// Quality of parallax occlusion mapping, for materials with a height texture. The view ray
+/// is marched through the height field in more steps the more grazing it is.
+#[derive(Debug, Derivative, Clone, Copy, serde::Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ParallaxSettings {
+    /// Steps when looking straight at the surface
+    #[derivative(Default(value = "8"))]
+    pub min_steps: u32,
+    /// Steps at grazing angles. Zero disables parallax occlusion mapping.
+    #[derivative(Default(value = "32"))]
+    pub max_steps: u32,
+}
+
+impl ParallaxSettings {
+    /// Layout of the `parallax_steps` vector in `pbr.frag`
+    pub fn uniform(&self) -> [f32; 4] {
+        let max_steps = self.max_steps.min(MAX_PARALLAX_STEPS);
+        [
+            self.min_steps.min(max_steps) as f32,
+            max_steps as f32,
+            0.0,
+            0.0,
+        ]
+    }
+}
+
+/// Most steps `pbr.frag` marches a height field in, whatever the settings.
+pub const MAX_PARALLAX_STEPS: u32 = 128;
+
+/// How the environment lights meshes in the main view. Cycled at runtime with F8, or with
+/// alt held for the right side of the split view.
+#[derive(Debug, Derivative, Clone, Copy, PartialEq, Eq)]
+#[derivative(Default)]
+#[repr(u32)]
+pub enum IblMode {
+    /// The split sum approximation, from the irradiance and prefiltered specular cube maps
+    /// and the specular BRDF map
+    #[derivative(Default)]
+    SplitSum = 0,
+    /// Ground truth to validate the split sum against: the environment is sampled per pixel
+    /// with the same BRDF as the lights, and frames are averaged while the camera is still,
+    /// see `accumulate`. Only the environment map is sampled, without probes, lightmaps or
+    /// voxel cones, and clearcoat still uses the split sum.
+    Reference = 1,
+}
+
+impl IblMode {
+    const ALL: [IblMode; 2] = [IblMode::SplitSum, IblMode::Reference];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+}
+
+/// Sampling of `IblMode::Reference`.
+#[derive(Debug, Derivative, Clone, Copy, serde::Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct IblReferenceSettings {
+    /// Environment samples per pixel each frame, alternating between the diffuse and
+    /// specular lobes
+    #[derivative(Default(value = "8"))]
+    pub samples_per_frame: u32,
+    /// Frames averaged before the image is left as it is
+    #[derivative(Default(value = "4096"))]
+    pub max_frames: u32,
+}
+
+/// Exponential height fog and aerial perspective, applied to meshes by distance and to the
+/// environment map background as if it were far away.
+#[derive(Debug, Derivative, Clone, Copy, serde::Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct FogSettings {
+    /// Fog density at `base_height`, per world unit. Zero disables height fog.
+    pub density: f32,
+    /// How quickly the fog thins out above `base_height`, per world unit
+    #[derivative(Default(value = "0.2"))]
+    pub height_falloff: f32,
+    pub base_height: f32,
+    /// Color of the light scattered in by fog and aerial perspective
+    #[derivative(Default(value = "[0.5, 0.6, 0.7]"))]
+    pub color: [f32; 3],
+    /// Maximum opacity of the height fog, so the horizon doesn't disappear completely
+    #[derivative(Default(value = "1.0"))]
+    pub max_opacity: f32,
+    /// Strength of aerial perspective, which scatters in more blue than red with distance,
+    /// per world unit. Zero disables it.
+    pub aerial_perspective: f32,
+}
+
+/// Fog parameters in the layout used by the shaders.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct FogUniform {
+    /// Fog color in `rgb`, maximum opacity in `a`
+    pub color: [f32; 4],
+    /// Density at the camera's height, height falloff and aerial perspective, `w` is unused
+    pub params: [f32; 4],
+}
+
+impl FogUniform {
+    pub fn new(settings: &FogSettings, camera_height: f32) -> Self {
+        FogUniform {
+            color: [
+                settings.color[0],
+                settings.color[1],
+                settings.color[2],
+                settings.max_opacity,
+            ],
+            params: [
+                settings.density
+                    * (-settings.height_falloff * (camera_height - settings.base_height)).exp(),
+                settings.height_falloff,
+                settings.aerial_perspective,
+                0.0,
+            ],
+        }
+    }
 }
 
 #[derive(Default)]
@@ -51,4 +467,75 @@ pub struct Aux {
     pub tonemapper_args: tonemap::TonemapperArgs,
     pub cube_display: environment_map::CubeDisplay,
     pub cube_roughness: f32,
+    /// When set, the displayed cube map is sampled at exactly this mip, to inspect each one
+    pub cube_mip: Option<u8>,
+    /// Number of mips of the environment cube map, which depends on how it was loaded
+    pub env_cube_mip_levels: u8,
+    pub debug_view: mesh::DebugView,
+    pub split_view: tonemap::SplitView,
+    pub scopes: scopes::ScopesMode,
+    /// The optional passes the graph should have. The graph is rebuilt once they differ from
+    /// those it was built with.
+    pub render_settings: crate::scene::RenderSettings,
+    /// Whether the material debug views are compiled into the shaders, which also rebuilds
+    /// the graph when changed
+    pub shader_debug_views: bool,
+    pub occlusion: OcclusionSettings,
+    pub parallax: ParallaxSettings,
+    pub fog: FogSettings,
+    pub background: environment_map::Background,
+    pub grid: grid::GridSettings,
+    /// Set to capture a panorama of the scene after the next frame
+    pub capture_panorama: bool,
+    /// Set to save the main view before tonemapping after the next frame
+    pub capture_screenshot: bool,
+    /// Set to capture the next frame with RenderDoc, with the `rd` feature
+    pub capture_frame: bool,
+    /// Set to bake the light probe grid again after the next frame
+    pub bake_probes: bool,
+    /// Set to unload the meshes and materials no entity uses anymore, see `asset_gc`
+    pub collect_assets: bool,
+    pub voxel_gi: crate::voxel_gi::VoxelGiUniform,
+    /// Tints fragments by the shadow cascade they sample and outlines the cascades
+    pub shadow_cascade_debug: bool,
+    /// Stops fitting the shadow cascades to the camera, to look at them from elsewhere
+    pub shadow_cascades_frozen: bool,
+    /// Image based lighting of the main view, left of the split view's divider
+    pub ibl_mode: IblMode,
+    pub ibl_reference: IblReferenceSettings,
+    /// Frames averaged so far while `IblMode::Reference` is shown, which also seeds its samples
+    pub ibl_reference_frames: u32,
+}
+
+impl Aux {
+    /// Whether either side of the main view uses `IblMode::Reference`, which needs its frames
+    /// averaged by the `accumulate` node
+    pub fn ibl_accumulation(&self) -> bool {
+        self.ibl_mode == IblMode::Reference
+            || (self.split_view.enabled && self.split_view.ibl_mode == IblMode::Reference)
+    }
+
+    /// Layout of the `ibl` vector in `pbr.frag` for the main view
+    pub fn ibl_uniform(&self) -> [f32; 4] {
+        let right = if self.split_view.enabled {
+            self.split_view.ibl_mode
+        } else {
+            self.ibl_mode
+        };
+        [
+            self.ibl_mode as u32 as f32,
+            right as u32 as f32,
+            self.ibl_reference_frames as f32,
+            self.ibl_reference.samples_per_frame.max(1) as f32,
+        ]
+    }
+
+    /// Number of mips of the cube map selected by `cube_display`
+    pub fn cube_mip_levels(&self) -> u8 {
+        match self.cube_display {
+            environment_map::CubeDisplay::Environment => self.env_cube_mip_levels,
+            environment_map::CubeDisplay::Irradiance => 1,
+            environment_map::CubeDisplay::Specular => crate::SPEC_CUBEMAP_MIP_LEVELS,
+        }
+    }
 }
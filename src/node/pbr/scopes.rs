@@ -0,0 +1,675 @@
+//! Scopes drawn over the tonemapped image to judge exposure and clipping while tuning the
+//! tonemapper: a histogram of the luminance of the HDR image and a waveform of its red, green
+//! and blue channels across its width. A compute node bins the HDR image every frame they
+//! are shown, and a pipeline in the tonemap subpass draws the bins in the top left corner.
+//!
+//! Values are binned by their log2, so that the bins don't depend on the exposure, which is
+//! only applied when they are drawn. The scopes span 12 stops below the value the exposure
+//! saturates to 4 stops above it, with what is over it marked in red.
+use derivative::Derivative;
+
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, ExecutableState, Families, Family, FamilyId, Fence, MultiShot,
+        PendingState, Queue, QueueId, RenderPassEncoder, SimultaneousUse, Submission, Submit,
+    },
+    factory::Factory,
+    frame::Frames,
+    graph::{
+        gfx_acquire_barriers, gfx_release_barriers, render::*, BufferAccess, BufferId, DynNode,
+        GraphBuilder, GraphContext, ImageAccess, ImageId, NodeBuffer, NodeBuildError, NodeBuilder,
+        NodeId, NodeImage,
+    },
+    hal::{device::Device, pso::DescriptorPool},
+    resource::{
+        DescriptorSetLayout, Escape, Filter, Handle, ImageView, ImageViewInfo, Sampler,
+        SamplerDesc, ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use std::mem::size_of;
+
+use crate::node::pbr::Aux;
+
+lazy_static::lazy_static! {
+    static ref CLEAR: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/scopes_clear.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref BIN: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/scopes.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/fullscreen_triangle.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/scopes.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*FRAGMENT).unwrap();
+}
+
+/// Number of luminance bins of the histogram. Must match the scopes shaders.
+pub const HISTOGRAM_BINS: u32 = 128;
+/// Number of columns of the image the waveform is binned in. Must match the scopes shaders.
+pub const WAVEFORM_COLUMNS: u32 = 256;
+/// Number of bins of each channel in a waveform column. Must match the scopes shaders.
+pub const WAVEFORM_ROWS: u32 = 128;
+
+/// Size of the buffer of bins: the histogram, then the waveform by column, row and channel.
+const BUFFER_WORDS: u32 = HISTOGRAM_BINS + WAVEFORM_COLUMNS * WAVEFORM_ROWS * 3;
+
+/// Matches `local_size_x` of `scopes_clear.comp`, and both sizes of `scopes.comp`.
+const CLEAR_GROUP_SIZE: u32 = 64;
+const BIN_GROUP_SIZE: u32 = 8;
+
+/// Which scopes are drawn. Cycled at runtime with the H key.
+#[derive(Debug, Derivative, Clone, Copy, PartialEq, Eq)]
+#[derivative(Default)]
+#[repr(u32)]
+pub enum ScopesMode {
+    #[derivative(Default)]
+    Off = 0,
+    Histogram = 1,
+    Waveform = 2,
+    Both = 3,
+}
+
+impl ScopesMode {
+    pub fn next(self) -> Self {
+        match self {
+            ScopesMode::Off => ScopesMode::Histogram,
+            ScopesMode::Histogram => ScopesMode::Waveform,
+            ScopesMode::Waveform => ScopesMode::Both,
+            ScopesMode::Both => ScopesMode::Off,
+        }
+    }
+}
+
+/// Adds the node binning `hdr` into a new buffer of scopes, which the overlay pipeline must
+/// be given. The node runs after `dependency`, which draws `hdr`.
+pub fn add_scopes_node<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, specs::World>,
+    description: &mut crate::graph_dump::GraphDescription,
+    hdr: ImageId,
+    family: FamilyId,
+    dependency: NodeId,
+) -> (NodeId, BufferId) {
+    let scopes = description.create_buffer(
+        builder,
+        "scope_bins",
+        BUFFER_WORDS as u64 * size_of::<u32>() as u64,
+    );
+    let node = description.add_node(
+        builder,
+        "scopes",
+        ScopesNode::builder(hdr, scopes, family).with_dependency(dependency),
+    );
+    (node, scopes)
+}
+
+#[derive(Debug)]
+pub struct ScopesNode<B: hal::Backend> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+    descriptor_pool: B::DescriptorPool,
+    set_layout: B::DescriptorSetLayout,
+    pipeline_layout: B::PipelineLayout,
+    clear_pipeline: B::ComputePipeline,
+    bin_pipeline: B::ComputePipeline,
+    image_sampler: Escape<Sampler<B>>,
+    image_view: Escape<ImageView<B>>,
+}
+
+impl<B: hal::Backend> ScopesNode<B> {
+    pub fn builder(hdr: ImageId, scopes: BufferId, family: FamilyId) -> ScopesNodeBuilder {
+        ScopesNodeBuilder {
+            hdr,
+            scopes,
+            family,
+            dependencies: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ScopesNodeBuilder {
+    hdr: ImageId,
+    scopes: BufferId,
+    family: FamilyId,
+    dependencies: Vec<NodeId>,
+}
+
+impl ScopesNodeBuilder {
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn with_dependency(mut self, dependency: NodeId) -> Self {
+        self.dependencies.push(dependency);
+        self
+    }
+}
+
+impl<B> NodeBuilder<B, specs::World> for ScopesNodeBuilder
+where
+    B: hal::Backend,
+{
+    fn family(&self, _factory: &mut Factory<B>, _families: &Families<B>) -> Option<FamilyId> {
+        Some(self.family)
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        vec![(
+            self.scopes,
+            BufferAccess {
+                access: hal::buffer::Access::SHADER_READ | hal::buffer::Access::SHADER_WRITE,
+                usage: hal::buffer::Usage::STORAGE,
+                stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+            },
+        )]
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![(
+            self.hdr,
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+            },
+        )]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        _aux: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, specs::World>>, NodeBuildError> {
+        assert_eq!(buffers.len(), 1);
+        assert_eq!(images.len(), 1);
+
+        let image_handle = ctx
+            .get_image(images[0].id)
+            .expect("Scopes HDR image missing");
+        let extent = image_handle.kind().extent();
+        let image_view = factory
+            .create_image_view(
+                image_handle.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format: image_handle.format(),
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[0].range.clone(),
+                },
+            )
+            .expect("Could not create scopes input image view");
+        let image_sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+            .unwrap();
+        let scopes = ctx
+            .get_buffer(buffers[0].id)
+            .expect("Scopes buffer missing");
+
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory
+                .device()
+                .create_descriptor_set_layout(
+                    vec![
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 0,
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 1,
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 2,
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                    ],
+                    std::iter::empty::<B::Sampler>(),
+                )
+                .unwrap();
+            let pipeline_layout = factory
+                .device()
+                .create_pipeline_layout(
+                    Some(&set_layout),
+                    std::iter::empty::<(hal::pso::ShaderStageFlags, std::ops::Range<u32>)>(),
+                )
+                .unwrap();
+            (set_layout, pipeline_layout)
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    1,
+                    vec![
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: 1,
+                        },
+                    ],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layout).unwrap();
+            factory.write_descriptor_sets(vec![
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Sampler(image_sampler.raw())),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        image_view.raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 2,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Buffer(scopes.raw(), None..None)),
+                },
+            ]);
+            set
+        };
+
+        let (clear_pipeline, bin_pipeline) = unsafe {
+            (
+                create_pipeline(factory, &pipeline_layout, &*CLEAR).unwrap(),
+                create_pipeline(factory, &pipeline_layout, &*BIN).unwrap(),
+            )
+        };
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+        unsafe {
+            super::begin_marker(&mut encoder, "Scopes binning");
+            let (stages, barriers) = gfx_acquire_barriers(ctx, buffers.iter(), images.iter());
+            if !barriers.is_empty() {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+            }
+
+            encoder.bind_compute_descriptor_sets(
+                &pipeline_layout,
+                0,
+                Some(&set),
+                std::iter::empty(),
+            );
+
+            encoder.bind_compute_pipeline(&clear_pipeline);
+            encoder.dispatch([
+                (BUFFER_WORDS + CLEAR_GROUP_SIZE - 1) / CLEAR_GROUP_SIZE,
+                1,
+                1,
+            ]);
+            let written = hal::buffer::Access::SHADER_WRITE
+                ..hal::buffer::Access::SHADER_READ | hal::buffer::Access::SHADER_WRITE;
+            encoder.pipeline_barrier(
+                hal::pso::PipelineStage::COMPUTE_SHADER..hal::pso::PipelineStage::COMPUTE_SHADER,
+                hal::memory::Dependencies::empty(),
+                Some(hal::memory::Barrier::Buffer {
+                    states: written,
+                    families: None,
+                    target: scopes.raw(),
+                    range: None..None,
+                }),
+            );
+
+            encoder.bind_compute_pipeline(&bin_pipeline);
+            encoder.dispatch([
+                (extent.width + BIN_GROUP_SIZE - 1) / BIN_GROUP_SIZE,
+                (extent.height + BIN_GROUP_SIZE - 1) / BIN_GROUP_SIZE,
+                1,
+            ]);
+
+            let (stages, barriers) = gfx_release_barriers(ctx, buffers.iter(), images.iter());
+            if !barriers.is_empty() {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+            }
+            super::end_marker(&mut encoder);
+        }
+
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        Ok(Box::new(ScopesNode {
+            pool,
+            submit,
+            buffer,
+            descriptor_pool,
+            set_layout,
+            pipeline_layout,
+            clear_pipeline,
+            bin_pipeline,
+            image_sampler,
+            image_view,
+        }))
+    }
+}
+
+impl<B> DynNode<B, specs::World> for ScopesNode<B>
+where
+    B: hal::Backend,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        aux: &specs::World,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("ScopesNode::run");
+        // Still waits and signals when the scopes are hidden, for the nodes around it
+        let shown = aux.read_resource::<Aux>().scopes != ScopesMode::Off;
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(if shown { Some(&self.submit) } else { None })
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, _aux: &specs::World) {
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+        factory
+            .device()
+            .destroy_compute_pipeline(self.clear_pipeline);
+        factory.device().destroy_compute_pipeline(self.bin_pipeline);
+        factory
+            .device()
+            .destroy_pipeline_layout(self.pipeline_layout);
+        factory
+            .device()
+            .destroy_descriptor_set_layout(self.set_layout);
+    }
+}
+
+unsafe fn create_pipeline<B: hal::Backend>(
+    factory: &Factory<B>,
+    layout: &B::PipelineLayout,
+    shader: &PathBufShaderInfo,
+) -> Result<B::ComputePipeline, failure::Error> {
+    let module = factory.device().create_shader_module(&shader.spirv()?)?;
+    let pipeline = factory.device().create_compute_pipeline(
+        &hal::pso::ComputePipelineDesc::new(
+            hal::pso::EntryPoint {
+                entry: "main",
+                module: &module,
+                specialization: hal::pso::Specialization::default(),
+            },
+            layout,
+        ),
+        None,
+    );
+    factory.device().destroy_shader_module(module);
+    Ok(pipeline?)
+}
+
+/// Push constants of the overlay.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct OverlayArgs {
+    exposure_ev100: f32,
+    mode: u32,
+    /// Number of pixels of the binned image, which the bins are normalized by
+    pixels: u32,
+}
+
+impl OverlayArgs {
+    /// Push constant range, in 32-bit words.
+    const RANGE: std::ops::Range<u32> = 0..(size_of::<OverlayArgs>() / 4) as u32;
+
+    fn as_words(&self) -> [u32; 3] {
+        [self.exposure_ev100.to_bits(), self.mode, self.pixels]
+    }
+}
+
+/// Draws the bins of the scopes node over the tonemapped image.
+#[derive(Debug)]
+pub struct PipelineDesc {
+    pixels: u32,
+}
+
+impl PipelineDesc {
+    /// Draws the bins of an image of `width` by `height` pixels.
+    pub fn new(width: u32, height: u32) -> Self {
+        PipelineDesc {
+            pixels: width * height,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    set: B::DescriptorSet,
+    pool: B::DescriptorPool,
+    pixels: u32,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn buffers(&self) -> Vec<BufferAccess> {
+        vec![BufferAccess {
+            access: hal::buffer::Access::SHADER_READ,
+            usage: hal::buffer::Usage::STORAGE,
+            stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
+        }]
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        None
+    }
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::ALL,
+            blend: Some(hal::pso::BlendState::ALPHA),
+        }]
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _world: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        Layout {
+            sets: vec![SetLayout {
+                bindings: vec![hal::pso::DescriptorSetLayoutBinding {
+                    binding: 0,
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                }],
+            }],
+            push_constants: vec![(hal::pso::ShaderStageFlags::FRAGMENT, OverlayArgs::RANGE)],
+        }
+    }
+
+    fn build<'a>(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        _world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.len() == 1);
+        assert!(images.is_empty());
+        assert!(set_layouts.len() == 1);
+
+        let scopes = ctx
+            .get_buffer(buffers[0].id)
+            .expect("Scopes buffer missing");
+
+        let mut pool = unsafe {
+            factory.create_descriptor_pool(
+                1,
+                vec![hal::pso::DescriptorRangeDesc {
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                }],
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )?
+        };
+
+        let set = unsafe {
+            let set = pool.allocate_set(&set_layouts[0].raw()).unwrap();
+            factory.write_descriptor_sets(vec![hal::pso::DescriptorSetWrite {
+                set: &set,
+                binding: 0,
+                array_offset: 0,
+                descriptors: Some(hal::pso::Descriptor::Buffer(scopes.raw(), None..None)),
+            }]);
+            set
+        };
+
+        Ok(Pipeline {
+            set,
+            pool,
+            pixels: self.pixels,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        _factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        _index: usize,
+        _world: &specs::World,
+    ) -> PrepareResult {
+        // Recorded every frame, since the exposure pushed to the overlay changes at any time
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        _index: usize,
+        world: &specs::World,
+    ) {
+        let _scope = crate::profile::scope("ScopesPipeline::draw");
+        let aux = world.read_resource::<Aux>();
+        if aux.scopes == ScopesMode::Off {
+            return;
+        }
+
+        let args = OverlayArgs {
+            exposure_ev100: aux.tonemapper_args.exposure_ev100,
+            mode: aux.scopes as u32,
+            pixels: self.pixels,
+        };
+        unsafe {
+            super::begin_marker(&mut encoder, "Scopes overlay");
+            encoder.bind_graphics_descriptor_sets(layout, 0, Some(&self.set), std::iter::empty());
+            encoder.push_constants(
+                layout,
+                hal::pso::ShaderStageFlags::FRAGMENT,
+                OverlayArgs::RANGE.start,
+                &args.as_words(),
+            );
+            encoder.draw(0..3, 0..1);
+            super::end_marker(&mut encoder);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, _aux: &specs::World) {
+        unsafe {
+            self.pool.reset();
+            factory.destroy_descriptor_pool(self.pool);
+        }
+    }
+}
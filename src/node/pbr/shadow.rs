@@ -0,0 +1,716 @@
+//! Cascaded shadow maps for the first directional light that casts shadows. The view is split
+//! into up to `MAX_CASCADES` slices by distance from the camera, and each slice is covered by
+//! an orthographic depth render from the light, copied into a layer of the `ShadowMap`.
+//!
+//! Meshes marked `components::Static` are drawn into a cache of each cascade, only when the
+//! cascade moves or a static mesh changes. Every frame the cache is copied into the cascade's
+//! depth and only the moving meshes are drawn over it.
+use rendy::{
+    command::{Families, FamilyId, QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{render::*, GraphBuilder, GraphContext, NodeBuffer, NodeId, NodeImage},
+    memory::MemoryUsageValue,
+    mesh::{AsVertex, PosNormTangTex},
+    resource::{
+        DescriptorSetLayout, Escape, Handle, Image, ImageInfo, ImageView, ImageViewInfo, ViewKind,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use crate::{
+    asset, components,
+    draw_stats::{DrawCounts, DrawStats},
+    frame_arena::FrameArena,
+    geometry_pool::GeometryPool,
+    node::pbr::capture::{CopyFromLayer, CopyToLayer},
+    render_frame::RenderFrames,
+    scene::Quality,
+};
+
+/// The most cascades a light can split the view into, the size of the shader's arrays.
+pub const MAX_CASCADES: usize = 4;
+
+/// Colors the cascades are tinted and outlined with in the cascade debug view, nearest first.
+/// Must match `CASCADE_COLORS` in `pbr.frag`.
+pub const CASCADE_COLORS: [[f32; 4]; MAX_CASCADES] = [
+    [1.0, 0.25, 0.25, 1.0],
+    [0.25, 1.0, 0.25, 1.0],
+    [0.25, 0.5, 1.0, 1.0],
+    [1.0, 1.0, 0.25, 1.0],
+];
+
+/// How far each cascade's depth range reaches towards the light from the center of the slice
+/// it covers, in radii of the slice's bounding sphere. Casters further out cast no shadow.
+const CASTER_RADII: f32 = 3.0;
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/shadow.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap();
+}
+
+/// One slice of the view and the light's projection covering it.
+#[derive(Debug, Clone, Copy)]
+pub struct Cascade {
+    /// Projection and view of the light, from world space to the cascade's layer
+    pub view_proj: nalgebra::Matrix4<f32>,
+    /// Corners of the slice of the camera's frustum, the four on its near side first
+    pub corners: [nalgebra::Point3<f32>; 8],
+}
+
+/// The cascades of the shadowed light for the current frame, written by the
+/// `ShadowCascadeSystem`.
+#[derive(Debug, Default)]
+pub struct ShadowCascades {
+    /// Index of the shadowed light in the lights passed to the shaders, see `gather_lights`
+    pub light: Option<usize>,
+    pub settings: components::ShadowSettings,
+    pub cascades: Vec<Cascade>,
+    /// Changes whenever a static mesh is added, removed or moved, so their cached shadows are
+    /// drawn again
+    pub static_generation: u64,
+}
+
+/// Shadow parameters in the layout used by the shaders.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ShadowUniform {
+    pub view_proj: [nalgebra::Matrix4<f32>; MAX_CASCADES],
+    /// Number of cascades, depth bias, normal bias and filter radius in texels
+    pub params: [f32; 4],
+    /// Index of the shadowed light or -1, and whether fragments are tinted by cascade.
+    /// `zw` are unused
+    pub light: [i32; 4],
+}
+
+impl ShadowCascades {
+    /// The uniform values for these cascades, tinting fragments by the cascade they sample
+    /// when `debug` is set.
+    pub fn uniform(&self, debug: bool) -> ShadowUniform {
+        let mut view_proj = [nalgebra::Matrix4::identity(); MAX_CASCADES];
+        for (matrix, cascade) in view_proj.iter_mut().zip(self.cascades.iter()) {
+            *matrix = cascade.view_proj;
+        }
+        ShadowUniform {
+            view_proj,
+            params: [
+                self.cascades.len() as f32,
+                self.settings.depth_bias,
+                self.settings.normal_bias,
+                self.settings.pcf_radius,
+            ],
+            light: [
+                match self.light {
+                    Some(index) if !self.cascades.is_empty() => index as i32,
+                    _ => -1,
+                },
+                debug as i32,
+                0,
+                0,
+            ],
+        }
+    }
+}
+
+/// Corners of the part of the camera's frustum between distances `near` and `far`, in world
+/// space.
+fn slice_corners(
+    camera: &components::Camera,
+    camera_transform: &nalgebra::Matrix4<f32>,
+    near: f32,
+    far: f32,
+) -> [nalgebra::Point3<f32>; 8] {
+    let tan_half_fov = (camera.fov / 2.0).tan();
+    let mut corners = [nalgebra::Point3::origin(); 8];
+    for (i, distance) in [near, far].iter().enumerate() {
+        let height = distance * tan_half_fov;
+        let width = height * camera.aspect;
+        let quad = [
+            (-width, -height),
+            (width, -height),
+            (width, height),
+            (-width, height),
+        ];
+        for (j, (x, y)) in quad.iter().enumerate() {
+            let corner = nalgebra::Point3::new(*x, *y, -distance);
+            corners[i * 4 + j] =
+                nalgebra::Point3::from_homogeneous(camera_transform * corner.to_homogeneous())
+                    .unwrap();
+        }
+    }
+    corners
+}
+
+/// An orthographic projection of the box `half_size` wide in each direction around the view
+/// axis, between `near` and `far`, to depths from 0 to 1.
+fn orthographic(half_size: f32, near: f32, far: f32) -> nalgebra::Matrix4<f32> {
+    #[rustfmt::skip]
+    let proj = nalgebra::Matrix4::new(
+        1.0 / half_size, 0.0, 0.0, 0.0,
+        0.0, 1.0 / half_size, 0.0, 0.0,
+        0.0, 0.0, -1.0 / (far - near), -near / (far - near),
+        0.0, 0.0, 0.0, 1.0,
+    );
+    proj
+}
+
+/// Splits the view of `camera` into cascades and fits a projection from the light to each.
+/// Splits are spaced between evenly and logarithmically by the `split_lambda` of `settings`,
+/// up to its `max_distance`. `direction` points towards the light.
+pub fn fit_cascades(
+    camera: &components::Camera,
+    camera_transform: &nalgebra::Matrix4<f32>,
+    direction: &nalgebra::Vector3<f32>,
+    settings: &components::ShadowSettings,
+) -> Vec<Cascade> {
+    let count = (settings.cascades as usize).max(1).min(MAX_CASCADES);
+    let near = camera.znear;
+    let far = if camera.infinite_far {
+        settings.max_distance
+    } else {
+        camera.zfar.min(settings.max_distance)
+    }
+    .max(near * 2.0);
+
+    let up = if direction.y.abs() > 0.99 {
+        nalgebra::Vector3::x()
+    } else {
+        nalgebra::Vector3::y()
+    };
+    // Only rotates into the light's space, to snap the cascades to its texels
+    let rotation = nalgebra::Matrix4::look_at_rh(
+        &nalgebra::Point3::origin(),
+        &nalgebra::Point3::from(-direction),
+        &up,
+    );
+
+    let mut slice_near = near;
+    (1..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let even = near + (far - near) * t;
+            let logarithmic = near * (far / near).powf(t);
+            let split = even + (logarithmic - even) * settings.split_lambda;
+            let corners = slice_corners(camera, camera_transform, slice_near, split);
+            slice_near = split;
+
+            let center = corners
+                .iter()
+                .fold(nalgebra::Vector3::zeros(), |sum, corner| {
+                    sum + corner.coords
+                })
+                / 8.0;
+            // Rounded up so that the cascade, and with it the size of its texels, only
+            // changes size in steps as the camera turns
+            let radius = corners
+                .iter()
+                .map(|corner| (corner.coords - center).norm())
+                .fold(0.0, f32::max);
+            let radius = (radius * 16.0).ceil() / 16.0;
+
+            // Moving the cascade by whole texels keeps the edges of shadows from crawling
+            // as the camera moves
+            let texel = 2.0 * radius / settings.resolution.max(1) as f32;
+            let mut light_center = (rotation * center.to_homogeneous()).xyz();
+            light_center.x = (light_center.x / texel).floor() * texel;
+            light_center.y = (light_center.y / texel).floor() * texel;
+            let center = (rotation.transpose() * light_center.to_homogeneous()).xyz();
+
+            let eye = center + direction * radius * CASTER_RADII;
+            let view = nalgebra::Matrix4::look_at_rh(
+                &nalgebra::Point3::from(eye),
+                &nalgebra::Point3::from(center),
+                &up,
+            );
+            Cascade {
+                view_proj: orthographic(radius, 0.0, radius * (CASTER_RADII + 1.0)) * view,
+                corners,
+            }
+        })
+        .collect()
+}
+
+/// The first light that is directional and casts shadows, with its index in the lights
+/// passed to the shaders. Other lights' shadow settings are ignored.
+pub fn shadowed_light<'a>(
+    lights: &'a specs::ReadStorage<'_, components::Light>,
+    transforms: &'a specs::ReadStorage<'_, components::GlobalTransform>,
+) -> Option<(
+    usize,
+    &'a components::Light,
+    &'a components::GlobalTransform,
+)> {
+    use specs::prelude::*;
+
+    (lights, transforms)
+        .join()
+        .take(crate::MAX_LIGHTS)
+        .enumerate()
+        .find(|(_, (light, _))| light.shadow.enabled && light.intensity.is_directional())
+        .map(|(index, (light, transform))| (index, light, transform))
+}
+
+/// The depth of every cascade of the shadowed light, one layer each. Without a shadowed
+/// light it is a single unused texel.
+#[derive(Debug)]
+pub struct ShadowMap<B: hal::Backend> {
+    image: Handle<Image<B>>,
+    view: Escape<ImageView<B>>,
+    /// The depth of only the static meshes of every cascade, copied under the others
+    static_image: Handle<Image<B>>,
+    /// The cascade projection and `static_generation` each layer of `static_image` was last
+    /// drawn with, `None` until it's first drawn
+    static_cached: Vec<Option<(nalgebra::Matrix4<f32>, u64)>>,
+    /// Whether the static meshes of each cascade are drawn in the current frame, and so copied
+    /// into `static_image`
+    static_redrawn: Vec<bool>,
+    resolution: u32,
+    cascades: usize,
+}
+
+/// The largest resolution and most cascades of the shadow map at `quality`.
+fn quality_limits(quality: Quality) -> (u32, usize) {
+    match quality {
+        Quality::Low => (512, 2),
+        Quality::Medium => (1024, 3),
+        Quality::High => (u32::max_value(), MAX_CASCADES),
+    }
+}
+
+impl<B: hal::Backend> ShadowMap<B> {
+    /// Sized for the shadowed light of the scene as loaded into `world`, within the limits of
+    /// `quality`. Without a quality, or a shadowed light, there are no cascades. The layers are
+    /// left undefined until the shadow passes first draw them.
+    pub fn new(
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        queue: QueueId,
+        world: &specs::World,
+        quality: Option<Quality>,
+    ) -> Result<Self, failure::Error> {
+        use specs::prelude::*;
+
+        // Global transforms are only added by the first dispatch, so the light is found
+        // by its settings alone
+        let lights = world.read_storage::<components::Light>();
+        let settings = lights
+            .join()
+            .find(|light| light.shadow.enabled && light.intensity.is_directional())
+            .map(|light| light.shadow);
+        let (resolution, cascades) = match (settings, quality) {
+            (Some(settings), Some(quality)) => {
+                let (max_resolution, max_cascades) = quality_limits(quality);
+                (
+                    settings.resolution.min(max_resolution).max(1),
+                    (settings.cascades as usize).max(1).min(max_cascades),
+                )
+            }
+            _ => (1, 0),
+        };
+        let layers = cascades.max(1) as u16;
+
+        let info = ImageInfo {
+            kind: hal::image::Kind::D2(resolution, resolution, layers, 1),
+            levels: 1,
+            format: hal::format::Format::D32Sfloat,
+            tiling: hal::image::Tiling::Optimal,
+            view_caps: hal::image::ViewCapabilities::empty(),
+            usage: hal::image::Usage::SAMPLED | hal::image::Usage::TRANSFER_DST,
+        };
+        let image: Handle<Image<B>> = factory
+            .create_image(info.clone(), MemoryUsageValue::Data)?
+            .into();
+        // Kept in the same layout as the shadow map between copies, though it's never sampled
+        let static_image: Handle<Image<B>> = factory
+            .create_image(
+                ImageInfo {
+                    usage: info.usage | hal::image::Usage::TRANSFER_SRC,
+                    ..info
+                },
+                MemoryUsageValue::Data,
+            )?
+            .into();
+        let range = hal::image::SubresourceRange {
+            aspects: hal::format::Aspects::DEPTH,
+            levels: 0..1,
+            layers: 0..layers,
+        };
+        let view = factory.create_image_view(
+            image.clone(),
+            ImageViewInfo {
+                view_kind: ViewKind::D2Array,
+                format: hal::format::Format::D32Sfloat,
+                swizzle: hal::format::Swizzle::NO,
+                range: range.clone(),
+            },
+        )?;
+
+        unsafe {
+            crate::upload::submit_barriers(
+                factory,
+                families,
+                queue,
+                hal::pso::PipelineStage::TOP_OF_PIPE..hal::pso::PipelineStage::FRAGMENT_SHADER,
+                vec![image.raw(), static_image.raw()]
+                    .into_iter()
+                    .map(|target| hal::memory::Barrier::Image {
+                        states: (hal::image::Access::empty(), hal::image::Layout::Undefined)
+                            ..(
+                                hal::image::Access::SHADER_READ,
+                                hal::image::Layout::ShaderReadOnlyOptimal,
+                            ),
+                        families: None,
+                        target,
+                        range: range.clone(),
+                    })
+                    .collect::<Vec<_>>(),
+            )?;
+        }
+
+        if cascades > 0 {
+            log::info!(
+                "Rendering {} shadow cascades of {}x{}",
+                cascades,
+                resolution,
+                resolution
+            );
+        }
+
+        Ok(ShadowMap {
+            image,
+            view,
+            static_image,
+            static_cached: vec![None; cascades],
+            static_redrawn: vec![false; cascades],
+            resolution,
+            cascades,
+        })
+    }
+
+    pub fn view(&self) -> &ImageView<B> {
+        &self.view
+    }
+
+    /// Number of cascades rendered into the map, 0 without a shadowed light
+    pub fn cascades(&self) -> usize {
+        self.cascades
+    }
+
+    /// Whether the static meshes of `cascade` have to be drawn again for its projection, and
+    /// if so marks them as drawn with it.
+    fn redraw_static(
+        &mut self,
+        cascade: usize,
+        view_proj: nalgebra::Matrix4<f32>,
+        static_generation: u64,
+    ) -> bool {
+        let key = Some((view_proj, static_generation));
+        let redraw = self.static_cached[cascade] != key;
+        if redraw {
+            self.static_cached[cascade] = key;
+        }
+        self.static_redrawn[cascade] = redraw;
+        redraw
+    }
+}
+
+/// Adds the depth passes for each cascade of `shadow_map`, each copied into its layer. Passes
+/// sampling the shadow map must depend on all of the returned nodes.
+///
+/// The static meshes are drawn into their own depth, copied into the cache only in the frames
+/// they're drawn. The cache is then copied into the depth the moving meshes are drawn into.
+pub fn add_shadow_nodes<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, specs::World>,
+    description: &mut crate::graph_dump::GraphDescription,
+    shadow_map: &ShadowMap<B>,
+    family: FamilyId,
+) -> Vec<NodeId> {
+    let kind = hal::image::Kind::D2(shadow_map.resolution, shadow_map.resolution, 1, 1);
+    (0..shadow_map.cascades)
+        .map(|cascade| {
+            let static_depth = description.create_image(
+                builder,
+                &format!("shadow_static_depth_{}", cascade),
+                kind,
+                1,
+                hal::format::Format::D32Sfloat,
+                Some(hal::command::ClearValue {
+                    depth_stencil: hal::command::ClearDepthStencil {
+                        depth: 1.0,
+                        stencil: 0,
+                    },
+                }),
+            );
+            let static_pass = description.add_node(
+                builder,
+                &format!("shadow_static_{}", cascade),
+                PipelineDesc {
+                    cascade,
+                    casters: Casters::Static,
+                }
+                .builder()
+                .into_subpass()
+                .with_depth_stencil(static_depth)
+                .into_pass(),
+            );
+            let static_copy = description.add_node(
+                builder,
+                &format!("shadow_static_copy_{}", cascade),
+                CopyToLayer::builder(
+                    static_depth,
+                    shadow_map.static_image.clone(),
+                    cascade as u16,
+                    family,
+                )
+                .with_condition(move |world: &specs::World| {
+                    world.read_resource::<ShadowMap<B>>().static_redrawn[cascade]
+                })
+                .with_dependency(static_pass),
+            );
+
+            // Entirely overwritten by the cache, so never cleared
+            let depth = description.create_image(
+                builder,
+                &format!("shadow_depth_{}", cascade),
+                kind,
+                1,
+                hal::format::Format::D32Sfloat,
+                None,
+            );
+            let restore = description.add_node(
+                builder,
+                &format!("shadow_restore_{}", cascade),
+                CopyFromLayer::builder(
+                    shadow_map.static_image.clone(),
+                    cascade as u16,
+                    depth,
+                    family,
+                )
+                .with_dependency(static_copy),
+            );
+            let pass = description.add_node(
+                builder,
+                &format!("shadow_{}", cascade),
+                PipelineDesc {
+                    cascade,
+                    casters: Casters::Dynamic,
+                }
+                .builder()
+                .into_subpass()
+                .with_dependency(restore)
+                .with_depth_stencil(depth)
+                .into_pass(),
+            );
+            description.add_node(
+                builder,
+                &format!("shadow_copy_{}", cascade),
+                CopyToLayer::builder(depth, shadow_map.image.clone(), cascade as u16, family)
+                    .with_dependency(pass),
+            )
+        })
+        .collect()
+}
+
+/// Push constants of the depth pass: the light's projection and view, then the model matrix
+/// of the mesh, as column major floats.
+const PUSH_CONSTANT_WORDS: u32 = 32;
+
+/// Which of the meshes casting shadows a pipeline draws
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Casters {
+    /// Those marked `components::Static`, only when the cascade's cache is out of date
+    Static,
+    /// The others, every frame
+    Dynamic,
+}
+
+/// Draws the depth of the meshes casting shadows as seen from the light, for one cascade.
+#[derive(Debug)]
+pub struct PipelineDesc {
+    cascade: usize,
+    casters: Casters,
+}
+
+#[derive(Debug)]
+pub struct Pipeline {
+    cascade: usize,
+    casters: Casters,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline;
+
+    fn vertices(
+        &self,
+    ) -> Vec<(
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    )> {
+        vec![PosNormTangTex::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex)]
+    }
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        Vec::new()
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        Some(super::depth_test(false, true))
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _aux: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        Layout {
+            sets: Vec::new(),
+            push_constants: vec![(hal::pso::ShaderStageFlags::VERTEX, 0..PUSH_CONSTANT_WORDS)],
+        }
+    }
+
+    fn build<'a>(
+        self,
+        _ctx: &GraphContext<B>,
+        _factory: &mut Factory<B>,
+        _queue: QueueId,
+        _world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert!(images.is_empty());
+        assert!(set_layouts.is_empty());
+
+        Ok(Pipeline {
+            cascade: self.cascade,
+            casters: self.casters,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        _factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        _index: usize,
+        _world: &specs::World,
+    ) -> PrepareResult {
+        // The cascades follow the camera, so they change nearly every frame, and the static
+        // meshes' pass decides whether to draw them while drawing
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        _index: usize,
+        world: &specs::World,
+    ) {
+        let _scope = crate::profile::scope("ShadowPipeline::draw");
+
+        let cascades = world.read_resource::<ShadowCascades>();
+        let cascade = cascades.cascades.get(self.cascade);
+        if self.casters == Casters::Static {
+            let redraw = world.write_resource::<ShadowMap<B>>().redraw_static(
+                self.cascade,
+                cascade.map_or_else(nalgebra::Matrix4::zeros, |cascade| cascade.view_proj),
+                cascades.static_generation,
+            );
+            if !redraw {
+                return;
+            }
+        }
+        let cascade = match cascade {
+            Some(cascade) => cascade,
+            None => return,
+        };
+
+        let frames = world.read_resource::<RenderFrames>();
+        let mesh_storage = world.read_resource::<asset::MeshStorage>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
+        let geometry_pool = world.read_resource::<GeometryPool<B>>();
+        let arena = world.read_resource::<FrameArena>();
+        let is_static = self.casters == Casters::Static;
+        let mask = cascades.settings.layers;
+        let casters: &[(asset::MeshHandle, nalgebra::Matrix4<f32>)] = arena.alloc_iter(
+            frames
+                .front()
+                .instances
+                .iter()
+                .filter(|instance| {
+                    instance.casts_shadows
+                        && instance.is_static == is_static
+                        && instance.visible(mask)
+                })
+                .map(|instance| (instance.mesh, instance.transform)),
+        );
+        unsafe {
+            super::begin_marker(
+                &mut encoder,
+                &format!(
+                    "Shadow cascade {}: {} {} meshes",
+                    self.cascade,
+                    casters.len(),
+                    if is_static { "static" } else { "moving" }
+                ),
+            );
+        }
+        geometry_pool.bind(0, false, &mut encoder);
+        let mut counts = DrawCounts {
+            pipeline_binds: 1,
+            vertex_buffer_binds: 1,
+            ..Default::default()
+        };
+        for (mesh, transform) in casters.iter() {
+            let constants = arena.alloc_iter(
+                cascade
+                    .view_proj
+                    .iter()
+                    .chain(transform.iter())
+                    .map(|value| value.to_bits()),
+            );
+            unsafe {
+                encoder.push_constants(layout, hal::pso::ShaderStageFlags::VERTEX, 0, constants);
+            }
+            for primitive in mesh_storage.0[*mesh].primitives.iter() {
+                let range = primitive_storage.0[*primitive].range;
+                unsafe {
+                    encoder.draw_indexed(range.indices(), range.vertex_offset, 0..1);
+                }
+                counts.draw_calls += 1;
+                counts.draws += 1;
+            }
+        }
+        world.write_resource::<DrawStats>().current += counts;
+        unsafe {
+            super::end_marker(&mut encoder);
+        }
+    }
+
+    fn dispose(self, _factory: &mut Factory<B>, _world: &specs::World) {}
+}
@@ -0,0 +1,535 @@
+//! Screen space subsurface scattering. Materials with `ShaderFeatures::HAS_SUBSURFACE` are
+//! drawn a second time after the scene, with only their diffuse light and their scattering
+//! radius on screen, into an image sharing the scene's depth. A compute node blurs that light
+//! horizontally and then vertically, and writes the scene's image with the blurred light in
+//! place of the sharp one to a new image, which is read in place of the HDR target.
+//!
+//! The wrap lighting and transmission through thin parts are shaded in the scene pass as well,
+//! so materials still look soft where the blur isn't drawn, like in captures and the debug
+//! window.
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, ExecutableState, Families, Family, FamilyId, Fence, MultiShot,
+        PendingState, Queue, SimultaneousUse, Submission, Submit,
+    },
+    factory::Factory,
+    frame::Frames,
+    graph::{
+        gfx_acquire_barriers, gfx_release_barriers,
+        render::{RenderGroupBuilder, SimpleGraphicsPipelineDesc, SubpassBuilder},
+        BufferAccess, BufferId, DynNode, GraphBuilder, GraphContext, ImageAccess, ImageId,
+        NodeBuffer, NodeBuildError, NodeBuilder, NodeId, NodeImage,
+    },
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    resource::{
+        Buffer, BufferInfo, Escape, Filter, ImageView, ImageViewInfo, Sampler, SamplerDesc,
+        ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use std::mem::size_of;
+
+use crate::{
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::mesh,
+    shader::ShaderFeatures,
+};
+
+lazy_static::lazy_static! {
+    static ref BLUR: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/subsurface_blur.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+}
+
+/// Matches both sizes of `subsurface_blur.comp`.
+const GROUP_SIZE: u32 = 8;
+
+/// The passes of `subsurface_blur.comp`, pushed as a constant. Must match the shader.
+const PASS_HORIZONTAL: u32 = 0;
+const PASS_VERTICAL: u32 = 1;
+
+/// Format of the image with the blurred light, matching the `rgba32f` qualifier of
+/// `subsurface_blur.comp`.
+const SCATTERED_FORMAT: hal::format::Format = hal::format::Format::Rgba32Sfloat;
+
+/// Whether any of the shader variants draws subsurface scattering materials, without which
+/// there is nothing to blur.
+pub fn has_subsurface_variants(shader_variants: &[ShaderFeatures]) -> bool {
+    shader_variants
+        .iter()
+        .any(|features| features.contains(ShaderFeatures::HAS_SUBSURFACE))
+}
+
+/// Adds the pass drawing the diffuse light of subsurface scattering materials, tested against
+/// the scene's `depth`, and the node blurring it into a copy of `hdr`, which is returned to be
+/// read in its place. Both images are `width` by `height`, and the nodes run after
+/// `dependency`, which draws them.
+pub fn add_subsurface_nodes<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, specs::World>,
+    description: &mut crate::graph_dump::GraphDescription,
+    factory: &Factory<B>,
+    shader_variants: &[ShaderFeatures],
+    num_materials: usize,
+    reversed_z: bool,
+    hdr: ImageId,
+    depth: ImageId,
+    width: u32,
+    height: u32,
+    color_target_format: hal::format::Format,
+    family: FamilyId,
+    dependency: NodeId,
+) -> (NodeId, ImageId) {
+    let subsurface = description.create_image(
+        builder,
+        "subsurface",
+        hal::image::Kind::D2(width, height, 1, 1),
+        1,
+        color_target_format,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        }),
+    );
+
+    let mut subpass = SubpassBuilder::new();
+    for features in shader_variants
+        .iter()
+        .filter(|features| features.contains(ShaderFeatures::HAS_SUBSURFACE))
+    {
+        subpass = subpass.with_group(
+            mesh::PipelineDesc::new(factory, num_materials)
+                .with_features(*features | ShaderFeatures::SUBSURFACE_PASS)
+                .with_reversed_z(reversed_z)
+                .builder(),
+        );
+    }
+    let pass = description.add_node(
+        builder,
+        "subsurface",
+        subpass
+            .with_color(subsurface)
+            .with_depth_stencil(depth)
+            .with_dependency(dependency)
+            .into_pass(),
+    );
+
+    let scattered = description.create_image(
+        builder,
+        "scattered",
+        hal::image::Kind::D2(width, height, 1, 1),
+        1,
+        SCATTERED_FORMAT,
+        None,
+    );
+    let blur = description.add_node(
+        builder,
+        "subsurface_blur",
+        BlurNodeBuilder {
+            hdr,
+            subsurface,
+            scattered,
+            family,
+            dependencies: vec![pass],
+        },
+    );
+    (blur, scattered)
+}
+
+#[derive(Debug)]
+struct BlurNodeBuilder {
+    hdr: ImageId,
+    subsurface: ImageId,
+    scattered: ImageId,
+    family: FamilyId,
+    dependencies: Vec<NodeId>,
+}
+
+#[derive(Debug)]
+struct BlurNode<B: hal::Backend> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+    descriptor_pool: B::DescriptorPool,
+    set_layout: B::DescriptorSetLayout,
+    pipeline_layout: B::PipelineLayout,
+    pipeline: B::ComputePipeline,
+    sampler: Escape<Sampler<B>>,
+    hdr_view: Escape<ImageView<B>>,
+    subsurface_view: Escape<ImageView<B>>,
+    scattered_view: Escape<ImageView<B>>,
+    /// The horizontally blurred light of every pixel
+    blurred: Escape<Buffer<B>>,
+    /// Id of the node's buffer in `GpuMemoryStats`
+    memory_stats_id: usize,
+}
+
+impl<B> NodeBuilder<B, specs::World> for BlurNodeBuilder
+where
+    B: hal::Backend,
+{
+    fn family(&self, _factory: &mut Factory<B>, _families: &Families<B>) -> Option<FamilyId> {
+        Some(self.family)
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        let sampled = ImageAccess {
+            access: hal::image::Access::SHADER_READ,
+            usage: hal::image::Usage::SAMPLED,
+            layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+        };
+        vec![
+            (self.hdr, sampled),
+            (self.subsurface, sampled),
+            (
+                self.scattered,
+                ImageAccess {
+                    access: hal::image::Access::SHADER_WRITE,
+                    usage: hal::image::Usage::STORAGE,
+                    layout: hal::image::Layout::General,
+                    stages: hal::pso::PipelineStage::COMPUTE_SHADER,
+                },
+            ),
+        ]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        aux: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, specs::World>>, NodeBuildError> {
+        assert!(buffers.is_empty());
+        assert_eq!(images.len(), 3);
+
+        let mut views = images.iter().map(|node_image| {
+            let image = ctx
+                .get_image(node_image.id)
+                .expect("Subsurface blur image missing");
+            factory
+                .create_image_view(
+                    image.clone(),
+                    ImageViewInfo {
+                        view_kind: ViewKind::D2,
+                        format: image.format(),
+                        swizzle: hal::format::Swizzle::NO,
+                        range: node_image.range.clone(),
+                    },
+                )
+                .expect("Could not create subsurface blur image view")
+        });
+        let hdr_view = views.next().unwrap();
+        let subsurface_view = views.next().unwrap();
+        let scattered_view = views.next().unwrap();
+        let extent = ctx.get_image(images[0].id).unwrap().kind().extent();
+
+        let sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+            .unwrap();
+        let blurred = factory
+            .create_buffer(
+                BufferInfo {
+                    size: (extent.width * extent.height) as u64 * size_of::<[f32; 4]>() as u64,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                MemoryUsageValue::Data,
+            )
+            .unwrap();
+
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory
+                .device()
+                .create_descriptor_set_layout(
+                    vec![
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 0,
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 1,
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 2,
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 3,
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                        hal::pso::DescriptorSetLayoutBinding {
+                            binding: 4,
+                            ty: hal::pso::DescriptorType::StorageImage,
+                            count: 1,
+                            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                            immutable_samplers: false,
+                        },
+                    ],
+                    std::iter::empty::<B::Sampler>(),
+                )
+                .unwrap();
+            let pipeline_layout = factory
+                .device()
+                .create_pipeline_layout(
+                    Some(&set_layout),
+                    Some((hal::pso::ShaderStageFlags::COMPUTE, 0..3)),
+                )
+                .unwrap();
+            (set_layout, pipeline_layout)
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory
+                .create_descriptor_pool(
+                    1,
+                    vec![
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::Sampler,
+                            count: 1,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::SampledImage,
+                            count: 2,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::StorageBuffer,
+                            count: 1,
+                        },
+                        hal::pso::DescriptorRangeDesc {
+                            ty: hal::pso::DescriptorType::StorageImage,
+                            count: 1,
+                        },
+                    ],
+                    hal::pso::DescriptorPoolCreateFlags::empty(),
+                )
+                .unwrap()
+        };
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layout).unwrap();
+            factory.write_descriptor_sets(vec![
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Sampler(sampler.raw())),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        hdr_view.raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 2,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        subsurface_view.raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 3,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Buffer(blurred.raw(), None..None)),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 4,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        scattered_view.raw(),
+                        hal::image::Layout::General,
+                    )),
+                },
+            ]);
+            set
+        };
+
+        let pipeline = unsafe { create_pipeline(factory, &pipeline_layout, &*BLUR).unwrap() };
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+        unsafe {
+            super::begin_marker(&mut encoder, "Subsurface blur");
+            let (stages, barriers) = gfx_acquire_barriers(ctx, buffers.iter(), images.iter());
+            if !barriers.is_empty() {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+            }
+
+            encoder.bind_compute_pipeline(&pipeline);
+            encoder.bind_compute_descriptor_sets(
+                &pipeline_layout,
+                0,
+                Some(&set),
+                std::iter::empty(),
+            );
+            for &pass in [PASS_HORIZONTAL, PASS_VERTICAL].iter() {
+                if pass == PASS_VERTICAL {
+                    // The vertical pass blurs what the horizontal one wrote
+                    encoder.pipeline_barrier(
+                        hal::pso::PipelineStage::COMPUTE_SHADER
+                            ..hal::pso::PipelineStage::COMPUTE_SHADER,
+                        hal::memory::Dependencies::empty(),
+                        Some(hal::memory::Barrier::Buffer {
+                            states: hal::buffer::Access::SHADER_WRITE
+                                ..hal::buffer::Access::SHADER_READ,
+                            families: None,
+                            target: blurred.raw(),
+                            range: None..None,
+                        }),
+                    );
+                }
+                encoder.push_constants(
+                    &pipeline_layout,
+                    hal::pso::ShaderStageFlags::COMPUTE,
+                    0,
+                    &[extent.width, extent.height, pass],
+                );
+                encoder.dispatch([
+                    (extent.width + GROUP_SIZE - 1) / GROUP_SIZE,
+                    (extent.height + GROUP_SIZE - 1) / GROUP_SIZE,
+                    1,
+                ]);
+            }
+
+            let (stages, barriers) = gfx_release_barriers(ctx, buffers.iter(), images.iter());
+            if !barriers.is_empty() {
+                encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+            }
+            super::end_marker(&mut encoder);
+        }
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&blurred);
+        let memory_stats_id = aux
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline("Subsurface blur".to_owned(), memory);
+
+        Ok(Box::new(BlurNode {
+            pool,
+            submit,
+            buffer,
+            descriptor_pool,
+            set_layout,
+            pipeline_layout,
+            pipeline,
+            sampler,
+            hdr_view,
+            subsurface_view,
+            scattered_view,
+            blurred,
+            memory_stats_id,
+        }))
+    }
+}
+
+impl<B> DynNode<B, specs::World> for BlurNode<B>
+where
+    B: hal::Backend,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        _aux: &specs::World,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        let _scope = crate::profile::scope("BlurNode::run");
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submit))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, aux: &specs::World) {
+        aux.write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+        factory.device().destroy_compute_pipeline(self.pipeline);
+        factory
+            .device()
+            .destroy_pipeline_layout(self.pipeline_layout);
+        factory
+            .device()
+            .destroy_descriptor_set_layout(self.set_layout);
+    }
+}
+
+unsafe fn create_pipeline<B: hal::Backend>(
+    factory: &Factory<B>,
+    layout: &B::PipelineLayout,
+    shader: &PathBufShaderInfo,
+) -> Result<B::ComputePipeline, failure::Error> {
+    let module = factory.device().create_shader_module(&shader.spirv()?)?;
+    let pipeline = factory.device().create_compute_pipeline(
+        &hal::pso::ComputePipelineDesc::new(
+            hal::pso::EntryPoint {
+                entry: "main",
+                module: &module,
+                specialization: hal::pso::Specialization::default(),
+            },
+            layout,
+        ),
+        None,
+    );
+    factory.device().destroy_shader_module(module);
+    Ok(pipeline?)
+}
@@ -14,7 +14,10 @@ use rendy::hal;
 
 use std::mem::size_of;
 
-use crate::node::pbr::Aux;
+use crate::{
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node::pbr::{mesh, Aux, View},
+};
 
 lazy_static::lazy_static! {
     static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
@@ -39,21 +42,92 @@ lazy_static::lazy_static! {
 #[derive(Debug, Clone, Copy, Default)]
 #[repr(C)]
 pub struct TonemapperArgs {
-    pub exposure: f32,
+    /// Exposure value at ISO 100. Higher values darken the image, by one stop per unit.
+    pub exposure_ev100: f32,
     pub curve: i32,
     pub comparison_factor: f32,
+    /// Non-zero if the output must be gamma encoded in the shader, because the swapchain
+    /// format is UNORM rather than sRGB and won't encode it on write.
+    pub encode_gamma: i32,
+    /// Non-zero to pass the HDR image's alpha through to the output for compositing,
+    /// otherwise the output is opaque.
+    pub output_alpha: i32,
+    /// Non-zero to color the image by how many stops each pixel is from saturating at the
+    /// exposure, like the false color display of a camera.
+    pub false_color: i32,
 }
 
 impl std::fmt::Display for TonemapperArgs {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Exposure: {}, Curve: {}", self.exposure, self.curve)
+        write!(
+            f,
+            "Exposure: {} EV100 (x{}), Curve: {}",
+            self.exposure_ev100,
+            ev100_to_exposure(self.exposure_ev100),
+            self.curve
+        )
+    }
+}
+
+/// The factor scene luminance is multiplied by for a given exposure value at ISO 100,
+/// such that luminance saturating the sensor maps to 1.0. Must match `tonemap.frag`.
+pub fn ev100_to_exposure(ev100: f32) -> f32 {
+    1.0 / (1.2 * 2f32.powf(ev100))
+}
+
+/// The exposure value at ISO 100 for an exposure factor, the inverse of `ev100_to_exposure`.
+pub fn exposure_to_ev100(exposure: f32) -> f32 {
+    (1.0 / (1.2 * exposure)).log2()
+}
+
+/// Side by side comparison of two tonemappers, shading debug views or IBL modes in the main
+/// view. The left of the divider uses `Aux::tonemapper_args`, `Aux::debug_view` and
+/// `Aux::ibl_mode`, the right these.
+#[derive(Debug, Clone, Copy)]
+pub struct SplitView {
+    pub enabled: bool,
+    /// Position of the divider, from 0 at the left edge of the image to 1 at the right
+    pub divider: f32,
+    pub exposure_ev100: f32,
+    pub curve: i32,
+    pub debug_view: mesh::DebugView,
+    pub ibl_mode: super::IblMode,
+    /// Width of the main view's render target in pixels, to place the divider in the mesh
+    /// pass, whose fragments only know their pixel position
+    pub render_width: u32,
+}
+
+impl SplitView {
+    /// Column of the render target the right side starts at
+    pub fn divider_pixel(&self) -> u32 {
+        (self.divider.max(0.0).min(1.0) * self.render_width as f32) as u32
+    }
+}
+
+impl std::fmt::Display for SplitView {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Right side: Exposure: {} EV100, Curve: {}, Debug view: {:?}, IBL: {:?}",
+            self.exposure_ev100, self.curve, self.debug_view, self.ibl_mode
+        )
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct SplitArgs {
+    exposure_ev100: f32,
+    curve: i32,
+    enabled: i32,
+    divider: f32,
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct UniformArgs {
     tonemapper: TonemapperArgs,
+    split: SplitArgs,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -92,7 +166,17 @@ impl Settings {
 }
 
 #[derive(Debug, Default)]
-pub struct PipelineDesc;
+pub struct PipelineDesc {
+    view: View,
+}
+
+impl PipelineDesc {
+    /// Tonemap the image of another view than the main window's, which is never split.
+    pub fn with_view(mut self, view: View) -> Self {
+        self.view = view;
+        self
+    }
+}
 
 #[derive(Debug)]
 pub struct Pipeline<B: hal::Backend> {
@@ -102,6 +186,8 @@ pub struct Pipeline<B: hal::Backend> {
     image_sampler: Escape<Sampler<B>>,
     image_view: Escape<ImageView<B>>,
     settings: Settings,
+    view: View,
+    memory_stats_id: usize,
 }
 
 impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
@@ -123,6 +209,14 @@ where
         None
     }
 
+    /// Replaces rather than blends, so the output alpha ends up in the swapchain as is
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::ALL,
+            blend: None,
+        }]
+    }
+
     fn load_shader_set(
         &self,
         factory: &mut Factory<B>,
@@ -216,7 +310,7 @@ where
                 image_handle.clone(),
                 ImageViewInfo {
                     view_kind: ViewKind::D2,
-                    format: hal::format::Format::Rgba32Sfloat,
+                    format: image_handle.format(),
                     swizzle: hal::format::Swizzle::NO,
                     range: images[0].range.clone(),
                 },
@@ -269,6 +363,12 @@ where
                 sets.push(set);
             }
         }
+        let mut memory = MemoryTotals::default();
+        memory.add_buffer(&buffer);
+        let memory_stats_id = world
+            .write_resource::<GpuMemoryStats>()
+            .add_pipeline("Tonemap".to_owned(), memory);
+
         Ok(Pipeline {
             buffer,
             sets,
@@ -276,6 +376,8 @@ where
             image_sampler,
             descriptor_pool,
             settings,
+            view: self.view,
+            memory_stats_id,
         })
     }
 }
@@ -294,6 +396,7 @@ where
         index: usize,
         world: &specs::World,
     ) -> PrepareResult {
+        let _scope = crate::profile::scope("TonemapPipeline::prepare");
         let aux = world.read_resource::<Aux>();
         unsafe {
             factory
@@ -302,6 +405,12 @@ where
                     self.settings.uniform_offset(index as u64),
                     &[UniformArgs {
                         tonemapper: aux.tonemapper_args,
+                        split: SplitArgs {
+                            exposure_ev100: aux.split_view.exposure_ev100,
+                            curve: aux.split_view.curve,
+                            enabled: (aux.split_view.enabled && self.view == View::Main) as i32,
+                            divider: aux.split_view.divider,
+                        },
                     }],
                 )
                 .unwrap()
@@ -316,7 +425,9 @@ where
         index: usize,
         _world: &specs::World,
     ) {
+        let _scope = crate::profile::scope("TonemapPipeline::draw");
         unsafe {
+            super::begin_marker(&mut encoder, "Tonemap");
             encoder.bind_graphics_descriptor_sets(
                 layout,
                 0,
@@ -328,10 +439,14 @@ where
             // which is actually just a triangle with two of the vertices positioned
             // correctly off screen. This way we don't need a vertex buffer.
             encoder.draw(0..3, 0..1);
+            super::end_marker(&mut encoder);
         }
     }
 
-    fn dispose(mut self, factory: &mut Factory<B>, _world: &specs::World) {
+    fn dispose(mut self, factory: &mut Factory<B>, world: &specs::World) {
+        world
+            .write_resource::<GpuMemoryStats>()
+            .remove_pipeline(self.memory_stats_id);
         unsafe {
             self.descriptor_pool.reset();
             factory.destroy_descriptor_pool(self.descriptor_pool);
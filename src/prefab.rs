@@ -0,0 +1,273 @@
+//! Reusable entity templates of the scene config. A prefab is a mesh, optionally drawn with
+//! another material than its own, and other prefabs placed relative to it as its children.
+//! The `instances` of the scene config stamp prefabs into the scene, one copy at a time or
+//! many at once, on a grid or scattered at random over a box.
+//!
+//! Each prefab's mesh is loaded once, whether or not it is instanced, and every copy of the
+//! prefab shares it. A mesh is given room for as many instances as the scene places.
+//!
+//! The scene's `array` names the prefab whose mesh is copied on a grid at runtime, a row at a
+//! time with **X**, **Y** and **Z**, see `crate::systems::InstanceArraySize`.
+use crate::{
+    asset, components,
+    scene::{MaterialSource, MeshSource},
+};
+
+use derivative::Derivative;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use specs::prelude::*;
+
+use std::collections::HashMap;
+
+/// How deep prefabs may be nested in each other, to catch prefabs containing themselves
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Deserialize)]
+pub struct Prefab {
+    /// What instances and other prefabs refer to it by
+    pub name: String,
+    #[serde(default)]
+    pub mesh: Option<MeshSource>,
+    /// Draws every primitive of the mesh with this material instead of its own
+    #[serde(default)]
+    pub material: Option<MaterialSource>,
+    /// Other prefabs, copied along with this one
+    #[serde(default)]
+    pub children: Vec<PrefabChild>,
+    /// Whether the mesh is drawn into the shadow maps
+    #[serde(default = "Prefab::default_casts_shadows")]
+    pub casts_shadows: bool,
+    /// Marks the copies as never moving, so the mesh's shadows are cached
+    #[serde(default, rename = "static")]
+    pub is_static: bool,
+    /// The layers the copies are in, see `components::Layers`
+    #[serde(default)]
+    pub layers: Option<components::Layers>,
+}
+
+impl Prefab {
+    fn default_casts_shadows() -> bool {
+        true
+    }
+}
+
+/// A prefab placed relative to the one it is a child of
+#[derive(Debug, Deserialize)]
+pub struct PrefabChild {
+    pub prefab: String,
+    #[serde(default)]
+    pub transform: components::Transform,
+}
+
+/// Copies of a prefab placed in the scene
+#[derive(Debug, Deserialize)]
+pub struct PrefabInstances {
+    pub prefab: String,
+    pub placement: Placement,
+}
+
+/// Where the copies of a prefab go
+#[derive(Debug, Deserialize)]
+pub enum Placement {
+    /// A single copy
+    At(components::Transform),
+    Grid(GridPlacement),
+    Scatter(ScatterPlacement),
+}
+
+/// Copies `spacing` apart along each axis, on a grid centered on `center`.
+#[derive(Debug, Derivative, Clone, Copy, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct GridPlacement {
+    pub center: [f32; 3],
+    /// Number of copies along each axis
+    #[derivative(Default(value = "[1, 1, 1]"))]
+    pub counts: [u32; 3],
+    #[derivative(Default(value = "[1.0, 1.0, 1.0]"))]
+    pub spacing: [f32; 3],
+    /// Of every copy
+    #[derivative(Default(value = "1.0"))]
+    pub scale: f32,
+}
+
+/// Copies at random positions in a box. The same `seed` places them the same way every time.
+#[derive(Debug, Derivative, Clone, Copy, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ScatterPlacement {
+    #[derivative(Default(value = "[-1.0, 0.0, -1.0]"))]
+    pub min: [f32; 3],
+    #[derivative(Default(value = "[1.0, 0.0, 1.0]"))]
+    pub max: [f32; 3],
+    #[derivative(Default(value = "1"))]
+    pub count: u32,
+    pub seed: u64,
+    /// Turn each copy about the Y axis by a random angle
+    #[derivative(Default(value = "true"))]
+    pub random_yaw: bool,
+    /// The smallest and largest scale of the copies
+    #[derivative(Default(value = "[1.0, 1.0]"))]
+    pub scale: [f32; 2],
+}
+
+/// The copies of a prefab's mesh added and removed at runtime, without its children.
+#[derive(Debug, Derivative, Clone, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ArraySettings {
+    pub prefab: String,
+    /// Between the copies along each axis
+    #[derivative(Default(value = "[3.0, 4.0, 4.0]"))]
+    pub spacing: [f32; 3],
+    /// Of every copy
+    #[derivative(Default(value = "1.0"))]
+    pub scale: f32,
+    /// The most copies along each axis. The mesh gets room for all of them on load.
+    #[derivative(Default(value = "[8, 4, 8]"))]
+    pub max_counts: [u8; 3],
+}
+
+impl ArraySettings {
+    /// The most copies in the array
+    pub fn max_instances(&self) -> usize {
+        self.max_counts
+            .iter()
+            .map(|&count| count as usize)
+            .product()
+    }
+}
+
+impl Placement {
+    /// The transforms of the copies, in the scene
+    pub fn transforms(&self) -> Vec<nalgebra::Similarity3<f32>> {
+        match self {
+            Placement::At(transform) => vec![transform.0],
+            Placement::Grid(grid) => grid.transforms(),
+            Placement::Scatter(scatter) => scatter.transforms(),
+        }
+    }
+}
+
+impl GridPlacement {
+    /// Along X first, then Y, then Z, with Z changing fastest
+    pub fn transforms(&self) -> Vec<nalgebra::Similarity3<f32>> {
+        let [x_count, y_count, z_count] = self.counts;
+        let offset = |axis: usize, i: u32| {
+            let count = self.counts[axis];
+            self.center[axis] + self.spacing[axis] * (i as f32 - (count - 1) as f32 * 0.5)
+        };
+        let mut transforms = Vec::with_capacity((x_count * y_count * z_count) as usize);
+        for x in 0..x_count {
+            for y in 0..y_count {
+                for z in 0..z_count {
+                    transforms.push(nalgebra::Similarity3::from_parts(
+                        nalgebra::Translation3::new(offset(0, x), offset(1, y), offset(2, z)),
+                        nalgebra::UnitQuaternion::identity(),
+                        self.scale,
+                    ));
+                }
+            }
+        }
+        transforms
+    }
+}
+
+impl ScatterPlacement {
+    fn transforms(&self) -> Vec<nalgebra::Similarity3<f32>> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(self.seed);
+        let mut between = |min: f32, max: f32| {
+            if max > min {
+                rng.gen_range(min, max)
+            } else {
+                min
+            }
+        };
+        (0..self.count)
+            .map(|_| {
+                let translation = nalgebra::Translation3::new(
+                    between(self.min[0], self.max[0]),
+                    between(self.min[1], self.max[1]),
+                    between(self.min[2], self.max[2]),
+                );
+                let yaw = if self.random_yaw {
+                    between(0.0, 2.0 * std::f32::consts::PI)
+                } else {
+                    0.0
+                };
+                let scale = between(self.scale[0], self.scale[1]);
+                nalgebra::Similarity3::from_parts(
+                    translation,
+                    nalgebra::UnitQuaternion::from_axis_angle(&nalgebra::Vector3::y_axis(), yaw),
+                    scale,
+                )
+            })
+            .collect()
+    }
+}
+
+/// The prefabs of a scene by name, with the meshes they were loaded with
+pub type LoadedPrefabs<'a> = HashMap<&'a str, (&'a Prefab, Option<asset::MeshHandle>)>;
+
+/// Creates the entities of a copy of the prefab `name` and of its children, returning the
+/// copy's root.
+pub fn spawn(
+    world: &mut specs::World,
+    prefabs: &LoadedPrefabs,
+    name: &str,
+    transform: components::Transform,
+    parent: Option<Entity>,
+) -> Result<Entity, failure::Error> {
+    spawn_nested(world, prefabs, name, transform, parent, 0)
+}
+
+fn spawn_nested(
+    world: &mut specs::World,
+    prefabs: &LoadedPrefabs,
+    name: &str,
+    transform: components::Transform,
+    parent: Option<Entity>,
+    depth: usize,
+) -> Result<Entity, failure::Error> {
+    if depth > MAX_DEPTH {
+        failure::bail!(
+            "Prefab {:?} is nested more than {} levels deep, does it contain itself?",
+            name,
+            MAX_DEPTH
+        );
+    }
+    let (prefab, mesh) = prefabs
+        .get(name)
+        .ok_or_else(|| failure::format_err!("Prefab {:?} is not defined", name))?;
+
+    let mut entity_builder = world.create_entity().with(transform);
+    if let Some(mesh) = mesh {
+        entity_builder = entity_builder.with(components::Mesh(*mesh));
+    }
+    if !prefab.casts_shadows {
+        entity_builder = entity_builder.with(components::NoShadows);
+    }
+    if prefab.is_static {
+        entity_builder = entity_builder.with(components::Static);
+    }
+    if let Some(layers) = prefab.layers {
+        entity_builder = entity_builder.with(layers);
+    }
+    if let Some(parent) = parent {
+        entity_builder = entity_builder.with(components::Parent::new(parent));
+    }
+    let entity = entity_builder.build();
+
+    for child in prefab.children.iter() {
+        spawn_nested(
+            world,
+            prefabs,
+            &child.prefab,
+            child.transform.clone(),
+            Some(entity),
+            depth + 1,
+        )?;
+    }
+    Ok(entity)
+}
@@ -0,0 +1,193 @@
+//! A grid of light probes, each storing the irradiance arriving at a point of the scene as
+//! L2 spherical harmonics. They are baked by drawing the scene into a cube from each probe,
+//! and let meshes pick up indirect diffuse light from their surroundings rather than only
+//! from the environment map.
+use derivative::Derivative;
+use rendy::{
+    factory::Factory,
+    memory::MemoryUsageValue,
+    resource::{Buffer, BufferInfo, Escape},
+};
+use serde::Deserialize;
+
+use rendy::hal;
+
+use crate::node::{env_preprocess::debug::Subresource, pbr::capture::face_basis};
+
+/// Probes evenly spaced over a box, covering its corners.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ProbeGridSettings {
+    #[derivative(Default(value = "[-1.0, -1.0, -1.0]"))]
+    pub min: [f32; 3],
+    #[derivative(Default(value = "[1.0, 1.0, 1.0]"))]
+    pub max: [f32; 3],
+    /// Number of probes along each axis, at least 2
+    #[derivative(Default(value = "[3, 3, 3]"))]
+    pub counts: [u32; 3],
+    /// Size of the cube faces drawn at each probe
+    #[derivative(Default(value = "32"))]
+    pub resolution: u32,
+}
+
+impl ProbeGridSettings {
+    fn counts(&self) -> [u32; 3] {
+        [
+            self.counts[0].max(2),
+            self.counts[1].max(2),
+            self.counts[2].max(2),
+        ]
+    }
+
+    fn spacing(&self) -> [f32; 3] {
+        let counts = self.counts();
+        let mut spacing = [0.0; 3];
+        for axis in 0..3 {
+            spacing[axis] = (self.max[axis] - self.min[axis]) / (counts[axis] - 1) as f32;
+        }
+        spacing
+    }
+
+    /// Positions of the probes, along X first, then Y, then Z, as they are indexed in
+    /// `pbr.frag`.
+    pub fn positions(&self) -> Vec<nalgebra::Point3<f32>> {
+        let counts = self.counts();
+        let spacing = self.spacing();
+        let mut positions = Vec::with_capacity((counts[0] * counts[1] * counts[2]) as usize);
+        for z in 0..counts[2] {
+            for y in 0..counts[1] {
+                for x in 0..counts[0] {
+                    positions.push(nalgebra::Point3::new(
+                        self.min[0] + x as f32 * spacing[0],
+                        self.min[1] + y as f32 * spacing[1],
+                        self.min[2] + z as f32 * spacing[2],
+                    ));
+                }
+            }
+        }
+        positions
+    }
+}
+
+/// Irradiance as the nine L2 spherical harmonics coefficients, RGB in `xyz`. Scaled to match
+/// the irradiance cube map, so evaluating them gives a value to multiply albedo by.
+pub type ShCoefficients = [[f32; 4]; 9];
+
+fn sh_basis(d: &nalgebra::Vector3<f32>) -> [f32; 9] {
+    [
+        0.282_095,
+        0.488_603 * d.y,
+        0.488_603 * d.z,
+        0.488_603 * d.x,
+        1.092_548 * d.x * d.y,
+        1.092_548 * d.y * d.z,
+        0.315_392 * (3.0 * d.z * d.z - 1.0),
+        1.092_548 * d.x * d.z,
+        0.546_274 * (d.x * d.x - d.y * d.y),
+    ]
+}
+
+/// Projects the radiance of the six faces of a cube, in the layer order of `face_basis`,
+/// onto spherical harmonics and convolves it with a cosine lobe.
+pub fn project_irradiance(faces: &[Subresource]) -> ShCoefficients {
+    let mut coefficients = [[0.0; 4]; 9];
+    for (face, data) in faces.iter().enumerate().take(6) {
+        let (forward, up) = face_basis(face);
+        let right = forward.cross(&up);
+        let size = data.width as f32;
+        for y in 0..data.height {
+            for x in 0..data.width {
+                let a = (x as f32 + 0.5) / size * 2.0 - 1.0;
+                let b = 1.0 - (y as f32 + 0.5) / size * 2.0;
+                // Solid angle of the texel, smaller towards the edges of the face
+                let weight = (2.0 / size).powi(2) / (1.0 + a * a + b * b).powf(1.5);
+                let dir = (forward + right * a + up * b).normalize();
+                let radiance = data.texels[(y * data.width + x) as usize];
+                for (coefficient, basis) in coefficients.iter_mut().zip(sh_basis(&dir).iter()) {
+                    for c in 0..3 {
+                        coefficient[c] += radiance[c] * basis * weight;
+                    }
+                }
+            }
+        }
+    }
+
+    // The cosine lobe convolution is pi, 2pi/3 and pi/4 for the three bands, divided by pi
+    // since the irradiance cube map is stored divided by it as well
+    let band_scale = [
+        1.0,
+        2.0 / 3.0,
+        2.0 / 3.0,
+        2.0 / 3.0,
+        0.25,
+        0.25,
+        0.25,
+        0.25,
+        0.25,
+    ];
+    for (coefficient, scale) in coefficients.iter_mut().zip(band_scale.iter()) {
+        for c in coefficient.iter_mut().take(3) {
+            *c *= scale;
+        }
+    }
+    coefficients
+}
+
+/// Matches the header of the `ProbeGrid` storage buffer in `pbr.frag`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ProbeGridHeader {
+    min: [f32; 4],
+    spacing: [f32; 4],
+    /// Probes along each axis in `xyz`, and whether the grid is baked in `w`
+    counts: [i32; 4],
+}
+
+/// Creates the storage buffer read by `pbr.frag`. Without a baked grid, the shader falls back
+/// to the irradiance cube map everywhere.
+pub fn create_buffer<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    grid: Option<(&ProbeGridSettings, &[ShCoefficients])>,
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    let header_size = std::mem::size_of::<ProbeGridHeader>() as u64;
+    let (header, coefficients) = match grid {
+        Some((settings, coefficients)) => {
+            let counts = settings.counts();
+            let spacing = settings.spacing();
+            (
+                ProbeGridHeader {
+                    min: [settings.min[0], settings.min[1], settings.min[2], 0.0],
+                    spacing: [spacing[0], spacing[1], spacing[2], 0.0],
+                    counts: [counts[0] as i32, counts[1] as i32, counts[2] as i32, 1],
+                },
+                coefficients
+                    .iter()
+                    .flat_map(|c| c.iter().cloned())
+                    .collect(),
+            )
+        }
+        // The array can't be empty, so there is a single unused probe
+        None => (
+            ProbeGridHeader {
+                min: [0.0; 4],
+                spacing: [1.0; 4],
+                counts: [0; 4],
+            },
+            vec![[0.0; 4]; 9],
+        ),
+    };
+
+    let mut buffer = factory.create_buffer(
+        BufferInfo {
+            size: header_size + (coefficients.len() * std::mem::size_of::<[f32; 4]>()) as u64,
+            usage: hal::buffer::Usage::STORAGE,
+        },
+        MemoryUsageValue::Dynamic,
+    )?;
+    unsafe {
+        factory.upload_visible_buffer(&mut buffer, 0, &[header])?;
+        factory.upload_visible_buffer(&mut buffer, header_size, &coefficients[..])?;
+    }
+    Ok(buffer)
+}
@@ -0,0 +1,143 @@
+//! Primitive shapes generated at load time, so test scenes can be built without asset files.
+use genmesh::{
+    generators::{IndexedPolygon, SharedVertex},
+    EmitTriangles, Triangulate,
+};
+use serde::Deserialize;
+
+use std::f32::consts::PI;
+
+use crate::mesh_file::MeshData;
+
+/// A generated mesh. Shapes are centered on the origin and fit in a 2x2x2 box.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum Shape {
+    Cube,
+    /// A sphere of `u` segments around and `v` rings from pole to pole
+    UvSphere {
+        u: usize,
+        v: usize,
+    },
+    /// A sphere made by subdividing an icosahedron, with evenly sized triangles
+    IcoSphere {
+        subdivisions: usize,
+    },
+    /// A plane in the XZ plane, facing up
+    Plane {
+        subdivisions: usize,
+    },
+    /// A torus around the Z axis
+    Torus {
+        radius: f32,
+        tube_radius: f32,
+        radial_segments: usize,
+        tubular_segments: usize,
+    },
+    /// A capped cylinder along the Z axis
+    Cylinder {
+        segments: usize,
+        height_segments: usize,
+    },
+}
+
+impl Shape {
+    pub fn generate(&self) -> MeshData {
+        let mut data = match *self {
+            Shape::Cube => indexed(&genmesh::generators::Cube::new()),
+            Shape::UvSphere { u, v } => {
+                indexed(&genmesh::generators::SphereUv::new(u.max(3), v.max(2)))
+            }
+            Shape::IcoSphere { subdivisions } => {
+                indexed(&genmesh::generators::IcoSphere::subdivide(subdivisions))
+            }
+            Shape::Plane { subdivisions } => {
+                let subdivisions = subdivisions.max(1);
+                let mut data = indexed(&genmesh::generators::Plane::subdivide(
+                    subdivisions,
+                    subdivisions,
+                ));
+                // Generated in the XY plane facing +Z, turn it to face +Y
+                let to_xz = |v: [f32; 3]| [v[0], v[2], -v[1]];
+                data.positions = data.positions.into_iter().map(to_xz).collect();
+                data.normals = data
+                    .normals
+                    .map(|normals| normals.into_iter().map(to_xz).collect());
+                data
+            }
+            Shape::Torus {
+                radius,
+                tube_radius,
+                radial_segments,
+                tubular_segments,
+            } => indexed(&genmesh::generators::Torus::new(
+                radius,
+                tube_radius,
+                radial_segments.max(3),
+                tubular_segments.max(3),
+            )),
+            Shape::Cylinder {
+                segments,
+                height_segments,
+            } => indexed(&genmesh::generators::Cylinder::subdivide(
+                segments.max(3),
+                height_segments.max(1),
+            )),
+        };
+
+        let shape = *self;
+        data.tex_coords = Some(
+            data.positions
+                .iter()
+                .zip(data.normals.as_ref().unwrap().iter())
+                .map(|(p, n)| shape.tex_coord(*p, *n))
+                .collect(),
+        );
+        data
+    }
+
+    /// A simple projection for each shape. Shapes have no seams of their own in the
+    /// generated vertices, so some faces along the wrap around are stretched.
+    fn tex_coord(&self, p: [f32; 3], n: [f32; 3]) -> [f32; 2] {
+        let around = |x: f32, y: f32| 0.5 + y.atan2(x) / (2.0 * PI);
+        match self {
+            Shape::Cube => {
+                // Project onto the face the normal points out of
+                let (a, b) = if n[0].abs() > 0.5 {
+                    (p[2], p[1])
+                } else if n[1].abs() > 0.5 {
+                    (p[0], p[2])
+                } else {
+                    (p[0], p[1])
+                };
+                [a * 0.5 + 0.5, 0.5 - b * 0.5]
+            }
+            Shape::UvSphere { .. } | Shape::IcoSphere { .. } => [
+                around(n[2], n[0]),
+                0.5 - n[1].max(-1.0).min(1.0).asin() / PI,
+            ],
+            Shape::Plane { .. } => [p[0] * 0.5 + 0.5, p[2] * 0.5 + 0.5],
+            Shape::Torus { .. } | Shape::Cylinder { .. } => [around(p[0], p[1]), 0.5 - p[2] * 0.5],
+        }
+    }
+}
+
+fn indexed<G, P>(generator: &G) -> MeshData
+where
+    G: SharedVertex<genmesh::Vertex> + IndexedPolygon<P>,
+    P: EmitTriangles<Vertex = usize>,
+{
+    let (positions, normals): (Vec<[f32; 3]>, Vec<[f32; 3]>) = generator
+        .shared_vertex_iter()
+        .map(|v| (v.pos.into(), v.normal.into()))
+        .unzip();
+    let indices = genmesh::Vertices::vertices(generator.indexed_polygon_iter().triangulate())
+        .map(|i| i as u32)
+        .collect();
+
+    MeshData {
+        positions,
+        normals: Some(normals),
+        tex_coords: None,
+        indices,
+    }
+}
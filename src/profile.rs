@@ -0,0 +1,177 @@
+//! CPU profiling scopes, recorded when the app is run with `--profile` to find hotspots in
+//! big scenes. Each scope is timed from `scope` until the returned guard is dropped, on
+//! whichever thread it runs, including the dispatcher's workers.
+//!
+//! The average time of each scope is logged along with the FPS, and every scope of the run
+//! is written as a Chrome trace when the app closes, which can be opened in
+//! `chrome://tracing` or https://ui.perfetto.dev as a flamegraph per thread.
+//!
+//! The names of the scopes open on each thread are kept whether or not they're timed, to tell
+//! where validation messages come from, see `crate::validation`.
+use serde::Serialize;
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref START: Instant = Instant::now();
+    static ref RECORDING: Mutex<Recording> = Mutex::new(Recording::default());
+}
+
+thread_local! {
+    static THREAD: usize = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+    static OPEN: RefCell<Vec<&'static str>> = RefCell::new(Vec::new());
+}
+
+struct Span {
+    name: &'static str,
+    thread: usize,
+    /// Since `START`
+    start: Duration,
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct Recording {
+    spans: Vec<Span>,
+    /// Total time and count of each scope since the last `take_averages`
+    totals: HashMap<&'static str, (Duration, u32)>,
+}
+
+/// Starts recording scopes. Until then `scope` doesn't time anything.
+pub fn enable() {
+    lazy_static::initialize(&START);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Times the current scope until the guard is dropped, so it has to be bound to a variable
+/// like `let _scope = profile::scope("name");` rather than `_`.
+pub fn scope(name: &'static str) -> Scope {
+    OPEN.with(|open| open.borrow_mut().push(name));
+    Scope {
+        name,
+        start: if is_enabled() {
+            Some(Instant::now())
+        } else {
+            None
+        },
+    }
+}
+
+#[must_use = "the scope ends when the guard is dropped"]
+pub struct Scope {
+    name: &'static str,
+    start: Option<Instant>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        OPEN.with(|open| open.borrow_mut().pop());
+        if let Some(start) = self.start {
+            let duration = start.elapsed();
+            let span = Span {
+                name: self.name,
+                thread: THREAD.with(|thread| *thread),
+                start: start.duration_since(*START),
+                duration,
+            };
+            let mut recording = RECORDING.lock().unwrap();
+            let total = recording
+                .totals
+                .entry(self.name)
+                .or_insert((Duration::default(), 0));
+            total.0 += duration;
+            total.1 += 1;
+            recording.spans.push(span);
+        }
+    }
+}
+
+/// The scopes open on the current thread, outermost first, e.g.
+/// `pbr graph > TonemapPipeline::draw`. `None` outside of any scope.
+pub fn open_scopes() -> Option<String> {
+    OPEN.with(|open| {
+        let open = open.borrow();
+        if open.is_empty() {
+            None
+        } else {
+            Some(open.join(" > "))
+        }
+    })
+}
+
+/// The average duration and count of each scope since the last call, slowest first.
+pub fn take_averages() -> Vec<(&'static str, Duration, u32)> {
+    let mut recording = RECORDING.lock().unwrap();
+    let mut averages = recording
+        .totals
+        .drain()
+        .map(|(name, (total, count))| (name, total / count, count))
+        .collect::<Vec<_>>();
+    averages.sort_by(|a, b| b.1.cmp(&a.1));
+    averages
+}
+
+/// A complete event in the Chrome trace event format, timed in microseconds
+#[derive(Serialize)]
+struct TraceEvent {
+    name: &'static str,
+    ph: &'static str,
+    ts: f64,
+    dur: f64,
+    pid: u32,
+    tid: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace {
+    trace_events: Vec<TraceEvent>,
+    display_time_unit: &'static str,
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1_000_000.0 + f64::from(duration.subsec_nanos()) / 1_000.0
+}
+
+/// Writes every scope recorded so far to `path` as a Chrome trace.
+pub fn write_chrome_trace(path: &Path) -> Result<(), failure::Error> {
+    let recording = RECORDING.lock().unwrap();
+    let trace = Trace {
+        trace_events: recording
+            .spans
+            .iter()
+            .map(|span| TraceEvent {
+                name: span.name,
+                ph: "X",
+                ts: micros(span.start),
+                dur: micros(span.duration),
+                pid: 0,
+                tid: span.thread,
+            })
+            .collect(),
+        display_time_unit: "ms",
+    };
+    std::fs::write(path, serde_json::to_string(&trace)?)?;
+    log::info!(
+        "Wrote {} profiling scopes to {:?}",
+        recording.spans.len(),
+        path
+    );
+    Ok(())
+}
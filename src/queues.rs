@@ -0,0 +1,60 @@
+//! Queue selection. Besides the graphics queue everything is rendered with, one queue is
+//! requested from each family which is dedicated to transfer or compute work, if the device
+//! has such families, so that uploads and environment filtering can run alongside rendering.
+use rendy::{
+    command::{Families, FamilyId, QueueId},
+    core::DeviceId,
+    factory::QueuesConfigure,
+};
+
+use rendy::hal;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueConfig;
+
+unsafe impl QueuesConfigure for QueueConfig {
+    type Priorities = [f32; 1];
+    type Families = Vec<(FamilyId, [f32; 1])>;
+
+    fn configure(
+        self,
+        device: DeviceId,
+        families: &[impl hal::queue::QueueFamily],
+    ) -> Vec<(FamilyId, [f32; 1])> {
+        let graphics = families
+            .iter()
+            .find(|f| f.queue_type().supports_graphics() && f.max_queues() > 0);
+        let dedicated = |queue_type| {
+            families
+                .iter()
+                .find(move |f| f.queue_type() == queue_type && f.max_queues() > 0)
+        };
+
+        graphics
+            .into_iter()
+            .chain(dedicated(hal::queue::QueueType::Transfer))
+            .chain(dedicated(hal::queue::QueueType::Compute))
+            .map(|f| {
+                (
+                    FamilyId {
+                        device,
+                        index: f.id().0,
+                    },
+                    [1.0],
+                )
+            })
+            .collect()
+    }
+}
+
+/// Find the queue of a family which only supports the given type of work.
+pub fn dedicated_queue<B: hal::Backend>(
+    families: &Families<B>,
+    queue_type: hal::queue::QueueType,
+) -> Option<QueueId> {
+    families
+        .as_slice()
+        .iter()
+        .find(|family| family.capability() == queue_type)
+        .map(|family| family.as_slice()[0].id())
+}
@@ -0,0 +1,71 @@
+//! Rays from a camera through points of the window, to pick what is under the cursor.
+//!
+//! Cursor positions are taken in logical pixels as winit reports them, and converted with the
+//! window's scale factor without snapping them to pixel centers, so a ray lands exactly
+//! where the cursor points at any DPI and render scale.
+use crate::{input::ViewportInfo, node::pbr::CameraArgs};
+use rendy::init::winit::dpi::LogicalPosition;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: nalgebra::Point3<f32>,
+    pub direction: nalgebra::Unit<nalgebra::Vector3<f32>>,
+}
+
+impl Ray {
+    /// The point `t` units along the ray
+    pub fn at(&self, t: f32) -> nalgebra::Point3<f32> {
+        self.origin + self.direction.as_ref() * t
+    }
+}
+
+/// Normalized device coordinates of a cursor position, with y up as `Camera::projection`
+/// has it.
+pub fn cursor_to_ndc(pos: LogicalPosition, viewport: &ViewportInfo) -> nalgebra::Point2<f32> {
+    let pos = pos.to_physical(viewport.scale_factor);
+    nalgebra::Point2::new(
+        (2.0 * pos.x / viewport.physical_size.width - 1.0) as f32,
+        (1.0 - 2.0 * pos.y / viewport.physical_size.height) as f32,
+    )
+}
+
+/// The ray from the camera through the cursor at `pos`. Only the x and y rows of the
+/// projection are used, so it works the same with reversed or infinite depth.
+pub fn screen_ray(camera: &CameraArgs, pos: LogicalPosition, viewport: &ViewportInfo) -> Ray {
+    let ndc = cursor_to_ndc(pos, viewport);
+    let proj = &camera.proj;
+    // The point at a depth of 1 in view space which projects to `ndc`
+    let view_direction = nalgebra::Vector3::new(
+        (ndc.x + proj[(0, 2)]) / proj[(0, 0)],
+        (ndc.y + proj[(1, 2)]) / proj[(1, 1)],
+        -1.0,
+    );
+    let view_inverse = camera
+        .view
+        .try_inverse()
+        .expect("Camera view isn't invertible");
+    Ray {
+        origin: camera.camera_pos,
+        direction: nalgebra::Unit::new_normalize(
+            (view_inverse * view_direction.to_homogeneous()).xyz(),
+        ),
+    }
+}
+
+/// The camera of the main window as of the last dispatch, to cast rays from the cursor
+/// with. Kept up to date by `systems::RaycastUpdateSystem`.
+#[derive(Debug, Default)]
+pub struct Raycast {
+    pub camera: Option<CameraArgs>,
+    pub viewport: Option<ViewportInfo>,
+}
+
+impl Raycast {
+    /// `None` before the first dispatch, or while there is no active camera
+    pub fn cursor_ray(&self, pos: LogicalPosition) -> Option<Ray> {
+        match (&self.camera, &self.viewport) {
+            (Some(camera), Some(viewport)) => Some(screen_ray(camera, pos, viewport)),
+            _ => None,
+        }
+    }
+}
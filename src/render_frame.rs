@@ -0,0 +1,212 @@
+//! The extract phase between simulation and rendering. `RenderExtractSystem` runs after every
+//! other system that changes what is drawn, and copies the little the render graph needs out of
+//! the ECS into a `RenderFrame`: the cameras, the lights, the mesh instances and the billboards.
+//! Nodes read it in `prepare` instead of joining component storages.
+//!
+//! The frames are double buffered in `RenderFrames`. The system fills the back one and swaps
+//! it to the front once it's done, so the front one is never written while the graph renders
+//! it, which is what lets simulation run alongside rendering later on.
+use crate::{
+    asset, components,
+    node::pbr::{CameraArgs, LightData},
+    systems::{InstanceIndex, MeshInstance, MeshInstanceStorage},
+};
+use specs::prelude::*;
+
+/// A camera the scene is drawn from
+#[derive(Debug, Clone, Copy)]
+pub struct RenderView {
+    pub camera: CameraArgs,
+    /// The layers of the entities it sees, see `components::Layers`
+    pub layers: u32,
+}
+
+/// An entity's mesh instance, as the mesh pipelines, culling and shadows draw it
+#[derive(Debug, Clone, Copy)]
+pub struct RenderInstance {
+    /// Id of the entity, which the bitsets of `systems::InstanceCache` are indexed by
+    pub id: specs::world::Index,
+    pub mesh: asset::MeshHandle,
+    pub instance: InstanceIndex,
+    pub transform: nalgebra::Matrix4<f32>,
+    /// See `components::Layers`
+    pub layers: u32,
+    /// See `components::LightList`
+    pub light_mask: u32,
+    pub casts_shadows: bool,
+    /// See `components::Static`
+    pub is_static: bool,
+}
+
+impl RenderInstance {
+    /// Whether a view seeing the layers of `mask` sees it
+    pub fn visible(&self, mask: u32) -> bool {
+        self.layers & mask != 0
+    }
+}
+
+/// A billboard at its entity's position
+#[derive(Debug, Clone, Copy)]
+pub struct RenderBillboard {
+    pub position: nalgebra::Point3<f32>,
+    pub billboard: components::Billboard,
+}
+
+/// What the render graph draws in a frame, copied out of the ECS.
+#[derive(Debug, Default)]
+pub struct RenderFrame {
+    /// From the active camera, `None` if the scene has none
+    pub main_view: Option<RenderView>,
+    /// From the camera marked with `components::DebugWindowCamera`, if there is one
+    pub debug_view: Option<RenderView>,
+    /// At most `MAX_LIGHTS` of them
+    pub lights: Vec<LightData>,
+    /// Ordered by entity id
+    pub instances: Vec<RenderInstance>,
+    pub billboards: Vec<RenderBillboard>,
+    /// The index in `instances` of each entity's instance, by entity id
+    instance_indices: Vec<Option<u32>>,
+}
+
+impl RenderFrame {
+    /// The instance of the entity with id `id`, if it has one.
+    pub fn instance(&self, id: specs::world::Index) -> Option<&RenderInstance> {
+        let index = (*self.instance_indices.get(id as usize)?)?;
+        Some(&self.instances[index as usize])
+    }
+
+    /// Empties the frame, keeping its allocations for the next one.
+    fn clear(&mut self) {
+        self.main_view = None;
+        self.debug_view = None;
+        self.lights.clear();
+        self.instances.clear();
+        self.billboards.clear();
+        self.instance_indices.clear();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RenderFrames {
+    frames: [RenderFrame; 2],
+    front: usize,
+}
+
+impl RenderFrames {
+    /// The last frame extracted, which the graph renders
+    pub fn front(&self) -> &RenderFrame {
+        &self.frames[self.front]
+    }
+}
+
+/// Copies the render state of the frame into the back `RenderFrame`, then swaps it to the
+/// front. Runs after the instances, levels of detail and light lists are updated.
+pub struct RenderExtractSystem;
+
+impl<'a> System<'a> for RenderExtractSystem {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, RenderFrames>,
+        Read<'a, MeshInstanceStorage>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::Camera>,
+        ReadStorage<'a, components::ActiveCamera>,
+        ReadStorage<'a, components::DebugWindowCamera>,
+        ReadStorage<'a, components::Light>,
+        ReadStorage<'a, components::Mesh>,
+        ReadStorage<'a, components::Layers>,
+        ReadStorage<'a, components::NoShadows>,
+        ReadStorage<'a, components::Static>,
+        ReadStorage<'a, components::LightList>,
+        ReadStorage<'a, components::Billboard>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut frames,
+            mesh_instance_storage,
+            transforms,
+            cameras,
+            active_cameras,
+            debug_cameras,
+            lights,
+            meshes,
+            layers,
+            no_shadows,
+            statics,
+            light_lists,
+            billboards,
+        ): Self::SystemData,
+    ) {
+        let _scope = crate::profile::scope("RenderExtractSystem");
+        let back = 1 - frames.front;
+        let frame = &mut frames.frames[back];
+        frame.clear();
+
+        let view =
+            |camera: &components::Camera, transform: &components::GlobalTransform| RenderView {
+                camera: (camera, transform).into(),
+                layers: camera.layers,
+            };
+        frame.main_view = (&active_cameras, &cameras, &transforms)
+            .join()
+            .map(|(_, camera, transform)| view(camera, transform))
+            .next();
+        frame.debug_view = (&debug_cameras, &cameras, &transforms)
+            .join()
+            .map(|(_, camera, transform)| view(camera, transform))
+            .next();
+
+        frame.lights.extend(
+            (&lights, &transforms)
+                .join()
+                .take(crate::MAX_LIGHTS)
+                .map(|(light, transform)| LightData::new(light, transform)),
+        );
+
+        for (entity, transform, _, layers, no_shadows, is_static, light_list) in (
+            &entities,
+            &transforms,
+            &meshes,
+            layers.maybe(),
+            no_shadows.maybe(),
+            statics.maybe(),
+            light_lists.maybe(),
+        )
+            .join()
+        {
+            let MeshInstance { mesh, instance } = match mesh_instance_storage.get(entity.id()) {
+                Some(mesh_instance) => *mesh_instance,
+                None => continue,
+            };
+            let id = entity.id();
+            if frame.instance_indices.len() <= id as usize {
+                frame.instance_indices.resize(id as usize + 1, None);
+            }
+            frame.instance_indices[id as usize] = Some(frame.instances.len() as u32);
+            frame.instances.push(RenderInstance {
+                id,
+                mesh,
+                instance,
+                transform: transform.0,
+                layers: layers.map_or(1, |layers| layers.0),
+                light_mask: light_list.map_or(0, |light_list| light_list.0),
+                casts_shadows: no_shadows.is_none(),
+                is_static: is_static.is_some(),
+            });
+        }
+
+        frame.billboards.extend(
+            (&billboards, &transforms)
+                .join()
+                .map(|(billboard, transform)| RenderBillboard {
+                    position: nalgebra::Point3::from(transform.0.column(3).xyz()),
+                    billboard: *billboard,
+                }),
+        );
+
+        frames.front = back;
+    }
+}
@@ -0,0 +1,296 @@
+//! Recording of the input events of a run and their replay, to reproduce input dependent
+//! bugs on another run or machine.
+//!
+//! A log starts with a header holding the scene and settings the run started with, followed
+//! by one line per frame with the events the systems saw during it and the time it advanced
+//! them by. Each line is written as soon as its frame is done, so a log survives a crash of
+//! the run it records. Replaying feeds the recorded events and frame times to the systems in
+//! place of the live ones; the window must be left alone until the replay is done.
+use crate::{components, node, settings};
+use rendy::init::winit::{
+    dpi::{LogicalPosition, LogicalSize},
+    event::{
+        DeviceEvent, DeviceId, ElementState, Event, KeyboardInput, ModifiersState, MouseButton,
+        MouseScrollDelta, WindowEvent,
+    },
+    window::WindowId,
+};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+/// The state a recorded run started from, which the replay starts from as well.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    /// Path of the scene config, as given on the command line
+    pub scene: String,
+    /// The settings the run started with, including the size of its window
+    pub settings: settings::Settings,
+}
+
+/// The events the systems read, without the ids winit gives them, which can't be
+/// serialized.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RecordedEvent {
+    Resized(LogicalSize),
+    HiDpiFactorChanged(f64),
+    CursorMoved {
+        position: LogicalPosition,
+        modifiers: ModifiersState,
+    },
+    MouseInput {
+        state: ElementState,
+        button: MouseButton,
+        modifiers: ModifiersState,
+    },
+    KeyboardInput(KeyboardInput),
+    MouseMotion {
+        delta: (f64, f64),
+    },
+    MouseWheel {
+        delta: MouseScrollDelta,
+    },
+}
+
+impl RecordedEvent {
+    /// The recorded form of an event, or `None` if no system reads it
+    pub fn from_event(event: &Event<()>) -> Option<Self> {
+        match event {
+            Event::WindowEvent { event, .. } => match *event {
+                WindowEvent::Resized(size) => Some(RecordedEvent::Resized(size)),
+                WindowEvent::HiDpiFactorChanged(scale_factor) => {
+                    Some(RecordedEvent::HiDpiFactorChanged(scale_factor))
+                }
+                WindowEvent::CursorMoved {
+                    position,
+                    modifiers,
+                    ..
+                } => Some(RecordedEvent::CursorMoved {
+                    position,
+                    modifiers,
+                }),
+                WindowEvent::MouseInput {
+                    state,
+                    button,
+                    modifiers,
+                    ..
+                } => Some(RecordedEvent::MouseInput {
+                    state,
+                    button,
+                    modifiers,
+                }),
+                WindowEvent::KeyboardInput { input, .. } => {
+                    Some(RecordedEvent::KeyboardInput(input))
+                }
+                _ => None,
+            },
+            Event::DeviceEvent { event, .. } => match *event {
+                DeviceEvent::MouseMotion { delta } => Some(RecordedEvent::MouseMotion { delta }),
+                DeviceEvent::MouseWheel { delta } => Some(RecordedEvent::MouseWheel { delta }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The event as winit would have sent it to the given window
+    pub fn to_event(&self, window_id: WindowId) -> Event<()> {
+        // The systems don't tell devices apart
+        let device_id = unsafe { DeviceId::dummy() };
+        let window_event = |event| Event::WindowEvent { window_id, event };
+        let device_event = |event| Event::DeviceEvent { device_id, event };
+        match *self {
+            RecordedEvent::Resized(size) => window_event(WindowEvent::Resized(size)),
+            RecordedEvent::HiDpiFactorChanged(scale_factor) => {
+                window_event(WindowEvent::HiDpiFactorChanged(scale_factor))
+            }
+            RecordedEvent::CursorMoved {
+                position,
+                modifiers,
+            } => window_event(WindowEvent::CursorMoved {
+                device_id,
+                position,
+                modifiers,
+            }),
+            RecordedEvent::MouseInput {
+                state,
+                button,
+                modifiers,
+            } => window_event(WindowEvent::MouseInput {
+                device_id,
+                state,
+                button,
+                modifiers,
+            }),
+            RecordedEvent::KeyboardInput(input) => {
+                window_event(WindowEvent::KeyboardInput { device_id, input })
+            }
+            RecordedEvent::MouseMotion { delta } => {
+                device_event(DeviceEvent::MouseMotion { delta })
+            }
+            RecordedEvent::MouseWheel { delta } => device_event(DeviceEvent::MouseWheel { delta }),
+        }
+    }
+}
+
+/// One dispatch of the systems
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Seconds since the first frame, when this one started
+    pub time: f64,
+    /// Seconds the systems were advanced by
+    pub delta: f32,
+    pub events: Vec<RecordedEvent>,
+}
+
+/// Where the events of each frame come from, and whether they are written down.
+pub enum EventLog {
+    Live,
+    Record {
+        writer: BufWriter<File>,
+        time: f64,
+    },
+    Replay {
+        frames: VecDeque<RecordedFrame>,
+        replayed: usize,
+    },
+}
+
+impl EventLog {
+    /// Starts a log at `path`, replacing any file there
+    pub fn record(path: &Path, header: &Header) -> Result<Self, failure::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", ron::ser::to_string(header)?)?;
+        log::info!("Recording events to {:?}", path);
+        Ok(EventLog::Record { writer, time: 0.0 })
+    }
+
+    /// Reads the log at `path`, returning its header and the log to replay it
+    pub fn replay(path: &Path) -> Result<(Header, Self), failure::Error> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = match lines.next() {
+            Some(line) => ron::de::from_str(&line?)?,
+            None => failure::bail!("Event log {:?} is empty", path),
+        };
+        let mut frames = VecDeque::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            match ron::de::from_str(&line) {
+                Ok(frame) => frames.push_back(frame),
+                // The recorded run may have crashed while writing its last frame
+                Err(e) => {
+                    log::warn!("Ignoring the rest of {:?} from frame {}: {}", path, i, e);
+                    break;
+                }
+            }
+        }
+        log::info!("Replaying {} frames from {:?}", frames.len(), path);
+        Ok((
+            header,
+            EventLog::Replay {
+                frames,
+                replayed: 0,
+            },
+        ))
+    }
+
+    pub fn is_replay(&self) -> bool {
+        match self {
+            EventLog::Replay { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether a replay has played all of its frames
+    pub fn is_finished(&self) -> bool {
+        match self {
+            EventLog::Replay { frames, .. } => frames.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Prepares the events of the next frame. Records the live `events` and `delta`, or
+    /// replaces them by the recorded ones when replaying. Returns the frame's delta time.
+    /// Once a replay is done, the live events are used again.
+    pub fn frame(
+        &mut self,
+        events: &mut Vec<Event<()>>,
+        delta: f32,
+        window_id: WindowId,
+    ) -> Result<f32, failure::Error> {
+        match self {
+            EventLog::Live => Ok(delta),
+            EventLog::Record { writer, time } => {
+                let frame = RecordedFrame {
+                    time: *time,
+                    delta,
+                    events: events
+                        .iter()
+                        .filter_map(RecordedEvent::from_event)
+                        .collect(),
+                };
+                writeln!(writer, "{}", ron::ser::to_string(&frame)?)?;
+                writer.flush()?;
+                *time += delta as f64;
+                Ok(delta)
+            }
+            EventLog::Replay { frames, replayed } => match frames.pop_front() {
+                Some(frame) => {
+                    events.clear();
+                    events.extend(frame.events.iter().map(|e| e.to_event(window_id)));
+                    *replayed += 1;
+                    if frames.is_empty() {
+                        log::info!("Replay finished after {} frames", replayed);
+                    }
+                    Ok(frame.delta)
+                }
+                None => Ok(delta),
+            },
+        }
+    }
+
+    /// The state a replay ended in, for runs to be compared with each other
+    pub fn summary(&self, world: &specs::World) -> ReplaySummary {
+        let replayed = match self {
+            EventLog::Replay { replayed, .. } => *replayed,
+            _ => 0,
+        };
+        let transforms = world.read_storage::<components::GlobalTransform>();
+        let active_cameras = world.read_storage::<components::ActiveCamera>();
+        let (camera_position, camera_forward) = (&active_cameras, &transforms)
+            .join()
+            .next()
+            .map(|(_, transform)| {
+                let position = transform.0.column(3).xyz();
+                let forward = -transform.0.column(2).xyz().normalize();
+                (
+                    [position.x, position.y, position.z],
+                    [forward.x, forward.y, forward.z],
+                )
+            })
+            .unwrap_or_default();
+        let tonemapper_args = world.read_resource::<node::pbr::Aux>().tonemapper_args;
+        ReplaySummary {
+            frames: replayed,
+            camera_position,
+            camera_forward,
+            exposure_ev100: tonemapper_args.exposure_ev100,
+            tonemap_curve: tonemapper_args.curve,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplaySummary {
+    pub frames: usize,
+    pub camera_position: [f32; 3],
+    pub camera_forward: [f32; 3],
+    pub exposure_ev100: f32,
+    pub tonemap_curve: i32,
+}
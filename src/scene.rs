@@ -1,15 +1,22 @@
 //! A simple scene description format which allows loading models (meshes) and transforms
 //! from multiple glTF files, as well as to define a scene graph hierarchy and cameras and lights.
-use crate::{asset, components};
+use crate::{
+    asset, components,
+    geometry_pool::{GeometryPool, GeometryPoolBuilder},
+    prefab,
+    slot_map::SlotMap,
+};
 
+use derivative::Derivative;
 use rendy::hal;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use specs::prelude::*;
 
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
     fs::File,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 /// The path to the base directory of a glTF asset
@@ -24,15 +31,220 @@ pub type GltfFileIndex = usize;
 /// a list of entities in the scene.
 #[derive(Debug, Deserialize)]
 pub struct SceneConfig {
-    pub environment_map: String,
-    pub environment_filter_quality: Quality,
+    pub environment_map: EnvironmentSource,
+    /// The qualities to filter the environment map at, in order. Each one replaces the
+    /// result of the previous one once it is done, so a quick first pass can be followed
+    /// by slower, better ones.
+    pub environment_filter_quality: Vec<Quality>,
+    /// Switches the specular filter between fast and high quality sampling, and optionally
+    /// sets its sample count for each mip level.
+    #[serde(default)]
+    pub specular_filter: crate::node::env_preprocess::filter::SpecularFilterSettings,
+    /// The cube map an equirectangular environment map is converted to. Picked from the
+    /// map's resolution by default.
+    #[serde(default)]
+    pub environment_cubemap: EnvironmentCubemapSettings,
     pub mipmap_model_textures: bool,
+    /// Read the environment cube maps back once they are created and check that every face
+    /// of every mip was written, logging a warning otherwise. Slow, so off by default.
+    #[serde(default)]
+    pub validate_environment: bool,
+    /// A directory to write every mip of the environment cube maps and the BRDF lookup table
+    /// to once they are created, as PNG and OpenEXR images. Slow, so only meant for debugging.
+    #[serde(default)]
+    pub dump_environment: Option<String>,
+    /// The number of worker threads used to run systems. Defaults to one per logical core.
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    /// Whether to compile the material debug views into the PBR shaders.
+    #[serde(default)]
+    pub shader_debug_views: bool,
+    /// How strongly ambient occlusion attenuates diffuse and specular image based lighting.
+    #[serde(default)]
+    pub occlusion: crate::node::pbr::OcclusionSettings,
+    /// Step counts of parallax occlusion mapping, for materials with a height texture.
+    #[serde(default)]
+    pub parallax: crate::node::pbr::ParallaxSettings,
+    /// How far the spheres of the white furnace test may be from the environment's radiance,
+    /// see `crate::furnace`.
+    #[serde(default)]
+    pub furnace: crate::furnace::FurnaceTolerance,
+    /// How far a panorama of the scene may be from its golden image, see `crate::golden`.
+    #[serde(default)]
+    pub golden: crate::golden::GoldenTolerance,
+    /// Samples of the reference image based lighting, which is toggled at runtime.
+    #[serde(default)]
+    pub ibl_reference: crate::node::pbr::IblReferenceSettings,
+    /// Height fog and aerial perspective. Disabled by default.
+    #[serde(default)]
+    pub fog: crate::node::pbr::FogSettings,
+    /// What is drawn behind the meshes. Defaults to the environment map.
+    #[serde(default)]
+    pub background: crate::node::pbr::environment_map::Background,
+    /// Keep the alpha channel through tonemapping and ask for a transparent window, so the
+    /// render can be composited over other windows. Use with a `Transparent` background.
+    /// Whether the window actually shows through depends on the platform's compositor.
+    #[serde(default)]
+    pub transparent_window: bool,
+    /// Use a reversed depth range, which avoids z-fighting in large scenes with a close
+    /// near plane.
+    #[serde(default)]
+    pub reversed_z: bool,
+    /// Which optional passes the render graph is built with.
+    #[serde(default)]
+    pub render_settings: RenderSettings,
+    /// Opens a second window showing the scene from the camera marked `debug_window`, or one
+    /// of the environment cube maps.
+    #[serde(default)]
+    pub debug_window: Option<DebugWindowSettings>,
+    /// Use 16 bit float formats for the HDR target and the rendered environment
+    /// intermediates instead of 32 bit ones. The filtered irradiance and specular cube maps
+    /// and cube maps loaded from files stay 32 bit, since their data is written as such.
+    #[serde(default)]
+    pub half_float_targets: bool,
+    /// The ground grid, hidden by default.
+    #[serde(default)]
+    pub grid: crate::node::pbr::grid::GridSettings,
+    /// A grid of light probes to bake at load, giving meshes indirect diffuse light from
+    /// the scene around them. Without one, only the environment map lights them.
+    #[serde(default)]
+    pub probes: Option<crate::probes::ProbeGridSettings>,
+    /// Experimental dynamic indirect light by voxel cone tracing, disabled by default.
+    #[serde(default)]
+    pub voxel_gi: crate::voxel_gi::VoxelGiSettings,
+    /// A Rhai script run every frame, relative to the application root. Only used when built
+    /// with the `scripting` feature, see `crate::script`.
+    #[serde(default)]
+    pub script: Option<String>,
+    /// The unit scale and up axis of the glTF sources, if they don't follow the glTF
+    /// convention of Y-up and meters.
+    #[serde(default)]
+    pub import: ImportSettings,
     pub gltf_sources: Vec<(BasePath, Filename)>,
+    /// Materials for meshes which don't come with their own, like OBJ, PLY and procedural
+    /// ones.
+    #[serde(default)]
+    pub materials: Vec<SceneMaterial>,
     pub entities: Vec<SceneEntity>,
+    /// Reusable entity templates, see `crate::prefab`.
+    #[serde(default)]
+    pub prefabs: Vec<prefab::Prefab>,
+    /// Copies of the prefabs placed in the scene, after `entities`. Scripts don't see them.
+    #[serde(default)]
+    pub instances: Vec<prefab::PrefabInstances>,
+    /// The prefab copied in rows with X, Y and Z at runtime. Without one, they do nothing.
+    #[serde(default)]
+    pub array: Option<prefab::ArraySettings>,
+    /// Merge the meshes of the entities marked `static` sharing a material when the scene is
+    /// loaded, see `crate::static_batch`. Off by default.
+    #[serde(default)]
+    pub static_batching: bool,
+}
+
+/// The optional passes of the main window's render graph. Everything but occlusion culling is
+/// on by default.
+#[derive(Debug, Derivative, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct RenderSettings {
+    /// Quality of the shadowed light's cascades, which caps the resolution and number of
+    /// cascades its settings ask for. `None` draws no shadows at all. `High` keeps the light's
+    /// settings as they are.
+    #[derivative(Default(value = "Some(Quality::High)"))]
+    pub shadows: Option<Quality>,
+    /// Skip drawing meshes hidden behind others in the main view, found by testing their
+    /// bounding spheres against a depth pre-pass. Off by default, since the pre-pass costs
+    /// more than it saves in scenes without much occlusion.
+    pub occlusion_culling: bool,
+    /// Bin the rendered image into the histogram and waveform scopes. Without it the scopes
+    /// can't be shown.
+    #[derivative(Default(value = "true"))]
+    pub scopes: bool,
+    /// Blur the diffuse light of subsurface scattering materials under their surface. Without
+    /// it they only have wrap lighting and transmission, and no pass is added for scenes
+    /// without such materials.
+    #[derivative(Default(value = "true"))]
+    pub subsurface_scattering: bool,
+}
+
+/// The second window opened with `debug_window`. Closing it only hides it, and it isn't
+/// resized with the main window.
+#[derive(Debug, Derivative, Clone, Copy, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct DebugWindowSettings {
+    /// Logical size of the window
+    #[derivative(Default(value = "(640.0, 480.0)"))]
+    pub size: (f64, f64),
+    /// Show only this cube map around the debug window's camera, instead of the scene
+    pub cube_map: Option<crate::node::pbr::environment_map::CubeDisplay>,
+}
+
+/// Where the environment map is loaded from. Paths are relative to the application root.
+#[derive(Debug, Clone, Deserialize)]
+pub enum EnvironmentSource {
+    /// A Radiance `.hdr` or OpenEXR `.exr` image in equirectangular projection, converted to
+    /// a cube map on load
+    Equirectangular(String),
+    /// A finished cube map as one image per face, in the order +X, -X, +Y, -Y, +Z, -Z
+    CubeFaces(Vec<String>),
+    /// A finished cube map in a KTX file, with 16 or 32 bit float texels
+    Ktx(String),
+    /// The same linear radiance in every direction, as used by the white furnace test
+    Uniform([f32; 3]),
+}
+
+/// Overrides of the cube map an equirectangular environment map is converted to, see
+/// `crate::node::env_preprocess::pipeline`.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct EnvironmentCubemapSettings {
+    /// Width and height of each face
+    pub resolution: Option<u32>,
+    pub mip_levels: Option<u8>,
+}
+
+/// Conventions the glTF sources were exported with. Transforms loaded from glTF nodes are
+/// converted from them to the renderer's Y-up, meter based world.
+#[derive(Debug, Derivative, Clone, Copy, Deserialize)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct ImportSettings {
+    /// Size of one source unit in meters, e.g. 0.01 for centimeters
+    #[derivative(Default(value = "1.0"))]
+    pub unit_scale: f32,
+    pub up_axis: UpAxis,
+}
+
+#[derive(Debug, Derivative, Clone, Copy, Deserialize)]
+#[derivative(Default)]
+pub enum UpAxis {
+    #[derivative(Default)]
+    Y,
+    Z,
+}
+
+impl ImportSettings {
+    /// Transform from the source's world space to the renderer's
+    pub fn conversion(&self) -> nalgebra::Similarity3<f32> {
+        let rotation = match self.up_axis {
+            UpAxis::Y => nalgebra::UnitQuaternion::identity(),
+            // Turns +Z into +Y, and +Y into -Z
+            UpAxis::Z => nalgebra::UnitQuaternion::from_axis_angle(
+                &nalgebra::Vector3::x_axis(),
+                -std::f32::consts::FRAC_PI_2,
+            ),
+        };
+        nalgebra::Similarity3::from_parts(
+            nalgebra::Translation3::identity(),
+            rotation,
+            self.unit_scale,
+        )
+    }
 }
 
 /// Determines the quality of some part of the render
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Quality {
     Low,
     Medium,
@@ -43,7 +255,7 @@ pub enum Quality {
 pub type SceneEntityIndex = usize;
 
 /// An entity in the scene. Must have a transform, and can optionally have
-/// a parent, mesh, light, and camera components.
+/// a parent, mesh, light, camera, billboard, particle emitter and animation components.
 #[derive(Debug, Deserialize)]
 pub struct SceneEntity {
     /// The transform of this entity. Can either be specified manually in the scene config file
@@ -57,10 +269,73 @@ pub struct SceneEntity {
     /// be loaded from the index of the mesh in the glTF source file, or from the index of a node in the
     /// glTF file.
     mesh: Option<MeshSource>,
+    /// Draws every primitive of the mesh with this material instead of its own, e.g. one of
+    /// the scene's `materials` on a mesh from a glTF file
+    material: Option<MaterialSource>,
     /// Designates this entity as a light, with an intensity and color
     light: Option<components::Light>,
     /// Designates this entity as a camera, with associated camera parameters
     camera: Option<CameraData>,
+    /// Draws a camera-facing sprite at this entity's position, e.g. to show where a light is
+    billboard: Option<components::Billboard>,
+    /// Continuously emits particles from this entity's position
+    particle_emitter: Option<components::ParticleEmitter>,
+    /// Rotates this entity continuously
+    spin: Option<components::Spin>,
+    /// Moves this entity back and forth along an axis
+    oscillate: Option<components::Oscillate>,
+    /// Circles this entity around a point
+    orbit: Option<components::Orbit>,
+    /// Baked indirect diffuse light for this entity's mesh, used instead of the light probes
+    /// and irradiance map. Every instance of the mesh shares it.
+    lightmap: Option<LightmapSettings>,
+    /// Simpler meshes drawn instead of `mesh` as it gets smaller on screen. Every instance of
+    /// the mesh shares them.
+    #[serde(default)]
+    lods: Vec<LodSource>,
+    /// Whether this entity's mesh is drawn into the shadow maps
+    #[serde(default = "SceneEntity::default_casts_shadows")]
+    casts_shadows: bool,
+    /// Marks this entity as never moving, so its mesh's shadows are cached
+    #[serde(default, rename = "static")]
+    is_static: bool,
+    /// The layers this entity is in, which cameras and shadows see it, see
+    /// `components::Layers`. The first layer only by default.
+    #[serde(default)]
+    layers: Option<components::Layers>,
+}
+
+impl SceneEntity {
+    fn default_casts_shadows() -> bool {
+        true
+    }
+}
+
+/// A level of detail of an entity's mesh
+#[derive(Debug, Deserialize)]
+pub struct LodSource {
+    pub mesh: MeshSource,
+    /// The height of the mesh's bounding sphere on screen, as a fraction of the screen height,
+    /// below which this level is drawn
+    pub screen_size: f32,
+}
+
+/// A lightmap, addressed by a mesh's second set of texture coordinates, or its first if it
+/// only has one. Loaded from `path` if the file exists, and written there when baked with
+/// `--bake-lightmaps`.
+#[derive(Debug, Deserialize)]
+pub struct LightmapSettings {
+    /// Relative to the application root. OpenEXR is best, as the values aren't clamped.
+    pub path: String,
+    /// Width and height of the lightmap when baked
+    #[serde(default = "LightmapSettings::default_resolution")]
+    pub resolution: u32,
+}
+
+impl LightmapSettings {
+    fn default_resolution() -> u32 {
+        64
+    }
 }
 
 /// The source of the transform.
@@ -79,6 +354,52 @@ pub enum MeshSource {
     Node(GltfNode),
     /// A mesh in a glTF source file
     Mesh(GltfMesh),
+    /// A mesh in an OBJ or PLY file, which has no materials of its own
+    Obj(MeshFile),
+    /// A primitive shape generated on load
+    Procedural(ProceduralMesh),
+}
+
+/// A generated mesh, with a material defined in the scene file.
+#[derive(Debug, Deserialize)]
+pub struct ProceduralMesh {
+    pub shape: crate::procedural::Shape,
+    #[serde(default)]
+    pub material: MaterialSource,
+}
+
+/// The material of a mesh which doesn't come with one.
+#[derive(Debug, Deserialize)]
+pub enum MaterialSource {
+    /// Constant factors, for this mesh only
+    Inline(asset::InlineMaterial),
+    /// A material from the `materials` list of the scene config, by name
+    Named(String),
+}
+
+impl Default for MaterialSource {
+    fn default() -> Self {
+        MaterialSource::Inline(Default::default())
+    }
+}
+
+/// A material defined in the scene file, which meshes refer to by name.
+#[derive(Debug, Deserialize)]
+pub struct SceneMaterial {
+    pub name: String,
+    #[serde(default)]
+    pub factors: asset::InlineMaterial,
+    #[serde(default)]
+    pub textures: asset::MaterialTextures,
+}
+
+/// A mesh loaded from an OBJ or PLY file, with a material defined in the scene file.
+#[derive(Debug, Deserialize)]
+pub struct MeshFile {
+    /// Relative to the application root
+    pub path: String,
+    #[serde(default)]
+    pub material: MaterialSource,
 }
 
 /// Data for the camera. This is an orbiting camera which orbits at a distance
@@ -92,8 +413,24 @@ pub struct CameraData {
     pub fov: f32,
     pub znear: f32,
     pub zfar: f32,
+    /// Put the far plane at infinity, ignoring `zfar`. Best used with `reversed_z`.
+    #[serde(default)]
+    pub infinite_far: bool,
     /// Whether this is thet active (primary) camera. There can only be one active camera at a time.
     pub active: bool,
+    /// Whether this camera is shown in the debug window, if the scene opens one. It isn't
+    /// moved by input.
+    #[serde(default)]
+    pub debug_window: bool,
+    /// The layers of the entities this camera sees, as a mask. All of them by default.
+    #[serde(default = "CameraData::default_layers")]
+    pub layers: u32,
+}
+
+impl CameraData {
+    fn default_layers() -> u32 {
+        components::Layers::ALL
+    }
 }
 
 /// A glTF node in one of the source files.
@@ -117,46 +454,260 @@ pub enum GltfMesh {
 impl SceneConfig {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
         let path = Path::new(&crate::application_root_dir()).join(path.as_ref());
-        let file = File::open(path).unwrap();
+        let file = File::open(&path).map_err(|e| failure::format_err!("{:?}: {}", path, e))?;
         let reader = std::io::BufReader::new(file);
         ron::de::from_reader(reader).map_err(From::from)
     }
 
+    /// The directory and path of each glTF source.
+    fn gltf_paths(&self) -> impl Iterator<Item = (PathBuf, PathBuf)> + '_ {
+        self.gltf_sources.iter().map(|(base_path, file_name)| {
+            let base_path = Path::new(&crate::application_root_dir()).join(base_path);
+            let file_path = base_path.join(file_name);
+            (base_path, file_path)
+        })
+    }
+
+    /// Checks that the glTF sources can be parsed and only use the buffers and images the
+    /// loader supports, without loading them. Scenes are switched to by unloading the current
+    /// one first, so this catches most of what would fail halfway through `load`.
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        for (base_path, file_path) in self.gltf_paths() {
+            let checked = File::open(&file_path)
+                .map_err(failure::Error::from)
+                .and_then(|file| {
+                    let gltf = gltf::Gltf::from_reader(std::io::BufReader::new(file))?;
+                    asset::check_gltf_sources(&base_path, &gltf)
+                });
+            checked.map_err(|e| failure::format_err!("{:?}: {}", file_path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Adds a glTF file to the sources, and an entity for each node of its default scene, or
+    /// its first one, at the same place in the hierarchy. Used for files dropped onto the
+    /// window.
+    pub fn add_gltf(&mut self, path: &Path) -> Result<(), failure::Error> {
+        let gltf = gltf::Gltf::from_reader(std::io::BufReader::new(File::open(path)?))?;
+        let scene = gltf
+            .default_scene()
+            .or_else(|| gltf.scenes().next())
+            .ok_or_else(|| failure::format_err!("{:?} has no scene", path))?;
+
+        let file = self.gltf_sources.len();
+        let base_path = path.parent().unwrap_or_else(|| Path::new(""));
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| failure::format_err!("{:?} is not a file", path))?;
+        self.gltf_sources.push((
+            base_path.to_string_lossy().into_owned(),
+            file_name.to_string_lossy().into_owned(),
+        ));
+
+        let mut nodes = scene.nodes().map(|node| (node, None)).collect::<Vec<_>>();
+        while let Some((node, parent)) = nodes.pop() {
+            let index = self.entities.len();
+            let gltf_node = || GltfNode::Index(file, node.index());
+            self.entities.push(SceneEntity {
+                transform: TransformSource::Gltf(gltf_node()),
+                parent,
+                mesh: node.mesh().map(|_| MeshSource::Node(gltf_node())),
+                material: None,
+                light: None,
+                camera: None,
+                billboard: None,
+                particle_emitter: None,
+                spin: None,
+                oscillate: None,
+                orbit: None,
+                lightmap: None,
+                lods: Vec::new(),
+                casts_shadows: SceneEntity::default_casts_shadows(),
+                is_static: false,
+                layers: None,
+            });
+            nodes.extend(node.children().map(|child| (child, Some(index))));
+        }
+        Ok(())
+    }
+
+    /// The handle of a named material, or a new material built from inline factors.
+    fn resolve_material<B: hal::Backend>(
+        &self,
+        source: &MaterialSource,
+        material_names: &HashMap<String, asset::MaterialHandle>,
+        material_storage: &mut Vec<Option<asset::MaterialData<B>>>,
+        factory: &mut rendy::factory::Factory<B>,
+        queues: &crate::upload::UploadQueues,
+    ) -> Result<asset::MaterialHandle, failure::Error> {
+        match source {
+            MaterialSource::Named(name) => material_names
+                .get(name)
+                .cloned()
+                .ok_or_else(|| failure::format_err!("Material {:?} is not defined", name)),
+            MaterialSource::Inline(factors) => {
+                material_storage.push(Some(asset::build_material(
+                    factors,
+                    &Default::default(),
+                    false,
+                    factory,
+                    queues,
+                )?));
+                Ok(asset::MaterialHandle::from_index(
+                    material_storage.len() - 1,
+                ))
+            }
+        }
+    }
+
+    /// Loads the mesh of an entity, or finds it among the meshes of the glTF files.
+    fn load_mesh<B: hal::Backend>(
+        &self,
+        source: &MeshSource,
+        gltfs: &[gltf::Gltf],
+        gltf_file_offsets: &[(usize, usize, usize)],
+        material_names: &HashMap<String, asset::MaterialHandle>,
+        material_storage: &mut Vec<Option<asset::MaterialData<B>>>,
+        primitive_storage: &mut Vec<Option<asset::Primitive>>,
+        mesh_storage: &mut Vec<Option<asset::Mesh>>,
+        geometry_pool: &mut GeometryPoolBuilder,
+        factory: &mut rendy::factory::Factory<B>,
+        queues: &crate::upload::UploadQueues,
+    ) -> Result<asset::MeshHandle, failure::Error> {
+        match source {
+            MeshSource::Node(gltf_node) => {
+                let src: GltfFileIndex = gltf_node.into();
+                if src >= gltfs.len() {
+                    failure::bail!("Data source Gltf File of {:?} out of bounds", gltf_node);
+                }
+                let node: gltf::Node =
+                    GltfNodeWrapper::from((&gltfs[src], gltf_node)).try_into()?;
+                let node_mesh = node.mesh().ok_or(failure::format_err!(
+                    "Entity with Combined data refers to node with no Mesh: {:?}",
+                    gltf_node
+                ))?;
+                Ok(asset::MeshHandle::from_index(
+                    gltf_file_offsets[src].0 + node_mesh.index(),
+                ))
+            }
+            MeshSource::Mesh(mesh) => {
+                let mesh = match mesh {
+                    GltfMesh::Index(src, idx) => {
+                        gltfs[*src]
+                            .meshes()
+                            .nth(*idx)
+                            .ok_or(failure::format_err!(
+                                "GltfMesh refers to mesh that does not exist: {:?}",
+                                mesh
+                            ))?
+                            .index()
+                            + gltf_file_offsets[*src].0
+                    }
+                    GltfMesh::Name(src, name) => {
+                        gltfs[*src]
+                            .meshes()
+                            .find(|mesh| {
+                                if let Some(mesh_name) = mesh.name() {
+                                    if mesh_name == name {
+                                        true
+                                    } else {
+                                        false
+                                    }
+                                } else {
+                                    false
+                                }
+                            })
+                            .ok_or(failure::format_err!(
+                                "GltfMesh refers to mesh that does not exist: {:?}",
+                                mesh
+                            ))?
+                            .index()
+                            + gltf_file_offsets[*src].0
+                    }
+                };
+                Ok(asset::MeshHandle::from_index(mesh))
+            }
+            MeshSource::Obj(mesh_file) => {
+                let material = self.resolve_material(
+                    &mesh_file.material,
+                    material_names,
+                    material_storage,
+                    factory,
+                    queues,
+                )?;
+                let mesh = asset::load_mesh_file(
+                    Path::new(&crate::application_root_dir()).join(&mesh_file.path),
+                    material,
+                    256,
+                    primitive_storage,
+                    mesh_storage,
+                    geometry_pool,
+                )?;
+                Ok(mesh)
+            }
+            MeshSource::Procedural(procedural) => {
+                let material = self.resolve_material(
+                    &procedural.material,
+                    material_names,
+                    material_storage,
+                    factory,
+                    queues,
+                )?;
+                let mesh = asset::add_mesh_data(
+                    procedural.shape.generate(),
+                    format!("{:?}", procedural.shape),
+                    material,
+                    256,
+                    primitive_storage,
+                    mesh_storage,
+                    geometry_pool,
+                )?;
+                Ok(mesh)
+            }
+        }
+    }
+
     pub fn load<B: hal::Backend>(
         mut self,
         aspect: f32,
         factory: &mut rendy::factory::Factory<B>,
-        queue: rendy::command::QueueId,
+        queues: &crate::upload::UploadQueues,
         world: &mut specs::World,
     ) -> Result<
         (
             asset::MaterialStorage<B>,
-            asset::PrimitiveStorage<B>,
+            asset::PrimitiveStorage,
+            GeometryPool<B>,
             asset::MeshStorage,
+            asset::LightmapStorage<B>,
             Vec<specs::Entity>,
         ),
         failure::Error,
     > {
         let mut mesh_storage = Vec::new();
         let mut primitive_storage = Vec::new();
+        let mut geometry_pool = GeometryPoolBuilder::default();
         let mut material_storage = Vec::new();
         let mut scene_entities = Vec::new();
-        // (node, mesh, material)
+        let mut lightmaps = Vec::new();
+        // (mesh, material, node)
         let mut gltf_file_offsets = vec![(0, 0, 0)];
 
-        let (gltfs, basepaths): (Vec<_>, Vec<_>) = self
-            .gltf_sources
-            .drain(..)
-            .map(|path| {
-                let base_path = Path::new(&crate::application_root_dir()).join(path.0);
-                let file = File::open(base_path.join(path.1)).unwrap();
-                let reader = std::io::BufReader::new(file);
-                (gltf::Gltf::from_reader(reader).unwrap(), base_path.clone())
+        let (gltfs, paths): (Vec<_>, Vec<_>) = self
+            .gltf_paths()
+            .map(|(base_path, file_path)| {
+                let reader = std::io::BufReader::new(File::open(&file_path)?);
+                Ok((gltf::Gltf::from_reader(reader)?, (base_path, file_path)))
             })
+            .collect::<Result<Vec<_>, failure::Error>>()?
+            .into_iter()
             .unzip();
 
-        for (source_index, (gltf, base_path)) in gltfs.iter().zip(basepaths.iter()).enumerate() {
+        for (source_index, (gltf, (base_path, file_path))) in
+            gltfs.iter().zip(paths.iter()).enumerate()
+        {
             let gltf_buffers = asset::GltfBuffers::load_from_gltf(base_path, gltf)?;
+            let material_extensions = asset::load_gltf_material_extensions(file_path, gltf)?;
 
             let offsets = gltf_file_offsets[source_index];
             let base_mesh_index = offsets.0;
@@ -175,13 +726,15 @@ impl SceneConfig {
                     self.mipmap_model_textures,
                     base_path,
                     &gltf_buffers,
+                    &material_extensions,
                     base_mesh_index,
                     base_material_index,
                     &mut material_storage,
                     &mut primitive_storage,
                     &mut mesh_storage,
+                    &mut geometry_pool,
                     factory,
-                    queue,
+                    queues,
                 )?;
             }
 
@@ -192,7 +745,27 @@ impl SceneConfig {
             ))
         }
 
+        // Appended after the glTF materials, so their offsets stay the same
+        let mut material_names = HashMap::new();
+        for material in &self.materials {
+            if material_names.contains_key(&material.name) {
+                failure::bail!("Material {:?} is defined more than once", material.name);
+            }
+            material_storage.push(Some(asset::build_material(
+                &material.factors,
+                &material.textures,
+                self.mipmap_model_textures,
+                factory,
+                queues,
+            )?));
+            material_names.insert(
+                material.name.clone(),
+                asset::MaterialHandle::from_index(material_storage.len() - 1),
+            );
+        }
+
         let mut active_camera_de = false;
+        let mut shadowed_light = false;
         for (i, scene_entity) in self.entities.iter().enumerate() {
             let mut entity_builder = world.create_entity();
 
@@ -201,84 +774,123 @@ impl SceneConfig {
                     let src: GltfFileIndex = gltf_node.into();
                     let node: gltf::Node =
                         GltfNodeWrapper::from((&gltfs[src], gltf_node)).try_into()?;
-                    components::Transform::from(node.transform())
+                    let transform = components::Transform::from(node.transform());
+                    // Children of glTF nodes are already converted through their parent
+                    let parent_from_gltf = match scene_entity.parent {
+                        Some(parent) => match self.entities.get(parent) {
+                            Some(SceneEntity {
+                                transform: TransformSource::Gltf(_),
+                                ..
+                            }) => true,
+                            _ => false,
+                        },
+                        None => false,
+                    };
+                    if parent_from_gltf {
+                        transform
+                    } else {
+                        components::Transform(self.import.conversion() * transform.0)
+                    }
                 }
                 TransformSource::Manual(transform) => transform.clone(),
             };
             entity_builder = entity_builder.with(transform);
 
-            match &scene_entity.mesh {
-                Some(MeshSource::Node(gltf_node)) => {
-                    let src: GltfFileIndex = gltf_node.into();
-                    if src >= gltfs.len() {
-                        failure::bail!("Data source Gltf File for entity: {} out of bounds", i);
-                    }
-                    let node: gltf::Node =
-                        GltfNodeWrapper::from((&gltfs[src], gltf_node)).try_into()?;
-                    let node_mesh = node.mesh().ok_or(failure::format_err!(
-                        "Entity with Combined data refers to node with no Mesh: {:?}",
-                        gltf_node
-                    ))?;
-                    entity_builder = entity_builder.with(components::Mesh(
-                        gltf_file_offsets[src].0 + node_mesh.index(),
-                    ));
+            let mesh = match &scene_entity.mesh {
+                Some(source) => Some(self.load_mesh(
+                    source,
+                    &gltfs,
+                    &gltf_file_offsets,
+                    &material_names,
+                    &mut material_storage,
+                    &mut primitive_storage,
+                    &mut mesh_storage,
+                    &mut geometry_pool,
+                    factory,
+                    queues,
+                )?),
+                None => None,
+            };
+            let mesh = match (mesh, &scene_entity.material) {
+                (Some(mesh), Some(material)) => {
+                    let material = self.resolve_material(
+                        material,
+                        &material_names,
+                        &mut material_storage,
+                        factory,
+                        queues,
+                    )?;
+                    Some(asset::add_material_variant(
+                        mesh,
+                        material,
+                        &mut primitive_storage,
+                        &mut mesh_storage,
+                    ))
                 }
-                Some(MeshSource::Mesh(mesh)) => {
-                    let mesh = match mesh {
-                        GltfMesh::Index(src, idx) => components::Mesh(
-                            gltfs[*src]
-                                .meshes()
-                                .nth(*idx)
-                                .ok_or(failure::format_err!(
-                                    "GltfMesh refers to mesh that does not exist: {:?}",
-                                    mesh
-                                ))?
-                                .index()
-                                + gltf_file_offsets[*src].1,
-                        ),
-                        GltfMesh::Name(src, name) => components::Mesh(
-                            gltfs[*src]
-                                .meshes()
-                                .find(|mesh| {
-                                    if let Some(mesh_name) = mesh.name() {
-                                        if mesh_name == name {
-                                            true
-                                        } else {
-                                            false
-                                        }
-                                    } else {
-                                        false
-                                    }
-                                })
-                                .ok_or(failure::format_err!(
-                                    "GltfMesh refers to mesh that does not exist: {:?}",
-                                    mesh
-                                ))?
-                                .index()
-                                + gltf_file_offsets[*src].1,
-                        ),
-                    };
-                    entity_builder = entity_builder.with(mesh);
+                (None, Some(_)) => {
+                    log::warn!("Entity {} has a material but no mesh, ignoring it", i);
+                    None
                 }
-                None => (),
+                (mesh, None) => mesh,
+            };
+            if let Some(mesh) = mesh {
+                entity_builder = entity_builder.with(components::Mesh(mesh));
+            }
+            if !scene_entity.casts_shadows {
+                entity_builder = entity_builder.with(components::NoShadows);
+            }
+            if scene_entity.is_static {
+                entity_builder = entity_builder.with(components::Static);
+            }
+            if let Some(layers) = scene_entity.layers {
+                entity_builder = entity_builder.with(layers);
             }
 
             if let Some(light) = &scene_entity.light {
+                if light.shadow.enabled && !light.intensity.is_directional() {
+                    log::warn!("Only directional lights cast shadows so far, the shadow settings of a point light are ignored");
+                } else if light.shadow.enabled {
+                    if shadowed_light {
+                        log::warn!("Only the first directional light casting shadows has them drawn, the shadow settings of entity {} are ignored", i);
+                    }
+                    shadowed_light = true;
+                }
                 entity_builder = entity_builder.with(*light);
             }
 
+            if let Some(billboard) = &scene_entity.billboard {
+                entity_builder = entity_builder.with(*billboard);
+            }
+
+            if let Some(emitter) = &scene_entity.particle_emitter {
+                entity_builder = entity_builder.with(*emitter);
+            }
+
+            if let Some(spin) = &scene_entity.spin {
+                entity_builder = entity_builder.with(*spin);
+            }
+
+            if let Some(oscillate) = &scene_entity.oscillate {
+                entity_builder = entity_builder.with(*oscillate);
+            }
+
+            if let Some(orbit) = &scene_entity.orbit {
+                entity_builder = entity_builder.with(*orbit);
+            }
+
             if let Some(camera_data) = &scene_entity.camera {
                 entity_builder = entity_builder.with(components::Camera {
                     yaw: camera_data.yaw,
                     pitch: camera_data.pitch,
                     dist: camera_data.distance,
                     focus: nalgebra::Point3::from(camera_data.focus_point),
-                    proj: nalgebra::Perspective3::new(
-                        aspect,
-                        camera_data.fov,
-                        camera_data.znear,
-                        camera_data.zfar,
-                    ),
+                    fov: camera_data.fov,
+                    aspect,
+                    znear: camera_data.znear,
+                    zfar: camera_data.zfar,
+                    infinite_far: camera_data.infinite_far,
+                    reversed_z: self.reversed_z,
+                    layers: camera_data.layers,
                 });
                 if camera_data.active {
                     if !active_camera_de {
@@ -288,9 +900,81 @@ impl SceneConfig {
                         failure::bail!("Attempted to load multiple active cameras");
                     }
                 }
+                if camera_data.debug_window {
+                    entity_builder = entity_builder.with(components::DebugWindowCamera);
+                }
+            }
+
+            if !scene_entity.lods.is_empty() {
+                let mesh =
+                    mesh.ok_or_else(|| failure::format_err!("Entity {} has LODs but no mesh", i))?;
+                if !mesh_storage[mesh.index()].as_ref().unwrap().lods.is_empty() {
+                    log::warn!(
+                        "The mesh of entity {} already has LODs, ignoring its own",
+                        i
+                    );
+                } else {
+                    let mut lods = Vec::with_capacity(scene_entity.lods.len());
+                    for lod in scene_entity.lods.iter() {
+                        let lod_mesh = self.load_mesh(
+                            &lod.mesh,
+                            &gltfs,
+                            &gltf_file_offsets,
+                            &material_names,
+                            &mut material_storage,
+                            &mut primitive_storage,
+                            &mut mesh_storage,
+                            &mut geometry_pool,
+                            factory,
+                            queues,
+                        )?;
+                        mesh_storage[lod_mesh.index()].as_mut().unwrap().lod_of = Some(mesh);
+                        lods.push(asset::Lod {
+                            mesh: lod_mesh,
+                            screen_size: lod.screen_size,
+                        });
+                    }
+                    lods.sort_by(|a, b| b.screen_size.partial_cmp(&a.screen_size).unwrap());
+                    mesh_storage[mesh.index()].as_mut().unwrap().lods = lods;
+                }
             }
 
-            scene_entities.push(entity_builder.build());
+            let entity = entity_builder.build();
+            if let Some(settings) = &scene_entity.lightmap {
+                let mesh = mesh.ok_or_else(|| {
+                    failure::format_err!("Entity {} has a lightmap but no mesh", i)
+                })?;
+                let mesh_data = mesh_storage[mesh.index()].as_mut().unwrap();
+                if mesh_data.lightmap.is_some() {
+                    log::warn!(
+                        "The mesh of entity {} already has a lightmap, ignoring its own",
+                        i
+                    );
+                } else {
+                    let path = Path::new(&crate::application_root_dir()).join(&settings.path);
+                    let texture = if path.exists() {
+                        Some(
+                            asset::load_lightmap(&path)?
+                                .build(queues.texture_state(false), factory)?,
+                        )
+                    } else {
+                        log::warn!(
+                            "Lightmap {:?} doesn't exist yet, bake it with --bake-lightmaps",
+                            path
+                        );
+                        None
+                    };
+                    mesh_data.lightmap = Some(lightmaps.len());
+                    lightmaps.push(asset::Lightmap {
+                        mesh,
+                        entity,
+                        path,
+                        resolution: settings.resolution,
+                        texture,
+                    });
+                }
+            }
+            scene_entities.push(entity);
         }
 
         for (i, scene_entity) in self.entities.iter().enumerate() {
@@ -303,29 +987,148 @@ impl SceneConfig {
             }
         }
 
-        let material_storage = asset::MaterialStorage(
-            material_storage
-                .into_iter()
-                .map(|mut m| m.take().unwrap())
-                .collect::<Vec<_>>(),
-        );
-        let primitive_storage = asset::PrimitiveStorage(
-            primitive_storage
-                .into_iter()
-                .map(|mut p| p.take().unwrap())
-                .collect::<Vec<_>>(),
-        );
-        let mesh_storage = asset::MeshStorage(
-            mesh_storage
-                .into_iter()
-                .map(|mut m| m.take().unwrap())
-                .collect::<Vec<_>>(),
-        );
+        let mut prefabs = prefab::LoadedPrefabs::new();
+        for prefab in self.prefabs.iter() {
+            let mesh = match &prefab.mesh {
+                Some(source) => Some(self.load_mesh(
+                    source,
+                    &gltfs,
+                    &gltf_file_offsets,
+                    &material_names,
+                    &mut material_storage,
+                    &mut primitive_storage,
+                    &mut mesh_storage,
+                    &mut geometry_pool,
+                    factory,
+                    queues,
+                )?),
+                None => None,
+            };
+            let mesh = match (mesh, &prefab.material) {
+                (Some(mesh), Some(material)) => {
+                    let material = self.resolve_material(
+                        material,
+                        &material_names,
+                        &mut material_storage,
+                        factory,
+                        queues,
+                    )?;
+                    Some(asset::add_material_variant(
+                        mesh,
+                        material,
+                        &mut primitive_storage,
+                        &mut mesh_storage,
+                    ))
+                }
+                (None, Some(_)) => {
+                    log::warn!(
+                        "Prefab {:?} has a material but no mesh, ignoring it",
+                        prefab.name
+                    );
+                    None
+                }
+                (mesh, None) => mesh,
+            };
+            if prefabs
+                .insert(prefab.name.as_str(), (prefab, mesh))
+                .is_some()
+            {
+                failure::bail!("Prefab {:?} is defined more than once", prefab.name);
+            }
+        }
+        for instances in self.instances.iter() {
+            for transform in instances.placement.transforms() {
+                prefab::spawn(
+                    world,
+                    &prefabs,
+                    &instances.prefab,
+                    components::Transform(transform),
+                    None,
+                )?;
+            }
+        }
+        if self.static_batching {
+            crate::static_batch::bake(
+                world,
+                &mut primitive_storage,
+                &mut mesh_storage,
+                &mut geometry_pool,
+            );
+        }
+
+        let instance_array = match &self.array {
+            Some(settings) => {
+                let (_, mesh) = prefabs.get(settings.prefab.as_str()).ok_or_else(|| {
+                    failure::format_err!("The array's prefab {:?} is not defined", settings.prefab)
+                })?;
+                if mesh.is_none() {
+                    log::warn!(
+                        "The array's prefab {:?} has no mesh, it stays empty",
+                        settings.prefab
+                    );
+                }
+                crate::systems::InstanceArray {
+                    mesh: *mesh,
+                    settings: settings.clone(),
+                }
+            }
+            None => Default::default(),
+        };
+
+        // Meshes are placed more times than they have room for by default by instancing
+        // prefabs and the array, and their levels of detail are drawn for as many entities
+        let mut instance_counts = vec![0; mesh_storage.len()];
+        for mesh in world.read_storage::<components::Mesh>().join() {
+            instance_counts[mesh.0.index()] += 1;
+        }
+        if let Some(mesh) = instance_array.mesh {
+            instance_counts[mesh.index()] += instance_array.settings.max_instances();
+        }
+        for (index, count) in instance_counts.into_iter().enumerate() {
+            let mesh = match mesh_storage[index].as_mut() {
+                Some(mesh) if count > mesh.max_instances as usize => mesh,
+                _ => continue,
+            };
+            let max_instances = u16::try_from(count).map_err(|_| {
+                failure::format_err!(
+                    "Mesh {:?} needs room for {} instances, more than {} can be drawn",
+                    mesh.name,
+                    count,
+                    u16::max_value()
+                )
+            })?;
+            mesh.max_instances = max_instances;
+            let lods = mesh.lods.iter().map(|lod| lod.mesh).collect::<Vec<_>>();
+            for lod in lods {
+                let lod = mesh_storage[lod.index()].as_mut().unwrap();
+                lod.max_instances = lod.max_instances.max(max_instances);
+            }
+        }
+        world.add_resource(instance_array);
+
+        // Materials of the glTF files which no primitive uses were never loaded, and leave
+        // their slots empty
+        let material_storage = asset::MaterialStorage(SlotMap::from_slots(material_storage));
+        let primitive_storage = asset::PrimitiveStorage(SlotMap::from_slots(primitive_storage));
+        let mesh_storage = asset::MeshStorage(SlotMap::from_slots(mesh_storage));
+
+        let lightmap_storage = asset::LightmapStorage {
+            lightmaps,
+            placeholder: rendy::texture::TextureBuilder::new()
+                .with_kind(hal::image::Kind::D2(1, 1, 1, 1))
+                .with_view_kind(hal::image::ViewKind::D2)
+                .with_data_width(1)
+                .with_data_height(1)
+                .with_data(vec![rendy::texture::pixel::Rgba32Sfloat { repr: [0.0; 4] }])
+                .build(queues.texture_state(false), factory)?,
+        };
 
         Ok((
             material_storage,
             primitive_storage,
+            geometry_pool.build(factory, queues)?,
             mesh_storage,
+            lightmap_storage,
             scene_entities,
         ))
     }
@@ -0,0 +1,182 @@
+//! Switching between scene configs without restarting. `--scene` can be given more than once,
+//! and **F3** and **Shift+F3** switch to the next and previous of them after the frame. Every
+//! entity of the current scene is deleted and its assets are dropped, then the other scene is
+//! loaded like the first one was, its environment map preprocessed and filtered, and the render
+//! graph rebuilt for its materials.
+//!
+//! glTF files dropped onto the window are added to the current scene, which is then loaded again
+//! the same way. They stay in it until another scene is switched to.
+//!
+//! A scene whose config or glTF files can't be read, or which uses parts of glTF the loader
+//! doesn't support, is refused before anything is unloaded. If it still fails to load after,
+//! the current scene is loaded again, without the file dropped last.
+//!
+//! The rest of the renderer is set up once from the first scene, so scenes are only switched to
+//! if they share its `SharedSettings`: the render settings, shadows included, the light probe
+//! grid, voxel GI and the script. Otherwise the switch is refused with an error, and the
+//! current scene stays. The targets and the window keep the settings of the first scene.
+use crate::{command, gizmo, inspector, render_frame, scene, systems};
+
+/// The settings of the first scene which the renderer is set up with, and which the scenes
+/// switched to must have too.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SharedSettings {
+    render_settings: scene::RenderSettings,
+    probes: Option<crate::probes::ProbeGridSettings>,
+    voxel_gi: crate::voxel_gi::VoxelGiSettings,
+    script: Option<String>,
+}
+
+impl SharedSettings {
+    pub fn of(scene_config: &scene::SceneConfig) -> Self {
+        SharedSettings {
+            render_settings: scene_config.render_settings,
+            probes: scene_config.probes,
+            voxel_gi: scene_config.voxel_gi,
+            script: scene_config.script.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SceneManager {
+    /// Paths of the scene configs, relative to the application root unless absolute
+    scenes: Vec<String>,
+    current: usize,
+    /// The scene to switch to after the frame
+    requested: Option<usize>,
+    /// glTF files dropped onto the window, added to the current scene
+    gltfs: Vec<String>,
+    /// The glTF file dropped since the current scene was loaded
+    dropped_gltf: Option<String>,
+    shared_settings: SharedSettings,
+}
+
+impl SceneManager {
+    /// Starts in the first of `scenes`, which is the one loaded at startup with
+    /// `shared_settings`.
+    pub fn new(scenes: Vec<String>, shared_settings: SharedSettings) -> Self {
+        SceneManager {
+            scenes,
+            current: 0,
+            requested: None,
+            gltfs: Vec::new(),
+            dropped_gltf: None,
+            shared_settings,
+        }
+    }
+
+    /// Fails if the scene's settings differ from those the renderer was set up with.
+    pub fn check(&self, scene_config: &scene::SceneConfig) -> Result<(), failure::Error> {
+        let settings = SharedSettings::of(scene_config);
+        let shared = &self.shared_settings;
+        let differing = [
+            (
+                "render_settings",
+                settings.render_settings != shared.render_settings,
+            ),
+            ("probes", settings.probes != shared.probes),
+            ("voxel_gi", settings.voxel_gi != shared.voxel_gi),
+            ("script", settings.script != shared.script),
+        ]
+        .iter()
+        .filter(|(_, differs)| *differs)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+        if !differing.is_empty() {
+            failure::bail!(
+                "its {} differ from the first scene's, which the renderer was set up with",
+                differing.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Switches to the scene after the current one, or the one before it if `backwards`,
+    /// wrapping around.
+    pub fn request_next(&mut self, backwards: bool) {
+        let count = self.scenes.len();
+        if count < 2 {
+            log::warn!("No other scene to switch to, give more with --scene");
+            return;
+        }
+        self.requested = Some(if backwards {
+            (self.current + count - 1) % count
+        } else {
+            (self.current + 1) % count
+        });
+    }
+
+    /// Loads the current scene again with the glTF file at `path` added to it.
+    pub fn request_gltf(&mut self, path: String) {
+        self.dropped_gltf = Some(path);
+        self.requested = Some(self.current);
+    }
+
+    /// The scene to switch to, if one was requested since the last call.
+    pub fn take_request(&mut self) -> Option<usize> {
+        self.requested.take()
+    }
+
+    pub fn path(&self, index: usize) -> &str {
+        &self.scenes[index]
+    }
+
+    /// The scene loaded last.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Reads the config of the scene at `index` with its glTF files added, and checks that it
+    /// can be switched to, before the current scene is unloaded for it.
+    pub fn read_config(&self, index: usize) -> Result<scene::SceneConfig, failure::Error> {
+        let mut scene_config = scene::SceneConfig::from_path(self.path(index))?;
+        for gltf in self.gltfs(index).iter() {
+            scene_config.add_gltf(std::path::Path::new(gltf))?;
+        }
+        self.check(&scene_config)?;
+        scene_config.validate()?;
+        Ok(scene_config)
+    }
+
+    /// The glTF files to add to the scene at `index` when it is loaded, the dropped ones if it
+    /// is the current scene.
+    pub fn gltfs(&self, index: usize) -> Vec<String> {
+        if index == self.current {
+            self.gltfs
+                .iter()
+                .chain(self.dropped_gltf.iter())
+                .cloned()
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Records that the scene at `index` was loaded, with the files of `gltfs`.
+    pub fn set_current(&mut self, index: usize) {
+        self.gltfs = self.gltfs(index);
+        self.dropped_gltf = None;
+        self.current = index;
+    }
+
+    /// Forgets the glTF file dropped last, after the scene failed to load with it.
+    pub fn discard_dropped(&mut self) {
+        self.dropped_gltf = None;
+    }
+}
+
+/// Deletes every entity, and forgets what the resources kept about them, for another scene to
+/// be loaded into the world.
+pub fn unload(world: &mut specs::World) {
+    world.delete_all();
+    world.maintain();
+    *world.write_resource::<systems::MeshInstanceStorage>() = Default::default();
+    *world.write_resource::<systems::ParticleStorage>() = Default::default();
+    *world.write_resource::<render_frame::RenderFrames>() = Default::default();
+    *world.write_resource::<systems::InstanceArrayEntities>() = Default::default();
+    *world.write_resource::<systems::InstanceArraySize>() = Default::default();
+    *world.write_resource::<gizmo::Gizmo>() = Default::default();
+    *world.write_resource::<command::CommandStack>() = Default::default();
+    *world.write_resource::<inspector::Inspector>() = Default::default();
+}
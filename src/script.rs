@@ -0,0 +1,425 @@
+//! Optional scripting of the scene with [Rhai](https://rhai.rs), to prototype behavior without
+//! recompiling. The script named by the scene config is compiled once at startup. Its
+//! `init(scene)` function, if it has one, is called on the first frame, then its
+//! `update(scene, time, dt)` function every frame before transforms are propagated. Rhai
+//! functions can't see variables outside of them, so whatever should last between frames is
+//! kept in the scene.
+//!
+//! Scripts see the scene's entities by index, in the order they are listed in the scene
+//! config, followed by the ones spawned by the script itself:
+//!
+//! - `scene.entity_count()`
+//! - `scene.get_position(i)`, `scene.set_position(i, pos)`
+//! - `scene.get_scale(i)`, `scene.set_scale(i, scale)`
+//! - `scene.rotate(i, axis, angle)`, counterclockwise in radians seen from the tip of `axis`
+//! - `scene.is_light(i)`, `scene.get_light_intensity(i)`, `scene.set_light_intensity(i, value)`,
+//!   `scene.set_light_color(i, color)`, in the units the light was defined with
+//! - `scene.get_exposure()`, `scene.set_exposure(ev100)`
+//! - `scene.spawn(mesh, pos, scale)`, which returns the index of the new entity, or -1 if the
+//!   mesh already has as many instances as it can draw
+//!
+//! Positions, axes and colors are `vec3(x, y, z)` values. Indices out of range and entities
+//! without the needed component are ignored, reading zeros. Errors in the script are logged
+//! and stop it, rather than the renderer.
+use crate::{asset, components, node, systems};
+use rhai::{Engine, Scope, AST, FLOAT, INT};
+use specs::prelude::*;
+
+use std::sync::{Arc, Mutex};
+
+/// The most operations a single call of the script may run, so that an endless loop is
+/// reported instead of freezing the renderer.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vec3 {
+    pub x: FLOAT,
+    pub y: FLOAT,
+    pub z: FLOAT,
+}
+
+impl From<nalgebra::Vector3<f32>> for Vec3 {
+    fn from(v: nalgebra::Vector3<f32>) -> Self {
+        Vec3 {
+            x: v.x as FLOAT,
+            y: v.y as FLOAT,
+            z: v.z as FLOAT,
+        }
+    }
+}
+
+impl From<Vec3> for nalgebra::Vector3<f32> {
+    fn from(v: Vec3) -> Self {
+        nalgebra::Vector3::new(v.x as f32, v.y as f32, v.z as f32)
+    }
+}
+
+/// The state of one entity as the script sees it, written back after the script has run if
+/// it was changed.
+#[derive(Debug)]
+struct ScriptEntity {
+    transform: Option<nalgebra::Similarity3<f32>>,
+    light: Option<components::Light>,
+    transform_changed: bool,
+    light_changed: bool,
+}
+
+#[derive(Debug)]
+struct Spawn {
+    mesh: asset::MeshHandle,
+    position: nalgebra::Vector3<f32>,
+    scale: f32,
+}
+
+#[derive(Debug, Default)]
+struct SceneState {
+    entities: Vec<ScriptEntity>,
+    exposure: f32,
+    exposure_changed: bool,
+    /// The meshes by the index of their handles, with the instances they can still take,
+    /// counting down as the script spawns them. `None` for removed meshes.
+    free_instances: Vec<Option<(asset::MeshHandle, u32)>>,
+    spawns: Vec<Spawn>,
+}
+
+/// The handle to the scene passed to the script. Shared, since Rhai clones function arguments.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptScene(Arc<Mutex<SceneState>>);
+
+impl ScriptScene {
+    fn with_entity<T: Default>(&self, index: INT, f: impl FnOnce(&mut ScriptEntity) -> T) -> T {
+        let mut state = self.0.lock().unwrap();
+        if index < 0 {
+            return T::default();
+        }
+        state
+            .entities
+            .get_mut(index as usize)
+            .map(f)
+            .unwrap_or_default()
+    }
+
+    fn with_transform<T: Default>(
+        &self,
+        index: INT,
+        f: impl FnOnce(&mut nalgebra::Similarity3<f32>) -> T,
+    ) -> T {
+        self.with_entity(index, |entity| match &mut entity.transform {
+            Some(transform) => f(transform),
+            None => T::default(),
+        })
+    }
+
+    /// Like `with_transform`, for changes to it
+    fn modify_transform(&self, index: INT, f: impl FnOnce(&mut nalgebra::Similarity3<f32>)) {
+        self.with_entity(index, |entity| {
+            if let Some(transform) = &mut entity.transform {
+                f(transform);
+                entity.transform_changed = true;
+            }
+        })
+    }
+
+    fn modify_light(&self, index: INT, f: impl FnOnce(&mut components::Light)) {
+        self.with_entity(index, |entity| {
+            if let Some(light) = &mut entity.light {
+                f(light);
+                entity.light_changed = true;
+            }
+        })
+    }
+
+    fn spawn(&self, mesh: INT, position: Vec3, scale: FLOAT) -> INT {
+        let mut state = self.0.lock().unwrap();
+        let mesh = match state.free_instances.get_mut(mesh as usize) {
+            Some(Some((mesh, free))) if *free > 0 => {
+                *free -= 1;
+                *mesh
+            }
+            _ => return -1,
+        };
+        state.spawns.push(Spawn {
+            mesh,
+            position: position.into(),
+            scale: scale as f32,
+        });
+        (state.entities.len() + state.spawns.len() - 1) as INT
+    }
+}
+
+fn register_api(engine: &mut Engine) {
+    engine.register_type_with_name::<Vec3>("Vec3");
+    engine.register_fn("vec3", |x: FLOAT, y: FLOAT, z: FLOAT| Vec3 { x, y, z });
+    engine.register_get_set("x", |v: &mut Vec3| v.x, |v: &mut Vec3, x: FLOAT| v.x = x);
+    engine.register_get_set("y", |v: &mut Vec3| v.y, |v: &mut Vec3, y: FLOAT| v.y = y);
+    engine.register_get_set("z", |v: &mut Vec3| v.z, |v: &mut Vec3, z: FLOAT| v.z = z);
+    engine.register_fn("+", |a: Vec3, b: Vec3| Vec3 {
+        x: a.x + b.x,
+        y: a.y + b.y,
+        z: a.z + b.z,
+    });
+    engine.register_fn("-", |a: Vec3, b: Vec3| Vec3 {
+        x: a.x - b.x,
+        y: a.y - b.y,
+        z: a.z - b.z,
+    });
+    engine.register_fn("*", |a: Vec3, s: FLOAT| Vec3 {
+        x: a.x * s,
+        y: a.y * s,
+        z: a.z * s,
+    });
+    engine.register_fn("to_string", |v: &mut Vec3| {
+        format!("vec3({}, {}, {})", v.x, v.y, v.z)
+    });
+
+    engine.register_type_with_name::<ScriptScene>("Scene");
+    engine.register_fn("entity_count", |scene: &mut ScriptScene| {
+        let state = scene.0.lock().unwrap();
+        state.entities.len() as INT
+    });
+    engine.register_fn("get_position", |scene: &mut ScriptScene, i: INT| {
+        scene.with_transform(i, |t| Vec3::from(t.isometry.translation.vector))
+    });
+    engine.register_fn(
+        "set_position",
+        |scene: &mut ScriptScene, i: INT, position: Vec3| {
+            scene.modify_transform(i, |t| t.isometry.translation.vector = position.into())
+        },
+    );
+    engine.register_fn("get_scale", |scene: &mut ScriptScene, i: INT| {
+        scene.with_transform(i, |t| t.scaling() as FLOAT)
+    });
+    engine.register_fn(
+        "set_scale",
+        |scene: &mut ScriptScene, i: INT, scale: FLOAT| {
+            // A zero scale would make the transform singular
+            if scale > 0.0 {
+                scene.modify_transform(i, |t| t.set_scaling(scale as f32))
+            }
+        },
+    );
+    engine.register_fn(
+        "rotate",
+        |scene: &mut ScriptScene, i: INT, axis: Vec3, angle: FLOAT| {
+            if let Some(axis) =
+                nalgebra::Unit::try_new(nalgebra::Vector3::<f32>::from(axis), 1.0e-6)
+            {
+                scene.modify_transform(i, |t| {
+                    let rotation = &mut t.isometry.rotation;
+                    *rotation =
+                        *rotation * nalgebra::UnitQuaternion::from_axis_angle(&axis, angle as f32);
+                })
+            }
+        },
+    );
+    engine.register_fn("is_light", |scene: &mut ScriptScene, i: INT| {
+        scene.with_entity(i, |entity| entity.light.is_some())
+    });
+    engine.register_fn("get_light_intensity", |scene: &mut ScriptScene, i: INT| {
+        scene.with_entity(i, |entity| {
+            entity
+                .light
+                .map(|light| match light.intensity {
+                    components::LightIntensity::Candela(v)
+                    | components::LightIntensity::Lumens(v)
+                    | components::LightIntensity::Lux(v) => v as FLOAT,
+                })
+                .unwrap_or_default()
+        })
+    });
+    engine.register_fn(
+        "set_light_intensity",
+        |scene: &mut ScriptScene, i: INT, value: FLOAT| {
+            let value = (value as f32).max(0.0);
+            scene.modify_light(i, |light| {
+                light.intensity = match light.intensity {
+                    components::LightIntensity::Candela(_) => {
+                        components::LightIntensity::Candela(value)
+                    }
+                    components::LightIntensity::Lumens(_) => {
+                        components::LightIntensity::Lumens(value)
+                    }
+                    components::LightIntensity::Lux(_) => components::LightIntensity::Lux(value),
+                }
+            })
+        },
+    );
+    engine.register_fn(
+        "set_light_color",
+        |scene: &mut ScriptScene, i: INT, color: Vec3| {
+            scene.modify_light(i, |light| {
+                light.color = [color.x as f32, color.y as f32, color.z as f32]
+            })
+        },
+    );
+    engine.register_fn("get_exposure", |scene: &mut ScriptScene| {
+        scene.0.lock().unwrap().exposure as FLOAT
+    });
+    engine.register_fn("set_exposure", |scene: &mut ScriptScene, ev100: FLOAT| {
+        let mut state = scene.0.lock().unwrap();
+        state.exposure = ev100 as f32;
+        state.exposure_changed = true;
+    });
+    engine.register_fn(
+        "spawn",
+        |scene: &mut ScriptScene, mesh: INT, position: Vec3, scale: FLOAT| {
+            scene.spawn(mesh, position, scale)
+        },
+    );
+}
+
+/// Runs the scene's script each frame. Does nothing once the script has failed.
+pub struct ScriptSystem {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// The entities the script can reach, by index
+    entities: Vec<Entity>,
+    /// Whether `init` was called yet
+    started: bool,
+    failed: bool,
+}
+
+impl ScriptSystem {
+    /// Compiles the script at `path`, relative to the application root. `entities` are the
+    /// scene's entities in the order of the scene config.
+    pub fn new(path: &str, entities: Vec<Entity>) -> Result<Self, failure::Error> {
+        let path = std::path::PathBuf::from(crate::application_root_dir()).join(path);
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|s| log::info!("[script] {}", s));
+        engine.on_debug(|s| log::debug!("[script] {}", s));
+        register_api(&mut engine);
+
+        let ast = engine
+            .compile_file(path.clone())
+            .map_err(|e| failure::format_err!("Failed to compile {}: {}", path.display(), e))?;
+        log::info!("Loaded script {}", path.display());
+
+        Ok(ScriptSystem {
+            engine,
+            ast,
+            scope: Scope::new(),
+            entities,
+            started: false,
+            failed: false,
+        })
+    }
+}
+
+impl<'a> System<'a> for ScriptSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, systems::FrameTime>,
+        Read<'a, asset::MeshStorage>,
+        Write<'a, node::pbr::Aux>,
+        WriteStorage<'a, components::Transform>,
+        WriteStorage<'a, components::Light>,
+        WriteStorage<'a, components::Mesh>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, frame_time, mesh_storage, mut aux, mut transforms, mut lights, mut meshes): Self::SystemData,
+    ) {
+        if self.failed {
+            return;
+        }
+
+        let first_frame = !self.started;
+        self.started = true;
+
+        let mut free_instances = vec![None; mesh_storage.0.len()];
+        for (handle, mesh) in mesh_storage.0.iter() {
+            free_instances[handle.index()] = Some((handle, mesh.max_instances as u32));
+        }
+        for mesh in (&meshes).join() {
+            if let Some(Some((handle, free))) = free_instances.get_mut(mesh.0.index()) {
+                if *handle == mesh.0 {
+                    *free = free.saturating_sub(1);
+                }
+            }
+        }
+
+        let scene = ScriptScene(Arc::new(Mutex::new(SceneState {
+            entities: self
+                .entities
+                .iter()
+                .map(|entity| ScriptEntity {
+                    transform: transforms.get(*entity).map(|t| t.0),
+                    light: lights.get(*entity).cloned(),
+                    transform_changed: false,
+                    light_changed: false,
+                })
+                .collect(),
+            exposure: aux.tonemapper_args.exposure_ev100,
+            exposure_changed: false,
+            free_instances,
+            spawns: Vec::new(),
+        })));
+
+        let mut result: Result<(), _> = Ok(());
+        if first_frame {
+            // `init` is optional
+            result = match self
+                .engine
+                .call_fn(&mut self.scope, &self.ast, "init", (scene.clone(),))
+            {
+                Err(e) if matches!(*e, rhai::EvalAltResult::ErrorFunctionNotFound(..)) => Ok(()),
+                result => result,
+            };
+        }
+        if result.is_ok() {
+            result = self.engine.call_fn(
+                &mut self.scope,
+                &self.ast,
+                "update",
+                (
+                    scene.clone(),
+                    frame_time.elapsed as FLOAT,
+                    frame_time.delta as FLOAT,
+                ),
+            );
+        }
+        if let Err(e) = result {
+            log::error!("Script failed, it won't run again: {}", e);
+            self.failed = true;
+            return;
+        }
+
+        let mut state = scene.0.lock().unwrap();
+        // Only what the script changed is written, so other entities aren't flagged as moved
+        for (entity, script_entity) in self.entities.iter().zip(state.entities.iter()) {
+            if script_entity.transform_changed {
+                if let (Some(transform), Some(new)) =
+                    (transforms.get_mut(*entity), script_entity.transform)
+                {
+                    transform.0 = new;
+                }
+            }
+            if script_entity.light_changed {
+                if let (Some(light), Some(new)) = (lights.get_mut(*entity), script_entity.light) {
+                    *light = new;
+                }
+            }
+        }
+        if state.exposure_changed {
+            aux.tonemapper_args.exposure_ev100 = state.exposure;
+        }
+        for spawn in state.spawns.drain(..) {
+            let entity = entities.create();
+            if let Ok(entry) = transforms.entry(entity) {
+                entry.or_insert(components::Transform(nalgebra::Similarity3::from_parts(
+                    nalgebra::Translation3::from(spawn.position),
+                    nalgebra::UnitQuaternion::identity(),
+                    spawn.scale.max(1.0e-6),
+                )));
+            }
+            if let Ok(entry) = meshes.entry(entity) {
+                entry.or_insert(components::Mesh(spawn.mesh));
+            }
+            self.entities.push(entity);
+        }
+    }
+}
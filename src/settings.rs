@@ -0,0 +1,98 @@
+//! User preferences, kept separate from the scene description. Loaded from `settings.ron`
+//! at startup and written back on exit, so interactive adjustments persist between runs.
+use derivative::Derivative;
+use serde::{Deserialize, Serialize};
+
+use std::{fs::File, path::PathBuf};
+
+use crate::scene::Quality;
+
+const SETTINGS_FILE: &str = "settings.ron";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct Settings {
+    pub window: WindowSettings,
+    /// Exposure value at ISO 100
+    #[derivative(Default(value = "crate::node::pbr::tonemap::exposure_to_ev100(1.7)"))]
+    pub exposure_ev100: f32,
+    /// The tonemapping curve, as cycled through with the tonemapper controls
+    pub tonemap_curve: i32,
+    /// Scale of the resolution the scene is rendered at, relative to the window
+    #[derivative(Default(value = "1.0"))]
+    pub render_scale: f32,
+    /// Filter the environment map once at this quality instead of following the scene's
+    /// filter schedule
+    pub environment_filter_quality: Option<Quality>,
+    /// What to do while the window is unfocused. Nothing is rendered while it's minimized.
+    pub unfocused: Unfocused,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct WindowSettings {
+    /// Logical size of the window
+    #[derivative(Default(value = "1280.0"))]
+    pub width: f64,
+    #[derivative(Default(value = "960.0"))]
+    pub height: f64,
+    pub mode: WindowMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub enum WindowMode {
+    #[derivative(Default)]
+    Windowed,
+    Maximized,
+    /// Borderless fullscreen on the primary monitor
+    Fullscreen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Derivative)]
+#[derivative(Default)]
+pub enum Unfocused {
+    /// Keep rendering as fast as when focused
+    #[derivative(Default)]
+    Render,
+    /// Render at most this many frames per second
+    Throttle(f32),
+    /// Stop rendering until the window is focused again
+    Pause,
+}
+
+impl Settings {
+    fn path() -> PathBuf {
+        PathBuf::from(crate::application_root_dir()).join(SETTINGS_FILE)
+    }
+
+    /// Loads the settings file, falling back to the defaults if there is none yet or it
+    /// can't be read.
+    pub fn load() -> Self {
+        let path = Self::path();
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return Settings::default(),
+        };
+        match ron::de::from_reader(std::io::BufReader::new(file)) {
+            Ok(settings) => settings,
+            Err(e) => {
+                log::warn!("Ignoring invalid settings file {:?}: {}", path, e);
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), failure::Error> {
+        let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(Self::path(), contents)?;
+        Ok(())
+    }
+
+    /// The render scale, clamped to a sane range.
+    pub fn render_scale(&self) -> f64 {
+        f64::from(self.render_scale.max(0.25).min(2.0))
+    }
+}
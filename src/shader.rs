@@ -0,0 +1,184 @@
+//! Shader permutations. A single GLSL source is compiled with different sets of feature
+//! `#define`s injected after its `#version` directive, and each resulting module is cached
+//! so that every permutation is only compiled once.
+use rendy::shader::{Shader, ShaderKind, SourceLanguage, SourceShaderInfo, SpirvShader};
+
+use std::{
+    collections::HashMap,
+    ops::{BitAnd, BitOr, BitOrAssign},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// A set of optional shader features, each of which maps to a preprocessor define.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShaderFeatures(u32);
+
+impl ShaderFeatures {
+    pub const NONE: ShaderFeatures = ShaderFeatures(0);
+    /// The material has an emissive texture
+    pub const HAS_EMISSIVE: ShaderFeatures = ShaderFeatures(1 << 0);
+    /// The material has a clearcoat layer
+    pub const HAS_CLEARCOAT: ShaderFeatures = ShaderFeatures(1 << 1);
+    /// Fragments below the material's alpha cutoff are discarded
+    pub const ALPHA_MASK: ShaderFeatures = ShaderFeatures(1 << 2);
+    /// Material debug views can be selected at runtime
+    pub const DEBUG_VIEW: ShaderFeatures = ShaderFeatures(1 << 3);
+    /// The material has a bent normal texture, for specular occlusion
+    pub const HAS_BENT_NORMAL: ShaderFeatures = ShaderFeatures(1 << 4);
+    /// The material has a height texture, which offsets its texture coordinates with
+    /// parallax occlusion mapping
+    pub const HAS_HEIGHT: ShaderFeatures = ShaderFeatures(1 << 5);
+    /// The material's specular lobe is stretched along a direction of anisotropy
+    pub const HAS_ANISOTROPY: ShaderFeatures = ShaderFeatures(1 << 6);
+    /// The material scatters light under its surface, wrapping diffuse light past the
+    /// terminator and letting it through thin parts
+    pub const HAS_SUBSURFACE: ShaderFeatures = ShaderFeatures(1 << 7);
+    /// Only the diffuse light of subsurface scattering materials is drawn, to be blurred, see
+    /// `node::pbr::subsurface`
+    pub const SUBSURFACE_PASS: ShaderFeatures = ShaderFeatures(1 << 8);
+
+    /// The features that depend on a material, rather than on global render settings
+    pub const MATERIAL: ShaderFeatures = ShaderFeatures(
+        ShaderFeatures::HAS_EMISSIVE.0
+            | ShaderFeatures::HAS_CLEARCOAT.0
+            | ShaderFeatures::ALPHA_MASK.0
+            | ShaderFeatures::HAS_BENT_NORMAL.0
+            | ShaderFeatures::HAS_HEIGHT.0
+            | ShaderFeatures::HAS_ANISOTROPY.0
+            | ShaderFeatures::HAS_SUBSURFACE.0,
+    );
+
+    const DEFINES: [(ShaderFeatures, &'static str); 9] = [
+        (ShaderFeatures::HAS_EMISSIVE, "HAS_EMISSIVE"),
+        (ShaderFeatures::HAS_CLEARCOAT, "HAS_CLEARCOAT"),
+        (ShaderFeatures::ALPHA_MASK, "ALPHA_MASK"),
+        (ShaderFeatures::DEBUG_VIEW, "DEBUG_VIEW"),
+        (ShaderFeatures::HAS_BENT_NORMAL, "HAS_BENT_NORMAL"),
+        (ShaderFeatures::HAS_HEIGHT, "HAS_HEIGHT"),
+        (ShaderFeatures::HAS_ANISOTROPY, "HAS_ANISOTROPY"),
+        (ShaderFeatures::HAS_SUBSURFACE, "HAS_SUBSURFACE"),
+        (ShaderFeatures::SUBSURFACE_PASS, "SUBSURFACE_PASS"),
+    ];
+
+    pub fn contains(self, other: ShaderFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: ShaderFeatures) {
+        self.0 |= other.0;
+    }
+
+    /// The preprocessor defines enabled by this set of features.
+    pub fn defines(self) -> impl Iterator<Item = &'static str> {
+        Self::DEFINES
+            .iter()
+            .filter(move |(feature, _)| self.contains(*feature))
+            .map(|(_, define)| *define)
+    }
+}
+
+impl BitOr for ShaderFeatures {
+    type Output = ShaderFeatures;
+
+    fn bitor(self, other: ShaderFeatures) -> ShaderFeatures {
+        ShaderFeatures(self.0 | other.0)
+    }
+}
+
+impl BitAnd for ShaderFeatures {
+    type Output = ShaderFeatures;
+
+    fn bitand(self, other: ShaderFeatures) -> ShaderFeatures {
+        ShaderFeatures(self.0 & other.0)
+    }
+}
+
+impl BitOrAssign for ShaderFeatures {
+    fn bitor_assign(&mut self, other: ShaderFeatures) {
+        self.insert(other);
+    }
+}
+
+impl std::fmt::Display for ShaderFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let defines = self.defines().collect::<Vec<_>>();
+        if defines.is_empty() {
+            write!(f, "(none)")
+        } else {
+            write!(f, "{}", defines.join(" | "))
+        }
+    }
+}
+
+/// Compiles and caches permutations of a single GLSL shader.
+#[derive(Debug)]
+pub struct ShaderVariants {
+    path: PathBuf,
+    kind: ShaderKind,
+    cache: Mutex<HashMap<ShaderFeatures, SpirvShader>>,
+}
+
+impl ShaderVariants {
+    pub fn new(path: PathBuf, kind: ShaderKind) -> Self {
+        ShaderVariants {
+            path,
+            kind,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the compiled permutation of this shader with the given features enabled,
+    /// compiling it if it hasn't been requested before.
+    pub fn get(&self, features: ShaderFeatures) -> Result<SpirvShader, failure::Error> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(shader) = cache.get(&features) {
+            return Ok(shader.clone());
+        }
+
+        log::info!(
+            "Compiling shader variant {:?} with features: {}",
+            self.path,
+            features
+        );
+        let source = inject_defines(&std::fs::read_to_string(&self.path)?, features);
+        let shader = SourceShaderInfo::new(
+            source,
+            self.path.to_string_lossy().into_owned(),
+            self.kind,
+            SourceLanguage::GLSL,
+            "main",
+        )
+        .precompile()?;
+
+        cache.insert(features, shader.clone());
+        Ok(shader)
+    }
+}
+
+/// Insert a `#define` for each feature directly after the `#version` directive, which
+/// must remain the first statement in the source.
+fn inject_defines(source: &str, features: ShaderFeatures) -> String {
+    let defines = features
+        .defines()
+        .map(|define| format!("#define {}\n", define))
+        .collect::<String>();
+
+    match source.find("#version") {
+        Some(version_start) => {
+            let version_end = source[version_start..]
+                .find('\n')
+                .map(|i| version_start + i + 1)
+                .unwrap_or_else(|| source.len());
+            let mut out = String::with_capacity(source.len() + defines.len() + 1);
+            out.push_str(&source[..version_end]);
+            if !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&defines);
+            out.push_str(&source[version_end..]);
+            out
+        }
+        None => defines + source,
+    }
+}
@@ -0,0 +1,269 @@
+//! Storage of assets addressed by generational handles. A handle is the index of its slot and
+//! the generation the slot was at when the asset was inserted. Removing an asset bumps the
+//! generation, so handles kept to it are told apart from those of an asset put in the same
+//! slot later, instead of silently addressing the wrong one.
+//!
+//! Slots are never moved, so handle indices can address arrays on the GPU which are sized
+//! by `SlotMap::len`, e.g. of instance transforms or material uniforms.
+//!
+//! Handles are typed by the asset by default, or by another type `K` for assets whose type
+//! has parameters their handles shouldn't, like the backend of a material's textures.
+use std::{cmp::Ordering, fmt, hash, marker::PhantomData};
+
+pub struct Handle<T> {
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    /// The handle of the asset at `index` of a map made with `SlotMap::from_slots`, whose slots
+    /// all start at the first generation, for loaders which decide where assets go before
+    /// making them.
+    pub fn from_index(index: usize) -> Self {
+        Handle {
+            index: index as u32,
+            generation: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Index of the slot, to address arrays with an element per slot.
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+// Implemented by hand, since deriving them would require `T` to implement them too
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.index, self.generation) == (other.index, other.generation)
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> PartialOrd for Handle<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Handle<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.index, self.generation).cmp(&(other.index, other.generation))
+    }
+}
+
+impl<T> hash::Hash for Handle<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        (self.index, self.generation).hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+pub struct SlotMap<T, K = T> {
+    slots: Vec<Slot<T>>,
+    /// Indices of the empty slots, reused by `insert`
+    free: Vec<u32>,
+    _marker: PhantomData<fn() -> K>,
+}
+
+impl<T, K> Default for SlotMap<T, K> {
+    fn default() -> Self {
+        SlotMap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, K> SlotMap<T, K> {
+    /// A map of the given slots, whose values are addressed by `Handle::from_index`.
+    pub fn from_slots(slots: Vec<Option<T>>) -> Self {
+        let free = (0..slots.len() as u32)
+            .filter(|&index| slots[index as usize].is_none())
+            .collect();
+        SlotMap {
+            slots: slots
+                .into_iter()
+                .map(|value| Slot {
+                    generation: 0,
+                    value,
+                })
+                .collect(),
+            free,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Number of slots, empty or not, which bounds the indices of the handles.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn insert(&mut self, value: T) -> Handle<K> {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+                Handle {
+                    index,
+                    generation: slot.generation,
+                    _marker: PhantomData,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                });
+                Handle::from_index(self.slots.len() - 1)
+            }
+        }
+    }
+
+    /// Takes the asset out of its slot, after which its handles are stale. `None` if they
+    /// already were.
+    pub fn remove(&mut self, handle: Handle<K>) -> Option<T> {
+        let slot = self.slots.get_mut(handle.index())?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation += 1;
+        self.free.push(handle.index);
+        Some(value)
+    }
+
+    /// `None` if the handle is stale.
+    pub fn get(&self, handle: Handle<K>) -> Option<&T> {
+        self.slots
+            .get(handle.index())
+            .filter(|slot| slot.generation == handle.generation)?
+            .value
+            .as_ref()
+    }
+
+    pub fn get_mut(&mut self, handle: Handle<K>) -> Option<&mut T> {
+        self.slots
+            .get_mut(handle.index())
+            .filter(|slot| slot.generation == handle.generation)?
+            .value
+            .as_mut()
+    }
+
+    /// The assets with their handles, in the order of their slots.
+    pub fn iter(&self) -> impl Iterator<Item = (Handle<K>, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let handle = Handle {
+                index: index as u32,
+                generation: slot.generation,
+                _marker: PhantomData,
+            };
+            slot.value.as_ref().map(|value| (handle, value))
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    /// Every slot in order, `None` where it's empty, to fill arrays addressed by
+    /// `Handle::index`.
+    pub fn slots(&self) -> impl Iterator<Item = Option<&T>> {
+        self.slots.iter().map(|slot| slot.value.as_ref())
+    }
+}
+
+impl<T, K> std::ops::Index<Handle<K>> for SlotMap<T, K> {
+    type Output = T;
+
+    /// Panics if the handle is stale, rather than returning whatever took its slot.
+    fn index(&self, handle: Handle<K>) -> &T {
+        match self.get(handle) {
+            Some(value) => value,
+            None => panic!("Stale handle {:?}", handle),
+        }
+    }
+}
+
+impl<T, K> std::ops::IndexMut<Handle<K>> for SlotMap<T, K> {
+    /// Panics if the handle is stale, like `Index`.
+    fn index_mut(&mut self, handle: Handle<K>) -> &mut T {
+        match self.get_mut(handle) {
+            Some(value) => value,
+            None => panic!("Stale handle {:?}", handle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_slots_are_reused_with_a_new_generation() {
+        let mut map: SlotMap<&str> = SlotMap::default();
+        let first = map.insert("first");
+        let second = map.insert("second");
+        assert_eq!(map.remove(first), Some("first"));
+
+        let third = map.insert("third");
+        assert_eq!(third.index(), first.index());
+        assert_ne!(third, first);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(third), Some(&"third"));
+        assert_eq!(map[second], "second");
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(third, &"third"), (second, &"second")]
+        );
+    }
+
+    #[test]
+    fn stale_handles_address_nothing() {
+        let mut map: SlotMap<u32> = SlotMap::from_slots(vec![Some(0), None]);
+        let stale = Handle::from_index(0);
+        assert_eq!(map.remove(stale), Some(0));
+        let current = map.insert(1);
+
+        assert_eq!(map.get(stale), None);
+        assert_eq!(map.get_mut(stale), None);
+        assert_eq!(map.remove(stale), None);
+        assert_eq!(map.get(current), Some(&1));
+        // An empty slot of `from_slots` is free too, past the one just reused
+        assert_eq!(map.insert(2), Handle::from_index(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Stale handle 0v0")]
+    fn indexing_with_a_stale_handle_panics() {
+        let mut map: SlotMap<u32> = SlotMap::default();
+        let handle = map.insert(0);
+        map.remove(handle);
+        map.insert(1);
+        let _ = map[handle];
+    }
+}
@@ -0,0 +1,135 @@
+//! Smoke test of the render graph. `--smoke` rebuilds the graph in every configuration it can
+//! be toggled into at runtime, and runs a frame in each, so shaders which no longer compile
+//! and pipelines whose layouts no longer match their shaders turn up without trying every
+//! combination of keys by hand. With validation layers enabled, a configuration also fails
+//! when they report errors, e.g. for layouts which the driver tolerates.
+//!
+//! Starts from the scene's configuration, then flips each toggle on its own, then all of
+//! them at once. Shadows are left as the scene has them, since the shadow map is only made at
+//! load.
+//!
+//! `tests/smoke.rs` runs it as a test, ignored by default since it needs a GPU.
+use crate::{node::pbr::Aux, node::pbr::IblMode, scene::RenderSettings};
+
+/// The parts of `Aux` which change how the graph is built.
+#[derive(Debug, Clone, Copy)]
+struct Configuration {
+    render_settings: RenderSettings,
+    shader_debug_views: bool,
+    ibl_mode: IblMode,
+}
+
+impl Configuration {
+    fn from_aux(aux: &Aux) -> Self {
+        Configuration {
+            render_settings: aux.render_settings,
+            shader_debug_views: aux.shader_debug_views,
+            ibl_mode: aux.ibl_mode,
+        }
+    }
+
+    fn apply(&self, aux: &mut Aux) {
+        aux.render_settings = self.render_settings;
+        aux.shader_debug_views = self.shader_debug_views;
+        aux.ibl_mode = self.ibl_mode;
+    }
+
+    /// Flips one of the `TOGGLES` toggles of the configuration.
+    fn toggle(&mut self, toggle: usize) {
+        let render_settings = &mut self.render_settings;
+        match toggle {
+            0 => render_settings.occlusion_culling = !render_settings.occlusion_culling,
+            1 => render_settings.scopes = !render_settings.scopes,
+            2 => render_settings.subsurface_scattering = !render_settings.subsurface_scattering,
+            3 => self.shader_debug_views = !self.shader_debug_views,
+            _ => self.ibl_mode = self.ibl_mode.next(),
+        }
+    }
+}
+
+/// Occlusion culling, scopes, subsurface scattering, the material debug views and the
+/// reference image based lighting, which accumulates frames
+const TOGGLES: usize = 5;
+
+pub struct SmokeTest {
+    configurations: Vec<Configuration>,
+    /// The configuration the graph is built with, or being rebuilt into
+    current: usize,
+    /// Validation errors logged before the current configuration was applied
+    validation_errors: usize,
+    failed: Vec<Configuration>,
+}
+
+impl SmokeTest {
+    /// Starts from the configuration in `aux`, which the graph was first built with.
+    pub fn new(aux: &Aux) -> Self {
+        let first = Configuration::from_aux(aux);
+        let mut configurations = vec![first];
+        let mut all = first;
+        for toggle in 0..TOGGLES {
+            let mut configuration = first;
+            configuration.toggle(toggle);
+            configurations.push(configuration);
+            all.toggle(toggle);
+        }
+        configurations.push(all);
+        SmokeTest {
+            configurations,
+            current: 0,
+            validation_errors: crate::validation::errors(),
+            failed: Vec::new(),
+        }
+    }
+
+    /// Records whether a frame ran in the current configuration, then moves `aux` on to the
+    /// next one, for the graph to be rebuilt in. Once every configuration has had a frame,
+    /// logs which failed and returns whether they all ran.
+    pub fn frame_done(&mut self, aux: &mut Aux, ran: bool) -> Option<bool> {
+        let configuration = self.configurations[self.current];
+        let validation_errors = crate::validation::errors();
+        if !ran {
+            log::error!("Smoke test failed to run a frame with {:?}", configuration);
+            self.failed.push(configuration);
+        } else if validation_errors > self.validation_errors {
+            log::error!(
+                "Smoke test ran a frame with {:?}, with {} validation errors",
+                configuration,
+                validation_errors - self.validation_errors
+            );
+            self.failed.push(configuration);
+        } else {
+            log::info!("Smoke test ran a frame with {:?}", configuration);
+        }
+        self.validation_errors = validation_errors;
+        self.current += 1;
+        if let Some(next) = self.configurations.get(self.current) {
+            next.apply(aux);
+            return None;
+        }
+
+        if self.failed.is_empty() {
+            log::info!(
+                "Smoke test passed in {} configurations",
+                self.configurations.len()
+            );
+        } else {
+            log::error!(
+                "Smoke test failed in {} of {} configurations",
+                self.failed.len(),
+                self.configurations.len()
+            );
+        }
+        Some(self.failed.is_empty())
+    }
+
+    /// Records that the graph couldn't be built in the current configuration. The app closes
+    /// after, so this fails the test.
+    pub fn build_failed(&mut self) {
+        let configuration = self.configurations[self.current];
+        log::error!(
+            "Smoke test failed to build the graph with {:?}",
+            configuration
+        );
+        self.failed.push(configuration);
+    }
+}
@@ -0,0 +1,211 @@
+//! Static batching, enabled with `static_batching` in the scene config. Once a scene's
+//! entities are created, the meshes of those marked `static` are merged into a few big ones,
+//! one for each material, with their vertices moved to where the entities place them. The
+//! entities keep their transforms but lose their meshes, so they no longer take an instance
+//! each, and each batch is drawn in a single call instead of one per mesh they had.
+//!
+//! Entities only share a batch if they also cast shadows alike and are in the same layers.
+//! Meshes with levels of detail or a lightmap are left as they are, since both are chosen or
+//! baked for each mesh. A batch is culled as a whole, so scattered entities make batches which
+//! are rarely culled, and the meshes only static entities used stay loaded until assets are
+//! collected with **F4**.
+use crate::{asset, components, geometry_pool::GeometryPoolBuilder};
+
+use rendy::mesh::PosNormTangTex;
+use specs::prelude::*;
+
+use std::collections::HashMap;
+
+/// How deep parents are followed, to catch entities which are their own ancestor
+const MAX_DEPTH: usize = 64;
+
+/// What the primitives in a batch have in common
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct BatchKey {
+    material: asset::MaterialHandle,
+    casts_shadows: bool,
+    /// See `components::Layers`
+    layers: u32,
+}
+
+/// The transform of an entity in the world, from its own and its ancestors', or `None` if one
+/// of them has none.
+fn world_transform(
+    entity: Entity,
+    transforms: &ReadStorage<'_, components::Transform>,
+    parents: &ReadStorage<'_, components::Parent>,
+) -> Option<nalgebra::Similarity3<f32>> {
+    let mut transform = transforms.get(entity)?.0;
+    let mut current = entity;
+    for _ in 0..MAX_DEPTH {
+        match parents.get(current) {
+            Some(parent) => {
+                transform = transforms.get(parent.entity)?.0 * transform;
+                current = parent.entity;
+            }
+            None => return Some(transform),
+        }
+    }
+    None
+}
+
+/// Merges the meshes of the static entities into batches, adding each batch's mesh and an
+/// entity drawing it. Runs while the scene is loaded, before the geometry pool is built.
+pub fn bake(
+    world: &mut specs::World,
+    primitive_storage: &mut Vec<Option<asset::Primitive>>,
+    mesh_storage: &mut Vec<Option<asset::Mesh>>,
+    geometry_pool: &mut GeometryPoolBuilder,
+) {
+    let mut batched_entities = Vec::new();
+    let mut batch_index = HashMap::new();
+    let mut batches: Vec<(
+        BatchKey,
+        Vec<(asset::PrimitiveHandle, nalgebra::Similarity3<f32>)>,
+    )> = Vec::new();
+    {
+        let entities = world.entities();
+        let meshes = world.read_storage::<components::Mesh>();
+        let statics = world.read_storage::<components::Static>();
+        let transforms = world.read_storage::<components::Transform>();
+        let parents = world.read_storage::<components::Parent>();
+        let no_shadows = world.read_storage::<components::NoShadows>();
+        let layers = world.read_storage::<components::Layers>();
+        for (entity, mesh, _, no_shadows, layers) in (
+            &entities,
+            &meshes,
+            &statics,
+            no_shadows.maybe(),
+            layers.maybe(),
+        )
+            .join()
+        {
+            let mesh_data = mesh_storage[mesh.0.index()]
+                .as_ref()
+                .expect("Entities' meshes are loaded");
+            if !mesh_data.lods.is_empty() || mesh_data.lod_of.is_some() {
+                continue;
+            }
+            if mesh_data.lightmap.is_some() {
+                continue;
+            }
+            let transform = match world_transform(entity, &transforms, &parents) {
+                Some(transform) => transform,
+                None => continue,
+            };
+            for primitive in mesh_data.primitives.iter() {
+                let key = BatchKey {
+                    material: primitive_storage[primitive.index()]
+                        .as_ref()
+                        .expect("Primitives of loaded meshes are loaded")
+                        .mat,
+                    casts_shadows: no_shadows.is_none(),
+                    layers: layers.map_or(1, |layers| layers.0),
+                };
+                let index = *batch_index.entry(key).or_insert_with(|| {
+                    batches.push((key, Vec::new()));
+                    batches.len() - 1
+                });
+                batches[index].1.push((*primitive, transform));
+            }
+            batched_entities.push(entity);
+        }
+    }
+    if batched_entities.is_empty() {
+        return;
+    }
+
+    {
+        let mut meshes = world.write_storage::<components::Mesh>();
+        for entity in batched_entities.iter() {
+            meshes.remove(*entity);
+        }
+    }
+
+    let primitive_count = batches
+        .iter()
+        .map(|(_, primitives)| primitives.len())
+        .sum::<usize>();
+    for (i, (key, primitives)) in batches.iter().enumerate() {
+        let mut vertices = Vec::new();
+        let mut lightmap_uvs = Vec::new();
+        let mut indices = Vec::new();
+        for (primitive, transform) in primitives.iter() {
+            let primitive = primitive_storage[primitive.index()]
+                .as_ref()
+                .expect("Primitives of loaded meshes are loaded");
+            let geometry = &primitive.geometry;
+            let first_vertex = vertices.len() as u32;
+            let rotation = transform.isometry.rotation;
+            vertices.extend(
+                geometry_pool
+                    .vertices(primitive.range, geometry.positions.len())
+                    .iter()
+                    .map(|v| {
+                        let position =
+                            transform.transform_point(&nalgebra::Point3::from(v.position.0));
+                        let normal = rotation * nalgebra::Vector3::from(v.normal.0);
+                        let [x, y, z, w] = v.tangent.0;
+                        let tangent = rotation * nalgebra::Vector3::new(x, y, z);
+                        PosNormTangTex {
+                            position: [position.x, position.y, position.z].into(),
+                            normal: [normal.x, normal.y, normal.z].into(),
+                            tangent: [tangent.x, tangent.y, tangent.z, w].into(),
+                            tex_coord: v.tex_coord,
+                        }
+                    }),
+            );
+            lightmap_uvs.extend_from_slice(&geometry.lightmap_uvs);
+            indices.extend(geometry.indices.iter().map(|index| first_vertex + index));
+        }
+
+        // Centered on the entity drawing it, for its bounding sphere to be as small as it can
+        let mut min = nalgebra::Vector3::repeat(std::f32::INFINITY);
+        let mut max = nalgebra::Vector3::repeat(std::f32::NEG_INFINITY);
+        for v in vertices.iter() {
+            let position = nalgebra::Vector3::from(v.position.0);
+            min = min.inf(&position);
+            max = max.sup(&position);
+        }
+        let center = (min + max) * 0.5;
+        for v in vertices.iter_mut() {
+            let position = nalgebra::Vector3::from(v.position.0) - center;
+            v.position = [position.x, position.y, position.z].into();
+        }
+
+        let mesh = asset::add_primitive_mesh(
+            &vertices,
+            lightmap_uvs,
+            indices,
+            format!("Static batch {}", i),
+            key.material,
+            1,
+            primitive_storage,
+            mesh_storage,
+            geometry_pool,
+        );
+        let mut entity_builder = world
+            .create_entity()
+            .with(components::Transform(nalgebra::Similarity3::from_parts(
+                nalgebra::Translation3::from(center),
+                nalgebra::UnitQuaternion::identity(),
+                1.0,
+            )))
+            .with(components::Mesh(mesh))
+            .with(components::Static);
+        if !key.casts_shadows {
+            entity_builder = entity_builder.with(components::NoShadows);
+        }
+        if key.layers != 1 {
+            entity_builder = entity_builder.with(components::Layers(key.layers));
+        }
+        entity_builder.build();
+    }
+
+    log::info!(
+        "Merged the {} primitives of {} static entities into {} batches",
+        primitive_count,
+        batched_entities.len(),
+        batches.len()
+    );
+}
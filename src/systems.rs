@@ -1,12 +1,58 @@
-use crate::{asset, components, input, node};
-use nalgebra::Similarity3;
+use crate::{
+    asset, components,
+    geometry_pool::GeometryPool,
+    gizmo, input,
+    memory_stats::{GpuMemoryStats, MemoryTotals},
+    node, raycast,
+    scene_manager::SceneManager,
+};
+use rand::Rng;
 use rendy::{hal, init::winit};
-use specs::{prelude::*, storage::UnprotectedStorage};
+use specs::prelude::*;
 
 use std::collections::HashSet;
 
 pub use crate::transform::systems::*;
 
+/// The time advanced by the current dispatch, set before each one. Systems animate by it
+/// rather than measuring time themselves, so that a replay plays at the recorded pace.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameTime {
+    /// Seconds since the previous dispatch
+    pub delta: f32,
+    /// Seconds since the first dispatch
+    pub elapsed: f32,
+}
+
+impl FrameTime {
+    /// Frame times longer than this are clamped, so that a long stall doesn't make
+    /// everything jump ahead or spawn a burst of particles all at once
+    pub const MAX_DELTA: f32 = 0.1;
+
+    pub fn advance(&mut self, delta: f32) {
+        self.delta = delta.min(Self::MAX_DELTA);
+        self.elapsed += self.delta;
+    }
+}
+
+/// Keeps the `ViewportInfo` of the main window up to date, before the systems reading it run.
+pub struct ViewportSystem;
+
+impl<'a> System<'a> for ViewportSystem {
+    type SystemData = (
+        Read<'a, input::EventBucket>,
+        WriteExpect<'a, input::ViewportInfo>,
+    );
+
+    fn run(&mut self, (events, mut viewport): Self::SystemData) {
+        for event in events.0.iter() {
+            if let winit::event::Event::WindowEvent { window_id, event } = event {
+                viewport.update_with_window_event(*window_id, event);
+            }
+        }
+    }
+}
+
 pub struct InputSystem;
 
 impl<'a> System<'a> for InputSystem {
@@ -24,27 +70,42 @@ impl<'a> System<'a> for InputSystem {
     }
 }
 
-pub struct PbrAuxInputSystem {
-    pub helmet_mesh: asset::MeshHandle,
-}
+pub struct PbrAuxInputSystem;
 
 impl<'a> System<'a> for PbrAuxInputSystem {
     type SystemData = (
         Read<'a, input::EventBucket>,
         Read<'a, input::InputState>,
+        ReadExpect<'a, input::ViewportInfo>,
         Read<'a, asset::MeshStorage>,
         Write<'a, node::pbr::Aux>,
-        Write<'a, HelmetArraySize>,
+        Read<'a, InstanceArray>,
+        Write<'a, InstanceArraySize>,
+        Write<'a, SceneManager>,
     );
 
     fn run(
         &mut self,
-        (events, input, mesh_storage, mut aux, mut helmet_array_size): Self::SystemData,
+        (
+            events,
+            input,
+            viewport,
+            mesh_storage,
+            mut aux,
+            instance_array,
+            mut instance_array_size,
+            mut scene_manager,
+        ): Self::SystemData,
     ) {
         use input::MouseState;
         use winit::event::{ElementState, Event, ModifiersState, VirtualKeyCode, WindowEvent};
 
-        let mesh = &mesh_storage.0[self.helmet_mesh];
+        // Rows are only added to an array with a mesh
+        let max_instances = instance_array
+            .mesh
+            .and_then(|mesh| mesh_storage.0.get(mesh))
+            .map(|mesh| mesh.max_instances);
+        let max_counts = instance_array.settings.max_counts;
 
         let mut input = (*input).clone();
         for event in events.0.iter() {
@@ -62,7 +123,18 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                             ) = (input.mouse, input.modifiers)
                             {
                                 aux.tonemapper_args.comparison_factor =
-                                    input.calc_comparison_factor();
+                                    input.calc_comparison_factor(&viewport);
+                            }
+                            // Drags the divider of the split view
+                            if let (
+                                MouseState {
+                                    left: ElementState::Pressed,
+                                    ..
+                                },
+                                ModifiersState { alt: true, .. },
+                            ) = (input.mouse, input.modifiers)
+                            {
+                                aux.split_view.divider = input.calc_comparison_factor(&viewport);
                             }
                         }
                         WindowEvent::KeyboardInput {
@@ -76,42 +148,142 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                                         ElementState::Pressed,
                                         ModifiersState { shift: false, .. },
                                     ) => {
-                                        helmet_array_size.try_add_x(mesh.max_instances);
+                                        if let Some(max) = max_instances {
+                                            instance_array_size.try_add_x(max, max_counts[0]);
+                                        }
                                     }
                                     (
                                         VirtualKeyCode::X,
                                         ElementState::Pressed,
                                         ModifiersState { shift: true, .. },
                                     ) => {
-                                        helmet_array_size.try_sub_x();
+                                        instance_array_size.try_sub_x();
                                     }
                                     (
                                         VirtualKeyCode::Y,
                                         ElementState::Pressed,
-                                        ModifiersState { shift: false, .. },
+                                        ModifiersState {
+                                            shift: false,
+                                            ctrl: false,
+                                            ..
+                                        },
                                     ) => {
-                                        helmet_array_size.try_add_y(mesh.max_instances);
+                                        if let Some(max) = max_instances {
+                                            instance_array_size.try_add_y(max, max_counts[1]);
+                                        }
                                     }
                                     (
                                         VirtualKeyCode::Y,
                                         ElementState::Pressed,
-                                        ModifiersState { shift: true, .. },
+                                        ModifiersState {
+                                            shift: true,
+                                            ctrl: false,
+                                            ..
+                                        },
                                     ) => {
-                                        helmet_array_size.try_sub_y();
+                                        instance_array_size.try_sub_y();
                                     }
                                     (
                                         VirtualKeyCode::Z,
                                         ElementState::Pressed,
-                                        ModifiersState { shift: false, .. },
+                                        ModifiersState {
+                                            shift: false,
+                                            ctrl: false,
+                                            ..
+                                        },
                                     ) => {
-                                        helmet_array_size.try_add_z(mesh.max_instances);
+                                        if let Some(max) = max_instances {
+                                            instance_array_size.try_add_z(max, max_counts[2]);
+                                        }
                                     }
                                     (
                                         VirtualKeyCode::Z,
                                         ElementState::Pressed,
-                                        ModifiersState { shift: true, .. },
+                                        ModifiersState {
+                                            shift: true,
+                                            ctrl: false,
+                                            ..
+                                        },
+                                    ) => {
+                                        instance_array_size.try_sub_z();
+                                    }
+                                    // Split view controls, holding alt to change its right side
+                                    (
+                                        VirtualKeyCode::D,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
                                     ) => {
-                                        helmet_array_size.try_sub_z();
+                                        aux.split_view.enabled = !aux.split_view.enabled;
+                                        if aux.split_view.enabled {
+                                            log::info!("Split view on. {}", aux.split_view);
+                                        }
+                                    }
+                                    (
+                                        VirtualKeyCode::E,
+                                        ElementState::Pressed,
+                                        ModifiersState {
+                                            alt: true,
+                                            shift: false,
+                                            ..
+                                        },
+                                    ) => {
+                                        aux.split_view.exposure_ev100 -=
+                                            input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        log::info!("{}", aux.split_view);
+                                    }
+                                    (
+                                        VirtualKeyCode::E,
+                                        ElementState::Pressed,
+                                        ModifiersState {
+                                            alt: true,
+                                            shift: true,
+                                            ..
+                                        },
+                                    ) => {
+                                        aux.split_view.exposure_ev100 +=
+                                            input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        log::info!("{}", aux.split_view);
+                                    }
+                                    (
+                                        VirtualKeyCode::A,
+                                        ElementState::Pressed,
+                                        ModifiersState { alt: true, .. },
+                                    ) => aux.split_view.curve = 0,
+                                    (
+                                        VirtualKeyCode::U,
+                                        ElementState::Pressed,
+                                        ModifiersState { alt: true, .. },
+                                    ) => aux.split_view.curve = 1,
+                                    (
+                                        VirtualKeyCode::C,
+                                        ElementState::Pressed,
+                                        ModifiersState { alt: true, .. },
+                                    ) => aux.split_view.curve = 2,
+                                    (
+                                        VirtualKeyCode::V,
+                                        ElementState::Pressed,
+                                        ModifiersState {
+                                            alt: true,
+                                            shift: false,
+                                            ..
+                                        },
+                                    ) if aux.shader_debug_views => {
+                                        aux.split_view.debug_view =
+                                            aux.split_view.debug_view.next();
+                                        log::info!("{}", aux.split_view);
+                                    }
+                                    (
+                                        VirtualKeyCode::V,
+                                        ElementState::Pressed,
+                                        ModifiersState {
+                                            alt: true,
+                                            shift: true,
+                                            ..
+                                        },
+                                    ) if aux.shader_debug_views => {
+                                        aux.split_view.debug_view =
+                                            aux.split_view.debug_view.prev();
+                                        log::info!("{}", aux.split_view);
                                     }
                                     // Tonemapper controls
                                     (
@@ -119,7 +291,7 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                                         ElementState::Pressed,
                                         ModifiersState { shift: false, .. },
                                     ) => {
-                                        aux.tonemapper_args.exposure +=
+                                        aux.tonemapper_args.exposure_ev100 -=
                                             input::EXPOSURE_ADJUST_SENSITIVITY;
                                     }
                                     (
@@ -127,7 +299,7 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                                         ElementState::Pressed,
                                         ModifiersState { shift: true, .. },
                                     ) => {
-                                        aux.tonemapper_args.exposure -=
+                                        aux.tonemapper_args.exposure_ev100 +=
                                             input::EXPOSURE_ADJUST_SENSITIVITY;
                                     }
                                     (
@@ -145,6 +317,11 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                                         ElementState::Pressed,
                                         ModifiersState { .. },
                                     ) => aux.tonemapper_args.curve = 2,
+                                    (
+                                        VirtualKeyCode::F,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.tonemapper_args.false_color ^= 1,
                                     // Environment Cube map display
                                     (
                                         VirtualKeyCode::M,
@@ -184,6 +361,231 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                                             .cube_roughness
                                             .min(crate::SPEC_CUBEMAP_MIP_LEVELS as f32 - 1.0);
                                     }
+                                    (
+                                        VirtualKeyCode::Tab,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.cube_display = aux.cube_display.next();
+                                        log::info!("Displaying {:?} cube map", aux.cube_display);
+                                    }
+                                    (
+                                        VirtualKeyCode::Tab,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.cube_display = aux.cube_display.prev();
+                                        log::info!("Displaying {:?} cube map", aux.cube_display);
+                                    }
+                                    // Adjusts the specular roughness shown without switching
+                                    // to the specular map, so it can be set up beforehand
+                                    (
+                                        VirtualKeyCode::LBracket,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.cube_roughness = (aux.cube_roughness
+                                            - input::CUBE_ROUGHNESS_SENSITIVITY)
+                                            .max(0.0);
+                                        log::info!(
+                                            "Specular cube roughness: {}",
+                                            aux.cube_roughness
+                                        );
+                                    }
+                                    (
+                                        VirtualKeyCode::RBracket,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.cube_roughness = (aux.cube_roughness
+                                            + input::CUBE_ROUGHNESS_SENSITIVITY)
+                                            .min(crate::SPEC_CUBEMAP_MIP_LEVELS as f32 - 1.0);
+                                        log::info!(
+                                            "Specular cube roughness: {}",
+                                            aux.cube_roughness
+                                        );
+                                    }
+                                    // Step through the mips of the displayed cube map, then back
+                                    // to the regular view
+                                    (
+                                        VirtualKeyCode::L,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.cube_mip = match aux.cube_mip {
+                                            None => Some(0),
+                                            Some(mip) if mip + 1 < aux.cube_mip_levels() => {
+                                                Some(mip + 1)
+                                            }
+                                            Some(_) => None,
+                                        };
+                                        match aux.cube_mip {
+                                            Some(mip) => log::info!(
+                                                "Displaying mip {} of {} of the {:?} cube map",
+                                                mip,
+                                                aux.cube_mip_levels(),
+                                                aux.cube_display
+                                            ),
+                                            None => log::info!("Displaying cube map normally"),
+                                        }
+                                    }
+                                    // Shading debug views, only compiled into the shader
+                                    // variants with `shader_debug_views`
+                                    (VirtualKeyCode::V, ElementState::Pressed, _)
+                                        if !aux.shader_debug_views =>
+                                    {
+                                        log::warn!(
+                                            "Debug views need `shader_debug_views: true` in the scene config"
+                                        );
+                                    }
+                                    (
+                                        VirtualKeyCode::V,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => aux.debug_view = aux.debug_view.next(),
+                                    (
+                                        VirtualKeyCode::V,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => aux.debug_view = aux.debug_view.prev(),
+                                    // Exposure scopes
+                                    (
+                                        VirtualKeyCode::H,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.scopes = aux.scopes.next();
+                                        log::info!("Scopes: {:?}", aux.scopes);
+                                    }
+                                    // Ground grid
+                                    (
+                                        VirtualKeyCode::G,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.grid.enabled = !aux.grid.enabled,
+                                    (
+                                        VirtualKeyCode::P,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        log::info!("Capturing panorama");
+                                        aux.capture_panorama = true;
+                                    }
+                                    (
+                                        VirtualKeyCode::P,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        log::info!("Capturing HDR screenshot");
+                                        aux.capture_screenshot = true;
+                                    }
+                                    (
+                                        VirtualKeyCode::O,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.occlusion.specular_mode =
+                                            aux.occlusion.specular_mode.next();
+                                        log::info!(
+                                            "Specular occlusion: {:?}",
+                                            aux.occlusion.specular_mode
+                                        );
+                                    }
+                                    (
+                                        VirtualKeyCode::F12,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.capture_frame = true,
+                                    // Showing the reference IBL on either side also rebuilds
+                                    // the graph, to average its frames
+                                    (
+                                        VirtualKeyCode::F8,
+                                        ElementState::Pressed,
+                                        ModifiersState { alt: true, .. },
+                                    ) => {
+                                        aux.split_view.ibl_mode = aux.split_view.ibl_mode.next();
+                                        log::info!("{}", aux.split_view);
+                                    }
+                                    (
+                                        VirtualKeyCode::F8,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.ibl_mode = aux.ibl_mode.next();
+                                        log::info!("IBL: {:?}", aux.ibl_mode);
+                                    }
+                                    // Passes toggled here rebuild the graph after the frame
+                                    (
+                                        VirtualKeyCode::F5,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.render_settings.occlusion_culling =
+                                            !aux.render_settings.occlusion_culling
+                                    }
+                                    (
+                                        VirtualKeyCode::F6,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.render_settings.scopes = !aux.render_settings.scopes,
+                                    (
+                                        VirtualKeyCode::F9,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        aux.render_settings.subsurface_scattering =
+                                            !aux.render_settings.subsurface_scattering
+                                    }
+                                    (
+                                        VirtualKeyCode::F7,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.shader_debug_views = !aux.shader_debug_views,
+                                    (
+                                        VirtualKeyCode::B,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => {
+                                        log::info!("Baking light probes");
+                                        aux.bake_probes = true;
+                                    }
+                                    (
+                                        VirtualKeyCode::F4,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.collect_assets = true,
+                                    // Scene switching
+                                    (
+                                        VirtualKeyCode::F3,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => scene_manager.request_next(false),
+                                    (
+                                        VirtualKeyCode::F3,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => scene_manager.request_next(true),
+                                    // Shadow cascade debug view
+                                    (
+                                        VirtualKeyCode::K,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => aux.shadow_cascade_debug = !aux.shadow_cascade_debug,
+                                    (
+                                        VirtualKeyCode::K,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.shadow_cascades_frozen = !aux.shadow_cascades_frozen;
+                                        log::info!(
+                                            "Shadow cascades {}",
+                                            if aux.shadow_cascades_frozen {
+                                                "frozen"
+                                            } else {
+                                                "following the camera"
+                                            }
+                                        );
+                                    }
                                     _ => (),
                                 }
                             }
@@ -197,26 +599,73 @@ impl<'a> System<'a> for PbrAuxInputSystem {
     }
 }
 
-pub struct CameraInputSystem;
+/// Orbits the active camera around its focus with the mouse buttons, or flies it around with
+/// the cursor grabbed after Q is pressed, until Escape. Left dragging doesn't orbit while it
+/// drags a handle of the gizmo.
+#[derive(Default)]
+pub struct CameraInputSystem {
+    fly: bool,
+    /// Arrow keys and page up/down held down, which move the camera while flying
+    fly_keys: HashSet<winit::event::VirtualKeyCode>,
+}
 
 impl<'a> System<'a> for CameraInputSystem {
     type SystemData = (
+        Entities<'a>,
         Read<'a, input::EventBucket>,
         Read<'a, input::InputState>,
+        ReadExpect<'a, input::ViewportInfo>,
+        Read<'a, FrameTime>,
+        Read<'a, gizmo::Gizmo>,
+        Write<'a, input::WindowControl>,
         WriteStorage<'a, components::Transform>,
         ReadStorage<'a, components::ActiveCamera>,
+        ReadStorage<'a, components::DebugWindowCamera>,
         WriteStorage<'a, components::Camera>,
     );
 
     fn run(
         &mut self,
-        (events, input, mut transforms, active_cameras, mut cameras): Self::SystemData,
+        (
+            entities,
+            events,
+            input,
+            viewport,
+            frame_time,
+            gizmo,
+            mut window_control,
+            mut transforms,
+            active_cameras,
+            debug_cameras,
+            mut cameras,
+        ): Self::SystemData,
     ) {
         use input::{
-            MouseState, ROTATE_SENSITIVITY, TRANSLATE_SENSITIVITY, ZOOM_MOUSE_SENSITIVITY,
-            ZOOM_SCROLL_SENSITIVITY,
+            MouseState, FLY_SPEED, ROTATE_SENSITIVITY, TRANSLATE_SENSITIVITY,
+            ZOOM_MOUSE_SENSITIVITY, ZOOM_SCROLL_SENSITIVITY,
+        };
+        use winit::event::{
+            DeviceEvent, ElementState, Event, KeyboardInput, ModifiersState, MouseScrollDelta,
+            VirtualKeyCode, WindowEvent,
         };
-        use winit::event::{DeviceEvent, ElementState, Event, ModifiersState, MouseScrollDelta};
+
+        let viewport_changed = events.0.iter().any(|event| match event {
+            Event::WindowEvent {
+                window_id,
+                event: WindowEvent::Resized(_),
+            }
+            | Event::WindowEvent {
+                window_id,
+                event: WindowEvent::HiDpiFactorChanged(_),
+            } => *window_id == viewport.window_id,
+            _ => false,
+        });
+        if viewport_changed && viewport.has_area() {
+            for (camera, _) in (&mut cameras, !&debug_cameras).join() {
+                camera.aspect = viewport.aspect();
+            }
+        }
+
         if let Some((_, transform, camera)) = (&active_cameras, &mut transforms, &mut cameras)
             .join()
             .next()
@@ -226,6 +675,58 @@ impl<'a> System<'a> for CameraInputSystem {
                 match event {
                     Event::WindowEvent { event, .. } => {
                         input.update_with_window_event(&event);
+                        if let WindowEvent::KeyboardInput {
+                            input:
+                                KeyboardInput {
+                                    virtual_keycode: Some(key),
+                                    state,
+                                    ..
+                                },
+                            ..
+                        } = event
+                        {
+                            match (key, state) {
+                                (VirtualKeyCode::Q, ElementState::Pressed) if !self.fly => {
+                                    self.fly = true;
+                                    window_control.grab_cursor = Some(true);
+                                }
+                                (VirtualKeyCode::Escape, ElementState::Pressed) if self.fly => {
+                                    self.fly = false;
+                                    self.fly_keys.clear();
+                                    window_control.grab_cursor = Some(false);
+                                }
+                                (
+                                    VirtualKeyCode::Up
+                                    | VirtualKeyCode::Down
+                                    | VirtualKeyCode::Left
+                                    | VirtualKeyCode::Right
+                                    | VirtualKeyCode::PageUp
+                                    | VirtualKeyCode::PageDown,
+                                    _,
+                                ) if self.fly => {
+                                    if *state == ElementState::Pressed {
+                                        self.fly_keys.insert(*key);
+                                    } else {
+                                        self.fly_keys.remove(key);
+                                    }
+                                }
+                                _ => (),
+                            }
+                        }
+                    }
+                    // Looks around from the eye with raw motion, whatever the buttons held
+                    Event::DeviceEvent {
+                        event: DeviceEvent::MouseMotion { delta },
+                        ..
+                    } if self.fly => {
+                        let eye = camera.eye();
+                        camera.yaw += -delta.0 as f32 * ROTATE_SENSITIVITY;
+                        camera.pitch += delta.1 as f32 * ROTATE_SENSITIVITY;
+                        camera.pitch = camera
+                            .pitch
+                            .max(-std::f32::consts::FRAC_PI_2 + 0.0001)
+                            .min(std::f32::consts::FRAC_PI_2 - 0.0001);
+                        camera.set_eye(eye);
                     }
                     Event::DeviceEvent { event, .. } => match event {
                         DeviceEvent::MouseMotion { delta } => {
@@ -236,7 +737,7 @@ impl<'a> System<'a> for CameraInputSystem {
                                         ..
                                     },
                                     ModifiersState { ctrl: false, .. },
-                                ) => {
+                                ) if !gizmo.is_dragging() => {
                                     camera.yaw += -delta.0 as f32 * ROTATE_SENSITIVITY;
                                     camera.pitch += delta.1 as f32 * ROTATE_SENSITIVITY;
                                     camera.pitch = camera
@@ -292,112 +793,128 @@ impl<'a> System<'a> for CameraInputSystem {
                 }
             }
 
-            let eye = camera.focus
-                + (camera.dist
-                    * nalgebra::Vector3::new(
-                        camera.yaw.sin() * camera.pitch.cos(),
-                        camera.pitch.sin(),
-                        camera.yaw.cos() * camera.pitch.cos(),
-                    ));
-
-            transform.0 = Similarity3::from_parts(
-                nalgebra::Translation::from(eye.coords.clone()),
-                // Invert direction for right handed
-                nalgebra::UnitQuaternion::face_towards(
-                    &(eye - camera.focus),
-                    &nalgebra::Vector3::y(),
-                ),
-                1.0,
-            );
+            if self.fly {
+                let forward = (camera.focus - camera.eye()).normalize();
+                let right = forward.cross(&nalgebra::Vector3::y()).normalize();
+                let mut direction = nalgebra::Vector3::zeros();
+                for key in self.fly_keys.iter() {
+                    direction += match key {
+                        VirtualKeyCode::Up => forward,
+                        VirtualKeyCode::Down => -forward,
+                        VirtualKeyCode::Right => right,
+                        VirtualKeyCode::Left => -right,
+                        VirtualKeyCode::PageUp => nalgebra::Vector3::y(),
+                        VirtualKeyCode::PageDown => -nalgebra::Vector3::y(),
+                        _ => nalgebra::Vector3::zeros(),
+                    };
+                }
+                // Moving the focus moves the eye along with it
+                camera.focus += direction * FLY_SPEED * frame_time.delta;
+            }
+
+            transform.0 = camera.orbit_transform();
+        }
+
+        // Only written when it changed, so the transform isn't flagged as moved every frame
+        let moved_debug_cameras = (&entities, &debug_cameras, &transforms, &cameras)
+            .join()
+            .map(|(entity, _, transform, camera)| (entity, transform, camera.orbit_transform()))
+            .filter(|(_, transform, orbit_transform)| transform.0 != *orbit_transform)
+            .map(|(entity, _, orbit_transform)| (entity, orbit_transform))
+            .collect::<Vec<_>>();
+        for (entity, orbit_transform) in moved_debug_cameras {
+            if let Some(transform) = transforms.get_mut(entity) {
+                transform.0 = orbit_transform;
+            }
         }
     }
 }
 
+/// The copies of a mesh resized with X, Y and Z, set up on load from the scene's `array`.
+#[derive(Debug, Default, Clone)]
+pub struct InstanceArray {
+    /// The mesh of the array's prefab. Without one, the array stays empty.
+    pub mesh: Option<asset::MeshHandle>,
+    pub settings: crate::prefab::ArraySettings,
+}
+
 #[derive(Default)]
-pub struct HelmetArrayEntities(pub Vec<Entity>);
+pub struct InstanceArrayEntities(pub Vec<Entity>);
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
-pub struct HelmetArraySize {
+pub struct InstanceArraySize {
     pub x: u8,
     pub y: u8,
     pub z: u8,
 }
 
-impl HelmetArraySize {
+impl InstanceArraySize {
     pub fn size(&self) -> usize {
         self.x as usize * self.y as usize * self.z as usize
     }
 
-    pub fn generate_transforms(&self) -> Vec<nalgebra::Similarity3<f32>> {
-        let x_size = 3.0;
-        let y_size = 4.0;
-        let z_size = 4.0;
-        let mut transforms = Vec::with_capacity(self.size());
-        for x in 0..self.x {
-            for y in 0..self.y {
-                for z in 0..self.z {
-                    transforms.push(nalgebra::Similarity3::from_parts(
-                        nalgebra::Translation3::new(
-                            (x as f32 * x_size) - (x_size * (self.x - 1) as f32 * 0.5),
-                            (y as f32 * y_size) - (y_size * (self.y - 1) as f32 * 0.5),
-                            (z as f32 * z_size) - (z_size * (self.z - 1) as f32 * 0.5),
-                        ),
-                        nalgebra::UnitQuaternion::identity(),
-                        1.0,
-                    ));
-                }
-            }
+    pub fn generate_transforms(
+        &self,
+        settings: &crate::prefab::ArraySettings,
+    ) -> Vec<nalgebra::Similarity3<f32>> {
+        crate::prefab::GridPlacement {
+            center: [0.0; 3],
+            counts: [self.x as u32, self.y as u32, self.z as u32],
+            spacing: settings.spacing,
+            scale: settings.scale,
         }
-        transforms
+        .transforms()
     }
 
-    pub fn try_add_x(&mut self, max: u16) {
+    /// Adds a row along X, unless the array would have more than `max` copies, or more than
+    /// `max_count` along X.
+    pub fn try_add_x(&mut self, max: u16, max_count: u8) {
         let mut n_size = *self;
-        n_size.x = n_size.x.checked_add(1).unwrap_or(u8::max_value());
+        n_size.x = n_size.x.saturating_add(1).min(max_count);
         if n_size.size() <= max as _ {
             *self = n_size
         }
     }
 
-    pub fn try_add_y(&mut self, max: u16) {
+    pub fn try_add_y(&mut self, max: u16, max_count: u8) {
         let mut n_size = *self;
-        n_size.y = n_size.y.checked_add(1).unwrap_or(u8::max_value());
+        n_size.y = n_size.y.saturating_add(1).min(max_count);
         if n_size.size() <= max as _ {
             *self = n_size
         }
     }
 
-    pub fn try_add_z(&mut self, max: u16) {
+    pub fn try_add_z(&mut self, max: u16, max_count: u8) {
         let mut n_size = *self;
-        n_size.z = n_size.z.checked_add(1).unwrap_or(u8::max_value());
+        n_size.z = n_size.z.saturating_add(1).min(max_count);
         if n_size.size() <= max as _ {
             *self = n_size
         }
     }
 
     pub fn try_sub_x(&mut self) {
-        self.x = (self.x - 1).max(1);
+        self.x = self.x.saturating_sub(1).max(1);
     }
 
     pub fn try_sub_y(&mut self) {
-        self.y = (self.y - 1).max(1);
+        self.y = self.y.saturating_sub(1).max(1);
     }
 
     pub fn try_sub_z(&mut self) {
-        self.z = (self.z - 1).max(1);
+        self.z = self.z.saturating_sub(1).max(1);
     }
 }
 
-pub struct HelmetArraySizeUpdateSystem {
-    pub curr_size: HelmetArraySize,
-    pub helmet_mesh: asset::MeshHandle,
+pub struct InstanceArraySizeUpdateSystem {
+    pub curr_size: InstanceArraySize,
 }
 
-impl<'a> System<'a> for HelmetArraySizeUpdateSystem {
+impl<'a> System<'a> for InstanceArraySizeUpdateSystem {
     type SystemData = (
         Entities<'a>,
-        Write<'a, HelmetArrayEntities>,
-        Read<'a, HelmetArraySize>,
+        Write<'a, InstanceArrayEntities>,
+        Read<'a, InstanceArraySize>,
+        Read<'a, InstanceArray>,
         WriteStorage<'a, components::Transform>,
         WriteStorage<'a, components::Mesh>,
     );
@@ -406,47 +923,503 @@ impl<'a> System<'a> for HelmetArraySizeUpdateSystem {
         &mut self,
         (
             entities,
-            mut helmet_array_entities,
-            helmet_array_size,
+            mut instance_array_entities,
+            instance_array_size,
+            instance_array,
             mut transforms,
             mut meshes,
         ): Self::SystemData,
     ) {
-        if *helmet_array_size != self.curr_size {
-            while helmet_array_entities.0.len() < helmet_array_size.size() {
-                helmet_array_entities.0.push(entities.create());
+        let mesh = match instance_array.mesh {
+            Some(mesh) => mesh,
+            None => return,
+        };
+        if *instance_array_size != self.curr_size {
+            while instance_array_entities.0.len() < instance_array_size.size() {
+                instance_array_entities.0.push(entities.create());
             }
-            while helmet_array_entities.0.len() > helmet_array_size.size() {
-                let entity = helmet_array_entities.0.pop().unwrap();
+            while instance_array_entities.0.len() > instance_array_size.size() {
+                let entity = instance_array_entities.0.pop().unwrap();
                 entities.delete(entity).unwrap();
                 meshes.remove(entity);
                 transforms.remove(entity);
             }
-            let new_helmet_transforms = helmet_array_size.generate_transforms();
-            for (transform, entity) in new_helmet_transforms
+            let new_transforms = instance_array_size.generate_transforms(&instance_array.settings);
+            for (transform, entity) in new_transforms
                 .into_iter()
-                .zip(helmet_array_entities.0.iter())
+                .zip(instance_array_entities.0.iter())
             {
                 if let Ok(entry) = transforms.entry(*entity) {
                     let entity_transform = entry.or_insert(Default::default());
                     entity_transform.0 = transform
                 }
                 if let Ok(entry) = meshes.entry(*entity) {
-                    entry.or_insert(components::Mesh(self.helmet_mesh));
+                    entry.or_insert(components::Mesh(mesh));
+                }
+            }
+            self.curr_size = *instance_array_size;
+        }
+    }
+}
+
+/// The most particles alive at once, across all emitters. Emitters stop spawning
+/// while the limit is reached.
+pub const MAX_PARTICLES: usize = 8192;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Particle {
+    pub position: nalgebra::Point3<f32>,
+    pub velocity: nalgebra::Vector3<f32>,
+    pub acceleration: nalgebra::Vector3<f32>,
+    pub age: f32,
+    pub lifetime: f32,
+    pub size: f32,
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+}
+
+impl Particle {
+    /// The color at the particle's current age
+    pub fn color(&self) -> [f32; 4] {
+        let t = (self.age / self.lifetime).min(1.0);
+        let mut color = [0.0; 4];
+        for i in 0..4 {
+            color[i] = self.start_color[i] + (self.end_color[i] - self.start_color[i]) * t;
+        }
+        color
+    }
+}
+
+/// All live particles, in no particular order.
+#[derive(Default, Debug)]
+pub struct ParticleStorage(pub Vec<Particle>);
+
+/// Keeps the `Raycast` resource on the active camera, as it is after this dispatch moves it.
+pub struct RaycastUpdateSystem;
+
+impl<'a> System<'a> for RaycastUpdateSystem {
+    type SystemData = (
+        ReadExpect<'a, input::ViewportInfo>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::Camera>,
+        ReadStorage<'a, components::ActiveCamera>,
+        Write<'a, raycast::Raycast>,
+    );
+
+    fn run(
+        &mut self,
+        (viewport, transforms, cameras, active_cameras, mut raycast): Self::SystemData,
+    ) {
+        raycast.camera = (&active_cameras, &cameras, &transforms)
+            .join()
+            .map(|(_, camera, transform)| (camera, transform).into())
+            .next();
+        raycast.viewport = Some(*viewport);
+    }
+}
+
+/// How far past a LOD's `screen_size` an entity has to get, relative to it, to switch to or
+/// back from that LOD. Keeps entities right at a threshold from popping back and forth.
+pub const LOD_HYSTERESIS: f32 = 0.1;
+
+/// Swaps the meshes of entities for the level of detail matching how big their base mesh is
+/// on screen from the active camera, see `LOD_HYSTERESIS`. Entities whose mesh has no LODs are
+/// left alone.
+pub struct LodSelectionSystem;
+
+impl<'a> System<'a> for LodSelectionSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, asset::MeshStorage>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::Camera>,
+        ReadStorage<'a, components::ActiveCamera>,
+        WriteStorage<'a, components::Mesh>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mesh_storage, transforms, cameras, active_cameras, mut meshes): Self::SystemData,
+    ) {
+        let _scope = crate::profile::scope("LodSelectionSystem");
+        let (camera, camera_transform) = match (&cameras, &active_cameras, &transforms)
+            .join()
+            .map(|(camera, _, transform)| (camera, transform))
+            .next()
+        {
+            Some(camera) => camera,
+            None => return,
+        };
+        let camera_pos = camera_transform.0.column(3).xyz();
+        let tan_half_fov = (camera.fov / 2.0).tan();
+
+        let mut swaps = Vec::new();
+        for (entity, mesh, transform) in (&entities, &meshes, &transforms).join() {
+            let current = &mesh_storage.0[mesh.0];
+            let base = current.lod_of.unwrap_or(mesh.0);
+            let base_data = &mesh_storage.0[base];
+            if base_data.lods.is_empty() {
+                continue;
+            }
+            let scale = (0..3)
+                .map(|i| transform.0.column(i).xyz().norm())
+                .fold(0.0, f32::max);
+            let distance = (transform.0.column(3).xyz() - camera_pos).norm();
+            let screen_size = base_data.bounding_radius * scale / (distance * tan_half_fov);
+            // 0 for the base mesh, then 1 for the first LOD and so on
+            let current_level = base_data
+                .lods
+                .iter()
+                .position(|lod| lod.mesh == mesh.0)
+                .map_or(0, |i| i + 1);
+            let target = base_data
+                .lods
+                .iter()
+                .enumerate()
+                .take_while(|(i, lod)| {
+                    // The thresholds crossed to get to the current level are easier to stay
+                    // under, and the next ones harder to cross
+                    let margin = if *i < current_level {
+                        1.0 + LOD_HYSTERESIS
+                    } else {
+                        1.0 - LOD_HYSTERESIS
+                    };
+                    screen_size < lod.screen_size * margin
+                })
+                .last()
+                .map_or(base, |(_, lod)| lod.mesh);
+            if target != mesh.0 {
+                swaps.push((entity, target));
+            }
+        }
+        // Removed and inserted rather than modified, so `InstanceCacheUpdateSystem` moves the
+        // entity to the instances of the new mesh
+        for (entity, target) in swaps {
+            meshes.remove(entity);
+            meshes
+                .insert(entity, components::Mesh(target))
+                .expect("Entity of a mesh isn't alive");
+        }
+    }
+}
+
+/// Finds the lights reaching each mesh, by testing the mesh's bounding sphere against the
+/// sphere each point light reaches, so that its fragments only shade those. Lights are
+/// numbered like `node::pbr::gather_lights` does.
+pub struct LightListSystem;
+
+impl<'a> System<'a> for LightListSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, asset::MeshStorage>,
+        ReadStorage<'a, components::Light>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::Mesh>,
+        WriteStorage<'a, components::LightList>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mesh_storage, lights, transforms, meshes, mut light_lists): Self::SystemData,
+    ) {
+        let _scope = crate::profile::scope("LightListSystem");
+        // A bit per light, so the mask holds at most 32 of them
+        let reaches = (&lights, &transforms)
+            .join()
+            .take(crate::MAX_LIGHTS.min(32))
+            .map(|(light, transform)| {
+                light
+                    .reach()
+                    .map(|reach| (transform.0.column(3).xyz(), reach))
+            })
+            .collect::<Vec<_>>();
+
+        for (entity, mesh, transform) in (&entities, &meshes, &transforms).join() {
+            let center = transform.0.column(3).xyz();
+            let scale = (0..3)
+                .map(|i| transform.0.column(i).xyz().norm())
+                .fold(0.0, f32::max);
+            let radius = mesh_storage.0[mesh.0].bounding_radius * scale;
+            let mask = reaches
+                .iter()
+                .enumerate()
+                .filter(|(_, reach)| match reach {
+                    Some((position, reach)) => (position - center).norm() <= radius + reach,
+                    None => true,
+                })
+                .fold(0, |mask, (i, _)| mask | 1 << i);
+            let light_list = components::LightList(mask);
+            match light_lists.get_mut(entity) {
+                Some(current) => *current = light_list,
+                None => {
+                    light_lists
+                        .insert(entity, light_list)
+                        .expect("Entity of a mesh isn't alive");
+                }
+            }
+        }
+    }
+}
+
+/// Fits the shadow cascades of the shadowed directional light to the active camera, and
+/// outlines them with debug lines while the cascade debug view is on. While they are frozen
+/// they are left where they are, so the camera can move away to look at them.
+pub struct ShadowCascadeSystem {
+    /// Number of cascades the shadow map has layers for
+    pub max_cascades: usize,
+    /// Meshes added, removed or changed, to find changes to the static meshes
+    pub mesh_reader_id: ReaderId<ComponentEvent>,
+    pub transform_reader_id: ReaderId<ComponentEvent>,
+    pub layers_reader_id: ReaderId<ComponentEvent>,
+}
+
+impl<'a> System<'a> for ShadowCascadeSystem {
+    type SystemData = (
+        Read<'a, node::pbr::Aux>,
+        ReadStorage<'a, components::Light>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::Camera>,
+        ReadStorage<'a, components::ActiveCamera>,
+        ReadStorage<'a, components::Mesh>,
+        ReadStorage<'a, components::Static>,
+        ReadStorage<'a, components::Layers>,
+        Write<'a, node::pbr::shadow::ShadowCascades>,
+        Write<'a, node::pbr::lines::DebugLines>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            aux,
+            lights,
+            transforms,
+            cameras,
+            active_cameras,
+            meshes,
+            statics,
+            layers,
+            mut shadow_cascades,
+            mut debug_lines,
+        ): Self::SystemData,
+    ) {
+        use node::pbr::shadow;
+
+        // Reading marks every event as read, even those `any` stops before
+        let static_changed = meshes
+            .channel()
+            .read(&mut self.mesh_reader_id)
+            .chain(transforms.channel().read(&mut self.transform_reader_id))
+            .chain(layers.channel().read(&mut self.layers_reader_id))
+            .any(|event| match event {
+                ComponentEvent::Inserted(id)
+                | ComponentEvent::Modified(id)
+                | ComponentEvent::Removed(id) => statics.mask().contains(*id),
+            });
+        let static_generation = shadow_cascades.static_generation + static_changed as u64;
+        shadow_cascades.static_generation = static_generation;
+        let previous_layers = shadow_cascades.settings.layers;
+
+        let camera = (&active_cameras, &cameras, &transforms)
+            .join()
+            .map(|(_, camera, transform)| (*camera, transform.0))
+            .next();
+        if !aux.shadow_cascades_frozen {
+            *shadow_cascades = match (shadow::shadowed_light(&lights, &transforms), camera) {
+                (Some((index, light, transform)), Some((camera, camera_transform)))
+                    if self.max_cascades > 0 =>
+                {
+                    let settings = components::ShadowSettings {
+                        cascades: light.shadow.cascades.min(self.max_cascades as u32),
+                        ..light.shadow
+                    };
+                    let direction = transform.0.column(2).xyz().normalize();
+                    shadow::ShadowCascades {
+                        light: Some(index),
+                        settings,
+                        cascades: shadow::fit_cascades(
+                            &camera,
+                            &camera_transform,
+                            &direction,
+                            &settings,
+                        ),
+                        static_generation,
+                    }
                 }
+                _ => shadow::ShadowCascades {
+                    static_generation,
+                    ..Default::default()
+                },
+            };
+        }
+        // The static meshes' cached shadows are drawn again for other casting layers too
+        if shadow_cascades.settings.layers != previous_layers {
+            shadow_cascades.static_generation += 1;
+        }
+
+        if aux.shadow_cascade_debug {
+            for (cascade, color) in shadow_cascades
+                .cascades
+                .iter()
+                .zip(shadow::CASCADE_COLORS.iter())
+            {
+                debug_lines.frustum(&cascade.corners, *color);
+            }
+        }
+    }
+}
+
+/// Spawns particles from every `ParticleEmitter`, integrates them, and removes
+/// them once their lifetime is up.
+pub struct ParticleSystem {
+    /// Seeded the same on every run, so that a replay spawns the same particles
+    pub rng: rand::rngs::StdRng,
+}
+
+impl Default for ParticleSystem {
+    fn default() -> Self {
+        use rand::SeedableRng;
+        ParticleSystem {
+            rng: rand::rngs::StdRng::seed_from_u64(0),
+        }
+    }
+}
+
+impl<'a> System<'a> for ParticleSystem {
+    type SystemData = (
+        Read<'a, FrameTime>,
+        Write<'a, ParticleStorage>,
+        WriteStorage<'a, components::ParticleEmitter>,
+        ReadStorage<'a, components::GlobalTransform>,
+    );
+
+    fn run(&mut self, (frame_time, mut particles, mut emitters, transforms): Self::SystemData) {
+        let dt = frame_time.delta;
+
+        let particles = &mut particles.0;
+        particles.retain(|particle| particle.age + dt < particle.lifetime);
+        for particle in particles.iter_mut() {
+            particle.age += dt;
+            particle.velocity += particle.acceleration * dt;
+            particle.position += particle.velocity * dt;
+        }
+
+        let rng = &mut self.rng;
+        for (emitter, transform) in (&mut emitters, &transforms).join() {
+            emitter.accumulator += emitter.rate * dt;
+            let origin = nalgebra::Point3::from(transform.0.column(3).xyz());
+            while emitter.accumulator >= 1.0 && particles.len() < MAX_PARTICLES {
+                emitter.accumulator -= 1.0;
+                let spread = nalgebra::Vector3::new(
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(-1.0, 1.0),
+                ) * emitter.spread;
+                particles.push(Particle {
+                    position: origin,
+                    velocity: nalgebra::Vector3::from(emitter.velocity) + spread,
+                    acceleration: nalgebra::Vector3::from(emitter.acceleration),
+                    age: 0.0,
+                    lifetime: emitter.lifetime,
+                    size: emitter.size,
+                    start_color: emitter.start_color,
+                    end_color: emitter.end_color,
+                });
             }
+            emitter.accumulator = emitter.accumulator.min(1.0);
+        }
+    }
+}
+
+/// Moves entities with `Spin`, `Oscillate` or `Orbit` components by updating their
+/// transforms, before the global transforms are computed.
+pub struct ProceduralAnimationSystem;
+
+impl<'a> System<'a> for ProceduralAnimationSystem {
+    type SystemData = (
+        Read<'a, FrameTime>,
+        WriteStorage<'a, components::Transform>,
+        ReadStorage<'a, components::Spin>,
+        WriteStorage<'a, components::Oscillate>,
+        ReadStorage<'a, components::Orbit>,
+    );
+
+    fn run(
+        &mut self,
+        (frame_time, mut transforms, spins, mut oscillates, orbits): Self::SystemData,
+    ) {
+        use std::f32::consts::PI;
+
+        let dt = frame_time.delta;
+        let t = frame_time.elapsed;
+
+        // Only entities which are animated are touched, so the others aren't flagged as moved
+        for (transform, spin) in (&mut transforms, &spins).join() {
+            if let Some(axis) = nalgebra::Unit::try_new(nalgebra::Vector3::from(spin.axis), 1.0e-6)
+            {
+                let rotation = &mut transform.0.isometry.rotation;
+                *rotation =
+                    *rotation * nalgebra::UnitQuaternion::from_axis_angle(&axis, spin.speed * dt);
+            }
+        }
+
+        for (transform, oscillate) in (&mut transforms, &mut oscillates).join() {
+            let translation = &mut transform.0.isometry.translation.vector;
+            let origin = nalgebra::Vector3::from(*oscillate.origin.get_or_insert([
+                translation.x,
+                translation.y,
+                translation.z,
+            ]));
+            let axis = nalgebra::Vector3::from(oscillate.axis)
+                .try_normalize(1.0e-6)
+                .unwrap_or_else(nalgebra::Vector3::zeros);
+            *translation =
+                origin + axis * oscillate.amplitude * (2.0 * PI * oscillate.frequency * t).sin();
+        }
+
+        for (transform, orbit) in (&mut transforms, &orbits).join() {
+            let angle = orbit.speed * t;
+            transform.0.isometry.translation.vector = nalgebra::Vector3::from(orbit.center)
+                + nalgebra::Vector3::new(angle.cos(), 0.0, -angle.sin()) * orbit.radius;
         }
     }
 }
 
 pub type InstanceIndex = u16;
+#[derive(Debug, Clone, Copy)]
 pub struct MeshInstance {
     pub mesh: asset::MeshHandle,
     pub instance: InstanceIndex,
 }
 
+/// The instance of each entity with a mesh, by entity id. Kept by `InstanceCacheUpdateSystem`,
+/// so entities whose mesh was added since it last ran don't have one yet.
 #[derive(Default)]
-pub struct MeshInstanceStorage(pub DenseVecStorage<MeshInstance>);
+pub struct MeshInstanceStorage {
+    instances: Vec<Option<MeshInstance>>,
+}
+
+impl MeshInstanceStorage {
+    pub fn get(&self, id: specs::world::Index) -> Option<&MeshInstance> {
+        self.instances.get(id as usize)?.as_ref()
+    }
+
+    fn get_mut(&mut self, id: specs::world::Index) -> Option<&mut MeshInstance> {
+        self.instances.get_mut(id as usize)?.as_mut()
+    }
+
+    /// Returns the instance the entity had already, if any.
+    fn insert(&mut self, id: specs::world::Index, instance: MeshInstance) -> Option<MeshInstance> {
+        let id = id as usize;
+        if id >= self.instances.len() {
+            self.instances.resize(id + 1, None);
+        }
+        self.instances[id].replace(instance)
+    }
+
+    fn remove(&mut self, id: specs::world::Index) -> Option<MeshInstance> {
+        self.instances.get_mut(id as usize)?.take()
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct InstanceCache {
@@ -454,31 +1427,72 @@ pub struct InstanceCache {
     pub dirty_mesh_indirects: Vec<HashSet<asset::MeshHandle>>,
     pub mesh_instance_counts: Vec<u32>,
     pub material_bitsets: Vec<BitSet>,
+    /// The entities with an instance of each mesh
+    pub mesh_entity_bitsets: Vec<BitSet>,
+}
+
+impl InstanceCache {
+    /// A cache without instances, for storages of `num_meshes` meshes and `num_materials`
+    /// materials.
+    pub fn new(frames_in_flight: usize, num_meshes: usize, num_materials: usize) -> Self {
+        InstanceCache {
+            dirty_entities: vec![BitSet::new(); frames_in_flight],
+            dirty_mesh_indirects: vec![HashSet::new(); frames_in_flight],
+            mesh_instance_counts: vec![0; num_meshes],
+            material_bitsets: vec![BitSet::new(); num_materials],
+            mesh_entity_bitsets: vec![BitSet::new(); num_meshes],
+        }
+    }
 }
 
-pub struct InstanceCacheUpdateSystem<B> {
+/// Keeps the instances of every mesh packed at the start of its transforms, and marks what
+/// changed for each frame in flight. Only touches the world, so it runs without a device.
+pub struct InstanceCacheUpdateSystem {
     pub frames_in_flight: usize,
     pub previous_frame: usize,
     pub mesh_reader_id: ReaderId<ComponentEvent>,
     pub transform_reader_id: ReaderId<ComponentEvent>,
+    /// Entities changing layers, whose instances are shown or hidden in each view
+    pub layers_reader_id: ReaderId<ComponentEvent>,
     pub dirty_entities_scratch: BitSet,
     pub dirty_mesh_indirects_scratch: HashSet<asset::MeshHandle>,
     pub mesh_inserted: BitSet,
     pub mesh_deleted: BitSet,
-    pub mesh_modified: BitSet,
-    pub mesh_entity_bitsets: Vec<BitSet>,
-    pub _pd: core::marker::PhantomData<B>,
 }
 
-impl<'a, B: hal::Backend> System<'a> for InstanceCacheUpdateSystem<B> {
+impl InstanceCacheUpdateSystem {
+    /// Registers its readers of the world's storages. The entities which already have a mesh
+    /// get their instances the first time it runs.
+    pub fn new(world: &World, frames_in_flight: usize) -> Self {
+        let mut meshes = world.write_storage::<components::Mesh>();
+        InstanceCacheUpdateSystem {
+            frames_in_flight,
+            previous_frame: frames_in_flight - 1,
+            mesh_reader_id: meshes.register_reader(),
+            transform_reader_id: world
+                .write_storage::<components::GlobalTransform>()
+                .register_reader(),
+            layers_reader_id: world
+                .write_storage::<components::Layers>()
+                .register_reader(),
+            dirty_entities_scratch: BitSet::new(),
+            dirty_mesh_indirects_scratch: HashSet::new(),
+            mesh_inserted: meshes.mask().clone(),
+            mesh_deleted: BitSet::new(),
+        }
+    }
+}
+
+impl<'a> System<'a> for InstanceCacheUpdateSystem {
     type SystemData = (
         Entities<'a>,
         Write<'a, InstanceCache>,
         Read<'a, asset::MeshStorage>,
         Write<'a, MeshInstanceStorage>,
-        Read<'a, asset::PrimitiveStorage<B>>,
+        Read<'a, asset::PrimitiveStorage>,
         ReadStorage<'a, components::Mesh>,
         ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::Layers>,
     );
 
     fn run(
@@ -491,8 +1505,10 @@ impl<'a, B: hal::Backend> System<'a> for InstanceCacheUpdateSystem<B> {
             primitive_storage,
             meshes,
             transforms,
+            layers,
         ): Self::SystemData,
     ) {
+        let _scope = crate::profile::scope("InstanceCacheUpdateSystem");
         cache.dirty_entities[self.previous_frame].clear();
         cache.dirty_mesh_indirects[self.previous_frame].clear();
         self.dirty_entities_scratch.clear();
@@ -524,41 +1540,62 @@ impl<'a, B: hal::Backend> System<'a> for InstanceCacheUpdateSystem<B> {
                     _ => (),
                 };
             }
+            for event in layers.channel().read(&mut self.layers_reader_id) {
+                match event {
+                    ComponentEvent::Inserted(id)
+                    | ComponentEvent::Modified(id)
+                    | ComponentEvent::Removed(id) => {
+                        if mesh_mask.contains(*id) {
+                            self.dirty_entities_scratch.add(*id);
+                        }
+                    }
+                }
+            }
         }
-        for (entity, _) in (&entities, &self.mesh_deleted).join() {
-            let MeshInstance { mesh, instance } =
-                unsafe { mesh_instance_storage.0.remove(entity.id()) };
-            self.mesh_entity_bitsets[mesh].remove(entity.id());
-            cache.mesh_instance_counts[mesh] -= 1;
-            for primitive_idx in mesh_storage.0[mesh].primitives.iter() {
-                let primitive = &primitive_storage.0[*primitive_idx];
-                cache.material_bitsets[primitive.mat].remove(entity.id());
+        // Joined by id rather than with the entities, since deleted entities are no longer
+        // alive but still hold their instance, as may others of the same mesh deleted with them
+        for id in (&self.mesh_deleted).join() {
+            // A mesh inserted and removed since the last run never got an instance
+            let MeshInstance { mesh, instance } = match mesh_instance_storage.remove(id) {
+                Some(mesh_instance) => mesh_instance,
+                None => continue,
+            };
+            cache.mesh_entity_bitsets[mesh.index()].remove(id);
+            cache.mesh_instance_counts[mesh.index()] -= 1;
+            for primitive_handle in mesh_storage.0[mesh].primitives.iter() {
+                let primitive = &primitive_storage.0[*primitive_handle];
+                cache.material_bitsets[primitive.mat.index()].remove(id);
             }
-            for (entity, _) in (&entities, &self.mesh_entity_bitsets[mesh]).join() {
-                let mesh_instance = unsafe { mesh_instance_storage.0.get_mut(entity.id()) };
+            for other in (&cache.mesh_entity_bitsets[mesh.index()]).join() {
+                let mesh_instance = mesh_instance_storage
+                    .get_mut(other)
+                    .expect("Entities of a mesh have an instance");
                 if mesh_instance.instance > instance {
                     mesh_instance.instance -= 1;
-                    self.dirty_entities_scratch.add(entity.id());
+                    self.dirty_entities_scratch.add(other);
                 }
             }
             self.dirty_mesh_indirects_scratch.insert(mesh);
         }
         for (entity, mesh, _) in (&entities, &meshes, &self.mesh_inserted).join() {
-            unsafe {
-                mesh_instance_storage.0.insert(
-                    entity.id(),
-                    MeshInstance {
-                        mesh: mesh.0,
-                        instance: cache.mesh_instance_counts[mesh.0] as InstanceIndex,
-                    },
-                );
-            }
-            cache.mesh_instance_counts[mesh.0] += 1;
-            for primitive_idx in mesh_storage.0[mesh.0].primitives.iter() {
-                let primitive = &primitive_storage.0[*primitive_idx];
-                cache.material_bitsets[primitive.mat].add(entity.id());
+            let previous = mesh_instance_storage.insert(
+                entity.id(),
+                MeshInstance {
+                    mesh: mesh.0,
+                    instance: cache.mesh_instance_counts[mesh.0.index()] as InstanceIndex,
+                },
+            );
+            debug_assert!(
+                previous.is_none(),
+                "Entity {} got a second mesh instance",
+                entity.id()
+            );
+            cache.mesh_instance_counts[mesh.0.index()] += 1;
+            for primitive_handle in mesh_storage.0[mesh.0].primitives.iter() {
+                let primitive = &primitive_storage.0[*primitive_handle];
+                cache.material_bitsets[primitive.mat.index()].add(entity.id());
             }
-            self.mesh_entity_bitsets[mesh.0].add(entity.id());
+            cache.mesh_entity_bitsets[mesh.0.index()].add(entity.id());
             self.dirty_entities_scratch.add(entity.id());
             self.dirty_mesh_indirects_scratch.insert(mesh.0);
         }
@@ -569,6 +1606,238 @@ impl<'a, B: hal::Backend> System<'a> for InstanceCacheUpdateSystem<B> {
         self.previous_frame = (self.previous_frame + 1) % self.frames_in_flight;
         self.mesh_inserted.clear();
         self.mesh_deleted.clear();
-        self.mesh_modified.clear();
+    }
+}
+
+/// Gathers the GPU memory held by the asset storages into `GpuMemoryStats`, logging a
+/// summary whenever it changes after the first frame, e.g. when an environment map is
+/// replaced or lightmaps are baked.
+pub struct GpuMemoryStatsSystem<B>(pub core::marker::PhantomData<B>);
+
+impl<'a, B: hal::Backend> System<'a> for GpuMemoryStatsSystem<B> {
+    type SystemData = (
+        Write<'a, GpuMemoryStats>,
+        ReadExpect<'a, asset::MaterialStorage<B>>,
+        ReadExpect<'a, GeometryPool<B>>,
+        ReadExpect<'a, asset::LightmapStorage<B>>,
+        ReadExpect<'a, node::pbr::EnvironmentStorage<B>>,
+    );
+
+    fn run(
+        &mut self,
+        (mut stats, material_storage, geometry_pool, lightmap_storage, env_storage): Self::SystemData,
+    ) {
+        let mut textures = MemoryTotals::default();
+        let mut material_uniforms = MemoryTotals::default();
+        for material in material_storage.0.values() {
+            for texture in [
+                &material.albedo,
+                &material.normal,
+                &material.metallic_roughness,
+                &material.ao,
+                &material.emissive,
+                &material.bent_normal,
+                &material.height,
+                &material.thickness,
+                &material.anisotropy,
+            ]
+            .iter()
+            {
+                textures.add_image(texture.image());
+            }
+            material_uniforms.add_buffer(&material.uniform_buffer);
+        }
+
+        let mut meshes = MemoryTotals::default();
+        meshes.add_buffer(&geometry_pool.vertices);
+        meshes.add_buffer(&geometry_pool.lightmap_uvs);
+        meshes.add_buffer(&geometry_pool.indices);
+
+        let mut lightmaps = MemoryTotals::default();
+        lightmaps.add_image(lightmap_storage.placeholder.image());
+        for lightmap in lightmap_storage.lightmaps.iter() {
+            if let Some(texture) = lightmap.texture.as_ref() {
+                lightmaps.add_image(texture.image());
+            }
+        }
+
+        let mut environment = MemoryTotals::default();
+        if let Some(env_cube) = env_storage.env_cube.as_ref() {
+            environment.add_image(env_cube.image());
+        }
+        for texture in [
+            &env_storage.irradiance_cube,
+            &env_storage.spec_cube,
+            &env_storage.spec_brdf_map,
+            &env_storage.spec_brdf_avg_map,
+            &env_storage.voxel_radiance,
+        ]
+        .iter()
+        .filter_map(|texture| texture.as_ref())
+        {
+            environment.add_image(texture.image());
+        }
+        if let Some(probes) = env_storage.probes.as_ref() {
+            environment.add_buffer(probes);
+        }
+
+        let mut assets = std::collections::BTreeMap::new();
+        assets.insert("textures", textures);
+        assets.insert("material uniforms", material_uniforms);
+        assets.insert("meshes", meshes);
+        assets.insert("lightmaps", lightmaps);
+        assets.insert("environment", environment);
+        if assets != stats.assets {
+            // The first time is logged once the pipelines are built too
+            let first = stats.assets.is_empty();
+            stats.assets = assets;
+            if !first {
+                stats.log_summary();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{geometry_pool::GeometryRange, slot_map::SlotMap};
+
+    /// A world with two meshes of one primitive each, the first of material 0 and the second
+    /// of material 1, and the system keeping their instances for two frames in flight.
+    fn setup() -> (World, InstanceCacheUpdateSystem) {
+        let mut world = World::new();
+        world.register::<components::Mesh>();
+        world.register::<components::GlobalTransform>();
+        world.register::<components::Layers>();
+        let primitive = |mesh, mat| {
+            Some(asset::Primitive {
+                range: GeometryRange::default(),
+                geometry: Default::default(),
+                mesh_handle: asset::MeshHandle::from_index(mesh),
+                mat: asset::MaterialHandle::from_index(mat),
+            })
+        };
+        let mesh = |primitive| {
+            Some(asset::Mesh {
+                primitives: vec![asset::PrimitiveHandle::from_index(primitive)],
+                ..Default::default()
+            })
+        };
+        world.add_resource(asset::PrimitiveStorage(SlotMap::from_slots(vec![
+            primitive(0, 0),
+            primitive(1, 1),
+        ])));
+        world.add_resource(asset::MeshStorage(SlotMap::from_slots(vec![
+            mesh(0),
+            mesh(1),
+        ])));
+        world.add_resource(MeshInstanceStorage::default());
+        world.add_resource(InstanceCache::new(2, 2, 2));
+        let system = InstanceCacheUpdateSystem::new(&world, 2);
+        (world, system)
+    }
+
+    fn spawn(world: &mut World, mesh: usize) -> Entity {
+        world
+            .create_entity()
+            .with(components::Mesh(asset::MeshHandle::from_index(mesh)))
+            .build()
+    }
+
+    fn instance(world: &World, entity: Entity) -> Option<InstanceIndex> {
+        world
+            .read_resource::<MeshInstanceStorage>()
+            .get(entity.id())
+            .map(|mesh_instance| mesh_instance.instance)
+    }
+
+    #[test]
+    fn instances_are_assigned_in_order() {
+        let (mut world, mut system) = setup();
+        let first = [spawn(&mut world, 0), spawn(&mut world, 0)];
+        let second = spawn(&mut world, 1);
+        system.run_now(&world.res);
+
+        assert_eq!(instance(&world, first[0]), Some(0));
+        assert_eq!(instance(&world, first[1]), Some(1));
+        assert_eq!(instance(&world, second), Some(0));
+        let cache = world.read_resource::<InstanceCache>();
+        assert_eq!(cache.mesh_instance_counts, vec![2, 1]);
+        assert!(cache.material_bitsets[0].contains(first[1].id()));
+        assert!(!cache.material_bitsets[0].contains(second.id()));
+        assert!(cache.material_bitsets[1].contains(second.id()));
+        assert!(cache.mesh_entity_bitsets[1].contains(second.id()));
+        for frame in 0..2 {
+            for entity in first.iter().chain(Some(&second)) {
+                assert!(cache.dirty_entities[frame].contains(entity.id()));
+            }
+            assert_eq!(cache.dirty_mesh_indirects[frame].len(), 2);
+        }
+    }
+
+    #[test]
+    fn removal_compacts_instances() {
+        let (mut world, mut system) = setup();
+        let entities = [
+            spawn(&mut world, 0),
+            spawn(&mut world, 0),
+            spawn(&mut world, 0),
+        ];
+        let other = spawn(&mut world, 1);
+        system.run_now(&world.res);
+
+        world.delete_entity(entities[0]).unwrap();
+        world.maintain();
+        system.run_now(&world.res);
+
+        assert_eq!(instance(&world, entities[0]), None);
+        assert_eq!(instance(&world, entities[1]), Some(0));
+        assert_eq!(instance(&world, entities[2]), Some(1));
+        assert_eq!(instance(&world, other), Some(0));
+        {
+            let cache = world.read_resource::<InstanceCache>();
+            assert_eq!(cache.mesh_instance_counts, vec![2, 1]);
+            assert!(!cache.mesh_entity_bitsets[0].contains(entities[0].id()));
+            assert!(!cache.material_bitsets[0].contains(entities[0].id()));
+            // The frame the second run cleared only holds what moved in it
+            let frame = 0;
+            assert!(cache.dirty_entities[frame].contains(entities[1].id()));
+            assert!(cache.dirty_entities[frame].contains(entities[2].id()));
+            assert!(!cache.dirty_entities[frame].contains(other.id()));
+            let mesh = asset::MeshHandle::from_index(0);
+            assert!(cache.dirty_mesh_indirects[frame].contains(&mesh));
+            assert_eq!(cache.dirty_mesh_indirects[frame].len(), 1);
+            // While the other still holds the first run's changes, not yet drawn in it
+            assert!(cache.dirty_entities[1].contains(other.id()));
+        }
+
+        // Instances added later go after the compacted ones
+        let added = spawn(&mut world, 0);
+        system.run_now(&world.res);
+        assert_eq!(instance(&world, added), Some(2));
+        assert_eq!(
+            world.read_resource::<InstanceCache>().mesh_instance_counts,
+            vec![3, 1]
+        );
+    }
+
+    #[test]
+    fn removing_the_mesh_component_frees_the_instance() {
+        let (mut world, mut system) = setup();
+        let entities = [spawn(&mut world, 0), spawn(&mut world, 0)];
+        system.run_now(&world.res);
+
+        world
+            .write_storage::<components::Mesh>()
+            .remove(entities[0]);
+        system.run_now(&world.res);
+
+        assert_eq!(instance(&world, entities[0]), None);
+        assert_eq!(instance(&world, entities[1]), Some(0));
+        assert_eq!(
+            world.read_resource::<InstanceCache>().mesh_instance_counts,
+            vec![1, 0]
+        );
     }
 }
@@ -41,24 +41,25 @@ impl Transform {
 
 impl From<gltf::scene::Transform> for Transform {
     fn from(transform: gltf::scene::Transform) -> Self {
-        use gltf::scene::Transform as GltfTransform;
-        match transform {
-            GltfTransform::Matrix { .. } => unimplemented!(),
-            GltfTransform::Decomposed {
-                translation,
-                rotation,
-                scale,
-            } => Transform::new(
-                nalgebra::Translation3::new(translation[0], translation[1], translation[2]),
-                nalgebra::UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(
-                    rotation[3],
-                    rotation[0],
-                    rotation[1],
-                    rotation[2],
-                )),
-                scale.iter().sum::<f32>() / 3.0,
-            ),
-        }
+        // Matrices are decomposed, losing any shear, like non-uniform scales are averaged
+        let (translation, rotation, scale) = transform.decomposed();
+        let scale = scale.iter().sum::<f32>() / 3.0;
+        Transform::new(
+            nalgebra::Translation3::new(translation[0], translation[1], translation[2]),
+            nalgebra::UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(
+                rotation[3],
+                rotation[0],
+                rotation[1],
+                rotation[2],
+            )),
+            // Similarities can't scale by 0, which glTF uses to hide nodes, so they're only
+            // shrunk as far as possible
+            if scale == 0.0 {
+                std::f32::MIN_POSITIVE
+            } else {
+                scale
+            },
+        )
     }
 }
 
@@ -186,7 +187,12 @@ impl<'de> Deserialize<'de> for Transform {
             }
         }
 
-        const FIELDS: &'static [&'static str] = &["translation", "rotation", "scale"];
+        const FIELDS: &'static [&'static str] = &[
+            "translation",
+            "euler_rotation",
+            "quaternion_rotation",
+            "scale",
+        ];
         deserializer.deserialize_struct("Transform", FIELDS, TransformVisitor)
     }
 }
@@ -196,20 +202,77 @@ impl Serialize for Transform {
     where
         S: Serializer,
     {
+        // Written the way it's read back: the quaternion with `w` first, as a field the
+        // deserializer knows, and in the order `visit_seq` expects
         #[derive(Serialize)]
         struct TransformValues {
             translation: [f32; 3],
-            rotation: [f32; 4],
+            quaternion_rotation: [f32; 4],
             scale: f32,
         }
 
+        let rotation = self.0.isometry.rotation.as_ref();
         Serialize::serialize(
             &TransformValues {
                 translation: self.0.isometry.translation.vector.into(),
-                rotation: self.0.isometry.rotation.as_ref().coords.into(),
+                quaternion_rotation: [rotation.w, rotation.i, rotation.j, rotation.k],
                 scale: self.0.scaling(),
             },
             serializer,
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn ron_round_trip(
+            translation in prop::array::uniform3(-1.0e4f32..1.0e4),
+            rotation in prop::array::uniform4(-1.0f32..1.0),
+            scale in 0.01f32..100.0
+        ) {
+            let quaternion = Quaternion::new(rotation[0], rotation[1], rotation[2], rotation[3]);
+            prop_assume!(quaternion.norm() > 1.0e-3);
+            let transform = Transform::new(
+                Translation3::new(translation[0], translation[1], translation[2]),
+                UnitQuaternion::from_quaternion(quaternion),
+                scale,
+            );
+
+            let text = ron::ser::to_string(&transform).unwrap();
+            let read: Transform = ron::de::from_str(&text).unwrap();
+
+            // The rotation is normalized again when read, which may change its last bits
+            let (a, b) = (&transform.0, &read.0);
+            prop_assert_eq!(a.isometry.translation, b.isometry.translation);
+            prop_assert_eq!(a.scaling(), b.scaling());
+            let (qa, qb) = (a.isometry.rotation.as_ref(), b.isometry.rotation.as_ref());
+            prop_assert!((qa.coords - qb.coords).amax() < 1.0e-6, "{} != {}", qa, qb);
+        }
+
+        #[test]
+        fn euler_rotation_is_read(
+            translation in prop::array::uniform3(-1.0e4f32..1.0e4),
+            angles in prop::array::uniform3(-std::f32::consts::PI..std::f32::consts::PI),
+            scale in 0.01f32..100.0
+        ) {
+            let text = format!(
+                "(translation: ({}, {}, {}), euler_rotation: ({}, {}, {}), scale: {})",
+                translation[0], translation[1], translation[2], angles[0], angles[1], angles[2],
+                scale
+            );
+            let read: Transform = ron::de::from_str(&text).unwrap();
+
+            let expected = Transform::new(
+                Translation3::new(translation[0], translation[1], translation[2]),
+                UnitQuaternion::from_euler_angles(angles[0], angles[1], angles[2]),
+                scale,
+            );
+            prop_assert_eq!(read, expected);
+        }
+    }
+}
@@ -9,6 +9,9 @@ use specs::prelude::{
 
 use hibitset::BitSet;
 
+/// Sentinel for entities that are not part of the cached hierarchy traversal.
+const NOT_IN_HIERARCHY: usize = std::usize::MAX;
+
 pub struct TransformSystem {
     local_modified: BitSet,
     global_modified: BitSet,
@@ -18,6 +21,15 @@ pub struct TransformSystem {
     parent_events_id: Option<ReaderId<HierarchyEvent>>,
 
     scratch: Vec<Entity>,
+
+    /// Position of each parented entity in `ParentHierarchy::all()`, indexed by entity id.
+    /// Only rebuilt when the hierarchy changes.
+    traversal_order: Vec<usize>,
+    traversal_order_dirty: bool,
+
+    /// Entities whose subtrees need to be propagated this frame.
+    dirty_roots: Vec<Entity>,
+    stack: Vec<Entity>,
 }
 
 impl TransformSystem {
@@ -29,7 +41,35 @@ impl TransformSystem {
             local_modified: BitSet::default(),
             global_modified: BitSet::default(),
             scratch: Vec::new(),
+            traversal_order: Vec::new(),
+            traversal_order_dirty: true,
+            dirty_roots: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    fn rebuild_traversal_order(&mut self, hierarchy: &ParentHierarchy) {
+        for order in self.traversal_order.iter_mut() {
+            *order = NOT_IN_HIERARCHY;
+        }
+        for (i, entity) in hierarchy.all().iter().enumerate() {
+            let id = entity.id() as usize;
+            if id >= self.traversal_order.len() {
+                self.traversal_order.resize(id + 1, NOT_IN_HIERARCHY);
+            }
+            self.traversal_order[id] = i;
         }
+        self.traversal_order_dirty = false;
+    }
+}
+
+/// Sort key which places parents before their children. Entities without a parent
+/// come first, as their subtrees can never contain one another.
+#[inline]
+fn traversal_key(traversal_order: &[usize], entity: Entity) -> usize {
+    match traversal_order.get(entity.id() as usize) {
+        Some(&order) if order != NOT_IN_HIERARCHY => order + 1,
+        _ => 0,
     }
 }
 
@@ -42,6 +82,7 @@ impl<'a> System<'a> for TransformSystem {
         WriteStorage<'a, GlobalTransform>,
     );
     fn run(&mut self, (entities, hierarchy, locals, parents, mut globals): Self::SystemData) {
+        let _scope = crate::profile::scope("TransformSystem");
         self.local_modified.clear();
         self.global_modified.clear();
 
@@ -74,6 +115,7 @@ impl<'a> System<'a> for TransformSystem {
                 .as_mut()
                 .expect("`TransformSystem::setup` was not called before `TransformSystem::run`"),
         ) {
+            self.traversal_order_dirty = true;
             match *event {
                 HierarchyEvent::Removed(entity) => {
                     // Sometimes the user may have already deleted the entity.
@@ -87,6 +129,10 @@ impl<'a> System<'a> for TransformSystem {
             }
         }
 
+        if self.traversal_order_dirty {
+            self.rebuild_traversal_order(&hierarchy);
+        }
+
         // Compute transforms without parents.
         for (entity, _, local, global, _) in (
             &*entities,
@@ -105,12 +151,31 @@ impl<'a> System<'a> for TransformSystem {
             );
         }
 
-        // Compute transforms with parents.
-        for entity in hierarchy.all() {
-            let self_dirty = self.local_modified.contains(entity.id());
-            if let (Some(parent), Some(local)) = (parents.get(*entity), locals.get(*entity)) {
-                let parent_dirty = self.global_modified.contains(parent.entity.id());
-                if parent_dirty || self_dirty {
+        // Every modified entity is the root of a subtree which needs to be propagated.
+        // Visiting them in hierarchy order means a dirty ancestor is always handled
+        // before its dirty descendants, so no subtree is walked twice.
+        self.dirty_roots.clear();
+        self.dirty_roots
+            .extend((&*entities, &self.local_modified).join().map(|d| d.0));
+        let traversal_order = &self.traversal_order;
+        self.dirty_roots
+            .sort_unstable_by_key(|entity| traversal_key(traversal_order, *entity));
+
+        for i in 0..self.dirty_roots.len() {
+            let root = self.dirty_roots[i];
+            if parents.get(root).is_some() {
+                // Already updated while propagating a dirty ancestor.
+                if self.global_modified.contains(root.id()) {
+                    continue;
+                }
+                self.stack.push(root);
+            } else {
+                self.stack.extend_from_slice(hierarchy.children(root));
+            }
+
+            // Compute transforms with parents, walking only the dirty subtree.
+            while let Some(entity) = self.stack.pop() {
+                if let (Some(parent), Some(local)) = (parents.get(entity), locals.get(entity)) {
                     let combined_transform = if let Some(parent_global) = globals.get(parent.entity)
                     {
                         (parent_global.0 * local.0.to_homogeneous())
@@ -118,11 +183,12 @@ impl<'a> System<'a> for TransformSystem {
                         local.0.to_homogeneous()
                     };
 
-                    if let Some(global) = globals.get_mut(*entity) {
+                    if let Some(global) = globals.get_mut(entity) {
                         self.global_modified.add(entity.id());
                         global.0 = combined_transform;
                     }
                 }
+                self.stack.extend_from_slice(hierarchy.children(entity));
             }
         }
     }
@@ -136,3 +202,180 @@ impl<'a> System<'a> for TransformSystem {
         self.locals_events_id = Some(locals.register_reader());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Matrix4, Translation3, UnitQuaternion};
+    use proptest::prelude::*;
+    use specs::prelude::{Builder, RunNow, World};
+    use specs_hierarchy::HierarchySystem;
+
+    struct Systems {
+        hierarchy: HierarchySystem<Parent>,
+        transform: TransformSystem,
+    }
+
+    impl Systems {
+        fn new(world: &mut World) -> Self {
+            world.register::<Transform>();
+            world.register::<GlobalTransform>();
+            world.register::<Parent>();
+            let mut hierarchy = HierarchySystem::<Parent>::new();
+            System::setup(&mut hierarchy, &mut world.res);
+            let mut transform = TransformSystem::new();
+            System::setup(&mut transform, &mut world.res);
+            Systems {
+                hierarchy,
+                transform,
+            }
+        }
+
+        fn run(&mut self, world: &mut World) {
+            self.hierarchy.run_now(&world.res);
+            self.transform.run_now(&world.res);
+            world.maintain();
+        }
+    }
+
+    /// Creates an entity for each local transform, in order, parented to the earlier entity
+    /// at its index in `parents`, if any.
+    fn build(world: &mut World, locals: &[Transform], parents: &[Option<usize>]) -> Vec<Entity> {
+        let mut entities: Vec<Entity> = Vec::new();
+        for (local, parent) in locals.iter().zip(parents.iter()) {
+            let mut builder = world.create_entity().with(local.clone());
+            if let Some(parent) = parent {
+                builder = builder.with(Parent::new(entities[*parent]));
+            }
+            entities.push(builder.build());
+        }
+        entities
+    }
+
+    /// The global transforms composed from the roots down, from scratch.
+    fn composed(locals: &[Transform], parents: &[Option<usize>]) -> Vec<Matrix4<f32>> {
+        let mut globals: Vec<Matrix4<f32>> = Vec::new();
+        for (local, parent) in locals.iter().zip(parents.iter()) {
+            let local = local.0.to_homogeneous();
+            globals.push(match parent {
+                Some(parent) => globals[*parent] * local,
+                None => local,
+            });
+        }
+        globals
+    }
+
+    fn assert_globals(world: &World, entities: &[Entity], expected: &[Matrix4<f32>]) {
+        let globals = world.read_storage::<GlobalTransform>();
+        for (i, (entity, expected)) in entities.iter().zip(expected.iter()).enumerate() {
+            let actual = globals
+                .get(*entity)
+                .expect("Entities get a global transform")
+                .0;
+            let error = (actual - expected).amax();
+            let tolerance = 1.0e-4 * expected.amax().max(1.0);
+            assert!(error <= tolerance, "Node {}: {} != {}", i, actual, expected);
+        }
+    }
+
+    fn transform() -> impl Strategy<Value = Transform> {
+        (
+            prop::array::uniform3(-10.0f32..10.0),
+            prop::array::uniform3(-std::f32::consts::PI..std::f32::consts::PI),
+            0.5f32..2.0,
+        )
+            .prop_map(|(t, r, scale)| {
+                Transform::new(
+                    Translation3::new(t[0], t[1], t[2]),
+                    UnitQuaternion::from_euler_angles(r[0], r[1], r[2]),
+                    scale,
+                )
+            })
+    }
+
+    /// Local transforms and the parent of each node, which is an earlier node if any, so that
+    /// the nodes form a forest.
+    fn forest() -> impl Strategy<Value = (Vec<Transform>, Vec<Option<usize>>)> {
+        prop::collection::vec((transform(), prop::option::of(any::<usize>())), 1..16).prop_map(
+            |nodes| {
+                nodes
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (local, parent))| {
+                        (local, parent.filter(|_| i > 0).map(|parent| parent % i))
+                    })
+                    .unzip::<_, _, Vec<_>, Vec<_>>()
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn globals_compose_locals(
+            (mut locals, parents) in forest(),
+            changes in prop::collection::vec((any::<usize>(), transform()), 0..8)
+        ) {
+            let mut world = World::new();
+            let mut systems = Systems::new(&mut world);
+            let entities = build(&mut world, &locals, &parents);
+            systems.run(&mut world);
+            assert_globals(&world, &entities, &composed(&locals, &parents));
+
+            // Only the subtrees of the changed nodes are propagated again
+            {
+                let mut storage = world.write_storage::<Transform>();
+                for (node, local) in changes {
+                    let node = node % locals.len();
+                    *storage.get_mut(entities[node]).unwrap() = local.clone();
+                    locals[node] = local;
+                }
+            }
+            systems.run(&mut world);
+            assert_globals(&world, &entities, &composed(&locals, &parents));
+        }
+    }
+
+    #[test]
+    fn only_dirty_subtrees_are_propagated() {
+        let mut world = World::new();
+        let mut systems = Systems::new(&mut world);
+        // A root with two children, the first of which has a child of its own
+        let locals = vec![Transform::default(); 4];
+        let parents = [None, Some(0), Some(1), Some(0)];
+        let entities = build(&mut world, &locals, &parents);
+        systems.run(&mut world);
+
+        let mut reader = world.write_storage::<GlobalTransform>().register_reader();
+        world
+            .write_storage::<Transform>()
+            .get_mut(entities[1])
+            .unwrap()
+            .0
+            .append_translation_mut(&Translation3::new(1.0, 0.0, 0.0));
+        systems.run(&mut world);
+
+        let mut modified = BitSet::new();
+        for event in world
+            .read_storage::<GlobalTransform>()
+            .channel()
+            .read(&mut reader)
+        {
+            if let ComponentEvent::Modified(id) = event {
+                modified.add(*id);
+            }
+        }
+        assert!(modified.contains(entities[1].id()));
+        assert!(modified.contains(entities[2].id()));
+        assert!(!modified.contains(entities[0].id()));
+        assert!(!modified.contains(entities[3].id()));
+        let translation = |entity: Entity| {
+            world
+                .read_storage::<GlobalTransform>()
+                .get(entity)
+                .unwrap()
+                .0[(0, 3)]
+        };
+        assert_eq!(translation(entities[2]), 1.0);
+        assert_eq!(translation(entities[3]), 0.0);
+    }
+}
@@ -0,0 +1,376 @@
+//! Uploading asset data through a dedicated transfer queue family, when the device has one.
+//!
+//! Resources written on the transfer queue are owned by the transfer family, so before the
+//! graphics queue can read them their ownership has to be released by the transfer queue
+//! and acquired by the graphics queue with a matching pair of barriers. The acquire waits for
+//! the release with a semaphore, so neither blocks the CPU.
+use derivative::Derivative;
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, Families, Fence, OneShot, PendingOnceState, PrimaryLevel,
+        QueueId, Submission,
+    },
+    factory::{BufferState, Factory, ImageState},
+    resource::{Buffer, Image},
+};
+
+use rendy::hal;
+
+use std::ops::Range;
+
+/// The queues used to upload data and to render with it. When the device has no dedicated
+/// transfer family, both are the same graphics queue.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadQueues {
+    pub graphics: QueueId,
+    pub transfer: QueueId,
+}
+
+impl UploadQueues {
+    pub fn new<B: hal::Backend>(graphics: QueueId, families: &Families<B>) -> Self {
+        let transfer = crate::queues::dedicated_queue(families, hal::queue::QueueType::Transfer)
+            .unwrap_or(graphics);
+
+        if transfer != graphics {
+            log::info!(
+                "Uploading assets on dedicated transfer queue: {:?}",
+                transfer
+            );
+        }
+
+        UploadQueues { graphics, transfer }
+    }
+
+    pub fn dedicated_transfer(&self) -> bool {
+        self.transfer.family != self.graphics.family
+    }
+
+    /// The state to upload a texture into. Once uploaded (and, with a dedicated transfer
+    /// queue, acquired by the graphics queue) it can be sampled from fragment shaders.
+    ///
+    /// Mips are generated with blits, which need a graphics queue, so textures which generate
+    /// them are always uploaded on the graphics queue.
+    pub fn texture_state(&self, generate_mips: bool) -> ImageState {
+        if self.dedicated_transfer() && !generate_mips {
+            ImageState {
+                queue: self.transfer,
+                stage: hal::pso::PipelineStage::TRANSFER,
+                access: hal::image::Access::TRANSFER_WRITE,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            }
+        } else {
+            ImageState {
+                queue: self.graphics,
+                stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                access: hal::image::Access::SHADER_READ,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            }
+        }
+    }
+
+    /// The state to upload a uniform buffer into, read from fragment shaders once uploaded.
+    pub fn uniform_state(&self) -> BufferState {
+        if self.dedicated_transfer() {
+            BufferState {
+                queue: self.transfer,
+                stage: hal::pso::PipelineStage::TRANSFER,
+                access: hal::buffer::Access::TRANSFER_WRITE,
+            }
+        } else {
+            BufferState {
+                queue: self.graphics,
+                stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                access: hal::buffer::Access::UNIFORM_READ,
+            }
+        }
+    }
+
+    /// The state to upload vertex or index data into, read with `access` as vertex input once
+    /// uploaded.
+    pub fn vertex_state(&self, access: hal::buffer::Access) -> BufferState {
+        if self.dedicated_transfer() {
+            BufferState {
+                queue: self.transfer,
+                stage: hal::pso::PipelineStage::TRANSFER,
+                access: hal::buffer::Access::TRANSFER_WRITE,
+            }
+        } else {
+            BufferState {
+                queue: self.graphics,
+                stage: hal::pso::PipelineStage::VERTEX_INPUT,
+                access,
+            }
+        }
+    }
+
+    fn family_range(&self) -> Range<hal::queue::QueueFamilyId> {
+        hal::queue::QueueFamilyId(self.transfer.family.index)
+            ..hal::queue::QueueFamilyId(self.graphics.family.index)
+    }
+}
+
+/// Command buffers recorded for an ownership transfer on both queues, and the semaphore
+/// ordering them, kept until the acquire on the graphics queue completes.
+#[derive(Debug)]
+struct PendingTransfer<B: hal::Backend> {
+    submits: Vec<OneShotSubmit<B>>,
+    semaphore: B::Semaphore,
+    fence: Fence<B>,
+}
+
+impl<B: hal::Backend> PendingTransfer<B> {
+    unsafe fn destroy(self, factory: &mut Factory<B>) {
+        for submit in self.submits {
+            submit.destroy(factory);
+        }
+        factory.destroy_semaphore(self.semaphore);
+        factory.destroy_fence(self.fence);
+    }
+}
+
+/// The ownership transfers of `transfer_to_graphics` which may still be running. The graphics
+/// queue orders them before what's submitted after them by itself, so their command buffers
+/// only have to be freed once they complete, with `collect`.
+#[derive(Debug, Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct PendingTransfers<B: hal::Backend>(Vec<PendingTransfer<B>>);
+
+impl<B: hal::Backend> PendingTransfers<B> {
+    /// Frees the transfers which have completed.
+    pub fn collect(&mut self, factory: &mut Factory<B>) -> Result<(), failure::Error> {
+        let mut i = 0;
+        while i < self.0.len() {
+            if unsafe { factory.device().get_fence_status(self.0[i].fence.raw())? } {
+                unsafe {
+                    self.0.swap_remove(i).destroy(factory);
+                }
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for every transfer and frees them, before the factory is destroyed.
+    pub fn dispose(&mut self, factory: &mut Factory<B>) -> Result<(), failure::Error> {
+        for mut transfer in self.0.drain(..) {
+            factory.wait_for_fence(&mut transfer.fence, !0)?;
+            unsafe {
+                transfer.destroy(factory);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hand textures, uniform buffers and geometry uploaded with `texture_state`, `uniform_state`
+/// and `vertex_state` over from the transfer queue to the graphics queue. Each buffer is given
+/// with how it's read afterwards. Does nothing without a dedicated transfer queue.
+///
+/// The release is submitted on the transfer queue and the acquire on the graphics queue right
+/// away, the acquire waiting for the release with a semaphore, so that anything submitted to
+/// the graphics queue afterwards sees the resources. Their command buffers are added to
+/// `pending`.
+pub fn transfer_to_graphics<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queues: &UploadQueues,
+    images: &[&Image<B>],
+    buffers: &[(&Buffer<B>, hal::buffer::Access)],
+    pending: &mut PendingTransfers<B>,
+) -> Result<(), failure::Error> {
+    if !queues.dedicated_transfer() || (images.is_empty() && buffers.is_empty()) {
+        return Ok(());
+    }
+
+    // Pending uploads are only submitted on maintain, and must be submitted before the release
+    factory.maintain(families);
+
+    let images = images
+        .iter()
+        .map(|image| (*image, hal::image::Layout::ShaderReadOnlyOptimal))
+        .collect::<Vec<_>>();
+    let read_stages =
+        hal::pso::PipelineStage::VERTEX_INPUT | hal::pso::PipelineStage::FRAGMENT_SHADER;
+
+    let semaphore = factory.create_semaphore()?;
+    let mut fence = factory.create_fence(false)?;
+    let submits = unsafe {
+        vec![
+            submit_once(
+                factory,
+                families,
+                queues.transfer,
+                hal::pso::PipelineStage::TRANSFER..hal::pso::PipelineStage::BOTTOM_OF_PIPE,
+                ownership_barriers(queues, &images, buffers),
+                SubmitSync {
+                    wait: None,
+                    signal: Some(&semaphore),
+                    fence: None,
+                },
+            )?,
+            submit_once(
+                factory,
+                families,
+                queues.graphics,
+                read_stages..read_stages,
+                ownership_barriers(queues, &images, buffers),
+                SubmitSync {
+                    wait: Some((&semaphore, read_stages)),
+                    signal: None,
+                    fence: Some(&mut fence),
+                },
+            )?,
+        ]
+    };
+    pending.0.push(PendingTransfer {
+        submits,
+        semaphore,
+        fence,
+    });
+    Ok(())
+}
+
+/// Acquire ownership of resources released by the transfer queue on the graphics queue.
+/// Each image is given with the layout it was released from, and is transitioned to
+/// `ShaderReadOnlyOptimal`. The release must have completed before this is called, and this
+/// waits for the acquire to complete.
+pub fn acquire_on_graphics<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queues: &UploadQueues,
+    images: &[(&Image<B>, hal::image::Layout)],
+    buffers: &[(&Buffer<B>, hal::buffer::Access)],
+) -> Result<(), failure::Error> {
+    if !queues.dedicated_transfer() || (images.is_empty() && buffers.is_empty()) {
+        return Ok(());
+    }
+
+    unsafe {
+        submit_barriers(
+            factory,
+            families,
+            queues.graphics,
+            hal::pso::PipelineStage::TOP_OF_PIPE
+                ..hal::pso::PipelineStage::VERTEX_INPUT | hal::pso::PipelineStage::FRAGMENT_SHADER,
+            ownership_barriers(queues, images, buffers),
+        )
+    }
+}
+
+/// The release and acquire barriers of an ownership transfer must match exactly, so the
+/// same barriers are recorded on both queues.
+fn ownership_barriers<'a, B: hal::Backend>(
+    queues: &UploadQueues,
+    images: &[(&'a Image<B>, hal::image::Layout)],
+    buffers: &[(&'a Buffer<B>, hal::buffer::Access)],
+) -> Vec<hal::memory::Barrier<'a, B>> {
+    let image_barriers = images
+        .iter()
+        .map(|(image, layout)| hal::memory::Barrier::Image {
+            states: (hal::image::Access::TRANSFER_WRITE, *layout)
+                ..(
+                    hal::image::Access::SHADER_READ,
+                    hal::image::Layout::ShaderReadOnlyOptimal,
+                ),
+            families: Some(queues.family_range()),
+            target: image.raw(),
+            range: hal::image::SubresourceRange {
+                aspects: hal::format::Aspects::COLOR,
+                levels: 0..image.levels(),
+                layers: 0..image.layers(),
+            },
+        });
+
+    let buffer_barriers = buffers
+        .iter()
+        .map(|(buffer, access)| hal::memory::Barrier::Buffer {
+            states: hal::buffer::Access::TRANSFER_WRITE..*access,
+            families: Some(queues.family_range()),
+            target: buffer.raw(),
+            range: None..None,
+        });
+
+    image_barriers.chain(buffer_barriers).collect()
+}
+
+/// A submitted one-shot command buffer and the pool it came from.
+#[derive(Debug)]
+struct OneShotSubmit<B: hal::Backend> {
+    pool: CommandPool<B>,
+    buffer: CommandBuffer<B, hal::queue::QueueType, PendingOnceState, PrimaryLevel>,
+}
+
+impl<B: hal::Backend> OneShotSubmit<B> {
+    /// The submission must have completed.
+    unsafe fn destroy(mut self, factory: &mut Factory<B>) {
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+    }
+}
+
+/// What a submission waits for and signals.
+struct SubmitSync<'a, B: hal::Backend> {
+    wait: Option<(&'a B::Semaphore, hal::pso::PipelineStage)>,
+    signal: Option<&'a B::Semaphore>,
+    fence: Option<&'a mut Fence<B>>,
+}
+
+/// Record the barriers into a one-shot command buffer and submit it.
+unsafe fn submit_once<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    stages: Range<hal::pso::PipelineStage>,
+    barriers: Vec<hal::memory::Barrier<'_, B>>,
+    sync: SubmitSync<'_, B>,
+) -> Result<OneShotSubmit<B>, failure::Error> {
+    let family = families.family_mut(queue.family);
+    let mut pool = factory.create_command_pool(family)?;
+
+    let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+    let mut buf_recording = buf_initial.begin(OneShot, ());
+    buf_recording
+        .encoder()
+        .pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers);
+    let (submit, buffer) = buf_recording.finish().submit_once();
+
+    family.queue_mut(queue.index).submit(
+        Some(
+            Submission::new()
+                .submits(Some(submit))
+                .wait(sync.wait)
+                .signal(sync.signal),
+        ),
+        sync.fence,
+    );
+    Ok(OneShotSubmit { pool, buffer })
+}
+
+/// Record the barriers into a one-shot command buffer, submit it and wait for it to complete,
+/// for transitions made once while setting up.
+pub unsafe fn submit_barriers<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    stages: Range<hal::pso::PipelineStage>,
+    barriers: Vec<hal::memory::Barrier<'_, B>>,
+) -> Result<(), failure::Error> {
+    let mut fence = factory.create_fence(false)?;
+    let submit = submit_once(
+        factory,
+        families,
+        queue,
+        stages,
+        barriers,
+        SubmitSync {
+            wait: None,
+            signal: None,
+            fence: Some(&mut fence),
+        },
+    )?;
+    factory.wait_for_fence(&mut fence, !0)?;
+    factory.destroy_fence(fence);
+    submit.destroy(factory);
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Messages of the graphics API's validation layers. Debug builds of the Vulkan backend enable
+//! the Khronos validation layer and log its messages, and the `validation` feature asks the
+//! Vulkan loader for it in release builds too, where the layer prints its messages itself.
+//! Debug builds of the DX12 backend enable its debug layer, whose messages go to the debugger.
+//!
+//! The logger adds the profiling scopes open when a backend logs a warning or an error, which
+//! name the graph node recording or submitting the commands, and counts them, so the smoke
+//! test and the end of the run can report them. `--abort-on-validation-error` aborts on the
+//! first error, to stop in a debugger where it happened.
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static ABORT_ON_ERROR: AtomicBool = AtomicBool::new(false);
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Targets the backends log validation messages under
+const BACKEND_TARGETS: [&str; 2] = ["gfx_backend_vulkan", "gfx_backend_dx12"];
+
+struct ValidationLogger {
+    inner: env_logger::Logger,
+}
+
+impl log::Log for ValidationLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        let from_backend = BACKEND_TARGETS
+            .iter()
+            .any(|target| record.target().starts_with(target));
+        if !from_backend || record.level() > log::Level::Warn {
+            self.inner.log(record);
+            return;
+        }
+
+        if record.level() == log::Level::Error {
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+        match crate::profile::open_scopes() {
+            Some(scopes) => self.inner.log(
+                &log::Record::builder()
+                    .args(format_args!("{} (in {})", record.args(), scopes))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.inner.log(record),
+        }
+        if record.level() == log::Level::Error && ABORT_ON_ERROR.load(Ordering::Relaxed) {
+            self.inner.flush();
+            std::process::abort();
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Installs the logger, with the filters of `builder`.
+pub fn init_logger(builder: &mut env_logger::Builder) {
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(ValidationLogger { inner }))
+        .expect("The logger was already set");
+}
+
+/// Asks the Vulkan loader for the Khronos validation layer, unless `VK_INSTANCE_LAYERS` already
+/// names the layers to use. Has to be called before the instance is created.
+#[cfg(feature = "validation")]
+pub fn enable_layers() {
+    if std::env::var_os("VK_INSTANCE_LAYERS").is_none() {
+        std::env::set_var("VK_INSTANCE_LAYERS", "VK_LAYER_KHRONOS_validation");
+        log::info!("Enabled the Vulkan validation layer");
+    }
+}
+
+#[cfg(not(feature = "validation"))]
+pub fn enable_layers() {}
+
+pub fn set_abort_on_error(abort: bool) {
+    ABORT_ON_ERROR.store(abort, Ordering::Relaxed);
+}
+
+/// Number of validation errors logged so far.
+pub fn errors() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// Logs how many validation errors and warnings there were, if any.
+pub fn log_summary() {
+    let errors = errors();
+    let warnings = WARNINGS.load(Ordering::Relaxed);
+    if errors > 0 {
+        log::error!(
+            "{} validation errors and {} warnings during the run",
+            errors,
+            warnings
+        );
+    } else if warnings > 0 {
+        log::warn!("{} validation warnings during the run", warnings);
+    }
+}
@@ -0,0 +1,799 @@
+//! Experimental dynamic global illumination by voxel cone tracing. Every frame the scene is
+//! voxelized into a cube around it with compute shaders, the voxels are lit by the scene's
+//! lights, and the result is filtered into the mips of a 3D texture. `pbr.frag` then traces
+//! cones through that texture to gather indirect diffuse and specular light, falling back
+//! to the probes and environment map for whatever light the cones don't catch.
+//!
+//! Voxels only know the albedo and emissive factors of their materials, not their textures,
+//! and direct light is shadowed by marching through the voxels, so the result is a rough
+//! approximation which gets blockier the larger the volume is for its resolution.
+use derivative::Derivative;
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, Families, Fence, OneShot, PendingOnceState, PrimaryLevel,
+        QueueId, Submission,
+    },
+    factory::{Factory, ImageState},
+    hal::{device::Device, pso::DescriptorPool},
+    memory::MemoryUsageValue,
+    resource::{Buffer, BufferInfo, Escape, Handle, Image},
+    shader::{PathBufShaderInfo, Shader, ShaderKind, SourceLanguage},
+    texture::Texture,
+};
+use serde::Deserialize;
+
+use rendy::hal;
+
+use std::mem::size_of;
+
+use crate::{asset, components, node::pbr::LightData};
+
+lazy_static::lazy_static! {
+    static ref CLEAR: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/voxel_clear.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref VOXELIZE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/voxelize.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref INJECT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/voxel_inject.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref DOWNSAMPLE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/voxel_downsample.comp"),
+        ShaderKind::Compute,
+        SourceLanguage::GLSL,
+        "main",
+    );
+}
+
+/// Matches `local_size_x` of `voxelize.comp`.
+const TRIANGLE_GROUP_SIZE: u32 = 64;
+
+/// Matches `local_size_x`, `local_size_y` and `local_size_z` of the shaders working on voxels.
+const VOXEL_GROUP_SIZE: u32 = 4;
+
+/// The cube around the scene which is voxelized, and whether to do so at all.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Derivative)]
+#[derivative(Default)]
+#[serde(default)]
+pub struct VoxelGiSettings {
+    pub enabled: bool,
+    /// Corner of the cube with the smallest coordinates
+    #[derivative(Default(value = "[-2.0, -2.0, -2.0]"))]
+    pub min: [f32; 3],
+    /// Length of the cube's sides
+    #[derivative(Default(value = "4.0"))]
+    pub size: f32,
+    /// Voxels along each side of the cube, rounded up to a power of two
+    #[derivative(Default(value = "64"))]
+    pub resolution: u32,
+    /// Trace a cone for specular reflections too, rather than only for diffuse light
+    #[derivative(Default(value = "true"))]
+    pub specular: bool,
+}
+
+impl VoxelGiSettings {
+    fn resolution(&self) -> u32 {
+        self.resolution.max(2).next_power_of_two()
+    }
+
+    fn mip_levels(&self) -> u8 {
+        self.resolution().trailing_zeros() as u8 + 1
+    }
+
+    fn voxel_size(&self) -> f32 {
+        self.size / self.resolution() as f32
+    }
+}
+
+/// Voxel cone tracing parameters in the layout used by `pbr.frag`. Left zeroed, cone tracing
+/// is disabled.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct VoxelGiUniform {
+    /// Corner of the volume in `xyz`, length of its sides in `w`
+    pub bounds: [f32; 4],
+    /// Whether cone tracing is enabled, voxels along each side, whether specular cones are
+    /// traced and the number of mips
+    pub params: [f32; 4],
+}
+
+impl VoxelGiUniform {
+    pub fn new(settings: &VoxelGiSettings) -> Self {
+        if !settings.enabled {
+            return Default::default();
+        }
+        VoxelGiUniform {
+            bounds: [
+                settings.min[0],
+                settings.min[1],
+                settings.min[2],
+                settings.size,
+            ],
+            params: [
+                1.0,
+                settings.resolution() as f32,
+                if settings.specular { 1.0 } else { 0.0 },
+                settings.mip_levels() as f32,
+            ],
+        }
+    }
+}
+
+/// Matches the `VoxelArgs` uniform block of the voxel shaders.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct VoxelArgs {
+    /// Corner of the volume in `xyz`, size of a voxel in `w`
+    grid: [f32; 4],
+    /// Voxels along each side, number of triangles and number of lights, `w` is unused
+    counts: [i32; 4],
+    lights: [LightData; crate::MAX_LIGHTS],
+}
+
+/// A world space triangle, matching `Triangle` in `voxelize.comp`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Triangle {
+    corners: [[f32; 4]; 3],
+    albedo: [f32; 4],
+    emissive: [f32; 4],
+}
+
+/// Matches `Voxel` in the voxel shaders: albedo with coverage in `a`, normal and emission.
+const VOXEL_SIZE: u64 = size_of::<[f32; 12]>() as u64;
+
+/// Push constants of `voxel_downsample.comp`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct DownsampleArgs {
+    /// Index of the first voxel of the level read from in the radiance buffer
+    src_offset: u32,
+    /// Index of the first voxel of the level written to in the radiance buffer
+    dst_offset: u32,
+    dst_resolution: u32,
+}
+
+impl DownsampleArgs {
+    /// Push constant range, in 32-bit words.
+    const RANGE: std::ops::Range<u32> = 0..(size_of::<DownsampleArgs>() / 4) as u32;
+
+    fn as_words(&self) -> [u32; 3] {
+        [self.src_offset, self.dst_offset, self.dst_resolution]
+    }
+}
+
+/// One mip level of the radiance volume.
+#[derive(Debug, Clone, Copy)]
+struct VolumeMip {
+    level: u8,
+    resolution: u32,
+    /// Offset of the level in the radiance buffer, in voxels
+    offset: u32,
+}
+
+impl VolumeMip {
+    const TEXEL_SIZE: u64 = size_of::<[f32; 4]>() as u64;
+
+    fn texels(&self) -> u32 {
+        self.resolution * self.resolution * self.resolution
+    }
+}
+
+fn volume_mips(resolution: u32, levels: u8) -> Vec<VolumeMip> {
+    let mut offset = 0;
+    (0..levels)
+        .map(|level| {
+            let mip = VolumeMip {
+                level,
+                resolution: resolution >> level,
+                offset,
+            };
+            offset += mip.texels();
+            mip
+        })
+        .collect()
+}
+
+/// A black 3D texture readable by fragment shaders on `queue`. With a single voxel, it stands
+/// in for the radiance volume when voxel cone tracing is disabled.
+pub fn create_volume<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    queue: QueueId,
+    resolution: u32,
+    mip_levels: u8,
+) -> Result<Texture<B>, failure::Error> {
+    Ok(rendy::texture::TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D3(
+            resolution, resolution, resolution,
+        ))
+        .with_mip_levels(rendy::texture::MipLevels::Levels(
+            std::num::NonZeroU8::new(mip_levels).unwrap(),
+        ))
+        .with_view_kind(rendy::resource::ViewKind::D3)
+        .with_data_width(resolution)
+        .with_data_height(resolution)
+        .with_data(vec![
+            rendy::texture::pixel::Rgba32Sfloat {
+                repr: [0.0, 0.0, 0.0, 0.0]
+            };
+            (resolution * resolution * resolution) as usize
+        ])
+        .build(
+            ImageState {
+                queue,
+                stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                access: hal::image::Access::SHADER_READ,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            },
+            factory,
+        )?)
+}
+
+/// Voxelizes and lights the scene into a radiance volume once per frame, on the graphics
+/// queue ahead of the frame's rendering.
+#[derive(Debug)]
+pub struct VoxelGi<B: hal::Backend> {
+    settings: VoxelGiSettings,
+    queue: QueueId,
+    pool: CommandPool<B>,
+    descriptor_pool: B::DescriptorPool,
+    set_layout: B::DescriptorSetLayout,
+    set: B::DescriptorSet,
+    pipeline_layout: B::PipelineLayout,
+    clear_pipeline: B::ComputePipeline,
+    voxelize_pipeline: B::ComputePipeline,
+    inject_pipeline: B::ComputePipeline,
+    downsample_pipeline: B::ComputePipeline,
+    args: Escape<Buffer<B>>,
+    triangles: Escape<Buffer<B>>,
+    triangle_capacity: usize,
+    triangle_count: usize,
+    /// The meshes and transforms the triangles were gathered from, to gather them again
+    /// only once something moves
+    instances: Vec<(asset::MeshHandle, nalgebra::Matrix4<f32>)>,
+    voxels: Escape<Buffer<B>>,
+    radiance: Escape<Buffer<B>>,
+    mips: Vec<VolumeMip>,
+    volume: Handle<Image<B>>,
+    pass: Option<VoxelPass<B>>,
+}
+
+/// The work submitted for a frame, which has to finish before the buffers it reads are
+/// written for the next one.
+#[derive(Debug)]
+struct VoxelPass<B: hal::Backend> {
+    buffer: CommandBuffer<B, hal::queue::QueueType, PendingOnceState, PrimaryLevel>,
+    fence: Fence<B>,
+}
+
+impl<B: hal::Backend> VoxelGi<B> {
+    /// Set up voxel cone tracing, returning the radiance volume it writes to, which is
+    /// readable by fragment shaders on `queue` between updates.
+    pub fn new(
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        queue: QueueId,
+        settings: VoxelGiSettings,
+    ) -> Result<(Self, Texture<B>), failure::Error> {
+        let resolution = settings.resolution();
+        let mips = volume_mips(resolution, settings.mip_levels());
+        let total_texels: u32 = mips.iter().map(VolumeMip::texels).sum();
+        log::info!(
+            "Voxel cone tracing with {}^3 voxels of {} units",
+            resolution,
+            settings.voxel_size()
+        );
+
+        let volume = create_volume(factory, queue, resolution, settings.mip_levels())?;
+
+        let args = factory.create_buffer(
+            BufferInfo {
+                size: size_of::<VoxelArgs>() as u64,
+                usage: hal::buffer::Usage::UNIFORM,
+            },
+            MemoryUsageValue::Dynamic,
+        )?;
+        let triangles = create_triangle_buffer(factory, 1)?;
+        let voxels = factory.create_buffer(
+            BufferInfo {
+                size: mips[0].texels() as u64 * VOXEL_SIZE,
+                usage: hal::buffer::Usage::STORAGE,
+            },
+            MemoryUsageValue::Data,
+        )?;
+        let radiance = factory.create_buffer(
+            BufferInfo {
+                size: total_texels as u64 * VolumeMip::TEXEL_SIZE,
+                usage: hal::buffer::Usage::STORAGE | hal::buffer::Usage::TRANSFER_SRC,
+            },
+            MemoryUsageValue::Data,
+        )?;
+
+        let storage_binding = |binding| hal::pso::DescriptorSetLayoutBinding {
+            binding,
+            ty: hal::pso::DescriptorType::StorageBuffer,
+            count: 1,
+            stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+            immutable_samplers: false,
+        };
+        let (set_layout, pipeline_layout) = unsafe {
+            let set_layout = factory.device().create_descriptor_set_layout(
+                vec![
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 0,
+                        ty: hal::pso::DescriptorType::UniformBuffer,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::COMPUTE,
+                        immutable_samplers: false,
+                    },
+                    // triangles
+                    storage_binding(1),
+                    // voxels
+                    storage_binding(2),
+                    // radiance
+                    storage_binding(3),
+                ],
+                std::iter::empty::<B::Sampler>(),
+            )?;
+            let pipeline_layout = factory.device().create_pipeline_layout(
+                Some(&set_layout),
+                Some((hal::pso::ShaderStageFlags::COMPUTE, DownsampleArgs::RANGE)),
+            )?;
+            (set_layout, pipeline_layout)
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory.create_descriptor_pool(
+                1,
+                vec![
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::UniformBuffer,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::StorageBuffer,
+                        count: 3,
+                    },
+                ],
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )?
+        };
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layout)?;
+            factory.write_descriptor_sets(
+                [
+                    (0, args.raw()),
+                    (1, triangles.raw()),
+                    (2, voxels.raw()),
+                    (3, radiance.raw()),
+                ]
+                .iter()
+                .map(|(binding, buffer)| hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: *binding,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Buffer(*buffer, None..None)),
+                }),
+            );
+            set
+        };
+
+        let (clear_pipeline, voxelize_pipeline, inject_pipeline, downsample_pipeline) = unsafe {
+            (
+                create_pipeline(factory, &pipeline_layout, &*CLEAR)?,
+                create_pipeline(factory, &pipeline_layout, &*VOXELIZE)?,
+                create_pipeline(factory, &pipeline_layout, &*INJECT)?,
+                create_pipeline(factory, &pipeline_layout, &*DOWNSAMPLE)?,
+            )
+        };
+
+        let pool = factory.create_command_pool(families.family_mut(queue.family))?;
+
+        Ok((
+            VoxelGi {
+                settings,
+                queue,
+                pool,
+                descriptor_pool,
+                set_layout,
+                set,
+                pipeline_layout,
+                clear_pipeline,
+                voxelize_pipeline,
+                inject_pipeline,
+                downsample_pipeline,
+                args,
+                triangles,
+                triangle_capacity: 1,
+                triangle_count: 0,
+                instances: Vec::new(),
+                voxels,
+                radiance,
+                mips,
+                volume: volume.image().clone(),
+                pass: None,
+            },
+            volume,
+        ))
+    }
+
+    /// Voxelize and light the scene as it is now, and filter it into the radiance volume.
+    /// Waits for the previous update to finish first.
+    pub fn update(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        world: &specs::World,
+    ) -> Result<(), failure::Error> {
+        if let Some(pass) = self.pass.take() {
+            self.finish_pass(factory, pass)?;
+        }
+
+        self.write_triangles(factory, world)?;
+
+        let (n_lights, lights) = crate::node::pbr::gather_lights(world);
+        let args = VoxelArgs {
+            grid: [
+                self.settings.min[0],
+                self.settings.min[1],
+                self.settings.min[2],
+                self.settings.voxel_size(),
+            ],
+            counts: [
+                self.settings.resolution() as i32,
+                self.triangle_count as i32,
+                n_lights as i32,
+                0,
+            ],
+            lights,
+        };
+        unsafe {
+            factory.upload_visible_buffer(&mut self.args, 0, &[args])?;
+        }
+
+        let voxel_groups = |resolution: u32| {
+            let groups = (resolution + VOXEL_GROUP_SIZE - 1) / VOXEL_GROUP_SIZE;
+            [groups, groups, groups]
+        };
+        let compute_to_compute =
+            hal::pso::PipelineStage::COMPUTE_SHADER..hal::pso::PipelineStage::COMPUTE_SHADER;
+        let written = hal::buffer::Access::SHADER_WRITE
+            ..hal::buffer::Access::SHADER_READ | hal::buffer::Access::SHADER_WRITE;
+
+        let family = families.family_mut(self.queue.family);
+        let buf_initial = self.pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(OneShot, ());
+        {
+            let mut encoder = buf_recording.encoder();
+            unsafe {
+                encoder.bind_compute_descriptor_sets(
+                    &self.pipeline_layout,
+                    0,
+                    Some(&self.set),
+                    std::iter::empty(),
+                );
+
+                encoder.bind_compute_pipeline(&self.clear_pipeline);
+                encoder.dispatch(voxel_groups(self.mips[0].resolution));
+                encoder.pipeline_barrier(
+                    compute_to_compute.clone(),
+                    hal::memory::Dependencies::empty(),
+                    Some(buffer_barrier(&self.voxels, written.clone())),
+                );
+
+                if self.triangle_count > 0 {
+                    encoder.bind_compute_pipeline(&self.voxelize_pipeline);
+                    encoder.dispatch([
+                        (self.triangle_count as u32 + TRIANGLE_GROUP_SIZE - 1)
+                            / TRIANGLE_GROUP_SIZE,
+                        1,
+                        1,
+                    ]);
+                    encoder.pipeline_barrier(
+                        compute_to_compute.clone(),
+                        hal::memory::Dependencies::empty(),
+                        Some(buffer_barrier(&self.voxels, written.clone())),
+                    );
+                }
+
+                encoder.bind_compute_pipeline(&self.inject_pipeline);
+                encoder.dispatch(voxel_groups(self.mips[0].resolution));
+
+                encoder.bind_compute_pipeline(&self.downsample_pipeline);
+                for levels in self.mips.windows(2) {
+                    encoder.pipeline_barrier(
+                        compute_to_compute.clone(),
+                        hal::memory::Dependencies::empty(),
+                        Some(buffer_barrier(&self.radiance, written.clone())),
+                    );
+                    let args = DownsampleArgs {
+                        src_offset: levels[0].offset,
+                        dst_offset: levels[1].offset,
+                        dst_resolution: levels[1].resolution,
+                    };
+                    encoder.push_constants(
+                        &self.pipeline_layout,
+                        hal::pso::ShaderStageFlags::COMPUTE,
+                        DownsampleArgs::RANGE.start,
+                        &args.as_words(),
+                    );
+                    encoder.dispatch(voxel_groups(levels[1].resolution));
+                }
+
+                // Frames in flight may still be sampling the volume, so wait for their
+                // fragment shaders before copying over it
+                encoder.pipeline_barrier(
+                    hal::pso::PipelineStage::COMPUTE_SHADER
+                        | hal::pso::PipelineStage::FRAGMENT_SHADER
+                        ..hal::pso::PipelineStage::TRANSFER,
+                    hal::memory::Dependencies::empty(),
+                    vec![
+                        buffer_barrier(
+                            &self.radiance,
+                            hal::buffer::Access::SHADER_WRITE..hal::buffer::Access::TRANSFER_READ,
+                        ),
+                        self.volume_barrier(
+                            (
+                                hal::image::Access::SHADER_READ,
+                                hal::image::Layout::ShaderReadOnlyOptimal,
+                            )
+                                ..(
+                                    hal::image::Access::TRANSFER_WRITE,
+                                    hal::image::Layout::TransferDstOptimal,
+                                ),
+                        ),
+                    ],
+                );
+
+                encoder.copy_buffer_to_image(
+                    self.radiance.raw(),
+                    self.volume.raw(),
+                    hal::image::Layout::TransferDstOptimal,
+                    self.mips.iter().map(|mip| hal::command::BufferImageCopy {
+                        buffer_offset: mip.offset as u64 * VolumeMip::TEXEL_SIZE,
+                        buffer_width: mip.resolution,
+                        buffer_height: mip.resolution,
+                        image_layers: hal::image::SubresourceLayers {
+                            aspects: hal::format::Aspects::COLOR,
+                            level: mip.level,
+                            layers: 0..1,
+                        },
+                        image_offset: hal::image::Offset::ZERO,
+                        image_extent: hal::image::Extent {
+                            width: mip.resolution,
+                            height: mip.resolution,
+                            depth: mip.resolution,
+                        },
+                    }),
+                );
+
+                encoder.pipeline_barrier(
+                    hal::pso::PipelineStage::TRANSFER..hal::pso::PipelineStage::FRAGMENT_SHADER,
+                    hal::memory::Dependencies::empty(),
+                    Some(self.volume_barrier(
+                        (
+                            hal::image::Access::TRANSFER_WRITE,
+                            hal::image::Layout::TransferDstOptimal,
+                        )
+                            ..(
+                                hal::image::Access::SHADER_READ,
+                                hal::image::Layout::ShaderReadOnlyOptimal,
+                            ),
+                    )),
+                );
+            }
+        }
+        let (submit, buffer) = buf_recording.finish().submit_once();
+
+        let mut fence = factory.create_fence(false)?;
+        unsafe {
+            family.queue_mut(self.queue.index).submit(
+                Some(Submission::new().submits(Some(submit))),
+                Some(&mut fence),
+            );
+        }
+
+        self.pass = Some(VoxelPass { buffer, fence });
+        Ok(())
+    }
+
+    /// Gather the triangles of every mesh in world space and write them to the triangle
+    /// buffer, unless no mesh was added, removed or moved since the last time.
+    fn write_triangles(
+        &mut self,
+        factory: &mut Factory<B>,
+        world: &specs::World,
+    ) -> Result<(), failure::Error> {
+        use specs::prelude::*;
+
+        let meshes = world.read_storage::<components::Mesh>();
+        let transforms = world.read_storage::<components::GlobalTransform>();
+        let instances = (&meshes, &transforms)
+            .join()
+            .map(|(mesh, transform)| (mesh.0, transform.0))
+            .collect::<Vec<_>>();
+        if instances == self.instances {
+            return Ok(());
+        }
+
+        let mesh_storage = world.read_resource::<asset::MeshStorage>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage>();
+        let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+
+        let mut triangles = Vec::new();
+        for (mesh, transform) in instances.iter() {
+            for primitive in mesh_storage.0[*mesh].primitives.iter() {
+                let primitive = &primitive_storage.0[*primitive];
+                let factors = &material_storage.0[primitive.mat].factors;
+                let emissive = factors.emissive;
+                let corners = primitive
+                    .geometry
+                    .positions
+                    .iter()
+                    .map(|position| {
+                        let p = transform
+                            * nalgebra::Point3::from(nalgebra::Vector3::from(*position))
+                                .to_homogeneous();
+                        [p.x, p.y, p.z, 1.0]
+                    })
+                    .collect::<Vec<_>>();
+                for triangle in primitive.geometry.indices.chunks(3) {
+                    if triangle.len() < 3 {
+                        continue;
+                    }
+                    triangles.push(Triangle {
+                        corners: [
+                            corners[triangle[0] as usize],
+                            corners[triangle[1] as usize],
+                            corners[triangle[2] as usize],
+                        ],
+                        albedo: factors.albedo,
+                        emissive: [emissive[0], emissive[1], emissive[2], 0.0],
+                    });
+                }
+            }
+        }
+
+        if triangles.len() > self.triangle_capacity {
+            self.triangle_capacity = triangles.len().next_power_of_two();
+            self.triangles = create_triangle_buffer(factory, self.triangle_capacity)?;
+            unsafe {
+                factory.write_descriptor_sets(Some(hal::pso::DescriptorSetWrite {
+                    set: &self.set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Buffer(
+                        self.triangles.raw(),
+                        None..None,
+                    )),
+                }));
+            }
+        }
+        if !triangles.is_empty() {
+            unsafe {
+                factory.upload_visible_buffer(&mut self.triangles, 0, &triangles)?;
+            }
+        }
+        log::debug!("Voxelizing {} triangles", triangles.len());
+        self.triangle_count = triangles.len();
+        self.instances = instances;
+        Ok(())
+    }
+
+    fn finish_pass(
+        &mut self,
+        factory: &mut Factory<B>,
+        mut pass: VoxelPass<B>,
+    ) -> Result<(), failure::Error> {
+        factory.wait_for_fence(&mut pass.fence, !0)?;
+        unsafe {
+            self.pool.free_buffers(Some(pass.buffer.mark_complete()));
+            factory.destroy_fence(pass.fence);
+        }
+        Ok(())
+    }
+
+    /// Wait for the update in flight and destroy everything but the radiance volume.
+    pub fn dispose(mut self, factory: &mut Factory<B>) -> Result<(), failure::Error> {
+        if let Some(pass) = self.pass.take() {
+            self.finish_pass(factory, pass)?;
+        }
+
+        unsafe {
+            factory.destroy_command_pool(self.pool);
+            self.descriptor_pool.reset();
+            factory.destroy_descriptor_pool(self.descriptor_pool);
+            for pipeline in vec![
+                self.clear_pipeline,
+                self.voxelize_pipeline,
+                self.inject_pipeline,
+                self.downsample_pipeline,
+            ] {
+                factory.device().destroy_compute_pipeline(pipeline);
+            }
+            factory
+                .device()
+                .destroy_pipeline_layout(self.pipeline_layout);
+            factory
+                .device()
+                .destroy_descriptor_set_layout(self.set_layout);
+        }
+        Ok(())
+    }
+
+    fn volume_barrier(
+        &self,
+        states: std::ops::Range<hal::image::State>,
+    ) -> hal::memory::Barrier<'_, B> {
+        hal::memory::Barrier::Image {
+            states,
+            families: None,
+            target: self.volume.raw(),
+            range: hal::image::SubresourceRange {
+                aspects: hal::format::Aspects::COLOR,
+                levels: 0..self.volume.levels(),
+                layers: 0..1,
+            },
+        }
+    }
+}
+
+fn buffer_barrier<B: hal::Backend>(
+    buffer: &Buffer<B>,
+    states: std::ops::Range<hal::buffer::State>,
+) -> hal::memory::Barrier<'_, B> {
+    hal::memory::Barrier::Buffer {
+        states,
+        families: None,
+        target: buffer.raw(),
+        range: None..None,
+    }
+}
+
+fn create_triangle_buffer<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    capacity: usize,
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    Ok(factory.create_buffer(
+        BufferInfo {
+            size: (capacity * size_of::<Triangle>()) as u64,
+            usage: hal::buffer::Usage::STORAGE,
+        },
+        MemoryUsageValue::Dynamic,
+    )?)
+}
+
+unsafe fn create_pipeline<B: hal::Backend>(
+    factory: &Factory<B>,
+    layout: &B::PipelineLayout,
+    shader: &PathBufShaderInfo,
+) -> Result<B::ComputePipeline, failure::Error> {
+    let module = factory.device().create_shader_module(&shader.spirv()?)?;
+    let pipeline = factory.device().create_compute_pipeline(
+        &hal::pso::ComputePipelineDesc::new(
+            hal::pso::EntryPoint {
+                entry: "main",
+                module: &module,
+                specialization: hal::pso::Specialization::default(),
+            },
+            layout,
+        ),
+        None,
+    );
+    factory.device().destroy_shader_module(module);
+    Ok(pipeline?)
+}
@@ -0,0 +1,26 @@
+//! Runs the app's smoke test of the render graph, see `src/smoke.rs`, as a test target rather
+//! than by hand. It needs a GPU and a backend, so it's ignored by default, run it with e.g.
+//! `cargo test --features vulkan --test smoke -- --ignored`.
+use std::process::Command;
+
+/// Runs the smoke test of a scene, which exits with an error if any configuration of the
+/// graph failed to build or run a frame.
+fn smoke(scene: &str) {
+    let status = Command::new(env!("CARGO_BIN_EXE_rendy-pbr"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(&["--smoke", "--scene", scene])
+        .status()
+        .expect("The app starts");
+    assert!(
+        status.success(),
+        "The smoke test of {} failed with {}, its log says in which configurations",
+        scene,
+        status
+    );
+}
+
+#[test]
+#[ignore]
+fn default_scene() {
+    smoke("assets/scene.ron");
+}